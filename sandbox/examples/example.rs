@@ -1,10 +1,119 @@
+use std::path::Path;
+use std::time::Instant;
+
+use ash::vk;
+
 use winit::{
-    event::{Event, WindowEvent},
+    event::{
+        DeviceEvent, ElementState, Event, KeyboardInput, MouseButton, MouseScrollDelta,
+        VirtualKeyCode, WindowEvent,
+    },
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
 
-use ash_sample;
+use ash_sample::assets::{load_obj, ObjVertex};
+use ash_sample::{
+    mat4_mul, Camera, CameraInput, FlyController, GraphicsPipeline, MeshId, OrbitController,
+    PipelineBuilder, Renderer, RendererBuilder, Result, Transform,
+};
+
+/// winitのイベントを溜めて、フレームごとの`CameraInput`にする
+///
+/// WASDで前後左右、EとQで上下に移動する。右ボタンを押している間はマウスで向きを変え、
+/// ホイールでオービットの距離を変える。
+#[derive(Default)]
+struct CameraInputState {
+    /// 押されている移動キー。右、左、上、下、前、後の順
+    held: [bool; 6],
+    looking: bool,
+    look: [f32; 2],
+    zoom: f32,
+}
+
+impl CameraInputState {
+    fn window_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        virtual_keycode: Some(key),
+                        state,
+                        ..
+                    },
+                ..
+            } => {
+                let index = match key {
+                    VirtualKeyCode::D => 0,
+                    VirtualKeyCode::A => 1,
+                    VirtualKeyCode::E => 2,
+                    VirtualKeyCode::Q => 3,
+                    VirtualKeyCode::W => 4,
+                    VirtualKeyCode::S => 5,
+                    _ => return,
+                };
+                self.held[index] = *state == ElementState::Pressed;
+            }
+            WindowEvent::MouseInput {
+                state,
+                button: MouseButton::Right,
+                ..
+            } => self.looking = *state == ElementState::Pressed,
+            WindowEvent::MouseWheel { delta, .. } => {
+                self.zoom += match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y,
+                    // おおよそ1行分のピクセル数で割る
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / 40.0,
+                };
+            }
+            // フォーカスを失うとキーを離したイベントが届かない
+            WindowEvent::Focused(false) => {
+                self.held = [false; 6];
+                self.looking = false;
+            }
+            _ => (),
+        }
+    }
+
+    fn device_event(&mut self, event: &DeviceEvent) {
+        if let DeviceEvent::MouseMotion { delta } = event {
+            if self.looking {
+                self.look[0] += delta.0 as f32;
+                self.look[1] += delta.1 as f32;
+            }
+        }
+    }
+
+    /// 押されているキーの移動と、前回から溜まった回転とズームを返す
+    fn take(&mut self) -> CameraInput {
+        let axis = |positive: bool, negative: bool| positive as i32 as f32 - negative as i32 as f32;
+        let h = self.held;
+        CameraInput {
+            movement: [axis(h[0], h[1]), axis(h[2], h[3]), axis(h[4], h[5])],
+            look: std::mem::take(&mut self.look),
+            zoom: std::mem::take(&mut self.zoom),
+        }
+    }
+}
+
+/// `examples/assets/cube.obj`のメッシュと、法線を色にして描くパイプラインを作る
+fn create_cube(renderer: &mut Renderer, camera: &Camera) -> Result<(MeshId, GraphicsPipeline)> {
+    let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    let model = load_obj(examples.join("assets/cube.obj"))?;
+    let mesh = renderer.create_obj_mesh(&model)?;
+    let vertex_shader = renderer.load_shader_spv(&examples.join("shaders/mesh.vert.spv"))?;
+    let fragment_shader = renderer.load_shader_spv(&examples.join("shaders/mesh.frag.spv"))?;
+    let builder = PipelineBuilder::new()
+        .vertex_shader(renderer.shader_module(vertex_shader)?)
+        .fragment_shader(renderer.shader_module(fragment_shader)?)
+        .vertex_layout(0, &ObjVertex::layout())
+        .depth_compare_op(camera.depth_compare_op())
+        .push_constant_range(vk::ShaderStageFlags::VERTEX, 0, 64);
+    let pipeline = renderer.create_swapchain_pipeline(&builder);
+    renderer.destroy_shader_module(vertex_shader)?;
+    renderer.destroy_shader_module(fragment_shader)?;
+    Ok((mesh, pipeline?))
+}
 
 fn main() {
     let event_loop = EventLoop::new();
@@ -14,21 +123,115 @@ fn main() {
         // .with_inner_size(winit::dpi::LogicalSize::new(128.0, 128.0))
         .build(&event_loop)
         .unwrap();
-    ash_sample::test(&window);
+    let mut renderer = match RendererBuilder::new()
+        .application_name(c"Example")
+        .build(&window)
+    {
+        Ok(renderer) => renderer,
+        Err(err) => {
+            eprintln!("Failed to create renderer: {}", err);
+            return;
+        }
+    };
+
+    let size = window.inner_size();
+    let mut camera = Camera {
+        position: [0.0, 1.0, 5.0],
+        aspect: size.width.max(1) as f32 / size.height.max(1) as f32,
+        ..Camera::default()
+    };
+    let (cube, mut pipeline) = match create_cube(&mut renderer, &camera) {
+        Ok((cube, pipeline)) => (cube, Some(pipeline)),
+        Err(err) => {
+            eprintln!("Failed to create the cube: {}", err);
+            return;
+        }
+    };
+    let start = Instant::now();
+    let fly = FlyController::default();
+    let mut orbit = OrbitController::default();
+    // Tabでフライとオービットを切り替える
+    let mut orbiting = false;
+    let mut input = CameraInputState::default();
+    let mut last_frame = Instant::now();
 
     event_loop.run(move |event, _, control_flow| {
-        *control_flow = ControlFlow::Wait;
+        *control_flow = ControlFlow::Poll;
 
         match event {
-            Event::WindowEvent { event, window_id } => match (event, window_id) {
-                (WindowEvent::CloseRequested, _window_id) if _window_id == window.id() => {
-                    *control_flow = ControlFlow::Exit
+            Event::WindowEvent { event, window_id } if window_id == window.id() => {
+                input.window_event(&event);
+                match event {
+                    WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                    WindowEvent::Resized(size) => {
+                        if size.height > 0 {
+                            camera.aspect = size.width as f32 / size.height as f32;
+                        }
+                        renderer
+                            .recreate_swapchain(size.width, size.height)
+                            .expect("Failed to recreate swapchain")
+                    }
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                virtual_keycode: Some(VirtualKeyCode::Tab),
+                                state: ElementState::Pressed,
+                                ..
+                            },
+                        ..
+                    } => orbiting = !orbiting,
+                    _ => (),
                 }
-                _ => (),
-            },
+            }
+            Event::DeviceEvent { event, .. } => input.device_event(&event),
             Event::MainEventsCleared => {
                 window.request_redraw();
             }
+            Event::RedrawRequested(_) => {
+                let now = Instant::now();
+                let delta_seconds = now.duration_since(last_frame).as_secs_f32();
+                last_frame = now;
+                let frame_input = input.take();
+                let previous = camera;
+                if orbiting {
+                    orbit.update(&mut camera, &frame_input, delta_seconds);
+                } else {
+                    fly.update(&mut camera, &frame_input, delta_seconds);
+                }
+                if camera != previous {
+                    let [x, y, z] = camera.position;
+                    window.set_title(&format!("Example ({:.2}, {:.2}, {:.2})", x, y, z));
+                }
+
+                if let Some(command_buffer) = renderer.begin_frame().expect("Failed to begin frame")
+                {
+                    renderer
+                        .begin_swapchain_rendering(command_buffer, [0.1, 0.2, 0.4, 1.0])
+                        .expect("Failed to begin rendering");
+                    let angle = start.elapsed().as_secs_f32() * 0.5;
+                    let model = Transform::from_axis_angle([0.0, 1.0, 0.0], angle).matrix();
+                    let model_view_projection = mat4_mul(&camera.view_projection_matrix(), &model);
+                    let push_constants: Vec<u8> = model_view_projection
+                        .iter()
+                        .flatten()
+                        .flat_map(|value| value.to_ne_bytes())
+                        .collect();
+                    if let Some(pipeline) = &pipeline {
+                        renderer
+                            .draw_mesh(cube, pipeline, &[], &push_constants)
+                            .expect("Failed to draw the cube");
+                    }
+                    renderer
+                        .end_swapchain_rendering(command_buffer)
+                        .expect("Failed to end rendering");
+                    renderer.end_frame().expect("Failed to end frame");
+                }
+            }
+            Event::LoopDestroyed => {
+                if let Some(pipeline) = pipeline.take() {
+                    renderer.destroy_graphics_pipeline(pipeline);
+                }
+            }
             _ => (),
         }
     });
@@ -0,0 +1,16 @@
+#version 450
+
+// mesh.vert.spvはこのシェーダーと同じ内容
+layout(push_constant) uniform PushConstants {
+    mat4 model_view_projection;
+};
+
+layout(location = 0) in vec3 in_position;
+layout(location = 1) in vec3 in_normal;
+
+layout(location = 0) out vec3 out_color;
+
+void main() {
+    gl_Position = model_view_projection * vec4(in_position, 1.0);
+    out_color = in_normal * 0.5 + 0.5;
+}
@@ -1,3 +1,247 @@
+mod ambient_occlusion;
+mod anti_aliasing;
+pub mod assets;
+mod auto_exposure;
+mod budget;
+mod buffer;
+mod builder;
+mod camera;
+mod camera_animation;
+mod camera_effects;
+mod clustered;
+mod color_grading;
+mod color_vision;
+mod compute;
+mod conditional_rendering;
+mod culling;
+mod deferred;
+mod deletion_queue;
+mod depth_prepass;
+mod depth_variant;
+mod descriptor;
+mod displacement;
+mod display;
+mod dynamic_rendering;
+mod environment;
+mod error;
+mod external;
+mod frame;
+mod gpu_driven;
+mod gpu_primitives;
+mod handle;
+mod hdr;
+mod hdr_output;
+mod hi_z;
+mod hot_reload;
+mod ibl;
+mod image_processing;
+mod instancing;
+mod lens_flare;
+mod lighting;
+mod lod;
+mod material;
+mod memory;
+mod mesh;
+mod msaa;
+mod oit;
+mod outline;
+mod particles;
+mod per_frame;
+mod photometry;
+mod physical_camera;
+mod pipeline;
+mod point_shadow;
+mod post_process;
+mod raw;
+mod readback;
+mod render_graph;
+mod render_pass;
 mod renderer;
+mod scene;
+mod screen_space_reflections;
+mod shader;
+mod shader_library;
+mod shadow;
+mod skinning;
+mod stereo;
+mod submission;
+mod texture;
+mod texture_feedback;
+mod texture_format;
+mod texture_layers;
+mod view_settings;
 
+pub use ambient_occlusion::{
+    AmbientOcclusion, AmbientOcclusionSettings, AmbientOcclusionShaders, AmbientOcclusionTargets,
+    AmbientOcclusionUniform, AMBIENT_OCCLUSION_FORMAT, AMBIENT_OCCLUSION_GROUP_SIZE,
+    MAX_AMBIENT_OCCLUSION_SAMPLES,
+};
+pub use anti_aliasing::{
+    AntiAliasing, AntiAliasingMode, AntiAliasingSettings, AntiAliasingShaders, AntiAliasingTargets,
+    ANTI_ALIASING_GROUP_SIZE, MOTION_VECTOR_FORMAT, TAA_JITTER_SAMPLES,
+};
+pub use auto_exposure::{
+    AutoExposure, AutoExposureSettings, AutoExposureShaders, ExposureAveragePushConstants,
+    LuminanceHistogramPushConstants, LUMINANCE_HISTOGRAM_BINS, LUMINANCE_HISTOGRAM_GROUP_SIZE,
+};
+pub use budget::{
+    BudgetExceededFn, BudgetReport, BudgetTracker, BudgetUsage, SystemBudget, MAX_BUDGET_SCOPES,
+};
+pub use buffer::{Buffer, BufferId};
+pub use builder::{RendererBuilder, RendererConfig};
+pub use camera::{
+    jitter_projection, mat4_inverse, mat4_mul, Camera, CameraInput, FlyController, Mat4,
+    OrbitController, Projection, MAT4_IDENTITY,
+};
+pub use camera_animation::{
+    AnimationEvent, CameraAnimation, CameraAnimationPlayer, CameraAnimationSample, Easing,
+    Interpolate, Keyframe, Track, YawPitch,
+};
+pub use camera_effects::{
+    CameraEffectShaders, CameraEffectTargets, CameraEffects, DepthOfFieldPushConstants,
+    DepthOfFieldSettings, MotionBlurPushConstants, MotionBlurSettings, CAMERA_EFFECT_GROUP_SIZE,
+    MAX_MOTION_BLUR_SAMPLES,
+};
+pub use clustered::{
+    ClusterCullPushConstants, ClusterSettings, ClusteredLighting, ClusteredLightingShaders,
+    CLUSTER_CULL_GROUP_SIZE,
+};
+pub use color_grading::{ColorGrading, NEUTRAL_LUT_SIZE};
+pub use color_vision::{
+    ColorVisionDeficiency, ColorVisionFilter, ColorVisionMode, ColorVisionScope,
+    COLOR_VISION_LUT_SIZE, UI_SAFE_PALETTE,
+};
+pub use compute::{ComputePipeline, DISPATCH_INDIRECT_COMMAND_SIZE};
+pub use conditional_rendering::ConditionalRendering;
+pub use culling::{Aabb, Bvh, CullingStats, Frustum};
+pub use deferred::{
+    DeferredLighting, DeferredLightingPushConstants, DeferredLightingShaders, GBuffer, RenderPath,
+    GBUFFER_ALBEDO_FORMAT, GBUFFER_MATERIAL_FORMAT, GBUFFER_NORMAL_FORMAT,
+};
+pub use deletion_queue::DeletionQueue;
+pub use depth_variant::{
+    DepthShaderSources, DepthVariantFeatures, DEPTH_VARIANT_DISPLACEMENT_SET,
+    DEPTH_VARIANT_MATERIAL_SET,
+};
+pub use descriptor::{
+    DescriptorAllocator, DescriptorBinding, DescriptorLayoutCache, DescriptorWriter,
+};
+pub use displacement::{
+    displaced_vertex_shader, DisplacementUniform, DisplacementUniforms, VertexDisplacement,
+    VertexPass,
+};
+pub use display::{enumerate_displays, DisplayInfo, DisplayModeInfo, DisplaySelection};
+pub use dynamic_rendering::{DynamicRendering, RenderingAttachment};
+pub use environment::{Environment, EnvironmentShaders, SkyboxPushConstants};
+pub use error::{RendererError, Result};
+pub use external::ExternalContext;
+pub use frame::{FrameContext, MAX_FRAMES_IN_FLIGHT};
+pub use gpu_driven::{
+    GpuDrawInstance, GpuDriven, GpuDrivenBatch, GpuDrivenShaders, InstanceData,
+    GPU_CULLING_GROUP_SIZE,
+};
+pub use gpu_primitives::{
+    GpuPrimitiveSettings, GpuPrimitiveShaders, GpuPrimitives, RADIX_BITS, RADIX_BUCKETS,
+};
+pub use handle::{Handle, Pool};
+pub use hdr::{
+    Hdr, HdrTarget, ToneMapOperator, ToneMappingPushConstants, ToneMappingSettings,
+    ToneMappingShaders, HDR_FORMAT,
+};
+pub use hdr_output::{
+    CalibrationPattern, CalibrationPushConstants, DisplayLuminance, HdrCalibration,
+    HdrCalibrationShaders, HdrOutputInfo, LuminanceProbe, LuminanceProbePushConstants,
+    LuminanceProbeResult, LuminanceProbeShaders, OutputEncoding, LUMINANCE_PROBE_GROUP_SIZE,
+};
+pub use hi_z::{HiZ, HiZPyramid, HiZShaders, HI_Z_FORMAT, HI_Z_GROUP_SIZE};
+pub use hot_reload::{ReloadablePipeline, ReloadablePipelineId, ShaderHotReload, ShaderReload};
+pub use ibl::{
+    IblImage, IblShaders, ImageBasedLighting, PrefilterPushConstants, BRDF_LUT_RESOLUTION,
+    IRRADIANCE_RESOLUTION, PREFILTERED_MIP_LEVELS, PREFILTERED_RESOLUTION,
+};
+pub use image_processing::{
+    DownsampleFilter, DualFilterPass, ImageProcessing, ImageProcessingShaders, TextureLevel,
+    IMAGE_PROCESSING_GROUP_SIZE,
+};
+pub use instancing::{InstanceBuffer, InstanceTransform};
+pub use lens_flare::{
+    FlareSource, LensFlare, LensFlarePushConstants, LensFlareSettings, LensFlareShaders,
+    MAX_FLARE_SOURCES,
+};
+pub use lighting::{GpuLight, LightId, LightInstance};
+pub use lod::{
+    lod_screen_size, simplify_by_clustering, LodFade, LodLevel, LodMesh, LodSelection, LodSettings,
+};
+pub use material::{
+    AlphaMode, CelShading, Material, MaterialDesc, MaterialId, MaterialUniform, PbrPushConstants,
+    ReceiveFlags, ShadingModel, MATERIAL_BINDING_BASE_COLOR, MATERIAL_BINDING_EMISSIVE,
+    MATERIAL_BINDING_METALLIC_ROUGHNESS, MATERIAL_BINDING_NORMAL, MATERIAL_BINDING_OCCLUSION,
+    MATERIAL_BINDING_UNIFORM,
+};
+pub use memory::{
+    Allocation, AllocationDesc, AllocationKind, DedicatedResource, MemoryAllocator, MemoryStats,
+};
+pub use mesh::{Mesh, MeshId, Submesh, VertexAttribute, VertexLayout};
+pub use msaa::MsaaColorTarget;
+pub use oit::{
+    MaterialPipelines, Oit, OitShaders, OitTargets, OIT_ACCUMULATION_FORMAT, OIT_REVEALAGE_FORMAT,
+};
+pub use outline::{
+    Outline, OutlinePushConstants, OutlineSettings, OutlineShaders, OutlineTargets,
+    OUTLINE_GROUP_SIZE,
+};
+pub use particles::{
+    ParticleBlend, ParticleDrawPushConstants, ParticleEmitter, ParticleEmitterSettings,
+    ParticleShaders, ParticleSimulationPushConstants, Particles, PARTICLE_GROUP_SIZE,
+    PARTICLE_STRIDE,
+};
+pub use per_frame::PerFrame;
+pub use photometry::{
+    IesProfile, IesProfiles, PhotometricIntensity, IES_PROFILE_FORMAT, IES_PROFILE_RESOLUTION,
+};
+pub use physical_camera::{PhysicalCamera, ISO_100_GRAIN};
+pub use pipeline::{BlendMode, GraphicsPipeline, PipelineBuilder};
+pub use point_shadow::{
+    point_shadow_face_view_projections, GpuPointShadow, PointShadowMap, PointShadowSettings,
+    MAX_POINT_SHADOWS,
+};
+pub use post_process::{
+    Bloom, BloomChain, BloomSettings, BloomShaders, PostProcessSettings, BLOOM_GROUP_SIZE,
+    BLOOM_MAX_MIP_LEVELS,
+};
+pub use readback::{Readback, ReadbackId, ReadbackRing};
+pub use render_graph::{
+    format_aspect_mask, BufferAccess, GraphBuffer, GraphImage, ImageAccess, ImportedImage,
+    PassBuilder, PassContext, RenderGraph, TransientImageDesc,
+};
+pub use render_pass::{Framebuffer, RenderPass};
 pub use renderer::Renderer;
+pub use scene::{Light, LightKind, Node, NodeId, RenderMode, Scene, ScenePass, Transform};
+pub use screen_space_reflections::{
+    ScreenSpaceReflectionSettings, ScreenSpaceReflectionShaders, ScreenSpaceReflectionTargets,
+    ScreenSpaceReflectionUniform, ScreenSpaceReflections, SCREEN_SPACE_REFLECTION_GROUP_SIZE,
+};
+pub use shader::{read_spirv, ShaderId, ShaderModule, ShaderSource};
+pub use shader_library::{
+    shader_include, write_shader_library, ShaderInclude, SHADER_INCLUDES, SHADER_LIBRARY_VERSION,
+};
+pub use shadow::{
+    directional_shadow_camera, ShadowCascades, ShadowMap, ShadowSettings, ShadowUniform,
+    MAX_SHADOW_CASCADES,
+};
+pub use skinning::{
+    DeformableMesh, MorphTarget, SkinVertex, Skinning, SkinningShaders, SKINNING_GROUP_SIZE,
+};
+pub use stereo::{Eye, StereoCamera, StereoLayout};
+pub use submission::SubmitPolicy;
+pub use texture::{mip_level_count, ScreenImage, Texture, TextureId, TextureKind};
+pub use texture_feedback::{
+    TextureFeedback, TextureUsage, TextureWaste, TextureWasteReport, TEXTURE_FEEDBACK_GRID,
+    TEXTURE_FEEDBACK_REGIONS, TEXTURE_FEEDBACK_UNSAMPLED,
+};
+pub use texture_format::{
+    format_block, texture_sample_type, CompressionFamily, FormatBlock, TextureFormatSupport,
+    TextureSampleType,
+};
+pub use texture_layers::{SamplerDesc, TextureDesc, TextureViewDesc};
+pub use view_settings::{ResolvedViewSettings, ViewSettings};
@@ -0,0 +1,593 @@
+use super::buffer::write_buffer;
+use super::camera::{mat4_inverse, Mat4};
+use super::descriptor::{allocate_set, create_pool};
+use super::error::{RendererError, Result};
+use super::memory::MemoryAllocator;
+use super::renderer::create_depth_sampling_view;
+use super::texture::{create_screen_image, ScreenImage};
+use super::{
+    BufferId, ComputePipeline, DescriptorBinding, DescriptorWriter, GraphImage, ImageAccess,
+    ImportedImage, RenderGraph, Renderer, SamplerDesc, ShaderId,
+};
+use ash::{vk, Device};
+
+/// アンビエントオクルージョンの結果のフォーマット。1が遮蔽なし
+pub const AMBIENT_OCCLUSION_FORMAT: vk::Format = vk::Format::R16_SFLOAT;
+/// SSAOとブラーのシェーダーのワークグループの幅と高さ
+pub const AMBIENT_OCCLUSION_GROUP_SIZE: u32 = 8;
+/// 1画素あたりのサンプル数の上限
+pub const MAX_AMBIENT_OCCLUSION_SAMPLES: u32 = 64;
+
+/// フレームごとに渡すSSAOの設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AmbientOcclusionSettings {
+    /// サンプルを置く半球の半径。ビュー空間の単位
+    pub radius: f32,
+    /// 遮蔽の強さ。1で遮蔽されたサンプルの割合をそのまま暗くする
+    pub intensity: f32,
+    pub sample_count: u32,
+}
+
+impl Default for AmbientOcclusionSettings {
+    fn default() -> Self {
+        Self {
+            radius: 0.5,
+            intensity: 1.0,
+            sample_count: 16,
+        }
+    }
+}
+
+/// SSAOとブラーのユニフォームバッファの内容。std140と同じ配置
+///
+/// ```glsl
+/// layout(set = 1, binding = 0) uniform Params {
+///     mat4 projection;
+///     mat4 inverse_projection;
+///     float radius;
+///     float intensity;
+///     uint sample_count;
+///     uint frame; // サンプルの回転を毎フレーム変える
+/// };
+/// ```
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AmbientOcclusionUniform {
+    pub projection: Mat4,
+    pub inverse_projection: Mat4,
+    pub radius: f32,
+    pub intensity: f32,
+    pub sample_count: u32,
+    pub frame: u32,
+}
+
+/// SSAOとブラーのコンピュートシェーダー
+///
+/// どちらも8x8のワークグループで出力の1画素を1スレッドが処理し、セット0に`ssao_set_layout`、
+/// セット1に`AmbientOcclusionUniform`のセットを使う。法線は深度から復元するので、
+/// 深度のプリパスだけでよい。
+///
+/// ```glsl
+/// layout(local_size_x = 8, local_size_y = 8) in;
+/// layout(set = 0, binding = 0) uniform sampler2D depth;
+/// layout(set = 0, binding = 1) uniform sampler2D src;
+/// layout(set = 0, binding = 2, r16f) uniform writeonly image2D dst;
+/// vec3 view_position(vec2 uv) {
+///     vec4 p = inverse_projection * vec4(uv * 2.0 - 1.0, textureLod(depth, uv, 0.0).r, 1.0);
+///     return p.xyz / p.w;
+/// }
+///
+/// // ssao: 法線の向きの半球に螺旋状にサンプルを置き、深度バッファより奥にあるものを数える
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy), size = imageSize(dst);
+///     if (any(greaterThanEqual(p, size))) return;
+///     vec2 t = 1.0 / vec2(size), uv = (vec2(p) + 0.5) * t;
+///     vec3 position = view_position(uv);
+///     vec3 normal = normalize(cross(view_position(uv + vec2(0.0, t.y)) - position,
+///                                   view_position(uv + vec2(t.x, 0.0)) - position));
+///     vec3 tangent = normalize(cross(normal, abs(normal.z) < 0.999 ? vec3(0, 0, 1) : vec3(1, 0, 0)));
+///     vec3 bitangent = cross(normal, tangent);
+///     float noise = fract(52.9829189 * fract(dot(vec2(p), vec2(0.06711056, 0.00583715))) + float(frame) * 0.618034);
+///     float occlusion = 0.0;
+///     for (uint i = 0; i < sample_count; ++i) {
+///         float s = (float(i) + 0.5) / float(sample_count), r = sqrt(s);
+///         float phi = float(i) * 2.399963 + noise * 6.283185;
+///         vec3 h = vec3(r * cos(phi), r * sin(phi), sqrt(1.0 - s));
+///         vec3 sample_position = position + (tangent * h.x + bitangent * h.y + normal * h.z) * radius * mix(0.1, 1.0, s);
+///         vec4 clip = projection * vec4(sample_position, 1.0);
+///         float scene_z = view_position(clip.xy / clip.w * 0.5 + 0.5).z;
+///         float range = smoothstep(0.0, 1.0, radius / abs(position.z - scene_z));
+///         occlusion += (scene_z >= sample_position.z + 0.025 * radius ? 1.0 : 0.0) * range;
+///     }
+///     imageStore(dst, p, vec4(clamp(1.0 - intensity * occlusion / float(sample_count), 0.0, 1.0)));
+/// }
+///
+/// // blur: 深度の差で重みを下げる9タップのバイラテラルフィルタ。縦と横で2回実行する
+/// layout(push_constant) uniform Blur { ivec2 direction; };
+/// float linear_depth(ivec2 p) {
+///     vec4 v = inverse_projection * vec4(0.0, 0.0, texelFetch(depth, p, 0).r, 1.0);
+///     return v.z / v.w;
+/// }
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy), size = imageSize(dst);
+///     if (any(greaterThanEqual(p, size))) return;
+///     float center = linear_depth(p), sum = 0.0, total = 0.0;
+///     for (int i = -4; i <= 4; ++i) {
+///         ivec2 q = clamp(p + direction * i, ivec2(0), size - 1);
+///         float w = exp(-float(i * i) / 8.0) * exp(-abs(linear_depth(q) - center) / (0.1 * radius));
+///         sum += texelFetch(src, q, 0).r * w;
+///         total += w;
+///     }
+///     imageStore(dst, p, vec4(sum / total));
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AmbientOcclusionShaders {
+    pub ssao: ShaderId,
+    pub blur: ShaderId,
+}
+
+/// スワップチェインの解像度に合わせて作り直すSSAOのリソース
+pub struct AmbientOcclusionTargets {
+    /// `images[0]`にSSAOを書き、`images[1]`を経由して縦横にぼかした結果を`images[0]`に戻す
+    pub images: [ScreenImage; 2],
+    /// 深度バッファの深度のアスペクトだけのビュー
+    pub depth_view: vk::ImageView,
+    pub descriptor_pool: vk::DescriptorPool,
+    pub ssao_set: vk::DescriptorSet,
+    /// 横方向、縦方向の順
+    pub blur_sets: [vk::DescriptorSet; 2],
+    /// ライティングのパスで使う`ambient_occlusion_set_layout`のセット
+    pub lighting_set: vk::DescriptorSet,
+    pub extent: vk::Extent2D,
+}
+
+impl AmbientOcclusionTargets {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        device.destroy_descriptor_pool(self.descriptor_pool, None);
+        device.destroy_image_view(self.depth_view, None);
+        for image in self.images.iter() {
+            image.destroy(device, allocator);
+        }
+    }
+}
+
+/// `enable_ambient_occlusion`で作るSSAOのリソース
+pub struct AmbientOcclusion {
+    pub ssao_pipeline: ComputePipeline,
+    pub blur_pipeline: ComputePipeline,
+    pub sampler: vk::Sampler,
+    /// フレームコンテキストごとの`AmbientOcclusionUniform`。`Renderer`のバッファとして別に破棄される
+    pub uniform_buffers: Vec<BufferId>,
+    pub uniform_sets: Vec<vk::DescriptorSet>,
+    pub targets: AmbientOcclusionTargets,
+}
+
+impl AmbientOcclusion {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        self.ssao_pipeline.destroy(device);
+        self.blur_pipeline.destroy(device);
+        device.destroy_sampler(self.sampler, None);
+        self.targets.destroy(device, allocator);
+    }
+}
+
+impl Renderer {
+    /// ライティングのパスでSSAOの結果を読むデスクリプタセットレイアウト
+    ///
+    /// ```glsl
+    /// layout(set = 5, binding = 0) uniform sampler2D ambient_occlusion;
+    /// // 環境光とIBLの項に掛ける。フラグで受け取らない描画物には掛けない
+    /// float ao = texture(ambient_occlusion, gl_FragCoord.xy / vec2(textureSize(ambient_occlusion, 0))).r;
+    /// if (!tempura_receives(receive_flags, TEMPURA_RECEIVE_AMBIENT_OCCLUSION)) ao = 1.0;
+    /// ```
+    pub fn ambient_occlusion_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[DescriptorBinding::sampled_image(
+            0,
+            vk::ShaderStageFlags::FRAGMENT,
+        )])
+    }
+
+    /// SSAOとブラーのセット0のレイアウト。バインディング0が深度、1が入力、2が出力のストレージイメージ
+    pub fn ssao_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        let stage = vk::ShaderStageFlags::COMPUTE;
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, stage),
+            DescriptorBinding::sampled_image(1, stage),
+            DescriptorBinding::new(2, vk::DescriptorType::STORAGE_IMAGE, stage),
+        ])
+    }
+
+    /// SSAOとブラーのセット1のレイアウト。バインディング0が`AmbientOcclusionUniform`
+    pub fn ssao_params_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[DescriptorBinding::uniform_buffer(
+            0,
+            vk::ShaderStageFlags::COMPUTE,
+        )])
+    }
+
+    /// 深度バッファからスクリーンスペースのアンビエントオクルージョンを求めるパスを有効にする
+    ///
+    /// すでに有効な場合は作り直す。深度をサンプリングするのでMSAAとは併用できない。
+    pub fn enable_ambient_occlusion(&mut self, shaders: AmbientOcclusionShaders) -> Result<()> {
+        if self.msaa_samples != vk::SampleCountFlags::TYPE_1 {
+            return Err(RendererError::Validation(
+                "ambient occlusion cannot be combined with MSAA".to_owned(),
+            ));
+        }
+        let storage_supported = unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.pdevice, AMBIENT_OCCLUSION_FORMAT)
+        }
+        .optimal_tiling_features
+        .contains(vk::FormatFeatureFlags::STORAGE_IMAGE);
+        if !storage_supported {
+            return Err(RendererError::Validation(format!(
+                "ambient occlusion format {:?} cannot be used as a storage image",
+                AMBIENT_OCCLUSION_FORMAT
+            )));
+        }
+        self.disable_ambient_occlusion()?;
+        let layouts = [self.ssao_set_layout()?, self.ssao_params_set_layout()?];
+        let ssao_pipeline = self.create_compute_pipeline(shaders.ssao, "main", &layouts, 0)?;
+        let blur_pipeline = match self.create_compute_pipeline(shaders.blur, "main", &layouts, 8) {
+            Ok(pipeline) => pipeline,
+            Err(err) => {
+                self.destroy_compute_pipeline(ssao_pipeline);
+                return Err(err);
+            }
+        };
+        let resources = self
+            .create_sampler(&SamplerDesc::nearest())
+            .and_then(|sampler| {
+                let targets = unsafe { self.create_ambient_occlusion_targets(sampler) };
+                match targets {
+                    Ok(targets) => Ok((sampler, targets)),
+                    Err(err) => {
+                        unsafe { self.device.destroy_sampler(sampler, None) };
+                        Err(err)
+                    }
+                }
+            });
+        let (sampler, targets) = match resources {
+            Ok(resources) => resources,
+            Err(err) => {
+                self.destroy_compute_pipeline(ssao_pipeline);
+                self.destroy_compute_pipeline(blur_pipeline);
+                return Err(err);
+            }
+        };
+        let mut ambient_occlusion = AmbientOcclusion {
+            ssao_pipeline,
+            blur_pipeline,
+            sampler,
+            uniform_buffers: Vec::with_capacity(self.frames.len()),
+            uniform_sets: Vec::with_capacity(self.frames.len()),
+            targets,
+        };
+        for _ in 0..self.frames.len() {
+            let result = self
+                .create_buffer(
+                    std::mem::size_of::<AmbientOcclusionUniform>() as vk::DeviceSize,
+                    vk::BufferUsageFlags::UNIFORM_BUFFER,
+                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                )
+                .and_then(|id| {
+                    ambient_occlusion.uniform_buffers.push(id);
+                    self.allocate_descriptor_set(layouts[1])
+                });
+            let descriptor_set = match result {
+                Ok(set) => set,
+                Err(err) => {
+                    self.destroy_ambient_occlusion(ambient_occlusion)?;
+                    return Err(err);
+                }
+            };
+            let buffer = *ambient_occlusion.uniform_buffers.last().unwrap();
+            unsafe {
+                DescriptorWriter::new()
+                    .buffer(
+                        0,
+                        vk::DescriptorType::UNIFORM_BUFFER,
+                        self.buffers.get(buffer).unwrap(),
+                    )
+                    .update(&self.device, descriptor_set);
+            }
+            ambient_occlusion.uniform_sets.push(descriptor_set);
+        }
+        self.ambient_occlusion = Some(ambient_occlusion);
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからSSAOのリソースを破棄する
+    pub fn disable_ambient_occlusion(&mut self) -> Result<()> {
+        match self.ambient_occlusion.take() {
+            Some(ambient_occlusion) => self.destroy_ambient_occlusion(ambient_occlusion),
+            None => Ok(()),
+        }
+    }
+
+    pub fn ambient_occlusion(&self) -> Option<&AmbientOcclusion> {
+        self.ambient_occlusion.as_ref()
+    }
+
+    /// このフレームの投影行列と設定を記録中のフレームのユニフォームバッファに書き込み、
+    /// ライティングのパスで使う`ambient_occlusion_set_layout`のセットを返す
+    ///
+    /// `projection`は深度を描いたときと同じ投影行列(TAAのジッターを含む)を渡す。
+    pub fn update_ambient_occlusion(
+        &mut self,
+        projection: &Mat4,
+        settings: &AmbientOcclusionSettings,
+    ) -> Result<vk::DescriptorSet> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "ambient occlusion can only be updated between begin_frame and end_frame"
+                    .to_owned(),
+            ));
+        }
+        if settings.radius <= 0.0
+            || settings.intensity < 0.0
+            || !(1..=MAX_AMBIENT_OCCLUSION_SAMPLES).contains(&settings.sample_count)
+        {
+            return Err(RendererError::Validation(format!(
+                "ambient occlusion needs a positive radius {}, a non-negative intensity {} and \
+                 1 to {} samples, not {}",
+                settings.radius,
+                settings.intensity,
+                MAX_AMBIENT_OCCLUSION_SAMPLES,
+                settings.sample_count
+            )));
+        }
+        let inverse_projection = mat4_inverse(projection).ok_or_else(|| {
+            RendererError::Validation("projection matrix is not invertible".to_owned())
+        })?;
+        let ambient_occlusion = self.enabled_ambient_occlusion()?;
+        let uniform = AmbientOcclusionUniform {
+            projection: *projection,
+            inverse_projection,
+            radius: settings.radius,
+            intensity: settings.intensity,
+            sample_count: settings.sample_count,
+            frame: self.frame_count as u32,
+        };
+        let buffer = self
+            .buffers
+            .get(ambient_occlusion.uniform_buffers[self.current_frame])
+            .unwrap();
+        unsafe { write_buffer(buffer, &[uniform])? };
+        Ok(ambient_occlusion.targets.lighting_set)
+    }
+
+    /// `depth`からSSAOを求めて縦横にぼかすパスをグラフに追加する
+    ///
+    /// `depth`は`import_depth_image`でインポートし、深度を書き込み済みのもの。
+    /// 同じフレームで`update_ambient_occlusion`を呼んでおくこと。返すイメージを、
+    /// ライティングのパスで`ImageAccess::FRAGMENT_SHADER_READ`として宣言する。
+    pub fn add_ambient_occlusion_pass(
+        &self,
+        graph: &mut RenderGraph,
+        depth: GraphImage,
+    ) -> Result<GraphImage> {
+        let ambient_occlusion = self.enabled_ambient_occlusion()?;
+        let targets = &ambient_occlusion.targets;
+        let [occlusion, blurred] = [
+            ("ambient_occlusion", &targets.images[0]),
+            ("ambient_occlusion_blur", &targets.images[1]),
+        ]
+        .map(|(name, image)| {
+            graph.import_image(
+                name,
+                ImportedImage {
+                    image: image.image,
+                    view: image.view,
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    initial_layout: vk::ImageLayout::UNDEFINED,
+                    final_layout: None,
+                },
+            )
+        });
+        let groups = [
+            targets.extent.width.div_ceil(AMBIENT_OCCLUSION_GROUP_SIZE),
+            targets.extent.height.div_ceil(AMBIENT_OCCLUSION_GROUP_SIZE),
+            1,
+        ];
+        let uniform_set = ambient_occlusion.uniform_sets[self.current_frame];
+
+        let ssao_set = targets.ssao_set;
+        graph
+            .add_pass("ssao")
+            .image(depth, ImageAccess::COMPUTE_SHADER_DEPTH_READ)
+            .image(occlusion, ImageAccess::COMPUTE_SHADER_WRITE)
+            .execute(move |ctx, command_buffer| {
+                let renderer = ctx.renderer;
+                let pipeline = &renderer.ambient_occlusion.as_ref().unwrap().ssao_pipeline;
+                renderer
+                    .dispatch(
+                        command_buffer,
+                        pipeline,
+                        &[ssao_set, uniform_set],
+                        &[],
+                        groups,
+                    )
+                    .expect("ssao has no push constants");
+            });
+        let blurs = [
+            ("ssao_blur_x", [1i32, 0], occlusion, blurred),
+            ("ssao_blur_y", [0, 1], blurred, occlusion),
+        ];
+        for ((name, direction, src, dst), blur_set) in blurs.into_iter().zip(targets.blur_sets) {
+            graph
+                .add_pass(name)
+                .image(depth, ImageAccess::COMPUTE_SHADER_DEPTH_READ)
+                .image(src, ImageAccess::COMPUTE_SHADER_READ)
+                .image(dst, ImageAccess::COMPUTE_SHADER_WRITE)
+                .execute(move |ctx, command_buffer| {
+                    let renderer = ctx.renderer;
+                    let pipeline = &renderer.ambient_occlusion.as_ref().unwrap().blur_pipeline;
+                    let data: Vec<u8> = direction
+                        .into_iter()
+                        .flat_map(|value| value.to_ne_bytes())
+                        .collect();
+                    renderer
+                        .dispatch(
+                            command_buffer,
+                            pipeline,
+                            &[blur_set, uniform_set],
+                            &data,
+                            groups,
+                        )
+                        .expect("blur direction fits the push constants");
+                });
+        }
+        Ok(occlusion)
+    }
+
+    /// スワップチェインの解像度に合わせてSSAOのイメージを作り直す
+    ///
+    /// 古いリソースは使用中のフレームが完了してから破棄する。
+    pub(crate) fn recreate_ambient_occlusion_targets(&mut self) -> Result<()> {
+        let Some(sampler) = self.ambient_occlusion.as_ref().map(|ao| ao.sampler) else {
+            return Ok(());
+        };
+        let targets = unsafe { self.create_ambient_occlusion_targets(sampler)? };
+        let old = std::mem::replace(
+            &mut self.ambient_occlusion.as_mut().unwrap().targets,
+            targets,
+        );
+        self.destroy_deferred(move |device, allocator| unsafe { old.destroy(device, allocator) });
+        Ok(())
+    }
+
+    fn enabled_ambient_occlusion(&self) -> Result<&AmbientOcclusion> {
+        self.ambient_occlusion
+            .as_ref()
+            .ok_or_else(|| RendererError::Validation("ambient occlusion is not enabled".to_owned()))
+    }
+
+    fn destroy_ambient_occlusion(&mut self, ambient_occlusion: AmbientOcclusion) -> Result<()> {
+        for &id in ambient_occlusion.uniform_buffers.iter() {
+            self.destroy_buffer(id)?;
+        }
+        self.destroy_deferred(move |device, allocator| unsafe {
+            ambient_occlusion.destroy(device, allocator)
+        });
+        Ok(())
+    }
+
+    unsafe fn create_ambient_occlusion_targets(
+        &mut self,
+        sampler: vk::Sampler,
+    ) -> Result<AmbientOcclusionTargets> {
+        let ssao_layout = self.ssao_set_layout()?;
+        let lighting_layout = self.ambient_occlusion_set_layout()?;
+        let extent = self.surface_resolution;
+        let usage = vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED;
+        let first = create_screen_image(
+            &self.device,
+            &mut self.allocator,
+            AMBIENT_OCCLUSION_FORMAT,
+            usage,
+            extent,
+        )?;
+        let second = match create_screen_image(
+            &self.device,
+            &mut self.allocator,
+            AMBIENT_OCCLUSION_FORMAT,
+            usage,
+            extent,
+        ) {
+            Ok(image) => image,
+            Err(err) => {
+                first.destroy(&self.device, &mut self.allocator);
+                return Err(err);
+            }
+        };
+        let mut targets = AmbientOcclusionTargets {
+            images: [first, second],
+            depth_view: vk::ImageView::null(),
+            descriptor_pool: vk::DescriptorPool::null(),
+            ssao_set: vk::DescriptorSet::null(),
+            blur_sets: [vk::DescriptorSet::null(); 2],
+            lighting_set: vk::DescriptorSet::null(),
+            extent,
+        };
+        match self.init_ambient_occlusion_targets(
+            &mut targets,
+            ssao_layout,
+            lighting_layout,
+            sampler,
+        ) {
+            Ok(()) => Ok(targets),
+            Err(err) => {
+                targets.destroy(&self.device, &mut self.allocator);
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn init_ambient_occlusion_targets(
+        &self,
+        targets: &mut AmbientOcclusionTargets,
+        ssao_layout: vk::DescriptorSetLayout,
+        lighting_layout: vk::DescriptorSetLayout,
+        sampler: vk::Sampler,
+    ) -> Result<()> {
+        targets.depth_view =
+            create_depth_sampling_view(&self.device, self.depth_image, self.depth_format)?;
+        targets.descriptor_pool = create_pool(
+            &self.device,
+            4,
+            &[
+                (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 2.0),
+                (vk::DescriptorType::STORAGE_IMAGE, 1.0),
+            ],
+        )?;
+        let sampled = |image: &ScreenImage| vk::DescriptorImageInfo {
+            sampler,
+            image_view: image.view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        };
+        let storage = |image: &ScreenImage| vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: image.view,
+            image_layout: vk::ImageLayout::GENERAL,
+        };
+        let [occlusion, blurred] = &targets.images;
+
+        let ssao_set = allocate_set(&self.device, targets.descriptor_pool, ssao_layout)?;
+        DescriptorWriter::new()
+            .depth_image(0, targets.depth_view, sampler)
+            .image(2, vk::DescriptorType::STORAGE_IMAGE, storage(occlusion))
+            .update(&self.device, ssao_set);
+        let mut blur_sets = [vk::DescriptorSet::null(); 2];
+        for (set, (src, dst)) in blur_sets
+            .iter_mut()
+            .zip([(occlusion, blurred), (blurred, occlusion)])
+        {
+            *set = allocate_set(&self.device, targets.descriptor_pool, ssao_layout)?;
+            DescriptorWriter::new()
+                .depth_image(0, targets.depth_view, sampler)
+                .image(1, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, sampled(src))
+                .image(2, vk::DescriptorType::STORAGE_IMAGE, storage(dst))
+                .update(&self.device, *set);
+        }
+        let lighting_set = allocate_set(&self.device, targets.descriptor_pool, lighting_layout)?;
+        DescriptorWriter::new()
+            .image(
+                0,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                sampled(occlusion),
+            )
+            .update(&self.device, lighting_set);
+
+        targets.ssao_set = ssao_set;
+        targets.blur_sets = blur_sets;
+        targets.lighting_set = lighting_set;
+        Ok(())
+    }
+}
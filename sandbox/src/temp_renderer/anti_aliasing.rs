@@ -0,0 +1,783 @@
+use super::camera::{mat4_inverse, mat4_mul, Mat4};
+use super::descriptor::{allocate_set, create_pool};
+use super::error::{RendererError, Result};
+use super::memory::MemoryAllocator;
+use super::renderer::{create_depth_sampling_view, depth_aspect_mask};
+use super::texture::{create_screen_image, image_barrier, ScreenImage};
+use super::{
+    ComputePipeline, DescriptorBinding, DescriptorWriter, Renderer, SamplerDesc, ShaderId,
+    HDR_FORMAT,
+};
+use ash::{vk, Device};
+
+/// アンチエイリアスのシェーダーのワークグループの幅と高さ
+pub const ANTI_ALIASING_GROUP_SIZE: u32 = 8;
+/// TAAのジッターを繰り返すフレーム数
+pub const TAA_JITTER_SAMPLES: u64 = 8;
+/// モーションベクトルのフォーマット。現在のUVから前のフレームのUVを引いた値
+pub const MOTION_VECTOR_FORMAT: vk::Format = vk::Format::R16G16_SFLOAT;
+
+/// `apply_post_processing`でかけるアンチエイリアス
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AntiAliasingMode {
+    None,
+    /// 1パスで輪郭をぼかす
+    #[default]
+    Fxaa,
+    /// 投影をフレームごとにずらし、履歴と混ぜる。MSAAとは併用できない
+    Taa,
+}
+
+/// アンチエイリアスの設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AntiAliasingSettings {
+    pub mode: AntiAliasingMode,
+    /// TAAで履歴を混ぜる割合。0以上1未満
+    pub history_weight: f32,
+}
+
+impl Default for AntiAliasingSettings {
+    fn default() -> Self {
+        Self {
+            mode: AntiAliasingMode::default(),
+            history_weight: 0.9,
+        }
+    }
+}
+
+/// アンチエイリアスのコンピュートシェーダー
+///
+/// どれも8x8のワークグループで出力の1画素を1スレッドが処理し、`anti_aliasing_set_layout`の
+/// デスクリプタセットを使う。結果はレンダラーがHDRのレンダーターゲットにコピーする。
+/// FXAAはHDRのままかけるので、輝度はトーンマッピングした近似値で判定する。
+///
+/// ```glsl
+/// layout(local_size_x = 8, local_size_y = 8) in;
+/// layout(set = 0, binding = 0) uniform sampler2D color;
+/// layout(set = 0, binding = 1) uniform sampler2D depth;
+/// layout(set = 0, binding = 2) uniform sampler2D motion_vectors;
+/// layout(set = 0, binding = 3) uniform sampler2D history;
+/// layout(set = 0, binding = 4, rgba16f) uniform writeonly image2D dst; // motion_vectorsではrg16f
+///
+/// // fxaa
+/// float luma(vec3 c) { return dot(c / (1.0 + c), vec3(0.299, 0.587, 0.114)); }
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy), size = imageSize(dst);
+///     if (any(greaterThanEqual(p, size))) return;
+///     vec2 t = 1.0 / vec2(size), uv = (vec2(p) + 0.5) * t;
+///     vec3 m = texture(color, uv).rgb;
+///     float lm = luma(m);
+///     float nw = luma(texture(color, uv + vec2(-1, -1) * t).rgb);
+///     float ne = luma(texture(color, uv + vec2(1, -1) * t).rgb);
+///     float sw = luma(texture(color, uv + vec2(-1, 1) * t).rgb);
+///     float se = luma(texture(color, uv + vec2(1, 1) * t).rgb);
+///     float lo = min(lm, min(min(nw, ne), min(sw, se))), hi = max(lm, max(max(nw, ne), max(sw, se)));
+///     if (hi - lo < max(0.0312, hi * 0.125)) { imageStore(dst, p, vec4(m, 1.0)); return; }
+///     vec2 dir = vec2(-((nw + ne) - (sw + se)), (nw + sw) - (ne + se));
+///     float reduce = max((nw + ne + sw + se) * 0.03125, 1.0 / 128.0);
+///     dir = clamp(dir / (min(abs(dir.x), abs(dir.y)) + reduce), -8.0, 8.0) * t;
+///     vec3 a = 0.5 * (texture(color, uv - dir / 6.0).rgb + texture(color, uv + dir / 6.0).rgb);
+///     vec3 b = a * 0.5 + 0.25 * (texture(color, uv - dir * 0.5).rgb + texture(color, uv + dir * 0.5).rgb);
+///     float lb = luma(b);
+///     imageStore(dst, p, vec4(lb < lo || lb > hi ? a : b, 1.0));
+/// }
+///
+/// // motion_vectors: 深度から前のフレームのUVを求める。reprojectionは前のフレームの
+/// // ビュー射影行列と現在の逆行列の積
+/// layout(push_constant) uniform Params { mat4 reprojection; };
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy), size = imageSize(dst);
+///     if (any(greaterThanEqual(p, size))) return;
+///     vec2 uv = (vec2(p) + 0.5) / vec2(size);
+///     vec4 previous = reprojection * vec4(uv * 2.0 - 1.0, texelFetch(depth, p, 0).r, 1.0);
+///     imageStore(dst, p, vec4(uv - (previous.xy / previous.w * 0.5 + 0.5), 0.0, 0.0));
+/// }
+///
+/// // taa: 履歴を近傍の最小と最大でクランプしてゴーストを抑える
+/// layout(push_constant) uniform Params { float history_weight; uint reset; };
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy), size = imageSize(dst);
+///     if (any(greaterThanEqual(p, size))) return;
+///     vec3 current = texelFetch(color, p, 0).rgb;
+///     if (reset != 0) { imageStore(dst, p, vec4(current, 1.0)); return; }
+///     vec3 lo = current, hi = current;
+///     for (int y = -1; y <= 1; ++y) {
+///         for (int x = -1; x <= 1; ++x) {
+///             vec3 c = texelFetch(color, clamp(p + ivec2(x, y), ivec2(0), size - 1), 0).rgb;
+///             lo = min(lo, c);
+///             hi = max(hi, c);
+///         }
+///     }
+///     vec2 uv = (vec2(p) + 0.5) / vec2(size);
+///     vec2 previous_uv = uv - texelFetch(motion_vectors, p, 0).xy;
+///     bool inside = all(equal(previous_uv, clamp(previous_uv, 0.0, 1.0)));
+///     vec3 previous = clamp(texture(history, previous_uv).rgb, lo, hi);
+///     imageStore(dst, p, vec4(mix(current, previous, inside ? history_weight : 0.0), 1.0));
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AntiAliasingShaders {
+    pub fxaa: ShaderId,
+    pub motion_vectors: ShaderId,
+    pub taa: ShaderId,
+}
+
+/// HDRのターゲットを作り直すたびに作り直すアンチエイリアスのリソース
+///
+/// 履歴とモーションベクトルは常に`GENERAL`レイアウトで書き込む。
+pub struct AntiAliasingTargets {
+    /// TAAで交互に読み書きする履歴。FXAAは`history[0]`に出力する
+    pub history: [ScreenImage; 2],
+    pub motion_vectors: ScreenImage,
+    /// 深度バッファの深度のアスペクトだけのビュー
+    pub depth_view: vk::ImageView,
+    pub descriptor_pool: vk::DescriptorPool,
+    pub fxaa_set: vk::DescriptorSet,
+    pub motion_vector_set: vk::DescriptorSet,
+    /// `i`番目は`history[1 - i]`を読んで`history[i]`に書く
+    pub taa_sets: [vk::DescriptorSet; 2],
+    pub extent: vk::Extent2D,
+}
+
+impl AntiAliasingTargets {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        device.destroy_descriptor_pool(self.descriptor_pool, None);
+        device.destroy_image_view(self.depth_view, None);
+        self.motion_vectors.destroy(device, allocator);
+        for history in self.history.iter() {
+            history.destroy(device, allocator);
+        }
+    }
+}
+
+/// `enable_anti_aliasing`で作るアンチエイリアスのリソース
+pub struct AntiAliasing {
+    pub fxaa_pipeline: ComputePipeline,
+    pub motion_vector_pipeline: ComputePipeline,
+    pub taa_pipeline: ComputePipeline,
+    pub sampler: vk::Sampler,
+    pub targets: AntiAliasingTargets,
+    /// 次のTAAで書き込む履歴
+    pub history_index: usize,
+    /// もう一方の履歴に前のフレームの結果が入っているか
+    pub history_valid: bool,
+    /// 前のフレームのジッターなしのビュー射影行列
+    pub previous_view_projection: Mat4,
+}
+
+impl AntiAliasing {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        self.fxaa_pipeline.destroy(device);
+        self.motion_vector_pipeline.destroy(device);
+        self.taa_pipeline.destroy(device);
+        device.destroy_sampler(self.sampler, None);
+        self.targets.destroy(device, allocator);
+    }
+}
+
+/// `base`進法のファン・デル・コルプト列の`index`番目。0から1
+fn halton(mut index: u64, base: u64) -> f32 {
+    let mut result = 0.0;
+    let mut fraction = 1.0;
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+impl Renderer {
+    /// アンチエイリアスのデスクリプタセットレイアウト
+    ///
+    /// バインディング0から3が色、深度、モーションベクトル、履歴の入力、4が出力のストレージイメージ。
+    pub fn anti_aliasing_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        let stage = vk::ShaderStageFlags::COMPUTE;
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, stage),
+            DescriptorBinding::sampled_image(1, stage),
+            DescriptorBinding::sampled_image(2, stage),
+            DescriptorBinding::sampled_image(3, stage),
+            DescriptorBinding::new(4, vk::DescriptorType::STORAGE_IMAGE, stage),
+        ])
+    }
+
+    /// HDRのレンダーターゲットにかけるアンチエイリアスを有効にする。すでに有効な場合は作り直す
+    ///
+    /// `enable_hdr`の後で呼ぶ。FXAAとTAAは`set_post_process`で切り替え、履歴とモーションベクトルは
+    /// HDRのターゲットに合わせてレンダラーが作り直す。
+    pub fn enable_anti_aliasing(&mut self, shaders: AntiAliasingShaders) -> Result<()> {
+        self.enabled_hdr()?;
+        let storage_supported = unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.pdevice, MOTION_VECTOR_FORMAT)
+        }
+        .optimal_tiling_features
+        .contains(vk::FormatFeatureFlags::STORAGE_IMAGE);
+        if !storage_supported {
+            return Err(RendererError::Validation(format!(
+                "motion vector format {:?} cannot be used as a storage image",
+                MOTION_VECTOR_FORMAT
+            )));
+        }
+        let layout = self.anti_aliasing_set_layout()?;
+        let mut pipelines = Vec::with_capacity(3);
+        for (shader, push_constant_size) in [
+            (shaders.fxaa, 0),
+            (shaders.motion_vectors, 64),
+            (shaders.taa, 8),
+        ] {
+            match self.create_compute_pipeline(shader, "main", &[layout], push_constant_size) {
+                Ok(pipeline) => pipelines.push(pipeline),
+                Err(err) => {
+                    for pipeline in pipelines {
+                        self.destroy_compute_pipeline(pipeline);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        let resources = self
+            .create_sampler(&SamplerDesc::clamp_to_edge())
+            .and_then(|sampler| {
+                let targets = unsafe { self.create_anti_aliasing_targets(sampler) };
+                match targets {
+                    Ok(targets) => Ok((sampler, targets)),
+                    Err(err) => {
+                        unsafe { self.device.destroy_sampler(sampler, None) };
+                        Err(err)
+                    }
+                }
+            });
+        let (sampler, targets) = match resources {
+            Ok(resources) => resources,
+            Err(err) => {
+                for pipeline in pipelines {
+                    self.destroy_compute_pipeline(pipeline);
+                }
+                return Err(err);
+            }
+        };
+        let taa_pipeline = pipelines.pop().unwrap();
+        let motion_vector_pipeline = pipelines.pop().unwrap();
+        let fxaa_pipeline = pipelines.pop().unwrap();
+        let anti_aliasing = AntiAliasing {
+            fxaa_pipeline,
+            motion_vector_pipeline,
+            taa_pipeline,
+            sampler,
+            targets,
+            history_index: 0,
+            history_valid: false,
+            previous_view_projection: self.view_projection,
+        };
+        if let Some(old) = self.anti_aliasing.replace(anti_aliasing) {
+            self.destroy_deferred(move |device, allocator| unsafe {
+                old.destroy(device, allocator)
+            });
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからアンチエイリアスのリソースを破棄する
+    pub fn disable_anti_aliasing(&mut self) {
+        if let Some(anti_aliasing) = self.anti_aliasing.take() {
+            self.destroy_deferred(move |device, allocator| unsafe {
+                anti_aliasing.destroy(device, allocator)
+            });
+        }
+    }
+
+    /// このフレームのジッターなしのビュー射影行列を設定する
+    ///
+    /// TAAのモーションベクトルに使うので、`apply_post_processing`の前に毎フレーム呼ぶ。
+    pub fn set_view_projection(&mut self, view_projection: Mat4) {
+        self.view_projection = view_projection;
+    }
+
+    /// このフレームの投影行列に加えるNDCでのジッター
+    ///
+    /// TAAが有効な場合だけ0以外を返す。`camera::jitter_projection`で投影行列に適用する。
+    pub fn taa_jitter(&self) -> [f32; 2] {
+        let Some(anti_aliasing) = &self.anti_aliasing else {
+            return [0.0, 0.0];
+        };
+        if self.anti_aliasing_mode() != AntiAliasingMode::Taa {
+            return [0.0, 0.0];
+        }
+        let extent = anti_aliasing.targets.extent;
+        let index = self.frame_count % TAA_JITTER_SAMPLES + 1;
+        [
+            (halton(index, 2) - 0.5) * 2.0 / extent.width as f32,
+            (halton(index, 3) - 0.5) * 2.0 / extent.height as f32,
+        ]
+    }
+
+    /// TAAの履歴を捨てる。カメラが切り替わったときなどに呼ぶ
+    pub fn reset_anti_aliasing_history(&mut self) {
+        if let Some(anti_aliasing) = &mut self.anti_aliasing {
+            anti_aliasing.history_valid = false;
+        }
+    }
+
+    /// HDRのターゲットに合わせてアンチエイリアスの履歴とモーションベクトルを作り直す
+    ///
+    /// 古いリソースは使用中のフレームが完了してから破棄する。
+    pub(crate) fn recreate_anti_aliasing_targets(&mut self) -> Result<()> {
+        let Some(sampler) = self.anti_aliasing.as_ref().map(|aa| aa.sampler) else {
+            return Ok(());
+        };
+        let targets = unsafe { self.create_anti_aliasing_targets(sampler)? };
+        let anti_aliasing = self.anti_aliasing.as_mut().unwrap();
+        anti_aliasing.history_valid = false;
+        let old = std::mem::replace(&mut anti_aliasing.targets, targets);
+        self.destroy_deferred(move |device, allocator| unsafe { old.destroy(device, allocator) });
+        Ok(())
+    }
+
+    /// このフレームにかけるアンチエイリアス。`set_pass_enabled`で無効にしたものは`None`にする
+    fn anti_aliasing_mode(&self) -> AntiAliasingMode {
+        match self.view_post_process().anti_aliasing.mode {
+            AntiAliasingMode::Fxaa if !self.is_pass_enabled("fxaa") => AntiAliasingMode::None,
+            AntiAliasingMode::Taa if !self.is_pass_enabled("taa") => AntiAliasingMode::None,
+            mode => mode,
+        }
+    }
+
+    /// 設定されたアンチエイリアスを記録し、履歴を進める
+    pub(crate) fn apply_anti_aliasing(&mut self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        let Some(anti_aliasing) = &self.anti_aliasing else {
+            return Ok(());
+        };
+        let hdr_image = self.enabled_hdr()?.target.image;
+        let mode = self.anti_aliasing_mode();
+        match mode {
+            AntiAliasingMode::None => {}
+            AntiAliasingMode::Fxaa => self.record_fxaa(command_buffer, anti_aliasing, hdr_image)?,
+            AntiAliasingMode::Taa => self.record_taa(command_buffer, anti_aliasing, hdr_image)?,
+        }
+        let taa = mode == AntiAliasingMode::Taa;
+        let view_projection = self.view_projection;
+        let anti_aliasing = self.anti_aliasing.as_mut().unwrap();
+        if taa {
+            anti_aliasing.history_index = 1 - anti_aliasing.history_index;
+        }
+        // FXAAは`history[0]`を上書きするので、TAAに戻したら履歴を捨てる
+        anti_aliasing.history_valid = taa;
+        anti_aliasing.previous_view_projection = view_projection;
+        Ok(())
+    }
+
+    fn record_fxaa(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        anti_aliasing: &AntiAliasing,
+        hdr_image: vk::Image,
+    ) -> Result<()> {
+        let targets = &anti_aliasing.targets;
+        let output = targets.history[0].image;
+        unsafe {
+            self.begin_screen_pass(command_buffer, &[output], None);
+        }
+        self.dispatch(
+            command_buffer,
+            &anti_aliasing.fxaa_pipeline,
+            &[targets.fxaa_set],
+            &[],
+            self.anti_aliasing_groups(targets.extent),
+        )?;
+        unsafe { self.copy_to_hdr(command_buffer, output, hdr_image, targets.extent) };
+        Ok(())
+    }
+
+    fn record_taa(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        anti_aliasing: &AntiAliasing,
+        hdr_image: vk::Image,
+    ) -> Result<()> {
+        let targets = &anti_aliasing.targets;
+        let index = anti_aliasing.history_index;
+        let output = targets.history[index].image;
+        let mut undefined = vec![output, targets.motion_vectors.image];
+        if !anti_aliasing.history_valid {
+            undefined.push(targets.history[1 - index].image);
+        }
+        unsafe {
+            self.begin_screen_pass(command_buffer, &undefined, Some(self.depth_image));
+        }
+
+        // 前のフレームのビュー射影行列で現在の深度を投影し直す
+        let inverse = mat4_inverse(&self.view_projection).ok_or_else(|| {
+            RendererError::Validation("view projection matrix is not invertible".to_owned())
+        })?;
+        let reprojection = mat4_mul(&anti_aliasing.previous_view_projection, &inverse);
+        let data: Vec<u8> = reprojection
+            .iter()
+            .flatten()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect();
+        let groups = self.anti_aliasing_groups(targets.extent);
+        self.dispatch(
+            command_buffer,
+            &anti_aliasing.motion_vector_pipeline,
+            &[targets.motion_vector_set],
+            &data,
+            groups,
+        )?;
+        unsafe {
+            let barrier = *vk::MemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ);
+            self.restore_depth_attachment(command_buffer, &[barrier]);
+        }
+
+        let reset = !anti_aliasing.history_valid as u32;
+        let data: Vec<u8> = self
+            .view_post_process()
+            .anti_aliasing
+            .history_weight
+            .to_ne_bytes()
+            .into_iter()
+            .chain(reset.to_ne_bytes())
+            .collect();
+        self.dispatch(
+            command_buffer,
+            &anti_aliasing.taa_pipeline,
+            &[targets.taa_sets[index]],
+            &data,
+            groups,
+        )?;
+        unsafe { self.copy_to_hdr(command_buffer, output, hdr_image, targets.extent) };
+        Ok(())
+    }
+
+    /// HDRと深度の描画を待ち、`images`を前の内容を捨てて`GENERAL`にする
+    ///
+    /// `depth_image`を渡した場合はサンプリングできるレイアウトにする。
+    pub(crate) unsafe fn begin_screen_pass(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        images: &[vk::Image],
+        depth_image: Option<vk::Image>,
+    ) {
+        let range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let mut barriers: Vec<_> = images
+            .iter()
+            .map(|&image| {
+                *vk::ImageMemoryBarrier::builder()
+                    .image(image)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::GENERAL)
+                    .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .subresource_range(range)
+            })
+            .collect();
+        if let Some(depth_image) = depth_image {
+            barriers.push(
+                *vk::ImageMemoryBarrier::builder()
+                    .image(depth_image)
+                    .old_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                    .new_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+                    .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                    .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: depth_aspect_mask(self.depth_format),
+                        ..range
+                    }),
+            );
+        }
+        let memory_barrier = *vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ);
+        self.device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS
+                | vk::PipelineStageFlags::FRAGMENT_SHADER
+                | vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::DependencyFlags::empty(),
+            &[memory_barrier],
+            &[],
+            &barriers,
+        );
+    }
+
+    /// `begin_screen_pass`でサンプリングできるようにした深度を描画用のレイアウトに戻す
+    ///
+    /// 後のポストプロセスのパスが同じ前提で深度を扱えるように、深度を読むパスの最後に呼ぶ。
+    /// `memory_barriers`はコンピュートシェーダーの間の依存として同じバリアで記録する。
+    pub(crate) unsafe fn restore_depth_attachment(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        memory_barriers: &[vk::MemoryBarrier],
+    ) {
+        let barrier = *vk::ImageMemoryBarrier::builder()
+            .image(self.depth_image)
+            .old_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+            .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            .src_access_mask(vk::AccessFlags::SHADER_READ)
+            .dst_access_mask(
+                vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            )
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: depth_aspect_mask(self.depth_format),
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            });
+        self.device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::COMPUTE_SHADER
+                | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            vk::DependencyFlags::empty(),
+            memory_barriers,
+            &[],
+            &[barrier],
+        );
+    }
+
+    /// `GENERAL`の`output`をHDRのターゲットにコピーし、どちらも元のレイアウトに戻す
+    pub(crate) unsafe fn copy_to_hdr(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        output: vk::Image,
+        hdr_image: vk::Image,
+        extent: vk::Extent2D,
+    ) {
+        let barrier = |image,
+                       old,
+                       new,
+                       src_access,
+                       dst_access,
+                       src_stage: vk::PipelineStageFlags,
+                       dst_stage| {
+            image_barrier(
+                &self.device,
+                command_buffer,
+                image,
+                vk::ImageAspectFlags::COLOR,
+                0,
+                1,
+                old,
+                new,
+                src_access,
+                dst_access,
+                src_stage,
+                dst_stage,
+            )
+        };
+        barrier(
+            output,
+            vk::ImageLayout::GENERAL,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::AccessFlags::SHADER_WRITE,
+            vk::AccessFlags::TRANSFER_READ,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::TRANSFER,
+        );
+        barrier(
+            hdr_image,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::AccessFlags::empty(),
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::TRANSFER,
+        );
+        let layers = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let region = vk::ImageCopy {
+            src_subresource: layers,
+            dst_subresource: layers,
+            extent: extent.into(),
+            ..Default::default()
+        };
+        self.device.cmd_copy_image(
+            command_buffer,
+            output,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            hdr_image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[region],
+        );
+        // 出力は次のフレームのTAAで履歴として読む
+        barrier(
+            output,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::ImageLayout::GENERAL,
+            vk::AccessFlags::empty(),
+            vk::AccessFlags::SHADER_READ,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+        );
+        barrier(
+            hdr_image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::AccessFlags::SHADER_READ,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+        );
+    }
+
+    fn anti_aliasing_groups(&self, extent: vk::Extent2D) -> [u32; 3] {
+        [
+            extent.width.div_ceil(ANTI_ALIASING_GROUP_SIZE),
+            extent.height.div_ceil(ANTI_ALIASING_GROUP_SIZE),
+            1,
+        ]
+    }
+
+    /// # Safety
+    /// HDRが有効であること
+    unsafe fn create_anti_aliasing_targets(
+        &mut self,
+        sampler: vk::Sampler,
+    ) -> Result<AntiAliasingTargets> {
+        let layout = self.anti_aliasing_set_layout()?;
+        let hdr = self.enabled_hdr()?;
+        let hdr_view = hdr.target.view;
+        let extent = hdr.target.extent;
+        let history_usage = vk::ImageUsageFlags::STORAGE
+            | vk::ImageUsageFlags::SAMPLED
+            | vk::ImageUsageFlags::TRANSFER_SRC;
+        let motion_vector_usage = vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED;
+        let mut images = Vec::with_capacity(3);
+        for (format, usage) in [
+            (HDR_FORMAT, history_usage),
+            (HDR_FORMAT, history_usage),
+            (MOTION_VECTOR_FORMAT, motion_vector_usage),
+        ] {
+            match create_screen_image(&self.device, &mut self.allocator, format, usage, extent) {
+                Ok(image) => images.push(image),
+                Err(err) => {
+                    for image in images {
+                        image.destroy(&self.device, &mut self.allocator);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        let motion_vectors = images.pop().unwrap();
+        let history = [images.remove(0), images.remove(0)];
+        let mut targets = AntiAliasingTargets {
+            history,
+            motion_vectors,
+            depth_view: vk::ImageView::null(),
+            descriptor_pool: vk::DescriptorPool::null(),
+            fxaa_set: vk::DescriptorSet::null(),
+            motion_vector_set: vk::DescriptorSet::null(),
+            taa_sets: [vk::DescriptorSet::null(); 2],
+            extent,
+        };
+        match self.init_anti_aliasing_targets(&mut targets, layout, sampler, hdr_view) {
+            Ok(()) => Ok(targets),
+            Err(err) => {
+                targets.destroy(&self.device, &mut self.allocator);
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn init_anti_aliasing_targets(
+        &self,
+        targets: &mut AntiAliasingTargets,
+        layout: vk::DescriptorSetLayout,
+        sampler: vk::Sampler,
+        hdr_view: vk::ImageView,
+    ) -> Result<()> {
+        targets.depth_view =
+            create_depth_sampling_view(&self.device, self.depth_image, self.depth_format)?;
+        targets.descriptor_pool = create_pool(
+            &self.device,
+            4,
+            &[
+                (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 4.0),
+                (vk::DescriptorType::STORAGE_IMAGE, 1.0),
+            ],
+        )?;
+        let sampled =
+            |view: vk::ImageView, image_layout: vk::ImageLayout| vk::DescriptorImageInfo {
+                sampler,
+                image_view: view,
+                image_layout,
+            };
+        let storage = |view: vk::ImageView| vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: view,
+            image_layout: vk::ImageLayout::GENERAL,
+        };
+        let color = sampled(hdr_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+
+        targets.fxaa_set = allocate_set(&self.device, targets.descriptor_pool, layout)?;
+        DescriptorWriter::new()
+            .image(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, color)
+            .image(
+                4,
+                vk::DescriptorType::STORAGE_IMAGE,
+                storage(targets.history[0].view),
+            )
+            .update(&self.device, targets.fxaa_set);
+
+        targets.motion_vector_set = allocate_set(&self.device, targets.descriptor_pool, layout)?;
+        DescriptorWriter::new()
+            .depth_image(1, targets.depth_view, sampler)
+            .image(
+                4,
+                vk::DescriptorType::STORAGE_IMAGE,
+                storage(targets.motion_vectors.view),
+            )
+            .update(&self.device, targets.motion_vector_set);
+
+        for index in 0..2 {
+            let set = allocate_set(&self.device, targets.descriptor_pool, layout)?;
+            DescriptorWriter::new()
+                .image(0, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, color)
+                .image(
+                    2,
+                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    sampled(targets.motion_vectors.view, vk::ImageLayout::GENERAL),
+                )
+                .image(
+                    3,
+                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    sampled(targets.history[1 - index].view, vk::ImageLayout::GENERAL),
+                )
+                .image(
+                    4,
+                    vk::DescriptorType::STORAGE_IMAGE,
+                    storage(targets.history[index].view),
+                )
+                .update(&self.device, set);
+            targets.taa_sets[index] = set;
+        }
+        Ok(())
+    }
+}
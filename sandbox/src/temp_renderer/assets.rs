@@ -0,0 +1,938 @@
+//! ファイルからレンダラーで使えるデータを読み込むローダー
+
+use super::error::{RendererError, Result};
+use super::{MeshId, Renderer, Submesh, VertexLayout};
+use ash::vk;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// OBJから読み込んだ頂点
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ObjVertex {
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    pub uv: [f32; 2],
+}
+
+impl ObjVertex {
+    /// ロケーション0に位置、1に法線、2にUV
+    pub fn layout() -> VertexLayout {
+        VertexLayout::new(std::mem::size_of::<Self>() as u32)
+            .attribute(0, vk::Format::R32G32B32_SFLOAT, 0)
+            .attribute(1, vk::Format::R32G32B32_SFLOAT, 12)
+            .attribute(2, vk::Format::R32G32_SFLOAT, 24)
+    }
+}
+
+/// MTLのマテリアル
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjMaterial {
+    pub name: String,
+    /// `Kd`
+    pub diffuse: [f32; 3],
+    /// `map_Kd`。MTLファイルからの相対パスは解決済み
+    pub diffuse_map: Option<PathBuf>,
+}
+
+/// 同じマテリアルを使うインデックスの範囲
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjSubmesh {
+    pub range: Range<u32>,
+    /// `materials`のインデックス。`usemtl`より前の面は`None`
+    pub material: Option<usize>,
+}
+
+/// 頂点を重複なくまとめた、インデックス付きのメッシュデータ
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ObjModel {
+    pub vertices: Vec<ObjVertex>,
+    pub indices: Vec<u32>,
+    pub submeshes: Vec<ObjSubmesh>,
+    pub materials: Vec<ObjMaterial>,
+}
+
+/// Wavefront OBJと、参照しているMTLを読み込む
+///
+/// 多角形は扇状に三角形分割し、面はマテリアルごとにまとめる。法線のない頂点には、その位置を
+/// 共有する面の法線を面積で重み付けした平均を使う。UVはVulkanに合わせて上下を反転する。
+pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<ObjModel> {
+    let path = path.as_ref();
+    let source = std::fs::read_to_string(path)?;
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    let mut normals: Vec<[f32; 3]> = Vec::new();
+    let mut uvs: Vec<[f32; 2]> = Vec::new();
+    let mut materials: Vec<ObjMaterial> = Vec::new();
+    let mut current_material: Option<usize> = None;
+    // マテリアルごとの三角形の頂点(位置, UV, 法線)
+    let mut triangles: Vec<(Option<usize>, Vec<FaceVertex>)> = Vec::new();
+
+    for (line_index, line) in source.lines().enumerate() {
+        let error = |reason: &str| {
+            RendererError::InvalidAsset(format!(
+                "{}:{}: {}",
+                path.display(),
+                line_index + 1,
+                reason
+            ))
+        };
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        match keyword {
+            "v" => positions.push(parse_floats(tokens).ok_or_else(|| error("invalid vertex"))?),
+            "vn" => normals.push(parse_floats(tokens).ok_or_else(|| error("invalid normal"))?),
+            "vt" => {
+                let [u, v] = parse_floats(tokens).ok_or_else(|| error("invalid texcoord"))?;
+                uvs.push([u, 1.0 - v]);
+            }
+            "f" => {
+                let corners = tokens
+                    .map(|token| {
+                        parse_face_vertex(token, positions.len(), uvs.len(), normals.len())
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| error("invalid face"))?;
+                if corners.len() < 3 {
+                    return Err(error("face has fewer than 3 vertices"));
+                }
+                let face = match triangles
+                    .iter_mut()
+                    .find(|(material, _)| *material == current_material)
+                {
+                    Some((_, face)) => face,
+                    None => {
+                        triangles.push((current_material, Vec::new()));
+                        &mut triangles.last_mut().unwrap().1
+                    }
+                };
+                for i in 1..corners.len() - 1 {
+                    face.extend([corners[0], corners[i], corners[i + 1]]);
+                }
+            }
+            "mtllib" => {
+                let name = line["mtllib".len()..].trim();
+                materials.extend(load_mtl(&base_dir.join(name))?);
+            }
+            "usemtl" => {
+                let name = line["usemtl".len()..].trim();
+                let index = materials
+                    .iter()
+                    .position(|material| material.name == name)
+                    .ok_or_else(|| error(&format!("unknown material '{}'", name)))?;
+                current_material = Some(index);
+            }
+            // グループ、オブジェクト、スムージンググループなどは使わない
+            _ => {}
+        }
+    }
+
+    let generated_normals = smooth_normals(&positions, &triangles);
+    let mut model = ObjModel {
+        materials,
+        ..Default::default()
+    };
+    let mut unique: HashMap<FaceVertex, u32> = HashMap::new();
+    for (material, face) in triangles.iter() {
+        let start = model.indices.len() as u32;
+        for corner in face.iter() {
+            let index = *unique.entry(*corner).or_insert_with(|| {
+                model.vertices.push(ObjVertex {
+                    position: positions[corner.position],
+                    normal: corner
+                        .normal
+                        .map_or(generated_normals[corner.position], |normal| normals[normal]),
+                    uv: corner.uv.map_or([0.0, 0.0], |uv| uvs[uv]),
+                });
+                model.vertices.len() as u32 - 1
+            });
+            model.indices.push(index);
+        }
+        model.submeshes.push(ObjSubmesh {
+            range: start..model.indices.len() as u32,
+            material: *material,
+        });
+    }
+    Ok(model)
+}
+
+/// 面の頂点が参照する0始まりのインデックス
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FaceVertex {
+    position: usize,
+    uv: Option<usize>,
+    normal: Option<usize>,
+}
+
+fn parse_floats<'a, const N: usize>(tokens: impl Iterator<Item = &'a str>) -> Option<[f32; N]> {
+    let mut values = [0.0; N];
+    let mut count = 0;
+    // `v x y z w`や`vt u v w`の余分な成分は無視する
+    for (value, token) in values.iter_mut().zip(tokens) {
+        *value = token.parse().ok()?;
+        count += 1;
+    }
+    (count == N).then_some(values)
+}
+
+/// `v`、`v/vt`、`v//vn`、`v/vt/vn`を読む。負のインデックスはそれまでの要素数からの相対位置
+fn parse_face_vertex(
+    token: &str,
+    position_count: usize,
+    uv_count: usize,
+    normal_count: usize,
+) -> Option<FaceVertex> {
+    fn resolve(index: &str, count: usize) -> Option<usize> {
+        let index: i64 = index.parse().ok()?;
+        let resolved = if index < 0 {
+            count as i64 + index
+        } else {
+            index - 1
+        };
+        (0..count as i64)
+            .contains(&resolved)
+            .then_some(resolved as usize)
+    }
+
+    let mut parts = token.split('/');
+    let position = resolve(parts.next()?, position_count)?;
+    let uv = match parts.next() {
+        None | Some("") => None,
+        Some(index) => Some(resolve(index, uv_count)?),
+    };
+    let normal = match parts.next() {
+        None | Some("") => None,
+        Some(index) => Some(resolve(index, normal_count)?),
+    };
+    Some(FaceVertex {
+        position,
+        uv,
+        normal,
+    })
+}
+
+/// 位置ごとに、それを使う三角形の法線を面積で重み付けして平均する
+fn smooth_normals(
+    positions: &[[f32; 3]],
+    triangles: &[(Option<usize>, Vec<FaceVertex>)],
+) -> Vec<[f32; 3]> {
+    let mut normals = vec![[0.0f32; 3]; positions.len()];
+    for (_, face) in triangles.iter() {
+        for triangle in face.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| positions[triangle[i].position]);
+            let ab = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+            let ac = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+            // 外積の長さは三角形の面積の2倍なので、正規化しなければ面積の重みになる
+            let cross = [
+                ab[1] * ac[2] - ab[2] * ac[1],
+                ab[2] * ac[0] - ab[0] * ac[2],
+                ab[0] * ac[1] - ab[1] * ac[0],
+            ];
+            for corner in triangle.iter() {
+                let normal = &mut normals[corner.position];
+                for axis in 0..3 {
+                    normal[axis] += cross[axis];
+                }
+            }
+        }
+    }
+    for normal in normals.iter_mut() {
+        let length = (normal[0] * normal[0] + normal[1] * normal[1] + normal[2] * normal[2]).sqrt();
+        *normal = if length > f32::EPSILON {
+            normal.map(|value| value / length)
+        } else {
+            [0.0, 0.0, 1.0]
+        };
+    }
+    normals
+}
+
+/// MTLファイルの`newmtl`、`Kd`、`map_Kd`を読む
+fn load_mtl(path: &Path) -> Result<Vec<ObjMaterial>> {
+    let source = std::fs::read_to_string(path)?;
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    let mut materials: Vec<ObjMaterial> = Vec::new();
+
+    for (line_index, line) in source.lines().enumerate() {
+        let error = |reason: &str| {
+            RendererError::InvalidAsset(format!(
+                "{}:{}: {}",
+                path.display(),
+                line_index + 1,
+                reason
+            ))
+        };
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        if keyword == "newmtl" {
+            materials.push(ObjMaterial {
+                name: line["newmtl".len()..].trim().to_owned(),
+                diffuse: [1.0, 1.0, 1.0],
+                diffuse_map: None,
+            });
+            continue;
+        }
+        let Some(material) = materials.last_mut() else {
+            // 最初の`newmtl`より前の行は対象のマテリアルがない
+            continue;
+        };
+        match keyword {
+            "Kd" => {
+                material.diffuse =
+                    parse_floats(tokens).ok_or_else(|| error("invalid diffuse color"))?;
+            }
+            "map_Kd" => {
+                // `-bm 1.0`などのオプションの後の最後の項目をファイル名とする
+                let name = tokens.last().ok_or_else(|| error("missing diffuse map"))?;
+                material.diffuse_map = Some(base_dir.join(name));
+            }
+            _ => {}
+        }
+    }
+    Ok(materials)
+}
+
+/// Radiance HDR(RGBE)から読み込んだリニアのRGB画像。行は上から並ぶ
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct HdrImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[f32; 3]>,
+}
+
+impl HdrImage {
+    /// `R16G16B16A16_SFLOAT`のピクセル列に変換する。アルファは1
+    pub fn to_rgba16f(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|&[r, g, b]| [r, g, b, 1.0])
+            .flat_map(|value| f32_to_f16(value).to_ne_bytes())
+            .collect()
+    }
+}
+
+/// Radiance HDR(`.hdr`)を読み込む
+///
+/// 解像度が`-Y H +X W`のものだけに対応する。スキャンラインは新しい形式のランレングス圧縮と
+/// 非圧縮のどちらでもよい。
+pub fn load_hdr<P: AsRef<Path>>(path: P) -> Result<HdrImage> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path)?;
+    let invalid =
+        |reason: &str| RendererError::InvalidAsset(format!("{}: {}", path.display(), reason));
+
+    // ヘッダーは空行で終わり、次の行が解像度
+    let mut lines = bytes.split(|&byte| byte == b'\n');
+    let mut header_len = 0;
+    let mut format_checked = false;
+    for (index, line) in lines.by_ref().enumerate() {
+        header_len += line.len() + 1;
+        if index == 0 && !line.starts_with(b"#?") {
+            return Err(invalid("missing #? signature"));
+        }
+        if line.starts_with(b"FORMAT=") {
+            if line != b"FORMAT=32-bit_rle_rgbe" {
+                return Err(invalid("only the 32-bit_rle_rgbe format is supported"));
+            }
+            format_checked = true;
+        }
+        if line.is_empty() {
+            break;
+        }
+    }
+    let resolution = lines.next().ok_or_else(|| invalid("missing resolution"))?;
+    header_len += resolution.len() + 1;
+    let resolution = std::str::from_utf8(resolution).map_err(|_| invalid("bad resolution"))?;
+    let (height, width) = match resolution.split_whitespace().collect::<Vec<_>>()[..] {
+        ["-Y", height, "+X", width] => (
+            height.parse::<u32>().map_err(|_| invalid("bad height"))?,
+            width.parse::<u32>().map_err(|_| invalid("bad width"))?,
+        ),
+        _ => return Err(invalid("only -Y H +X W orientation is supported")),
+    };
+    if !format_checked || width == 0 || height == 0 {
+        return Err(invalid("missing FORMAT or empty image"));
+    }
+
+    let mut data = bytes.get(header_len..).unwrap_or_default();
+    // ヘッダーの解像度を信じて確保する前に、データが足りるか確かめる
+    let (width_len, height_len) = (width as usize, height as usize);
+    let enough_data = hdr_scanline_min_len(width_len)
+        .checked_mul(height_len)
+        .is_some_and(|len| len <= data.len());
+    if !enough_data {
+        return Err(invalid("truncated pixel data"));
+    }
+    let mut pixels = Vec::with_capacity(width_len * height_len);
+    let mut scanline = vec![[0u8; 4]; width_len];
+    for _ in 0..height {
+        data = read_hdr_scanline(data, &mut scanline)
+            .ok_or_else(|| invalid("truncated pixel data"))?;
+        pixels.extend(scanline.iter().map(|&rgbe| rgbe_to_rgb(rgbe)));
+    }
+    Ok(HdrImage {
+        width,
+        height,
+        pixels,
+    })
+}
+
+/// 幅`width`の1行に最低限必要なバイト数
+fn hdr_scanline_min_len(width: usize) -> usize {
+    if (8..0x8000).contains(&width) {
+        // ランレングス圧縮では、成分ごとに127ピクセルのランを2バイトで表せる
+        4 + 4 * 2 * width.div_ceil(127)
+    } else {
+        width.saturating_mul(4)
+    }
+}
+
+/// 1行を読み、残りのデータを返す
+fn read_hdr_scanline<'a>(data: &'a [u8], scanline: &mut [[u8; 4]]) -> Option<&'a [u8]> {
+    let width = scanline.len();
+    // 新しい形式のランレングス圧縮は2, 2と幅の上位、下位バイトで始まる
+    let is_rle = (8..0x8000).contains(&width)
+        && data.len() >= 4
+        && data[0] == 2
+        && data[1] == 2
+        && data[2] < 0x80
+        && ((data[2] as usize) << 8 | data[3] as usize) == width;
+    if !is_rle {
+        let (pixels, rest) = data.split_at_checked(width * 4)?;
+        for (pixel, bytes) in scanline.iter_mut().zip(pixels.chunks_exact(4)) {
+            pixel.copy_from_slice(bytes);
+        }
+        return Some(rest);
+    }
+
+    // R、G、B、Eの成分ごとに、ランと生データが交互に並ぶ
+    let mut data = &data[4..];
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let (&count, rest) = data.split_first()?;
+            if count > 128 {
+                let count = (count - 128) as usize;
+                let (&value, rest) = rest.split_first()?;
+                for pixel in scanline.get_mut(x..x + count)? {
+                    pixel[channel] = value;
+                }
+                x += count;
+                data = rest;
+            } else {
+                let count = count as usize;
+                if count == 0 {
+                    return None;
+                }
+                let (values, rest) = rest.split_at_checked(count)?;
+                for (pixel, &value) in scanline.get_mut(x..x + count)?.iter_mut().zip(values) {
+                    pixel[channel] = value;
+                }
+                x += count;
+                data = rest;
+            }
+        }
+    }
+    Some(data)
+}
+
+fn rgbe_to_rgb([r, g, b, e]: [u8; 4]) -> [f32; 3] {
+    if e == 0 {
+        return [0.0; 3];
+    }
+    // 仮数は(値 + 0.5) / 256、指数は128のバイアス付き
+    let scale = 2f32.powi(e as i32 - 136);
+    [
+        (r as f32 + 0.5) * scale,
+        (g as f32 + 0.5) * scale,
+        (b as f32 + 0.5) * scale,
+    ]
+}
+
+/// カラーグレーディングの3D LUT。赤が最も速く、青が最も遅く変わる順に並ぶ
+///
+/// 入力も出力もsRGBのガンマをかけた0から1の値。
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ColorGradingLut {
+    /// 1辺の格子点の数
+    pub size: u32,
+    pub pixels: Vec<[f32; 3]>,
+}
+
+impl ColorGradingLut {
+    /// 色を変えないLUT
+    pub fn neutral(size: u32) -> Self {
+        let max = size.saturating_sub(1).max(1) as f32;
+        let pixels = (0..size)
+            .flat_map(|b| (0..size).flat_map(move |g| (0..size).map(move |r| [r, g, b])))
+            .map(|[r, g, b]| [r as f32 / max, g as f32 / max, b as f32 / max])
+            .collect();
+        Self { size, pixels }
+    }
+
+    /// 青のスライスを横に並べたストリップ画像から作る
+    ///
+    /// `pixels`は`size * size`x`size`のRGBA8で、PNGなどをデコードしたもの。
+    /// アルファは無視する。
+    pub fn from_strip(width: u32, height: u32, pixels: &[u8]) -> Result<Self> {
+        let size = height;
+        if size < 2 || width != size * size {
+            return Err(RendererError::InvalidAsset(format!(
+                "a LUT strip must be size^2 x size pixels, got {}x{}",
+                width, height
+            )));
+        }
+        if pixels.len() != width as usize * height as usize * 4 {
+            return Err(RendererError::InvalidAsset(format!(
+                "expected {} bytes of RGBA pixels for {}x{}, got {}",
+                width as usize * height as usize * 4,
+                width,
+                height,
+                pixels.len()
+            )));
+        }
+        let mut lut = Vec::with_capacity(pixels.len() / 4);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    let offset = ((g * width + b * size + r) * 4) as usize;
+                    let texel = &pixels[offset..offset + 3];
+                    lut.push([
+                        texel[0] as f32 / 255.0,
+                        texel[1] as f32 / 255.0,
+                        texel[2] as f32 / 255.0,
+                    ]);
+                }
+            }
+        }
+        Ok(Self { size, pixels: lut })
+    }
+
+    /// `R16G16B16A16_SFLOAT`の青のスライスごとのピクセル列に変換する。アルファは1
+    pub fn to_rgba16f_slices(&self) -> Vec<Vec<u8>> {
+        let slice_len = (self.size * self.size) as usize;
+        self.pixels
+            .chunks(slice_len.max(1))
+            .map(|slice| {
+                slice
+                    .iter()
+                    .flat_map(|&[r, g, b]| [r, g, b, 1.0])
+                    .flat_map(|value| f32_to_f16(value).to_ne_bytes())
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Adobe/Resolveの`.cube`形式の3D LUTを読み込む
+///
+/// `LUT_3D_SIZE`の3D LUTだけに対応する。`DOMAIN_MIN`と`DOMAIN_MAX`は0と1であること。
+pub fn load_cube_lut<P: AsRef<Path>>(path: P) -> Result<ColorGradingLut> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)?;
+    let invalid =
+        |reason: String| RendererError::InvalidAsset(format!("{}: {}", path.display(), reason));
+
+    let mut size = None;
+    let mut pixels = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let keyword = words.next().unwrap();
+        match keyword {
+            "TITLE" | "LUT_IN_VIDEO_RANGE" | "LUT_OUT_VIDEO_RANGE" => {}
+            "LUT_1D_SIZE" => return Err(invalid("1D LUTs are not supported".to_owned())),
+            "LUT_3D_SIZE" => {
+                let value = words
+                    .next()
+                    .and_then(|word| word.parse::<u32>().ok())
+                    .filter(|value| (2..=256).contains(value))
+                    .ok_or_else(|| invalid(format!("invalid LUT_3D_SIZE on line {}", index + 1)))?;
+                size = Some(value);
+            }
+            "DOMAIN_MIN" | "DOMAIN_MAX" => {
+                let expected = if keyword == "DOMAIN_MIN" { 0.0 } else { 1.0 };
+                let values: Vec<f32> = words.filter_map(|word| word.parse().ok()).collect();
+                if values.len() != 3 || values.iter().any(|&value| value != expected) {
+                    return Err(invalid(format!(
+                        "{} must be {} {} {}",
+                        keyword, expected, expected, expected
+                    )));
+                }
+            }
+            _ => {
+                let values: Vec<f32> = line
+                    .split_whitespace()
+                    .map(|word| word.parse())
+                    .collect::<std::result::Result<_, _>>()
+                    .map_err(|_| invalid(format!("unexpected line {}: {}", index + 1, line)))?;
+                if values.len() != 3 {
+                    return Err(invalid(format!(
+                        "line {} must have 3 values, got {}",
+                        index + 1,
+                        values.len()
+                    )));
+                }
+                pixels.push([values[0], values[1], values[2]]);
+            }
+        }
+    }
+    let size = size.ok_or_else(|| invalid("missing LUT_3D_SIZE".to_owned()))?;
+    let expected = (size as usize).pow(3);
+    if pixels.len() != expected {
+        return Err(invalid(format!(
+            "expected {} entries for LUT_3D_SIZE {}, got {}",
+            expected,
+            size,
+            pixels.len()
+        )));
+    }
+    Ok(ColorGradingLut { size, pixels })
+}
+
+/// 半精度浮動小数点に丸める。範囲外は無限大、小さすぎる値は非正規化数か0になる
+pub(crate) fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        // 無限大とNaN
+        let nan = if mantissa != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let half = mantissa >> shift;
+        // 最近接偶数への丸め
+        let remainder = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        let round_up = remainder > halfway || (remainder == halfway && half & 1 == 1);
+        return sign | (half + round_up as u32) as u16;
+    }
+    let half = ((exponent as u32) << 10) | (mantissa >> 13);
+    let remainder = mantissa & 0x1fff;
+    let round_up = remainder > 0x1000 || (remainder == 0x1000 && half & 1 == 1);
+    // 繰り上がりで指数が増えても正しい値(もしくは無限大)になる
+    sign | (half + round_up as u32) as u16
+}
+
+impl Renderer {
+    /// `load_obj`で読み込んだモデルから、マテリアルごとのサブメッシュを持つメッシュを作る
+    ///
+    /// サブメッシュの順序は`model.submeshes`と同じ。
+    pub fn create_obj_mesh(&mut self, model: &ObjModel) -> Result<MeshId> {
+        let mesh = self.create_mesh(&model.vertices, Some(&model.indices), ObjVertex::layout())?;
+        let submeshes = model
+            .submeshes
+            .iter()
+            .map(|submesh| Submesh {
+                range: submesh.range.clone(),
+                vertex_offset: 0,
+            })
+            .collect();
+        let result = self.meshes.get_mut(mesh).unwrap().set_submeshes(submeshes);
+        match result {
+            Ok(()) => Ok(mesh),
+            Err(err) => {
+                self.destroy_mesh(mesh)?;
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `files`を一時ディレクトリに書き出して、最初のファイルをOBJとして読む
+    fn load(test: &str, files: &[(&str, &str)]) -> Result<ObjModel> {
+        let dir = std::env::temp_dir().join(format!("tempura_obj_{}_{}", std::process::id(), test));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, source) in files {
+            std::fs::write(dir.join(name), source).unwrap();
+        }
+        let model = load_obj(dir.join(files[0].0));
+        std::fs::remove_dir_all(&dir).unwrap();
+        model
+    }
+
+    fn positions(model: &ObjModel) -> Vec<[f32; 3]> {
+        model
+            .indices
+            .iter()
+            .map(|&index| model.vertices[index as usize].position)
+            .collect()
+    }
+
+    const QUAD: &str = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n";
+
+    #[test]
+    fn negative_indices_are_relative() {
+        let absolute = load("absolute", &[("a.obj", &format!("{}f 1 2 3 4\n", QUAD))]).unwrap();
+        let relative = load(
+            "relative",
+            &[("a.obj", &format!("{}f -4 -3 -2 -1\n", QUAD))],
+        )
+        .unwrap();
+        assert_eq!(relative, absolute);
+
+        // 負のインデックスはその行までに定義された要素を数える
+        let source = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\nv 5 5 5\n";
+        let model = load("relative_before", &[("a.obj", source)]).unwrap();
+        assert_eq!(
+            positions(&model),
+            [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]
+        );
+    }
+
+    #[test]
+    fn position_normal_faces_have_no_uv() {
+        let source = format!("{}vn 0 0 -1\nf 1//1 2//1 3//1\n", QUAD);
+        let model = load("position_normal", &[("a.obj", &source)]).unwrap();
+        assert_eq!(model.vertices.len(), 3);
+        for vertex in &model.vertices {
+            assert_eq!(vertex.normal, [0.0, 0.0, -1.0]);
+            assert_eq!(vertex.uv, [0.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn texcoords_are_flipped_and_normals_generated() {
+        let source = format!("{}vt 0.25 0.75\nf 1/1 2/1 3/1\n", QUAD);
+        let model = load("texcoord", &[("a.obj", &source)]).unwrap();
+        for vertex in &model.vertices {
+            assert_eq!(vertex.uv, [0.25, 0.25]);
+            assert_eq!(vertex.normal, [0.0, 0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn polygons_are_fan_triangulated() {
+        let source = format!("{}v -1 0.5 0\nf 1 2 3 4 5\n", QUAD);
+        let model = load("fan", &[("a.obj", &source)]).unwrap();
+        assert_eq!(model.vertices.len(), 5);
+        assert_eq!(model.indices, [0, 1, 2, 0, 2, 3, 0, 3, 4]);
+        assert_eq!(model.submeshes.len(), 1);
+        assert_eq!(model.submeshes[0].range, 0..9);
+    }
+
+    #[test]
+    fn invalid_faces_are_errors() {
+        let faces = [
+            ("too_large", "f 1 2 5\n"),
+            ("zero", "f 0 1 2\n"),
+            ("too_negative", "f -5 1 2\n"),
+            ("missing_uv", "f 1/1 2/1 3/1\n"),
+            ("missing_normal", "f 1//1 2//1 3//1\n"),
+            ("two_vertices", "f 1 2\n"),
+            ("not_a_number", "f 1 2 x\n"),
+        ];
+        for (test, face) in faces {
+            let source = format!("{}{}", QUAD, face);
+            match load(test, &[("a.obj", &source)]) {
+                // 5行目の面の誤りとして報告する
+                Err(RendererError::InvalidAsset(reason)) => assert!(reason.contains(":5: ")),
+                other => panic!("{}: {:?}", test, other),
+            }
+        }
+    }
+
+    #[test]
+    fn faces_are_grouped_by_material() {
+        let obj = format!(
+            "mtllib a.mtl\n{}f 1 2 3\nusemtl red\nf 1 2 3\nusemtl blue\nf 1 3 4\nusemtl red\nf 1 3 4\n",
+            QUAD
+        );
+        let mtl = "newmtl red\nKd 1 0 0\nmap_Kd -bm 1.0 red.png\nnewmtl blue\nKd 0 0 1\n";
+        let model = load("materials", &[("a.obj", &obj), ("a.mtl", mtl)]).unwrap();
+
+        assert_eq!(model.materials.len(), 2);
+        assert_eq!(model.materials[0].name, "red");
+        assert_eq!(model.materials[0].diffuse, [1.0, 0.0, 0.0]);
+        assert!(model.materials[0]
+            .diffuse_map
+            .as_ref()
+            .is_some_and(|path| path.ends_with("red.png")));
+        assert_eq!(model.materials[1].diffuse_map, None);
+
+        let groups: Vec<_> = model
+            .submeshes
+            .iter()
+            .map(|submesh| (submesh.material, submesh.range.clone()))
+            .collect();
+        assert_eq!(groups, [(None, 0..3), (Some(0), 3..9), (Some(1), 9..12)]);
+        // 2つ目の`usemtl red`の面は最初の赤の面の後ろにまとまる
+        let [a, b, c, d] = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        assert_eq!(positions(&model)[3..9], [a, b, c, a, c, d]);
+    }
+
+    #[test]
+    fn unknown_material_is_an_error() {
+        let source = format!("{}usemtl missing\nf 1 2 3\n", QUAD);
+        assert!(matches!(
+            load("unknown_material", &[("a.obj", &source)]),
+            Err(RendererError::InvalidAsset(_))
+        ));
+    }
+
+    /// `bytes`を一時ファイルに書き出して、HDRとして読む
+    fn load_hdr_bytes(test: &str, bytes: &[u8]) -> Result<HdrImage> {
+        let path =
+            std::env::temp_dir().join(format!("tempura_hdr_{}_{}.hdr", std::process::id(), test));
+        std::fs::write(&path, bytes).unwrap();
+        let image = load_hdr(&path);
+        std::fs::remove_file(&path).unwrap();
+        image
+    }
+
+    fn hdr_file(resolution: &str, pixels: &[u8]) -> Vec<u8> {
+        let mut bytes =
+            format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n{}\n", resolution).into_bytes();
+        bytes.extend_from_slice(pixels);
+        bytes
+    }
+
+    #[test]
+    fn reads_rle_scanline() {
+        // 幅10。Rは10のラン、Gは生データ10個、Bは3と7のラン、Eは128のラン
+        let mut data = vec![2, 2, 0, 10];
+        data.extend([128 + 10, 1]);
+        data.push(10);
+        data.extend(0..10);
+        data.extend([128 + 3, 5, 128 + 7, 6]);
+        data.extend([128 + 10, 128]);
+        data.extend([9, 9]);
+        let mut scanline = [[0u8; 4]; 10];
+        let rest = read_hdr_scanline(&data, &mut scanline).unwrap();
+        assert_eq!(rest, [9, 9]);
+        for (x, pixel) in scanline.iter().enumerate() {
+            let b = if x < 3 { 5 } else { 6 };
+            assert_eq!(*pixel, [1, x as u8, b, 128]);
+        }
+    }
+
+    #[test]
+    fn reads_flat_scanline() {
+        // 8ピクセル未満はランレングス圧縮されない
+        let data = [1, 2, 3, 4, 5, 6, 7, 8, 0];
+        let mut scanline = [[0u8; 4]; 2];
+        let rest = read_hdr_scanline(&data, &mut scanline).unwrap();
+        assert_eq!(scanline, [[1, 2, 3, 4], [5, 6, 7, 8]]);
+        assert_eq!(rest, [0]);
+    }
+
+    #[test]
+    fn rejects_broken_scanlines() {
+        let mut scanline = [[0u8; 4]; 10];
+        // ランが幅を超える
+        let overrun = [2, 2, 0, 10, 128 + 11, 1];
+        assert!(read_hdr_scanline(&overrun, &mut scanline).is_none());
+        // 長さ0の生データ
+        let empty = [2, 2, 0, 10, 0];
+        assert!(read_hdr_scanline(&empty, &mut scanline).is_none());
+        let truncated = [2, 2, 0, 10, 128 + 10];
+        assert!(read_hdr_scanline(&truncated, &mut scanline).is_none());
+        assert!(read_hdr_scanline(&[1, 2, 3], &mut [[0u8; 4]; 1]).is_none());
+    }
+
+    #[test]
+    fn converts_rgbe_to_rgb() {
+        assert_eq!(rgbe_to_rgb([255, 255, 255, 0]), [0.0; 3]);
+        // 指数128は仮数をそのまま(値 + 0.5) / 256にする
+        assert_eq!(
+            rgbe_to_rgb([128, 64, 0, 128]),
+            [128.5 / 256.0, 64.5 / 256.0, 0.5 / 256.0]
+        );
+        assert_eq!(rgbe_to_rgb([128, 0, 0, 130])[0], 128.5 / 256.0 * 4.0);
+    }
+
+    #[test]
+    fn loads_hdr_file() {
+        let image = load_hdr_bytes(
+            "small",
+            &hdr_file("-Y 2 +X 1", &[128, 0, 0, 129, 0, 128, 0, 129]),
+        )
+        .unwrap();
+        assert_eq!((image.width, image.height), (1, 2));
+        assert_eq!(
+            image.pixels,
+            [
+                [257.0 / 256.0, 1.0 / 256.0, 1.0 / 256.0],
+                [1.0 / 256.0, 257.0 / 256.0, 1.0 / 256.0]
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_resolution_larger_than_data() {
+        // 巨大な解像度でも確保する前にエラーになる
+        for resolution in [
+            "-Y 4294967295 +X 4294967295",
+            "-Y 1 +X 4294967295",
+            "-Y 100000 +X 100",
+        ] {
+            assert!(matches!(
+                load_hdr_bytes("huge", &hdr_file(resolution, &[0; 64])),
+                Err(RendererError::InvalidAsset(_))
+            ));
+        }
+        assert!(load_hdr_bytes("flipped", &hdr_file("+Y 1 +X 1", &[0; 4])).is_err());
+    }
+
+    #[test]
+    fn f16_rounds_to_nearest_even() {
+        assert_eq!(f32_to_f16(0.0), 0x0000);
+        assert_eq!(f32_to_f16(-0.0), 0x8000);
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(-2.0), 0xc000);
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        // 1 + 2^-11はちょうど中間なので偶数の1に、1 + 3 * 2^-11は1 + 2^-9に丸める
+        assert_eq!(f32_to_f16(1.0 + 2f32.powi(-11)), 0x3c00);
+        assert_eq!(f32_to_f16(1.0 + 3.0 * 2f32.powi(-11)), 0x3c02);
+        assert_eq!(f32_to_f16(1.0 + 2f32.powi(-11) + 2f32.powi(-20)), 0x3c01);
+        // 繰り上がりで無限大になる
+        assert_eq!(f32_to_f16(65520.0), 0x7c00);
+        assert_eq!(f32_to_f16(1e10), 0x7c00);
+        assert_eq!(f32_to_f16(f32::NEG_INFINITY), 0xfc00);
+        assert_eq!(f32_to_f16(f32::NAN) & 0x7e00, 0x7e00);
+    }
+
+    #[test]
+    fn f16_handles_subnormals() {
+        // 最小の非正規化数は2^-24
+        assert_eq!(f32_to_f16(2f32.powi(-24)), 0x0001);
+        assert_eq!(f32_to_f16(2f32.powi(-14) - 2f32.powi(-24)), 0x03ff);
+        assert_eq!(f32_to_f16(2f32.powi(-14)), 0x0400);
+        // 2^-25はちょうど中間なので0に、それより大きければ最小の非正規化数に丸める
+        assert_eq!(f32_to_f16(2f32.powi(-25)), 0x0000);
+        assert_eq!(f32_to_f16(2f32.powi(-25) * 1.5), 0x0001);
+        assert_eq!(f32_to_f16(3.0 * 2f32.powi(-25)), 0x0002);
+        assert_eq!(f32_to_f16(-1e-10), 0x8000);
+    }
+}
@@ -0,0 +1,404 @@
+use super::error::{RendererError, Result};
+use super::{
+    BufferAccess, BufferId, ComputePipeline, DescriptorBinding, DescriptorWriter, Renderer,
+    ShaderId,
+};
+use ash::{vk, Device};
+
+/// 輝度ヒストグラムのビンの数。ビン0は暗すぎて平均から除く画素
+pub const LUMINANCE_HISTOGRAM_BINS: u32 = 256;
+/// ヒストグラムのシェーダーのワークグループの幅と高さ
+pub const LUMINANCE_HISTOGRAM_GROUP_SIZE: u32 = 16;
+
+/// 自動露出のコンピュートシェーダー
+///
+/// デスクリプタセットは`auto_exposure_set_layout`で、バインディング0がHDRの
+/// レンダーターゲット、1がヒストグラム、2がトーンマッピングと共有する露出バッファ。
+///
+/// ```glsl
+/// // histogram: 16x16のワークグループで対数輝度のヒストグラムを作る
+/// layout(local_size_x = 16, local_size_y = 16) in;
+/// layout(set = 0, binding = 0) uniform sampler2D hdr;
+/// layout(set = 0, binding = 1) buffer Histogram { uint bins[256]; };
+/// layout(push_constant) uniform Params { float min_log_luminance; float inverse_log_luminance_range; };
+/// shared uint local_bins[256];
+/// void main() {
+///     local_bins[gl_LocalInvocationIndex] = 0;
+///     barrier();
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy);
+///     if (all(lessThan(p, textureSize(hdr, 0)))) {
+///         float l = dot(texelFetch(hdr, p, 0).rgb, vec3(0.2126, 0.7152, 0.0722));
+///         uint bin = l < 1e-5 ? 0 : uint(clamp((log2(l) - min_log_luminance) * inverse_log_luminance_range, 0.0, 1.0) * 254.0 + 1.0);
+///         atomicAdd(local_bins[bin], 1);
+///     }
+///     barrier();
+///     atomicAdd(bins[gl_LocalInvocationIndex], local_bins[gl_LocalInvocationIndex]);
+/// }
+///
+/// // average: 256のワークグループ1つで平均輝度を求めて順応させ、ヒストグラムを消す
+/// layout(local_size_x = 256) in;
+/// layout(set = 0, binding = 1) buffer Histogram { uint bins[256]; };
+/// layout(set = 0, binding = 2) buffer Exposure { float average_luminance; float exposure; };
+/// layout(push_constant) uniform Params {
+///     float min_log_luminance; float log_luminance_range; float time_coefficient;
+///     uint pixel_count; float exposure_compensation;
+/// };
+/// shared float weighted[256];
+/// void main() {
+///     uint i = gl_LocalInvocationIndex;
+///     uint count = bins[i];
+///     weighted[i] = float(count) * float(i);
+///     bins[i] = 0;
+///     barrier();
+///     for (uint stride = 128; stride > 0; stride >>= 1) {
+///         if (i < stride) weighted[i] += weighted[i + stride];
+///         barrier();
+///     }
+///     if (i == 0) {
+///         float valid = max(float(pixel_count) - float(count), 1.0);
+///         float log_average = (weighted[0] / valid - 1.0) / 254.0 * log_luminance_range + min_log_luminance;
+///         average_luminance += (exp2(log_average) - average_luminance) * time_coefficient;
+///         // EV100の露出。飽和する輝度は9.6 * 平均輝度
+///         exposure = exp2(exposure_compensation) / (9.6 * average_luminance);
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AutoExposureShaders {
+    pub histogram: ShaderId,
+    pub average: ShaderId,
+}
+
+/// 自動露出の設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoExposureSettings {
+    /// 露出を決める平均輝度の下限(EV100)。これより暗いシーンは明るくしすぎない
+    pub min_ev: f32,
+    /// 露出を決める平均輝度の上限(EV100)
+    pub max_ev: f32,
+    /// 順応の速さ。大きいほど明るさの変化に早く追従する
+    pub adaptation_speed: f32,
+    /// 計算した露出に掛ける補正(EV)
+    pub exposure_compensation: f32,
+}
+
+impl Default for AutoExposureSettings {
+    fn default() -> Self {
+        Self {
+            min_ev: -8.0,
+            max_ev: 8.0,
+            adaptation_speed: 1.5,
+            exposure_compensation: 0.0,
+        }
+    }
+}
+
+impl AutoExposureSettings {
+    /// ヒストグラムの最小の対数輝度。EV100の輝度は`2^EV * 0.125`
+    fn min_log_luminance(&self) -> f32 {
+        self.min_ev - 3.0
+    }
+
+    fn log_luminance_range(&self) -> f32 {
+        self.max_ev - self.min_ev
+    }
+}
+
+/// ヒストグラムのシェーダーに渡すプッシュ定数
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LuminanceHistogramPushConstants {
+    pub min_log_luminance: f32,
+    pub inverse_log_luminance_range: f32,
+}
+
+/// 平均のシェーダーに渡すプッシュ定数
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExposureAveragePushConstants {
+    pub min_log_luminance: f32,
+    pub log_luminance_range: f32,
+    /// 今回のフレームで目標の輝度に近づける割合
+    pub time_coefficient: f32,
+    pub pixel_count: u32,
+    pub exposure_compensation: f32,
+}
+
+/// `enable_auto_exposure`で作る自動露出のリソース
+pub struct AutoExposure {
+    pub settings: AutoExposureSettings,
+    /// `LUMINANCE_HISTOGRAM_BINS`個の`u32`。`Renderer`のバッファとして別に破棄される
+    pub histogram_buffer: BufferId,
+    /// `auto_exposure_set_layout`のデスクリプタセット。HDRのターゲットが変わったら書き直す
+    pub descriptor_set: vk::DescriptorSet,
+    pub histogram_pipeline: ComputePipeline,
+    pub average_pipeline: ComputePipeline,
+}
+
+impl AutoExposure {
+    /// # Safety
+    /// `device`は作成に使ったデバイスで、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device) {
+        self.histogram_pipeline.destroy(device);
+        self.average_pipeline.destroy(device);
+    }
+}
+
+impl Renderer {
+    /// 自動露出のデスクリプタセットレイアウト
+    pub fn auto_exposure_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, vk::ShaderStageFlags::COMPUTE),
+            DescriptorBinding::storage_buffer(1, vk::ShaderStageFlags::COMPUTE),
+            DescriptorBinding::storage_buffer(2, vk::ShaderStageFlags::COMPUTE),
+        ])
+    }
+
+    /// HDRのレンダーターゲットの輝度から露出を決める自動露出を有効にする
+    ///
+    /// `enable_hdr`の後で呼ぶ。毎フレーム`end_hdr_rendering`と`tone_map`の間で
+    /// `update_auto_exposure`を呼ぶと、トーンマッピングの露出に反映される。
+    pub fn enable_auto_exposure(
+        &mut self,
+        shaders: AutoExposureShaders,
+        settings: AutoExposureSettings,
+    ) -> Result<()> {
+        self.enabled_hdr()?;
+        validate_settings(&settings)?;
+        let layout = self.auto_exposure_set_layout()?;
+        let histogram_pipeline = self.create_compute_pipeline(
+            shaders.histogram,
+            "main",
+            &[layout],
+            std::mem::size_of::<LuminanceHistogramPushConstants>() as u32,
+        )?;
+        let average_pipeline = match self.create_compute_pipeline(
+            shaders.average,
+            "main",
+            &[layout],
+            std::mem::size_of::<ExposureAveragePushConstants>() as u32,
+        ) {
+            Ok(pipeline) => pipeline,
+            Err(err) => {
+                self.destroy_compute_pipeline(histogram_pipeline);
+                return Err(err);
+            }
+        };
+        let resources = self
+            .allocate_descriptor_set(layout)
+            .and_then(|descriptor_set| {
+                let histogram_buffer = self.create_buffer_with_data(
+                    &[0u32; LUMINANCE_HISTOGRAM_BINS as usize],
+                    vk::BufferUsageFlags::STORAGE_BUFFER,
+                )?;
+                Ok((descriptor_set, histogram_buffer))
+            });
+        let (descriptor_set, histogram_buffer) = match resources {
+            Ok(resources) => resources,
+            Err(err) => {
+                self.destroy_compute_pipeline(histogram_pipeline);
+                self.destroy_compute_pipeline(average_pipeline);
+                return Err(err);
+            }
+        };
+        let auto_exposure = AutoExposure {
+            settings,
+            histogram_buffer,
+            descriptor_set,
+            histogram_pipeline,
+            average_pipeline,
+        };
+        if let Some(old) = self.auto_exposure.replace(auto_exposure) {
+            self.destroy_auto_exposure(old)?;
+        }
+        self.rewrite_auto_exposure_set();
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してから自動露出のリソースを破棄する
+    ///
+    /// トーンマッピングは`ToneMappingSettings::exposure`だけを使うように戻る。
+    pub fn disable_auto_exposure(&mut self) -> Result<()> {
+        match self.auto_exposure.take() {
+            Some(auto_exposure) => self.destroy_auto_exposure(auto_exposure),
+            None => Ok(()),
+        }
+    }
+
+    pub fn auto_exposure(&self) -> Option<&AutoExposure> {
+        self.auto_exposure.as_ref()
+    }
+
+    /// 自動露出の設定を変更する。次の`update_auto_exposure`から反映される
+    pub fn set_auto_exposure(&mut self, settings: AutoExposureSettings) -> Result<()> {
+        validate_settings(&settings)?;
+        let auto_exposure = self
+            .auto_exposure
+            .as_mut()
+            .ok_or_else(|| RendererError::Validation("auto exposure is not enabled".to_owned()))?;
+        auto_exposure.settings = settings;
+        Ok(())
+    }
+
+    /// HDRのレンダーターゲットの輝度ヒストグラムを作り、露出を`delta_time`秒分だけ順応させる
+    ///
+    /// `end_hdr_rendering`の後、`begin_swapchain_rendering`の前にレンダーパスの外で呼ぶ。
+    pub fn update_auto_exposure(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        delta_time: f32,
+    ) -> Result<()> {
+        let hdr = self.enabled_hdr()?;
+        let auto_exposure = self
+            .auto_exposure
+            .as_ref()
+            .ok_or_else(|| RendererError::Validation("auto exposure is not enabled".to_owned()))?;
+        // 無効にしている間は露出を固定する
+        if !self.is_pass_enabled("auto_exposure") {
+            return Ok(());
+        }
+        let settings = &auto_exposure.settings;
+        let extent = hdr.target.extent;
+
+        // HDRの描画と、前のフレームの平均のシェーダーによるヒストグラムの消去を待つ
+        let barrier = *vk::MemoryBarrier::builder()
+            .src_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::SHADER_WRITE,
+            )
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            );
+        }
+
+        let histogram = LuminanceHistogramPushConstants {
+            min_log_luminance: settings.min_log_luminance(),
+            inverse_log_luminance_range: 1.0 / settings.log_luminance_range(),
+        };
+        let data: Vec<u8> = histogram
+            .min_log_luminance
+            .to_ne_bytes()
+            .into_iter()
+            .chain(histogram.inverse_log_luminance_range.to_ne_bytes())
+            .collect();
+        self.dispatch(
+            command_buffer,
+            &auto_exposure.histogram_pipeline,
+            &[auto_exposure.descriptor_set],
+            &data,
+            [
+                extent.width.div_ceil(LUMINANCE_HISTOGRAM_GROUP_SIZE),
+                extent.height.div_ceil(LUMINANCE_HISTOGRAM_GROUP_SIZE),
+                1,
+            ],
+        )?;
+        // 平均のシェーダーはヒストグラムを読んでから消す
+        self.buffer_barrier(
+            command_buffer,
+            auto_exposure.histogram_buffer,
+            BufferAccess::COMPUTE_SHADER_WRITE,
+            BufferAccess {
+                stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+                access: vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            },
+        )?;
+
+        let average = ExposureAveragePushConstants {
+            min_log_luminance: settings.min_log_luminance(),
+            log_luminance_range: settings.log_luminance_range(),
+            time_coefficient: (1.0 - (-delta_time * settings.adaptation_speed).exp())
+                .clamp(0.0, 1.0),
+            pixel_count: extent.width * extent.height,
+            exposure_compensation: settings.exposure_compensation,
+        };
+        let data: Vec<u8> = average
+            .min_log_luminance
+            .to_ne_bytes()
+            .into_iter()
+            .chain(average.log_luminance_range.to_ne_bytes())
+            .chain(average.time_coefficient.to_ne_bytes())
+            .chain(average.pixel_count.to_ne_bytes())
+            .chain(average.exposure_compensation.to_ne_bytes())
+            .collect();
+        self.dispatch(
+            command_buffer,
+            &auto_exposure.average_pipeline,
+            &[auto_exposure.descriptor_set],
+            &data,
+            [1, 1, 1],
+        )?;
+        self.buffer_barrier(
+            command_buffer,
+            hdr.exposure_buffer,
+            BufferAccess::COMPUTE_SHADER_WRITE,
+            BufferAccess::FRAGMENT_SHADER_READ,
+        )
+    }
+
+    /// `enable_hdr`で作り直したHDRのリソースを使うよう、新しいデスクリプタセットに切り替える
+    ///
+    /// 古いセットは使用中のフレームが参照しているので書き換えない。
+    pub(crate) fn refresh_auto_exposure(&mut self) -> Result<()> {
+        if self.auto_exposure.is_none() {
+            return Ok(());
+        }
+        let layout = self.auto_exposure_set_layout()?;
+        let descriptor_set = self.allocate_descriptor_set(layout)?;
+        self.auto_exposure.as_mut().unwrap().descriptor_set = descriptor_set;
+        self.rewrite_auto_exposure_set();
+        Ok(())
+    }
+
+    /// 自動露出のデスクリプタセットを現在のHDRのリソースで書き直す
+    pub(crate) fn rewrite_auto_exposure_set(&self) {
+        let (Some(auto_exposure), Some(hdr)) = (&self.auto_exposure, &self.hdr) else {
+            return;
+        };
+        let (Some(histogram_buffer), Some(exposure_buffer)) = (
+            self.buffers.get(auto_exposure.histogram_buffer),
+            self.buffers.get(hdr.exposure_buffer),
+        ) else {
+            return;
+        };
+        unsafe {
+            DescriptorWriter::new()
+                .image(
+                    0,
+                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    vk::DescriptorImageInfo {
+                        sampler: hdr.sampler,
+                        image_view: hdr.target.view,
+                        image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    },
+                )
+                .buffer(1, vk::DescriptorType::STORAGE_BUFFER, histogram_buffer)
+                .buffer(2, vk::DescriptorType::STORAGE_BUFFER, exposure_buffer)
+                .update(&self.device, auto_exposure.descriptor_set);
+        }
+    }
+
+    fn destroy_auto_exposure(&mut self, auto_exposure: AutoExposure) -> Result<()> {
+        let histogram_buffer = auto_exposure.histogram_buffer;
+        self.destroy_deferred(move |device, _| unsafe { auto_exposure.destroy(device) });
+        self.destroy_buffer(histogram_buffer)
+    }
+}
+
+fn validate_settings(settings: &AutoExposureSettings) -> Result<()> {
+    if settings.min_ev >= settings.max_ev || settings.adaptation_speed < 0.0 {
+        return Err(RendererError::Validation(format!(
+            "auto exposure needs min_ev {} < max_ev {} and a non-negative adaptation speed",
+            settings.min_ev, settings.max_ev
+        )));
+    }
+    Ok(())
+}
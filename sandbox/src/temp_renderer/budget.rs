@@ -0,0 +1,428 @@
+use super::error::{RendererError, Result};
+use super::Renderer;
+use ash::vk::PhysicalDevice;
+use ash::{vk, Device, Instance};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// 1フレームで計測できるスコープの最大数
+pub const MAX_BUDGET_SCOPES: u32 = 64;
+
+/// サブシステムに割り当てる1フレームあたりの時間(ミリ秒)。`None`なら制限しない
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SystemBudget {
+    pub cpu_ms: Option<f32>,
+    pub gpu_ms: Option<f32>,
+}
+
+impl SystemBudget {
+    pub fn new(cpu_ms: f32, gpu_ms: f32) -> Self {
+        Self {
+            cpu_ms: Some(cpu_ms),
+            gpu_ms: Some(gpu_ms),
+        }
+    }
+}
+
+/// 完了したフレームでの、サブシステム1つの実測値と予算
+#[derive(Debug, Clone, PartialEq)]
+pub struct BudgetUsage {
+    pub name: String,
+    pub budget: SystemBudget,
+    /// スコープの間にCPUで経過した時間
+    pub cpu_ms: f32,
+    /// スコープの間に記録したコマンドのGPU時間。タイムスタンプ非対応なら`None`
+    pub gpu_ms: Option<f32>,
+}
+
+impl BudgetUsage {
+    pub fn cpu_over_budget(&self) -> bool {
+        self.budget
+            .cpu_ms
+            .is_some_and(|budget| self.cpu_ms > budget)
+    }
+
+    pub fn gpu_over_budget(&self) -> bool {
+        matches!((self.gpu_ms, self.budget.gpu_ms), (Some(actual), Some(budget)) if actual > budget)
+    }
+
+    pub fn over_budget(&self) -> bool {
+        self.cpu_over_budget() || self.gpu_over_budget()
+    }
+}
+
+/// 完了したフレームのサブシステムごとの実測値
+///
+/// `Display`で実測値/予算を1行ずつ並べ、予算超過の行に印を付ける。レンダラーには文字を
+/// 描く機能がないので、画面上のHUDは描かない。この文字列をアプリケーションのUIやログに出す。
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BudgetReport {
+    /// 計測したフレームの番号
+    pub frame: u64,
+    pub usages: Vec<BudgetUsage>,
+}
+
+impl fmt::Display for BudgetReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn column(actual: Option<f32>, budget: Option<f32>) -> String {
+            let actual = actual.map_or("-".to_owned(), |ms| format!("{:.2}", ms));
+            let budget = budget.map_or("-".to_owned(), |ms| format!("{:.2}", ms));
+            format!("{:>6}/{:<6}ms", actual, budget)
+        }
+
+        writeln!(f, "frame {}", self.frame)?;
+        for usage in self.usages.iter() {
+            writeln!(
+                f,
+                "{:<16} cpu {} gpu {}{}",
+                usage.name,
+                column(Some(usage.cpu_ms), usage.budget.cpu_ms),
+                column(usage.gpu_ms, usage.budget.gpu_ms),
+                if usage.over_budget() {
+                    "  OVER BUDGET"
+                } else {
+                    ""
+                }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub type BudgetExceededFn = Box<dyn FnMut(&BudgetUsage)>;
+
+struct Scope {
+    name: String,
+    /// 開始のタイムスタンプのクエリ。終了は次のクエリ
+    first_query: u32,
+    cpu: Duration,
+    closed: bool,
+}
+
+/// フレームコンテキスト1つ分のスコープとタイムスタンプクエリ
+struct FrameScopes {
+    query_pool: vk::QueryPool,
+    frame: u64,
+    scopes: Vec<Scope>,
+    /// 開いているスコープの`scopes`内の位置と開始時刻
+    open: Vec<(usize, Instant)>,
+}
+
+/// サブシステムごとの予算と、CPU/GPU時間の計測
+pub struct BudgetTracker {
+    budgets: Vec<(String, SystemBudget)>,
+    frames: Vec<FrameScopes>,
+    /// タイムスタンプ1単位のナノ秒。タイムスタンプ非対応なら`None`
+    timestamp_period: Option<f32>,
+    timestamp_mask: u64,
+    on_exceeded: Option<BudgetExceededFn>,
+    report: BudgetReport,
+}
+
+impl BudgetTracker {
+    /// フレームコンテキストごとにタイムスタンプのクエリプールを作る
+    ///
+    /// # Safety
+    ///
+    /// `device`は`pdevice`から作成されていること。
+    pub unsafe fn new(
+        instance: &Instance,
+        pdevice: PhysicalDevice,
+        queue_family_index: u32,
+        device: &Device,
+        frame_count: usize,
+    ) -> Result<Self> {
+        let limits = instance.get_physical_device_properties(pdevice).limits;
+        let valid_bits = instance.get_physical_device_queue_family_properties(pdevice)
+            [queue_family_index as usize]
+            .timestamp_valid_bits;
+        let timestamp_period = (limits.timestamp_compute_and_graphics == vk::TRUE
+            && valid_bits > 0)
+            .then_some(limits.timestamp_period);
+
+        let mut tracker = Self {
+            budgets: Vec::new(),
+            frames: Vec::with_capacity(frame_count),
+            timestamp_period,
+            timestamp_mask: u64::MAX.checked_shr(64 - valid_bits).unwrap_or(0),
+            on_exceeded: None,
+            report: BudgetReport::default(),
+        };
+        for _ in 0..frame_count {
+            let query_pool = if timestamp_period.is_some() {
+                let create_info = *vk::QueryPoolCreateInfo::builder()
+                    .query_type(vk::QueryType::TIMESTAMP)
+                    .query_count(MAX_BUDGET_SCOPES * 2);
+                match device.create_query_pool(&create_info, None) {
+                    Ok(query_pool) => query_pool,
+                    Err(err) => {
+                        tracker.destroy(device);
+                        return Err(err.into());
+                    }
+                }
+            } else {
+                vk::QueryPool::null()
+            };
+            tracker.frames.push(FrameScopes {
+                query_pool,
+                frame: 0,
+                scopes: Vec::new(),
+                open: Vec::new(),
+            });
+        }
+        Ok(tracker)
+    }
+
+    /// # Safety
+    ///
+    /// クエリプールをGPUが使用中でないこと。
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for frame in self.frames.drain(..) {
+            if frame.query_pool != vk::QueryPool::null() {
+                device.destroy_query_pool(frame.query_pool, None);
+            }
+        }
+    }
+
+    /// 完了したフレームの結果を集計し、予算を超えたサブシステムについてコールバックを呼ぶ
+    unsafe fn collect(&mut self, device: &Device, frame_index: usize) -> Result<()> {
+        let frame = &mut self.frames[frame_index];
+        if frame.scopes.is_empty() {
+            return Ok(());
+        }
+
+        let mut usages: Vec<BudgetUsage> = Vec::new();
+        for scope in frame.scopes.drain(..).filter(|scope| scope.closed) {
+            let gpu_ms = match self.timestamp_period {
+                Some(period) => {
+                    let mut timestamps = [0u64; 2];
+                    device.get_query_pool_results(
+                        frame.query_pool,
+                        scope.first_query,
+                        2,
+                        &mut timestamps,
+                        vk::QueryResultFlags::TYPE_64,
+                    )?;
+                    let ticks = (timestamps[1] & self.timestamp_mask)
+                        .saturating_sub(timestamps[0] & self.timestamp_mask);
+                    Some(ticks as f32 * period / 1_000_000.0)
+                }
+                None => None,
+            };
+            let cpu_ms = scope.cpu.as_secs_f32() * 1000.0;
+
+            // 1フレームに同じ名前のスコープが複数あれば合計する
+            match usages.iter_mut().find(|usage| usage.name == scope.name) {
+                Some(usage) => {
+                    usage.cpu_ms += cpu_ms;
+                    usage.gpu_ms = usage.gpu_ms.zip(gpu_ms).map(|(a, b)| a + b);
+                }
+                None => usages.push(BudgetUsage {
+                    budget: self
+                        .budgets
+                        .iter()
+                        .find(|(name, _)| *name == scope.name)
+                        .map(|(_, budget)| *budget)
+                        .unwrap_or_default(),
+                    name: scope.name,
+                    cpu_ms,
+                    gpu_ms,
+                }),
+            }
+        }
+        frame.open.clear();
+
+        if let Some(on_exceeded) = self.on_exceeded.as_mut() {
+            for usage in usages.iter().filter(|usage| usage.over_budget()) {
+                on_exceeded(usage);
+            }
+        }
+        self.report = BudgetReport {
+            frame: frame.frame,
+            usages,
+        };
+        Ok(())
+    }
+}
+
+impl Renderer {
+    /// `name`のサブシステムの予算を設定する
+    pub fn set_budget(&mut self, name: &str, budget: SystemBudget) {
+        let budgets = &mut self.budget_tracker.budgets;
+        match budgets
+            .iter_mut()
+            .find(|(budget_name, _)| budget_name == name)
+        {
+            Some((_, current)) => *current = budget,
+            None => budgets.push((name.to_owned(), budget)),
+        }
+    }
+
+    /// 予算を超えたサブシステムがあると、結果が得られたフレームの`begin_frame`で呼ばれる
+    ///
+    /// 品質を自動で下げる判断などに使う。
+    pub fn on_budget_exceeded<F: FnMut(&BudgetUsage) + 'static>(&mut self, f: F) {
+        self.budget_tracker.on_exceeded = Some(Box::new(f));
+    }
+
+    /// 最後に結果が得られたフレームの実測値
+    pub fn budget_report(&self) -> &BudgetReport {
+        &self.budget_tracker.report
+    }
+
+    /// 記録中のフレームで`name`のサブシステムの計測を始める。スコープは入れ子にできる
+    pub fn begin_budget_scope(&mut self, name: &str) -> Result<()> {
+        self.check_budget_recording()?;
+        let command_buffer = self.frames[self.current_frame].recording_command_buffer();
+        let tracker = &mut self.budget_tracker;
+        let frame = &mut tracker.frames[self.current_frame];
+        let first_query = frame.scopes.len() as u32 * 2;
+        if first_query >= MAX_BUDGET_SCOPES * 2 {
+            return Err(RendererError::Validation(format!(
+                "more than {} budget scopes in one frame",
+                MAX_BUDGET_SCOPES
+            )));
+        }
+        if tracker.timestamp_period.is_some() {
+            unsafe {
+                self.device.cmd_write_timestamp(
+                    command_buffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    frame.query_pool,
+                    first_query,
+                );
+            }
+        }
+        frame.frame = self.frame_count;
+        frame.open.push((frame.scopes.len(), Instant::now()));
+        frame.scopes.push(Scope {
+            name: name.to_owned(),
+            first_query,
+            cpu: Duration::ZERO,
+            closed: false,
+        });
+        Ok(())
+    }
+
+    /// 最も内側で開いている`name`のスコープの計測を終える
+    pub fn end_budget_scope(&mut self, name: &str) -> Result<()> {
+        self.check_budget_recording()?;
+        let command_buffer = self.frames[self.current_frame].recording_command_buffer();
+        let tracker = &mut self.budget_tracker;
+        let frame = &mut tracker.frames[self.current_frame];
+        let Some(&(index, start)) = frame.open.last() else {
+            return Err(RendererError::Validation(format!(
+                "budget scope '{}' is not open",
+                name
+            )));
+        };
+        let scope = &mut frame.scopes[index];
+        if scope.name != name {
+            return Err(RendererError::Validation(format!(
+                "budget scope '{}' must be closed before '{}'",
+                scope.name, name
+            )));
+        }
+        if tracker.timestamp_period.is_some() {
+            unsafe {
+                self.device.cmd_write_timestamp(
+                    command_buffer,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    frame.query_pool,
+                    scope.first_query + 1,
+                );
+            }
+        }
+        scope.cpu = start.elapsed();
+        scope.closed = true;
+        frame.open.pop();
+        Ok(())
+    }
+
+    fn check_budget_recording(&self) -> Result<()> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "budget scopes can only be used between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// 前回このフレームコンテキストで計測した結果を集計する。フェンスを待った後に呼ぶ
+    pub(crate) unsafe fn collect_budgets(&mut self) -> Result<()> {
+        self.budget_tracker
+            .collect(&self.device, self.current_frame)
+    }
+
+    /// 記録を開始したコマンドバッファでクエリをリセットする
+    pub(crate) unsafe fn reset_budget_queries(&self, command_buffer: vk::CommandBuffer) {
+        let frame = &self.budget_tracker.frames[self.current_frame];
+        if frame.query_pool != vk::QueryPool::null() {
+            self.device.cmd_reset_query_pool(
+                command_buffer,
+                frame.query_pool,
+                0,
+                MAX_BUDGET_SCOPES * 2,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(budget: SystemBudget, cpu_ms: f32, gpu_ms: Option<f32>) -> BudgetUsage {
+        BudgetUsage {
+            name: "shadow".to_owned(),
+            budget,
+            cpu_ms,
+            gpu_ms,
+        }
+    }
+
+    #[test]
+    fn over_budget_compares_each_limit() {
+        let budget = SystemBudget::new(1.0, 2.0);
+        assert!(!usage(budget, 1.0, Some(2.0)).over_budget());
+        let cpu = usage(budget, 1.5, Some(1.0));
+        assert!(cpu.cpu_over_budget() && !cpu.gpu_over_budget() && cpu.over_budget());
+        let gpu = usage(budget, 0.5, Some(2.5));
+        assert!(!gpu.cpu_over_budget() && gpu.gpu_over_budget() && gpu.over_budget());
+        // GPU時間が測れなければGPUの予算は判定しない
+        assert!(!usage(budget, 0.5, None).over_budget());
+        // 予算のないサブシステムは超過しない
+        assert!(!usage(SystemBudget::default(), 100.0, Some(100.0)).over_budget());
+        let cpu_only = SystemBudget {
+            cpu_ms: Some(1.0),
+            gpu_ms: None,
+        };
+        assert!(!usage(cpu_only, 0.5, Some(100.0)).over_budget());
+    }
+
+    #[test]
+    fn report_marks_over_budget_lines() {
+        let report = BudgetReport {
+            frame: 42,
+            usages: vec![
+                usage(SystemBudget::new(1.0, 2.0), 0.5, Some(1.25)),
+                BudgetUsage {
+                    name: "post_process".to_owned(),
+                    ..usage(SystemBudget::new(1.0, 2.0), 0.25, Some(3.0))
+                },
+                BudgetUsage {
+                    name: "ui".to_owned(),
+                    ..usage(SystemBudget::default(), 0.125, None)
+                },
+            ],
+        };
+        assert_eq!(
+            report.to_string(),
+            "frame 42\n\
+             shadow           cpu   0.50/1.00  ms gpu   1.25/2.00  ms\n\
+             post_process     cpu   0.25/1.00  ms gpu   3.00/2.00  ms  OVER BUDGET\n\
+             ui               cpu   0.12/-     ms gpu      -/-     ms\n"
+        );
+        assert_eq!(BudgetReport::default().to_string(), "frame 0\n");
+    }
+}
@@ -0,0 +1,181 @@
+use super::error::{RendererError, Result};
+use super::handle::Handle;
+use super::memory::{Allocation, AllocationDesc, AllocationKind, MemoryAllocator};
+use super::Renderer;
+use ash::{vk, Device};
+
+/// メモリを割り当て済みのバッファ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub buffer: vk::Buffer,
+    pub allocation: Allocation,
+    pub size: vk::DeviceSize,
+    pub usage: vk::BufferUsageFlags,
+    /// `register_external_buffer`で登録したもので、レンダラーは破棄しない
+    pub external: bool,
+}
+
+pub type BufferId = Handle<Buffer>;
+
+impl Buffer {
+    /// # Safety
+    ///
+    /// `device`と`allocator`で作成されたバッファで、GPUが使用中でないこと。
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        if self.external {
+            return;
+        }
+        device.destroy_buffer(self.buffer, None);
+        allocator.free(device, self.allocation);
+    }
+}
+
+pub(crate) unsafe fn create_buffer(
+    device: &Device,
+    allocator: &mut MemoryAllocator,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+    memory_flags: vk::MemoryPropertyFlags,
+) -> Result<Buffer> {
+    let buffer_info = *vk::BufferCreateInfo::builder()
+        .size(size)
+        .usage(usage)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+    let buffer = device.create_buffer(&buffer_info, None)?;
+
+    let desc = AllocationDesc {
+        requirements: device.get_buffer_memory_requirements(buffer),
+        memory_flags,
+        kind: AllocationKind::Linear,
+        dedicated: None,
+    };
+    let allocation = match allocator.allocate(device, &desc) {
+        Ok(allocation) => allocation,
+        Err(err) => {
+            device.destroy_buffer(buffer, None);
+            return Err(err);
+        }
+    };
+    if let Err(err) = device.bind_buffer_memory(buffer, allocation.memory, allocation.offset) {
+        device.destroy_buffer(buffer, None);
+        allocator.free(device, allocation);
+        return Err(err.into());
+    }
+
+    Ok(Buffer {
+        buffer,
+        allocation,
+        size,
+        usage,
+        external: false,
+    })
+}
+
+/// `buffer`のメモリに`data`を書き込む。メモリはHOST_VISIBLEかつHOST_COHERENTであること
+pub(crate) unsafe fn write_buffer<T: Copy>(buffer: &Buffer, data: &[T]) -> Result<()> {
+    let size = std::mem::size_of_val(data);
+    if size as vk::DeviceSize > buffer.size {
+        return Err(RendererError::Validation(format!(
+            "writing {} bytes into a buffer of {} bytes",
+            size, buffer.size
+        )));
+    }
+    let ptr = buffer
+        .allocation
+        .mapped_ptr
+        .ok_or_else(|| RendererError::Validation("buffer memory is not host visible".to_owned()))?;
+    std::ptr::copy_nonoverlapping(data.as_ptr() as *const u8, ptr, size);
+    Ok(())
+}
+
+impl Renderer {
+    /// メモリ属性を指定してバッファを作成する
+    pub fn create_buffer(
+        &mut self,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        memory_flags: vk::MemoryPropertyFlags,
+    ) -> Result<BufferId> {
+        if size == 0 {
+            return Err(RendererError::Validation(
+                "buffer size must be greater than zero".to_owned(),
+            ));
+        }
+        let buffer =
+            unsafe { create_buffer(&self.device, &mut self.allocator, size, usage, memory_flags)? };
+        Ok(self.buffers.insert(buffer))
+    }
+
+    /// `data`を転送したDEVICE_LOCALのバッファを作成する
+    ///
+    /// ステージングバッファ経由でセットアップ用コマンドバッファからコピーし、転送の完了を待って返す。
+    pub fn create_buffer_with_data<T: Copy>(
+        &mut self,
+        data: &[T],
+        usage: vk::BufferUsageFlags,
+    ) -> Result<BufferId> {
+        let size = std::mem::size_of_val(data) as vk::DeviceSize;
+        if size == 0 {
+            return Err(RendererError::Validation(
+                "buffer data must not be empty".to_owned(),
+            ));
+        }
+        unsafe {
+            let staging = create_buffer(
+                &self.device,
+                &mut self.allocator,
+                size,
+                vk::BufferUsageFlags::TRANSFER_SRC,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )?;
+            let result = self.upload_buffer(&staging, data, usage);
+            // 転送の完了を待っているので、ステージングバッファはすぐに破棄できる
+            staging.destroy(&self.device, &mut self.allocator);
+            Ok(self.buffers.insert(result?))
+        }
+    }
+
+    unsafe fn upload_buffer<T: Copy>(
+        &mut self,
+        staging: &Buffer,
+        data: &[T],
+        usage: vk::BufferUsageFlags,
+    ) -> Result<Buffer> {
+        write_buffer(staging, data)?;
+        let buffer = create_buffer(
+            &self.device,
+            &mut self.allocator,
+            staging.size,
+            usage | vk::BufferUsageFlags::TRANSFER_DST,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )?;
+        let submitted = self.submit_setup_commands(|device, command_buffer| {
+            let region = vk::BufferCopy {
+                src_offset: 0,
+                dst_offset: 0,
+                size: staging.size,
+            };
+            device.cmd_copy_buffer(command_buffer, staging.buffer, buffer.buffer, &[region]);
+        });
+        if let Err(err) = submitted {
+            buffer.destroy(&self.device, &mut self.allocator);
+            return Err(err);
+        }
+        Ok(buffer)
+    }
+
+    pub fn buffer(&self, id: BufferId) -> Option<&Buffer> {
+        self.buffers.get(id)
+    }
+
+    /// 使用中のフレームが完了してからバッファを破棄する
+    pub fn destroy_buffer(&mut self, id: BufferId) -> Result<()> {
+        let buffer = self.buffers.remove(id).ok_or_else(|| {
+            RendererError::Validation(format!("buffer {:?} was already destroyed", id))
+        })?;
+        self.destroy_deferred(move |device, allocator| unsafe {
+            buffer.destroy(device, allocator)
+        });
+        Ok(())
+    }
+}
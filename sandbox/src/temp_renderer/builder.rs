@@ -0,0 +1,229 @@
+use super::error::Result;
+use super::{DisplaySelection, RenderPath, Renderer, SubmitPolicy, MAX_FRAMES_IN_FLIGHT};
+use ash::extensions::ext::DebugUtils;
+use ash::vk;
+use raw_window_handle::HasRawWindowHandle;
+use std::ffi::{CStr, CString};
+
+/// レンダラー作成時の設定
+#[derive(Debug, Clone)]
+pub struct RendererConfig {
+    pub application_name: CString,
+    pub application_version: u32,
+    pub api_version: u32,
+    pub enabled_layers: Vec<CString>,
+    pub instance_extensions: Vec<CString>,
+    pub device_extensions: Vec<CString>,
+    pub enable_debug_utils: bool,
+    /// サポートされていない場合はFIFOになる
+    pub preferred_present_mode: vk::PresentModeKHR,
+    /// `None`もしくはサポートされていない場合はサーフェスが最初に返すフォーマットを使う
+    pub preferred_surface_format: Option<vk::SurfaceFormatKHR>,
+    /// サポートされていない場合は他の深度フォーマットにフォールバックする
+    pub depth_format: vk::Format,
+    /// 1から[`MAX_FRAMES_IN_FLIGHT`]の範囲に丸められる
+    pub frames_in_flight: usize,
+    /// `begin_frame`でシェーダーファイルの更新を監視し、変更されたものを読み直す。
+    /// 失敗は`Renderer::take_shader_reload_failures`で取り出す
+    pub shader_hot_reload: bool,
+    /// 動的レンダリング(Vulkan 1.3、もしくは1.2とVK_KHR_dynamic_rendering)を使う。
+    /// デバイスが対応していない場合はレンダーパスを使う
+    pub dynamic_rendering: bool,
+    /// VK_EXT_conditional_renderingを使う。Vulkan 1.1以降で、デバイスが対応している場合だけ有効になる
+    pub conditional_rendering: bool,
+    /// VK_KHR_draw_indirect_countで、描画の数をGPUが決める間接描画を使う。
+    /// デバイスが拡張と複数の間接描画に対応している場合だけ有効になる
+    pub draw_indirect_count: bool,
+    /// 登録できるライトの最大数。シェーダーのライト配列の長さと合わせる
+    pub max_lights: u32,
+    /// `split_submission`でフレームを分けて提出するか
+    pub submit_policy: SubmitPolicy,
+    /// スワップチェインへの描画のサンプル数。デバイスがサポートする最大のサンプル数に丸められる
+    pub msaa_samples: vk::SampleCountFlags,
+    /// 読み戻しに使うリングバッファのバイト数。最初の読み戻しで確保する
+    pub readback_ring_size: vk::DeviceSize,
+    /// フォワードかディファードか。ディファードが使えない場合はフォワードになる
+    pub render_path: RenderPath,
+    /// メインのパスの前に深度のプリパスを描く。動的レンダリングが使えない場合は無効になる
+    pub depth_prepass: bool,
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        Self {
+            application_name: CString::default(),
+            application_version: 0,
+            api_version: vk::make_api_version(0, 1, 0, 0),
+            enabled_layers: vec![c"VK_LAYER_KHRONOS_validation".to_owned()],
+            instance_extensions: Vec::new(),
+            device_extensions: Vec::new(),
+            enable_debug_utils: true,
+            preferred_present_mode: vk::PresentModeKHR::MAILBOX,
+            preferred_surface_format: None,
+            depth_format: vk::Format::D16_UNORM,
+            frames_in_flight: 2,
+            shader_hot_reload: false,
+            dynamic_rendering: false,
+            conditional_rendering: false,
+            draw_indirect_count: false,
+            max_lights: 16,
+            submit_policy: SubmitPolicy::default(),
+            msaa_samples: vk::SampleCountFlags::TYPE_1,
+            readback_ring_size: 16 * 1024 * 1024,
+            render_path: RenderPath::default(),
+            depth_prepass: false,
+        }
+    }
+}
+
+impl RendererConfig {
+    pub(crate) fn frames_in_flight_count(&self) -> usize {
+        self.frames_in_flight.clamp(1, MAX_FRAMES_IN_FLIGHT)
+    }
+
+    pub(crate) fn required_instance_extensions(&self) -> Vec<&CStr> {
+        let mut extension_names: Vec<&CStr> = self
+            .instance_extensions
+            .iter()
+            .map(|name| name.as_c_str())
+            .collect();
+        if self.enable_debug_utils && !extension_names.contains(&DebugUtils::name()) {
+            extension_names.push(DebugUtils::name());
+        }
+        extension_names
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RendererBuilder {
+    config: RendererConfig,
+}
+
+impl RendererBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn application_name(mut self, name: &CStr) -> Self {
+        self.config.application_name = name.to_owned();
+        self
+    }
+
+    pub fn application_version(mut self, version: u32) -> Self {
+        self.config.application_version = version;
+        self
+    }
+
+    pub fn api_version(mut self, version: u32) -> Self {
+        self.config.api_version = version;
+        self
+    }
+
+    /// 有効にするレイヤーを置き換える(デフォルトは`VK_LAYER_KHRONOS_validation`のみ)
+    pub fn enabled_layers(mut self, layer_names: &[&CStr]) -> Self {
+        self.config.enabled_layers = layer_names.iter().map(|&name| name.to_owned()).collect();
+        self
+    }
+
+    pub fn instance_extension(mut self, extension_name: &CStr) -> Self {
+        self.config
+            .instance_extensions
+            .push(extension_name.to_owned());
+        self
+    }
+
+    pub fn device_extension(mut self, extension_name: &CStr) -> Self {
+        self.config
+            .device_extensions
+            .push(extension_name.to_owned());
+        self
+    }
+
+    pub fn debug_utils(mut self, enable: bool) -> Self {
+        self.config.enable_debug_utils = enable;
+        self
+    }
+
+    pub fn present_mode(mut self, present_mode: vk::PresentModeKHR) -> Self {
+        self.config.preferred_present_mode = present_mode;
+        self
+    }
+
+    pub fn surface_format(mut self, surface_format: vk::SurfaceFormatKHR) -> Self {
+        self.config.preferred_surface_format = Some(surface_format);
+        self
+    }
+
+    pub fn depth_format(mut self, depth_format: vk::Format) -> Self {
+        self.config.depth_format = depth_format;
+        self
+    }
+
+    pub fn frames_in_flight(mut self, frames_in_flight: usize) -> Self {
+        self.config.frames_in_flight = frames_in_flight;
+        self
+    }
+
+    pub fn shader_hot_reload(mut self, enable: bool) -> Self {
+        self.config.shader_hot_reload = enable;
+        self
+    }
+
+    pub fn dynamic_rendering(mut self, enable: bool) -> Self {
+        self.config.dynamic_rendering = enable;
+        self
+    }
+
+    pub fn conditional_rendering(mut self, enable: bool) -> Self {
+        self.config.conditional_rendering = enable;
+        self
+    }
+
+    pub fn draw_indirect_count(mut self, enable: bool) -> Self {
+        self.config.draw_indirect_count = enable;
+        self
+    }
+
+    pub fn max_lights(mut self, max_lights: u32) -> Self {
+        self.config.max_lights = max_lights;
+        self
+    }
+
+    pub fn submit_policy(mut self, policy: SubmitPolicy) -> Self {
+        self.config.submit_policy = policy;
+        self
+    }
+
+    pub fn msaa_samples(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.config.msaa_samples = samples;
+        self
+    }
+
+    pub fn readback_ring_size(mut self, size: vk::DeviceSize) -> Self {
+        self.config.readback_ring_size = size;
+        self
+    }
+
+    pub fn render_path(mut self, render_path: RenderPath) -> Self {
+        self.config.render_path = render_path;
+        self
+    }
+
+    pub fn depth_prepass(mut self, enabled: bool) -> Self {
+        self.config.depth_prepass = enabled;
+        self
+    }
+
+    pub fn config(&self) -> &RendererConfig {
+        &self.config
+    }
+
+    pub fn build(self, window_handle: &dyn HasRawWindowHandle) -> Result<Renderer> {
+        Renderer::with_config(window_handle, self.config)
+    }
+
+    /// ウィンドウを使わず、VK_KHR_displayでディスプレイに直接出力するレンダラーを作成する
+    pub fn build_for_display(self, selection: DisplaySelection) -> Result<Renderer> {
+        Renderer::with_display(selection, self.config)
+    }
+}
@@ -0,0 +1,456 @@
+use ash::vk;
+
+/// 列優先の4x4行列。`m[列][行]`で、GLSLの`mat4`とそのまま同じメモリ配置になる
+pub type Mat4 = [[f32; 4]; 4];
+
+pub const MAT4_IDENTITY: Mat4 = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+/// `a * b`。`b`を先に適用する
+pub fn mat4_mul(a: &Mat4, b: &Mat4) -> Mat4 {
+    let mut m = [[0.0; 4]; 4];
+    for (col, b_col) in b.iter().enumerate() {
+        for row in 0..4 {
+            m[col][row] = (0..4).map(|k| a[k][row] * b_col[k]).sum();
+        }
+    }
+    m
+}
+
+/// 逆行列。正則でなければ`None`
+pub fn mat4_inverse(m: &Mat4) -> Option<Mat4> {
+    // 余因子展開。`m[列][行]`なので添字の順に注意する
+    let a = |col: usize, row: usize| m[col][row];
+    let s0 = a(0, 0) * a(1, 1) - a(0, 1) * a(1, 0);
+    let s1 = a(0, 0) * a(1, 2) - a(0, 2) * a(1, 0);
+    let s2 = a(0, 0) * a(1, 3) - a(0, 3) * a(1, 0);
+    let s3 = a(0, 1) * a(1, 2) - a(0, 2) * a(1, 1);
+    let s4 = a(0, 1) * a(1, 3) - a(0, 3) * a(1, 1);
+    let s5 = a(0, 2) * a(1, 3) - a(0, 3) * a(1, 2);
+    let c5 = a(2, 2) * a(3, 3) - a(2, 3) * a(3, 2);
+    let c4 = a(2, 1) * a(3, 3) - a(2, 3) * a(3, 1);
+    let c3 = a(2, 1) * a(3, 2) - a(2, 2) * a(3, 1);
+    let c2 = a(2, 0) * a(3, 3) - a(2, 3) * a(3, 0);
+    let c1 = a(2, 0) * a(3, 2) - a(2, 2) * a(3, 0);
+    let c0 = a(2, 0) * a(3, 1) - a(2, 1) * a(3, 0);
+    let det = s0 * c5 - s1 * c4 + s2 * c3 + s3 * c2 - s4 * c1 + s5 * c0;
+    if det.abs() <= f32::EPSILON * f32::EPSILON {
+        return None;
+    }
+    let inv = 1.0 / det;
+    Some([
+        [
+            (a(1, 1) * c5 - a(1, 2) * c4 + a(1, 3) * c3) * inv,
+            (-a(0, 1) * c5 + a(0, 2) * c4 - a(0, 3) * c3) * inv,
+            (a(3, 1) * s5 - a(3, 2) * s4 + a(3, 3) * s3) * inv,
+            (-a(2, 1) * s5 + a(2, 2) * s4 - a(2, 3) * s3) * inv,
+        ],
+        [
+            (-a(1, 0) * c5 + a(1, 2) * c2 - a(1, 3) * c1) * inv,
+            (a(0, 0) * c5 - a(0, 2) * c2 + a(0, 3) * c1) * inv,
+            (-a(3, 0) * s5 + a(3, 2) * s2 - a(3, 3) * s1) * inv,
+            (a(2, 0) * s5 - a(2, 2) * s2 + a(2, 3) * s1) * inv,
+        ],
+        [
+            (a(1, 0) * c4 - a(1, 1) * c2 + a(1, 3) * c0) * inv,
+            (-a(0, 0) * c4 + a(0, 1) * c2 - a(0, 3) * c0) * inv,
+            (a(3, 0) * s4 - a(3, 1) * s2 + a(3, 3) * s0) * inv,
+            (-a(2, 0) * s4 + a(2, 1) * s2 - a(2, 3) * s0) * inv,
+        ],
+        [
+            (-a(1, 0) * c3 + a(1, 1) * c1 - a(1, 2) * c0) * inv,
+            (a(0, 0) * c3 - a(0, 1) * c1 + a(0, 2) * c0) * inv,
+            (-a(3, 0) * s3 + a(3, 1) * s1 - a(3, 2) * s0) * inv,
+            (a(2, 0) * s3 - a(2, 1) * s1 + a(2, 2) * s0) * inv,
+        ],
+    ])
+}
+
+/// 投影行列をNDCで`jitter`だけずらす。透視投影と平行投影のどちらにも使える
+pub fn jitter_projection(projection: &Mat4, jitter: [f32; 2]) -> Mat4 {
+    // クリップ座標に`jitter * w`を足す
+    let mut m = *projection;
+    for col in m.iter_mut() {
+        col[0] += jitter[0] * col[3];
+        col[1] += jitter[1] * col[3];
+    }
+    m
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn add_scaled(a: [f32; 3], b: [f32; 3], scale: f32) -> [f32; 3] {
+    [
+        a[0] + b[0] * scale,
+        a[1] + b[1] * scale,
+        a[2] + b[2] * scale,
+    ]
+}
+
+/// 射影の種類
+///
+/// ビュー空間は右手系で-Zが前方、Y軸が上。クリップ空間はVulkanに合わせてYが下向き、
+/// 深度は0から1になる。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    Perspective {
+        /// 垂直方向の画角(ラジアン)
+        fov_y: f32,
+        near: f32,
+        /// `None`なら無限遠まで
+        far: Option<f32>,
+    },
+    Orthographic {
+        /// 垂直方向に映る範囲の高さ
+        height: f32,
+        near: f32,
+        far: f32,
+    },
+}
+
+/// 位置とヨー/ピッチで向きを表すカメラ
+///
+/// ヨーとピッチがともに0のとき-Zを向く。ヨーは右回り、ピッチは上向きが正。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Camera {
+    pub position: [f32; 3],
+    pub yaw: f32,
+    pub pitch: f32,
+    pub projection: Projection,
+    /// 幅/高さ
+    pub aspect: f32,
+    /// 近くを1、遠くを0にする。浮動小数点の深度バッファの精度が遠方で良くなる
+    pub reversed_z: bool,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            position: [0.0, 0.0, 0.0],
+            yaw: 0.0,
+            pitch: 0.0,
+            projection: Projection::Perspective {
+                fov_y: 60f32.to_radians(),
+                near: 0.1,
+                far: Some(1000.0),
+            },
+            aspect: 16.0 / 9.0,
+            reversed_z: false,
+        }
+    }
+}
+
+impl Camera {
+    pub fn forward(&self) -> [f32; 3] {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        [sin_yaw * cos_pitch, sin_pitch, -cos_yaw * cos_pitch]
+    }
+
+    pub fn right(&self) -> [f32; 3] {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        [cos_yaw, 0.0, sin_yaw]
+    }
+
+    pub fn up(&self) -> [f32; 3] {
+        cross(self.right(), self.forward())
+    }
+
+    /// `target`の方を向く
+    pub fn look_at(&mut self, target: [f32; 3]) {
+        let d = [
+            target[0] - self.position[0],
+            target[1] - self.position[1],
+            target[2] - self.position[2],
+        ];
+        let horizontal = (d[0] * d[0] + d[2] * d[2]).sqrt();
+        if horizontal > f32::EPSILON || d[1].abs() > f32::EPSILON {
+            self.yaw = d[0].atan2(-d[2]);
+            self.pitch = d[1].atan2(horizontal);
+        }
+    }
+
+    pub fn view_matrix(&self) -> Mat4 {
+        let right = self.right();
+        let up = self.up();
+        let back = self.forward().map(|value| -value);
+        let p = self.position;
+        [
+            [right[0], up[0], back[0], 0.0],
+            [right[1], up[1], back[1], 0.0],
+            [right[2], up[2], back[2], 0.0],
+            [-dot(right, p), -dot(up, p), -dot(back, p), 1.0],
+        ]
+    }
+
+    pub fn projection_matrix(&self) -> Mat4 {
+        let mut m = [[0.0; 4]; 4];
+        match self.projection {
+            Projection::Perspective { fov_y, near, far } => {
+                let f = 1.0 / (fov_y * 0.5).tan();
+                m[0][0] = f / self.aspect;
+                m[1][1] = -f;
+                m[2][3] = -1.0;
+                // 深度 = (m22 * z + m32) / -z
+                let (m22, m32) = match (far, self.reversed_z) {
+                    (Some(far), false) => (far / (near - far), near * far / (near - far)),
+                    (Some(far), true) => (near / (far - near), near * far / (far - near)),
+                    (None, false) => (-1.0, -near),
+                    (None, true) => (0.0, near),
+                };
+                m[2][2] = m22;
+                m[3][2] = m32;
+            }
+            Projection::Orthographic { height, near, far } => {
+                let width = height * self.aspect;
+                m[0][0] = 2.0 / width;
+                m[1][1] = -2.0 / height;
+                let depth = far - near;
+                if self.reversed_z {
+                    m[2][2] = 1.0 / depth;
+                    m[3][2] = far / depth;
+                } else {
+                    m[2][2] = -1.0 / depth;
+                    m[3][2] = -near / depth;
+                }
+                m[3][3] = 1.0;
+            }
+        }
+        m
+    }
+
+    pub fn view_projection_matrix(&self) -> Mat4 {
+        mat4_mul(&self.projection_matrix(), &self.view_matrix())
+    }
+
+    /// 深度テストの比較演算。`reversed_z`なら手前ほど深度が大きい
+    pub fn depth_compare_op(&self) -> vk::CompareOp {
+        if self.reversed_z {
+            vk::CompareOp::GREATER_OR_EQUAL
+        } else {
+            vk::CompareOp::LESS_OR_EQUAL
+        }
+    }
+
+    /// 深度バッファをクリアする値(最も遠い深度)
+    pub fn clear_depth(&self) -> f32 {
+        if self.reversed_z {
+            0.0
+        } else {
+            1.0
+        }
+    }
+}
+
+/// 1フレーム分の入力。ウィンドウシステムのイベントから組み立てて、コントローラーに渡す
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CameraInput {
+    /// 右、上、前方向への移動量。キー入力なら-1から1
+    pub movement: [f32; 3],
+    /// マウスの移動量(ピクセル)。右と下が正
+    pub look: [f32; 2],
+    /// ホイールの回転量。奥への回転が正
+    pub zoom: f32,
+}
+
+// 真上や真下を向いたときに向きが不定にならないようにする
+const MAX_PITCH: f32 = std::f32::consts::FRAC_PI_2 - 0.01;
+
+/// 向いている方向に飛んで移動するFPS風のコントローラー
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlyController {
+    /// 1秒あたりの移動量
+    pub speed: f32,
+    /// 1ピクセルあたりの回転量(ラジアン)
+    pub sensitivity: f32,
+}
+
+impl Default for FlyController {
+    fn default() -> Self {
+        Self {
+            speed: 5.0,
+            sensitivity: 0.003,
+        }
+    }
+}
+
+impl FlyController {
+    pub fn update(&self, camera: &mut Camera, input: &CameraInput, delta_seconds: f32) {
+        camera.yaw += input.look[0] * self.sensitivity;
+        camera.pitch =
+            (camera.pitch - input.look[1] * self.sensitivity).clamp(-MAX_PITCH, MAX_PITCH);
+
+        let distance = self.speed * delta_seconds;
+        let [right, up, forward] = input.movement;
+        let mut position = camera.position;
+        position = add_scaled(position, camera.right(), right * distance);
+        position = add_scaled(position, [0.0, 1.0, 0.0], up * distance);
+        position = add_scaled(position, camera.forward(), forward * distance);
+        camera.position = position;
+    }
+}
+
+/// 注視点の周りを回るコントローラー
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrbitController {
+    pub target: [f32; 3],
+    pub distance: f32,
+    pub yaw: f32,
+    pub pitch: f32,
+    /// 1ピクセルあたりの回転量(ラジアン)
+    pub sensitivity: f32,
+    /// ホイール1単位で距離に掛ける倍率
+    pub zoom_factor: f32,
+    pub min_distance: f32,
+}
+
+impl Default for OrbitController {
+    fn default() -> Self {
+        Self {
+            target: [0.0, 0.0, 0.0],
+            distance: 5.0,
+            yaw: 0.0,
+            pitch: 0.3,
+            sensitivity: 0.005,
+            zoom_factor: 0.9,
+            min_distance: 0.01,
+        }
+    }
+}
+
+impl OrbitController {
+    /// 入力で回転とズームをして、カメラを注視点に向ける。移動入力は注視点を平行移動する
+    pub fn update(&mut self, camera: &mut Camera, input: &CameraInput, delta_seconds: f32) {
+        self.yaw -= input.look[0] * self.sensitivity;
+        self.pitch = (self.pitch + input.look[1] * self.sensitivity).clamp(-MAX_PITCH, MAX_PITCH);
+        self.distance = (self.distance * self.zoom_factor.powf(input.zoom)).max(self.min_distance);
+
+        let pan = self.distance * delta_seconds;
+        let [right, up, forward] = input.movement;
+        self.target = add_scaled(self.target, camera.right(), right * pan);
+        self.target = add_scaled(self.target, camera.up(), up * pan);
+        self.target = add_scaled(self.target, camera.forward(), forward * pan);
+
+        // 注視点からカメラへの方向。ヨー0ではカメラは+Z側から-Zを向く
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        let offset = [-sin_yaw * cos_pitch, sin_pitch, cos_yaw * cos_pitch];
+        camera.position = add_scaled(self.target, offset, self.distance);
+        camera.yaw = self.yaw;
+        camera.pitch = -self.pitch;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    /// 点を変換して、wで割ったNDCを返す
+    fn project(m: &Mat4, p: [f32; 3]) -> [f32; 3] {
+        let clip: Vec<f32> = (0..4)
+            .map(|row| m[0][row] * p[0] + m[1][row] * p[1] + m[2][row] * p[2] + m[3][row])
+            .collect();
+        [clip[0] / clip[3], clip[1] / clip[3], clip[2] / clip[3]]
+    }
+
+    fn camera(projection: Projection, reversed_z: bool) -> Camera {
+        Camera {
+            projection,
+            aspect: 1.5,
+            reversed_z,
+            ..Camera::default()
+        }
+    }
+
+    const PERSPECTIVE: Projection = Projection::Perspective {
+        fov_y: 1.0,
+        near: 0.5,
+        far: Some(100.0),
+    };
+    const ORTHOGRAPHIC: Projection = Projection::Orthographic {
+        height: 4.0,
+        near: 0.5,
+        far: 100.0,
+    };
+
+    #[test]
+    fn inverse_times_matrix_is_identity() {
+        let camera = Camera {
+            position: [1.0, -2.0, 3.0],
+            yaw: 0.7,
+            pitch: -0.3,
+            ..Camera::default()
+        };
+        for m in [camera.view_matrix(), camera.view_projection_matrix()] {
+            let product = mat4_mul(&mat4_inverse(&m).unwrap(), &m);
+            for (col, identity_col) in product.iter().zip(MAT4_IDENTITY.iter()) {
+                for (value, expected) in col.iter().zip(identity_col) {
+                    assert_near(*value, *expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn singular_matrix_has_no_inverse() {
+        let mut m = MAT4_IDENTITY;
+        m[2] = [0.0; 4];
+        assert_eq!(mat4_inverse(&m), None);
+    }
+
+    #[test]
+    fn depth_range_follows_reversed_z() {
+        for projection in [PERSPECTIVE, ORTHOGRAPHIC] {
+            for (reversed_z, near_depth, far_depth) in [(false, 0.0, 1.0), (true, 1.0, 0.0)] {
+                let m = camera(projection, reversed_z).projection_matrix();
+                assert_near(project(&m, [0.0, 0.0, -0.5])[2], near_depth);
+                assert_near(project(&m, [0.0, 0.0, -100.0])[2], far_depth);
+            }
+        }
+    }
+
+    #[test]
+    fn infinite_reversed_z_maps_near_to_one_and_infinity_to_zero() {
+        let projection = Projection::Perspective {
+            fov_y: 1.0,
+            near: 0.5,
+            far: None,
+        };
+        let m = camera(projection, true).projection_matrix();
+        assert_near(project(&m, [0.0, 0.0, -0.5])[2], 1.0);
+        assert!(project(&m, [0.0, 0.0, -1.0e6])[2] < 1e-5);
+    }
+
+    #[test]
+    fn clip_space_y_points_down() {
+        for projection in [PERSPECTIVE, ORTHOGRAPHIC] {
+            let m = camera(projection, false).projection_matrix();
+            assert!(project(&m, [0.0, 1.0, -10.0])[1] < 0.0);
+            assert!(project(&m, [0.0, -1.0, -10.0])[1] > 0.0);
+            assert!(project(&m, [1.0, 0.0, -10.0])[0] > 0.0);
+        }
+    }
+}
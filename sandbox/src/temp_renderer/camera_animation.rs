@@ -0,0 +1,504 @@
+use super::error::{RendererError, Result};
+use super::view_settings::ViewSettings;
+use super::{Camera, Projection, Renderer};
+use std::f32::consts::{PI, TAU};
+
+/// キーフレームから次のキーフレームまでの補間のかけ方
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Easing {
+    #[default]
+    Linear,
+    /// ゆっくり動き出す
+    EaseIn,
+    /// ゆっくり止まる
+    EaseOut,
+    EaseInOut,
+    /// 次のキーフレームまで値を変えない
+    Step,
+}
+
+impl Easing {
+    /// 0から1の`t`を補間の割合にする
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Self::Linear => t,
+            Self::EaseIn => t * t,
+            Self::EaseOut => t * (2.0 - t),
+            Self::EaseInOut => t * t * (3.0 - 2.0 * t),
+            Self::Step => 0.0,
+        }
+    }
+}
+
+/// トラックで補間できる値
+pub trait Interpolate: Copy {
+    fn interpolate(a: Self, b: Self, t: f32) -> Self;
+}
+
+impl Interpolate for f32 {
+    fn interpolate(a: Self, b: Self, t: f32) -> Self {
+        a + (b - a) * t
+    }
+}
+
+impl Interpolate for [f32; 3] {
+    fn interpolate(a: Self, b: Self, t: f32) -> Self {
+        [0, 1, 2].map(|i| a[i] + (b[i] - a[i]) * t)
+    }
+}
+
+/// カメラのヨーとピッチ(ラジアン)。ヨーは近い向きに回って補間する
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct YawPitch {
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+impl Interpolate for YawPitch {
+    fn interpolate(a: Self, b: Self, t: f32) -> Self {
+        let delta = (b.yaw - a.yaw + PI).rem_euclid(TAU) - PI;
+        Self {
+            yaw: a.yaw + delta * t,
+            pitch: a.pitch + (b.pitch - a.pitch) * t,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keyframe<T> {
+    /// アニメーションの先頭からの時間(秒)
+    pub time: f32,
+    pub value: T,
+    /// このキーフレームから次のキーフレームまでの補間
+    pub easing: Easing,
+}
+
+/// 1つの値のキーフレームの列。キーフレームは時間順に保つ
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track<T> {
+    keyframes: Vec<Keyframe<T>>,
+}
+
+impl<T> Default for Track<T> {
+    fn default() -> Self {
+        Self {
+            keyframes: Vec::new(),
+        }
+    }
+}
+
+impl<T: Interpolate> Track<T> {
+    /// キーフレームを時間順の位置に挿入する。同じ時間のものがあれば置き換える
+    pub fn insert(&mut self, keyframe: Keyframe<T>) {
+        match self
+            .keyframes
+            .binary_search_by(|other| other.time.total_cmp(&keyframe.time))
+        {
+            Ok(index) => self.keyframes[index] = keyframe,
+            Err(index) => self.keyframes.insert(index, keyframe),
+        }
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe<T>] {
+        &self.keyframes
+    }
+
+    /// 最後のキーフレームの時間。キーフレームがなければ0
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |keyframe| keyframe.time)
+    }
+
+    /// `time`での値。最初のキーフレームより前と最後より後は端の値になる
+    pub fn sample(&self, time: f32) -> Option<T> {
+        let next = self
+            .keyframes
+            .partition_point(|keyframe| keyframe.time <= time);
+        match (
+            self.keyframes.get(next.wrapping_sub(1)),
+            self.keyframes.get(next),
+        ) {
+            (Some(a), Some(b)) => {
+                let t = (time - a.time) / (b.time - a.time);
+                Some(T::interpolate(a.value, b.value, a.easing.apply(t)))
+            }
+            (Some(keyframe), None) | (None, Some(keyframe)) => Some(keyframe.value),
+            (None, None) => None,
+        }
+    }
+}
+
+/// アニメーションの途中でアプリケーションに通知するイベント
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimationEvent {
+    pub time: f32,
+    pub name: String,
+}
+
+/// カットシーンやベンチマークの移動経路に使う、カメラと露出のアニメーション
+///
+/// トラックは独立していて、キーフレームのないトラックはカメラの値を変えない。
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CameraAnimation {
+    pub position: Track<[f32; 3]>,
+    pub rotation: Track<YawPitch>,
+    /// 垂直方向の画角(ラジアン)。透視投影のカメラだけに適用する
+    pub fov_y: Track<f32>,
+    /// `ViewSettings::exposure`に設定する露出
+    pub exposure: Track<f32>,
+    /// 時間順に並べたイベント
+    events: Vec<AnimationEvent>,
+}
+
+/// `CameraAnimation::sample`で求めた、ある時間のカメラの値
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CameraAnimationSample {
+    pub position: Option<[f32; 3]>,
+    pub rotation: Option<YawPitch>,
+    pub fov_y: Option<f32>,
+    pub exposure: Option<f32>,
+}
+
+impl CameraAnimationSample {
+    /// 露出以外の値をカメラに設定する
+    pub fn apply_to_camera(&self, camera: &mut Camera) {
+        if let Some(position) = self.position {
+            camera.position = position;
+        }
+        if let Some(rotation) = self.rotation {
+            camera.yaw = rotation.yaw;
+            camera.pitch = rotation.pitch;
+        }
+        if let (Some(fov), Projection::Perspective { fov_y, .. }) =
+            (self.fov_y, &mut camera.projection)
+        {
+            *fov_y = fov;
+        }
+    }
+}
+
+impl CameraAnimation {
+    /// イベントを時間順の位置に追加する
+    pub fn add_event(&mut self, time: f32, name: impl Into<String>) {
+        let index = self.events.partition_point(|event| event.time <= time);
+        self.events.insert(
+            index,
+            AnimationEvent {
+                time,
+                name: name.into(),
+            },
+        );
+    }
+
+    pub fn events(&self) -> &[AnimationEvent] {
+        &self.events
+    }
+
+    /// 最も長いトラックかイベントの時間
+    pub fn duration(&self) -> f32 {
+        [
+            self.position.duration(),
+            self.rotation.duration(),
+            self.fov_y.duration(),
+            self.exposure.duration(),
+            self.events.last().map_or(0.0, |event| event.time),
+        ]
+        .into_iter()
+        .fold(0.0, f32::max)
+    }
+
+    pub fn sample(&self, time: f32) -> CameraAnimationSample {
+        CameraAnimationSample {
+            position: self.position.sample(time),
+            rotation: self.rotation.sample(time),
+            fov_y: self.fov_y.sample(time),
+            exposure: self.exposure.sample(time),
+        }
+    }
+}
+
+/// `CameraAnimation`を時間に沿って再生する
+///
+/// ベンチマークのように毎回同じ映像にしたい場合は、`update`に実時間ではなく固定の
+/// フレームの間隔を渡す。
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraAnimationPlayer {
+    pub animation: CameraAnimation,
+    /// 再生位置(秒)
+    pub time: f32,
+    /// 再生の速さ。1で等速
+    pub speed: f32,
+    /// 最後まで再生したら先頭に戻る
+    pub looping: bool,
+    pub playing: bool,
+}
+
+impl CameraAnimationPlayer {
+    /// 先頭で停止した状態で作る
+    pub fn new(animation: CameraAnimation) -> Self {
+        Self {
+            animation,
+            time: 0.0,
+            speed: 1.0,
+            looping: false,
+            playing: false,
+        }
+    }
+
+    pub fn play(&mut self) {
+        self.playing = true;
+    }
+
+    pub fn pause(&mut self) {
+        self.playing = false;
+    }
+
+    /// 再生位置を`time`にする。飛ばした区間のイベントは通知しない
+    pub fn seek(&mut self, time: f32) {
+        self.time = time.clamp(0.0, self.animation.duration());
+    }
+
+    /// 最後まで再生して止まったか
+    pub fn finished(&self) -> bool {
+        !self.looping && self.time >= self.animation.duration()
+    }
+
+    /// 再生位置を`delta_seconds`だけ進め、通り過ぎたイベントを時間順に返す
+    ///
+    /// 再生位置ちょうどのイベントは次の`update`で返す。ループしない場合は最後の
+    /// イベントまで返して止まる。
+    pub fn update(&mut self, delta_seconds: f32) -> Vec<AnimationEvent> {
+        let duration = self.animation.duration();
+        if !self.playing {
+            return Vec::new();
+        }
+        if !self.looping && self.time >= duration {
+            self.playing = false;
+            return Vec::new();
+        }
+        let mut events = Vec::new();
+        let mut from = self.time;
+        let mut to = from + delta_seconds * self.speed;
+        let mut include_end = false;
+        if self.looping && duration > 0.0 {
+            // 何周かした場合も、通り過ぎたイベントをすべて返す
+            while to >= duration {
+                self.collect_events(from, duration, true, &mut events);
+                to -= duration;
+                from = 0.0;
+            }
+        } else if to >= duration {
+            to = duration;
+            include_end = true;
+            self.playing = false;
+        }
+        self.collect_events(from, to, include_end, &mut events);
+        self.time = to;
+        events
+    }
+
+    /// 現在の再生位置の値
+    pub fn sample(&self) -> CameraAnimationSample {
+        self.animation.sample(self.time)
+    }
+
+    /// `from`以降で`to`より前のイベントを集める。`include_end`なら`to`ちょうどのものも含める
+    fn collect_events(
+        &self,
+        from: f32,
+        to: f32,
+        include_end: bool,
+        events: &mut Vec<AnimationEvent>,
+    ) {
+        events.extend(
+            self.animation
+                .events
+                .iter()
+                .filter(|event| {
+                    event.time >= from && (event.time < to || (include_end && event.time == to))
+                })
+                .cloned(),
+        );
+    }
+}
+
+impl Renderer {
+    /// アニメーションの値をカメラに設定し、露出を現在のビューの設定に重ねる
+    ///
+    /// 露出のトラックがなければビューの露出は変えない。
+    pub fn apply_camera_animation(
+        &mut self,
+        sample: &CameraAnimationSample,
+        camera: &mut Camera,
+    ) -> Result<()> {
+        if let Some(exposure) = sample.exposure {
+            if !exposure.is_finite() || exposure < 0.0 {
+                return Err(RendererError::Validation(format!(
+                    "invalid animated exposure: {}",
+                    exposure
+                )));
+            }
+            self.set_view_settings(ViewSettings {
+                exposure: Some(exposure),
+                ..self.view_settings
+            })?;
+        }
+        sample.apply_to_camera(camera);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key<T>(time: f32, value: T, easing: Easing) -> Keyframe<T> {
+        Keyframe {
+            time,
+            value,
+            easing,
+        }
+    }
+
+    fn track(keyframes: impl IntoIterator<Item = Keyframe<f32>>) -> Track<f32> {
+        let mut track = Track::default();
+        for keyframe in keyframes {
+            track.insert(keyframe);
+        }
+        track
+    }
+
+    #[test]
+    fn sample_interpolates_between_keys() {
+        let track = track([
+            key(0.0, 0.0, Easing::Linear),
+            key(1.0, 10.0, Easing::EaseIn),
+            key(2.0, 20.0, Easing::Step),
+            key(3.0, 30.0, Easing::Linear),
+        ]);
+        assert_eq!(track.sample(0.25), Some(2.5));
+        assert_eq!(track.sample(1.0), Some(10.0));
+        assert_eq!(track.sample(1.5), Some(12.5));
+        assert_eq!(track.sample(2.5), Some(20.0));
+    }
+
+    #[test]
+    fn sample_clamps_outside_keys() {
+        let track = track([key(1.0, 5.0, Easing::Linear), key(2.0, 7.0, Easing::Linear)]);
+        assert_eq!(track.sample(-1.0), Some(5.0));
+        assert_eq!(track.sample(2.0), Some(7.0));
+        assert_eq!(track.sample(10.0), Some(7.0));
+        assert_eq!(Track::<f32>::default().sample(0.0), None);
+    }
+
+    #[test]
+    fn insert_keeps_keys_sorted_and_replaces_same_time() {
+        let track = track([
+            key(2.0, 2.0, Easing::Linear),
+            key(0.0, 0.0, Easing::Linear),
+            key(2.0, 3.0, Easing::Linear),
+        ]);
+        let times: Vec<_> = track.keyframes().iter().map(|key| key.time).collect();
+        assert_eq!(times, [0.0, 2.0]);
+        assert_eq!(track.duration(), 2.0);
+        assert_eq!(track.sample(2.0), Some(3.0));
+    }
+
+    #[test]
+    fn yaw_takes_the_shorter_way_round() {
+        let a = YawPitch {
+            yaw: 170f32.to_radians(),
+            pitch: 0.0,
+        };
+        let b = YawPitch {
+            yaw: -170f32.to_radians(),
+            pitch: 0.5,
+        };
+        let middle = YawPitch::interpolate(a, b, 0.5);
+        assert!((middle.yaw - PI).abs() < 1e-5);
+        assert!((middle.pitch - 0.25).abs() < 1e-6);
+    }
+
+    /// 0秒から2秒まで位置が動き、0、0.5、2秒にイベントがあるアニメーション
+    fn player(looping: bool) -> CameraAnimationPlayer {
+        let mut animation = CameraAnimation::default();
+        animation
+            .position
+            .insert(key(0.0, [0.0, 0.0, 0.0], Easing::Linear));
+        animation
+            .position
+            .insert(key(2.0, [4.0, 0.0, 0.0], Easing::Linear));
+        animation.add_event(2.0, "end");
+        animation.add_event(0.0, "start");
+        animation.add_event(0.5, "middle");
+        let mut player = CameraAnimationPlayer::new(animation);
+        player.looping = looping;
+        player.play();
+        player
+    }
+
+    fn names(events: Vec<AnimationEvent>) -> Vec<String> {
+        events.into_iter().map(|event| event.name).collect()
+    }
+
+    #[test]
+    fn playback_stops_at_the_end() {
+        let mut player = player(false);
+        assert_eq!(player.animation.duration(), 2.0);
+        player.update(1.0);
+        assert_eq!(player.sample().position, Some([2.0, 0.0, 0.0]));
+        player.update(5.0);
+        assert_eq!(player.time, 2.0);
+        assert!(player.finished());
+        assert!(!player.playing);
+        assert_eq!(player.sample().position, Some([4.0, 0.0, 0.0]));
+        assert!(player.update(1.0).is_empty());
+    }
+
+    #[test]
+    fn looping_wraps_time() {
+        let mut player = player(true);
+        player.update(2.5);
+        assert_eq!(player.time, 0.5);
+        assert!(player.playing);
+        assert!(!player.finished());
+        assert_eq!(player.sample().position, Some([1.0, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn events_fire_once_when_passed() {
+        let mut player = player(false);
+        let mut fired = Vec::new();
+        // 0.5秒のイベントはフレームの境目にちょうど重なる
+        while player.playing {
+            fired.extend(names(player.update(0.25)));
+        }
+        assert_eq!(fired, ["start", "middle", "end"]);
+    }
+
+    #[test]
+    fn looping_events_fire_once_per_loop() {
+        let mut player = player(true);
+        let mut fired = Vec::new();
+        for _ in 0..16 {
+            fired.extend(names(player.update(0.25)));
+        }
+        assert_eq!(fired, ["start", "middle", "end", "start", "middle", "end"]);
+
+        // 1回の更新で何周しても周回ごとに返す
+        let mut player = self::player(true);
+        assert_eq!(
+            names(player.update(4.5)),
+            ["start", "middle", "end", "start", "middle", "end", "start"]
+        );
+    }
+
+    #[test]
+    fn seek_skips_events() {
+        let mut player = player(false);
+        player.seek(1.0);
+        assert_eq!(names(player.update(5.0)), ["end"]);
+        player.seek(-1.0);
+        assert_eq!(player.time, 0.0);
+    }
+}
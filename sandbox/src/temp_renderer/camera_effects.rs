@@ -0,0 +1,521 @@
+use super::camera::{mat4_inverse, mat4_mul, Mat4, MAT4_IDENTITY};
+use super::descriptor::{allocate_set, create_pool};
+use super::error::{RendererError, Result};
+use super::memory::MemoryAllocator;
+use super::renderer::create_depth_sampling_view;
+use super::texture::{create_screen_image, ScreenImage};
+use super::{
+    ComputePipeline, DescriptorBinding, DescriptorWriter, Renderer, SamplerDesc, ShaderId,
+    HDR_FORMAT,
+};
+use ash::{vk, Device};
+
+/// 被写界深度とモーションブラーのシェーダーのワークグループの幅と高さ
+pub const CAMERA_EFFECT_GROUP_SIZE: u32 = 8;
+/// モーションブラーの1画素あたりのサンプル数の上限
+pub const MAX_MOTION_BLUR_SAMPLES: u32 = 32;
+
+/// 被写界深度の設定。レンズの値から錯乱円の大きさを求める
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DepthOfFieldSettings {
+    pub enabled: bool,
+    /// ピントの合う距離。ビュー空間の単位(メートル)
+    pub focus_distance: f32,
+    /// 絞り値。小さいほどボケが大きい
+    pub f_number: f32,
+    /// 焦点距離(メートル)
+    pub focal_length: f32,
+    /// センサーの高さ(メートル)。35mmフルサイズなら0.024
+    pub sensor_height: f32,
+    /// 錯乱円の半径の上限(ピクセル)。ボケのサンプルの範囲でもある
+    pub max_coc_radius: f32,
+}
+
+impl Default for DepthOfFieldSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            focus_distance: 10.0,
+            f_number: 2.8,
+            focal_length: 0.05,
+            sensor_height: 0.024,
+            max_coc_radius: 12.0,
+        }
+    }
+}
+
+/// カメラの動きによるモーションブラーの設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotionBlurSettings {
+    pub enabled: bool,
+    /// シャッターが開いている時間の、フレームの間隔に対する割合
+    pub shutter: f32,
+    pub sample_count: u32,
+    /// ブラーの長さの上限(ピクセル)
+    pub max_blur_radius: f32,
+}
+
+impl Default for MotionBlurSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            shutter: 0.5,
+            sample_count: 8,
+            max_blur_radius: 32.0,
+        }
+    }
+}
+
+/// 被写界深度のシェーダーに渡すプッシュ定数
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DepthOfFieldPushConstants {
+    /// 深度`d`からカメラの距離を`-(x * d + y) / (z * d + w)`で求める。逆投影行列の要素
+    pub depth_params: [f32; 4],
+    pub focus_distance: f32,
+    /// `|距離 - focus_distance| / 距離`に掛けると錯乱円の半径(ピクセル)になる
+    pub coc_scale: f32,
+    pub max_coc_radius: f32,
+}
+
+impl DepthOfFieldPushConstants {
+    fn bytes(&self) -> Vec<u8> {
+        self.depth_params
+            .iter()
+            .chain([self.focus_distance, self.coc_scale, self.max_coc_radius].iter())
+            .flat_map(|value| value.to_ne_bytes())
+            .collect()
+    }
+}
+
+/// モーションブラーのシェーダーに渡すプッシュ定数
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotionBlurPushConstants {
+    /// 前のフレームのビュー射影行列と、現在のビュー射影行列の逆行列の積
+    pub reprojection: Mat4,
+    pub shutter: f32,
+    pub sample_count: u32,
+    pub max_blur_radius: f32,
+}
+
+impl MotionBlurPushConstants {
+    fn bytes(&self) -> Vec<u8> {
+        self.reprojection
+            .iter()
+            .flatten()
+            .flat_map(|value| value.to_ne_bytes())
+            .chain(self.shutter.to_ne_bytes())
+            .chain(self.sample_count.to_ne_bytes())
+            .chain(self.max_blur_radius.to_ne_bytes())
+            .collect()
+    }
+}
+
+/// 被写界深度とモーションブラーのコンピュートシェーダー
+///
+/// どちらも8x8のワークグループで出力の1画素を1スレッドが処理し、
+/// `camera_effect_set_layout`のデスクリプタセットを使う。結果はレンダラーが
+/// HDRのレンダーターゲットにコピーする。
+///
+/// ```glsl
+/// layout(local_size_x = 8, local_size_y = 8) in;
+/// layout(set = 0, binding = 0) uniform sampler2D color;
+/// layout(set = 0, binding = 1) uniform sampler2D depth;
+/// layout(set = 0, binding = 2, rgba16f) uniform writeonly image2D dst;
+///
+/// // depth_of_field: 錯乱円の範囲の円盤からボケを集める。手前の点は自分の錯乱円が
+/// // 届けば混ざり、奥の点は中心の錯乱円の範囲までしか混ざらない
+/// layout(push_constant) uniform Params {
+///     vec4 depth_params; float focus_distance; float coc_scale; float max_coc_radius;
+/// };
+/// float distance_at(ivec2 q) {
+///     float d = texelFetch(depth, q, 0).r;
+///     return -(depth_params.x * d + depth_params.y) / (depth_params.z * d + depth_params.w);
+/// }
+/// float coc(float z) { return min(coc_scale * abs(z - focus_distance) / z, max_coc_radius); }
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy), size = imageSize(dst);
+///     if (any(greaterThanEqual(p, size))) return;
+///     float center_distance = distance_at(p), center_coc = coc(center_distance);
+///     vec3 sum = texelFetch(color, p, 0).rgb;
+///     float total = 1.0;
+///     for (int i = 0; i < 48; ++i) {
+///         float r = sqrt((float(i) + 0.5) / 48.0) * max_coc_radius, a = float(i) * 2.399963;
+///         ivec2 q = clamp(p + ivec2(round(vec2(cos(a), sin(a)) * r)), ivec2(0), size - 1);
+///         float z = distance_at(q), sample_coc = coc(z);
+///         float reach = z < center_distance ? sample_coc : min(sample_coc, center_coc);
+///         float w = clamp(reach - r + 1.0, 0.0, 1.0);
+///         sum += texelFetch(color, q, 0).rgb * w;
+///         total += w;
+///     }
+///     imageStore(dst, p, vec4(sum / total, 1.0));
+/// }
+///
+/// // motion_blur: 深度から前のフレームのUVを求め、その間の色を平均する
+/// layout(push_constant) uniform Params {
+///     mat4 reprojection; float shutter; uint sample_count; float max_blur_radius;
+/// };
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy), size = imageSize(dst);
+///     if (any(greaterThanEqual(p, size))) return;
+///     vec2 uv = (vec2(p) + 0.5) / vec2(size);
+///     vec4 previous = reprojection * vec4(uv * 2.0 - 1.0, texelFetch(depth, p, 0).r, 1.0);
+///     vec2 velocity = (uv - (previous.xy / previous.w * 0.5 + 0.5)) * shutter * vec2(size);
+///     float len = length(velocity);
+///     if (len > max_blur_radius) velocity *= max_blur_radius / len;
+///     vec3 sum = vec3(0.0);
+///     for (uint i = 0; i < sample_count; ++i) {
+///         float t = (float(i) + 0.5) / float(sample_count) - 0.5;
+///         sum += texture(color, (vec2(p) + 0.5 + velocity * t) / vec2(size)).rgb;
+///     }
+///     imageStore(dst, p, vec4(sum / float(sample_count), 1.0));
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CameraEffectShaders {
+    pub depth_of_field: ShaderId,
+    pub motion_blur: ShaderId,
+}
+
+/// HDRのターゲットに合わせて作り直す被写界深度とモーションブラーのリソース
+///
+/// 2つのパスは同じ出力を順に使い、それぞれの後でHDRのターゲットにコピーする。
+pub struct CameraEffectTargets {
+    pub output: ScreenImage,
+    /// 深度バッファの深度のアスペクトだけのビュー
+    pub depth_view: vk::ImageView,
+    pub descriptor_pool: vk::DescriptorPool,
+    pub descriptor_set: vk::DescriptorSet,
+    pub extent: vk::Extent2D,
+}
+
+impl CameraEffectTargets {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        device.destroy_descriptor_pool(self.descriptor_pool, None);
+        device.destroy_image_view(self.depth_view, None);
+        self.output.destroy(device, allocator);
+    }
+}
+
+/// `enable_camera_effects`で作る被写界深度とモーションブラーのリソース
+pub struct CameraEffects {
+    pub depth_of_field_pipeline: ComputePipeline,
+    pub motion_blur_pipeline: ComputePipeline,
+    pub sampler: vk::Sampler,
+    pub targets: CameraEffectTargets,
+    /// `set_camera_effect_projection`で設定する投影行列
+    pub projection: Mat4,
+    /// 前のフレームのジッターなしのビュー射影行列
+    pub previous_view_projection: Mat4,
+}
+
+impl CameraEffects {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        self.depth_of_field_pipeline.destroy(device);
+        self.motion_blur_pipeline.destroy(device);
+        device.destroy_sampler(self.sampler, None);
+        self.targets.destroy(device, allocator);
+    }
+}
+
+impl Renderer {
+    /// 被写界深度とモーションブラーのデスクリプタセットレイアウト
+    ///
+    /// バインディング0がHDRの色、1が深度、2が出力のストレージイメージ。
+    pub fn camera_effect_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        let stage = vk::ShaderStageFlags::COMPUTE;
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, stage),
+            DescriptorBinding::sampled_image(1, stage),
+            DescriptorBinding::new(2, vk::DescriptorType::STORAGE_IMAGE, stage),
+        ])
+    }
+
+    /// HDRのレンダーターゲットにかける被写界深度とモーションブラーを有効にする
+    ///
+    /// すでに有効な場合は作り直す。`enable_hdr`の後で呼ぶ。フレームごとのオンとオフは
+    /// `set_post_process`で切り替える。深度をサンプリングするのでMSAAとは併用できない。
+    pub fn enable_camera_effects(&mut self, shaders: CameraEffectShaders) -> Result<()> {
+        self.enabled_hdr()?;
+        if self.msaa_samples != vk::SampleCountFlags::TYPE_1 {
+            return Err(RendererError::Validation(
+                "depth of field and motion blur cannot be combined with MSAA".to_owned(),
+            ));
+        }
+        let layout = self.camera_effect_set_layout()?;
+        let mut pipelines = Vec::with_capacity(2);
+        for (shader, push_constant_size) in [
+            (
+                shaders.depth_of_field,
+                std::mem::size_of::<DepthOfFieldPushConstants>(),
+            ),
+            (
+                shaders.motion_blur,
+                std::mem::size_of::<MotionBlurPushConstants>(),
+            ),
+        ] {
+            match self.create_compute_pipeline(shader, "main", &[layout], push_constant_size as u32)
+            {
+                Ok(pipeline) => pipelines.push(pipeline),
+                Err(err) => {
+                    for pipeline in pipelines {
+                        self.destroy_compute_pipeline(pipeline);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        let resources = self
+            .create_sampler(&SamplerDesc::nearest())
+            .and_then(|sampler| {
+                let targets = unsafe { self.create_camera_effect_targets(sampler) };
+                match targets {
+                    Ok(targets) => Ok((sampler, targets)),
+                    Err(err) => {
+                        unsafe { self.device.destroy_sampler(sampler, None) };
+                        Err(err)
+                    }
+                }
+            });
+        let (sampler, targets) = match resources {
+            Ok(resources) => resources,
+            Err(err) => {
+                for pipeline in pipelines {
+                    self.destroy_compute_pipeline(pipeline);
+                }
+                return Err(err);
+            }
+        };
+        let motion_blur_pipeline = pipelines.pop().unwrap();
+        let depth_of_field_pipeline = pipelines.pop().unwrap();
+        let projection = self
+            .camera_effects
+            .as_ref()
+            .map_or(MAT4_IDENTITY, |effects| effects.projection);
+        let camera_effects = CameraEffects {
+            depth_of_field_pipeline,
+            motion_blur_pipeline,
+            sampler,
+            targets,
+            projection,
+            previous_view_projection: self.view_projection,
+        };
+        if let Some(old) = self.camera_effects.replace(camera_effects) {
+            self.destroy_deferred(move |device, allocator| unsafe {
+                old.destroy(device, allocator)
+            });
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してから被写界深度とモーションブラーのリソースを破棄する
+    pub fn disable_camera_effects(&mut self) {
+        if let Some(camera_effects) = self.camera_effects.take() {
+            self.destroy_deferred(move |device, allocator| unsafe {
+                camera_effects.destroy(device, allocator)
+            });
+        }
+    }
+
+    pub fn camera_effects(&self) -> Option<&CameraEffects> {
+        self.camera_effects.as_ref()
+    }
+
+    /// 深度から距離を求めるための投影行列を設定する。深度を描いたカメラのものを渡す
+    pub fn set_camera_effect_projection(&mut self, projection: Mat4) -> Result<()> {
+        if mat4_inverse(&projection).is_none() {
+            return Err(RendererError::Validation(
+                "projection matrix is not invertible".to_owned(),
+            ));
+        }
+        let camera_effects = self.camera_effects.as_mut().ok_or_else(|| {
+            RendererError::Validation("camera effects are not enabled".to_owned())
+        })?;
+        camera_effects.projection = projection;
+        Ok(())
+    }
+
+    /// モーションブラーの前のフレームを捨てる。カメラが切り替わったときなどに呼ぶ
+    pub fn reset_motion_blur(&mut self) {
+        let view_projection = self.view_projection;
+        if let Some(camera_effects) = &mut self.camera_effects {
+            camera_effects.previous_view_projection = view_projection;
+        }
+    }
+
+    /// HDRのターゲットに合わせて出力を作り直す
+    ///
+    /// 古いリソースは使用中のフレームが完了してから破棄する。
+    pub(crate) fn recreate_camera_effect_targets(&mut self) -> Result<()> {
+        let Some(sampler) = self.camera_effects.as_ref().map(|effects| effects.sampler) else {
+            return Ok(());
+        };
+        let targets = unsafe { self.create_camera_effect_targets(sampler)? };
+        let old = std::mem::replace(&mut self.camera_effects.as_mut().unwrap().targets, targets);
+        self.destroy_deferred(move |device, allocator| unsafe { old.destroy(device, allocator) });
+        Ok(())
+    }
+
+    /// 設定で有効な被写界深度とモーションブラーを記録し、前のフレームの行列を進める
+    pub(crate) fn apply_camera_effects(&mut self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        let Some(camera_effects) = &self.camera_effects else {
+            return Ok(());
+        };
+        let hdr_image = self.enabled_hdr()?.target.image;
+        let settings = self.view_post_process();
+        let depth_of_field = settings.depth_of_field;
+        let motion_blur = settings.motion_blur;
+        let mut passes = Vec::with_capacity(2);
+        if depth_of_field.enabled && self.is_pass_enabled("depth_of_field") {
+            let inverse = mat4_inverse(&camera_effects.projection).ok_or_else(|| {
+                RendererError::Validation("projection matrix is not invertible".to_owned())
+            })?;
+            let height = camera_effects.targets.extent.height as f32;
+            let f = depth_of_field.focal_length;
+            // 錯乱円の直径 f^2 / (N (S - f)) * |z - S| / z をセンサーの高さでピクセルの半径にする
+            let coc_scale = f * f / (depth_of_field.f_number * (depth_of_field.focus_distance - f))
+                * 0.5
+                / depth_of_field.sensor_height
+                * height;
+            let push_constants = DepthOfFieldPushConstants {
+                depth_params: [inverse[2][2], inverse[3][2], inverse[2][3], inverse[3][3]],
+                focus_distance: depth_of_field.focus_distance,
+                coc_scale,
+                max_coc_radius: depth_of_field.max_coc_radius,
+            };
+            passes.push((
+                &camera_effects.depth_of_field_pipeline,
+                push_constants.bytes(),
+            ));
+        }
+        if motion_blur.enabled && self.is_pass_enabled("motion_blur") {
+            let inverse = mat4_inverse(&self.view_projection).ok_or_else(|| {
+                RendererError::Validation("view projection matrix is not invertible".to_owned())
+            })?;
+            let push_constants = MotionBlurPushConstants {
+                reprojection: mat4_mul(&camera_effects.previous_view_projection, &inverse),
+                shutter: motion_blur.shutter,
+                sample_count: motion_blur.sample_count,
+                max_blur_radius: motion_blur.max_blur_radius,
+            };
+            passes.push((&camera_effects.motion_blur_pipeline, push_constants.bytes()));
+        }
+        let targets = &camera_effects.targets;
+        let groups = [
+            targets.extent.width.div_ceil(CAMERA_EFFECT_GROUP_SIZE),
+            targets.extent.height.div_ceil(CAMERA_EFFECT_GROUP_SIZE),
+            1,
+        ];
+        for (pipeline, push_constants) in passes {
+            unsafe {
+                self.begin_screen_pass(
+                    command_buffer,
+                    &[targets.output.image],
+                    Some(self.depth_image),
+                );
+            }
+            self.dispatch(
+                command_buffer,
+                pipeline,
+                &[targets.descriptor_set],
+                &push_constants,
+                groups,
+            )?;
+            unsafe {
+                self.restore_depth_attachment(command_buffer, &[]);
+                self.copy_to_hdr(
+                    command_buffer,
+                    targets.output.image,
+                    hdr_image,
+                    targets.extent,
+                );
+            }
+        }
+        let view_projection = self.view_projection;
+        self.camera_effects
+            .as_mut()
+            .unwrap()
+            .previous_view_projection = view_projection;
+        Ok(())
+    }
+
+    /// # Safety
+    /// HDRが有効であること
+    unsafe fn create_camera_effect_targets(
+        &mut self,
+        sampler: vk::Sampler,
+    ) -> Result<CameraEffectTargets> {
+        let layout = self.camera_effect_set_layout()?;
+        let hdr = self.enabled_hdr()?;
+        let hdr_view = hdr.target.view;
+        let extent = hdr.target.extent;
+        let output = create_screen_image(
+            &self.device,
+            &mut self.allocator,
+            HDR_FORMAT,
+            vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC,
+            extent,
+        )?;
+        let mut targets = CameraEffectTargets {
+            output,
+            depth_view: vk::ImageView::null(),
+            descriptor_pool: vk::DescriptorPool::null(),
+            descriptor_set: vk::DescriptorSet::null(),
+            extent,
+        };
+        match self.init_camera_effect_targets(&mut targets, layout, sampler, hdr_view) {
+            Ok(()) => Ok(targets),
+            Err(err) => {
+                targets.destroy(&self.device, &mut self.allocator);
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn init_camera_effect_targets(
+        &self,
+        targets: &mut CameraEffectTargets,
+        layout: vk::DescriptorSetLayout,
+        sampler: vk::Sampler,
+        hdr_view: vk::ImageView,
+    ) -> Result<()> {
+        targets.depth_view =
+            create_depth_sampling_view(&self.device, self.depth_image, self.depth_format)?;
+        targets.descriptor_pool = create_pool(
+            &self.device,
+            1,
+            &[
+                (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 2.0),
+                (vk::DescriptorType::STORAGE_IMAGE, 1.0),
+            ],
+        )?;
+        targets.descriptor_set = allocate_set(&self.device, targets.descriptor_pool, layout)?;
+        DescriptorWriter::new()
+            .image(
+                0,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                vk::DescriptorImageInfo {
+                    sampler,
+                    image_view: hdr_view,
+                    image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                },
+            )
+            .depth_image(1, targets.depth_view, sampler)
+            .image(
+                2,
+                vk::DescriptorType::STORAGE_IMAGE,
+                vk::DescriptorImageInfo {
+                    sampler: vk::Sampler::null(),
+                    image_view: targets.output.view,
+                    image_layout: vk::ImageLayout::GENERAL,
+                },
+            )
+            .update(&self.device, targets.descriptor_set);
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+use super::error::{RendererError, Result};
+use super::{
+    BufferAccess, BufferId, Camera, ComputePipeline, DescriptorBinding, DescriptorWriter, Mat4,
+    Projection, Renderer, ShaderId,
+};
+use ash::{vk, Device};
+
+/// カリングのシェーダーのワークグループの大きさ。1スレッドが1クラスターを受け持つ
+pub const CLUSTER_CULL_GROUP_SIZE: u32 = 64;
+
+/// クラスターのバッファの先頭の`cluster_grid`と`cluster_params`のバイト数
+const CLUSTER_HEADER_SIZE: vk::DeviceSize = 32;
+
+/// クラスター化したライトのカリングのコンピュートシェーダー
+///
+/// 視錐台を画面のタイルと指数的に分けた深度で3次元の格子に分け、クラスターごとに
+/// 影響するライトの番号の一覧を作る。セット0が`light_set_layout`、セット1が
+/// `cluster_set_layout`。ディレクショナルライトと範囲のないライトはすべてのクラスターに入る。
+///
+/// ```glsl
+/// layout(local_size_x = 64) in;
+/// layout(set = 0, binding = 0) uniform Lights { uvec4 light_count; Light lights[MAX_LIGHTS]; };
+/// layout(set = 1, binding = 0) buffer Clusters {
+///     uvec4 cluster_grid;    // xyz: 分割数、w: クラスターあたりの最大のライトの数
+///     vec4 cluster_params;   // x: near、y: far、zw: 1 / 画面の大きさ
+///     uint cluster_lights[]; // クラスターごとに、ライトの数に続けてw個のライトの番号
+/// };
+/// layout(push_constant) uniform Params {
+///     mat4 view; vec2 projection_scale; float near; float far; uvec4 grid; vec2 inverse_screen_size;
+/// };
+/// void main() {
+///     uint index = gl_GlobalInvocationID.x;
+///     if (index == 0) {
+///         cluster_grid = grid;
+///         cluster_params = vec4(near, far, inverse_screen_size);
+///     }
+///     if (index >= grid.x * grid.y * grid.z) return;
+///     uvec3 c = uvec3(index % grid.x, index / grid.x % grid.y, index / (grid.x * grid.y));
+///     float z0 = near * pow(far / near, float(c.z) / float(grid.z));
+///     float z1 = near * pow(far / near, float(c.z + 1) / float(grid.z));
+///     // NDCのタイルの端を、深度1あたりのビュー空間の位置にする
+///     vec2 a = (vec2(c.xy) / vec2(grid.xy) * 2.0 - 1.0) / projection_scale;
+///     vec2 b = (vec2(c.xy + 1) / vec2(grid.xy) * 2.0 - 1.0) / projection_scale;
+///     vec3 aabb_min = vec3(min(min(a * z0, a * z1), min(b * z0, b * z1)), -z1);
+///     vec3 aabb_max = vec3(max(max(a * z0, a * z1), max(b * z0, b * z1)), -z0);
+///     uint offset = tempura_cluster_offset(index, grid);
+///     uint count = 0;
+///     for (uint i = 0; i < light_count.x && count < grid.w; ++i) {
+///         float range = lights[i].position_range.w;
+///         if (lights[i].direction_type.w != 0.0 && range > 0.0) {
+///             vec3 p = (view * vec4(lights[i].position_range.xyz, 1.0)).xyz;
+///             vec3 d = p - clamp(p, aabb_min, aabb_max);
+///             if (dot(d, d) > range * range) continue;
+///         }
+///         cluster_lights[offset + 1 + count++] = i;
+///     }
+///     cluster_lights[offset] = count;
+/// }
+/// ```
+///
+/// フォワードのシェーダーは`tempura/clustered.glsl`で自分のクラスターを求め、その
+/// ライトだけを回す。
+///
+/// ```glsl
+/// uint cluster = tempura_cluster_index(gl_FragCoord.xy, view_depth, cluster_grid, cluster_params);
+/// uint offset = tempura_cluster_offset(cluster, cluster_grid);
+/// for (uint i = 0; i < cluster_lights[offset]; ++i) {
+///     Light light = lights[cluster_lights[offset + 1 + i]];
+///     // ...
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClusteredLightingShaders {
+    pub cull: ShaderId,
+}
+
+/// クラスターの分け方
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClusterSettings {
+    /// 横、縦、深度の分割数
+    pub grid: [u32; 3],
+    /// 1つのクラスターに入れるライトの数の上限。超えた分は番号の小さいものから入れる
+    pub max_lights_per_cluster: u32,
+    /// 遠くのクリップ面がない透視投影で、クラスターで分ける深度の範囲
+    pub max_distance: f32,
+}
+
+impl Default for ClusterSettings {
+    fn default() -> Self {
+        Self {
+            grid: [16, 9, 24],
+            max_lights_per_cluster: 64,
+            max_distance: 1000.0,
+        }
+    }
+}
+
+impl ClusterSettings {
+    pub fn cluster_count(&self) -> u32 {
+        self.grid.iter().product()
+    }
+}
+
+/// カリングのシェーダーに渡すプッシュ定数
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClusterCullPushConstants {
+    pub view: Mat4,
+    /// 射影行列の`m[0][0]`と`m[1][1]`
+    pub projection_scale: [f32; 2],
+    pub near: f32,
+    pub far: f32,
+    /// xyz: 分割数、w: クラスターあたりの最大のライトの数
+    pub grid: [u32; 4],
+    pub inverse_screen_size: [f32; 2],
+}
+
+impl ClusterCullPushConstants {
+    fn bytes(&self) -> Vec<u8> {
+        self.view
+            .iter()
+            .flatten()
+            .chain(self.projection_scale.iter())
+            .chain([self.near, self.far].iter())
+            .flat_map(|value| value.to_ne_bytes())
+            .chain(self.grid.iter().flat_map(|value| value.to_ne_bytes()))
+            .chain(
+                self.inverse_screen_size
+                    .iter()
+                    .flat_map(|value| value.to_ne_bytes()),
+            )
+            .collect()
+    }
+}
+
+/// `enable_clustered_lighting`で作るクラスターのリソース
+pub struct ClusteredLighting {
+    pub settings: ClusterSettings,
+    pub pipeline: ComputePipeline,
+    /// フレームコンテキストごとのクラスターのバッファ。`Renderer`のバッファとして別に破棄される
+    pub buffers: Vec<BufferId>,
+    /// `cluster_set_layout`のデスクリプタセット。`buffers`と同じ順
+    pub descriptor_sets: Vec<vk::DescriptorSet>,
+}
+
+impl ClusteredLighting {
+    /// # Safety
+    /// `device`は作成に使ったデバイスで、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device) {
+        self.pipeline.destroy(device);
+    }
+}
+
+impl Renderer {
+    /// クラスターのデスクリプタセットレイアウト。バインディング0がクラスターのバッファ
+    pub fn cluster_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[DescriptorBinding::storage_buffer(
+            0,
+            vk::ShaderStageFlags::COMPUTE | vk::ShaderStageFlags::FRAGMENT,
+        )])
+    }
+
+    /// クラスター化したライトのカリングを有効にする
+    ///
+    /// 毎フレーム`cull_lights`でクラスターごとのライトの一覧を作り、フォワードのパスで
+    /// そのデスクリプタセットをバインドする。
+    pub fn enable_clustered_lighting(
+        &mut self,
+        shaders: ClusteredLightingShaders,
+        settings: ClusterSettings,
+    ) -> Result<()> {
+        validate_settings(&settings)?;
+        let light_layout = self.light_set_layout()?;
+        let cluster_layout = self.cluster_set_layout()?;
+        let pipeline = self.create_compute_pipeline(
+            shaders.cull,
+            "main",
+            &[light_layout, cluster_layout],
+            std::mem::size_of::<ClusterCullPushConstants>() as u32,
+        )?;
+        let size = CLUSTER_HEADER_SIZE
+            + settings.cluster_count() as vk::DeviceSize
+                * (settings.max_lights_per_cluster as vk::DeviceSize + 1)
+                * 4;
+        let mut buffers = Vec::with_capacity(self.frames.len());
+        let mut descriptor_sets = Vec::with_capacity(self.frames.len());
+        for _ in 0..self.frames.len() {
+            let resources = self
+                .create_buffer(
+                    size,
+                    vk::BufferUsageFlags::STORAGE_BUFFER,
+                    vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                )
+                .and_then(
+                    |buffer| match self.allocate_descriptor_set(cluster_layout) {
+                        Ok(descriptor_set) => Ok((buffer, descriptor_set)),
+                        Err(err) => {
+                            self.destroy_buffer(buffer)?;
+                            Err(err)
+                        }
+                    },
+                );
+            match resources {
+                Ok((buffer, descriptor_set)) => {
+                    unsafe {
+                        DescriptorWriter::new()
+                            .buffer(
+                                0,
+                                vk::DescriptorType::STORAGE_BUFFER,
+                                self.buffers.get(buffer).unwrap(),
+                            )
+                            .update(&self.device, descriptor_set);
+                    }
+                    buffers.push(buffer);
+                    descriptor_sets.push(descriptor_set);
+                }
+                Err(err) => {
+                    for buffer in buffers {
+                        self.destroy_buffer(buffer)?;
+                    }
+                    self.destroy_compute_pipeline(pipeline);
+                    return Err(err);
+                }
+            }
+        }
+        let clustered_lighting = ClusteredLighting {
+            settings,
+            pipeline,
+            buffers,
+            descriptor_sets,
+        };
+        if let Some(old) = self.clustered_lighting.replace(clustered_lighting) {
+            self.destroy_clustered_lighting(old)?;
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからクラスターのリソースを破棄する
+    pub fn disable_clustered_lighting(&mut self) -> Result<()> {
+        match self.clustered_lighting.take() {
+            Some(clustered_lighting) => self.destroy_clustered_lighting(clustered_lighting),
+            None => Ok(()),
+        }
+    }
+
+    pub fn clustered_lighting(&self) -> Option<&ClusteredLighting> {
+        self.clustered_lighting.as_ref()
+    }
+
+    /// `camera`の視錐台をクラスターに分け、`light_set`のライトを振り分ける
+    ///
+    /// `light_set`は`light_descriptor_set`が返したもの。レンダーパスの外で、クラスターを
+    /// 使う描画の前に呼ぶ。返したデスクリプタセットは記録中のフレームの間だけ有効。
+    /// 透視投影のカメラだけに対応する。
+    pub fn cull_lights(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        light_set: vk::DescriptorSet,
+        camera: &Camera,
+    ) -> Result<vk::DescriptorSet> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "lights can only be culled between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        let clustered_lighting = self.clustered_lighting.as_ref().ok_or_else(|| {
+            RendererError::Validation("clustered lighting is not enabled".to_owned())
+        })?;
+        let settings = &clustered_lighting.settings;
+        let Projection::Perspective { near, far, .. } = camera.projection else {
+            return Err(RendererError::Validation(
+                "clustered lighting needs a perspective camera".to_owned(),
+            ));
+        };
+        let projection = camera.projection_matrix();
+        let extent = self.surface_resolution;
+        let [x, y, z] = settings.grid;
+        let push_constants = ClusterCullPushConstants {
+            view: camera.view_matrix(),
+            projection_scale: [projection[0][0], projection[1][1]],
+            near,
+            far: far.unwrap_or(settings.max_distance),
+            grid: [x, y, z, settings.max_lights_per_cluster],
+            inverse_screen_size: [1.0 / extent.width as f32, 1.0 / extent.height as f32],
+        };
+        let buffer = clustered_lighting.buffers[self.current_frame];
+        let descriptor_set = clustered_lighting.descriptor_sets[self.current_frame];
+        // 同じフレームで前のビューの描画が読み終わるのを待つ
+        self.buffer_barrier(
+            command_buffer,
+            buffer,
+            BufferAccess::FRAGMENT_SHADER_READ,
+            BufferAccess::COMPUTE_SHADER_WRITE,
+        )?;
+        self.dispatch(
+            command_buffer,
+            &clustered_lighting.pipeline,
+            &[light_set, descriptor_set],
+            &push_constants.bytes(),
+            [
+                settings.cluster_count().div_ceil(CLUSTER_CULL_GROUP_SIZE),
+                1,
+                1,
+            ],
+        )?;
+        self.buffer_barrier(
+            command_buffer,
+            buffer,
+            BufferAccess::COMPUTE_SHADER_WRITE,
+            BufferAccess::FRAGMENT_SHADER_READ,
+        )?;
+        Ok(descriptor_set)
+    }
+
+    fn destroy_clustered_lighting(&mut self, clustered_lighting: ClusteredLighting) -> Result<()> {
+        let buffers = clustered_lighting.buffers.clone();
+        self.destroy_deferred(move |device, _| unsafe { clustered_lighting.destroy(device) });
+        buffers
+            .into_iter()
+            .try_for_each(|buffer| self.destroy_buffer(buffer))
+    }
+}
+
+fn validate_settings(settings: &ClusterSettings) -> Result<()> {
+    if settings.grid.contains(&0)
+        || settings.max_lights_per_cluster == 0
+        || !settings.max_distance.is_finite()
+        || settings.max_distance <= 0.0
+    {
+        return Err(RendererError::Validation(format!(
+            "invalid cluster settings: {:?}",
+            settings
+        )));
+    }
+    Ok(())
+}
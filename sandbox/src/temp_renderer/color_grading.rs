@@ -0,0 +1,187 @@
+use super::assets::ColorGradingLut;
+use super::color_vision::filter_lut;
+use super::descriptor::{allocate_set, create_pool};
+use super::error::{RendererError, Result};
+use super::{
+    DescriptorBinding, DescriptorWriter, Renderer, SamplerDesc, TextureDesc, TextureId, TextureKind,
+};
+use ash::{vk, Device};
+
+/// LUTが指定されていないときに使う、色を変えないLUTの1辺の格子点の数
+///
+/// 線形補間で恒等変換を正確に表せるので、小さくてよい。
+pub const NEUTRAL_LUT_SIZE: u32 = 2;
+
+/// トーンマッピングで使うカラーグレーディングのLUT(セット1)
+///
+/// `texture`は`Renderer`のテクスチャとして破棄する。
+pub struct ColorGrading {
+    pub texture: TextureId,
+    pub size: u32,
+    /// 指定されたLUTがなく、色を変えないLUTを使っている
+    pub neutral: bool,
+    /// `set_color_grading_lut`で指定されたLUT。色覚のフィルターを変えたときに焼き込み直す
+    pub source: Option<ColorGradingLut>,
+    pub descriptor_pool: vk::DescriptorPool,
+    /// `color_grading_set_layout`のデスクリプタセット
+    pub descriptor_set: vk::DescriptorSet,
+}
+
+impl ColorGrading {
+    /// # Safety
+    /// `device`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device) {
+        device.destroy_descriptor_pool(self.descriptor_pool, None);
+    }
+}
+
+impl Renderer {
+    /// カラーグレーディングのデスクリプタセットレイアウト。バインディング0が3D LUT
+    pub fn color_grading_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[DescriptorBinding::sampled_image(
+            0,
+            vk::ShaderStageFlags::FRAGMENT,
+        )])
+    }
+
+    /// トーンマッピングの後にかける3D LUTを設定する。`None`なら色を変えないLUTに戻す
+    ///
+    /// 次の`tone_map`から反映される。古いLUTは使用中のフレームが完了してから破棄する。
+    /// 色覚のフィルターが設定されていれば、LUTの出力にフィルターをかけたものを使う。
+    pub fn set_color_grading_lut(&mut self, lut: Option<&ColorGradingLut>) -> Result<()> {
+        self.enabled_hdr()?;
+        let color_grading = self.create_filtered_color_grading(lut)?;
+        if let Some(old) = self.color_grading.replace(color_grading) {
+            self.destroy_color_grading(old)?;
+        }
+        Ok(())
+    }
+
+    pub fn color_grading(&self) -> Option<&ColorGrading> {
+        self.color_grading.as_ref()
+    }
+
+    /// `enable_hdr`から呼ぶ。LUTがまだなければ色を変えないLUTを作る
+    pub(crate) fn ensure_color_grading(&mut self) -> Result<()> {
+        if self.color_grading.is_none() {
+            self.color_grading = Some(self.create_filtered_color_grading(None)?);
+        }
+        Ok(())
+    }
+
+    /// 指定されたLUTはそのままで、現在の色覚のフィルターでLUTを作り直す
+    pub(crate) fn rebuild_color_grading(&mut self) -> Result<()> {
+        let source = self
+            .color_grading
+            .as_ref()
+            .and_then(|color_grading| color_grading.source.clone());
+        let color_grading = self.create_filtered_color_grading(source.as_ref())?;
+        if let Some(old) = self.color_grading.replace(color_grading) {
+            self.destroy_color_grading(old)?;
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからLUTを破棄する
+    pub(crate) fn disable_color_grading(&mut self) -> Result<()> {
+        match self.color_grading.take() {
+            Some(color_grading) => self.destroy_color_grading(color_grading),
+            None => Ok(()),
+        }
+    }
+
+    fn create_filtered_color_grading(
+        &mut self,
+        lut: Option<&ColorGradingLut>,
+    ) -> Result<ColorGrading> {
+        let mut color_grading = match (lut, self.color_vision_filter) {
+            (lut, Some(filter)) => self.create_color_grading(&filter_lut(&filter, lut), false)?,
+            (Some(lut), None) => self.create_color_grading(lut, false)?,
+            (None, None) => {
+                self.create_color_grading(&ColorGradingLut::neutral(NEUTRAL_LUT_SIZE), true)?
+            }
+        };
+        color_grading.source = lut.cloned();
+        Ok(color_grading)
+    }
+
+    fn create_color_grading(
+        &mut self,
+        lut: &ColorGradingLut,
+        neutral: bool,
+    ) -> Result<ColorGrading> {
+        let size = lut.size;
+        if size < 2 || lut.pixels.len() != (size as usize).pow(3) {
+            return Err(RendererError::Validation(format!(
+                "a {}^3 LUT needs {} entries, got {}",
+                size,
+                (size as usize).pow(3),
+                lut.pixels.len()
+            )));
+        }
+        let layout = self.color_grading_set_layout()?;
+        let texture = self.create_texture(&TextureDesc {
+            kind: TextureKind::Texture3D { depth: size },
+            format: vk::Format::R16G16B16A16_SFLOAT,
+            width: size,
+            height: size,
+            mip_levels: 1,
+            sampler: SamplerDesc::clamp_to_edge(),
+            storage: false,
+        })?;
+        let result = lut
+            .to_rgba16f_slices()
+            .iter()
+            .enumerate()
+            .try_for_each(|(slice, data)| self.write_texture_layer(texture, slice as u32, 0, data))
+            .and_then(|_| unsafe {
+                let pool = create_pool(
+                    &self.device,
+                    1,
+                    &[(vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1.0)],
+                )?;
+                match allocate_set(&self.device, pool, layout) {
+                    Ok(set) => Ok((pool, set)),
+                    Err(err) => {
+                        self.device.destroy_descriptor_pool(pool, None);
+                        Err(err.into())
+                    }
+                }
+            });
+        let (descriptor_pool, descriptor_set) = match result {
+            Ok(resources) => resources,
+            Err(err) => {
+                self.destroy_texture(texture)?;
+                return Err(err);
+            }
+        };
+        let texture_info = self.textures.get(texture).unwrap();
+        unsafe {
+            DescriptorWriter::new()
+                .image(
+                    0,
+                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    vk::DescriptorImageInfo {
+                        sampler: texture_info.sampler,
+                        image_view: texture_info.view,
+                        image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    },
+                )
+                .update(&self.device, descriptor_set);
+        }
+        Ok(ColorGrading {
+            texture,
+            size,
+            neutral,
+            source: None,
+            descriptor_pool,
+            descriptor_set,
+        })
+    }
+
+    fn destroy_color_grading(&mut self, color_grading: ColorGrading) -> Result<()> {
+        let texture = color_grading.texture;
+        self.destroy_deferred(move |device, _| unsafe { color_grading.destroy(device) });
+        self.destroy_texture(texture)
+    }
+}
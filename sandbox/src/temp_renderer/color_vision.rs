@@ -0,0 +1,239 @@
+use super::assets::ColorGradingLut;
+use super::error::{RendererError, Result};
+use super::{mat4_mul, Mat4, Renderer, MAT4_IDENTITY};
+
+/// 色覚のフィルターをかけるLUTの1辺の格子点の数
+pub const COLOR_VISION_LUT_SIZE: u32 = 32;
+
+/// 色覚の多様性に配慮したUIの配色(Okabe-Ito)。sRGBの8ビット
+///
+/// 黒、オレンジ、空色、青緑、黄、青、朱、赤紫の順。どの色覚でも互いに区別しやすい。
+pub const UI_SAFE_PALETTE: [[u8; 3]; 8] = [
+    [0, 0, 0],
+    [230, 159, 0],
+    [86, 180, 233],
+    [0, 158, 115],
+    [240, 228, 66],
+    [0, 114, 178],
+    [213, 94, 0],
+    [204, 121, 167],
+];
+
+/// 2色覚の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorVisionDeficiency {
+    /// 1型。L錐体がない
+    Protanopia,
+    /// 2型。M錐体がない
+    Deuteranopia,
+    /// 3型。S錐体がない
+    Tritanopia,
+}
+
+impl ColorVisionDeficiency {
+    /// Machadoらのモデルの、程度が最大のときのリニアなRGBの変換。`[行][列]`
+    fn simulation(self) -> [[f32; 3]; 3] {
+        match self {
+            ColorVisionDeficiency::Protanopia => [
+                [0.152286, 1.052583, -0.204868],
+                [0.114503, 0.786281, 0.099216],
+                [-0.003882, -0.048116, 1.051998],
+            ],
+            ColorVisionDeficiency::Deuteranopia => [
+                [0.367322, 0.860646, -0.227968],
+                [0.280085, 0.672501, 0.047413],
+                [-0.011820, 0.042940, 0.968881],
+            ],
+            ColorVisionDeficiency::Tritanopia => [
+                [1.255528, -0.076749, -0.178779],
+                [-0.078411, 0.930809, 0.147602],
+                [0.004733, 0.691367, 0.303900],
+            ],
+        }
+    }
+
+    /// 見分けられない差を、見分けられるチャンネルに移す変換。`[行][列]`
+    fn error_shift(self) -> [[f32; 3]; 3] {
+        match self {
+            ColorVisionDeficiency::Protanopia | ColorVisionDeficiency::Deuteranopia => {
+                [[0.0, 0.0, 0.0], [0.7, 1.0, 0.0], [0.7, 0.0, 1.0]]
+            }
+            ColorVisionDeficiency::Tritanopia => {
+                [[1.0, 0.0, 0.7], [0.0, 1.0, 0.7], [0.0, 0.0, 0.0]]
+            }
+        }
+    }
+}
+
+/// フィルターの働き
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorVisionMode {
+    /// その色覚での見え方を再現する。開発中の確認用
+    Simulate,
+    /// 見分けにくい色の差を見分けやすい色に移す(Daltonization)。プレイヤー向けの設定
+    Compensate,
+}
+
+/// フィルターをかける範囲
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorVisionScope {
+    /// トーンマッピングで3Dのシーンだけにかける
+    Scene,
+    /// シーンに加えて、UIのシェーダーで`color_vision_ui_matrix`を掛けて画面全体にかける
+    FullFrame,
+}
+
+/// 色覚のフィルター
+///
+/// リニアなRGBに掛ける3x3の行列で表す。シーンにはカラーグレーディングのLUTに焼き込んでかけるので、
+/// トーンマッピングのシェーダーを変える必要はない。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorVisionFilter {
+    pub deficiency: ColorVisionDeficiency,
+    pub mode: ColorVisionMode,
+    /// 0から1。0なら色を変えず、1で2色覚になる
+    pub severity: f32,
+    pub scope: ColorVisionScope,
+}
+
+impl ColorVisionFilter {
+    pub fn simulate(deficiency: ColorVisionDeficiency) -> Self {
+        Self {
+            deficiency,
+            mode: ColorVisionMode::Simulate,
+            severity: 1.0,
+            scope: ColorVisionScope::Scene,
+        }
+    }
+
+    pub fn compensate(deficiency: ColorVisionDeficiency) -> Self {
+        Self {
+            deficiency,
+            mode: ColorVisionMode::Compensate,
+            severity: 1.0,
+            scope: ColorVisionScope::FullFrame,
+        }
+    }
+
+    /// リニアなRGBに掛ける行列。4行目と4列目は恒等変換
+    pub fn matrix(&self) -> Mat4 {
+        let t = self.severity.clamp(0.0, 1.0);
+        let simulation = to_mat4(self.deficiency.simulation());
+        // 程度は恒等変換との線形補間で近似する
+        let simulation = combine(&MAT4_IDENTITY, &simulation, |i, s| i + (s - i) * t);
+        match self.mode {
+            ColorVisionMode::Simulate => simulation,
+            ColorVisionMode::Compensate => {
+                // c + E * (c - S * c)
+                let error = combine(&MAT4_IDENTITY, &simulation, |i, s| i - s);
+                let shift = mat4_mul(&to_mat4(self.deficiency.error_shift()), &error);
+                combine(&MAT4_IDENTITY, &shift, |i, s| i + s)
+            }
+        }
+    }
+
+    /// sRGBのガンマをかけた0から1の色にフィルターをかける。UIの配色の確認などに使う
+    pub fn apply_srgb(&self, color: [f32; 3]) -> [f32; 3] {
+        apply_srgb(&self.matrix(), color)
+    }
+}
+
+fn to_mat4(rows: [[f32; 3]; 3]) -> Mat4 {
+    let mut m = MAT4_IDENTITY;
+    for (row, values) in rows.iter().enumerate() {
+        for (col, &value) in values.iter().enumerate() {
+            m[col][row] = value;
+        }
+    }
+    m
+}
+
+fn combine(a: &Mat4, b: &Mat4, f: impl Fn(f32, f32) -> f32) -> Mat4 {
+    let mut m = [[0.0; 4]; 4];
+    for col in 0..4 {
+        for row in 0..4 {
+            m[col][row] = f(a[col][row], b[col][row]);
+        }
+    }
+    m
+}
+
+fn apply_srgb(matrix: &Mat4, color: [f32; 3]) -> [f32; 3] {
+    let linear = color.map(srgb_to_linear);
+    [0, 1, 2].map(|row| {
+        let value: f32 = (0..3).map(|col| matrix[col][row] * linear[col]).sum();
+        linear_to_srgb(value.clamp(0.0, 1.0))
+    })
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// `lut`の出力にフィルターをかけたLUT。`lut`がなければ`COLOR_VISION_LUT_SIZE`の色を変えないLUTにかける
+pub(crate) fn filter_lut(
+    filter: &ColorVisionFilter,
+    lut: Option<&ColorGradingLut>,
+) -> ColorGradingLut {
+    let matrix = filter.matrix();
+    let lut = lut
+        .cloned()
+        .unwrap_or_else(|| ColorGradingLut::neutral(COLOR_VISION_LUT_SIZE));
+    ColorGradingLut {
+        size: lut.size,
+        pixels: lut
+            .pixels
+            .iter()
+            .map(|&color| apply_srgb(&matrix, color))
+            .collect(),
+    }
+}
+
+impl Renderer {
+    /// 色覚のフィルターを設定する。`None`なら外す
+    ///
+    /// シーンにはカラーグレーディングのLUTに焼き込んでかけるので、HDRが有効なら次の`tone_map`から
+    /// 反映される。HDRが無効な間は設定だけを保ち、`enable_hdr`で反映する。
+    pub fn set_color_vision_filter(&mut self, filter: Option<ColorVisionFilter>) -> Result<()> {
+        if let Some(filter) = &filter {
+            if !(0.0..=1.0).contains(&filter.severity) {
+                return Err(RendererError::Validation(format!(
+                    "color vision severity {} is outside 0 to 1",
+                    filter.severity
+                )));
+            }
+        }
+        self.color_vision_filter = filter;
+        if self.hdr.is_some() {
+            self.rebuild_color_grading()?;
+        }
+        Ok(())
+    }
+
+    pub fn color_vision_filter(&self) -> Option<&ColorVisionFilter> {
+        self.color_vision_filter.as_ref()
+    }
+
+    /// UIのシェーダーでリニアな色に掛ける行列
+    ///
+    /// フィルターが`ColorVisionScope::FullFrame`のときだけフィルターの行列になり、
+    /// それ以外は恒等変換。`tempura/color_vision.glsl`の`tempura_color_vision`に渡す。
+    pub fn color_vision_ui_matrix(&self) -> Mat4 {
+        match &self.color_vision_filter {
+            Some(filter) if filter.scope == ColorVisionScope::FullFrame => filter.matrix(),
+            _ => MAT4_IDENTITY,
+        }
+    }
+}
@@ -0,0 +1,389 @@
+use super::error::{RendererError, Result};
+use super::texture_format::sampled_aspect_mask;
+use super::{Buffer, BufferAccess, BufferId, ImageAccess, Renderer, ShaderId, TextureId};
+use ash::{vk, Device};
+use std::ffi::CString;
+
+/// `vkCmdDispatchIndirect`が読む引数1つ分のバイト数
+pub const DISPATCH_INDIRECT_COMMAND_SIZE: vk::DeviceSize =
+    std::mem::size_of::<vk::DispatchIndirectCommand>() as vk::DeviceSize;
+
+pub struct ComputePipeline {
+    pub pipeline: vk::Pipeline,
+    pub layout: vk::PipelineLayout,
+    /// プッシュ定数のバイト数。0ならプッシュ定数を使わない
+    pub push_constant_size: u32,
+}
+
+impl ComputePipeline {
+    /// # Safety
+    /// `device`は作成に使ったデバイスで、GPUがこのパイプラインを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device) {
+        device.destroy_pipeline(self.pipeline, None);
+        device.destroy_pipeline_layout(self.layout, None);
+    }
+}
+
+impl Renderer {
+    /// `shader`の`entry_point`を実行するコンピュートパイプラインを作成する
+    pub fn create_compute_pipeline(
+        &self,
+        shader: ShaderId,
+        entry_point: &str,
+        set_layouts: &[vk::DescriptorSetLayout],
+        push_constant_size: u32,
+    ) -> Result<ComputePipeline> {
+        self.create_compute_pipeline_with_constants(
+            shader,
+            entry_point,
+            set_layouts,
+            push_constant_size,
+            &[],
+        )
+    }
+
+    /// `create_compute_pipeline`と同じだが、`constants[i]`を`constant_id = i`の
+    /// 特殊化定数に設定する
+    ///
+    /// `layout(local_size_x_id = 0) in;`のようにすると、ワークグループの大きさを
+    /// シェーダーを作り直さずに変えられる。
+    pub fn create_compute_pipeline_with_constants(
+        &self,
+        shader: ShaderId,
+        entry_point: &str,
+        set_layouts: &[vk::DescriptorSetLayout],
+        push_constant_size: u32,
+        constants: &[u32],
+    ) -> Result<ComputePipeline> {
+        let module = self
+            .shader_modules
+            .get(shader)
+            .ok_or_else(|| {
+                RendererError::Validation(format!("shader {:?} was already destroyed", shader))
+            })?
+            .module;
+        if !push_constant_size.is_multiple_of(4) {
+            return Err(RendererError::Validation(format!(
+                "push constant size {} must be a multiple of 4",
+                push_constant_size
+            )));
+        }
+        let entry_point = CString::new(entry_point).map_err(|_| {
+            RendererError::Validation(format!("invalid entry point name {:?}", entry_point))
+        })?;
+
+        unsafe {
+            let push_constant_ranges = [vk::PushConstantRange {
+                stage_flags: vk::ShaderStageFlags::COMPUTE,
+                offset: 0,
+                size: push_constant_size,
+            }];
+            let mut layout_create_info =
+                vk::PipelineLayoutCreateInfo::builder().set_layouts(set_layouts);
+            if push_constant_size > 0 {
+                layout_create_info = layout_create_info.push_constant_ranges(&push_constant_ranges);
+            }
+            let layout = self
+                .device
+                .create_pipeline_layout(&layout_create_info, None)?;
+
+            let map_entries: Vec<_> = (0..constants.len() as u32)
+                .map(|id| vk::SpecializationMapEntry {
+                    constant_id: id,
+                    offset: id * 4,
+                    size: 4,
+                })
+                .collect();
+            let data: Vec<u8> = constants.iter().flat_map(|c| c.to_ne_bytes()).collect();
+            let specialization_info = *vk::SpecializationInfo::builder()
+                .map_entries(&map_entries)
+                .data(&data);
+            let mut stage = vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::COMPUTE)
+                .module(module)
+                .name(&entry_point);
+            if !constants.is_empty() {
+                stage = stage.specialization_info(&specialization_info);
+            }
+            let stage = *stage;
+            let pipeline_create_info = *vk::ComputePipelineCreateInfo::builder()
+                .stage(stage)
+                .layout(layout);
+            let pipeline = match self.device.create_compute_pipelines(
+                vk::PipelineCache::null(),
+                &[pipeline_create_info],
+                None,
+            ) {
+                Ok(pipelines) => pipelines[0],
+                Err((_, err)) => {
+                    self.device.destroy_pipeline_layout(layout, None);
+                    return Err(err.into());
+                }
+            };
+            Ok(ComputePipeline {
+                pipeline,
+                layout,
+                push_constant_size,
+            })
+        }
+    }
+
+    /// 使用中のフレームが完了してからパイプラインを破棄する
+    pub fn destroy_compute_pipeline(&mut self, pipeline: ComputePipeline) {
+        self.destroy_deferred(move |device, _| unsafe { pipeline.destroy(device) });
+    }
+
+    /// `count`個の間接ディスパッチ引数を持つDEVICE_LOCALのバッファを作成する
+    ///
+    /// コンピュートシェーダーからの書き込みと、`init_dispatch_args`での初期化ができる。
+    pub fn create_dispatch_args_buffer(&mut self, count: u32) -> Result<BufferId> {
+        self.create_buffer(
+            DISPATCH_INDIRECT_COMMAND_SIZE * count as vk::DeviceSize,
+            vk::BufferUsageFlags::INDIRECT_BUFFER
+                | vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::TRANSFER_DST,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )
+    }
+
+    /// `buffer`の`offset`にディスパッチ引数を書き込むコマンドを記録する
+    ///
+    /// 生成側のパスがアトミック加算でグループ数を数える場合は、`[0, 1, 1]`などで初期化する。
+    /// 書き込みは転送ステージで行われるので、使う側とは`BufferAccess::TRANSFER_WRITE`で同期する。
+    pub fn init_dispatch_args(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        buffer: BufferId,
+        offset: vk::DeviceSize,
+        group_count: [u32; 3],
+    ) -> Result<()> {
+        let buffer =
+            self.dispatch_args_buffer(buffer, offset, vk::BufferUsageFlags::TRANSFER_DST)?;
+        let data: Vec<u8> = group_count.iter().flat_map(|n| n.to_ne_bytes()).collect();
+        unsafe {
+            self.device
+                .cmd_update_buffer(command_buffer, buffer.buffer, offset, &data);
+        }
+        Ok(())
+    }
+
+    /// `buffer`の`offset`から`size`バイトを0で埋めるコマンドを記録する
+    ///
+    /// `size`に`vk::WHOLE_SIZE`を指定するとバッファの終わりまで埋める。
+    pub fn zero_buffer(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        buffer: BufferId,
+        offset: vk::DeviceSize,
+        size: vk::DeviceSize,
+    ) -> Result<()> {
+        let buffer = self.buffers.get(buffer).ok_or_else(|| {
+            RendererError::Validation(format!("buffer {:?} was destroyed", buffer))
+        })?;
+        let in_range = size == vk::WHOLE_SIZE || offset + size <= buffer.size;
+        if !offset.is_multiple_of(4)
+            || offset >= buffer.size
+            || !in_range
+            || (size != vk::WHOLE_SIZE && !size.is_multiple_of(4))
+        {
+            return Err(RendererError::Validation(format!(
+                "fill range {}+{} must be 4-byte aligned and within the buffer's {} bytes",
+                offset, size, buffer.size
+            )));
+        }
+        unsafe {
+            self.device
+                .cmd_fill_buffer(command_buffer, buffer.buffer, offset, size, 0);
+        }
+        Ok(())
+    }
+
+    /// コンピュートパイプラインで`group_count`個のワークグループを実行する
+    ///
+    /// `descriptor_sets`はセット0から順にバインドする。
+    pub fn dispatch(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        pipeline: &ComputePipeline,
+        descriptor_sets: &[vk::DescriptorSet],
+        push_constants: &[u8],
+        group_count: [u32; 3],
+    ) -> Result<()> {
+        self.bind_compute(command_buffer, pipeline, descriptor_sets, push_constants)?;
+        let [x, y, z] = group_count;
+        unsafe {
+            self.device.cmd_dispatch(command_buffer, x, y, z);
+        }
+        Ok(())
+    }
+
+    /// `dispatch`と同じだが、ワークグループ数を`buffer`の`offset`から読む
+    ///
+    /// 引数を書き込んだパスとの間には、レンダーグラフで`BufferAccess::INDIRECT_BUFFER`を
+    /// 宣言するか、`buffer_barrier`でバリアを張ること。
+    pub fn dispatch_indirect(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        pipeline: &ComputePipeline,
+        descriptor_sets: &[vk::DescriptorSet],
+        push_constants: &[u8],
+        buffer: BufferId,
+        offset: vk::DeviceSize,
+    ) -> Result<()> {
+        let buffer =
+            self.dispatch_args_buffer(buffer, offset, vk::BufferUsageFlags::INDIRECT_BUFFER)?;
+        self.bind_compute(command_buffer, pipeline, descriptor_sets, push_constants)?;
+        unsafe {
+            self.device
+                .cmd_dispatch_indirect(command_buffer, buffer.buffer, offset);
+        }
+        Ok(())
+    }
+
+    /// レンダーグラフの外で、`src`の書き込みを`dst`から見えるようにするバリアを記録する
+    pub fn buffer_barrier(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        buffer: BufferId,
+        src: BufferAccess,
+        dst: BufferAccess,
+    ) -> Result<()> {
+        let buffer = self.buffers.get(buffer).ok_or_else(|| {
+            RendererError::Validation(format!("buffer {:?} was destroyed", buffer))
+        })?;
+        let barrier = *vk::BufferMemoryBarrier::builder()
+            .src_access_mask(src.access)
+            .dst_access_mask(dst.access)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(buffer.buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE);
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                src.stage,
+                dst.stage,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[barrier],
+                &[],
+            );
+        }
+        Ok(())
+    }
+
+    /// レンダーグラフの外で、テクスチャの全てのミップとレイヤーを`src`から`dst`に遷移させるバリアを記録する
+    ///
+    /// `storage`で作ったテクスチャにコンピュートシェーダーから書き込むときは
+    /// `ImageAccess::COMPUTE_SHADER_WRITE`に遷移させる。`DescriptorWriter::texture`で
+    /// サンプリングする前に、`SHADER_READ_ONLY_OPTIMAL`のアクセスに戻すこと。
+    pub fn texture_barrier(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        texture: TextureId,
+        src: ImageAccess,
+        dst: ImageAccess,
+    ) -> Result<()> {
+        let texture = self.textures.get(texture).ok_or_else(|| {
+            RendererError::Validation(format!("texture {:?} was destroyed", texture))
+        })?;
+        let barrier = *vk::ImageMemoryBarrier::builder()
+            .src_access_mask(src.access)
+            .dst_access_mask(dst.access)
+            .old_layout(src.layout)
+            .new_layout(dst.layout)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(texture.image)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: sampled_aspect_mask(texture.format),
+                base_mip_level: 0,
+                level_count: vk::REMAINING_MIP_LEVELS,
+                base_array_layer: 0,
+                layer_count: vk::REMAINING_ARRAY_LAYERS,
+            });
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                src.stage,
+                dst.stage,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier],
+            );
+        }
+        Ok(())
+    }
+
+    fn dispatch_args_buffer(
+        &self,
+        id: BufferId,
+        offset: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+    ) -> Result<&Buffer> {
+        let buffer = self
+            .buffers
+            .get(id)
+            .ok_or_else(|| RendererError::Validation(format!("buffer {:?} was destroyed", id)))?;
+        if !buffer.usage.contains(usage) {
+            return Err(RendererError::Validation(format!(
+                "buffer {:?} requires {:?} usage",
+                id, usage
+            )));
+        }
+        if !offset.is_multiple_of(4) || offset + DISPATCH_INDIRECT_COMMAND_SIZE > buffer.size {
+            return Err(RendererError::Validation(format!(
+                "dispatch arguments at offset {} must be 4-byte aligned and within the buffer",
+                offset
+            )));
+        }
+        Ok(buffer)
+    }
+
+    fn bind_compute(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        pipeline: &ComputePipeline,
+        descriptor_sets: &[vk::DescriptorSet],
+        push_constants: &[u8],
+    ) -> Result<()> {
+        if !push_constants.len().is_multiple_of(4)
+            || push_constants.len() as u32 > pipeline.push_constant_size
+        {
+            return Err(RendererError::Validation(format!(
+                "{} bytes of push constants must be a multiple of 4 within the pipeline's {} bytes",
+                push_constants.len(),
+                pipeline.push_constant_size
+            )));
+        }
+        unsafe {
+            self.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                pipeline.pipeline,
+            );
+            if !descriptor_sets.is_empty() {
+                self.device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    pipeline.layout,
+                    0,
+                    descriptor_sets,
+                    &[],
+                );
+            }
+            if !push_constants.is_empty() {
+                self.device.cmd_push_constants(
+                    command_buffer,
+                    pipeline.layout,
+                    vk::ShaderStageFlags::COMPUTE,
+                    0,
+                    push_constants,
+                );
+            }
+        }
+        Ok(())
+    }
+}
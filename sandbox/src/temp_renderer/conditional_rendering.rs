@@ -0,0 +1,136 @@
+use super::error::{RendererError, Result};
+use super::{BufferId, Renderer, RendererConfig};
+use ash::{vk, Device, Instance};
+use std::ffi::{c_void, CStr};
+
+/// VK_EXT_conditional_renderingのコマンドの呼び出し先
+#[derive(Clone)]
+pub struct ConditionalRendering {
+    fp: vk::ExtConditionalRenderingFn,
+}
+
+impl ConditionalRendering {
+    pub fn name() -> &'static CStr {
+        vk::ExtConditionalRenderingFn::name()
+    }
+
+    /// # Safety
+    ///
+    /// `device`が`instance`から作成され、拡張を有効にしていること。
+    pub unsafe fn new(instance: &Instance, device: &Device) -> Self {
+        let fp = vk::ExtConditionalRenderingFn::load(|name| {
+            std::mem::transmute::<vk::PFN_vkVoidFunction, *const c_void>(
+                instance.get_device_proc_addr(device.handle(), name.as_ptr()),
+            )
+        });
+        Self { fp }
+    }
+
+    /// # Safety
+    ///
+    /// `begin_info`の内容が有効で、`command_buffer`が記録中であること。
+    pub unsafe fn cmd_begin_conditional_rendering(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        begin_info: &vk::ConditionalRenderingBeginInfoEXT,
+    ) {
+        (self.fp.cmd_begin_conditional_rendering_ext)(command_buffer, begin_info);
+    }
+
+    /// # Safety
+    ///
+    /// `command_buffer`で条件付きレンダリングを開始していること。
+    pub unsafe fn cmd_end_conditional_rendering(&self, command_buffer: vk::CommandBuffer) {
+        (self.fp.cmd_end_conditional_rendering_ext)(command_buffer);
+    }
+}
+
+/// 拡張と機能の両方に対応していれば`true`
+pub(crate) unsafe fn query_conditional_rendering_support(
+    instance: &Instance,
+    pdevice: vk::PhysicalDevice,
+    config: &RendererConfig,
+) -> Result<bool> {
+    // 機能の問い合わせにvkGetPhysicalDeviceFeatures2を使う
+    if config.api_version < vk::API_VERSION_1_1 {
+        return Ok(false);
+    }
+    let available_extensions = instance.enumerate_device_extension_properties(pdevice)?;
+    let has_extension = available_extensions.iter().any(|extension| {
+        CStr::from_ptr(extension.extension_name.as_ptr()) == ConditionalRendering::name()
+    });
+    if !has_extension {
+        return Ok(false);
+    }
+
+    let mut conditional_rendering_features =
+        vk::PhysicalDeviceConditionalRenderingFeaturesEXT::default();
+    let mut features =
+        vk::PhysicalDeviceFeatures2::builder().push_next(&mut conditional_rendering_features);
+    instance.get_physical_device_features2(pdevice, &mut features);
+    Ok(conditional_rendering_features.conditional_rendering == vk::TRUE)
+}
+
+impl Renderer {
+    /// `buffer`の`offset`にある32ビット値が0でない場合だけ、以降の描画とディスパッチを実行する
+    ///
+    /// `inverted`なら0の場合だけ実行する。値はオクルージョンクエリの結果を
+    /// `cmd_copy_query_pool_results`で書き込むなど、GPU側で用意する。書き込みとの同期には
+    /// レンダーグラフの`BufferAccess::CONDITIONAL_RENDERING_READ`を使う。
+    /// 条件付きレンダリングが有効でない場合は`RendererError::Validation`を返す。
+    pub fn begin_conditional(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        buffer: BufferId,
+        offset: vk::DeviceSize,
+        inverted: bool,
+    ) -> Result<()> {
+        let conditional_rendering = self.conditional_rendering()?;
+        let buffer = self.buffers.get(buffer).ok_or_else(|| {
+            RendererError::Validation("conditional rendering buffer was destroyed".to_owned())
+        })?;
+        if !buffer
+            .usage
+            .contains(vk::BufferUsageFlags::CONDITIONAL_RENDERING_EXT)
+        {
+            return Err(RendererError::Validation(
+                "conditional rendering buffer requires CONDITIONAL_RENDERING_EXT usage".to_owned(),
+            ));
+        }
+        if !offset.is_multiple_of(4) || offset + 4 > buffer.size {
+            return Err(RendererError::Validation(format!(
+                "conditional rendering offset {} must be 4-byte aligned and within the buffer",
+                offset
+            )));
+        }
+
+        let flags = if inverted {
+            vk::ConditionalRenderingFlagsEXT::INVERTED
+        } else {
+            vk::ConditionalRenderingFlagsEXT::empty()
+        };
+        let begin_info = *vk::ConditionalRenderingBeginInfoEXT::builder()
+            .buffer(buffer.buffer)
+            .offset(offset)
+            .flags(flags);
+        unsafe {
+            conditional_rendering.cmd_begin_conditional_rendering(command_buffer, &begin_info);
+        }
+        Ok(())
+    }
+
+    /// `begin_conditional`で開始した範囲を終える
+    pub fn end_conditional(&self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        let conditional_rendering = self.conditional_rendering()?;
+        unsafe {
+            conditional_rendering.cmd_end_conditional_rendering(command_buffer);
+        }
+        Ok(())
+    }
+
+    fn conditional_rendering(&self) -> Result<&ConditionalRendering> {
+        self.conditional_rendering.as_ref().ok_or_else(|| {
+            RendererError::Validation("conditional rendering is not enabled".to_owned())
+        })
+    }
+}
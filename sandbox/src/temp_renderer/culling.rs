@@ -0,0 +1,568 @@
+use super::scene::{Scene, ScenePass};
+use super::{Camera, Mat4, MeshId, NodeId, Renderer, VertexLayout};
+use ash::vk;
+
+/// BVHの葉に入れるノードの最大数
+const BVH_LEAF_SIZE: usize = 4;
+
+/// 軸に平行な境界ボックス
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+impl Aabb {
+    /// 何も含まない箱。`union`の初期値に使う
+    pub const EMPTY: Self = Self {
+        min: [f32::INFINITY; 3],
+        max: [f32::NEG_INFINITY; 3],
+    };
+
+    pub fn from_points(points: impl IntoIterator<Item = [f32; 3]>) -> Self {
+        points.into_iter().fold(Self::EMPTY, |aabb, point| Self {
+            min: [0, 1, 2].map(|i| aabb.min[i].min(point[i])),
+            max: [0, 1, 2].map(|i| aabb.max[i].max(point[i])),
+        })
+    }
+
+    /// 頂点の位置を囲む箱
+    ///
+    /// 位置はロケーション0の`R32G32B32_SFLOAT`か`R32G32B32A32_SFLOAT`の属性とする。
+    /// そのような属性がないか、頂点がなければ`None`。
+    pub fn from_vertices<V: Copy>(vertices: &[V], layout: &VertexLayout) -> Option<Self> {
+        let position = layout.attributes.iter().find(|attribute| {
+            attribute.location == 0
+                && matches!(
+                    attribute.format,
+                    vk::Format::R32G32B32_SFLOAT | vk::Format::R32G32B32A32_SFLOAT
+                )
+        })?;
+        let offset = position.offset as usize;
+        if vertices.is_empty() || offset + 12 > std::mem::size_of::<V>() {
+            return None;
+        }
+        Some(Self::from_points(vertices.iter().map(|vertex| unsafe {
+            (vertex as *const V as *const u8)
+                .add(offset)
+                .cast::<[f32; 3]>()
+                .read_unaligned()
+        })))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        (0..3).any(|i| self.min[i] > self.max[i])
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            min: [0, 1, 2].map(|i| self.min[i].min(other.min[i])),
+            max: [0, 1, 2].map(|i| self.max[i].max(other.max[i])),
+        }
+    }
+
+    pub fn center(&self) -> [f32; 3] {
+        [0, 1, 2].map(|i| (self.min[i] + self.max[i]) * 0.5)
+    }
+
+    /// `matrix`で変換した箱を囲む箱
+    pub fn transform(&self, matrix: &Mat4) -> Self {
+        if self.is_empty() {
+            return *self;
+        }
+        let center = self.center();
+        let extent = [0, 1, 2].map(|i| (self.max[i] - self.min[i]) * 0.5);
+        let mut new_center = [matrix[3][0], matrix[3][1], matrix[3][2]];
+        let mut new_extent = [0.0; 3];
+        for (row, (c, e)) in new_center.iter_mut().zip(new_extent.iter_mut()).enumerate() {
+            for col in 0..3 {
+                *c += matrix[col][row] * center[col];
+                *e += matrix[col][row].abs() * extent[col];
+            }
+        }
+        Self {
+            min: [0, 1, 2].map(|i| new_center[i] - new_extent[i]),
+            max: [0, 1, 2].map(|i| new_center[i] + new_extent[i]),
+        }
+    }
+}
+
+/// 視錐台の6枚の平面。`dot(n, p) + d >= 0`が内側
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frustum {
+    pub planes: [[f32; 4]; 6],
+}
+
+/// 箱と視錐台の関係
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Containment {
+    Outside,
+    Intersecting,
+    Inside,
+}
+
+impl Frustum {
+    /// ビュー射影行列から平面を取り出す。深度が0から1のクリップ空間とする
+    ///
+    /// 逆深度や無限遠の射影でも使える。無限遠の場合、遠い平面はどの点も内側になる。
+    pub fn from_view_projection(m: &Mat4) -> Self {
+        let row = |i: usize| [m[0][i], m[1][i], m[2][i], m[3][i]];
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+        let add = |a: [f32; 4], b: [f32; 4]| [0, 1, 2, 3].map(|i| a[i] + b[i]);
+        let sub = |a: [f32; 4], b: [f32; 4]| [0, 1, 2, 3].map(|i| a[i] - b[i]);
+        Self {
+            planes: [
+                add(r3, r0),
+                sub(r3, r0),
+                add(r3, r1),
+                sub(r3, r1),
+                r2,
+                sub(r3, r2),
+            ],
+        }
+    }
+
+    pub fn from_camera(camera: &Camera) -> Self {
+        Self::from_view_projection(&camera.view_projection_matrix())
+    }
+
+    /// 箱が視錐台と重なるか。判定は保守的で、角の近くでは外の箱も重なるとみなすことがある
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        self.classify(aabb) != Containment::Outside
+    }
+
+    fn classify(&self, aabb: &Aabb) -> Containment {
+        let mut containment = Containment::Inside;
+        for plane in self.planes.iter() {
+            // 法線の向きで最も内側の角と最も外側の角を調べる
+            let (mut far, mut near) = (plane[3], plane[3]);
+            for (i, &n) in plane[..3].iter().enumerate() {
+                let (a, b) = if n >= 0.0 {
+                    (aabb.max[i], aabb.min[i])
+                } else {
+                    (aabb.min[i], aabb.max[i])
+                };
+                far += n * a;
+                near += n * b;
+            }
+            if far < 0.0 {
+                return Containment::Outside;
+            }
+            if near < 0.0 {
+                containment = Containment::Intersecting;
+            }
+        }
+        containment
+    }
+}
+
+/// カリングの統計情報。`Renderer::culling_stats`でフレームごとに合計する
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CullingStats {
+    /// 視錐台と判定したBVHのノードの数
+    pub bvh_nodes_tested: u32,
+    /// カリングの対象にしたメッシュの数
+    pub meshes_tested: u32,
+    /// 視錐台の外にあって描かないメッシュの数
+    pub meshes_culled: u32,
+}
+
+impl CullingStats {
+    pub fn meshes_visible(&self) -> u32 {
+        self.meshes_tested - self.meshes_culled
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.bvh_nodes_tested += other.bvh_nodes_tested;
+        self.meshes_tested += other.meshes_tested;
+        self.meshes_culled += other.meshes_culled;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BvhNode {
+    bounds: Aabb,
+    /// `items`の範囲。子があれば2つの子の範囲を合わせたもの
+    start: usize,
+    count: usize,
+    /// 左右の子の位置
+    children: Option<[usize; 2]>,
+}
+
+/// シーンのメッシュを持つノードのワールド空間の箱の階層
+///
+/// `Scene::update_world_matrices`で変換やメッシュが変わったときに作り直す。
+#[derive(Debug, Clone, Default)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    items: Vec<(NodeId, Aabb)>,
+    /// 箱のないメッシュのノード。常に描く
+    unbounded: Vec<NodeId>,
+}
+
+impl Bvh {
+    /// 箱の中心の最も長い軸の中央値で分けて作る
+    pub fn build(items: impl IntoIterator<Item = (NodeId, Option<Aabb>)>) -> Self {
+        let mut bvh = Self::default();
+        for (id, bounds) in items {
+            match bounds {
+                Some(bounds) => bvh.items.push((id, bounds)),
+                None => bvh.unbounded.push(id),
+            }
+        }
+        if !bvh.items.is_empty() {
+            bvh.build_node(0, bvh.items.len());
+        }
+        bvh
+    }
+
+    /// メッシュを持つノードの数
+    pub fn len(&self) -> usize {
+        self.items.len() + self.unbounded.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty() && self.unbounded.is_empty()
+    }
+
+    /// ルートの箱。箱を持つノードがなければ`None`
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.nodes.first().map(|node| node.bounds)
+    }
+
+    /// 視錐台と重なるノードを`visit`に渡す。`filter`で除いたノードは数えない
+    fn cull(
+        &self,
+        frustum: &Frustum,
+        filter: impl Fn(NodeId) -> bool,
+        mut visit: impl FnMut(NodeId),
+    ) -> CullingStats {
+        let mut stats = CullingStats::default();
+        for &id in self.unbounded.iter().filter(|&&id| filter(id)) {
+            stats.meshes_tested += 1;
+            visit(id);
+        }
+        if self.nodes.is_empty() {
+            return stats;
+        }
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            stats.bvh_nodes_tested += 1;
+            let containment = frustum.classify(&node.bounds);
+            match (containment, node.children) {
+                (Containment::Intersecting, Some([left, right])) => {
+                    stack.push(right);
+                    stack.push(left);
+                }
+                (Containment::Intersecting, None) => {
+                    for &(id, bounds) in &self.items[node.start..node.start + node.count] {
+                        if !filter(id) {
+                            continue;
+                        }
+                        stats.meshes_tested += 1;
+                        if frustum.intersects(&bounds) {
+                            visit(id);
+                        } else {
+                            stats.meshes_culled += 1;
+                        }
+                    }
+                }
+                // 全体が外か内なら、子を調べずにまとめて決める
+                (containment, _) => {
+                    for &(id, _) in &self.items[node.start..node.start + node.count] {
+                        if !filter(id) {
+                            continue;
+                        }
+                        stats.meshes_tested += 1;
+                        if containment == Containment::Inside {
+                            visit(id);
+                        } else {
+                            stats.meshes_culled += 1;
+                        }
+                    }
+                }
+            }
+        }
+        stats
+    }
+
+    fn build_node(&mut self, start: usize, count: usize) -> usize {
+        let items = &mut self.items[start..start + count];
+        let bounds = items
+            .iter()
+            .fold(Aabb::EMPTY, |bounds, (_, aabb)| bounds.union(aabb));
+        let index = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds,
+            start,
+            count,
+            children: None,
+        });
+        if count <= BVH_LEAF_SIZE {
+            return index;
+        }
+        let size = [0, 1, 2].map(|i| bounds.max[i] - bounds.min[i]);
+        let axis = (0..3).fold(0, |axis, i| if size[i] > size[axis] { i } else { axis });
+        let half = count / 2;
+        items.select_nth_unstable_by(half, |(_, a), (_, b)| {
+            a.center()[axis].total_cmp(&b.center()[axis])
+        });
+        let left = self.build_node(start, half);
+        let right = self.build_node(start + half, count - half);
+        self.nodes[index].children = Some([left, right]);
+        index
+    }
+}
+
+impl Scene {
+    /// `frustum`と重なり、`pass`で描くメッシュを持つノードとそのワールド変換
+    ///
+    /// 最後の`update_world_matrices`の時点のBVHを使う。
+    pub fn cull_meshes(
+        &self,
+        frustum: &Frustum,
+        pass: ScenePass,
+    ) -> (Vec<(NodeId, MeshId, &Mat4)>, CullingStats) {
+        let mut meshes = Vec::new();
+        let stats = self.bvh().cull(
+            frustum,
+            |id| {
+                self.node(id)
+                    .is_some_and(|node| node.mesh.is_some() && node.render_mode.is_drawn_in(pass))
+            },
+            |id| {
+                let node = self.node(id).unwrap();
+                meshes.push((id, node.mesh.unwrap(), node.world_matrix()));
+            },
+        );
+        (meshes, stats)
+    }
+}
+
+impl Renderer {
+    /// カメラの視錐台でシーンのメッシュをカリングし、統計情報を今のフレームに足す
+    ///
+    /// 描く前に`Scene::update_world_matrices`を呼んでおく。影のパスは光源の視錐台で
+    /// `Scene::cull_meshes`を使う。
+    pub fn cull_scene<'a>(
+        &mut self,
+        scene: &'a Scene,
+        camera: &Camera,
+        pass: ScenePass,
+    ) -> Vec<(NodeId, MeshId, &'a Mat4)> {
+        let (meshes, stats) = scene.cull_meshes(&Frustum::from_camera(camera), pass);
+        if self.culling_stats_frame != self.frame_count {
+            self.culling_stats = CullingStats::default();
+            self.culling_stats_frame = self.frame_count;
+        }
+        self.culling_stats.add(&stats);
+        meshes
+    }
+
+    /// 今のフレームの`cull_scene`の統計情報の合計
+    pub fn culling_stats(&self) -> CullingStats {
+        if self.culling_stats_frame == self.frame_count {
+            self.culling_stats
+        } else {
+            CullingStats::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::memory::Allocation;
+    use super::super::{Buffer, Mesh, Pool, Projection, RenderMode, Transform};
+    use super::*;
+    use ash::vk;
+    use std::collections::HashSet;
+
+    fn aabb(center: [f32; 3], half_size: f32) -> Aabb {
+        Aabb {
+            min: center.map(|c| c - half_size),
+            max: center.map(|c| c + half_size),
+        }
+    }
+
+    /// 原点から-Zを向き、近い平面が1、遠い平面が100のカメラ
+    fn camera(reversed_z: bool) -> Camera {
+        Camera {
+            projection: Projection::Perspective {
+                fov_y: 90f32.to_radians(),
+                near: 1.0,
+                far: Some(100.0),
+            },
+            aspect: 1.0,
+            reversed_z,
+            ..Camera::default()
+        }
+    }
+
+    #[test]
+    fn frustum_classifies_boxes() {
+        for reversed_z in [false, true] {
+            let frustum = Frustum::from_camera(&camera(reversed_z));
+            let cases = [
+                (aabb([0.0, 0.0, -10.0], 1.0), Containment::Inside),
+                (aabb([3.0, -3.0, -50.0], 2.0), Containment::Inside),
+                // 後ろ、遠い平面の先、視野の左右上下の外
+                (aabb([0.0, 0.0, 10.0], 1.0), Containment::Outside),
+                (aabb([0.0, 0.0, -200.0], 1.0), Containment::Outside),
+                (aabb([30.0, 0.0, -10.0], 1.0), Containment::Outside),
+                (aabb([0.0, -30.0, -10.0], 1.0), Containment::Outside),
+                // 近い平面、遠い平面、右の平面にまたがる
+                (aabb([0.0, 0.0, -1.0], 0.5), Containment::Intersecting),
+                (aabb([0.0, 0.0, -100.0], 1.0), Containment::Intersecting),
+                (aabb([10.0, 0.0, -10.0], 1.0), Containment::Intersecting),
+            ];
+            for (bounds, expected) in cases {
+                assert_eq!(frustum.classify(&bounds), expected, "{:?}", bounds);
+                assert_eq!(
+                    frustum.intersects(&bounds),
+                    expected != Containment::Outside
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn infinite_frustum_has_no_far_plane() {
+        let camera = Camera {
+            projection: Projection::Perspective {
+                fov_y: 90f32.to_radians(),
+                near: 1.0,
+                far: None,
+            },
+            reversed_z: true,
+            ..camera(true)
+        };
+        let frustum = Frustum::from_camera(&camera);
+        assert!(frustum.intersects(&aabb([0.0, 0.0, -1.0e5], 1.0)));
+        assert!(!frustum.intersects(&aabb([0.0, 0.0, 10.0], 1.0)));
+    }
+
+    #[test]
+    fn bvh_matches_brute_force() {
+        let mut scene = Scene::new();
+        // 決まった系列の擬似乱数で箱をばらまく
+        let mut state = 12345u32;
+        let mut random = move || {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 8) as f32 / (1 << 24) as f32
+        };
+        let mut items = Vec::new();
+        for i in 0..300 {
+            let id = scene
+                .add_node(&i.to_string(), Transform::default(), None)
+                .unwrap();
+            let center = [0, 1, 2].map(|_| random() * 240.0 - 120.0);
+            items.push((id, aabb(center, random() * 5.0)));
+        }
+        let unbounded = scene
+            .add_node("unbounded", Transform::default(), None)
+            .unwrap();
+        let bvh = Bvh::build(
+            items
+                .iter()
+                .map(|&(id, bounds)| (id, Some(bounds)))
+                .chain([(unbounded, None)]),
+        );
+        assert_eq!(bvh.len(), items.len() + 1);
+
+        for yaw in [0.0f32, 1.0, 2.5, 4.0] {
+            let frustum = Frustum::from_camera(&Camera {
+                yaw,
+                pitch: 0.3,
+                ..camera(false)
+            });
+            let mut visible = HashSet::new();
+            let stats = bvh.cull(
+                &frustum,
+                |_| true,
+                |id| {
+                    visible.insert(id);
+                },
+            );
+            let mut expected: HashSet<_> = items
+                .iter()
+                .filter(|(_, bounds)| frustum.intersects(bounds))
+                .map(|&(id, _)| id)
+                .collect();
+            expected.insert(unbounded);
+            assert_eq!(visible, expected, "yaw {}", yaw);
+            assert_eq!(stats.meshes_tested as usize, items.len() + 1);
+            assert_eq!(stats.meshes_visible() as usize, expected.len());
+            assert!((stats.bvh_nodes_tested as usize) < items.len());
+        }
+    }
+
+    #[test]
+    fn bvh_filter_skips_nodes() {
+        let mut scene = Scene::new();
+        let ids: Vec<_> = (0..10)
+            .map(|i| {
+                scene
+                    .add_node(&i.to_string(), Transform::default(), None)
+                    .unwrap()
+            })
+            .collect();
+        let bvh = Bvh::build(
+            ids.iter()
+                .enumerate()
+                .map(|(i, &id)| (id, Some(aabb([i as f32, 0.0, -10.0], 0.5)))),
+        );
+        let frustum = Frustum::from_camera(&camera(false));
+        let mut visible = Vec::new();
+        let stats = bvh.cull(&frustum, |id| id != ids[0], |id| visible.push(id));
+        assert_eq!(stats.meshes_tested, 9);
+        assert!(!visible.contains(&ids[0]));
+    }
+
+    #[test]
+    fn scene_culls_shared_meshes_by_pooled_bounds() {
+        let mut buffers = Pool::new();
+        let vertex_buffer = buffers.insert(Buffer {
+            buffer: vk::Buffer::null(),
+            allocation: Allocation::external(0),
+            size: 0,
+            usage: vk::BufferUsageFlags::VERTEX_BUFFER,
+            external: true,
+        });
+        let mut meshes = Pool::new();
+        let mesh = meshes.insert(Mesh {
+            vertex_buffer,
+            index_buffer: None,
+            index_type: vk::IndexType::UINT32,
+            vertex_layout: VertexLayout::new(12),
+            vertex_count: 0,
+            index_count: 0,
+            submeshes: Vec::new(),
+            bounds: Some(aabb([0.0; 3], 0.5)),
+        });
+        let mut scene = Scene::new();
+        let mut add = |name: &str, z: f32, render_mode: RenderMode| {
+            let id = scene
+                .add_node(name, Transform::from_translation([0.0, 0.0, z]), None)
+                .unwrap();
+            let node = scene.node_mut(id).unwrap();
+            node.mesh = Some(mesh);
+            node.render_mode = render_mode;
+            id
+        };
+        let front = add("front", -10.0, RenderMode::Visible);
+        add("behind", 10.0, RenderMode::Visible);
+        let shadow = add("shadow", -20.0, RenderMode::ShadowOnly);
+        scene.update_world_matrices(&meshes);
+        assert_eq!(scene.meshes().filter(|&(_, id, _)| id == mesh).count(), 3);
+
+        let frustum = Frustum::from_camera(&camera(false));
+        let (main, _) = scene.cull_meshes(&frustum, ScenePass::Main);
+        let main: Vec<_> = main.iter().map(|&(id, mesh, _)| (id, mesh)).collect();
+        assert_eq!(main, [(front, mesh)]);
+        let (shadows, _) = scene.cull_meshes(&frustum, ScenePass::Shadow);
+        let mut shadows: Vec<_> = shadows.iter().map(|&(id, _, _)| id).collect();
+        shadows.sort_by_key(|id| id.index());
+        assert_eq!(shadows, [front, shadow]);
+    }
+}
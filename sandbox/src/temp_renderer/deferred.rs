@@ -0,0 +1,589 @@
+use super::descriptor::{allocate_set, create_pool};
+use super::error::{RendererError, Result};
+use super::memory::MemoryAllocator;
+use super::renderer::{create_depth_sampling_view, depth_aspect_mask};
+use super::texture::{create_screen_image, ScreenImage};
+use super::{
+    BlendMode, DescriptorBinding, DescriptorWriter, GraphicsPipeline, PipelineBuilder, Renderer,
+    RenderingAttachment, SamplerDesc, ShaderId, HDR_FORMAT,
+};
+use ash::{vk, Device};
+
+/// G-bufferのベースカラー。RGBにベースカラー、アルファに遮蔽率
+pub const GBUFFER_ALBEDO_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB;
+/// G-bufferのワールド空間の法線。`tempura_encode_octahedral`で2成分にする
+pub const GBUFFER_NORMAL_FORMAT: vk::Format = vk::Format::R16G16_SFLOAT;
+/// G-bufferのマテリアル。Rにメタリック、Gにラフネス、Bに`tempura_pack_receive_flags`したフラグ
+pub const GBUFFER_MATERIAL_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+
+/// 描画の方式。`RendererConfig::render_path`で選ぶ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum RenderPath {
+    /// マテリアルのシェーダーで全てのライトを計算する
+    #[default]
+    Forward,
+    /// G-bufferに書き込んでから、画面全体のパスでまとめてライティングする
+    ///
+    /// 動的レンダリングが必要で、MSAAとは併用できない。使えない場合は`Forward`になる。
+    Deferred,
+}
+
+/// G-bufferからライティングするシェーダー
+///
+/// 頂点バッファなしで画面全体を覆う三角形を描き、結果をHDRのレンダーターゲットに加算する。
+/// セット0が`gbuffer_set_layout`、1以降が`enable_deferred_lighting`に渡したレイアウト。
+///
+/// ```glsl
+/// // 頂点シェーダーはトーンマッピングと同じ
+///
+/// // フラグメントシェーダー
+/// #include "tempura/brdf.glsl"
+/// #include "tempura/packing.glsl"
+/// layout(set = 0, binding = 0) uniform sampler2D albedo;
+/// layout(set = 0, binding = 1) uniform sampler2D normal;
+/// layout(set = 0, binding = 2) uniform sampler2D material;
+/// layout(set = 0, binding = 3) uniform sampler2D depth;
+/// layout(push_constant) uniform Resolve { mat4 inverse_view_projection; vec4 camera_position; };
+/// layout(location = 0) out vec4 color;
+/// void main() {
+///     ivec2 p = ivec2(gl_FragCoord.xy);
+///     float d = texelFetch(depth, p, 0).r;
+///     if (d == 1.0) discard; // 何も描かれていない画素はクリアした色のまま
+///     vec2 uv = gl_FragCoord.xy / vec2(textureSize(depth, 0));
+///     vec4 world = inverse_view_projection * vec4(uv * 2.0 - 1.0, d, 1.0);
+///     vec3 position = world.xyz / world.w;
+///     vec4 base = texelFetch(albedo, p, 0);
+///     vec3 n = tempura_decode_octahedral(texelFetch(normal, p, 0).xy);
+///     vec3 mr = texelFetch(material, p, 0).rgb;
+///     uint flags = tempura_unpack_receive_flags(mr.b);
+///     vec3 v = normalize(camera_position.xyz - position);
+///     // ここからはフォワードのPBRシェーダーと同じく、セット1のライトを順に足す。
+///     // SSAOなどの効果はflagsに含まれるときだけ適用する
+///     color = vec4(shade(position, n, v, base.rgb, mr.x, mr.y, base.a), 1.0);
+/// }
+/// ```
+///
+/// G-bufferに書き込むフラグメントシェーダーは、ロケーション0にエミッシブ、1から3に
+/// ベースカラーと遮蔽率、法線、メタリックとラフネスと`receive_flags`を出力する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeferredLightingShaders {
+    pub vertex: ShaderId,
+    pub fragment: ShaderId,
+}
+
+/// ライティングのシェーダーに渡すプッシュ定数。フラグメントシェーダーで使う
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeferredLightingPushConstants {
+    /// 深度からワールド空間の位置を求める、ジッターなしのビュー射影行列の逆行列
+    pub inverse_view_projection: [[f32; 4]; 4],
+    /// wは使わない
+    pub camera_position: [f32; 4],
+}
+
+/// HDRのターゲットに合わせて作り直すG-buffer
+pub struct GBuffer {
+    pub albedo: ScreenImage,
+    pub normal: ScreenImage,
+    pub material: ScreenImage,
+    /// 深度バッファの深度のアスペクトだけのビュー
+    pub depth_view: vk::ImageView,
+    pub descriptor_pool: vk::DescriptorPool,
+    /// `gbuffer_set_layout`のデスクリプタセット
+    pub descriptor_set: vk::DescriptorSet,
+    pub extent: vk::Extent2D,
+}
+
+impl GBuffer {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        device.destroy_descriptor_pool(self.descriptor_pool, None);
+        device.destroy_image_view(self.depth_view, None);
+        self.material.destroy(device, allocator);
+        self.normal.destroy(device, allocator);
+        self.albedo.destroy(device, allocator);
+    }
+
+    fn images(&self) -> [vk::Image; 3] {
+        [self.albedo.image, self.normal.image, self.material.image]
+    }
+}
+
+/// `enable_deferred_lighting`で作るG-bufferとライティングのリソース
+pub struct DeferredLighting {
+    pub pipeline: GraphicsPipeline,
+    pub sampler: vk::Sampler,
+    pub gbuffer: GBuffer,
+}
+
+impl DeferredLighting {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        self.pipeline.destroy(device);
+        device.destroy_sampler(self.sampler, None);
+        self.gbuffer.destroy(device, allocator);
+    }
+}
+
+impl Renderer {
+    /// 作成時に選ばれた描画の方式
+    pub fn render_path(&self) -> RenderPath {
+        self.render_path
+    }
+
+    /// G-bufferのデスクリプタセットレイアウト
+    ///
+    /// バインディング0がベースカラー、1が法線、2がマテリアル、3が深度。
+    pub fn gbuffer_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        let stage = vk::ShaderStageFlags::FRAGMENT;
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, stage),
+            DescriptorBinding::sampled_image(1, stage),
+            DescriptorBinding::sampled_image(2, stage),
+            DescriptorBinding::sampled_image(3, stage),
+        ])
+    }
+
+    /// G-bufferとライティングのパイプラインを作成する
+    ///
+    /// `set_layouts`はライティングのシェーダーのセット1以降のレイアウトで、普通は
+    /// `light_set_layout`とフォワードのシェーダーと同じ影やIBLのレイアウトを並べる。
+    /// `enable_hdr`の後で呼ぶ。すでに有効な場合は作り直す。
+    pub fn enable_deferred_lighting(
+        &mut self,
+        shaders: DeferredLightingShaders,
+        set_layouts: &[vk::DescriptorSetLayout],
+    ) -> Result<()> {
+        if self.render_path != RenderPath::Deferred {
+            return Err(RendererError::Validation(
+                "the renderer was not created with the deferred render path".to_owned(),
+            ));
+        }
+        self.enabled_hdr()?;
+        let gbuffer_layout = self.gbuffer_set_layout()?;
+        let layouts: Vec<_> = std::iter::once(gbuffer_layout)
+            .chain(set_layouts.iter().copied())
+            .collect();
+        let builder = PipelineBuilder::new()
+            .vertex_shader(self.shader_module(shaders.vertex)?)
+            .fragment_shader(self.shader_module(shaders.fragment)?)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .depth_test(false)
+            .depth_write(false)
+            .blend_mode(BlendMode::Additive)
+            .descriptor_set_layouts(&layouts)
+            .push_constant_range(
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                std::mem::size_of::<DeferredLightingPushConstants>() as u32,
+            )
+            .rendering_formats(&[HDR_FORMAT], vk::Format::UNDEFINED);
+        let pipeline = self.create_graphics_pipeline(&builder, vk::RenderPass::null(), 0)?;
+        let resources = self
+            .create_sampler(&SamplerDesc::nearest())
+            .and_then(|sampler| match unsafe { self.create_gbuffer(sampler) } {
+                Ok(gbuffer) => Ok((sampler, gbuffer)),
+                Err(err) => {
+                    unsafe { self.device.destroy_sampler(sampler, None) };
+                    Err(err)
+                }
+            });
+        let (sampler, gbuffer) = match resources {
+            Ok(resources) => resources,
+            Err(err) => {
+                self.destroy_graphics_pipeline(pipeline);
+                return Err(err);
+            }
+        };
+        let deferred_lighting = DeferredLighting {
+            pipeline,
+            sampler,
+            gbuffer,
+        };
+        if let Some(old) = self.deferred_lighting.replace(deferred_lighting) {
+            self.destroy_deferred(move |device, allocator| unsafe {
+                old.destroy(device, allocator)
+            });
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからG-bufferとライティングのリソースを破棄する
+    pub fn disable_deferred_lighting(&mut self) {
+        if let Some(deferred_lighting) = self.deferred_lighting.take() {
+            self.destroy_deferred(move |device, allocator| unsafe {
+                deferred_lighting.destroy(device, allocator)
+            });
+        }
+    }
+
+    pub fn deferred_lighting(&self) -> Option<&DeferredLighting> {
+        self.deferred_lighting.as_ref()
+    }
+
+    /// G-bufferに書き込むパイプラインを作成する
+    ///
+    /// カラーアタッチメントはHDRのレンダーターゲット、ベースカラー、法線、マテリアルの順。
+    /// レイアウトやプッシュ定数は`pbr_pipeline_builder`のビルダーをそのまま使える。
+    pub fn create_gbuffer_pipeline(&self, builder: &PipelineBuilder) -> Result<GraphicsPipeline> {
+        self.enabled_deferred_lighting()?;
+        let builder = builder
+            .clone()
+            .color_attachments(&[BlendMode::Opaque; 4])
+            .rendering_formats(
+                &[
+                    HDR_FORMAT,
+                    GBUFFER_ALBEDO_FORMAT,
+                    GBUFFER_NORMAL_FORMAT,
+                    GBUFFER_MATERIAL_FORMAT,
+                ],
+                self.depth_format,
+            );
+        self.create_graphics_pipeline(&builder, vk::RenderPass::null(), 0)
+    }
+
+    /// HDRのレンダーターゲット、G-buffer、深度バッファへの描画を開始する
+    ///
+    /// HDRのレンダーターゲットは`clear_color`で、G-bufferは0でクリアする。不透明な物を
+    /// `create_gbuffer_pipeline`のパイプラインで描き、`end_gbuffer_rendering`で終了する。
+    pub fn begin_gbuffer_rendering(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        clear_color: [f32; 4],
+    ) -> Result<()> {
+        let deferred_lighting = self.enabled_deferred_lighting()?;
+        let hdr = self.enabled_hdr()?;
+        let gbuffer = &deferred_lighting.gbuffer;
+        let color_range = color_subresource_range();
+        let (depth_old_layout, depth) = self.main_pass_depth_attachment();
+        // 前のフレームのライティングとトーンマッピングの読み込みと、深度の書き込みを待つ
+        let mut barriers: Vec<_> = std::iter::once(hdr.target.image)
+            .chain(gbuffer.images())
+            .map(|image| {
+                *vk::ImageMemoryBarrier::builder()
+                    .image(image)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                    .subresource_range(color_range)
+            })
+            .collect();
+        barriers.push(
+            *vk::ImageMemoryBarrier::builder()
+                .image(self.depth_image)
+                .old_layout(depth_old_layout)
+                .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                .dst_access_mask(
+                    vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                )
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: depth_aspect_mask(self.depth_format),
+                    ..color_range
+                }),
+        );
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &barriers,
+            );
+        }
+        let clear = |float32| vk::ClearValue {
+            color: vk::ClearColorValue { float32 },
+        };
+        let colors: Vec<_> = [
+            (hdr.target.view, clear_color),
+            (gbuffer.albedo.view, [0.0; 4]),
+            (gbuffer.normal.view, [0.0; 4]),
+            (gbuffer.material.view, [0.0; 4]),
+        ]
+        .into_iter()
+        .map(|(view, color)| {
+            RenderingAttachment::clear(
+                view,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                clear(color),
+            )
+        })
+        .collect();
+        let render_area = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: gbuffer.extent,
+        };
+        self.begin_rendering(command_buffer, render_area, &colors, Some(depth))
+    }
+
+    /// `begin_gbuffer_rendering`で開始した描画を終了し、G-bufferと深度をサンプリング可能にする
+    pub fn end_gbuffer_rendering(&self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        let deferred_lighting = self.enabled_deferred_lighting()?;
+        self.end_rendering(command_buffer)?;
+        let color_range = color_subresource_range();
+        let mut barriers: Vec<_> = deferred_lighting
+            .gbuffer
+            .images()
+            .into_iter()
+            .map(|image| {
+                *vk::ImageMemoryBarrier::builder()
+                    .image(image)
+                    .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                    .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                    .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                    .subresource_range(color_range)
+            })
+            .collect();
+        barriers.push(
+            *vk::ImageMemoryBarrier::builder()
+                .image(self.depth_image)
+                .old_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .new_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+                .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: depth_aspect_mask(self.depth_format),
+                    ..color_range
+                }),
+        );
+        // エミッシブを書いたHDRのレンダーターゲットには、ライティングで加算する
+        let hdr_barrier = *vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            );
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::DependencyFlags::empty(),
+                &[hdr_barrier],
+                &[],
+                &barriers,
+            );
+        }
+        Ok(())
+    }
+
+    /// G-bufferからライティングしてHDRのレンダーターゲットに加算する
+    ///
+    /// `end_gbuffer_rendering`の後で呼ぶ。`descriptor_sets`はセット1以降に割り当てる。
+    /// 終わるとHDRのレンダーターゲットは`end_hdr_rendering`の後と同じ状態になり、深度は
+    /// アタッチメントに戻るので、そのまま`apply_post_processing`や`tone_map`を呼べる。
+    pub fn resolve_deferred_lighting(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        push_constants: &DeferredLightingPushConstants,
+        descriptor_sets: &[vk::DescriptorSet],
+    ) -> Result<()> {
+        let deferred_lighting = self.enabled_deferred_lighting()?;
+        let hdr = self.enabled_hdr()?;
+        let gbuffer = &deferred_lighting.gbuffer;
+        let render_area = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: gbuffer.extent,
+        };
+        let color =
+            RenderingAttachment::load(hdr.target.view, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+        self.begin_rendering(command_buffer, render_area, &[color], None)?;
+        let sets: Vec<_> = std::iter::once(gbuffer.descriptor_set)
+            .chain(descriptor_sets.iter().copied())
+            .collect();
+        let data: Vec<u8> = push_constants
+            .inverse_view_projection
+            .iter()
+            .flatten()
+            .chain(push_constants.camera_position.iter())
+            .flat_map(|value| value.to_ne_bytes())
+            .collect();
+        let pipeline = &deferred_lighting.pipeline;
+        unsafe {
+            self.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.pipeline,
+            );
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.layout,
+                0,
+                &sets,
+                &[],
+            );
+            self.push_constants(command_buffer, pipeline, &data);
+            self.device.cmd_draw(command_buffer, 3, 1, 0, 0);
+        }
+        self.end_rendering(command_buffer)?;
+
+        let color_range = color_subresource_range();
+        let barriers = [
+            *vk::ImageMemoryBarrier::builder()
+                .image(hdr.target.image)
+                .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .subresource_range(color_range),
+            *vk::ImageMemoryBarrier::builder()
+                .image(self.depth_image)
+                .old_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+                .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .src_access_mask(vk::AccessFlags::SHADER_READ)
+                .dst_access_mask(
+                    vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                )
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: depth_aspect_mask(self.depth_format),
+                    ..color_range
+                }),
+        ];
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COMPUTE_SHADER
+                    | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &barriers,
+            );
+        }
+        Ok(())
+    }
+
+    /// HDRのターゲットに合わせてG-bufferを作り直す
+    ///
+    /// 古いG-bufferは使用中のフレームが完了してから破棄する。
+    pub(crate) fn recreate_gbuffer(&mut self) -> Result<()> {
+        let Some(sampler) = self
+            .deferred_lighting
+            .as_ref()
+            .map(|deferred_lighting| deferred_lighting.sampler)
+        else {
+            return Ok(());
+        };
+        let gbuffer = unsafe { self.create_gbuffer(sampler)? };
+        let old = std::mem::replace(
+            &mut self.deferred_lighting.as_mut().unwrap().gbuffer,
+            gbuffer,
+        );
+        self.destroy_deferred(move |device, allocator| unsafe { old.destroy(device, allocator) });
+        Ok(())
+    }
+
+    fn enabled_deferred_lighting(&self) -> Result<&DeferredLighting> {
+        self.deferred_lighting
+            .as_ref()
+            .ok_or_else(|| RendererError::Validation("deferred lighting is not enabled".to_owned()))
+    }
+
+    /// # Safety
+    /// HDRが有効であること
+    unsafe fn create_gbuffer(&mut self, sampler: vk::Sampler) -> Result<GBuffer> {
+        let layout = self.gbuffer_set_layout()?;
+        let extent = self.enabled_hdr()?.target.extent;
+        let usage = vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED;
+        let mut images = Vec::with_capacity(3);
+        for format in [
+            GBUFFER_ALBEDO_FORMAT,
+            GBUFFER_NORMAL_FORMAT,
+            GBUFFER_MATERIAL_FORMAT,
+        ] {
+            match create_screen_image(&self.device, &mut self.allocator, format, usage, extent) {
+                Ok(image) => images.push(image),
+                Err(err) => {
+                    for image in images {
+                        image.destroy(&self.device, &mut self.allocator);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        let material = images.pop().unwrap();
+        let normal = images.pop().unwrap();
+        let albedo = images.pop().unwrap();
+        let mut gbuffer = GBuffer {
+            albedo,
+            normal,
+            material,
+            depth_view: vk::ImageView::null(),
+            descriptor_pool: vk::DescriptorPool::null(),
+            descriptor_set: vk::DescriptorSet::null(),
+            extent,
+        };
+        match self.init_gbuffer(&mut gbuffer, layout, sampler) {
+            Ok(()) => Ok(gbuffer),
+            Err(err) => {
+                gbuffer.destroy(&self.device, &mut self.allocator);
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn init_gbuffer(
+        &self,
+        gbuffer: &mut GBuffer,
+        layout: vk::DescriptorSetLayout,
+        sampler: vk::Sampler,
+    ) -> Result<()> {
+        gbuffer.depth_view =
+            create_depth_sampling_view(&self.device, self.depth_image, self.depth_format)?;
+        gbuffer.descriptor_pool = create_pool(
+            &self.device,
+            1,
+            &[(vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 4.0)],
+        )?;
+        gbuffer.descriptor_set = allocate_set(&self.device, gbuffer.descriptor_pool, layout)?;
+        let image_info = |image_view| vk::DescriptorImageInfo {
+            sampler,
+            image_view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        };
+        DescriptorWriter::new()
+            .image(
+                0,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                image_info(gbuffer.albedo.view),
+            )
+            .image(
+                1,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                image_info(gbuffer.normal.view),
+            )
+            .image(
+                2,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                image_info(gbuffer.material.view),
+            )
+            .depth_image(3, gbuffer.depth_view, sampler)
+            .update(&self.device, gbuffer.descriptor_set);
+        Ok(())
+    }
+}
+
+fn color_subresource_range() -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
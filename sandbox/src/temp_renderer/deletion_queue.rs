@@ -0,0 +1,53 @@
+use super::memory::MemoryAllocator;
+use ash::Device;
+use std::collections::VecDeque;
+
+type DestroyFn = Box<dyn FnOnce(&Device, &mut MemoryAllocator)>;
+
+/// GPUが使い終わるまで破棄を遅らせるリソースのキュー
+///
+/// 登録時のフレーム番号を記録しておき、そのフレームの完了が確認できた時点で破棄する。
+#[derive(Default)]
+pub struct DeletionQueue {
+    pending: VecDeque<(u64, DestroyFn)>,
+}
+
+impl DeletionQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push<F: FnOnce(&Device, &mut MemoryAllocator) + 'static>(&mut self, frame: u64, f: F) {
+        self.pending.push_back((frame, Box::new(f)));
+    }
+
+    /// `completed_frame`以前に登録されたものを登録順に破棄する
+    pub fn flush_completed(
+        &mut self,
+        device: &Device,
+        allocator: &mut MemoryAllocator,
+        completed_frame: u64,
+    ) {
+        while let Some((frame, _)) = self.pending.front() {
+            if *frame > completed_frame {
+                break;
+            }
+            let (_, f) = self.pending.pop_front().unwrap();
+            f(device, allocator);
+        }
+    }
+
+    pub fn flush_all(&mut self, device: &Device, allocator: &mut MemoryAllocator) {
+        while let Some((_, f)) = self.pending.pop_front() {
+            f(device, allocator);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
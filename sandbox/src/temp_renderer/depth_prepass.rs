@@ -0,0 +1,153 @@
+use super::dynamic_rendering::RenderingAttachment;
+use super::error::{RendererError, Result};
+use super::renderer::depth_aspect_mask;
+use super::{GraphicsPipeline, PipelineBuilder, Renderer};
+use ash::vk;
+
+impl Renderer {
+    /// 深度のプリパスを使っているか
+    pub fn depth_prepass(&self) -> bool {
+        self.depth_prepass
+    }
+
+    /// 深度のプリパスを切り替える。動的レンダリングが有効な場合だけ使える
+    ///
+    /// メインのパスの深度テストが変わるので、`main_pass_pipeline_builder`で作った
+    /// パイプラインは切り替えた後に作り直す。
+    pub fn set_depth_prepass(&mut self, enabled: bool) -> Result<()> {
+        if enabled && self.dynamic_rendering.is_none() {
+            return Err(RendererError::Validation(
+                "the depth pre-pass needs dynamic rendering".to_owned(),
+            ));
+        }
+        self.depth_prepass = enabled;
+        Ok(())
+    }
+
+    /// メインのパスのパイプラインのビルダーに、深度のプリパスに合わせた深度テストを設定する
+    ///
+    /// プリパスが有効なら、プリパスと同じ深度の面だけを描くように`EQUAL`で比較し、
+    /// 深度を書き込まない。無効ならビルダーをそのまま返す。
+    pub fn main_pass_pipeline_builder(&self, builder: PipelineBuilder) -> PipelineBuilder {
+        if self.depth_prepass {
+            builder
+                .depth_test(true)
+                .depth_write(false)
+                .depth_compare_op(vk::CompareOp::EQUAL)
+        } else {
+            builder
+        }
+    }
+
+    /// メインのパスのビルダーから、深度だけを書き込むプリパスのパイプラインを作成する
+    ///
+    /// 頂点の配置とデスクリプタセットレイアウトはメインのパスと同じなので、同じメッシュと
+    /// セットで描ける。頂点シェーダーも同じものを使うので、深度がメインのパスと一致する。
+    /// アルファテストのあるマテリアルは`depth_variant_pipeline_builder`のビルダーを渡す。
+    pub fn create_depth_prepass_pipeline(
+        &self,
+        builder: &PipelineBuilder,
+    ) -> Result<GraphicsPipeline> {
+        if self.dynamic_rendering.is_none() {
+            return Err(RendererError::Validation(
+                "the depth pre-pass needs dynamic rendering".to_owned(),
+            ));
+        }
+        let builder = builder
+            .clone()
+            .depth_only()
+            .rendering_formats(&[], self.depth_format)
+            .samples(self.msaa_samples);
+        self.create_graphics_pipeline(&builder, vk::RenderPass::null(), 0)
+    }
+
+    /// 深度バッファをクリアして、深度のプリパスの描画を開始する
+    ///
+    /// `begin_hdr_rendering`や`begin_swapchain_rendering`の前に呼び、不透明な物を
+    /// `create_depth_prepass_pipeline`のパイプラインで描いて`end_depth_prepass`で終了する。
+    pub fn begin_depth_prepass(&self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        if !self.depth_prepass {
+            return Err(RendererError::Validation(
+                "the depth pre-pass is not enabled".to_owned(),
+            ));
+        }
+        // 前のフレームの深度の書き込みと、ポストプロセスでの読み込みを待つ
+        let barrier = *vk::ImageMemoryBarrier::builder()
+            .image(self.depth_image)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_access_mask(
+                vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            )
+            .subresource_range(depth_subresource_range(self.depth_format));
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COMPUTE_SHADER
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier],
+            );
+        }
+        let depth = RenderingAttachment::clear(
+            self.depth_image_view,
+            vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            },
+        );
+        let render_area = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: self.surface_resolution,
+        };
+        self.begin_rendering(command_buffer, render_area, &[], Some(depth))
+    }
+
+    /// `begin_depth_prepass`で開始した描画を終了する。深度はメインのパスで読み込む
+    pub fn end_depth_prepass(&self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        self.end_rendering(command_buffer)
+    }
+
+    /// メインのパスの深度のアタッチメントと、その前のバリアで使う古いレイアウト
+    ///
+    /// 深度のプリパスが有効なら、プリパスで書き込んだ深度を読み込む。そうでなければクリアする。
+    pub(crate) fn main_pass_depth_attachment(&self) -> (vk::ImageLayout, RenderingAttachment) {
+        let layout = vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL;
+        if self.depth_prepass {
+            (
+                layout,
+                RenderingAttachment::load(self.depth_image_view, layout),
+            )
+        } else {
+            let clear_depth = vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            };
+            (
+                vk::ImageLayout::UNDEFINED,
+                RenderingAttachment::clear(self.depth_image_view, layout, clear_depth),
+            )
+        }
+    }
+}
+
+fn depth_subresource_range(depth_format: vk::Format) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: depth_aspect_mask(depth_format),
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
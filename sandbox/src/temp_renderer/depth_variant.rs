@@ -0,0 +1,137 @@
+use super::displacement::vertex_shader_source;
+use super::error::{RendererError, Result};
+use super::{MaterialId, PipelineBuilder, Renderer, ShaderId, VertexPass};
+
+/// 深度のパスのシェーダーでマテリアルのデスクリプタセットを読むセット
+pub const DEPTH_VARIANT_MATERIAL_SET: u32 = 0;
+/// 深度のパスのシェーダーで変位のユニフォームを読むセット
+pub const DEPTH_VARIANT_DISPLACEMENT_SET: u32 = 1;
+
+/// 影と深度のプリパスのシェーダーに含めるマテリアルの機能
+///
+/// マテリアルがその機能を使わない場合は、`true`でも含めない。遠くのカスケードなどで
+/// 省いてよい場合は`false`にすると、安いシェーダーになる。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DepthVariantFeatures {
+    /// `MaterialDesc::displacement`の変位
+    pub displacement: bool,
+    /// `MaterialDesc::alpha_cutoff`のアルファテスト
+    pub alpha_test: bool,
+}
+
+impl Default for DepthVariantFeatures {
+    fn default() -> Self {
+        Self {
+            displacement: true,
+            alpha_test: true,
+        }
+    }
+}
+
+/// `material_depth_shaders`で生成した影と深度のプリパスのシェーダー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DepthShaderSources {
+    /// マテリアルが使うものに絞った機能。`depth_variant_pipeline_builder`に渡す
+    pub features: DepthVariantFeatures,
+    pub vertex: String,
+    /// アルファテストのフラグメントシェーダー。アルファテストがなければ`None`で、
+    /// フラグメントシェーダーなしで深度だけを書き込む
+    pub fragment: Option<String>,
+}
+
+/// アルファテストだけをするフラグメントシェーダーのGLSL
+const ALPHA_TEST_FRAGMENT_SHADER: &str = "#version 450\n\n\
+layout(location = 0) in vec2 uv;\n\
+layout(set = 0, binding = 0) uniform Material {\n    \
+    vec4 base_color_factor;\n    \
+    vec3 emissive_factor;\n    \
+    float metallic_factor;\n    \
+    float roughness_factor;\n    \
+    float normal_scale;\n    \
+    float occlusion_strength;\n    \
+    float alpha_cutoff;\n\
+};\n\
+layout(set = 0, binding = 1) uniform sampler2D base_color_texture;\n\n\
+void main() {\n    \
+    if (texture(base_color_texture, uv).a * base_color_factor.a < alpha_cutoff) {\n        \
+        discard;\n    \
+    }\n\
+}\n";
+
+impl Renderer {
+    /// マテリアルの影と深度のプリパス用のシェーダーのGLSLを生成する
+    ///
+    /// 変位は`material_vertex_shader`の`VertexPass::Depth`と同じものを埋め込むので、影が
+    /// 描画と食い違わない。マテリアルのデスクリプタセットは`DEPTH_VARIANT_MATERIAL_SET`、
+    /// `update_vertex_displacement`のセットは`DEPTH_VARIANT_DISPLACEMENT_SET`に割り当てる。
+    /// `features`が同じで変位のないマテリアルには同じシェーダーが生成される。
+    pub fn material_depth_shaders(
+        &self,
+        material: MaterialId,
+        features: DepthVariantFeatures,
+    ) -> Result<DepthShaderSources> {
+        let desc = &self
+            .materials
+            .get(material)
+            .ok_or_else(|| {
+                RendererError::Validation(format!("material {:?} was already destroyed", material))
+            })?
+            .desc;
+        let features = DepthVariantFeatures {
+            displacement: features.displacement && desc.displacement.is_some(),
+            alpha_test: features.alpha_test && desc.alpha_cutoff.is_some(),
+        };
+        let displacement = desc.displacement.as_ref().filter(|_| features.displacement);
+        Ok(DepthShaderSources {
+            features,
+            vertex: vertex_shader_source(
+                VertexPass::Depth,
+                displacement,
+                DEPTH_VARIANT_DISPLACEMENT_SET,
+                features.alpha_test,
+            ),
+            fragment: features
+                .alpha_test
+                .then(|| ALPHA_TEST_FRAGMENT_SHADER.to_owned()),
+        })
+    }
+
+    /// `shadow_pipeline_builder`などが返したビルダーに、`material_depth_shaders`の機能に
+    /// 必要なフラグメントシェーダーとデスクリプタセットレイアウトを設定する
+    ///
+    /// `fragment_shader`は`DepthShaderSources::fragment`をコンパイルしたもので、
+    /// アルファテストがある場合だけ必要。
+    pub fn depth_variant_pipeline_builder(
+        &mut self,
+        builder: PipelineBuilder,
+        features: DepthVariantFeatures,
+        fragment_shader: Option<ShaderId>,
+    ) -> Result<PipelineBuilder> {
+        let mut builder = builder;
+        match (features.alpha_test, fragment_shader) {
+            (true, Some(shader)) => {
+                builder = builder.fragment_shader(self.shader_module(shader)?);
+            }
+            (false, None) => {}
+            (true, None) => {
+                return Err(RendererError::Validation(
+                    "an alpha-tested depth variant needs a fragment shader".to_owned(),
+                ))
+            }
+            (false, Some(_)) => {
+                return Err(RendererError::Validation(
+                    "a depth variant without alpha test does not use a fragment shader".to_owned(),
+                ))
+            }
+        }
+        if features.displacement {
+            let material_layout = self.material_set_layout()?;
+            let displacement_layout = self.displacement_set_layout()?;
+            builder = builder.descriptor_set_layouts(&[material_layout, displacement_layout]);
+        } else if features.alpha_test {
+            let material_layout = self.material_set_layout()?;
+            builder = builder.descriptor_set_layouts(&[material_layout]);
+        }
+        Ok(builder)
+    }
+}
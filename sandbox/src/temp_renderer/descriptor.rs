@@ -0,0 +1,391 @@
+use super::error::Result;
+use super::{Buffer, Renderer, Texture};
+use ash::{vk, Device};
+use std::collections::HashMap;
+
+// 最初のプールで確保するセット数と、プールを増やす際の上限
+const INITIAL_SETS_PER_POOL: u32 = 64;
+const MAX_SETS_PER_POOL: u32 = 4096;
+
+/// デスクリプタセットレイアウトの1バインディング
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DescriptorBinding {
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    pub count: u32,
+    pub stages: vk::ShaderStageFlags,
+}
+
+impl DescriptorBinding {
+    pub fn new(
+        binding: u32,
+        descriptor_type: vk::DescriptorType,
+        stages: vk::ShaderStageFlags,
+    ) -> Self {
+        Self {
+            binding,
+            descriptor_type,
+            count: 1,
+            stages,
+        }
+    }
+
+    pub fn uniform_buffer(binding: u32, stages: vk::ShaderStageFlags) -> Self {
+        Self::new(binding, vk::DescriptorType::UNIFORM_BUFFER, stages)
+    }
+
+    pub fn storage_buffer(binding: u32, stages: vk::ShaderStageFlags) -> Self {
+        Self::new(binding, vk::DescriptorType::STORAGE_BUFFER, stages)
+    }
+
+    /// サンプラー付きのイメージ(`sampler2D`)
+    ///
+    /// 整数フォーマットの`usampler2D`と`isampler2D`、深度の`sampler2DShadow`も
+    /// デスクリプタの種類は同じ。どれで宣言するかは[`TextureSampleType`]で決める。
+    ///
+    /// [`TextureSampleType`]: super::TextureSampleType
+    pub fn sampled_image(binding: u32, stages: vk::ShaderStageFlags) -> Self {
+        Self::new(binding, vk::DescriptorType::COMBINED_IMAGE_SAMPLER, stages)
+    }
+
+    pub fn count(mut self, count: u32) -> Self {
+        self.count = count;
+        self
+    }
+}
+
+/// 同じバインディング構成のレイアウトを使い回すキャッシュ
+#[derive(Default)]
+pub struct DescriptorLayoutCache {
+    layouts: HashMap<Vec<DescriptorBinding>, vk::DescriptorSetLayout>,
+}
+
+impl DescriptorLayoutCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// バインディングの宣言順によらず、同じ構成なら同じレイアウトを返す
+    ///
+    /// # Safety
+    ///
+    /// `device`は常に同じデバイスであること。
+    pub unsafe fn get_or_create(
+        &mut self,
+        device: &Device,
+        bindings: &[DescriptorBinding],
+    ) -> Result<vk::DescriptorSetLayout> {
+        let mut key = bindings.to_vec();
+        key.sort_by_key(|binding| binding.binding);
+        if let Some(&layout) = self.layouts.get(&key) {
+            return Ok(layout);
+        }
+
+        let layout_bindings: Vec<_> = key
+            .iter()
+            .map(|binding| {
+                *vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding.binding)
+                    .descriptor_type(binding.descriptor_type)
+                    .descriptor_count(binding.count)
+                    .stage_flags(binding.stages)
+            })
+            .collect();
+        let create_info = *vk::DescriptorSetLayoutCreateInfo::builder().bindings(&layout_bindings);
+        let layout = device.create_descriptor_set_layout(&create_info, None)?;
+        self.layouts.insert(key, layout);
+        Ok(layout)
+    }
+
+    /// # Safety
+    ///
+    /// キャッシュしたレイアウトを以降使わないこと。
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for (_, layout) in self.layouts.drain() {
+            device.destroy_descriptor_set_layout(layout, None);
+        }
+    }
+}
+
+/// デスクリプタプールを自動で増やしながらセットを割り当てるアロケーター
+///
+/// プールが足りなくなると、前回より大きいプールを作って割り当て直す。
+/// `reset`ですべてのプールを空に戻せるので、フレームごとの一時的なセットにも使える。
+pub struct DescriptorAllocator {
+    /// セット1つあたりのデスクリプタ数の見込み
+    ratios: Vec<(vk::DescriptorType, f32)>,
+    sets_per_pool: u32,
+    ready_pools: Vec<vk::DescriptorPool>,
+    full_pools: Vec<vk::DescriptorPool>,
+}
+
+impl Default for DescriptorAllocator {
+    fn default() -> Self {
+        Self::new(&[
+            (vk::DescriptorType::UNIFORM_BUFFER, 1.0),
+            (vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC, 0.5),
+            (vk::DescriptorType::STORAGE_BUFFER, 1.0),
+            (vk::DescriptorType::STORAGE_BUFFER_DYNAMIC, 0.5),
+            (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 2.0),
+            (vk::DescriptorType::SAMPLED_IMAGE, 1.0),
+            (vk::DescriptorType::SAMPLER, 0.5),
+            (vk::DescriptorType::STORAGE_IMAGE, 0.5),
+        ])
+    }
+}
+
+impl DescriptorAllocator {
+    pub fn new(ratios: &[(vk::DescriptorType, f32)]) -> Self {
+        Self {
+            ratios: ratios.to_vec(),
+            sets_per_pool: INITIAL_SETS_PER_POOL,
+            ready_pools: Vec::new(),
+            full_pools: Vec::new(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `layout`は`device`で作成されていること。
+    pub unsafe fn allocate(
+        &mut self,
+        device: &Device,
+        layout: vk::DescriptorSetLayout,
+    ) -> Result<vk::DescriptorSet> {
+        let pool = self.get_pool(device)?;
+        match allocate_set(device, pool, layout) {
+            Ok(set) => {
+                self.ready_pools.push(pool);
+                Ok(set)
+            }
+            Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY | vk::Result::ERROR_FRAGMENTED_POOL) => {
+                // 空きのないプールは`reset`まで使わない
+                self.full_pools.push(pool);
+                let pool = self.get_pool(device)?;
+                let result = allocate_set(device, pool, layout);
+                self.ready_pools.push(pool);
+                Ok(result?)
+            }
+            Err(err) => {
+                self.ready_pools.push(pool);
+                Err(err.into())
+            }
+        }
+    }
+
+    /// 割り当てたセットをすべて解放する
+    ///
+    /// # Safety
+    ///
+    /// 割り当てたセットをGPUが使用中でないこと。
+    pub unsafe fn reset(&mut self, device: &Device) -> Result<()> {
+        self.ready_pools.append(&mut self.full_pools);
+        for &pool in self.ready_pools.iter() {
+            device.reset_descriptor_pool(pool, vk::DescriptorPoolResetFlags::empty())?;
+        }
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// 割り当てたセットをGPUが使用中でないこと。
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for pool in self.ready_pools.drain(..).chain(self.full_pools.drain(..)) {
+            device.destroy_descriptor_pool(pool, None);
+        }
+    }
+
+    unsafe fn get_pool(&mut self, device: &Device) -> Result<vk::DescriptorPool> {
+        if let Some(pool) = self.ready_pools.pop() {
+            return Ok(pool);
+        }
+        let pool = create_pool(device, self.sets_per_pool, &self.ratios)?;
+        // 次に作るプールは大きくしておく
+        self.sets_per_pool = (self.sets_per_pool + self.sets_per_pool / 2).min(MAX_SETS_PER_POOL);
+        Ok(pool)
+    }
+}
+
+pub(crate) unsafe fn create_pool(
+    device: &Device,
+    max_sets: u32,
+    ratios: &[(vk::DescriptorType, f32)],
+) -> Result<vk::DescriptorPool> {
+    let pool_sizes: Vec<_> = ratios
+        .iter()
+        .map(|&(ty, ratio)| vk::DescriptorPoolSize {
+            ty,
+            descriptor_count: ((ratio * max_sets as f32) as u32).max(1),
+        })
+        .collect();
+    let create_info = *vk::DescriptorPoolCreateInfo::builder()
+        .max_sets(max_sets)
+        .pool_sizes(&pool_sizes);
+    Ok(device.create_descriptor_pool(&create_info, None)?)
+}
+
+pub(crate) unsafe fn allocate_set(
+    device: &Device,
+    pool: vk::DescriptorPool,
+    layout: vk::DescriptorSetLayout,
+) -> std::result::Result<vk::DescriptorSet, vk::Result> {
+    let layouts = [layout];
+    let allocate_info = *vk::DescriptorSetAllocateInfo::builder()
+        .descriptor_pool(pool)
+        .set_layouts(&layouts);
+    Ok(device.allocate_descriptor_sets(&allocate_info)?[0])
+}
+
+/// デスクリプタセットへの書き込みをまとめて`update_descriptor_sets`する
+#[derive(Default)]
+pub struct DescriptorWriter {
+    buffer_infos: Vec<(u32, vk::DescriptorType, vk::DescriptorBufferInfo)>,
+    image_infos: Vec<(u32, vk::DescriptorType, vk::DescriptorImageInfo)>,
+}
+
+impl DescriptorWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn buffer(
+        mut self,
+        binding: u32,
+        descriptor_type: vk::DescriptorType,
+        buffer: &Buffer,
+    ) -> Self {
+        self.buffer_infos.push((
+            binding,
+            descriptor_type,
+            vk::DescriptorBufferInfo {
+                buffer: buffer.buffer,
+                offset: 0,
+                range: vk::WHOLE_SIZE,
+            },
+        ));
+        self
+    }
+
+    /// `SHADER_READ_ONLY_OPTIMAL`のテクスチャを`COMBINED_IMAGE_SAMPLER`として書き込む
+    ///
+    /// 深度テクスチャも`create_texture`などで作ったものはこのレイアウトになっている。
+    pub fn texture(mut self, binding: u32, texture: &Texture) -> Self {
+        self.image_infos.push((
+            binding,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::DescriptorImageInfo {
+                sampler: texture.sampler,
+                image_view: texture.view,
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            },
+        ));
+        self
+    }
+
+    /// `GENERAL`に遷移させたテクスチャを`STORAGE_IMAGE`として書き込む
+    ///
+    /// テクスチャは`TextureDesc::storage`で作り、`Renderer::texture_barrier`で
+    /// `ImageAccess::COMPUTE_SHADER_WRITE`などに遷移させてから使う。ストレージイメージの
+    /// ビューはミップを1つしか持てないので、ミップが複数ある場合は`create_texture_view`で
+    /// 1つのミップのビューを作り、`image`で書き込む。
+    pub fn storage_texture(mut self, binding: u32, texture: &Texture) -> Self {
+        self.image_infos.push((
+            binding,
+            vk::DescriptorType::STORAGE_IMAGE,
+            vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view: texture.view,
+                image_layout: vk::ImageLayout::GENERAL,
+            },
+        ));
+        self
+    }
+
+    /// 深度アタッチメントを`DEPTH_STENCIL_READ_ONLY_OPTIMAL`のまま`COMBINED_IMAGE_SAMPLER`として書き込む
+    ///
+    /// レンダーグラフで`ImageAccess::FRAGMENT_SHADER_DEPTH_READ`として宣言したイメージに使う。
+    /// `view`は深度のアスペクトだけを含むこと。
+    pub fn depth_image(mut self, binding: u32, view: vk::ImageView, sampler: vk::Sampler) -> Self {
+        self.image_infos.push((
+            binding,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::DescriptorImageInfo {
+                sampler,
+                image_view: view,
+                image_layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+            },
+        ));
+        self
+    }
+
+    pub fn image(
+        mut self,
+        binding: u32,
+        descriptor_type: vk::DescriptorType,
+        image_info: vk::DescriptorImageInfo,
+    ) -> Self {
+        self.image_infos
+            .push((binding, descriptor_type, image_info));
+        self
+    }
+
+    /// # Safety
+    ///
+    /// `set`と書き込むリソースが`device`で作成されており、`set`をGPUが使用中でないこと。
+    pub unsafe fn update(&self, device: &Device, set: vk::DescriptorSet) {
+        let buffer_writes = self
+            .buffer_infos
+            .iter()
+            .map(|(binding, descriptor_type, info)| {
+                *vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(*binding)
+                    .descriptor_type(*descriptor_type)
+                    .buffer_info(std::slice::from_ref(info))
+            });
+        let image_writes = self
+            .image_infos
+            .iter()
+            .map(|(binding, descriptor_type, info)| {
+                *vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(*binding)
+                    .descriptor_type(*descriptor_type)
+                    .image_info(std::slice::from_ref(info))
+            });
+        let writes: Vec<_> = buffer_writes.chain(image_writes).collect();
+        device.update_descriptor_sets(&writes, &[]);
+    }
+}
+
+impl Renderer {
+    /// バインディング構成に対応するレイアウトを返す。レイアウトはレンダラーの破棄時に破棄される
+    pub fn descriptor_set_layout(
+        &mut self,
+        bindings: &[DescriptorBinding],
+    ) -> Result<vk::DescriptorSetLayout> {
+        unsafe {
+            self.descriptor_layout_cache
+                .get_or_create(&self.device, bindings)
+        }
+    }
+
+    /// レンダラーの破棄まで有効なデスクリプタセットを割り当てる
+    pub fn allocate_descriptor_set(
+        &mut self,
+        layout: vk::DescriptorSetLayout,
+    ) -> Result<vk::DescriptorSet> {
+        unsafe { self.descriptor_allocator.allocate(&self.device, layout) }
+    }
+
+    /// 記録中のフレームでだけ使うデスクリプタセットを割り当てる
+    ///
+    /// 同じフレームコンテキストが次に`begin_frame`されたときに解放される。
+    pub fn allocate_transient_descriptor_set(
+        &mut self,
+        layout: vk::DescriptorSetLayout,
+    ) -> Result<vk::DescriptorSet> {
+        let frame = &mut self.frames[self.current_frame];
+        unsafe { frame.descriptor_allocator.allocate(&self.device, layout) }
+    }
+}
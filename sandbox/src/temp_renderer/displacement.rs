@@ -0,0 +1,343 @@
+use super::camera::Mat4;
+use super::error::{RendererError, Result};
+use super::{BufferId, DescriptorBinding, DescriptorWriter, MaterialId, PerFrame, Renderer};
+use ash::vk;
+use std::fmt::Write;
+
+/// マテリアルの頂点の変位
+///
+/// [`displaced_vertex_shader`]で、全てのパスの頂点シェーダーに同じ変位を埋め込む。
+/// 変位はオブジェクト空間で、モデル行列を掛ける前に行う。法線は変位させない。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VertexDisplacement {
+    /// 原点からの高さの2乗に比例して`direction`に揺らす。草木向け
+    Wind {
+        direction: [f32; 3],
+        strength: f32,
+        /// 1秒あたりの揺れの回数
+        frequency: f32,
+    },
+    /// UVのuに沿って進む波を法線の方向に加える。u = 0の辺は動かない
+    FlagWave {
+        amplitude: f32,
+        /// uの単位の波長
+        wavelength: f32,
+        /// 1秒あたりに進む波の数
+        speed: f32,
+    },
+    /// 法線の方向に周期的に膨らませる
+    Breathing {
+        amplitude: f32,
+        /// 1秒あたりの回数
+        rate: f32,
+    },
+    /// `vec3 displace(vec3 position, vec3 normal, vec2 uv, float time)`を定義するGLSL
+    ///
+    /// `tempura/displacement.glsl`はインクルード済みなので、その関数も使える。
+    Custom(&'static str),
+}
+
+impl VertexDisplacement {
+    pub(crate) fn validate(&self) -> Result<()> {
+        let values: &[f32] = match self {
+            Self::Wind {
+                direction,
+                strength,
+                frequency,
+            } => &[
+                direction[0],
+                direction[1],
+                direction[2],
+                *strength,
+                *frequency,
+            ],
+            Self::FlagWave {
+                amplitude,
+                wavelength,
+                speed,
+            } => {
+                if *wavelength <= 0.0 {
+                    return Err(RendererError::Validation(format!(
+                        "flag wave wavelength must be positive, not {}",
+                        wavelength
+                    )));
+                }
+                &[*amplitude, *wavelength, *speed]
+            }
+            Self::Breathing { amplitude, rate } => &[*amplitude, *rate],
+            Self::Custom(source) => {
+                if !source.contains("displace") {
+                    return Err(RendererError::Validation(
+                        "custom vertex displacement does not define displace()".to_owned(),
+                    ));
+                }
+                &[]
+            }
+        };
+        if values.iter().any(|value| !value.is_finite()) {
+            return Err(RendererError::Validation(format!(
+                "vertex displacement has a non-finite parameter: {:?}",
+                self
+            )));
+        }
+        Ok(())
+    }
+
+    /// `displace`関数のGLSL
+    fn function(&self) -> String {
+        const SIGNATURE: &str = "vec3 displace(vec3 position, vec3 normal, vec2 uv, float time)";
+        // `{:?}`は整数値でも小数点を付けるので、GLSLのfloatのリテラルになる
+        match self {
+            Self::Wind {
+                direction: [x, y, z],
+                strength,
+                frequency,
+            } => format!(
+                "{} {{\n    return tempura_wind(position, vec3({:?}, {:?}, {:?}), {:?}, {:?}, time);\n}}\n",
+                SIGNATURE, x, y, z, strength, frequency
+            ),
+            Self::FlagWave {
+                amplitude,
+                wavelength,
+                speed,
+            } => format!(
+                "{} {{\n    return tempura_flag_wave(position, normal, uv, {:?}, {:?}, {:?}, time);\n}}\n",
+                SIGNATURE, amplitude, wavelength, speed
+            ),
+            Self::Breathing { amplitude, rate } => format!(
+                "{} {{\n    return tempura_breathing(position, normal, {:?}, {:?}, time);\n}}\n",
+                SIGNATURE, amplitude, rate
+            ),
+            Self::Custom(source) => format!("{}\n", source.trim_end()),
+        }
+    }
+}
+
+/// 頂点シェーダーを生成するパス
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VertexPass {
+    /// `pbr_pipeline_builder`のパス。ワールド空間の位置と法線、UVを出力する
+    Forward,
+    /// `shadow_pipeline_builder`と`point_shadow_pipeline_builder`、深度のプリパス
+    Depth,
+    /// 今と前のフレームのクリップ座標を出力する。フラグメントシェーダーで
+    /// `(current_clip.xy / current_clip.w - previous_clip.xy / previous_clip.w) * 0.5`を
+    /// `MOTION_VECTOR_FORMAT`のイメージに書き込むと、TAAのモーションベクトルと同じ単位になる
+    MotionVectors,
+}
+
+/// 変位のユニフォームバッファの内容。std140と同じ配置
+///
+/// ```glsl
+/// layout(set = N, binding = 0) uniform Displacement {
+///     mat4 previous_view_projection; // 前のフレームの`set_view_projection`の行列
+///     float time;
+///     float previous_time;
+/// };
+/// ```
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisplacementUniform {
+    pub previous_view_projection: Mat4,
+    pub time: f32,
+    pub previous_time: f32,
+}
+
+/// `update_vertex_displacement`で作るフレームコンテキストごとのユニフォームバッファ
+///
+/// バッファは`Renderer`のバッファとして破棄される。
+pub struct DisplacementUniforms {
+    pub buffers: PerFrame<BufferId>,
+    pub descriptor_sets: Vec<vk::DescriptorSet>,
+    /// 最後に書き込んだ時刻と、そのときのビュー射影行列。次のフレームの「前のフレーム」になる
+    time: f32,
+    view_projection: Mat4,
+}
+
+/// `pass`の頂点シェーダーのGLSLを、`displacement`を埋め込んで生成する
+///
+/// 頂点は[`ObjVertex`](super::ObjVertex)の配置、プッシュ定数は[`PbrPushConstants`](super::PbrPushConstants)。
+/// 変位があるか`VertexPass::MotionVectors`なら、[`DisplacementUniform`]をセット`uniform_set`から読む。
+/// パイプラインの`descriptor_set_layouts`のその位置に`displacement_set_layout`を入れる。
+/// 生成したソースは`GL_GOOGLE_include_directive`と[`shader_include`](super::shader_include)で
+/// コンパイルする。同じ変位から全てのパスを生成すれば、影やモーションベクトルが
+/// 描画と食い違わない。
+pub fn displaced_vertex_shader(
+    pass: VertexPass,
+    displacement: Option<&VertexDisplacement>,
+    uniform_set: u32,
+) -> String {
+    vertex_shader_source(pass, displacement, uniform_set, false)
+}
+
+/// `output_uv`なら`VertexPass::Depth`でもロケーション0にUVを出力する。アルファテスト用
+pub(crate) fn vertex_shader_source(
+    pass: VertexPass,
+    displacement: Option<&VertexDisplacement>,
+    uniform_set: u32,
+    output_uv: bool,
+) -> String {
+    let mut source = String::from(
+        "#version 450\n\
+         #extension GL_GOOGLE_include_directive : require\n\
+         #include \"tempura/displacement.glsl\"\n\n\
+         layout(location = 0) in vec3 in_position;\n\
+         layout(location = 1) in vec3 in_normal;\n\
+         layout(location = 2) in vec2 in_uv;\n\
+         layout(push_constant) uniform PbrPushConstants { mat4 model; mat4 view_projection; };\n",
+    );
+    if displacement.is_some() || pass == VertexPass::MotionVectors {
+        // Stringへの書き込みは失敗しない
+        let _ = writeln!(
+            source,
+            "layout(set = {}, binding = 0) uniform Displacement {{ mat4 previous_view_projection; float time; float previous_time; }};",
+            uniform_set
+        );
+    } else {
+        source.push_str("const float time = 0.0;\n");
+    }
+    source.push('\n');
+    match displacement {
+        Some(displacement) => source.push_str(&displacement.function()),
+        None => source.push_str(
+            "vec3 displace(vec3 position, vec3 normal, vec2 uv, float time) {\n    return position;\n}\n",
+        ),
+    }
+    source.push('\n');
+    source.push_str(match pass {
+        VertexPass::Forward => {
+            "layout(location = 0) out vec3 world_position;\n\
+             layout(location = 1) out vec3 world_normal;\n\
+             layout(location = 2) out vec2 uv;\n\
+             void main() {\n    \
+                 vec4 world = model * vec4(displace(in_position, in_normal, in_uv, time), 1.0);\n    \
+                 world_position = world.xyz;\n    \
+                 world_normal = mat3(transpose(inverse(model))) * in_normal;\n    \
+                 uv = in_uv;\n    \
+                 gl_Position = view_projection * world;\n\
+             }\n"
+        }
+        VertexPass::Depth if output_uv => {
+            "layout(location = 0) out vec2 uv;\n\
+             void main() {\n    \
+                 uv = in_uv;\n    \
+                 gl_Position = view_projection * model * vec4(displace(in_position, in_normal, in_uv, time), 1.0);\n\
+             }\n"
+        }
+        VertexPass::Depth => {
+            "void main() {\n    \
+                 gl_Position = view_projection * model * vec4(displace(in_position, in_normal, in_uv, time), 1.0);\n\
+             }\n"
+        }
+        VertexPass::MotionVectors => {
+            "layout(location = 0) out vec4 current_clip;\n\
+             layout(location = 1) out vec4 previous_clip;\n\
+             void main() {\n    \
+                 current_clip = view_projection * model * vec4(displace(in_position, in_normal, in_uv, time), 1.0);\n    \
+                 previous_clip = previous_view_projection * model *\n        \
+                     vec4(displace(in_position, in_normal, in_uv, previous_time), 1.0);\n    \
+                 gl_Position = current_clip;\n\
+             }\n"
+        }
+    });
+    source
+}
+
+impl Renderer {
+    /// [`DisplacementUniform`]のデスクリプタセットレイアウト
+    pub fn displacement_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[DescriptorBinding::uniform_buffer(
+            0,
+            vk::ShaderStageFlags::VERTEX,
+        )])
+    }
+
+    /// マテリアルの変位を埋め込んだ`pass`の頂点シェーダーのGLSLを返す
+    pub fn material_vertex_shader(
+        &self,
+        material: MaterialId,
+        pass: VertexPass,
+        uniform_set: u32,
+    ) -> Result<String> {
+        let material = self.materials.get(material).ok_or_else(|| {
+            RendererError::Validation(format!("material {:?} was already destroyed", material))
+        })?;
+        Ok(displaced_vertex_shader(
+            pass,
+            material.desc.displacement.as_ref(),
+            uniform_set,
+        ))
+    }
+
+    /// 変位の時刻を記録中のフレームのユニフォームバッファに書き込み、
+    /// `displacement_set_layout`のデスクリプタセットを返す
+    ///
+    /// 前のフレームの時刻とビュー射影行列も書き込むので、`set_view_projection`の後に
+    /// 毎フレーム1回だけ呼ぶ。最初のフレームでは前のフレームの値を今のフレームと同じにする。
+    pub fn update_vertex_displacement(&mut self, time: f32) -> Result<vk::DescriptorSet> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "vertex displacement can only be updated between begin_frame and end_frame"
+                    .to_owned(),
+            ));
+        }
+        if !time.is_finite() {
+            return Err(RendererError::Validation(format!(
+                "vertex displacement time must be finite, not {}",
+                time
+            )));
+        }
+        let mut uniforms = match self.vertex_displacement.take() {
+            Some(uniforms) => uniforms,
+            None => self.create_displacement_uniforms(time)?,
+        };
+        let uniform = DisplacementUniform {
+            previous_view_projection: uniforms.view_projection,
+            time,
+            previous_time: uniforms.time,
+        };
+        let result = self.write_per_frame_buffer(&mut uniforms.buffers, &[uniform]);
+        if result.is_ok() {
+            uniforms.time = time;
+            uniforms.view_projection = self.view_projection;
+        }
+        let descriptor_set = uniforms.descriptor_sets[self.current_frame];
+        self.vertex_displacement = Some(uniforms);
+        result.map(|_| descriptor_set)
+    }
+
+    fn create_displacement_uniforms(&mut self, time: f32) -> Result<DisplacementUniforms> {
+        let layout = self.displacement_set_layout()?;
+        let buffers = self.create_per_frame_buffers(
+            std::mem::size_of::<DisplacementUniform>() as vk::DeviceSize,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+        )?;
+        let mut descriptor_sets = Vec::with_capacity(buffers.len());
+        let ids: Vec<BufferId> = buffers.iter().copied().collect();
+        for id in ids {
+            let descriptor_set = match self.allocate_descriptor_set(layout) {
+                Ok(set) => set,
+                Err(err) => {
+                    self.destroy_per_frame_buffers(buffers)?;
+                    return Err(err);
+                }
+            };
+            unsafe {
+                DescriptorWriter::new()
+                    .buffer(
+                        0,
+                        vk::DescriptorType::UNIFORM_BUFFER,
+                        self.buffers.get(id).unwrap(),
+                    )
+                    .update(&self.device, descriptor_set);
+            }
+            descriptor_sets.push(descriptor_set);
+        }
+        Ok(DisplacementUniforms {
+            buffers,
+            descriptor_sets,
+            time,
+            view_projection: self.view_projection,
+        })
+    }
+}
@@ -0,0 +1,224 @@
+use super::error::{RendererError, Result};
+use ash::extensions::khr::{self, Surface};
+use ash::{vk, Entry, Instance};
+use raw_window_handle::HasRawWindowHandle;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+/// VK_KHR_displayで直接出力できるディスプレイ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisplayInfo {
+    pub name: String,
+    pub physical_resolution: vk::Extent2D,
+    pub modes: Vec<DisplayModeInfo>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayModeInfo {
+    pub visible_region: vk::Extent2D,
+    /// ミリヘルツ
+    pub refresh_rate: u32,
+}
+
+/// 出力するディスプレイとモード。番号は[`enumerate_displays`]が返す順番
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DisplaySelection {
+    pub display_index: usize,
+    /// `None`なら解像度が最も大きく、その中でリフレッシュレートが最も高いモード
+    pub mode_index: Option<usize>,
+}
+
+/// スワップチェインを作るサーフェスの作り方
+#[derive(Clone, Copy)]
+pub(crate) enum SurfaceSource<'a> {
+    Window(&'a dyn HasRawWindowHandle),
+    Display(DisplaySelection),
+}
+
+impl SurfaceSource<'_> {
+    pub(crate) fn required_instance_extensions(&self) -> Result<Vec<*const c_char>> {
+        match self {
+            SurfaceSource::Window(window_handle) => {
+                Ok(ash_window::enumerate_required_extensions(window_handle)?.to_vec())
+            }
+            SurfaceSource::Display(_) => Ok(display_instance_extensions()
+                .iter()
+                .map(|name| name.as_ptr())
+                .collect()),
+        }
+    }
+
+    pub(crate) unsafe fn create_surface(
+        &self,
+        entry: &Entry,
+        instance: &Instance,
+    ) -> Result<vk::SurfaceKHR> {
+        match self {
+            SurfaceSource::Window(window_handle) => Ok(ash_window::create_surface(
+                entry,
+                instance,
+                window_handle,
+                None,
+            )?),
+            SurfaceSource::Display(selection) => create_display_surface(entry, instance, selection),
+        }
+    }
+}
+
+fn display_instance_extensions() -> [&'static CStr; 2] {
+    [Surface::name(), khr::Display::name()]
+}
+
+/// ウィンドウシステムを使わずに出力できるディスプレイを列挙する
+///
+/// 一時的なインスタンスを作って調べる。VK_KHR_displayに対応していない環境では
+/// `RendererError::MissingExtension`を返す。
+pub fn enumerate_displays() -> Result<Vec<DisplayInfo>> {
+    unsafe {
+        let entry = Entry::linked();
+        let extension_names: Vec<*const c_char> = display_instance_extensions()
+            .iter()
+            .map(|name| name.as_ptr())
+            .collect();
+        let available_extensions = entry.enumerate_instance_extension_properties(None)?;
+        for name in display_instance_extensions() {
+            if !available_extensions
+                .iter()
+                .any(|extension| CStr::from_ptr(extension.extension_name.as_ptr()) == name)
+            {
+                return Err(RendererError::MissingExtension(
+                    name.to_string_lossy().into_owned(),
+                ));
+            }
+        }
+        let create_info =
+            *vk::InstanceCreateInfo::builder().enabled_extension_names(&extension_names);
+        let instance = entry.create_instance(&create_info, None)?;
+        let result = query_displays(&entry, &instance).and_then(|displays| {
+            let display_loader = khr::Display::new(&entry, &instance);
+            displays
+                .iter()
+                .map(|&(pdevice, properties)| {
+                    let modes = display_loader
+                        .get_display_mode_properties(pdevice, properties.display)?
+                        .iter()
+                        .map(|mode| DisplayModeInfo {
+                            visible_region: mode.parameters.visible_region,
+                            refresh_rate: mode.parameters.refresh_rate,
+                        })
+                        .collect();
+                    let name = if properties.display_name.is_null() {
+                        String::new()
+                    } else {
+                        CStr::from_ptr(properties.display_name)
+                            .to_string_lossy()
+                            .into_owned()
+                    };
+                    Ok(DisplayInfo {
+                        name,
+                        physical_resolution: properties.physical_resolution,
+                        modes,
+                    })
+                })
+                .collect()
+        });
+        instance.destroy_instance(None);
+        result
+    }
+}
+
+/// 全ての物理デバイスのディスプレイを、物理デバイスの順に並べる
+unsafe fn query_displays(
+    entry: &Entry,
+    instance: &Instance,
+) -> Result<Vec<(vk::PhysicalDevice, vk::DisplayPropertiesKHR)>> {
+    let display_loader = khr::Display::new(entry, instance);
+    let mut displays = Vec::new();
+    for pdevice in instance.enumerate_physical_devices()? {
+        for properties in display_loader.get_physical_device_display_properties(pdevice)? {
+            displays.push((pdevice, properties));
+        }
+    }
+    Ok(displays)
+}
+
+unsafe fn create_display_surface(
+    entry: &Entry,
+    instance: &Instance,
+    selection: &DisplaySelection,
+) -> Result<vk::SurfaceKHR> {
+    let display_loader = khr::Display::new(entry, instance);
+    let displays = query_displays(entry, instance)?;
+    let &(pdevice, display_properties) =
+        displays.get(selection.display_index).ok_or_else(|| {
+            RendererError::Validation(format!(
+                "display {} does not exist ({} displays found)",
+                selection.display_index,
+                displays.len()
+            ))
+        })?;
+    let display = display_properties.display;
+
+    let modes = display_loader.get_display_mode_properties(pdevice, display)?;
+    let mode = match selection.mode_index {
+        Some(index) => modes.get(index).ok_or_else(|| {
+            RendererError::Validation(format!(
+                "display mode {} does not exist ({} modes found)",
+                index,
+                modes.len()
+            ))
+        })?,
+        None => modes
+            .iter()
+            .max_by_key(|mode| {
+                let region = mode.parameters.visible_region;
+                (
+                    region.width as u64 * region.height as u64,
+                    mode.parameters.refresh_rate,
+                )
+            })
+            .ok_or_else(|| RendererError::Validation("display has no modes".to_owned()))?,
+    };
+
+    // ディスプレイに出力でき、他のディスプレイで使われていないプレーンを探す
+    let planes = display_loader.get_physical_device_display_plane_properties(pdevice)?;
+    let mut plane = None;
+    for (index, properties) in planes.iter().enumerate() {
+        if properties.current_display != vk::DisplayKHR::null()
+            && properties.current_display != display
+        {
+            continue;
+        }
+        let supported =
+            display_loader.get_display_plane_supported_displays(pdevice, index as u32)?;
+        if supported.contains(&display) {
+            plane = Some((index as u32, properties.current_stack_index));
+            break;
+        }
+    }
+    let (plane_index, plane_stack_index) = plane.ok_or_else(|| {
+        RendererError::Validation("no display plane can present to the display".to_owned())
+    })?;
+
+    let capabilities =
+        display_loader.get_display_plane_capabilities(pdevice, mode.display_mode, plane_index)?;
+    let alpha_mode = [
+        vk::DisplayPlaneAlphaFlagsKHR::OPAQUE,
+        vk::DisplayPlaneAlphaFlagsKHR::GLOBAL,
+        vk::DisplayPlaneAlphaFlagsKHR::PER_PIXEL,
+        vk::DisplayPlaneAlphaFlagsKHR::PER_PIXEL_PREMULTIPLIED,
+    ]
+    .into_iter()
+    .find(|&alpha_mode| capabilities.supported_alpha.contains(alpha_mode))
+    .unwrap_or(vk::DisplayPlaneAlphaFlagsKHR::OPAQUE);
+
+    let create_info = *vk::DisplaySurfaceCreateInfoKHR::builder()
+        .display_mode(mode.display_mode)
+        .plane_index(plane_index)
+        .plane_stack_index(plane_stack_index)
+        .transform(vk::SurfaceTransformFlagsKHR::IDENTITY)
+        .global_alpha(1.0)
+        .alpha_mode(alpha_mode)
+        .image_extent(mode.parameters.visible_region);
+    Ok(display_loader.create_display_plane_surface(&create_info, None)?)
+}
@@ -0,0 +1,346 @@
+use super::error::{RendererError, Result};
+use super::renderer::depth_aspect_mask;
+use super::{GraphicsPipeline, PipelineBuilder, Renderer, RendererConfig};
+use ash::extensions::khr;
+use ash::{vk, Device, Instance};
+
+/// 動的レンダリングをコアと拡張のどちらで使うか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DynamicRenderingSupport {
+    /// Vulkan 1.3のコア機能
+    Core,
+    /// VK_KHR_dynamic_rendering。依存する拡張がコアに入っているVulkan 1.2以降でだけ使う
+    Extension,
+}
+
+/// 動的レンダリングのコマンドの呼び出し先
+#[derive(Clone)]
+pub enum DynamicRendering {
+    Core,
+    Extension(khr::DynamicRendering),
+}
+
+impl DynamicRendering {
+    /// # Safety
+    ///
+    /// `rendering_info`の内容が有効で、`command_buffer`が記録中であること。
+    pub unsafe fn cmd_begin_rendering(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        rendering_info: &vk::RenderingInfo,
+    ) {
+        match self {
+            Self::Core => device.cmd_begin_rendering(command_buffer, rendering_info),
+            Self::Extension(loader) => loader.cmd_begin_rendering(command_buffer, rendering_info),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `command_buffer`で動的レンダリングを開始していること。
+    pub unsafe fn cmd_end_rendering(&self, device: &Device, command_buffer: vk::CommandBuffer) {
+        match self {
+            Self::Core => device.cmd_end_rendering(command_buffer),
+            Self::Extension(loader) => loader.cmd_end_rendering(command_buffer),
+        }
+    }
+}
+
+/// `begin_rendering`に渡すアタッチメント
+#[derive(Clone, Copy)]
+pub struct RenderingAttachment {
+    pub image_view: vk::ImageView,
+    pub image_layout: vk::ImageLayout,
+    pub load_op: vk::AttachmentLoadOp,
+    pub store_op: vk::AttachmentStoreOp,
+    pub clear_value: vk::ClearValue,
+    /// マルチサンプルの結果を平均して書き込む先
+    pub resolve: Option<(vk::ImageView, vk::ImageLayout)>,
+}
+
+impl RenderingAttachment {
+    /// 開始時に`clear_value`でクリアして、結果を保存する
+    pub fn clear(
+        image_view: vk::ImageView,
+        image_layout: vk::ImageLayout,
+        clear_value: vk::ClearValue,
+    ) -> Self {
+        Self {
+            image_view,
+            image_layout,
+            load_op: vk::AttachmentLoadOp::CLEAR,
+            store_op: vk::AttachmentStoreOp::STORE,
+            clear_value,
+            resolve: None,
+        }
+    }
+
+    /// 以前の内容を読み込んで、結果を保存する
+    pub fn load(image_view: vk::ImageView, image_layout: vk::ImageLayout) -> Self {
+        Self {
+            image_view,
+            image_layout,
+            load_op: vk::AttachmentLoadOp::LOAD,
+            store_op: vk::AttachmentStoreOp::STORE,
+            clear_value: vk::ClearValue::default(),
+            resolve: None,
+        }
+    }
+
+    pub fn store_op(mut self, store_op: vk::AttachmentStoreOp) -> Self {
+        self.store_op = store_op;
+        self
+    }
+
+    /// 終了時に`image_view`へ解決する
+    pub fn resolve(mut self, image_view: vk::ImageView, image_layout: vk::ImageLayout) -> Self {
+        self.resolve = Some((image_view, image_layout));
+        self
+    }
+
+    pub(crate) fn info(&self) -> vk::RenderingAttachmentInfo {
+        let mut info = *vk::RenderingAttachmentInfo::builder()
+            .image_view(self.image_view)
+            .image_layout(self.image_layout)
+            .load_op(self.load_op)
+            .store_op(self.store_op)
+            .clear_value(self.clear_value);
+        if let Some((image_view, image_layout)) = self.resolve {
+            info.resolve_mode = vk::ResolveModeFlags::AVERAGE;
+            info.resolve_image_view = image_view;
+            info.resolve_image_layout = image_layout;
+        }
+        info
+    }
+}
+
+/// `pdevice`で動的レンダリングを使えるか調べる
+///
+/// 機能の問い合わせに`get_physical_device_features2`を使うので、Vulkan 1.2未満の設定では使わない。
+pub(crate) unsafe fn query_dynamic_rendering_support(
+    instance: &Instance,
+    pdevice: vk::PhysicalDevice,
+    config: &RendererConfig,
+) -> Result<Option<DynamicRenderingSupport>> {
+    if config.api_version < vk::API_VERSION_1_2 {
+        return Ok(None);
+    }
+    let properties = instance.get_physical_device_properties(pdevice);
+    let support = if config.api_version >= vk::API_VERSION_1_3
+        && properties.api_version >= vk::API_VERSION_1_3
+    {
+        DynamicRenderingSupport::Core
+    } else {
+        let available_extensions = instance.enumerate_device_extension_properties(pdevice)?;
+        let has_extension = available_extensions.iter().any(|extension| {
+            std::ffi::CStr::from_ptr(extension.extension_name.as_ptr())
+                == khr::DynamicRendering::name()
+        });
+        if !has_extension {
+            return Ok(None);
+        }
+        DynamicRenderingSupport::Extension
+    };
+
+    let mut dynamic_rendering_features = vk::PhysicalDeviceDynamicRenderingFeatures::default();
+    let mut features =
+        vk::PhysicalDeviceFeatures2::builder().push_next(&mut dynamic_rendering_features);
+    instance.get_physical_device_features2(pdevice, &mut features);
+    Ok((dynamic_rendering_features.dynamic_rendering == vk::TRUE).then_some(support))
+}
+
+impl Renderer {
+    /// 動的レンダリングを開始する。ビューポートとシザーは`render_area`に設定する
+    ///
+    /// 動的レンダリングが有効でない場合は`RendererError::Validation`を返す。
+    pub fn begin_rendering(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        render_area: vk::Rect2D,
+        color_attachments: &[RenderingAttachment],
+        depth_attachment: Option<RenderingAttachment>,
+    ) -> Result<()> {
+        let dynamic_rendering = self.dynamic_rendering.as_ref().ok_or_else(|| {
+            RendererError::Validation("dynamic rendering is not enabled".to_owned())
+        })?;
+        let color_infos: Vec<_> = color_attachments
+            .iter()
+            .map(RenderingAttachment::info)
+            .collect();
+        let depth_info = depth_attachment.map(|attachment| attachment.info());
+        let mut rendering_info = vk::RenderingInfo::builder()
+            .render_area(render_area)
+            .layer_count(1)
+            .color_attachments(&color_infos);
+        if let Some(depth_info) = depth_info.as_ref() {
+            rendering_info = rendering_info.depth_attachment(depth_info);
+            // ステンシルを含むフォーマットでは同じビューをステンシルにも指定する
+            if depth_aspect_mask(self.depth_format).contains(vk::ImageAspectFlags::STENCIL) {
+                rendering_info = rendering_info.stencil_attachment(depth_info);
+            }
+        }
+        unsafe {
+            dynamic_rendering.cmd_begin_rendering(&self.device, command_buffer, &rendering_info);
+            self.set_viewport_and_scissor(command_buffer, render_area);
+        }
+        Ok(())
+    }
+
+    pub fn end_rendering(&self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        let dynamic_rendering = self.dynamic_rendering.as_ref().ok_or_else(|| {
+            RendererError::Validation("dynamic rendering is not enabled".to_owned())
+        })?;
+        unsafe { dynamic_rendering.cmd_end_rendering(&self.device, command_buffer) };
+        Ok(())
+    }
+
+    /// `begin_swapchain_rendering`の中で使うパイプラインを作成する
+    ///
+    /// 動的レンダリングが有効ならスワップチェインと深度バッファのフォーマットを、
+    /// そうでなければフォワードパスを指定して作成する。サンプル数は`msaa_samples`になる。
+    pub fn create_swapchain_pipeline(&self, builder: &PipelineBuilder) -> Result<GraphicsPipeline> {
+        if self.dynamic_rendering.is_some() {
+            let builder = builder
+                .clone()
+                .rendering_formats(&[self.surface_format.format], self.depth_format)
+                .samples(self.msaa_samples);
+            self.create_graphics_pipeline(&builder, vk::RenderPass::null(), 0)
+        } else {
+            self.create_graphics_pipeline(builder, self.forward_pass.render_pass, 0)
+        }
+    }
+
+    /// 取得中のスワップチェインイメージと深度バッファへの描画を開始する
+    ///
+    /// 動的レンダリングが有効ならそれを使い、そうでなければフォワードパスにフォールバックする。
+    /// MSAAが有効な場合はマルチサンプルのカラーバッファに描画し、終了時にスワップチェインイメージへ解決する。
+    pub fn begin_swapchain_rendering(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        clear_color: [f32; 4],
+    ) -> Result<()> {
+        if self.dynamic_rendering.is_none() {
+            self.begin_forward_pass(command_buffer, clear_color);
+            return Ok(());
+        }
+
+        let present_image = self.present_images[self.present_index as usize];
+        let present_image_view = self.present_image_views[self.present_index as usize];
+        let (depth_old_layout, depth) = self.main_pass_depth_attachment();
+        unsafe {
+            // レンダーパスのサブパス依存関係に相当するバリア
+            let mut barriers = vec![
+                *vk::ImageMemoryBarrier::builder()
+                    .image(present_image)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                    .subresource_range(color_subresource_range()),
+                *vk::ImageMemoryBarrier::builder()
+                    .image(self.depth_image)
+                    .old_layout(depth_old_layout)
+                    .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                    .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                    .dst_access_mask(
+                        vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                    )
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: depth_aspect_mask(self.depth_format),
+                        ..color_subresource_range()
+                    }),
+            ];
+            if let Some(target) = self.msaa_color_target {
+                barriers.push(
+                    *vk::ImageMemoryBarrier::builder()
+                        .image(target.image)
+                        .old_layout(vk::ImageLayout::UNDEFINED)
+                        .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                        .subresource_range(color_subresource_range()),
+                );
+            }
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &barriers,
+            );
+        }
+
+        let render_area = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: self.surface_resolution,
+        };
+        let clear_value = vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: clear_color,
+            },
+        };
+        let color = match self.msaa_color_target {
+            // マルチサンプルの内容は解決した後に要らない
+            Some(target) => RenderingAttachment::clear(
+                target.view,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                clear_value,
+            )
+            .store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .resolve(
+                present_image_view,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            ),
+            None => RenderingAttachment::clear(
+                present_image_view,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                clear_value,
+            ),
+        };
+        let depth = depth.store_op(vk::AttachmentStoreOp::DONT_CARE);
+        self.begin_rendering(command_buffer, render_area, &[color], Some(depth))
+    }
+
+    /// `begin_swapchain_rendering`で開始した描画を終了し、スワップチェインイメージを表示可能にする
+    pub fn end_swapchain_rendering(&self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        if self.dynamic_rendering.is_none() {
+            self.end_forward_pass(command_buffer);
+            return Ok(());
+        }
+
+        self.end_rendering(command_buffer)?;
+        let present_image = self.present_images[self.present_index as usize];
+        let to_present = *vk::ImageMemoryBarrier::builder()
+            .image(present_image)
+            .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .subresource_range(color_subresource_range());
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_present],
+            );
+        }
+        Ok(())
+    }
+}
+
+fn color_subresource_range() -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
@@ -0,0 +1,486 @@
+use super::assets::load_hdr;
+use super::error::{RendererError, Result};
+use super::ibl::ImageBasedLighting;
+use super::memory::{Allocation, MemoryAllocator};
+use super::texture::create_image;
+use super::{
+    Camera, ComputePipeline, DescriptorBinding, DescriptorWriter, GraphicsPipeline, Mat4,
+    PipelineBuilder, Renderer, ShaderId, TextureId,
+};
+use ash::{vk, Device};
+use std::path::Path;
+
+/// 環境マップの変換とスカイボックスの描画に使うシェーダー
+///
+/// `equirect_to_cube`はエントリーポイント`main`のコンピュートシェーダーで、
+/// 正距円筒図法の画像をキューブマップの各面に書き込む。
+///
+/// ```glsl
+/// layout(local_size_x = 8, local_size_y = 8) in;
+/// layout(set = 0, binding = 0) uniform sampler2D equirect;
+/// layout(set = 0, binding = 1, rgba16f) uniform writeonly imageCube cube;
+///
+/// void main() {
+///     ivec3 id = ivec3(gl_GlobalInvocationID);
+///     int size = imageSize(cube).x;
+///     if (id.x >= size || id.y >= size) return;
+///     // 面ごとの方向はVulkanのキューブマップの規約に従う
+///     vec2 st = (vec2(id.xy) + 0.5) / float(size) * 2.0 - 1.0;
+///     vec3 d = normalize(face_direction(id.z, st));
+///     vec2 uv = vec2(atan(d.z, d.x) / (2.0 * PI) + 0.5, acos(d.y) / PI);
+///     imageStore(cube, id, vec4(textureLod(equirect, uv, 0.0).rgb, 1.0));
+/// }
+/// ```
+///
+/// `skybox_vertex`と`skybox_fragment`は[`SkyboxPushConstants`]を受け取り、頂点バッファなしで
+/// 画面全体を覆う三角形を描く。
+///
+/// ```glsl
+/// // 頂点シェーダー
+/// layout(push_constant) uniform Skybox { mat4 view; mat4 projection; };
+/// layout(location = 0) out vec3 direction;
+/// void main() {
+///     vec2 ndc = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2) * 2.0 - 1.0;
+///     // 透視投影の逆変換で視線方向を求め、ビューの回転だけを戻す
+///     vec3 view_dir = vec3((ndc.x + projection[2][0]) / projection[0][0],
+///                          (ndc.y + projection[2][1]) / projection[1][1], -1.0);
+///     direction = transpose(mat3(view)) * view_dir;
+///     gl_Position = vec4(ndc, 0.0, 1.0);
+/// }
+///
+/// // フラグメントシェーダー
+/// layout(set = 0, binding = 0) uniform samplerCube environment;
+/// layout(location = 0) in vec3 direction;
+/// layout(location = 0) out vec4 color;
+/// void main() { color = vec4(texture(environment, direction).rgb, 1.0); }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnvironmentShaders {
+    pub equirect_to_cube: ShaderId,
+    pub skybox_vertex: ShaderId,
+    pub skybox_fragment: ShaderId,
+}
+
+/// `skybox_pipeline_builder`のパイプラインに渡すプッシュ定数。頂点シェーダーで使う
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SkyboxPushConstants {
+    pub view: Mat4,
+    pub projection: Mat4,
+}
+
+/// HDR画像から変換した環境マップのキューブマップ
+pub struct Environment {
+    pub image: vk::Image,
+    /// キューブのビュー。変換時のストレージイメージとしても使う
+    pub view: vk::ImageView,
+    pub allocation: Allocation,
+    pub sampler: vk::Sampler,
+    /// キューブマップの各面の幅と高さ
+    pub resolution: u32,
+    /// `environment_set_layout`のデスクリプタセット
+    pub descriptor_set: vk::DescriptorSet,
+    /// `set_ibl_shaders`を設定していれば前計算される
+    pub ibl: Option<ImageBasedLighting>,
+}
+
+impl Environment {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこの環境マップを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        if let Some(ibl) = &self.ibl {
+            ibl.destroy(device, allocator);
+        }
+        device.destroy_sampler(self.sampler, None);
+        device.destroy_image_view(self.view, None);
+        device.destroy_image(self.image, None);
+        allocator.free(device, self.allocation);
+    }
+}
+
+const ENVIRONMENT_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+const EQUIRECT_TO_CUBE_GROUP_SIZE: u32 = 8;
+
+/// 正距円筒図法の画像の幅から、キューブマップの面の解像度を決める
+fn cube_resolution(equirect_width: u32) -> u32 {
+    (equirect_width / 4).clamp(16, 2048)
+}
+
+impl Renderer {
+    /// `set_environment`で使うシェーダーを設定する
+    pub fn set_environment_shaders(&mut self, shaders: EnvironmentShaders) {
+        self.environment_shaders = Some(shaders);
+    }
+
+    /// スカイボックスのデスクリプタセットレイアウト。バインディング0がキューブマップ
+    pub fn environment_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[DescriptorBinding::sampled_image(
+            0,
+            vk::ShaderStageFlags::FRAGMENT,
+        )])
+    }
+
+    /// 正距円筒図法のHDR画像を読み込み、キューブマップに変換して環境マップにする
+    ///
+    /// 変換はコンピュートシェーダーで行い、完了を待ってから戻る。`set_ibl_shaders`を
+    /// 設定していれば、イメージベースドライティングも前計算する。前の環境マップは使用中の
+    /// フレームが完了してから破棄する。先に`set_environment_shaders`を呼ぶこと。
+    pub fn set_environment<P: AsRef<Path>>(&mut self, hdr_path: P) -> Result<()> {
+        let shaders = self.environment_shaders.ok_or_else(|| {
+            RendererError::Validation(
+                "set_environment_shaders must be called before set_environment".to_owned(),
+            )
+        })?;
+        let hdr = load_hdr(hdr_path)?;
+        let equirect = self.create_texture_from_levels(
+            ENVIRONMENT_FORMAT,
+            hdr.width,
+            hdr.height,
+            &[&hdr.to_rgba16f()],
+        )?;
+        let environment = self.convert_equirect_to_cube(
+            equirect,
+            shaders.equirect_to_cube,
+            cube_resolution(hdr.width),
+        );
+        self.destroy_texture(equirect)?;
+        let mut environment = environment?;
+        if let Some(shaders) = self.ibl_shaders {
+            match self.create_image_based_lighting(&environment, shaders) {
+                Ok(ibl) => environment.ibl = Some(ibl),
+                Err(err) => {
+                    // 変換の完了を待っているので、すぐに破棄できる
+                    unsafe { environment.destroy(&self.device, &mut self.allocator) };
+                    return Err(err);
+                }
+            }
+        }
+        if let Some(old) = self.environment.replace(environment) {
+            self.destroy_environment(old);
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してから環境マップを破棄する
+    pub fn clear_environment(&mut self) {
+        if let Some(environment) = self.environment.take() {
+            self.destroy_environment(environment);
+        }
+    }
+
+    pub fn environment(&self) -> Option<&Environment> {
+        self.environment.as_ref()
+    }
+
+    /// スカイボックス用に設定したパイプラインビルダーを返す
+    ///
+    /// 深度テストは`depth_compare_op`で行い、深度は書き込まない。不透明なジオメトリの後に
+    /// 描けば、何も描かれていない画素だけが塗られる。カメラの`depth_compare_op`を渡すこと。
+    pub fn skybox_pipeline_builder(
+        &mut self,
+        depth_compare_op: vk::CompareOp,
+    ) -> Result<PipelineBuilder> {
+        let shaders = self.environment_shaders.ok_or_else(|| {
+            RendererError::Validation("environment shaders are not set".to_owned())
+        })?;
+        let module = |id: ShaderId| {
+            self.shader_modules
+                .get(id)
+                .map(|shader| shader.module)
+                .ok_or_else(|| {
+                    RendererError::Validation(format!("shader {:?} was already destroyed", id))
+                })
+        };
+        let vertex_module = module(shaders.skybox_vertex)?;
+        let fragment_module = module(shaders.skybox_fragment)?;
+        let layout = self.environment_set_layout()?;
+        Ok(PipelineBuilder::new()
+            .vertex_shader(vertex_module)
+            .fragment_shader(fragment_module)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .depth_write(false)
+            .depth_compare_op(depth_compare_op)
+            .descriptor_set_layouts(&[layout])
+            .push_constant_range(
+                vk::ShaderStageFlags::VERTEX,
+                0,
+                std::mem::size_of::<SkyboxPushConstants>() as u32,
+            ))
+    }
+
+    /// 環境マップをスカイボックスとして描く
+    ///
+    /// ビューポートの深度範囲を最も遠い深度に固定するので、ジオメトリより奥に描かれる。
+    /// 描いた後は`render_area`のビューポートを通常の深度範囲に戻す。
+    /// パイプラインは`skybox_pipeline_builder`で作ったもので、パイプラインと互換性のある
+    /// レンダーパスもしくは動的レンダリングの中で呼ぶこと。
+    pub fn draw_skybox(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        pipeline: &GraphicsPipeline,
+        camera: &Camera,
+        render_area: vk::Rect2D,
+    ) -> Result<()> {
+        let environment = self
+            .environment
+            .as_ref()
+            .ok_or_else(|| RendererError::Validation("no environment map is set".to_owned()))?;
+        let push_constants = SkyboxPushConstants {
+            view: camera.view_matrix(),
+            projection: camera.projection_matrix(),
+        };
+        let data: Vec<u8> = push_constants
+            .view
+            .iter()
+            .chain(push_constants.projection.iter())
+            .flatten()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect();
+        let depth = camera.clear_depth();
+        let viewport = vk::Viewport {
+            x: render_area.offset.x as f32,
+            y: render_area.offset.y as f32,
+            width: render_area.extent.width as f32,
+            height: render_area.extent.height as f32,
+            min_depth: depth,
+            max_depth: depth,
+        };
+        unsafe {
+            self.device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+            self.device
+                .cmd_set_scissor(command_buffer, 0, &[render_area]);
+            self.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.pipeline,
+            );
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.layout,
+                0,
+                &[environment.descriptor_set],
+                &[],
+            );
+            self.push_constants(command_buffer, pipeline, &data);
+            self.device.cmd_draw(command_buffer, 3, 1, 0, 0);
+            self.set_viewport_and_scissor(command_buffer, render_area);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn destroy_environment(&mut self, environment: Environment) {
+        self.destroy_deferred(move |device, allocator| unsafe {
+            environment.destroy(device, allocator)
+        });
+    }
+
+    fn convert_equirect_to_cube(
+        &mut self,
+        equirect: TextureId,
+        shader: ShaderId,
+        resolution: u32,
+    ) -> Result<Environment> {
+        let compute_layout = self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, vk::ShaderStageFlags::COMPUTE),
+            DescriptorBinding::new(
+                1,
+                vk::DescriptorType::STORAGE_IMAGE,
+                vk::ShaderStageFlags::COMPUTE,
+            ),
+        ])?;
+        let environment_layout = self.environment_set_layout()?;
+        let pipeline = self.create_compute_pipeline(shader, "main", &[compute_layout], 0)?;
+        let result = unsafe { self.create_environment_cube(resolution) }.and_then(|environment| {
+            let converted = self
+                .allocate_descriptor_set(compute_layout)
+                .and_then(|compute_set| {
+                    let texture = self.textures.get(equirect).ok_or_else(|| {
+                        RendererError::Validation(format!("texture {:?} was destroyed", equirect))
+                    })?;
+                    unsafe {
+                        DescriptorWriter::new()
+                            .texture(0, texture)
+                            .image(
+                                1,
+                                vk::DescriptorType::STORAGE_IMAGE,
+                                vk::DescriptorImageInfo {
+                                    sampler: vk::Sampler::null(),
+                                    image_view: environment.view,
+                                    image_layout: vk::ImageLayout::GENERAL,
+                                },
+                            )
+                            .update(&self.device, compute_set);
+                    }
+                    self.record_equirect_to_cube(&pipeline, compute_set, &environment)
+                })
+                .and_then(|_| self.allocate_descriptor_set(environment_layout));
+            match converted {
+                Ok(descriptor_set) => {
+                    unsafe {
+                        DescriptorWriter::new()
+                            .image(
+                                0,
+                                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                                vk::DescriptorImageInfo {
+                                    sampler: environment.sampler,
+                                    image_view: environment.view,
+                                    image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                                },
+                            )
+                            .update(&self.device, descriptor_set);
+                    }
+                    Ok(Environment {
+                        descriptor_set,
+                        ..environment
+                    })
+                }
+                Err(err) => {
+                    // 変換の完了を待っているので、すぐに破棄できる
+                    unsafe { environment.destroy(&self.device, &mut self.allocator) };
+                    Err(err)
+                }
+            }
+        });
+        self.destroy_compute_pipeline(pipeline);
+        result
+    }
+
+    /// レイアウトを遷移させながら変換のディスパッチを提出し、完了を待つ
+    fn record_equirect_to_cube(
+        &self,
+        pipeline: &ComputePipeline,
+        compute_set: vk::DescriptorSet,
+        environment: &Environment,
+    ) -> Result<()> {
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 6,
+        };
+        let to_general = *vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::GENERAL)
+            .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(environment.image)
+            .subresource_range(subresource_range);
+        let to_read_only = *vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::GENERAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(environment.image)
+            .subresource_range(subresource_range);
+        let groups = environment.resolution.div_ceil(EQUIRECT_TO_CUBE_GROUP_SIZE);
+        self.submit_setup_commands(|device, command_buffer| unsafe {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_general],
+            );
+            device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                pipeline.pipeline,
+            );
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                pipeline.layout,
+                0,
+                &[compute_set],
+                &[],
+            );
+            device.cmd_dispatch(command_buffer, groups, groups, 6);
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_read_only],
+            );
+        })
+    }
+
+    /// ストレージイメージとしても使える6レイヤーのキューブマップとサンプラーを作る
+    unsafe fn create_environment_cube(&mut self, resolution: u32) -> Result<Environment> {
+        let image_create_info = *vk::ImageCreateInfo::builder()
+            .flags(vk::ImageCreateFlags::CUBE_COMPATIBLE)
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(ENVIRONMENT_FORMAT)
+            .extent(vk::Extent3D {
+                width: resolution,
+                height: resolution,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(6)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let (image, allocation) = create_image(
+            &self.device,
+            &mut self.allocator,
+            &image_create_info,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            true,
+        )?;
+        let view_create_info = *vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::CUBE)
+            .format(ENVIRONMENT_FORMAT)
+            .subresource_range(
+                *vk::ImageSubresourceRange::builder()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .level_count(1)
+                    .layer_count(6),
+            );
+        let view = match self.device.create_image_view(&view_create_info, None) {
+            Ok(view) => view,
+            Err(err) => {
+                self.device.destroy_image(image, None);
+                self.allocator.free(&self.device, allocation);
+                return Err(err.into());
+            }
+        };
+        let sampler_info = *vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .max_lod(vk::LOD_CLAMP_NONE);
+        let sampler = match self.device.create_sampler(&sampler_info, None) {
+            Ok(sampler) => sampler,
+            Err(err) => {
+                self.device.destroy_image_view(view, None);
+                self.device.destroy_image(image, None);
+                self.allocator.free(&self.device, allocation);
+                return Err(err.into());
+            }
+        };
+        Ok(Environment {
+            image,
+            view,
+            allocation,
+            sampler,
+            resolution,
+            descriptor_set: vk::DescriptorSet::null(),
+            ibl: None,
+        })
+    }
+}
@@ -0,0 +1,67 @@
+use ash::vk;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum RendererError {
+    Vulkan(vk::Result),
+    MissingLayer(String),
+    MissingExtension(String),
+    NoSuitableDevice,
+    NoSurfaceFormat,
+    NoSuitableDepthFormat,
+    NoSuitableMemoryType,
+    InvalidSpirv(String),
+    /// モデルなどのアセットファイルの内容が不正
+    InvalidAsset(String),
+    Io(std::io::Error),
+    /// クレートのAPIの誤用
+    Validation(String),
+}
+
+pub type Result<T> = std::result::Result<T, RendererError>;
+
+impl fmt::Display for RendererError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RendererError::Vulkan(result) => write!(f, "Vulkan error: {}", result),
+            RendererError::MissingLayer(name) => write!(f, "missing instance layer: {}", name),
+            RendererError::MissingExtension(name) => write!(f, "missing extension: {}", name),
+            RendererError::NoSuitableDevice => {
+                write!(f, "no physical device supports graphics and presentation")
+            }
+            RendererError::NoSurfaceFormat => write!(f, "surface reports no supported formats"),
+            RendererError::NoSuitableDepthFormat => {
+                write!(f, "no depth format usable as a depth attachment")
+            }
+            RendererError::NoSuitableMemoryType => {
+                write!(f, "no memory type satisfies the requested properties")
+            }
+            RendererError::InvalidSpirv(reason) => write!(f, "invalid SPIR-V: {}", reason),
+            RendererError::InvalidAsset(reason) => write!(f, "invalid asset: {}", reason),
+            RendererError::Io(err) => write!(f, "I/O error: {}", err),
+            RendererError::Validation(message) => write!(f, "validation error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for RendererError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RendererError::Vulkan(result) => Some(result),
+            RendererError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<vk::Result> for RendererError {
+    fn from(result: vk::Result) -> Self {
+        RendererError::Vulkan(result)
+    }
+}
+
+impl From<std::io::Error> for RendererError {
+    fn from(err: std::io::Error) -> Self {
+        RendererError::Io(err)
+    }
+}
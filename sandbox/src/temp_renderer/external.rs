@@ -0,0 +1,181 @@
+use super::conditional_rendering::{query_conditional_rendering_support, ConditionalRendering};
+use super::dynamic_rendering::{query_dynamic_rendering_support, DynamicRenderingSupport};
+use super::error::{RendererError, Result};
+use super::gpu_driven::query_draw_indirect_count_support;
+use super::renderer::{create_debug_call_back, DeviceContext};
+use super::texture_format::TextureFormatSupport;
+use super::{Renderer, RendererConfig};
+use ash::extensions::ext::DebugUtils;
+use ash::extensions::khr::{self, Surface, Swapchain};
+use ash::{vk, Device, Entry, Instance};
+use std::ffi::{CStr, CString};
+
+/// アプリケーションが作成して管理するVulkanのコンテキスト
+///
+/// レンダラーはこれらを使うだけで破棄しない。
+#[derive(Clone)]
+pub struct ExternalContext {
+    pub entry: Entry,
+    pub instance: Instance,
+    pub physical_device: vk::PhysicalDevice,
+    pub device: Device,
+    /// グラフィックスと`surface`への表示ができるキューファミリー
+    pub queue_family_index: u32,
+    /// `queue_family_index`のキュー。レンダラーが提出と表示に使う
+    pub queue: vk::Queue,
+    /// スワップチェインを作るサーフェス
+    pub surface: vk::SurfaceKHR,
+    /// インスタンス作成時に有効にした拡張
+    pub instance_extensions: Vec<CString>,
+    /// デバイス作成時に有効にした拡張
+    pub device_extensions: Vec<CString>,
+    /// デバイス作成時に有効にした機能
+    pub enabled_features: vk::PhysicalDeviceFeatures,
+}
+
+impl ExternalContext {
+    fn has_instance_extension(&self, name: &CStr) -> bool {
+        self.instance_extensions
+            .iter()
+            .any(|extension| extension.as_c_str() == name)
+    }
+
+    fn has_device_extension(&self, name: &CStr) -> bool {
+        self.device_extensions
+            .iter()
+            .any(|extension| extension.as_c_str() == name)
+    }
+}
+
+impl Renderer {
+    /// アプリケーションが作成したインスタンスとデバイスでレンダラーを作成する
+    ///
+    /// VK_KHR_surfaceとVK_KHR_swapchain、`config`で指定した拡張が有効になっているかを確認する。
+    /// デバッグメッセンジャーは、`config`とインスタンスの両方でVK_EXT_debug_utilsが有効な場合
+    /// だけ作る。動的レンダリングと条件付きレンダリングは、拡張がデバイスで有効になっている
+    /// (動的レンダリングはVulkan 1.3のコアでもよい)場合だけ使う。
+    ///
+    /// # Safety
+    /// `context`のオブジェクトは全て同じインスタンスから作られた有効なもので、レンダラーの
+    /// 破棄まで破棄しないこと。動的レンダリングと条件付きレンダリングを使う場合は、デバイス
+    /// 作成時にその機能を有効にしていること。レンダラーと同時に`queue`を他のスレッドから
+    /// 使わないこと。
+    pub unsafe fn from_external(context: ExternalContext, config: RendererConfig) -> Result<Self> {
+        let ExternalContext {
+            ref entry,
+            ref instance,
+            physical_device: pdevice,
+            queue_family_index,
+            ..
+        } = context;
+
+        let mut required_instance_extensions = vec![Surface::name()];
+        required_instance_extensions.extend(
+            config
+                .required_instance_extensions()
+                .into_iter()
+                .filter(|&name| name != DebugUtils::name()),
+        );
+        let mut required_device_extensions = vec![Swapchain::name()];
+        required_device_extensions
+            .extend(config.device_extensions.iter().map(|name| name.as_c_str()));
+        if let Some(missing) = required_instance_extensions
+            .into_iter()
+            .find(|&name| !context.has_instance_extension(name))
+            .or_else(|| {
+                required_device_extensions
+                    .into_iter()
+                    .find(|&name| !context.has_device_extension(name))
+            })
+        {
+            return Err(RendererError::MissingExtension(
+                missing.to_string_lossy().into_owned(),
+            ));
+        }
+
+        let properties = instance.get_physical_device_properties(pdevice);
+        if properties.api_version < config.api_version {
+            return Err(RendererError::Validation(format!(
+                "external device supports Vulkan {}.{}, but the config requests {}.{}",
+                vk::api_version_major(properties.api_version),
+                vk::api_version_minor(properties.api_version),
+                vk::api_version_major(config.api_version),
+                vk::api_version_minor(config.api_version)
+            )));
+        }
+        let queue_families = instance.get_physical_device_queue_family_properties(pdevice);
+        let supports_graphics = queue_families
+            .get(queue_family_index as usize)
+            .is_some_and(|info| info.queue_flags.contains(vk::QueueFlags::GRAPHICS));
+        let surface_loader = Surface::new(entry, instance);
+        if !supports_graphics
+            || !surface_loader.get_physical_device_surface_support(
+                pdevice,
+                queue_family_index,
+                context.surface,
+            )?
+        {
+            return Err(RendererError::Validation(format!(
+                "queue family {} must support graphics and presentation to the surface",
+                queue_family_index
+            )));
+        }
+
+        let dynamic_rendering_support = if config.dynamic_rendering {
+            query_dynamic_rendering_support(instance, pdevice, &config)?.filter(|&support| {
+                support == DynamicRenderingSupport::Core
+                    || context.has_device_extension(khr::DynamicRendering::name())
+            })
+        } else {
+            None
+        };
+        let conditional_rendering_support = config.conditional_rendering
+            && context.has_device_extension(ConditionalRendering::name())
+            && query_conditional_rendering_support(instance, pdevice, &config)?;
+        // 間接描画の機能はアプリケーションが有効にしている場合だけ使う
+        let draw_indirect_count_support = config.draw_indirect_count
+            && context.has_device_extension(khr::DrawIndirectCount::name())
+            && context.enabled_features.multi_draw_indirect == vk::TRUE
+            && context.enabled_features.draw_indirect_first_instance == vk::TRUE
+            && query_draw_indirect_count_support(instance, pdevice)?;
+        // 有効にされていない圧縮フォーマットの機能は使わない
+        let supported = TextureFormatSupport::query(instance, pdevice);
+        let features = &context.enabled_features;
+        let texture_format_support = TextureFormatSupport {
+            astc_ldr: supported.astc_ldr && features.texture_compression_astc_ldr == vk::TRUE,
+            etc2: supported.etc2 && features.texture_compression_etc2 == vk::TRUE,
+            bc: supported.bc && features.texture_compression_bc == vk::TRUE,
+        };
+
+        let device_properties =
+            gpu_alloc_ash::device_properties(instance, config.api_version, pdevice)?;
+        // デバッグメッセンジャーは最後に作り、失敗時の破棄は`from_device_context`に任せる
+        let debug_callback =
+            if config.enable_debug_utils && context.has_instance_extension(DebugUtils::name()) {
+                let debug_utils_loader = DebugUtils::new(entry, instance);
+                create_debug_call_back(&debug_utils_loader)?
+            } else {
+                vk::DebugUtilsMessengerEXT::null()
+            };
+
+        Self::from_device_context(
+            DeviceContext {
+                entry: context.entry,
+                instance: context.instance,
+                debug_callback,
+                surface: context.surface,
+                pdevice,
+                device: context.device,
+                queue_family_index,
+                present_queue: context.queue,
+                dynamic_rendering_support,
+                conditional_rendering_support,
+                draw_indirect_count_support,
+                texture_format_support,
+                device_properties,
+                external: true,
+            },
+            config,
+        )
+    }
+}
@@ -0,0 +1,86 @@
+use super::descriptor::DescriptorAllocator;
+use super::error::Result;
+use ash::{vk, Device};
+
+pub const MAX_FRAMES_IN_FLIGHT: usize = 3;
+
+/// フレームごとに独立して持つ同期オブジェクトとコマンドバッファ、一時的なデスクリプタセット
+pub struct FrameContext {
+    pub command_buffer: vk::CommandBuffer,
+    pub in_flight_fence: vk::Fence,
+    pub image_available_semaphore: vk::Semaphore,
+    pub render_finished_semaphore: vk::Semaphore,
+    pub descriptor_allocator: DescriptorAllocator,
+    /// `split_submission`で続きを記録するコマンドバッファ。必要になったときに割り当てる
+    pub split_command_buffers: Vec<vk::CommandBuffer>,
+    /// 記録中のコマンドバッファ。0なら`command_buffer`、それ以外は`split_command_buffers`の1つ前
+    pub recording_index: usize,
+    /// このフレームの提出で、スワップチェインイメージの取得をすでに待ったか
+    pub swapchain_image_waited: bool,
+    /// 次の提出で待つ外部のセマフォ
+    pub external_waits: Vec<(vk::Semaphore, vk::PipelineStageFlags)>,
+    /// `end_frame`の提出でシグナルする外部のセマフォ
+    pub external_signals: Vec<vk::Semaphore>,
+}
+
+impl FrameContext {
+    pub(crate) unsafe fn new(device: &Device, command_pool: vk::CommandPool) -> Result<Self> {
+        let command_buffer_allocate_info = *vk::CommandBufferAllocateInfo::builder()
+            .command_buffer_count(1)
+            .command_pool(command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY);
+        // 失敗してもコマンドバッファはコマンドプールと一緒に破棄される
+        let command_buffer = device.allocate_command_buffers(&command_buffer_allocate_info)?[0];
+
+        let fence_create_info =
+            *vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+        let in_flight_fence = device.create_fence(&fence_create_info, None)?;
+
+        let semaphore_create_info = vk::SemaphoreCreateInfo::default();
+        let image_available_semaphore = match device.create_semaphore(&semaphore_create_info, None)
+        {
+            Ok(semaphore) => semaphore,
+            Err(err) => {
+                device.destroy_fence(in_flight_fence, None);
+                return Err(err.into());
+            }
+        };
+        let render_finished_semaphore = match device.create_semaphore(&semaphore_create_info, None)
+        {
+            Ok(semaphore) => semaphore,
+            Err(err) => {
+                device.destroy_semaphore(image_available_semaphore, None);
+                device.destroy_fence(in_flight_fence, None);
+                return Err(err.into());
+            }
+        };
+
+        Ok(Self {
+            command_buffer,
+            in_flight_fence,
+            image_available_semaphore,
+            render_finished_semaphore,
+            descriptor_allocator: DescriptorAllocator::default(),
+            split_command_buffers: Vec::new(),
+            recording_index: 0,
+            swapchain_image_waited: false,
+            external_waits: Vec::new(),
+            external_signals: Vec::new(),
+        })
+    }
+
+    pub fn recording_command_buffer(&self) -> vk::CommandBuffer {
+        match self.recording_index {
+            0 => self.command_buffer,
+            index => self.split_command_buffers[index - 1],
+        }
+    }
+
+    /// コマンドバッファはコマンドプールと一緒に破棄される
+    pub(crate) unsafe fn destroy(&mut self, device: &Device) {
+        self.descriptor_allocator.destroy(device);
+        device.destroy_semaphore(self.render_finished_semaphore, None);
+        device.destroy_semaphore(self.image_available_semaphore, None);
+        device.destroy_fence(self.in_flight_fence, None);
+    }
+}
@@ -0,0 +1,531 @@
+use super::error::{RendererError, Result};
+use super::{
+    Aabb, BufferAccess, BufferId, ComputePipeline, DescriptorBinding, DescriptorWriter, Frustum,
+    GraphicsPipeline, MeshId, PerFrame, Renderer, ShaderId, Submesh,
+};
+use ash::extensions::khr;
+use ash::{vk, Device, Instance};
+use std::ffi::CStr;
+
+/// カリングのシェーダーのワークグループの大きさ
+pub const GPU_CULLING_GROUP_SIZE: u32 = 64;
+
+/// `VkDrawIndexedIndirectCommand`のバイト数
+const DRAW_COMMAND_SIZE: u32 = 20;
+
+/// GPUでカリングするインスタンス1つ分の箱と描く範囲
+///
+/// 箱はワールド空間で、`Aabb::transform`でメッシュの箱から求める。
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct GpuDrawInstance {
+    pub bounds_min: [f32; 3],
+    pub index_count: u32,
+    pub bounds_max: [f32; 3],
+    pub first_index: u32,
+    pub vertex_offset: i32,
+    padding: [u32; 3],
+}
+
+impl GpuDrawInstance {
+    pub fn new(bounds: &Aabb, submesh: &Submesh) -> Self {
+        Self {
+            bounds_min: bounds.min,
+            index_count: submesh.range.end - submesh.range.start,
+            bounds_max: bounds.max,
+            first_index: submesh.range.start,
+            vertex_offset: submesh.vertex_offset,
+            padding: [0; 3],
+        }
+    }
+}
+
+/// 視錐台の外のインスタンスを除き、間接描画のコマンドを詰めて書くコンピュートシェーダー
+///
+/// コマンドの`firstInstance`にインスタンスの番号を入れるので、頂点シェーダーは
+/// `gl_InstanceIndex`でインスタンスごとのデータを読める。
+///
+/// ```glsl
+/// layout(local_size_x = 64) in;
+/// struct Instance {
+///     vec3 bounds_min; uint index_count; vec3 bounds_max; uint first_index;
+///     int vertex_offset; uint pad0, pad1, pad2;
+/// };
+/// struct DrawCommand {
+///     uint index_count; uint instance_count; uint first_index; int vertex_offset;
+///     uint first_instance;
+/// };
+/// layout(set = 0, binding = 0) readonly buffer Instances { Instance instances[]; };
+/// layout(set = 0, binding = 1) writeonly buffer Commands { DrawCommand commands[]; };
+/// // occluded_countは`HiZShaders::cull`だけが使う
+/// layout(set = 0, binding = 2) buffer Count { uint draw_count; uint occluded_count; };
+/// // Frustum::planesと同じく、dot(n, p) + d >= 0が内側
+/// layout(push_constant) uniform Params { vec4 planes[6]; uint instance_count; };
+/// void main() {
+///     uint i = gl_GlobalInvocationID.x;
+///     if (i >= instance_count) return;
+///     Instance instance = instances[i];
+///     for (int p = 0; p < 6; ++p) {
+///         vec3 n = planes[p].xyz;
+///         vec3 far = mix(instance.bounds_min, instance.bounds_max, greaterThanEqual(n, vec3(0.0)));
+///         if (dot(n, far) + planes[p].w < 0.0) return;
+///     }
+///     uint slot = atomicAdd(draw_count, 1);
+///     commands[slot] = DrawCommand(
+///         instance.index_count, 1, instance.first_index, instance.vertex_offset, i);
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpuDrivenShaders {
+    pub cull: ShaderId,
+}
+
+/// `enable_gpu_driven`で作るパイプライン
+pub struct GpuDriven {
+    pub pipeline: ComputePipeline,
+}
+
+impl GpuDriven {
+    /// # Safety
+    /// `device`は作成に使ったデバイスで、GPUがこのパイプラインを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device) {
+        self.pipeline.destroy(device);
+    }
+}
+
+/// インスタンスごとにアプリケーションが付けるデータ
+///
+/// 色の変化やダメージのマスクのように、マテリアルを分けずにオブジェクトごとに変えたい値を
+/// シェーダーに渡す。`set_instance_data`で設定し、頂点シェーダーとフラグメントシェーダーで
+/// `gl_InstanceIndex`番目の要素を読む。要素の配置はstd430に合わせる。
+///
+/// ```glsl
+/// struct InstanceData { vec4 tint; float damage; uint flags; uint pad0, pad1; };
+/// layout(set = 1, binding = 0) readonly buffer Instances { InstanceData instance_data[]; };
+/// layout(location = 3) flat out uint out_instance;
+/// void main() {
+///     out_instance = gl_InstanceIndex;
+///     // ...
+/// }
+/// ```
+pub struct InstanceData {
+    /// 1インスタンス分のバイト数
+    pub stride: u32,
+    /// フレームコンテキストごとのバッファ。カリングの前に記録中のフレームのものへ書き込む
+    pub buffers: PerFrame<BufferId>,
+    /// フレームコンテキストごとの`instance_data_set_layout`のデスクリプタセット
+    pub descriptor_sets: Vec<vk::DescriptorSet>,
+    data: Vec<u8>,
+}
+
+/// 1つのメッシュをGPUでカリングして描くインスタンスの集まり
+///
+/// インスタンスはデバイスローカルのバッファに置くので、変える場合はバッチを作り直す。
+/// バッファは`destroy_gpu_driven_batch`で破棄する。メッシュは破棄しない。
+pub struct GpuDrivenBatch {
+    pub mesh: MeshId,
+    pub instances: BufferId,
+    pub instance_count: u32,
+    /// カリングを通ったインスタンスの`VkDrawIndexedIndirectCommand`。後半の
+    /// `instance_count`個にはオクルージョンで除いたインスタンスのコマンドを書く
+    pub commands: PerFrame<BufferId>,
+    /// `commands`の前半と後半に書いたコマンドの数
+    pub counts: PerFrame<BufferId>,
+    /// フレームコンテキストごとの`gpu_driven_set_layout`のデスクリプタセット
+    pub descriptor_sets: Vec<vk::DescriptorSet>,
+    /// `set_instance_data`で付けたデータ
+    pub instance_data: Option<InstanceData>,
+    /// フレームコンテキストごとの、最後にカリングしたフレームの`frame_count`
+    culled_frames: Vec<Option<u64>>,
+}
+
+/// 拡張と、間接描画の複数のコマンドと`firstInstance`の機能に対応していれば`true`
+pub(crate) unsafe fn query_draw_indirect_count_support(
+    instance: &Instance,
+    pdevice: vk::PhysicalDevice,
+) -> Result<bool> {
+    let available_extensions = instance.enumerate_device_extension_properties(pdevice)?;
+    let has_extension = available_extensions.iter().any(|extension| {
+        CStr::from_ptr(extension.extension_name.as_ptr()) == khr::DrawIndirectCount::name()
+    });
+    let features = instance.get_physical_device_features(pdevice);
+    Ok(has_extension
+        && features.multi_draw_indirect == vk::TRUE
+        && features.draw_indirect_first_instance == vk::TRUE)
+}
+
+impl Renderer {
+    /// カリングのデスクリプタセットレイアウト。バインディング0から2がストレージバッファ
+    pub fn gpu_driven_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        let bindings: Vec<_> = (0..3)
+            .map(|binding| {
+                DescriptorBinding::storage_buffer(binding, vk::ShaderStageFlags::COMPUTE)
+            })
+            .collect();
+        self.descriptor_set_layout(&bindings)
+    }
+
+    /// インスタンスごとのデータのデスクリプタセットレイアウト。バインディング0が
+    /// 頂点シェーダーとフラグメントシェーダーから読むストレージバッファ
+    pub fn instance_data_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[DescriptorBinding::storage_buffer(
+            0,
+            vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+        )])
+    }
+
+    /// `cull_gpu_driven_batch`と`draw_gpu_driven_batch`を使えるようにする
+    ///
+    /// `RendererConfig::draw_indirect_count`が有効になっていない場合は
+    /// `RendererError::Validation`を返す。
+    pub fn enable_gpu_driven(&mut self, shaders: GpuDrivenShaders) -> Result<()> {
+        if self.draw_indirect_count.is_none() {
+            return Err(RendererError::Validation(
+                "gpu driven rendering needs draw indirect count support".to_owned(),
+            ));
+        }
+        let layout = self.gpu_driven_set_layout()?;
+        let pipeline = self.create_compute_pipeline(shaders.cull, "main", &[layout], 100)?;
+        if let Some(old) = self.gpu_driven.replace(GpuDriven { pipeline }) {
+            self.destroy_deferred(move |device, _| unsafe { old.destroy(device) });
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからパイプラインを破棄する
+    pub fn disable_gpu_driven(&mut self) {
+        if let Some(gpu_driven) = self.gpu_driven.take() {
+            self.destroy_deferred(move |device, _| unsafe { gpu_driven.destroy(device) });
+        }
+    }
+
+    pub fn gpu_driven(&self) -> Option<&GpuDriven> {
+        self.gpu_driven.as_ref()
+    }
+
+    /// インスタンスを転送し、カリングの結果を書くバッファを作る
+    ///
+    /// `mesh`はインデックス付きで、インスタンスの範囲はメッシュのインデックスに収める。
+    pub fn create_gpu_driven_batch(
+        &mut self,
+        mesh: MeshId,
+        instances: &[GpuDrawInstance],
+    ) -> Result<GpuDrivenBatch> {
+        let mesh_data = self.live_mesh(mesh)?;
+        let index_count = mesh_data.index_count;
+        if mesh_data.index_buffer.is_none() {
+            return Err(RendererError::Validation(
+                "gpu driven batches need an indexed mesh".to_owned(),
+            ));
+        }
+        if let Some(instance) = instances.iter().find(|instance| {
+            instance.first_index as u64 + instance.index_count as u64 > index_count as u64
+        }) {
+            return Err(RendererError::Validation(format!(
+                "instance indices {}+{} are outside the mesh's {} indices",
+                instance.first_index, instance.index_count, index_count
+            )));
+        }
+        let storage = vk::BufferUsageFlags::STORAGE_BUFFER;
+        let instance_buffer = self.create_buffer_with_data(instances, storage)?;
+        let mut buffers = vec![instance_buffer];
+        let result = self.create_gpu_driven_buffers(mesh, instances, &mut buffers);
+        match result {
+            Ok(batch) => Ok(batch),
+            Err(err) => {
+                for buffer in buffers {
+                    self.destroy_buffer(buffer)?;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// 使用中のフレームが完了してからバッチのバッファを破棄する
+    ///
+    /// デスクリプタセットはレンダラーの破棄まで解放されない。
+    pub fn destroy_gpu_driven_batch(&mut self, batch: GpuDrivenBatch) -> Result<()> {
+        let instance_data = batch
+            .instance_data
+            .map(|instance_data| instance_data.buffers.into_inner());
+        for buffer in std::iter::once(batch.instances)
+            .chain(batch.commands.into_inner())
+            .chain(batch.counts.into_inner())
+            .chain(instance_data.into_iter().flatten())
+        {
+            self.destroy_buffer(buffer)?;
+        }
+        Ok(())
+    }
+
+    /// インスタンスごとのデータを`data`で置き換える。`data`はインスタンスと同じ数にする
+    ///
+    /// 各フレームコンテキストのバッファには`cull_gpu_driven_batch`で転送するので、
+    /// 変わらないフレームでは呼ばなくてよい。
+    pub fn set_instance_data<T: Copy>(
+        &mut self,
+        batch: &mut GpuDrivenBatch,
+        data: &[T],
+    ) -> Result<()> {
+        let stride = std::mem::size_of::<T>();
+        if stride == 0 || !stride.is_multiple_of(4) {
+            return Err(RendererError::Validation(format!(
+                "instance data of {} bytes must be a non-zero multiple of 4",
+                stride
+            )));
+        }
+        if data.len() != batch.instance_count as usize {
+            return Err(RendererError::Validation(format!(
+                "{} instance data elements do not match {} instances",
+                data.len(),
+                batch.instance_count
+            )));
+        }
+        // `T`は`Copy`なので、バイト列として読んでもよい
+        let bytes = unsafe {
+            std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data))
+        }
+        .to_vec();
+        match &mut batch.instance_data {
+            Some(instance_data) if instance_data.stride == stride as u32 => {
+                instance_data.data = bytes;
+            }
+            _ => {
+                let instance_data = self.create_instance_data(stride as u32, bytes)?;
+                if let Some(old) = batch.instance_data.replace(instance_data) {
+                    self.destroy_per_frame_buffers(old.buffers)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 記録中のフレームの、インスタンスごとのデータのデスクリプタセット
+    ///
+    /// `draw_gpu_driven_batch`の`descriptor_sets`に、パイプラインのレイアウトの位置で渡す。
+    pub fn instance_data_set(&self, batch: &GpuDrivenBatch) -> Result<vk::DescriptorSet> {
+        let instance_data = batch.instance_data.as_ref().ok_or_else(|| {
+            RendererError::Validation("the batch has no instance data".to_owned())
+        })?;
+        Ok(instance_data.descriptor_sets[self.current_frame])
+    }
+
+    /// 記録中のフレームのバッファに、`frustum`と重なるインスタンスの描画コマンドを書く
+    ///
+    /// `begin_frame`と`end_frame`の間で、レンダーパスの外で呼ぶ。結果は間接描画から
+    /// 読めるように同期する。前のフレームで`build_hi_z`を呼んでいれば、その深度で
+    /// 隠れるインスタンスも除く。
+    pub fn cull_gpu_driven_batch(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        batch: &mut GpuDrivenBatch,
+        frustum: &Frustum,
+    ) -> Result<()> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "batches can only be culled between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        let gpu_driven = self.gpu_driven.as_ref().ok_or_else(|| {
+            RendererError::Validation("gpu driven rendering is not enabled".to_owned())
+        })?;
+        let (pipeline, occlusion_set) = match self.hi_z_occlusion()? {
+            Some((pipeline, descriptor_set)) => (pipeline, Some(descriptor_set)),
+            None => (&gpu_driven.pipeline, None),
+        };
+        let commands = *self.per_frame_mut(&mut batch.commands)?;
+        let count = *self.per_frame_mut(&mut batch.counts)?;
+        if let Some(instance_data) = &mut batch.instance_data {
+            self.write_per_frame_buffer(&mut instance_data.buffers, &instance_data.data)?;
+        }
+
+        // 前のフレームでこのバッファを読んだ描画は、フェンスを待ったので完了している
+        self.zero_buffer(command_buffer, count, 0, vk::WHOLE_SIZE)?;
+        let count_access = BufferAccess {
+            stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+            access: vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+        };
+        self.buffer_barrier(
+            command_buffer,
+            count,
+            BufferAccess::TRANSFER_WRITE,
+            count_access,
+        )?;
+        let mut data: Vec<u8> = frustum
+            .planes
+            .iter()
+            .flatten()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect();
+        data.extend_from_slice(&batch.instance_count.to_ne_bytes());
+        let mut descriptor_sets = vec![batch.descriptor_sets[self.current_frame]];
+        descriptor_sets.extend(occlusion_set);
+        self.dispatch(
+            command_buffer,
+            pipeline,
+            &descriptor_sets,
+            &data,
+            [batch.instance_count.div_ceil(GPU_CULLING_GROUP_SIZE), 1, 1],
+        )?;
+        self.buffer_barrier(
+            command_buffer,
+            commands,
+            BufferAccess::COMPUTE_SHADER_WRITE,
+            BufferAccess::INDIRECT_BUFFER,
+        )?;
+        self.buffer_barrier(
+            command_buffer,
+            count,
+            count_access,
+            BufferAccess::INDIRECT_BUFFER,
+        )?;
+        batch.culled_frames[self.current_frame] = Some(self.frame_count);
+        Ok(())
+    }
+
+    /// 今のフレームでカリングを通ったインスタンスを、1回の間接描画で描く
+    ///
+    /// 引数は`draw_mesh`と同じで、`begin_frame`と`end_frame`の間で、レンダーパスもしくは
+    /// 動的レンダリングの中で呼ぶ。先に同じフレームで`cull_gpu_driven_batch`を呼ぶこと。
+    pub fn draw_gpu_driven_batch(
+        &self,
+        batch: &GpuDrivenBatch,
+        pipeline: &GraphicsPipeline,
+        descriptor_sets: &[vk::DescriptorSet],
+        push_constants: &[u8],
+    ) -> Result<()> {
+        self.record_indirect_count_draw(batch, false, pipeline, descriptor_sets, push_constants)
+    }
+
+    /// 今のフレームでオクルージョンによって除いたインスタンスを描く
+    ///
+    /// カリングを確かめるデバッグ表示に使う。深度テストを無効にした単色のパイプラインなどで、
+    /// `draw_gpu_driven_batch`の後に重ねて描く。Hi-Zを使わなかったフレームでは何も描かない。
+    pub fn draw_occluded_instances(
+        &self,
+        batch: &GpuDrivenBatch,
+        pipeline: &GraphicsPipeline,
+        descriptor_sets: &[vk::DescriptorSet],
+        push_constants: &[u8],
+    ) -> Result<()> {
+        self.record_indirect_count_draw(batch, true, pipeline, descriptor_sets, push_constants)
+    }
+
+    fn record_indirect_count_draw(
+        &self,
+        batch: &GpuDrivenBatch,
+        occluded: bool,
+        pipeline: &GraphicsPipeline,
+        descriptor_sets: &[vk::DescriptorSet],
+        push_constants: &[u8],
+    ) -> Result<()> {
+        let draw_indirect_count = self.draw_indirect_count.as_ref().ok_or_else(|| {
+            RendererError::Validation(
+                "gpu driven rendering needs draw indirect count support".to_owned(),
+            )
+        })?;
+        if batch.culled_frames.get(self.current_frame).copied() != Some(Some(self.frame_count)) {
+            return Err(RendererError::Validation(
+                "the batch has not been culled in this frame".to_owned(),
+            ));
+        }
+        let commands = self.buffers.get(*self.per_frame(&batch.commands)).unwrap();
+        let count = self.buffers.get(*self.per_frame(&batch.counts)).unwrap();
+        let mesh = self.live_mesh(batch.mesh)?;
+        let command_buffer = self.bind_mesh(mesh, pipeline, descriptor_sets, push_constants)?;
+        unsafe {
+            draw_indirect_count.cmd_draw_indexed_indirect_count(
+                command_buffer,
+                commands.buffer,
+                occluded as u64 * batch.instance_count as u64 * DRAW_COMMAND_SIZE as u64,
+                count.buffer,
+                occluded as u64 * 4,
+                batch.instance_count,
+                DRAW_COMMAND_SIZE,
+            );
+        }
+        Ok(())
+    }
+
+    fn create_gpu_driven_buffers(
+        &mut self,
+        mesh: MeshId,
+        instances: &[GpuDrawInstance],
+        buffers: &mut Vec<BufferId>,
+    ) -> Result<GpuDrivenBatch> {
+        let instance_buffer = buffers[0];
+        let instance_count = instances.len() as u32;
+        let usage = vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::INDIRECT_BUFFER;
+        let commands_size = 2 * instance_count as vk::DeviceSize * DRAW_COMMAND_SIZE as u64;
+        let commands = self.create_per_frame(|renderer, _| {
+            renderer.create_buffer(commands_size, usage, vk::MemoryPropertyFlags::DEVICE_LOCAL)
+        })?;
+        buffers.extend(commands.iter().copied());
+        let counts = self.create_per_frame(|renderer, _| {
+            renderer.create_buffer(
+                8,
+                usage | vk::BufferUsageFlags::TRANSFER_DST,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            )
+        })?;
+        buffers.extend(counts.iter().copied());
+
+        let layout = self.gpu_driven_set_layout()?;
+        let command_ids: Vec<_> = commands.iter().copied().collect();
+        let count_ids: Vec<_> = counts.iter().copied().collect();
+        let mut descriptor_sets = Vec::with_capacity(command_ids.len());
+        for (command, count) in command_ids.into_iter().zip(count_ids) {
+            let descriptor_set = self.allocate_descriptor_set(layout)?;
+            let mut writer = DescriptorWriter::new();
+            for (binding, id) in [instance_buffer, command, count].into_iter().enumerate() {
+                let buffer = self.buffers.get(id).unwrap();
+                writer = writer.buffer(binding as u32, vk::DescriptorType::STORAGE_BUFFER, buffer);
+            }
+            unsafe { writer.update(&self.device, descriptor_set) };
+            descriptor_sets.push(descriptor_set);
+        }
+        let frame_count = self.frames.len();
+        Ok(GpuDrivenBatch {
+            mesh,
+            instances: instance_buffer,
+            instance_count,
+            commands,
+            counts,
+            descriptor_sets,
+            instance_data: None,
+            culled_frames: vec![None; frame_count],
+        })
+    }
+
+    fn create_instance_data(&mut self, stride: u32, data: Vec<u8>) -> Result<InstanceData> {
+        let buffers = self.create_per_frame_buffers(
+            data.len() as vk::DeviceSize,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        )?;
+        let layout = self.instance_data_set_layout()?;
+        let ids: Vec<_> = buffers.iter().copied().collect();
+        let mut descriptor_sets = Vec::with_capacity(ids.len());
+        for id in ids {
+            let descriptor_set = match self.allocate_descriptor_set(layout) {
+                Ok(descriptor_set) => descriptor_set,
+                Err(err) => {
+                    self.destroy_per_frame_buffers(buffers)?;
+                    return Err(err);
+                }
+            };
+            let buffer = self.buffers.get(id).unwrap();
+            unsafe {
+                DescriptorWriter::new()
+                    .buffer(0, vk::DescriptorType::STORAGE_BUFFER, buffer)
+                    .update(&self.device, descriptor_set)
+            };
+            descriptor_sets.push(descriptor_set);
+        }
+        Ok(InstanceData {
+            stride,
+            buffers,
+            descriptor_sets,
+            data,
+        })
+    }
+}
@@ -0,0 +1,492 @@
+use super::error::{RendererError, Result};
+use super::{BufferId, ComputePipeline, DescriptorBinding, DescriptorWriter, Renderer, ShaderId};
+use ash::{vk, Device};
+
+/// 基数ソートの1パスで並べるビット数
+pub const RADIX_BITS: u32 = 4;
+/// 基数ソートの1パスのバケットの数
+pub const RADIX_BUCKETS: u32 = 1 << RADIX_BITS;
+
+/// プレフィックスサムと基数ソートのコンピュートシェーダー
+///
+/// どれもワークグループの大きさを特殊化定数0で受け取り、セット0に
+/// `gpu_primitive_set_layout`を使う。`u32`の配列だけを扱う。
+///
+/// ```glsl
+/// layout(local_size_x_id = 0) in; // GpuPrimitiveSettings::group_size
+///
+/// // scan: 1つのワークグループが2 * group_size個の要素を排他的スキャンし、合計をsumsに書く
+/// layout(set = 0, binding = 0) buffer Data { uint data[]; };
+/// layout(set = 0, binding = 1) buffer Sums { uint sums[]; };
+/// layout(push_constant) uniform Params { uint count; uint write_sums; };
+/// shared uint temp[gl_WorkGroupSize.x * 2];
+/// void main() {
+///     uint n = gl_WorkGroupSize.x * 2, t = gl_LocalInvocationID.x, base = gl_WorkGroupID.x * n;
+///     temp[2 * t] = base + 2 * t < count ? data[base + 2 * t] : 0;
+///     temp[2 * t + 1] = base + 2 * t + 1 < count ? data[base + 2 * t + 1] : 0;
+///     uint offset = 1;
+///     for (uint d = n >> 1; d > 0; d >>= 1, offset <<= 1) {
+///         barrier();
+///         if (t < d) temp[offset * (2 * t + 2) - 1] += temp[offset * (2 * t + 1) - 1];
+///     }
+///     barrier();
+///     if (t == 0) {
+///         if (write_sums != 0) sums[gl_WorkGroupID.x] = temp[n - 1];
+///         temp[n - 1] = 0;
+///     }
+///     for (uint d = 1; d < n; d <<= 1) {
+///         offset >>= 1;
+///         barrier();
+///         if (t < d) {
+///             uint a = offset * (2 * t + 1) - 1, b = offset * (2 * t + 2) - 1;
+///             uint v = temp[a]; temp[a] = temp[b]; temp[b] += v;
+///         }
+///     }
+///     barrier();
+///     if (base + 2 * t < count) data[base + 2 * t] = temp[2 * t];
+///     if (base + 2 * t + 1 < count) data[base + 2 * t + 1] = temp[2 * t + 1];
+/// }
+///
+/// // scan_add: スキャンしたsumsをワークグループの要素に足す
+/// layout(push_constant) uniform Params { uint count; };
+/// void main() {
+///     uint i = gl_WorkGroupID.x * gl_WorkGroupSize.x * 2 + gl_LocalInvocationID.x;
+///     uint s = sums[gl_WorkGroupID.x];
+///     if (i < count) data[i] += s;
+///     if (i + gl_WorkGroupSize.x < count) data[i + gl_WorkGroupSize.x] += s;
+/// }
+///
+/// // radix_count: ワークグループごとの桁のヒストグラム。桁ごとにワークグループを並べるので、
+/// // 全体をスキャンするとそのまま書き込み位置になる
+/// layout(set = 0, binding = 0) buffer KeysIn { uint keys_in[]; };
+/// layout(set = 0, binding = 2) buffer Histogram { uint histogram[]; };
+/// layout(push_constant) uniform Params { uint count; uint shift; uint mask; };
+/// shared uint local_histogram[16];
+/// void main() {
+///     uint t = gl_LocalInvocationID.x, i = gl_GlobalInvocationID.x;
+///     if (t < 16) local_histogram[t] = 0;
+///     barrier();
+///     if (i < count) atomicAdd(local_histogram[(keys_in[i] >> shift) & mask], 1);
+///     barrier();
+///     if (t < 16) histogram[t * gl_NumWorkGroups.x + gl_WorkGroupID.x] = local_histogram[t];
+/// }
+///
+/// // radix_scatter: 同じ桁の前の要素の数で順位を決めるので、安定なソートになる
+/// layout(set = 0, binding = 1) buffer KeysOut { uint keys_out[]; };
+/// layout(set = 0, binding = 3) buffer ValuesIn { uint values_in[]; };
+/// layout(set = 0, binding = 4) buffer ValuesOut { uint values_out[]; };
+/// layout(push_constant) uniform Params { uint count; uint shift; uint mask; uint has_values; };
+/// shared uint digits[gl_WorkGroupSize.x];
+/// void main() {
+///     uint t = gl_LocalInvocationID.x, i = gl_GlobalInvocationID.x;
+///     uint key = i < count ? keys_in[i] : 0;
+///     uint digit = i < count ? (key >> shift) & mask : 16;
+///     digits[t] = digit;
+///     barrier();
+///     if (i >= count) return;
+///     uint rank = 0;
+///     for (uint j = 0; j < t; ++j) rank += digits[j] == digit ? 1 : 0;
+///     uint dst = histogram[digit * gl_NumWorkGroups.x + gl_WorkGroupID.x] + rank;
+///     keys_out[dst] = key;
+///     if (has_values != 0) values_out[dst] = values_in[i];
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpuPrimitiveShaders {
+    pub scan: ShaderId,
+    pub scan_add: ShaderId,
+    pub radix_count: ShaderId,
+    pub radix_scatter: ShaderId,
+}
+
+/// プレフィックスサムと基数ソートの設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpuPrimitiveSettings {
+    /// ワークグループの大きさ。32から1024の2の累乗
+    ///
+    /// 速い大きさはGPUによって違うので、`begin_budget_scope`で囲んで計測して選ぶ。
+    pub group_size: u32,
+    /// 一度に扱う要素の最大数。作業用のバッファをこの大きさで確保する
+    pub max_elements: u32,
+}
+
+impl Default for GpuPrimitiveSettings {
+    fn default() -> Self {
+        Self {
+            group_size: 256,
+            max_elements: 1 << 20,
+        }
+    }
+}
+
+/// `enable_gpu_primitives`で作るパイプラインと作業用のバッファ
+///
+/// バッファは`Renderer`のバッファとして別に破棄される。
+pub struct GpuPrimitives {
+    pub settings: GpuPrimitiveSettings,
+    pub scan_pipeline: ComputePipeline,
+    pub scan_add_pipeline: ComputePipeline,
+    pub radix_count_pipeline: ComputePipeline,
+    pub radix_scatter_pipeline: ComputePipeline,
+    /// スキャンの段ごとのワークグループの合計
+    pub scan_sums: Vec<BufferId>,
+    pub sort_keys: BufferId,
+    pub sort_values: BufferId,
+    pub sort_histogram: BufferId,
+}
+
+impl GpuPrimitives {
+    /// # Safety
+    /// `device`は作成に使ったデバイスで、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device) {
+        self.scan_pipeline.destroy(device);
+        self.scan_add_pipeline.destroy(device);
+        self.radix_count_pipeline.destroy(device);
+        self.radix_scatter_pipeline.destroy(device);
+    }
+
+    fn buffers(&self) -> Vec<BufferId> {
+        let mut buffers = self.scan_sums.clone();
+        buffers.extend([self.sort_keys, self.sort_values, self.sort_histogram]);
+        buffers
+    }
+}
+
+impl Renderer {
+    /// プレフィックスサムと基数ソートのデスクリプタセットレイアウト。バインディング0から4が
+    /// ストレージバッファ
+    pub fn gpu_primitive_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        let bindings: Vec<_> = (0..5)
+            .map(|binding| {
+                DescriptorBinding::storage_buffer(binding, vk::ShaderStageFlags::COMPUTE)
+            })
+            .collect();
+        self.descriptor_set_layout(&bindings)
+    }
+
+    /// `exclusive_scan`と`radix_sort`を使えるようにする
+    pub fn enable_gpu_primitives(
+        &mut self,
+        shaders: GpuPrimitiveShaders,
+        settings: GpuPrimitiveSettings,
+    ) -> Result<()> {
+        let group_size = settings.group_size;
+        if !group_size.is_power_of_two() || !(32..=1024).contains(&group_size) {
+            return Err(RendererError::Validation(format!(
+                "group size {} must be a power of two between 32 and 1024",
+                group_size
+            )));
+        }
+        if settings.max_elements == 0 {
+            return Err(RendererError::Validation(
+                "gpu primitives need at least one element".to_owned(),
+            ));
+        }
+        let layout = self.gpu_primitive_set_layout()?;
+        let mut pipelines = Vec::with_capacity(4);
+        for (shader, push_constant_size) in [
+            (shaders.scan, 8),
+            (shaders.scan_add, 4),
+            (shaders.radix_count, 12),
+            (shaders.radix_scatter, 16),
+        ] {
+            match self.create_compute_pipeline_with_constants(
+                shader,
+                "main",
+                &[layout],
+                push_constant_size,
+                &[group_size],
+            ) {
+                Ok(pipeline) => pipelines.push(pipeline),
+                Err(err) => {
+                    for pipeline in pipelines {
+                        self.destroy_compute_pipeline(pipeline);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        // ヒストグラムのスキャンも同じ作業用のバッファで行う
+        let sort_groups = settings.max_elements.div_ceil(group_size);
+        let histogram_len = RADIX_BUCKETS * sort_groups;
+        let mut lengths = Vec::new();
+        let mut n = settings.max_elements.max(histogram_len);
+        while n > group_size * 2 {
+            n = n.div_ceil(group_size * 2);
+            lengths.push(n);
+        }
+        lengths.extend([settings.max_elements, settings.max_elements, histogram_len]);
+        let mut buffers = Vec::with_capacity(lengths.len());
+        for len in lengths {
+            match self.create_buffer(
+                len as vk::DeviceSize * 4,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            ) {
+                Ok(buffer) => buffers.push(buffer),
+                Err(err) => {
+                    for buffer in buffers {
+                        self.destroy_buffer(buffer)?;
+                    }
+                    for pipeline in pipelines {
+                        self.destroy_compute_pipeline(pipeline);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        let sort_histogram = buffers.pop().unwrap();
+        let sort_values = buffers.pop().unwrap();
+        let sort_keys = buffers.pop().unwrap();
+        let mut pipelines = pipelines.into_iter();
+        let gpu_primitives = GpuPrimitives {
+            settings,
+            scan_pipeline: pipelines.next().unwrap(),
+            scan_add_pipeline: pipelines.next().unwrap(),
+            radix_count_pipeline: pipelines.next().unwrap(),
+            radix_scatter_pipeline: pipelines.next().unwrap(),
+            scan_sums: buffers,
+            sort_keys,
+            sort_values,
+            sort_histogram,
+        };
+        if let Some(old) = self.gpu_primitives.replace(gpu_primitives) {
+            self.destroy_gpu_primitives(old)?;
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからパイプラインと作業用のバッファを破棄する
+    pub fn disable_gpu_primitives(&mut self) -> Result<()> {
+        match self.gpu_primitives.take() {
+            Some(gpu_primitives) => self.destroy_gpu_primitives(gpu_primitives),
+            None => Ok(()),
+        }
+    }
+
+    pub fn gpu_primitives(&self) -> Option<&GpuPrimitives> {
+        self.gpu_primitives.as_ref()
+    }
+
+    /// `buffer`の先頭`count`個の`u32`を排他的プレフィックスサムで置き換える
+    ///
+    /// `begin_frame`と`end_frame`の間で、レンダーパスの外で呼ぶ。`buffer`への前の書き込みとは
+    /// 呼ぶ側で同期し、結果は`BufferAccess::COMPUTE_SHADER_WRITE`から同期して使う。
+    pub fn exclusive_scan(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        buffer: BufferId,
+        count: u32,
+    ) -> Result<()> {
+        self.validate_primitive_buffer(buffer, count)?;
+        if count == 0 {
+            return Ok(());
+        }
+        self.record_scan(command_buffer, buffer, count, 0)
+    }
+
+    /// `keys`の先頭`count`個の`u32`を、下位`key_bits`ビットで昇順に安定ソートする
+    ///
+    /// `values`があれば同じ順に並べ替える。`exclusive_scan`と同じく、呼ぶ側で前後の
+    /// 書き込みと同期する。ソートは4ビットずつのパスで行うので、キーの範囲が狭いと速い。
+    pub fn radix_sort(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        keys: BufferId,
+        values: Option<BufferId>,
+        count: u32,
+        key_bits: u32,
+    ) -> Result<()> {
+        self.validate_primitive_buffer(keys, count)?;
+        if let Some(values) = values {
+            self.validate_primitive_buffer(values, count)?;
+        }
+        if !(1..=32).contains(&key_bits) {
+            return Err(RendererError::Validation(format!(
+                "key bits {} must be between 1 and 32",
+                key_bits
+            )));
+        }
+        if count == 0 {
+            return Ok(());
+        }
+        let gpu_primitives = self.gpu_primitives.as_ref().unwrap();
+        let groups = count.div_ceil(gpu_primitives.settings.group_size);
+        let (sort_keys, sort_values, histogram) = (
+            gpu_primitives.sort_keys,
+            gpu_primitives.sort_values,
+            gpu_primitives.sort_histogram,
+        );
+        // 値がなければ使わないバインディングにキーを入れておく
+        let (values, has_values) = match values {
+            Some(values) => (values, 1u32),
+            None => (keys, 0),
+        };
+        let sort_values = if has_values != 0 {
+            sort_values
+        } else {
+            sort_keys
+        };
+        // 結果が`keys`に戻るようにパスの数を偶数にする。余分なパスは全要素を桁0として写すだけ
+        let passes = key_bits.div_ceil(RADIX_BITS);
+        for pass in 0..passes.next_multiple_of(2) {
+            let ((keys_in, keys_out), (values_in, values_out)) = if pass % 2 == 0 {
+                ((keys, sort_keys), (values, sort_values))
+            } else {
+                ((sort_keys, keys), (sort_values, values))
+            };
+            let shift = (pass * RADIX_BITS).min(32 - RADIX_BITS);
+            let mask = if pass < passes { RADIX_BUCKETS - 1 } else { 0 };
+            let descriptor_set = self.gpu_primitive_set(&[
+                (0, keys_in),
+                (1, keys_out),
+                (2, histogram),
+                (3, values_in),
+                (4, values_out),
+            ])?;
+            let gpu_primitives = self.gpu_primitives.as_ref().unwrap();
+            let data: Vec<u8> = [count, shift, mask]
+                .iter()
+                .flat_map(|value| value.to_ne_bytes())
+                .collect();
+            self.dispatch(
+                command_buffer,
+                &gpu_primitives.radix_count_pipeline,
+                &[descriptor_set],
+                &data,
+                [groups, 1, 1],
+            )?;
+            self.compute_barrier(command_buffer);
+            self.record_scan(command_buffer, histogram, RADIX_BUCKETS * groups, 0)?;
+            self.compute_barrier(command_buffer);
+            let gpu_primitives = self.gpu_primitives.as_ref().unwrap();
+            let data: Vec<u8> = [count, shift, mask, has_values]
+                .iter()
+                .flat_map(|value| value.to_ne_bytes())
+                .collect();
+            self.dispatch(
+                command_buffer,
+                &gpu_primitives.radix_scatter_pipeline,
+                &[descriptor_set],
+                &data,
+                [groups, 1, 1],
+            )?;
+            if pass + 1 < passes.next_multiple_of(2) {
+                self.compute_barrier(command_buffer);
+            }
+        }
+        Ok(())
+    }
+
+    /// ワークグループごとにスキャンし、2段目以降で合計をスキャンして足し戻す
+    fn record_scan(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        data: BufferId,
+        count: u32,
+        level: usize,
+    ) -> Result<()> {
+        let gpu_primitives = self.gpu_primitives.as_ref().unwrap();
+        let groups = count.div_ceil(gpu_primitives.settings.group_size * 2);
+        let sums = match gpu_primitives.scan_sums.get(level) {
+            Some(&sums) if groups > 1 => sums,
+            // 1つのワークグループで終わる段では合計を書かない
+            _ => data,
+        };
+        let descriptor_set = self.gpu_primitive_set(&[(0, data), (1, sums)])?;
+        let gpu_primitives = self.gpu_primitives.as_ref().unwrap();
+        let data_bytes: Vec<u8> = [count, (groups > 1) as u32]
+            .iter()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect();
+        self.dispatch(
+            command_buffer,
+            &gpu_primitives.scan_pipeline,
+            &[descriptor_set],
+            &data_bytes,
+            [groups, 1, 1],
+        )?;
+        if groups > 1 {
+            self.compute_barrier(command_buffer);
+            self.record_scan(command_buffer, sums, groups, level + 1)?;
+            self.compute_barrier(command_buffer);
+            let gpu_primitives = self.gpu_primitives.as_ref().unwrap();
+            self.dispatch(
+                command_buffer,
+                &gpu_primitives.scan_add_pipeline,
+                &[descriptor_set],
+                &count.to_ne_bytes(),
+                [groups, 1, 1],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn validate_primitive_buffer(&self, buffer: BufferId, count: u32) -> Result<()> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "gpu primitives can only be recorded between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        let gpu_primitives = self.gpu_primitives.as_ref().ok_or_else(|| {
+            RendererError::Validation("gpu primitives are not enabled".to_owned())
+        })?;
+        if count > gpu_primitives.settings.max_elements {
+            return Err(RendererError::Validation(format!(
+                "{} elements exceed the gpu primitive capacity of {}",
+                count, gpu_primitives.settings.max_elements
+            )));
+        }
+        let buffer_info = self.buffers.get(buffer).ok_or_else(|| {
+            RendererError::Validation(format!("buffer {:?} was destroyed", buffer))
+        })?;
+        if buffer_info.size < count as vk::DeviceSize * 4 {
+            return Err(RendererError::Validation(format!(
+                "buffer {:?} of {} bytes cannot hold {} elements",
+                buffer, buffer_info.size, count
+            )));
+        }
+        Ok(())
+    }
+
+    /// 記録中のフレームだけで使うデスクリプタセットに`buffers`を書き込む
+    fn gpu_primitive_set(&mut self, buffers: &[(u32, BufferId)]) -> Result<vk::DescriptorSet> {
+        let layout = self.gpu_primitive_set_layout()?;
+        let descriptor_set = self.allocate_transient_descriptor_set(layout)?;
+        let mut writer = DescriptorWriter::new();
+        for &(binding, buffer) in buffers {
+            let buffer = self.buffers.get(buffer).ok_or_else(|| {
+                RendererError::Validation(format!("buffer {:?} was destroyed", buffer))
+            })?;
+            writer = writer.buffer(binding, vk::DescriptorType::STORAGE_BUFFER, buffer);
+        }
+        unsafe { writer.update(&self.device, descriptor_set) };
+        Ok(descriptor_set)
+    }
+
+    fn compute_barrier(&self, command_buffer: vk::CommandBuffer) {
+        let barrier = *vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            );
+        }
+    }
+
+    fn destroy_gpu_primitives(&mut self, gpu_primitives: GpuPrimitives) -> Result<()> {
+        let buffers = gpu_primitives.buffers();
+        self.destroy_deferred(move |device, _| unsafe { gpu_primitives.destroy(device) });
+        buffers
+            .into_iter()
+            .try_for_each(|buffer| self.destroy_buffer(buffer))
+    }
+}
@@ -0,0 +1,252 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+
+/// 世代カウンタ付きのリソースハンドル
+///
+/// スロットが再利用されると世代が進むので、破棄済みのリソースを指すハンドルは`Pool`から
+/// 何も取得できなくなる。
+pub struct Handle<T> {
+    index: u32,
+    generation: u32,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Handle<T> {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+// `T`に境界を付けないように手で実装する
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Handle<T> {}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.generation == other.generation
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> Hash for Handle<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+        self.generation.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let type_name = std::any::type_name::<T>();
+        let type_name = type_name.rsplit("::").next().unwrap_or(type_name);
+        write!(
+            f,
+            "Handle<{}>({}v{})",
+            type_name, self.index, self.generation
+        )
+    }
+}
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+/// `Handle<T>`で要素を参照する世代付きスロットマップ
+pub struct Pool<T> {
+    slots: Vec<Slot<T>>,
+    free_indices: Vec<u32>,
+    len: usize,
+}
+
+impl<T> Default for Pool<T> {
+    fn default() -> Self {
+        Self {
+            slots: Vec::new(),
+            free_indices: Vec::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> Pool<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, value: T) -> Handle<T> {
+        self.len += 1;
+        if let Some(index) = self.free_indices.pop() {
+            let slot = &mut self.slots[index as usize];
+            slot.value = Some(value);
+            Handle {
+                index,
+                generation: slot.generation,
+                _marker: PhantomData,
+            }
+        } else {
+            let index = self.slots.len() as u32;
+            self.slots.push(Slot {
+                generation: 0,
+                value: Some(value),
+            });
+            Handle {
+                index,
+                generation: 0,
+                _marker: PhantomData,
+            }
+        }
+    }
+
+    pub fn remove(&mut self, handle: Handle<T>) -> Option<T> {
+        let slot = self.slots.get_mut(handle.index as usize)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        let value = slot.value.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free_indices.push(handle.index);
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn get(&self, handle: Handle<T>) -> Option<&T> {
+        self.slots
+            .get(handle.index as usize)
+            .filter(|slot| slot.generation == handle.generation)
+            .and_then(|slot| slot.value.as_ref())
+    }
+
+    pub fn get_mut(&mut self, handle: Handle<T>) -> Option<&mut T> {
+        self.slots
+            .get_mut(handle.index as usize)
+            .filter(|slot| slot.generation == handle.generation)
+            .and_then(|slot| slot.value.as_mut())
+    }
+
+    pub fn contains(&self, handle: Handle<T>) -> bool {
+        self.get(handle).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Handle<T>, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.value.as_ref().map(|value| {
+                (
+                    Handle {
+                        index: index as u32,
+                        generation: slot.generation,
+                        _marker: PhantomData,
+                    },
+                    value,
+                )
+            })
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Handle<T>, &mut T)> {
+        self.slots
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, slot)| {
+                let generation = slot.generation;
+                slot.value.as_mut().map(|value| {
+                    (
+                        Handle {
+                            index: index as u32,
+                            generation,
+                            _marker: PhantomData,
+                        },
+                        value,
+                    )
+                })
+            })
+    }
+
+    /// 全要素を取り出す。既存のハンドルはすべて無効になる
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut values = Vec::with_capacity(self.len);
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if let Some(value) = slot.value.take() {
+                slot.generation = slot.generation.wrapping_add(1);
+                self.free_indices.push(index as u32);
+                values.push(value);
+            }
+        }
+        self.len = 0;
+        values
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_handle_is_not_found() {
+        let mut pool = Pool::new();
+        let handle = pool.insert("a");
+        assert_eq!(pool.remove(handle), Some("a"));
+        assert_eq!(pool.get(handle), None);
+        assert!(!pool.contains(handle));
+        assert_eq!(pool.remove(handle), None);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn reused_slot_bumps_generation() {
+        let mut pool = Pool::new();
+        let first = pool.insert(1);
+        pool.remove(first);
+        let second = pool.insert(2);
+        assert_eq!(second.index(), first.index());
+        assert_eq!(second.generation(), first.generation() + 1);
+        assert_ne!(second, first);
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn stale_handle_is_rejected_after_reinsert() {
+        let mut pool = Pool::new();
+        let stale = pool.insert(1);
+        pool.remove(stale);
+        let current = pool.insert(2);
+        assert_eq!(pool.get(stale), None);
+        assert_eq!(pool.get_mut(stale), None);
+        assert_eq!(pool.remove(stale), None);
+        assert_eq!(pool.get(current), Some(&2));
+    }
+
+    #[test]
+    fn drain_invalidates_handles() {
+        let mut pool = Pool::new();
+        let handles = [pool.insert(1), pool.insert(2)];
+        assert_eq!(pool.drain(), vec![1, 2]);
+        assert!(pool.is_empty());
+        assert!(handles.iter().all(|&handle| !pool.contains(handle)));
+        let reused = pool.insert(3);
+        assert!(handles.iter().all(|&handle| handle != reused));
+        assert_eq!(
+            pool.iter().map(|(_, &value)| value).collect::<Vec<_>>(),
+            [3]
+        );
+    }
+}
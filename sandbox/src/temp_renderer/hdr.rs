@@ -0,0 +1,708 @@
+use super::error::{RendererError, Result};
+use super::memory::{Allocation, MemoryAllocator};
+use super::msaa::{create_msaa_color_target, MsaaColorTarget};
+use super::render_pass::{Framebuffer, RenderPass};
+use super::renderer::depth_aspect_mask;
+use super::texture::create_image;
+use super::view_settings::validate_tone_mapping;
+use super::{
+    Buffer, BufferId, DescriptorBinding, DescriptorWriter, GraphicsPipeline, PipelineBuilder,
+    Renderer, RenderingAttachment, SamplerDesc, ShaderId,
+};
+use ash::{vk, Device};
+
+/// HDRのレンダーターゲットのフォーマット
+pub const HDR_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+/// 露出バッファの初期値。`{ float average_luminance; float exposure; }`で露出が1になる
+pub(crate) const INITIAL_EXPOSURE: [f32; 2] = [1.0 / 9.6, 1.0];
+
+/// HDRの色を表示できる範囲に収める方法
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ToneMapOperator {
+    /// `c / (1 + c)`
+    Reinhard,
+    /// ACESのフィルミックカーブ(Narkowiczの近似)
+    #[default]
+    Aces,
+    /// Uncharted 2のフィルミックカーブ。白は11.2
+    Filmic,
+}
+
+impl ToneMapOperator {
+    /// シェーダーに渡す番号
+    pub fn index(self) -> u32 {
+        match self {
+            Self::Reinhard => 0,
+            Self::Aces => 1,
+            Self::Filmic => 2,
+        }
+    }
+}
+
+/// トーンマッピングの設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneMappingSettings {
+    pub operator: ToneMapOperator,
+    /// トーンマッピングの前に色に掛ける露出。自動露出が有効な場合はその露出にさらに掛ける
+    pub exposure: f32,
+    /// トーンマッピングの後の彩度。0で白黒、1でそのまま
+    pub saturation: f32,
+    /// センサーのノイズ(フィルムグレイン)の強さ。0でノイズなし
+    pub grain: f32,
+}
+
+impl Default for ToneMappingSettings {
+    fn default() -> Self {
+        Self {
+            operator: ToneMapOperator::default(),
+            exposure: 1.0,
+            saturation: 1.0,
+            grain: 0.0,
+        }
+    }
+}
+
+/// トーンマッピングのシェーダー
+///
+/// 頂点バッファなしで画面全体を覆う三角形を描き、HDRのレンダーターゲットを
+/// [`ToneMappingPushConstants`]の設定で変換する。最後にセット1の3D LUTでカラーグレーディングする。
+/// LUTは`set_color_grading_lut`で設定し、設定しなければ色を変えないLUTになる。
+/// `set_color_vision_filter`の色覚のフィルターもこのLUTに焼き込まれる。
+///
+/// ```glsl
+/// // 頂点シェーダー
+/// void main() {
+///     vec2 uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+///     gl_Position = vec4(uv * 2.0 - 1.0, 0.0, 1.0);
+/// }
+///
+/// // フラグメントシェーダー
+/// layout(set = 0, binding = 0) uniform sampler2D hdr;
+/// layout(set = 0, binding = 1) readonly buffer Exposure { float average_luminance; float auto_exposure; };
+/// layout(set = 1, binding = 0) uniform sampler3D lut;
+/// layout(push_constant) uniform ToneMapping {
+///     float exposure; uint operator; uint use_auto_exposure; float saturation;
+///     float grain; uint frame;
+/// };
+/// layout(location = 0) out vec4 color;
+/// void main() {
+///     float e = use_auto_exposure != 0 ? exposure * auto_exposure : exposure;
+///     vec3 c = texelFetch(hdr, ivec2(gl_FragCoord.xy), 0).rgb * e;
+///     if (operator == 0) c = c / (1.0 + c);
+///     else if (operator == 1) c = clamp((c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14), 0.0, 1.0);
+///     else c = uncharted2(c) / uncharted2(vec3(11.2));
+///     c = mix(vec3(dot(c, vec3(0.2126, 0.7152, 0.0722))), c, saturation);
+///     // 暗いところほど目立つノイズにする。フレームごとに模様を変える
+///     float noise = tempura_random(uvec2(gl_FragCoord.xy) + uvec2(frame * 1973u, frame * 9277u)) - 0.5;
+///     c = max(c + noise * grain * sqrt(c), 0.0);
+///     // LUTはsRGBのガンマをかけた値で引く。格子点の中心を通るように範囲を縮める
+///     float n = float(textureSize(lut, 0).x);
+///     vec3 graded = texture(lut, tempura_linear_to_srgb(c) * ((n - 1.0) / n) + 0.5 / n).rgb;
+///     c = tempura_srgb_to_linear(graded);
+///     // スワップチェインがSRGBフォーマットでなければ、ここでガンマ補正する
+///     color = vec4(c, 1.0);
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ToneMappingShaders {
+    pub vertex: ShaderId,
+    pub fragment: ShaderId,
+}
+
+/// トーンマッピングのパイプラインに渡すプッシュ定数。フラグメントシェーダーで使う
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneMappingPushConstants {
+    pub exposure: f32,
+    /// [`ToneMapOperator::index`]
+    pub operator: u32,
+    /// 0以外なら露出バッファの露出を掛ける
+    pub use_auto_exposure: u32,
+    pub saturation: f32,
+    pub grain: f32,
+    /// ノイズの模様を変えるためのフレームの番号
+    pub frame: u32,
+}
+
+/// スワップチェインと同じ解像度のHDRのカラーバッファ
+///
+/// 深度バッファはスワップチェインへの描画と共有する。
+pub struct HdrTarget {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    pub allocation: Allocation,
+    /// MSAAが有効な場合に描画するマルチサンプルのイメージ。終了時に`image`へ解決する
+    pub msaa: Option<MsaaColorTarget>,
+    /// 動的レンダリングが無効な場合だけ作る
+    pub framebuffer: Option<Framebuffer>,
+    pub extent: vk::Extent2D,
+}
+
+impl HdrTarget {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのターゲットを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        if let Some(framebuffer) = &self.framebuffer {
+            framebuffer.destroy(device);
+        }
+        if let Some(msaa) = &self.msaa {
+            msaa.destroy(device, allocator);
+        }
+        device.destroy_image_view(self.view, None);
+        device.destroy_image(self.image, None);
+        allocator.free(device, self.allocation);
+    }
+}
+
+/// `enable_hdr`で作るHDRの描画とトーンマッピングのリソース
+pub struct Hdr {
+    pub target: HdrTarget,
+    /// 動的レンダリングが無効な場合だけ作る
+    pub render_pass: Option<RenderPass>,
+    pub sampler: vk::Sampler,
+    /// `tone_mapping_set_layout`のデスクリプタセット。解像度が変わったら書き直す
+    pub descriptor_set: vk::DescriptorSet,
+    /// スワップチェインに描くトーンマッピングのパイプライン
+    pub pipeline: GraphicsPipeline,
+    pub settings: ToneMappingSettings,
+    /// 自動露出が書き込む`{ float average_luminance; float exposure; }`のストレージバッファ
+    ///
+    /// `Renderer`のバッファとして`disable_hdr`で破棄する。
+    pub exposure_buffer: BufferId,
+}
+
+impl Hdr {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        self.pipeline.destroy(device);
+        self.target.destroy(device, allocator);
+        if let Some(render_pass) = &self.render_pass {
+            render_pass.destroy(device);
+        }
+        device.destroy_sampler(self.sampler, None);
+    }
+
+    unsafe fn write_descriptor_set(&self, device: &Device, exposure_buffer: &Buffer) {
+        DescriptorWriter::new()
+            .buffer(1, vk::DescriptorType::STORAGE_BUFFER, exposure_buffer)
+            .image(
+                0,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                vk::DescriptorImageInfo {
+                    sampler: self.sampler,
+                    image_view: self.target.view,
+                    image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                },
+            )
+            .update(device, self.descriptor_set);
+    }
+}
+
+impl Renderer {
+    /// トーンマッピングのデスクリプタセットレイアウト
+    ///
+    /// バインディング0がHDRのレンダーターゲット、1が露出のストレージバッファ。
+    pub fn tone_mapping_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, vk::ShaderStageFlags::FRAGMENT),
+            DescriptorBinding::storage_buffer(1, vk::ShaderStageFlags::FRAGMENT),
+        ])
+    }
+
+    /// HDRのレンダーターゲットとトーンマッピングのパイプラインを作成する
+    ///
+    /// シーンは`begin_hdr_rendering`と`end_hdr_rendering`の間で描き、スワップチェインへの
+    /// 描画の中で`tone_map`を呼んで表示する。すでに有効な場合は作り直す。
+    pub fn enable_hdr(
+        &mut self,
+        shaders: ToneMappingShaders,
+        settings: ToneMappingSettings,
+    ) -> Result<()> {
+        let module = |id: ShaderId| {
+            self.shader_modules
+                .get(id)
+                .map(|shader| shader.module)
+                .ok_or_else(|| {
+                    RendererError::Validation(format!("shader {:?} was already destroyed", id))
+                })
+        };
+        let vertex_module = module(shaders.vertex)?;
+        let fragment_module = module(shaders.fragment)?;
+        let layout = self.tone_mapping_set_layout()?;
+        let color_grading_layout = self.color_grading_set_layout()?;
+        let builder = PipelineBuilder::new()
+            .vertex_shader(vertex_module)
+            .fragment_shader(fragment_module)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .depth_test(false)
+            .depth_write(false)
+            .descriptor_set_layouts(&[layout, color_grading_layout])
+            .push_constant_range(
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                std::mem::size_of::<ToneMappingPushConstants>() as u32,
+            );
+        let exposure_buffer =
+            self.create_buffer_with_data(&INITIAL_EXPOSURE, vk::BufferUsageFlags::STORAGE_BUFFER)?;
+        let pipeline = match self.create_swapchain_pipeline(&builder) {
+            Ok(pipeline) => pipeline,
+            Err(err) => {
+                self.destroy_buffer(exposure_buffer)?;
+                return Err(err);
+            }
+        };
+
+        let resources = self
+            .allocate_descriptor_set(layout)
+            .and_then(|descriptor_set| {
+                let sampler = self.create_sampler(&SamplerDesc {
+                    mag_filter: vk::Filter::NEAREST,
+                    min_filter: vk::Filter::NEAREST,
+                    mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+                    ..SamplerDesc::clamp_to_edge()
+                })?;
+                let render_pass = if self.dynamic_rendering.is_none() {
+                    match unsafe {
+                        RenderPass::offscreen(
+                            &self.device,
+                            HDR_FORMAT,
+                            self.depth_format,
+                            self.msaa_samples,
+                        )
+                    } {
+                        Ok(render_pass) => Some(render_pass),
+                        Err(err) => {
+                            unsafe { self.device.destroy_sampler(sampler, None) };
+                            return Err(err);
+                        }
+                    }
+                } else {
+                    None
+                };
+                match unsafe { self.create_hdr_target(render_pass.as_ref()) } {
+                    Ok(target) => Ok((descriptor_set, sampler, render_pass, target)),
+                    Err(err) => unsafe {
+                        if let Some(render_pass) = &render_pass {
+                            render_pass.destroy(&self.device);
+                        }
+                        self.device.destroy_sampler(sampler, None);
+                        Err(err)
+                    },
+                }
+            });
+        let (descriptor_set, sampler, render_pass, target) = match resources {
+            Ok(resources) => resources,
+            Err(err) => {
+                self.destroy_graphics_pipeline(pipeline);
+                self.destroy_buffer(exposure_buffer)?;
+                return Err(err);
+            }
+        };
+        let hdr = Hdr {
+            target,
+            render_pass,
+            sampler,
+            descriptor_set,
+            pipeline,
+            settings,
+            exposure_buffer,
+        };
+        unsafe {
+            hdr.write_descriptor_set(&self.device, self.buffers.get(exposure_buffer).unwrap())
+        };
+        if let Some(old) = self.hdr.replace(hdr) {
+            self.destroy_hdr(old)?;
+        }
+        self.recreate_bloom_chain()?;
+        self.recreate_anti_aliasing_targets()?;
+        self.recreate_screen_space_reflection_targets()?;
+        self.recreate_outline_targets()?;
+        self.recreate_camera_effect_targets()?;
+        self.recreate_gbuffer()?;
+        self.recreate_oit_targets()?;
+        self.ensure_color_grading()?;
+        self.refresh_luminance_probe()?;
+        self.refresh_auto_exposure()
+    }
+
+    /// 使用中のフレームが完了してからHDRのリソースを破棄する。自動露出とポストプロセスも無効になる
+    pub fn disable_hdr(&mut self) -> Result<()> {
+        self.disable_anti_aliasing();
+        self.disable_bloom();
+        self.disable_camera_effects();
+        self.disable_deferred_lighting();
+        self.disable_oit();
+        self.disable_particles();
+        self.disable_screen_space_reflections()?;
+        self.disable_outline();
+        self.disable_auto_exposure()?;
+        self.disable_luminance_probe()?;
+        self.disable_color_grading()?;
+        match self.hdr.take() {
+            Some(hdr) => self.destroy_hdr(hdr),
+            None => Ok(()),
+        }
+    }
+
+    pub fn hdr(&self) -> Option<&Hdr> {
+        self.hdr.as_ref()
+    }
+
+    /// トーンマッピングの方法と露出を変更する。次の`tone_map`から反映される
+    pub fn set_tone_mapping(&mut self, settings: ToneMappingSettings) -> Result<()> {
+        validate_tone_mapping(&settings)?;
+        self.enabled_hdr_mut()?.settings = settings;
+        Ok(())
+    }
+
+    /// 露出だけを変更する
+    pub fn set_exposure(&mut self, exposure: f32) -> Result<()> {
+        self.enabled_hdr_mut()?.settings.exposure = exposure;
+        Ok(())
+    }
+
+    /// `begin_hdr_rendering`の中で使うパイプラインを作成する
+    ///
+    /// 動的レンダリングが有効ならHDRと深度バッファのフォーマットを、そうでなければ
+    /// HDRのレンダーパスを指定して作成する。サンプル数は`msaa_samples`になる。
+    pub fn create_hdr_pipeline(&self, builder: &PipelineBuilder) -> Result<GraphicsPipeline> {
+        let hdr = self.enabled_hdr()?;
+        match &hdr.render_pass {
+            Some(render_pass) => self.create_graphics_pipeline(builder, render_pass.render_pass, 0),
+            None => {
+                let builder = builder
+                    .clone()
+                    .rendering_formats(&[HDR_FORMAT], self.depth_format)
+                    .samples(self.msaa_samples);
+                self.create_graphics_pipeline(&builder, vk::RenderPass::null(), 0)
+            }
+        }
+    }
+
+    /// HDRのレンダーターゲットと深度バッファへの描画を開始する
+    ///
+    /// ビューポートとシザーはターゲット全体に設定する。
+    pub fn begin_hdr_rendering(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        clear_color: [f32; 4],
+    ) -> Result<()> {
+        let hdr = self.enabled_hdr()?;
+        let target = &hdr.target;
+        let render_area = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: target.extent,
+        };
+        let clear_color = vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: clear_color,
+            },
+        };
+        let clear_depth = vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue {
+                depth: 1.0,
+                stencil: 0,
+            },
+        };
+
+        if let (Some(render_pass), Some(framebuffer)) = (&hdr.render_pass, &target.framebuffer) {
+            let clear_values = [clear_color, clear_depth];
+            let begin_info = *vk::RenderPassBeginInfo::builder()
+                .render_pass(render_pass.render_pass)
+                .framebuffer(framebuffer.framebuffer)
+                .render_area(render_area)
+                .clear_values(&clear_values);
+            unsafe {
+                self.device.cmd_begin_render_pass(
+                    command_buffer,
+                    &begin_info,
+                    vk::SubpassContents::INLINE,
+                );
+                self.set_viewport_and_scissor(command_buffer, render_area);
+            }
+            return Ok(());
+        }
+
+        let color_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let (depth_old_layout, depth) = self.main_pass_depth_attachment();
+        // 前のフレームのトーンマッピングの読み込みと、深度の書き込みを待つ
+        let mut barriers = vec![
+            *vk::ImageMemoryBarrier::builder()
+                .image(target.image)
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .subresource_range(color_range),
+            *vk::ImageMemoryBarrier::builder()
+                .image(self.depth_image)
+                .old_layout(depth_old_layout)
+                .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                .dst_access_mask(
+                    vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                )
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: depth_aspect_mask(self.depth_format),
+                    ..color_range
+                }),
+        ];
+        if let Some(msaa) = &target.msaa {
+            barriers.push(
+                *vk::ImageMemoryBarrier::builder()
+                    .image(msaa.image)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                    .subresource_range(color_range),
+            );
+        }
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &barriers,
+            );
+        }
+        let color = match &target.msaa {
+            Some(msaa) => RenderingAttachment::clear(
+                msaa.view,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                clear_color,
+            )
+            .store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .resolve(target.view, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+            None => RenderingAttachment::clear(
+                target.view,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                clear_color,
+            ),
+        };
+        // TAAのモーションベクトルで読むので深度も保存する
+        self.begin_rendering(command_buffer, render_area, &[color], Some(depth))
+    }
+
+    /// `begin_hdr_rendering`で開始した描画を終了し、HDRのレンダーターゲットをサンプリング可能にする
+    pub fn end_hdr_rendering(&self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        let hdr = self.enabled_hdr()?;
+        if hdr.render_pass.is_some() {
+            unsafe { self.device.cmd_end_render_pass(command_buffer) };
+            return Ok(());
+        }
+
+        self.end_rendering(command_buffer)?;
+        let to_read_only = *vk::ImageMemoryBarrier::builder()
+            .image(hdr.target.image)
+            .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            });
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_read_only],
+            );
+        }
+        Ok(())
+    }
+
+    /// HDRのレンダーターゲットをトーンマッピングして画面全体に描く
+    ///
+    /// `end_hdr_rendering`の後、`begin_swapchain_rendering`と`end_swapchain_rendering`の
+    /// 間で呼ぶ。UIなどはこの後に重ねて描ける。
+    pub fn tone_map(&self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        let hdr = self.enabled_hdr()?;
+        // `enable_hdr`が作るので、HDRが有効なら必ずある
+        let color_grading = self.color_grading.as_ref().unwrap();
+        let settings = self.resolved_view_settings().tone_mapping;
+        let push_constants = ToneMappingPushConstants {
+            exposure: settings.exposure,
+            operator: settings.operator.index(),
+            use_auto_exposure: self.auto_exposure.is_some() as u32,
+            saturation: settings.saturation,
+            grain: settings.grain,
+            frame: self.frame_count as u32,
+        };
+        let data: Vec<u8> = push_constants
+            .exposure
+            .to_ne_bytes()
+            .into_iter()
+            .chain(push_constants.operator.to_ne_bytes())
+            .chain(push_constants.use_auto_exposure.to_ne_bytes())
+            .chain(push_constants.saturation.to_ne_bytes())
+            .chain(push_constants.grain.to_ne_bytes())
+            .chain(push_constants.frame.to_ne_bytes())
+            .collect();
+        unsafe {
+            self.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                hdr.pipeline.pipeline,
+            );
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                hdr.pipeline.layout,
+                0,
+                &[hdr.descriptor_set, color_grading.descriptor_set],
+                &[],
+            );
+            self.push_constants(command_buffer, &hdr.pipeline, &data);
+            self.device.cmd_draw(command_buffer, 3, 1, 0, 0);
+        }
+        Ok(())
+    }
+
+    /// スワップチェインの解像度に合わせてHDRのレンダーターゲットを作り直す
+    ///
+    /// # Safety
+    /// GPUが古いターゲットを使い終わっていること
+    pub(crate) unsafe fn recreate_hdr_target(&mut self) -> Result<()> {
+        let Some(render_pass) = self.hdr.as_ref().map(|hdr| hdr.render_pass) else {
+            return Ok(());
+        };
+        let target = self.create_hdr_target(render_pass.as_ref())?;
+        let hdr = self.hdr.as_mut().unwrap();
+        let old = std::mem::replace(&mut hdr.target, target);
+        hdr.write_descriptor_set(&self.device, self.buffers.get(hdr.exposure_buffer).unwrap());
+        old.destroy(&self.device, &mut self.allocator);
+        self.rewrite_auto_exposure_set();
+        self.rewrite_luminance_probe_sets();
+        self.recreate_bloom_chain()?;
+        self.recreate_anti_aliasing_targets()?;
+        self.recreate_screen_space_reflection_targets()?;
+        self.recreate_outline_targets()?;
+        self.recreate_camera_effect_targets()?;
+        self.recreate_gbuffer()?;
+        self.recreate_oit_targets()
+    }
+
+    fn destroy_hdr(&mut self, hdr: Hdr) -> Result<()> {
+        let exposure_buffer = hdr.exposure_buffer;
+        self.destroy_deferred(move |device, allocator| unsafe { hdr.destroy(device, allocator) });
+        self.destroy_buffer(exposure_buffer)
+    }
+
+    pub(crate) fn enabled_hdr(&self) -> Result<&Hdr> {
+        self.hdr
+            .as_ref()
+            .ok_or_else(|| RendererError::Validation("HDR rendering is not enabled".to_owned()))
+    }
+
+    fn enabled_hdr_mut(&mut self) -> Result<&mut Hdr> {
+        self.hdr
+            .as_mut()
+            .ok_or_else(|| RendererError::Validation("HDR rendering is not enabled".to_owned()))
+    }
+
+    unsafe fn create_hdr_target(&mut self, render_pass: Option<&RenderPass>) -> Result<HdrTarget> {
+        let extent = self.surface_resolution;
+        let image_create_info = *vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(HDR_FORMAT)
+            .extent(extent.into())
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            // ポストプロセスはコンピュートシェーダーで直接書き込むか、結果をコピーする
+            .usage(
+                vk::ImageUsageFlags::COLOR_ATTACHMENT
+                    | vk::ImageUsageFlags::SAMPLED
+                    | vk::ImageUsageFlags::STORAGE
+                    | vk::ImageUsageFlags::TRANSFER_DST,
+            )
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let (image, allocation) = create_image(
+            &self.device,
+            &mut self.allocator,
+            &image_create_info,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            true,
+        )?;
+        let view_create_info = *vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(HDR_FORMAT)
+            .subresource_range(
+                *vk::ImageSubresourceRange::builder()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .level_count(1)
+                    .layer_count(1),
+            );
+        let view = match self.device.create_image_view(&view_create_info, None) {
+            Ok(view) => view,
+            Err(err) => {
+                self.device.destroy_image(image, None);
+                self.allocator.free(&self.device, allocation);
+                return Err(err.into());
+            }
+        };
+        let mut target = HdrTarget {
+            image,
+            view,
+            allocation,
+            msaa: None,
+            framebuffer: None,
+            extent,
+        };
+        let result = create_msaa_color_target(
+            &self.device,
+            &mut self.allocator,
+            extent,
+            HDR_FORMAT,
+            self.msaa_samples,
+        )
+        .and_then(|msaa| {
+            target.msaa = msaa;
+            let Some(render_pass) = render_pass else {
+                return Ok(());
+            };
+            let attachments = match msaa {
+                Some(msaa) => vec![msaa.view, self.depth_image_view, view],
+                None => vec![view, self.depth_image_view],
+            };
+            target.framebuffer = Some(Framebuffer::new(
+                &self.device,
+                render_pass,
+                &attachments,
+                extent,
+            )?);
+            Ok(())
+        });
+        match result {
+            Ok(()) => Ok(target),
+            Err(err) => {
+                target.destroy(&self.device, &mut self.allocator);
+                Err(err)
+            }
+        }
+    }
+}
@@ -0,0 +1,658 @@
+use super::error::{RendererError, Result};
+use super::{
+    BufferAccess, BufferId, ComputePipeline, DescriptorBinding, DescriptorWriter, PerFrame,
+    PipelineBuilder, Renderer, ShaderId,
+};
+use ash::{vk, Device};
+
+/// 輝度を調べるシェーダーのワークグループの幅と高さ
+pub const LUMINANCE_PROBE_GROUP_SIZE: u32 = 16;
+
+/// スワップチェインの色空間が求める出力の表し方
+///
+/// シェーダーでは`tempura/hdr_output.glsl`の`tempura_encode_output`に`index`を渡して変換する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputEncoding {
+    /// SDR。紙の白を1とするリニアな値をSRGBフォーマットに書く
+    Srgb,
+    /// HDR10。BT.2020の原色をPQで符号化する
+    Pq,
+    /// scRGB。BT.709の原色のリニアな値で、1が80nit
+    ScRgb,
+}
+
+impl OutputEncoding {
+    /// 対応する色空間。HDRでない色空間は`Srgb`になる
+    pub fn from_color_space(color_space: vk::ColorSpaceKHR) -> Self {
+        match color_space {
+            vk::ColorSpaceKHR::HDR10_ST2084_EXT => OutputEncoding::Pq,
+            vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT => OutputEncoding::ScRgb,
+            _ => OutputEncoding::Srgb,
+        }
+    }
+
+    /// シェーダーに渡す番号
+    pub fn index(self) -> u32 {
+        match self {
+            OutputEncoding::Srgb => 0,
+            OutputEncoding::Pq => 1,
+            OutputEncoding::ScRgb => 2,
+        }
+    }
+
+    pub fn is_hdr(self) -> bool {
+        self != OutputEncoding::Srgb
+    }
+}
+
+/// ディスプレイが表示できる輝度の範囲。単位はnit
+///
+/// Vulkanからはディスプレイの輝度を問い合わせられないので、OSのAPI(DXGIの
+/// `DXGI_OUTPUT_DESC1`など)で得た値か、キャリブレーション画面でユーザーが合わせた値を
+/// `set_display_luminance`で設定する。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisplayLuminance {
+    pub min_nits: f32,
+    pub max_nits: f32,
+    /// 画面全体を明るくしたときに保てる輝度
+    pub max_full_frame_nits: f32,
+}
+
+impl DisplayLuminance {
+    /// sRGBの基準の表示環境と同じ、最大80nitのSDRのディスプレイ
+    pub fn sdr() -> Self {
+        Self {
+            min_nits: 0.0,
+            max_nits: 80.0,
+            max_full_frame_nits: 80.0,
+        }
+    }
+}
+
+/// HDRの出力の状態
+#[derive(Debug, Clone, PartialEq)]
+pub struct HdrOutputInfo {
+    pub surface_format: vk::SurfaceFormatKHR,
+    pub encoding: OutputEncoding,
+    /// サーフェスが対応しているHDRの色空間のフォーマット。`RendererBuilder::surface_format`で選ぶ
+    pub hdr_surface_formats: Vec<vk::SurfaceFormatKHR>,
+    /// `set_display_luminance`で設定した輝度の範囲
+    pub display_luminance: Option<DisplayLuminance>,
+}
+
+/// キャリブレーション画面とテストパターンで描くもの
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalibrationPattern {
+    /// 出力の最大の輝度で塗った背景に`nits`の輝度の模様を描く。模様がちょうど見えなくなる値が
+    /// ディスプレイの最大の輝度
+    MaxLuminance { nits: f32 },
+    /// 黒の背景に`nits`の輝度の模様を描く。模様がちょうど見えなくなる値が最小の輝度
+    MinLuminance { nits: f32 },
+    /// 紙の白の輝度の灰色の階段。UIや白い紙が眩しくない明るさに合わせる
+    PaperWhite,
+    /// 左から右へ`HdrCalibration::min_nits`から`max_nits`まで対数で増える輝度のランプ
+    Ramp,
+    /// 紙の白の輝度の、白、黄、シアン、緑、マゼンタ、赤、青のカラーバー
+    ColorBars,
+}
+
+impl CalibrationPattern {
+    /// シェーダーに渡す番号と輝度
+    pub fn index_and_nits(self) -> (u32, f32) {
+        match self {
+            CalibrationPattern::MaxLuminance { nits } => (0, nits),
+            CalibrationPattern::MinLuminance { nits } => (1, nits),
+            CalibrationPattern::PaperWhite => (2, 0.0),
+            CalibrationPattern::Ramp => (3, 0.0),
+            CalibrationPattern::ColorBars => (4, 0.0),
+        }
+    }
+}
+
+/// キャリブレーション画面で合わせる出力の輝度。単位はnit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HdrCalibration {
+    /// SDRの白やUIを表示する輝度
+    pub paper_white_nits: f32,
+    pub min_nits: f32,
+    pub max_nits: f32,
+}
+
+impl Default for HdrCalibration {
+    fn default() -> Self {
+        Self {
+            paper_white_nits: 200.0,
+            min_nits: 0.01,
+            max_nits: 1000.0,
+        }
+    }
+}
+
+impl HdrCalibration {
+    /// ディスプレイの輝度の範囲を初期値にする
+    pub fn from_display(display: &DisplayLuminance) -> Self {
+        Self {
+            min_nits: display.min_nits.max(0.0001),
+            max_nits: display.max_nits,
+            ..Self::default()
+        }
+    }
+}
+
+/// キャリブレーション画面のシェーダー
+///
+/// 頂点バッファなしで画面全体を覆う三角形を描き、スワップチェインの色空間に合わせて
+/// テストパターンを書く。プッシュ定数は`CalibrationPushConstants`。
+///
+/// ```glsl
+/// // 頂点シェーダーはToneMappingShadersと同じ
+///
+/// // フラグメントシェーダー
+/// #include "tempura/hdr_output.glsl"
+/// layout(push_constant) uniform Calibration {
+///     vec2 size; uint pattern; uint encoding;
+///     float nits; float paper_white_nits; float min_nits; float max_nits;
+/// };
+/// layout(location = 0) out vec4 color;
+/// void main() {
+///     vec2 uv = gl_FragCoord.xy / size;
+///     // 中央の正方形を模様にする
+///     vec2 p = (gl_FragCoord.xy - size * 0.5) / size.y;
+///     bool inner = max(abs(p.x), abs(p.y)) < 0.15;
+///     vec3 c;
+///     if (pattern == 0u) c = vec3(inner ? nits : TEMPURA_PQ_MAX_NITS);
+///     else if (pattern == 1u) c = vec3(inner ? nits : 0.0);
+///     else if (pattern == 2u) c = vec3(paper_white_nits * floor(uv.x * 8.0 + 1.0) / 8.0);
+///     else if (pattern == 3u) c = vec3(min_nits * pow(max_nits / min_nits, uv.x));
+///     else {
+///         const vec3 bars[7] = vec3[](vec3(1, 1, 1), vec3(1, 1, 0), vec3(0, 1, 1),
+///             vec3(0, 1, 0), vec3(1, 0, 1), vec3(1, 0, 0), vec3(0, 0, 1));
+///         c = bars[min(int(uv.x * 7.0), 6)] * paper_white_nits;
+///     }
+///     color = vec4(tempura_encode_output(c, encoding, paper_white_nits), 1.0);
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HdrCalibrationShaders {
+    pub vertex: ShaderId,
+    pub fragment: ShaderId,
+}
+
+/// キャリブレーション画面のフラグメントシェーダーに渡すプッシュ定数
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationPushConstants {
+    /// スワップチェインのピクセル数
+    pub size: [f32; 2],
+    /// `CalibrationPattern::index_and_nits`の番号
+    pub pattern: u32,
+    /// `OutputEncoding::index`
+    pub encoding: u32,
+    pub nits: f32,
+    pub paper_white_nits: f32,
+    pub min_nits: f32,
+    pub max_nits: f32,
+}
+
+impl CalibrationPushConstants {
+    fn bytes(&self) -> Vec<u8> {
+        self.size
+            .iter()
+            .flat_map(|value| value.to_ne_bytes())
+            .chain(self.pattern.to_ne_bytes())
+            .chain(self.encoding.to_ne_bytes())
+            .chain(
+                [
+                    self.nits,
+                    self.paper_white_nits,
+                    self.min_nits,
+                    self.max_nits,
+                ]
+                .iter()
+                .flat_map(|value| value.to_ne_bytes()),
+            )
+            .collect()
+    }
+}
+
+/// HDRのレンダーターゲットの最小と最大の輝度を調べるシェーダー
+///
+/// セット0は`luminance_probe_set_layout`、プッシュ定数は`LuminanceProbePushConstants`。
+/// 正の浮動小数点数はビット列を符号なし整数として比べても大小が変わらないので、
+/// ビット列のアトミックな最小と最大で集める。
+///
+/// ```glsl
+/// #include "tempura/common.glsl"
+/// layout(local_size_x = 16, local_size_y = 16) in;
+/// layout(set = 0, binding = 0) uniform sampler2D hdr;
+/// layout(set = 0, binding = 1) buffer Probe {
+///     uint min_bits; uint max_bits; uint clipped_pixels; uint pixel_count;
+/// };
+/// layout(push_constant) uniform Params { float scale; float clip_nits; };
+/// shared uint group_min, group_max, group_clipped;
+/// void main() {
+///     if (gl_LocalInvocationIndex == 0u) {
+///         group_min = 0x7f7fffffu; group_max = 0u; group_clipped = 0u;
+///     }
+///     barrier();
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy);
+///     if (all(lessThan(p, textureSize(hdr, 0)))) {
+///         float nits = max(tempura_luminance(texelFetch(hdr, p, 0).rgb) * scale, 0.0);
+///         atomicMin(group_min, floatBitsToUint(nits));
+///         atomicMax(group_max, floatBitsToUint(nits));
+///         if (nits > clip_nits) atomicAdd(group_clipped, 1u);
+///     }
+///     barrier();
+///     if (gl_LocalInvocationIndex == 0u) {
+///         atomicMin(min_bits, group_min);
+///         atomicMax(max_bits, group_max);
+///         atomicAdd(clipped_pixels, group_clipped);
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LuminanceProbeShaders {
+    pub probe: ShaderId,
+}
+
+/// 輝度を調べるシェーダーに渡すプッシュ定数
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LuminanceProbePushConstants {
+    /// HDRのレンダーターゲットの値をnitにする係数。露出と紙の白の輝度を掛けたものなど
+    pub scale: f32,
+    /// これより明るい画素を白飛びとして数える
+    pub clip_nits: f32,
+}
+
+/// `luminance_probe_set_layout`のストレージバッファ。std430と同じ配置
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ProbeCounters {
+    min_bits: u32,
+    max_bits: u32,
+    clipped_pixels: u32,
+    pixel_count: u32,
+}
+
+impl ProbeCounters {
+    fn reset(pixel_count: u32) -> Self {
+        Self {
+            min_bits: f32::MAX.to_bits(),
+            max_bits: 0,
+            clipped_pixels: 0,
+            pixel_count,
+        }
+    }
+}
+
+/// 調べた輝度
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LuminanceProbeResult {
+    pub min_nits: f32,
+    pub max_nits: f32,
+    /// `clip_nits`より明るい画素の割合
+    pub clipped_fraction: f32,
+}
+
+/// `enable_luminance_probe`で作る輝度を調べるリソース
+///
+/// カウンターのバッファは`Renderer`のバッファとして別に破棄される。
+pub struct LuminanceProbe {
+    pub pipeline: ComputePipeline,
+    /// フレームコンテキストごとの`ProbeCounters`。HOST_VISIBLEで、結果をそのまま読む
+    pub counter_buffers: PerFrame<BufferId>,
+    /// フレームコンテキストごとの`luminance_probe_set_layout`のセット。HDRのターゲットが変わったら書き直す
+    pub sets: Vec<vk::DescriptorSet>,
+    /// フレームコンテキストごとの、結果を待っているか
+    pending: Vec<bool>,
+    result: Option<LuminanceProbeResult>,
+}
+
+impl LuminanceProbe {
+    /// 最後に読めた結果
+    pub fn result(&self) -> Option<LuminanceProbeResult> {
+        self.result
+    }
+
+    /// # Safety
+    /// `device`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device) {
+        self.pipeline.destroy(device);
+    }
+}
+
+impl Renderer {
+    /// 現在のスワップチェインの出力と、サーフェスが対応しているHDRのフォーマット
+    pub fn hdr_output_info(&self) -> Result<HdrOutputInfo> {
+        let formats = unsafe {
+            self.surface_loader
+                .get_physical_device_surface_formats(self.pdevice, self.surface)?
+        };
+        Ok(HdrOutputInfo {
+            surface_format: self.surface_format,
+            encoding: self.output_encoding(),
+            hdr_surface_formats: formats
+                .into_iter()
+                .filter(|format| OutputEncoding::from_color_space(format.color_space).is_hdr())
+                .collect(),
+            display_luminance: self.display_luminance,
+        })
+    }
+
+    pub fn output_encoding(&self) -> OutputEncoding {
+        OutputEncoding::from_color_space(self.surface_format.color_space)
+    }
+
+    /// OSから得たディスプレイの輝度の範囲を設定する。`None`なら分からないことにする
+    pub fn set_display_luminance(&mut self, luminance: Option<DisplayLuminance>) -> Result<()> {
+        if let Some(luminance) = &luminance {
+            if !(0.0 <= luminance.min_nits
+                && luminance.min_nits < luminance.max_nits
+                && luminance.max_full_frame_nits > 0.0)
+            {
+                return Err(RendererError::Validation(format!(
+                    "invalid display luminance range {:?}",
+                    luminance
+                )));
+            }
+        }
+        self.display_luminance = luminance;
+        Ok(())
+    }
+
+    /// キャリブレーション画面のパイプラインを作成する
+    pub fn enable_hdr_calibration(&mut self, shaders: HdrCalibrationShaders) -> Result<()> {
+        let module = |id: ShaderId| {
+            self.shader_modules
+                .get(id)
+                .map(|shader| shader.module)
+                .ok_or_else(|| {
+                    RendererError::Validation(format!("shader {:?} was already destroyed", id))
+                })
+        };
+        let builder = PipelineBuilder::new()
+            .vertex_shader(module(shaders.vertex)?)
+            .fragment_shader(module(shaders.fragment)?)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .depth_test(false)
+            .depth_write(false)
+            .push_constant_range(
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                std::mem::size_of::<CalibrationPushConstants>() as u32,
+            );
+        let pipeline = self.create_swapchain_pipeline(&builder)?;
+        if let Some(old) = self.hdr_calibration.replace(pipeline) {
+            self.destroy_graphics_pipeline(old);
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからキャリブレーション画面のパイプラインを破棄する
+    pub fn disable_hdr_calibration(&mut self) {
+        if let Some(pipeline) = self.hdr_calibration.take() {
+            self.destroy_graphics_pipeline(pipeline);
+        }
+    }
+
+    /// テストパターンを画面全体に描く
+    ///
+    /// `begin_swapchain_rendering`と`end_swapchain_rendering`の間で、トーンマッピングの
+    /// 代わりに呼ぶ。説明の文字などはこの後に重ねて描ける。
+    pub fn draw_calibration_pattern(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        pattern: CalibrationPattern,
+        calibration: &HdrCalibration,
+    ) -> Result<()> {
+        let pipeline = self.hdr_calibration.as_ref().ok_or_else(|| {
+            RendererError::Validation("HDR calibration is not enabled".to_owned())
+        })?;
+        validate_calibration(calibration)?;
+        let (index, nits) = pattern.index_and_nits();
+        let push_constants = CalibrationPushConstants {
+            size: [
+                self.surface_resolution.width as f32,
+                self.surface_resolution.height as f32,
+            ],
+            pattern: index,
+            encoding: self.output_encoding().index(),
+            nits,
+            paper_white_nits: calibration.paper_white_nits,
+            min_nits: calibration.min_nits,
+            max_nits: calibration.max_nits,
+        };
+        unsafe {
+            self.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.pipeline,
+            );
+            self.push_constants(command_buffer, pipeline, &push_constants.bytes());
+            self.device.cmd_draw(command_buffer, 3, 1, 0, 0);
+        }
+        Ok(())
+    }
+
+    /// 輝度を調べるデスクリプタセットレイアウト。バインディング0がHDRのレンダーターゲット、1がカウンター
+    pub fn luminance_probe_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        let stage = vk::ShaderStageFlags::COMPUTE;
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, stage),
+            DescriptorBinding::storage_buffer(1, stage),
+        ])
+    }
+
+    /// HDRのレンダーターゲットの最小と最大の輝度を調べるパスを有効にする。`enable_hdr`の後で呼ぶ
+    pub fn enable_luminance_probe(&mut self, shaders: LuminanceProbeShaders) -> Result<()> {
+        self.enabled_hdr()?;
+        let layout = self.luminance_probe_set_layout()?;
+        let pipeline = self.create_compute_pipeline(
+            shaders.probe,
+            "main",
+            &[layout],
+            std::mem::size_of::<LuminanceProbePushConstants>() as u32,
+        )?;
+        let counter_buffers = match self.create_per_frame_buffers(
+            std::mem::size_of::<ProbeCounters>() as vk::DeviceSize,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        ) {
+            Ok(buffers) => buffers,
+            Err(err) => {
+                self.destroy_compute_pipeline(pipeline);
+                return Err(err);
+            }
+        };
+        let sets = match self.allocate_luminance_probe_sets(layout) {
+            Ok(sets) => sets,
+            Err(err) => {
+                self.destroy_compute_pipeline(pipeline);
+                self.destroy_per_frame_buffers(counter_buffers)?;
+                return Err(err);
+            }
+        };
+        let probe = LuminanceProbe {
+            pipeline,
+            counter_buffers,
+            sets,
+            pending: vec![false; self.frames.len()],
+            result: None,
+        };
+        if let Some(old) = self.luminance_probe.replace(probe) {
+            self.destroy_luminance_probe(old)?;
+        }
+        self.rewrite_luminance_probe_sets();
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してから輝度を調べるリソースを破棄する
+    pub fn disable_luminance_probe(&mut self) -> Result<()> {
+        match self.luminance_probe.take() {
+            Some(probe) => self.destroy_luminance_probe(probe),
+            None => Ok(()),
+        }
+    }
+
+    pub fn luminance_probe(&self) -> Option<&LuminanceProbe> {
+        self.luminance_probe.as_ref()
+    }
+
+    /// HDRのレンダーターゲットの輝度を調べる
+    ///
+    /// `end_hdr_rendering`の後、レンダーパスの外で呼ぶ。GPUの結果はこのフレームコンテキストが
+    /// 次に使われるときに読むので、`LuminanceProbe::result`にはフレームコンテキストの数だけ
+    /// 遅れて反映される。
+    pub fn probe_luminance(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        push_constants: &LuminanceProbePushConstants,
+    ) -> Result<()> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "luminance can only be probed between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        let extent = self.enabled_hdr()?.target.extent;
+        let probe = self.luminance_probe.as_ref().ok_or_else(|| {
+            RendererError::Validation("luminance probe is not enabled".to_owned())
+        })?;
+        let counter_buffer = *self.per_frame(&probe.counter_buffers);
+        let buffer = self.buffers.get(counter_buffer).unwrap();
+        let ptr = buffer.allocation.mapped_ptr.ok_or_else(|| {
+            RendererError::Validation("luminance probe memory is not host visible".to_owned())
+        })? as *mut ProbeCounters;
+        // begin_frameでフェンスを待っているので、前回このフレームコンテキストで書いた結果を読める
+        let result = probe.pending[self.current_frame].then(|| {
+            let counters = unsafe { ptr.read() };
+            LuminanceProbeResult {
+                min_nits: f32::from_bits(counters.min_bits),
+                max_nits: f32::from_bits(counters.max_bits),
+                clipped_fraction: counters.clipped_pixels as f32
+                    / counters.pixel_count.max(1) as f32,
+            }
+        });
+        unsafe { ptr.write(ProbeCounters::reset(extent.width * extent.height)) };
+
+        // HDRの描画を待つ
+        let barrier = *vk::MemoryBarrier::builder()
+            .src_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::SHADER_WRITE,
+            )
+            .dst_access_mask(vk::AccessFlags::SHADER_READ);
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            );
+        }
+        let data: Vec<u8> = push_constants
+            .scale
+            .to_ne_bytes()
+            .into_iter()
+            .chain(push_constants.clip_nits.to_ne_bytes())
+            .collect();
+        self.dispatch(
+            command_buffer,
+            &probe.pipeline,
+            &[probe.sets[self.current_frame]],
+            &data,
+            [
+                extent.width.div_ceil(LUMINANCE_PROBE_GROUP_SIZE),
+                extent.height.div_ceil(LUMINANCE_PROBE_GROUP_SIZE),
+                1,
+            ],
+        )?;
+        self.buffer_barrier(
+            command_buffer,
+            counter_buffer,
+            BufferAccess::COMPUTE_SHADER_WRITE,
+            BufferAccess::HOST_READ,
+        )?;
+
+        let current_frame = self.current_frame;
+        let probe = self.luminance_probe.as_mut().unwrap();
+        probe.pending[current_frame] = true;
+        if result.is_some() {
+            probe.result = result;
+        }
+        Ok(())
+    }
+
+    /// `enable_hdr`で作り直したHDRのリソースを使うよう、新しいデスクリプタセットに切り替える
+    ///
+    /// 古いセットは使用中のフレームが参照しているので書き換えない。
+    pub(crate) fn refresh_luminance_probe(&mut self) -> Result<()> {
+        if self.luminance_probe.is_none() {
+            return Ok(());
+        }
+        let layout = self.luminance_probe_set_layout()?;
+        let sets = self.allocate_luminance_probe_sets(layout)?;
+        self.luminance_probe.as_mut().unwrap().sets = sets;
+        self.rewrite_luminance_probe_sets();
+        Ok(())
+    }
+
+    /// 輝度を調べるデスクリプタセットを現在のHDRのリソースで書き直す
+    pub(crate) fn rewrite_luminance_probe_sets(&self) {
+        let (Some(probe), Some(hdr)) = (&self.luminance_probe, &self.hdr) else {
+            return;
+        };
+        for (&set, id) in probe.sets.iter().zip(probe.counter_buffers.iter()) {
+            let Some(buffer) = self.buffers.get(*id) else {
+                continue;
+            };
+            unsafe {
+                DescriptorWriter::new()
+                    .image(
+                        0,
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::DescriptorImageInfo {
+                            sampler: hdr.sampler,
+                            image_view: hdr.target.view,
+                            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                        },
+                    )
+                    .buffer(1, vk::DescriptorType::STORAGE_BUFFER, buffer)
+                    .update(&self.device, set);
+            }
+        }
+    }
+
+    fn allocate_luminance_probe_sets(
+        &mut self,
+        layout: vk::DescriptorSetLayout,
+    ) -> Result<Vec<vk::DescriptorSet>> {
+        (0..self.frames.len())
+            .map(|_| self.allocate_descriptor_set(layout))
+            .collect()
+    }
+
+    fn destroy_luminance_probe(&mut self, probe: LuminanceProbe) -> Result<()> {
+        for &id in probe.counter_buffers.iter() {
+            self.destroy_buffer(id)?;
+        }
+        self.destroy_deferred(move |device, _| unsafe { probe.destroy(device) });
+        Ok(())
+    }
+}
+
+fn validate_calibration(calibration: &HdrCalibration) -> Result<()> {
+    if calibration.paper_white_nits > 0.0
+        && calibration.min_nits > 0.0
+        && calibration.min_nits < calibration.max_nits
+    {
+        Ok(())
+    } else {
+        Err(RendererError::Validation(format!(
+            "invalid HDR calibration {:?}",
+            calibration
+        )))
+    }
+}
@@ -0,0 +1,551 @@
+use super::buffer::write_buffer;
+use super::descriptor::{allocate_set, create_pool};
+use super::error::{RendererError, Result};
+use super::memory::{Allocation, MemoryAllocator};
+use super::renderer::create_depth_sampling_view;
+use super::texture::{create_image, image_barrier};
+use super::{
+    BufferId, Camera, ComputePipeline, DescriptorBinding, DescriptorWriter, Mat4, PerFrame,
+    Renderer, SamplerDesc, ShaderId,
+};
+use ash::{vk, Device};
+
+/// Hi-Zのピラミッドのフォーマット
+pub const HI_Z_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+/// 縮小のシェーダーのワークグループの幅と高さ
+pub const HI_Z_GROUP_SIZE: u32 = 8;
+
+/// Hi-Zのピラミッドを作るシェーダーと、それを使うカリングのシェーダー
+///
+/// ピラミッドの各テクセルには、覆う範囲で最も遠い深度を入れる。`downsample`は
+/// `hi_z_set_layout`を使い、最初の段では深度バッファから縮小する。
+///
+/// ```glsl
+/// // downsample
+/// layout(local_size_x = 8, local_size_y = 8) in;
+/// layout(set = 0, binding = 0) uniform sampler2D src;
+/// layout(set = 0, binding = 1, r32f) uniform writeonly image2D dst;
+/// layout(push_constant) uniform Params { uint reversed_z; };
+/// float farthest(float a, float b) { return reversed_z != 0 ? min(a, b) : max(a, b); }
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy), size = imageSize(dst);
+///     if (any(greaterThanEqual(p, size))) return;
+///     ivec2 src_size = textureSize(src, 0);
+///     // 奇数の大きさでは端の1列も含め、どの画素も落とさない
+///     ivec2 last = min(p * 2 + 1 + ivec2(equal(p, size - 1)) * (src_size & 1), src_size - 1);
+///     float d = texelFetch(src, p * 2, 0).r;
+///     for (int y = p.y * 2; y <= last.y; ++y)
+///         for (int x = p.x * 2; x <= last.x; ++x)
+///             d = farthest(d, texelFetch(src, ivec2(x, y), 0).r);
+///     imageStore(dst, p, vec4(d));
+/// }
+/// ```
+///
+/// `cull`は`GpuDrivenShaders::cull`の視錐台の判定の後に、前のフレームのビュー射影行列で
+/// 箱を投影して隠れているかを調べる。セット0は`gpu_driven_set_layout`、セット1は
+/// `hi_z_cull_set_layout`で、プッシュ定数も同じ。
+///
+/// ```glsl
+/// layout(set = 1, binding = 0) uniform sampler2D hi_z;
+/// layout(set = 1, binding = 1) uniform HiZ {
+///     mat4 view_projection; vec2 size; uint mip_count; uint reversed_z;
+/// };
+/// bool occluded(vec3 bounds_min, vec3 bounds_max) {
+///     vec2 lo = vec2(1.0), hi = vec2(0.0);
+///     float nearest = reversed_z != 0 ? 0.0 : 1.0;
+///     for (int c = 0; c < 8; ++c) {
+///         vec3 corner = mix(bounds_min, bounds_max, vec3(c & 1, (c >> 1) & 1, (c >> 2) & 1));
+///         vec4 clip = view_projection * vec4(corner, 1.0);
+///         // カメラの後ろにかかる箱は隠れているとみなさない
+///         if (clip.w <= 0.0) return false;
+///         vec3 ndc = clip.xyz / clip.w;
+///         lo = min(lo, ndc.xy * 0.5 + 0.5);
+///         hi = max(hi, ndc.xy * 0.5 + 0.5);
+///         nearest = reversed_z != 0 ? max(nearest, ndc.z) : min(nearest, ndc.z);
+///     }
+///     lo = clamp(lo, 0.0, 1.0);
+///     hi = clamp(hi, 0.0, 1.0);
+///     // 箱の範囲が2x2テクセルに収まるミップを読む
+///     vec2 extent = (hi - lo) * size;
+///     float mip = min(ceil(log2(max(max(extent.x, extent.y), 1.0))), float(mip_count - 1));
+///     vec4 d = vec4(textureLod(hi_z, lo, mip).r, textureLod(hi_z, vec2(hi.x, lo.y), mip).r,
+///                   textureLod(hi_z, vec2(lo.x, hi.y), mip).r, textureLod(hi_z, hi, mip).r);
+///     return reversed_z != 0
+///         ? nearest < min(min(d.x, d.y), min(d.z, d.w))
+///         : nearest > max(max(d.x, d.y), max(d.z, d.w));
+/// }
+/// void main() {
+///     // ... 視錐台の外ならreturn
+///     DrawCommand command = DrawCommand(
+///         instance.index_count, 1, instance.first_index, instance.vertex_offset, i);
+///     if (occluded(instance.bounds_min, instance.bounds_max)) {
+///         commands[instance_count + atomicAdd(occluded_count, 1)] = command;
+///         return;
+///     }
+///     commands[atomicAdd(draw_count, 1)] = command;
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HiZShaders {
+    pub downsample: ShaderId,
+    pub cull: ShaderId,
+}
+
+/// `hi_z_cull_set_layout`のユニフォームバッファ
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct HiZUniform {
+    view_projection: Mat4,
+    size: [f32; 2],
+    mip_count: u32,
+    reversed_z: u32,
+}
+
+/// 深度バッファの半分の解像度から1x1まで縮小するピラミッド
+///
+/// 常に`GENERAL`レイアウトで使う。スワップチェインを作り直すたびに作り直す。
+pub struct HiZPyramid {
+    pub image: vk::Image,
+    pub allocation: Allocation,
+    /// ミップごとのビュー
+    pub views: Vec<vk::ImageView>,
+    /// 全てのミップを見るビュー
+    pub view: vk::ImageView,
+    pub depth_view: vk::ImageView,
+    pub extents: Vec<vk::Extent2D>,
+    pub descriptor_pool: vk::DescriptorPool,
+    /// `i`番目はミップ`i - 1`(最初は深度バッファ)からミップ`i`に縮小する
+    pub downsample_sets: Vec<vk::DescriptorSet>,
+    /// フレームコンテキストごとの`hi_z_cull_set_layout`のデスクリプタセット
+    pub cull_sets: Vec<vk::DescriptorSet>,
+}
+
+impl HiZPyramid {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのピラミッドを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        device.destroy_descriptor_pool(self.descriptor_pool, None);
+        device.destroy_image_view(self.depth_view, None);
+        device.destroy_image_view(self.view, None);
+        for &view in self.views.iter() {
+            device.destroy_image_view(view, None);
+        }
+        device.destroy_image(self.image, None);
+        allocator.free(device, self.allocation);
+    }
+}
+
+/// ピラミッドを作ったフレームの情報
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct HiZSource {
+    frame: u64,
+    view_projection: Mat4,
+    reversed_z: bool,
+}
+
+/// `enable_hi_z`で作るオクルージョンカリングのリソース
+///
+/// ユニフォームバッファは`Renderer`のバッファとして別に破棄される。
+pub struct HiZ {
+    pub downsample_pipeline: ComputePipeline,
+    pub cull_pipeline: ComputePipeline,
+    pub sampler: vk::Sampler,
+    pub uniform_buffers: PerFrame<BufferId>,
+    pub pyramid: HiZPyramid,
+    source: Option<HiZSource>,
+}
+
+impl HiZ {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        self.downsample_pipeline.destroy(device);
+        self.cull_pipeline.destroy(device);
+        device.destroy_sampler(self.sampler, None);
+        self.pyramid.destroy(device, allocator);
+    }
+}
+
+impl Renderer {
+    /// 縮小のデスクリプタセットレイアウト。バインディング0が入力、1が出力のストレージイメージ
+    pub fn hi_z_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, vk::ShaderStageFlags::COMPUTE),
+            DescriptorBinding::new(
+                1,
+                vk::DescriptorType::STORAGE_IMAGE,
+                vk::ShaderStageFlags::COMPUTE,
+            ),
+        ])
+    }
+
+    /// Hi-Zを使うカリングのデスクリプタセットレイアウト。バインディング0がピラミッド、
+    /// 1がユニフォームバッファ
+    pub fn hi_z_cull_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, vk::ShaderStageFlags::COMPUTE),
+            DescriptorBinding::uniform_buffer(1, vk::ShaderStageFlags::COMPUTE),
+        ])
+    }
+
+    /// `cull_gpu_driven_batch`でオクルージョンカリングを行うようにする
+    ///
+    /// `enable_gpu_driven`の後で呼ぶ。マルチサンプルの深度は縮小できないので、MSAAとは
+    /// 組み合わせられない。
+    pub fn enable_hi_z(&mut self, shaders: HiZShaders) -> Result<()> {
+        if self.gpu_driven.is_none() {
+            return Err(RendererError::Validation(
+                "gpu driven rendering is not enabled".to_owned(),
+            ));
+        }
+        if self.msaa_samples != vk::SampleCountFlags::TYPE_1 {
+            return Err(RendererError::Validation(
+                "Hi-Z cannot be combined with MSAA".to_owned(),
+            ));
+        }
+        let layout = self.hi_z_set_layout()?;
+        let cull_layouts = [self.gpu_driven_set_layout()?, self.hi_z_cull_set_layout()?];
+        let downsample_pipeline =
+            self.create_compute_pipeline(shaders.downsample, "main", &[layout], 4)?;
+        let cull_pipeline =
+            match self.create_compute_pipeline(shaders.cull, "main", &cull_layouts, 100) {
+                Ok(pipeline) => pipeline,
+                Err(err) => {
+                    self.destroy_compute_pipeline(downsample_pipeline);
+                    return Err(err);
+                }
+            };
+        let resources = self
+            .create_sampler(&SamplerDesc::nearest())
+            .and_then(|sampler| {
+                let uniform_buffers = match self.create_per_frame_buffers(
+                    std::mem::size_of::<HiZUniform>() as vk::DeviceSize,
+                    vk::BufferUsageFlags::UNIFORM_BUFFER,
+                ) {
+                    Ok(buffers) => buffers,
+                    Err(err) => {
+                        unsafe { self.device.destroy_sampler(sampler, None) };
+                        return Err(err);
+                    }
+                };
+                let buffers: Vec<_> = uniform_buffers.iter().copied().collect();
+                match unsafe { self.create_hi_z_pyramid(sampler, &buffers) } {
+                    Ok(pyramid) => Ok((sampler, uniform_buffers, pyramid)),
+                    Err(err) => {
+                        unsafe { self.device.destroy_sampler(sampler, None) };
+                        self.destroy_per_frame_buffers(uniform_buffers)?;
+                        Err(err)
+                    }
+                }
+            });
+        let (sampler, uniform_buffers, pyramid) = match resources {
+            Ok(resources) => resources,
+            Err(err) => {
+                self.destroy_compute_pipeline(downsample_pipeline);
+                self.destroy_compute_pipeline(cull_pipeline);
+                return Err(err);
+            }
+        };
+        let hi_z = HiZ {
+            downsample_pipeline,
+            cull_pipeline,
+            sampler,
+            uniform_buffers,
+            pyramid,
+            source: None,
+        };
+        if let Some(old) = self.hi_z.replace(hi_z) {
+            self.destroy_hi_z(old)?;
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからHi-Zのリソースを破棄する
+    pub fn disable_hi_z(&mut self) -> Result<()> {
+        match self.hi_z.take() {
+            Some(hi_z) => self.destroy_hi_z(hi_z),
+            None => Ok(()),
+        }
+    }
+
+    pub fn hi_z(&self) -> Option<&HiZ> {
+        self.hi_z.as_ref()
+    }
+
+    /// 深度バッファからHi-Zのピラミッドを作る
+    ///
+    /// `camera`で深度を描き終えた後、レンダーパスの外で呼ぶ。ピラミッドは次のフレームの
+    /// `cull_gpu_driven_batch`で使うので、前のフレームから大きく動いた物や、前のフレームで
+    /// 見えていなかった物の後ろでは、実際には隠れていない物を除くことがある。
+    pub fn build_hi_z(&mut self, command_buffer: vk::CommandBuffer, camera: &Camera) -> Result<()> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "Hi-Z can only be built between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        let hi_z = self
+            .hi_z
+            .as_ref()
+            .ok_or_else(|| RendererError::Validation("Hi-Z is not enabled".to_owned()))?;
+        let pyramid = &hi_z.pyramid;
+        let levels = pyramid.views.len() as u32;
+        let compute_barrier = *vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+        unsafe {
+            self.begin_screen_pass(command_buffer, &[], Some(self.depth_image));
+            // 前のフレームのカリングでの読み込みを待ち、内容は捨てる
+            image_barrier(
+                &self.device,
+                command_buffer,
+                pyramid.image,
+                vk::ImageAspectFlags::COLOR,
+                0,
+                levels,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::GENERAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+            );
+        }
+        let reversed_z = camera.reversed_z as u32;
+        for (level, &set) in pyramid.downsample_sets.iter().enumerate() {
+            let extent = pyramid.extents[level];
+            self.dispatch(
+                command_buffer,
+                &hi_z.downsample_pipeline,
+                &[set],
+                &reversed_z.to_ne_bytes(),
+                [
+                    extent.width.div_ceil(HI_Z_GROUP_SIZE),
+                    extent.height.div_ceil(HI_Z_GROUP_SIZE),
+                    1,
+                ],
+            )?;
+            if level + 1 < pyramid.downsample_sets.len() {
+                unsafe {
+                    self.device.cmd_pipeline_barrier(
+                        command_buffer,
+                        vk::PipelineStageFlags::COMPUTE_SHADER,
+                        vk::PipelineStageFlags::COMPUTE_SHADER,
+                        vk::DependencyFlags::empty(),
+                        &[compute_barrier],
+                        &[],
+                        &[],
+                    );
+                }
+            }
+        }
+        // 最後の縮小の書き込みは次のフレームのカリングから読む
+        unsafe { self.restore_depth_attachment(command_buffer, &[compute_barrier]) };
+        let frame = self.frame_count;
+        self.hi_z.as_mut().unwrap().source = Some(HiZSource {
+            frame,
+            view_projection: camera.view_projection_matrix(),
+            reversed_z: camera.reversed_z,
+        });
+        Ok(())
+    }
+
+    /// 前のフレームでピラミッドを作っていれば、記録中のフレームのユニフォームバッファを書き、
+    /// カリングのパイプラインとセット1を返す
+    pub(crate) fn hi_z_occlusion(&self) -> Result<Option<(&ComputePipeline, vk::DescriptorSet)>> {
+        let Some(hi_z) = &self.hi_z else {
+            return Ok(None);
+        };
+        let Some(source) = hi_z
+            .source
+            .filter(|source| source.frame + 1 == self.frame_count)
+        else {
+            return Ok(None);
+        };
+        let base = hi_z.pyramid.extents[0];
+        let uniform = HiZUniform {
+            view_projection: source.view_projection,
+            size: [base.width as f32, base.height as f32],
+            mip_count: hi_z.pyramid.views.len() as u32,
+            reversed_z: source.reversed_z as u32,
+        };
+        let buffer = self
+            .buffers
+            .get(*self.per_frame(&hi_z.uniform_buffers))
+            .unwrap();
+        unsafe { write_buffer(buffer, &[uniform])? };
+        Ok(Some((
+            &hi_z.cull_pipeline,
+            hi_z.pyramid.cull_sets[self.current_frame],
+        )))
+    }
+
+    /// 深度バッファに合わせてピラミッドを作り直す
+    ///
+    /// 古いピラミッドは使用中のフレームが完了してから破棄する。作り直した後は、次に
+    /// `build_hi_z`を呼ぶまでオクルージョンカリングを行わない。
+    pub(crate) fn recreate_hi_z_pyramid(&mut self) -> Result<()> {
+        let Some((sampler, uniform_buffers)) = self.hi_z.as_ref().map(|hi_z| {
+            let buffers: Vec<_> = hi_z.uniform_buffers.iter().copied().collect();
+            (hi_z.sampler, buffers)
+        }) else {
+            return Ok(());
+        };
+        let pyramid = unsafe { self.create_hi_z_pyramid(sampler, &uniform_buffers)? };
+        let hi_z = self.hi_z.as_mut().unwrap();
+        let old = std::mem::replace(&mut hi_z.pyramid, pyramid);
+        hi_z.source = None;
+        self.destroy_deferred(move |device, allocator| unsafe { old.destroy(device, allocator) });
+        Ok(())
+    }
+
+    fn destroy_hi_z(&mut self, hi_z: HiZ) -> Result<()> {
+        for &id in hi_z.uniform_buffers.iter() {
+            self.destroy_buffer(id)?;
+        }
+        self.destroy_deferred(move |device, allocator| unsafe { hi_z.destroy(device, allocator) });
+        Ok(())
+    }
+
+    unsafe fn create_hi_z_pyramid(
+        &mut self,
+        sampler: vk::Sampler,
+        uniform_buffers: &[BufferId],
+    ) -> Result<HiZPyramid> {
+        let layout = self.hi_z_set_layout()?;
+        let cull_layout = self.hi_z_cull_set_layout()?;
+        let base = vk::Extent2D {
+            width: (self.surface_resolution.width / 2).max(1),
+            height: (self.surface_resolution.height / 2).max(1),
+        };
+        let levels = u32::BITS - base.width.max(base.height).leading_zeros();
+        let image_create_info = *vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(HI_Z_FORMAT)
+            .extent(base.into())
+            .mip_levels(levels)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let (image, allocation) = create_image(
+            &self.device,
+            &mut self.allocator,
+            &image_create_info,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            true,
+        )?;
+        let mut pyramid = HiZPyramid {
+            image,
+            allocation,
+            views: Vec::with_capacity(levels as usize),
+            view: vk::ImageView::null(),
+            depth_view: vk::ImageView::null(),
+            extents: (0..levels)
+                .map(|level| vk::Extent2D {
+                    width: (base.width >> level).max(1),
+                    height: (base.height >> level).max(1),
+                })
+                .collect(),
+            descriptor_pool: vk::DescriptorPool::null(),
+            downsample_sets: Vec::new(),
+            cull_sets: Vec::new(),
+        };
+        match self.init_hi_z_pyramid(&mut pyramid, layout, cull_layout, sampler, uniform_buffers) {
+            Ok(()) => Ok(pyramid),
+            Err(err) => {
+                pyramid.destroy(&self.device, &mut self.allocator);
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn init_hi_z_pyramid(
+        &self,
+        pyramid: &mut HiZPyramid,
+        layout: vk::DescriptorSetLayout,
+        cull_layout: vk::DescriptorSetLayout,
+        sampler: vk::Sampler,
+        uniform_buffers: &[BufferId],
+    ) -> Result<()> {
+        let levels = pyramid.extents.len() as u32;
+        let view_create_info = |base_mip_level, level_count| {
+            *vk::ImageViewCreateInfo::builder()
+                .image(pyramid.image)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(HI_Z_FORMAT)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level,
+                    level_count,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+        };
+        for level in 0..levels {
+            let view = self
+                .device
+                .create_image_view(&view_create_info(level, 1), None)?;
+            pyramid.views.push(view);
+        }
+        pyramid.view = self
+            .device
+            .create_image_view(&view_create_info(0, levels), None)?;
+        pyramid.depth_view =
+            create_depth_sampling_view(&self.device, self.depth_image, self.depth_format)?;
+        let set_count = levels + uniform_buffers.len() as u32;
+        pyramid.descriptor_pool = create_pool(
+            &self.device,
+            set_count,
+            &[
+                (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1.0),
+                (vk::DescriptorType::STORAGE_IMAGE, 1.0),
+                (vk::DescriptorType::UNIFORM_BUFFER, 1.0),
+            ],
+        )?;
+        let general = |view| vk::DescriptorImageInfo {
+            sampler,
+            image_view: view,
+            image_layout: vk::ImageLayout::GENERAL,
+        };
+        for (level, &view) in pyramid.views.iter().enumerate() {
+            let set = allocate_set(&self.device, pyramid.descriptor_pool, layout)?;
+            let writer = match level {
+                0 => DescriptorWriter::new().depth_image(0, pyramid.depth_view, sampler),
+                _ => DescriptorWriter::new().image(
+                    0,
+                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    general(pyramid.views[level - 1]),
+                ),
+            };
+            writer
+                .image(
+                    1,
+                    vk::DescriptorType::STORAGE_IMAGE,
+                    vk::DescriptorImageInfo {
+                        sampler: vk::Sampler::null(),
+                        ..general(view)
+                    },
+                )
+                .update(&self.device, set);
+            pyramid.downsample_sets.push(set);
+        }
+        for &id in uniform_buffers {
+            let set = allocate_set(&self.device, pyramid.descriptor_pool, cull_layout)?;
+            DescriptorWriter::new()
+                .image(
+                    0,
+                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    general(pyramid.view),
+                )
+                .buffer(
+                    1,
+                    vk::DescriptorType::UNIFORM_BUFFER,
+                    self.buffers.get(id).unwrap(),
+                )
+                .update(&self.device, set);
+            pyramid.cull_sets.push(set);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use super::error::{RendererError, Result};
+use super::handle::{Handle, Pool};
+use super::shader::{create_shader_module, file_modified};
+use super::{GraphicsPipeline, PipelineBuilder, Renderer, ShaderId};
+use ash::vk;
+use std::time::{Duration, Instant};
+
+// ファイルの更新確認は毎フレーム行うほどの頻度は要らない
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// シェーダーの再読み込み時に作り直されるパイプライン
+pub struct ReloadablePipeline {
+    pub pipeline: GraphicsPipeline,
+    builder: PipelineBuilder,
+    stages: Vec<(vk::ShaderStageFlags, ShaderId)>,
+}
+
+pub type ReloadablePipelineId = Handle<ReloadablePipeline>;
+
+/// シェーダーファイルの監視状態
+///
+/// ファイル監視の仕組みは使わず、`begin_frame`で更新時刻をポーリングする。
+#[derive(Default)]
+pub struct ShaderHotReload {
+    pub pipelines: Pool<ReloadablePipeline>,
+    last_poll: Option<Instant>,
+    /// `begin_frame`のポーリングで再読み込みに失敗したシェーダー。`take_shader_reload_failures`で取り出す
+    pub(crate) failures: Vec<(ShaderId, RendererError)>,
+}
+
+/// `reload_shaders`の結果
+#[derive(Debug, Default)]
+pub struct ShaderReload {
+    /// 作り直したシェーダーの数
+    pub reloaded: usize,
+    /// 読み込みやパイプライン作成に失敗したシェーダーとそのエラー。古いものを使い続けている
+    pub failures: Vec<(ShaderId, RendererError)>,
+}
+
+impl Renderer {
+    /// シェーダーファイルの更新に追従するパイプラインを作成する
+    ///
+    /// `builder`のシェーダーモジュールは`load_shader_spv`で読み込んだものでなければならず、
+    /// パイプラインを破棄するまで`destroy_shader_module`してはならない。
+    pub fn create_reloadable_pipeline(
+        &mut self,
+        builder: PipelineBuilder,
+        render_pass: vk::RenderPass,
+        subpass: u32,
+    ) -> Result<ReloadablePipelineId> {
+        // 作り直すときもレンダーパスのサンプル数に合わせる
+        let builder = match self.render_pass_samples(render_pass) {
+            Some(samples) => builder.samples(samples),
+            None => builder,
+        };
+        let pipeline = self.create_graphics_pipeline(&builder, render_pass, subpass)?;
+        let stages = builder
+            .shader_stages()
+            .filter_map(|(stage, module)| {
+                self.shader_modules
+                    .iter()
+                    .find(|(_, shader_module)| shader_module.module == module)
+                    .map(|(id, _)| (stage, id))
+            })
+            .collect();
+        Ok(self.shader_hot_reload.pipelines.insert(ReloadablePipeline {
+            pipeline,
+            builder,
+            stages,
+        }))
+    }
+
+    /// 最新のシェーダーで作られたパイプラインを返す。コマンド記録の度に取得し直すこと
+    pub fn reloadable_pipeline(&self, id: ReloadablePipelineId) -> Option<&GraphicsPipeline> {
+        self.shader_hot_reload
+            .pipelines
+            .get(id)
+            .map(|reloadable| &reloadable.pipeline)
+    }
+
+    pub fn destroy_reloadable_pipeline(&mut self, id: ReloadablePipelineId) -> Result<()> {
+        let reloadable = self.shader_hot_reload.pipelines.remove(id).ok_or_else(|| {
+            RendererError::Validation(format!("pipeline {:?} was already destroyed", id))
+        })?;
+        self.destroy_graphics_pipeline(reloadable.pipeline);
+        Ok(())
+    }
+
+    /// 更新されたシェーダーファイルを読み直し、それを使うパイプラインを作り直す
+    ///
+    /// 読み込みやパイプライン作成に失敗したシェーダーは古いものを使い続け、エラーを結果に含める。
+    pub fn reload_shaders(&mut self) -> ShaderReload {
+        let changed: Vec<ShaderId> = self
+            .shader_modules
+            .iter()
+            .filter_map(|(id, shader_module)| {
+                let path = shader_module.path.as_ref()?;
+                let modified = file_modified(path)?;
+                (Some(modified) != shader_module.modified).then_some(id)
+            })
+            .collect();
+
+        let mut result = ShaderReload::default();
+        for id in changed {
+            match self.reload_shader(id) {
+                Ok(()) => result.reloaded += 1,
+                Err(err) => result.failures.push((id, err)),
+            }
+        }
+        result
+    }
+
+    /// `begin_frame`のポーリングで再読み込みに失敗したシェーダーとそのエラーを取り出す
+    ///
+    /// 取り出すまで溜まり続けるので、ホットリロードを有効にしたら毎フレーム呼ぶ。
+    pub fn take_shader_reload_failures(&mut self) -> Vec<(ShaderId, RendererError)> {
+        std::mem::take(&mut self.shader_hot_reload.failures)
+    }
+
+    /// 前回から`POLL_INTERVAL`以上経っていれば`reload_shaders`し、失敗したシェーダーを返す
+    pub(crate) fn poll_shader_hot_reload(&mut self) -> Vec<(ShaderId, RendererError)> {
+        let now = Instant::now();
+        if let Some(last_poll) = self.shader_hot_reload.last_poll {
+            if now.duration_since(last_poll) < POLL_INTERVAL {
+                return Vec::new();
+            }
+        }
+        self.shader_hot_reload.last_poll = Some(now);
+        self.reload_shaders().failures
+    }
+
+    fn reload_shader(&mut self, id: ShaderId) -> Result<()> {
+        let shader_module = self.shader_modules.get_mut(id).ok_or_else(|| {
+            RendererError::Validation(format!("shader {:?} is destroyed or unknown", id))
+        })?;
+        let Some(path) = shader_module.path.clone() else {
+            return Ok(());
+        };
+        let modified = file_modified(&path);
+        // 壊れたファイルを毎回読み直さないよう、失敗しても更新時刻は記録する
+        shader_module.modified = modified;
+        let bytes = std::fs::read(&path)?;
+        let new_module = unsafe { create_shader_module(&self.device, &bytes)? };
+
+        let mut rebuilt = Vec::new();
+        let mut result = Ok(());
+        for (pipeline_id, reloadable) in self.shader_hot_reload.pipelines.iter() {
+            if reloadable
+                .stages
+                .iter()
+                .all(|(_, stage_id)| *stage_id != id)
+            {
+                continue;
+            }
+            if let Some((_, stage_id)) = reloadable
+                .stages
+                .iter()
+                .find(|(_, stage_id)| !self.shader_modules.contains(*stage_id))
+            {
+                result = Err(RendererError::Validation(format!(
+                    "pipeline {:?} references shader {:?} that is destroyed",
+                    pipeline_id, stage_id
+                )));
+                break;
+            }
+            let mut builder = reloadable.builder.clone();
+            for (stage, _) in reloadable
+                .stages
+                .iter()
+                .filter(|(_, stage_id)| *stage_id == id)
+            {
+                builder.set_stage_module(*stage, new_module);
+            }
+            let render_pass = reloadable.pipeline.render_pass;
+            let subpass = reloadable.pipeline.subpass;
+            match builder.build(&self.device, render_pass, subpass) {
+                Ok(pipeline) => rebuilt.push((pipeline_id, builder, pipeline)),
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        if let Err(err) = result {
+            // 作り直せたパイプラインも含めて古いシェーダーのまま使い続ける
+            for (_, _, pipeline) in rebuilt {
+                unsafe { pipeline.destroy(&self.device) };
+            }
+            unsafe { self.device.destroy_shader_module(new_module, None) };
+            return Err(err);
+        }
+
+        for (pipeline_id, builder, pipeline) in rebuilt {
+            if let Some(reloadable) = self.shader_hot_reload.pipelines.get_mut(pipeline_id) {
+                reloadable.builder = builder;
+                let old_pipeline = std::mem::replace(&mut reloadable.pipeline, pipeline);
+                self.destroy_graphics_pipeline(old_pipeline);
+            }
+        }
+
+        // IDはそのままで中身のモジュールだけを差し替える
+        if let Some(shader_module) = self.shader_modules.get_mut(id) {
+            let old_module = std::mem::replace(&mut shader_module.module, new_module);
+            unsafe { self.device.destroy_shader_module(old_module, None) };
+        }
+        Ok(())
+    }
+}
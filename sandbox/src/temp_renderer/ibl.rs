@@ -0,0 +1,613 @@
+use super::descriptor::{allocate_set, create_pool};
+use super::environment::Environment;
+use super::error::Result;
+use super::memory::{Allocation, MemoryAllocator};
+use super::texture::create_image;
+use super::{
+    mip_level_count, ComputePipeline, DescriptorBinding, DescriptorWriter, Renderer, ShaderId,
+};
+use ash::{vk, Device};
+
+/// 拡散反射の放射照度キューブマップの各面の幅と高さ
+pub const IRRADIANCE_RESOLUTION: u32 = 32;
+/// 鏡面反射のプリフィルタ済みキューブマップのミップ0の幅と高さ。環境マップより大きくはしない
+pub const PREFILTERED_RESOLUTION: u32 = 256;
+/// プリフィルタ済みキューブマップのミップ数。ミップ`i`の粗さは`i / (ミップ数 - 1)`
+pub const PREFILTERED_MIP_LEVELS: u32 = 6;
+/// Split-sum近似のBRDFのルックアップテーブルの幅と高さ
+pub const BRDF_LUT_RESOLUTION: u32 = 512;
+
+const IBL_CUBE_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+// RG16Fへのストレージ書き込みは拡張フォーマットの機能が必要なので、RGBA16Fを使う
+const BRDF_LUT_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+const IBL_GROUP_SIZE: u32 = 8;
+
+/// イメージベースドライティングの前計算に使うコンピュートシェーダー
+///
+/// どれもエントリーポイントは`main`で、`local_size_x = 8, local_size_y = 8`で書く。
+///
+/// ```glsl
+/// // irradiance: 環境マップを余弦で重み付けして半球で積分する
+/// layout(set = 0, binding = 0) uniform samplerCube environment;
+/// layout(set = 0, binding = 1, rgba16f) uniform writeonly imageCube irradiance;
+///
+/// // prefilter: GGXの重点サンプリングで、粗さごとにミップへ書き込む
+/// layout(set = 0, binding = 0) uniform samplerCube environment;
+/// layout(set = 0, binding = 1, rgba16f) uniform writeonly imageCube prefiltered; // 1ミップ分
+/// layout(push_constant) uniform Prefilter { float roughness; };
+///
+/// // brdf_lut: x = N・V、y = 粗さに対するスケールとバイアスをrgに書き込む
+/// layout(set = 0, binding = 0, rgba16f) uniform writeonly image2D brdf_lut;
+/// ```
+///
+/// キューブマップへの書き込みは`gl_GlobalInvocationID.z`が面の番号になる。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IblShaders {
+    pub irradiance: ShaderId,
+    pub prefilter: ShaderId,
+    pub brdf_lut: ShaderId,
+}
+
+/// `IblShaders::prefilter`に渡すプッシュ定数
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrefilterPushConstants {
+    pub roughness: f32,
+}
+
+/// 前計算した結果を入れるイメージ
+pub struct IblImage {
+    pub image: vk::Image,
+    /// サンプリング用のビュー。キューブマップなら全てのミップを含むキューブのビュー
+    pub view: vk::ImageView,
+    pub allocation: Allocation,
+    pub resolution: u32,
+    pub mip_levels: u32,
+    /// 6レイヤーのキューブマップか。`false`なら1レイヤーの2Dイメージ
+    pub cube: bool,
+}
+
+impl IblImage {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのイメージを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        device.destroy_image_view(self.view, None);
+        device.destroy_image(self.image, None);
+        allocator.free(device, self.allocation);
+    }
+}
+
+/// 環境マップから前計算したイメージベースドライティングのリソース
+pub struct ImageBasedLighting {
+    pub irradiance: IblImage,
+    pub prefiltered: IblImage,
+    /// `ibl_set_layout`のデスクリプタセット
+    pub descriptor_set: vk::DescriptorSet,
+}
+
+impl ImageBasedLighting {
+    /// BRDFのルックアップテーブルはレンダラーが持つので破棄しない
+    ///
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        self.irradiance.destroy(device, allocator);
+        self.prefiltered.destroy(device, allocator);
+    }
+}
+
+/// 一時的なデスクリプタセットで行う前計算のディスパッチ1回分
+struct IblDispatch<'a> {
+    pipeline: &'a ComputePipeline,
+    layout: vk::DescriptorSetLayout,
+    /// バインディング0でサンプリングするビュー。`None`なら書き込み先がバインディング0になる
+    source: Option<vk::ImageView>,
+    target: vk::ImageView,
+    push_constants: Vec<u8>,
+    group_count: [u32; 3],
+}
+
+/// `GENERAL`に遷移させて書き込み、`SHADER_READ_ONLY_OPTIMAL`にするイメージ
+struct IblTarget {
+    image: vk::Image,
+    mip_levels: u32,
+    layer_count: u32,
+}
+
+fn group_count(size: u32) -> u32 {
+    size.div_ceil(IBL_GROUP_SIZE)
+}
+
+impl Renderer {
+    /// 設定すると`set_environment`で環境マップからイメージベースドライティングを前計算する
+    pub fn set_ibl_shaders(&mut self, shaders: IblShaders) {
+        self.ibl_shaders = Some(shaders);
+    }
+
+    /// イメージベースドライティングのデスクリプタセットレイアウト
+    ///
+    /// `pbr_pipeline_builder`のパイプラインで使う場合は、ポイントシャドウの後ろのセット4に
+    /// 追加する。環境マップの`ibl`の`descriptor_set`をバインドする。
+    ///
+    /// ```glsl
+    /// layout(set = 4, binding = 0) uniform samplerCube irradiance_map;
+    /// layout(set = 4, binding = 1) uniform samplerCube prefiltered_map;
+    /// layout(set = 4, binding = 2) uniform sampler2D brdf_lut;
+    ///
+    /// vec3 ambient(vec3 n, vec3 v, vec3 albedo, float metallic, float roughness, float ao) {
+    ///     vec3 f0 = mix(vec3(0.04), albedo, metallic);
+    ///     float n_dot_v = max(dot(n, v), 0.0);
+    ///     vec3 f = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+    ///     vec3 diffuse = texture(irradiance_map, n).rgb * albedo * (1.0 - f) * (1.0 - metallic);
+    ///     float lod = roughness * float(textureQueryLevels(prefiltered_map) - 1);
+    ///     vec3 prefiltered = textureLod(prefiltered_map, reflect(-v, n), lod).rgb;
+    ///     vec2 brdf = texture(brdf_lut, vec2(n_dot_v, roughness)).rg;
+    ///     return (diffuse + prefiltered * (f * brdf.x + brdf.y)) * ao;
+    /// }
+    /// ```
+    pub fn ibl_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        let stages = vk::ShaderStageFlags::FRAGMENT;
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, stages),
+            DescriptorBinding::sampled_image(1, stages),
+            DescriptorBinding::sampled_image(2, stages),
+        ])
+    }
+
+    /// 環境マップから放射照度とプリフィルタ済みのキューブマップを計算し、完了を待つ
+    ///
+    /// BRDFのルックアップテーブルは最初の1回だけ計算する。
+    pub(crate) fn create_image_based_lighting(
+        &mut self,
+        environment: &Environment,
+        shaders: IblShaders,
+    ) -> Result<ImageBasedLighting> {
+        let brdf_lut = self.brdf_lut(shaders.brdf_lut)?;
+        let cube_layout = self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, vk::ShaderStageFlags::COMPUTE),
+            DescriptorBinding::new(
+                1,
+                vk::DescriptorType::STORAGE_IMAGE,
+                vk::ShaderStageFlags::COMPUTE,
+            ),
+        ])?;
+        let ibl_layout = self.ibl_set_layout()?;
+        let irradiance_pipeline =
+            self.create_compute_pipeline(shaders.irradiance, "main", &[cube_layout], 0)?;
+        let prefilter_pipeline = match self.create_compute_pipeline(
+            shaders.prefilter,
+            "main",
+            &[cube_layout],
+            std::mem::size_of::<PrefilterPushConstants>() as u32,
+        ) {
+            Ok(pipeline) => pipeline,
+            Err(err) => {
+                self.destroy_compute_pipeline(irradiance_pipeline);
+                return Err(err);
+            }
+        };
+
+        let prefiltered_resolution = PREFILTERED_RESOLUTION.min(environment.resolution);
+        let prefiltered_mip_levels = PREFILTERED_MIP_LEVELS.min(mip_level_count(
+            prefiltered_resolution,
+            prefiltered_resolution,
+        ));
+        let result = unsafe {
+            self.create_ibl_image(IRRADIANCE_RESOLUTION, 1, IBL_CUBE_FORMAT, true)
+                .and_then(|irradiance| {
+                    match self.create_ibl_image(
+                        prefiltered_resolution,
+                        prefiltered_mip_levels,
+                        IBL_CUBE_FORMAT,
+                        true,
+                    ) {
+                        Ok(prefiltered) => Ok((irradiance, prefiltered)),
+                        Err(err) => {
+                            irradiance.destroy(&self.device, &mut self.allocator);
+                            Err(err)
+                        }
+                    }
+                })
+        };
+        let (irradiance, prefiltered) = match result {
+            Ok(images) => images,
+            Err(err) => {
+                self.destroy_compute_pipeline(irradiance_pipeline);
+                self.destroy_compute_pipeline(prefilter_pipeline);
+                return Err(err);
+            }
+        };
+
+        let mut storage_views = Vec::new();
+        let dispatched = unsafe {
+            self.create_storage_views(&irradiance, IBL_CUBE_FORMAT, &mut storage_views)
+                .and_then(|_| {
+                    self.create_storage_views(&prefiltered, IBL_CUBE_FORMAT, &mut storage_views)
+                })
+                .and_then(|_| {
+                    let mut dispatches = vec![IblDispatch {
+                        pipeline: &irradiance_pipeline,
+                        layout: cube_layout,
+                        source: Some(environment.view),
+                        target: storage_views[0],
+                        push_constants: Vec::new(),
+                        group_count: [
+                            group_count(IRRADIANCE_RESOLUTION),
+                            group_count(IRRADIANCE_RESOLUTION),
+                            6,
+                        ],
+                    }];
+                    for mip in 0..prefiltered_mip_levels {
+                        let size = (prefiltered_resolution >> mip).max(1);
+                        let roughness = if prefiltered_mip_levels > 1 {
+                            mip as f32 / (prefiltered_mip_levels - 1) as f32
+                        } else {
+                            0.0
+                        };
+                        dispatches.push(IblDispatch {
+                            pipeline: &prefilter_pipeline,
+                            layout: cube_layout,
+                            source: Some(environment.view),
+                            target: storage_views[1 + mip as usize],
+                            push_constants: roughness.to_ne_bytes().to_vec(),
+                            group_count: [group_count(size), group_count(size), 6],
+                        });
+                    }
+                    self.run_ibl_dispatches(
+                        &dispatches,
+                        &[
+                            IblTarget {
+                                image: irradiance.image,
+                                mip_levels: 1,
+                                layer_count: 6,
+                            },
+                            IblTarget {
+                                image: prefiltered.image,
+                                mip_levels: prefiltered_mip_levels,
+                                layer_count: 6,
+                            },
+                        ],
+                        environment.sampler,
+                    )
+                })
+        };
+        unsafe {
+            // ディスパッチの完了を待っているので、書き込み用のビューはすぐに破棄できる
+            for view in storage_views {
+                self.device.destroy_image_view(view, None);
+            }
+        }
+        self.destroy_compute_pipeline(irradiance_pipeline);
+        self.destroy_compute_pipeline(prefilter_pipeline);
+
+        match dispatched.and_then(|_| self.allocate_descriptor_set(ibl_layout)) {
+            Ok(descriptor_set) => {
+                let image_info = |view| vk::DescriptorImageInfo {
+                    sampler: environment.sampler,
+                    image_view: view,
+                    image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                };
+                unsafe {
+                    DescriptorWriter::new()
+                        .image(
+                            0,
+                            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                            image_info(irradiance.view),
+                        )
+                        .image(
+                            1,
+                            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                            image_info(prefiltered.view),
+                        )
+                        .image(
+                            2,
+                            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                            image_info(brdf_lut),
+                        )
+                        .update(&self.device, descriptor_set);
+                }
+                Ok(ImageBasedLighting {
+                    irradiance,
+                    prefiltered,
+                    descriptor_set,
+                })
+            }
+            Err(err) => {
+                unsafe {
+                    irradiance.destroy(&self.device, &mut self.allocator);
+                    prefiltered.destroy(&self.device, &mut self.allocator);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// BRDFのルックアップテーブルのビュー。まだなければ計算する
+    fn brdf_lut(&mut self, shader: ShaderId) -> Result<vk::ImageView> {
+        if let Some(brdf_lut) = &self.brdf_lut {
+            return Ok(brdf_lut.view);
+        }
+        let layout = self.descriptor_set_layout(&[DescriptorBinding::new(
+            0,
+            vk::DescriptorType::STORAGE_IMAGE,
+            vk::ShaderStageFlags::COMPUTE,
+        )])?;
+        let pipeline = self.create_compute_pipeline(shader, "main", &[layout], 0)?;
+        let result = unsafe {
+            self.create_ibl_image(BRDF_LUT_RESOLUTION, 1, BRDF_LUT_FORMAT, false)
+                .and_then(|brdf_lut| {
+                    let mut storage_views = Vec::new();
+                    let dispatched = self
+                        .create_storage_views(&brdf_lut, BRDF_LUT_FORMAT, &mut storage_views)
+                        .and_then(|_| {
+                            self.run_ibl_dispatches(
+                                &[IblDispatch {
+                                    pipeline: &pipeline,
+                                    layout,
+                                    source: None,
+                                    target: storage_views[0],
+                                    push_constants: Vec::new(),
+                                    group_count: [
+                                        group_count(BRDF_LUT_RESOLUTION),
+                                        group_count(BRDF_LUT_RESOLUTION),
+                                        1,
+                                    ],
+                                }],
+                                &[IblTarget {
+                                    image: brdf_lut.image,
+                                    mip_levels: 1,
+                                    layer_count: 1,
+                                }],
+                                vk::Sampler::null(),
+                            )
+                        });
+                    for view in storage_views {
+                        self.device.destroy_image_view(view, None);
+                    }
+                    match dispatched {
+                        Ok(()) => Ok(brdf_lut),
+                        Err(err) => {
+                            brdf_lut.destroy(&self.device, &mut self.allocator);
+                            Err(err)
+                        }
+                    }
+                })
+        };
+        self.destroy_compute_pipeline(pipeline);
+        let brdf_lut = result?;
+        let view = brdf_lut.view;
+        self.brdf_lut = Some(brdf_lut);
+        Ok(view)
+    }
+
+    /// ストレージとサンプリングに使うイメージを作る。`cube`なら6レイヤーのキューブマップ
+    unsafe fn create_ibl_image(
+        &mut self,
+        resolution: u32,
+        mip_levels: u32,
+        format: vk::Format,
+        cube: bool,
+    ) -> Result<IblImage> {
+        let (flags, layer_count, view_type) = if cube {
+            (
+                vk::ImageCreateFlags::CUBE_COMPATIBLE,
+                6,
+                vk::ImageViewType::CUBE,
+            )
+        } else {
+            (vk::ImageCreateFlags::empty(), 1, vk::ImageViewType::TYPE_2D)
+        };
+        let image_create_info = *vk::ImageCreateInfo::builder()
+            .flags(flags)
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: resolution,
+                height: resolution,
+                depth: 1,
+            })
+            .mip_levels(mip_levels)
+            .array_layers(layer_count)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let (image, allocation) = create_image(
+            &self.device,
+            &mut self.allocator,
+            &image_create_info,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            true,
+        )?;
+        let view_create_info = *vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(view_type)
+            .format(format)
+            .subresource_range(
+                *vk::ImageSubresourceRange::builder()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .level_count(mip_levels)
+                    .layer_count(layer_count),
+            );
+        match self.device.create_image_view(&view_create_info, None) {
+            Ok(view) => Ok(IblImage {
+                image,
+                view,
+                allocation,
+                resolution,
+                mip_levels,
+                cube,
+            }),
+            Err(err) => {
+                self.device.destroy_image(image, None);
+                self.allocator.free(&self.device, allocation);
+                Err(err.into())
+            }
+        }
+    }
+
+    /// ミップごとの書き込み用のビューを`views`に追加する
+    unsafe fn create_storage_views(
+        &self,
+        image: &IblImage,
+        format: vk::Format,
+        views: &mut Vec<vk::ImageView>,
+    ) -> Result<()> {
+        let (view_type, layer_count) = if image.cube {
+            (vk::ImageViewType::CUBE, 6)
+        } else {
+            (vk::ImageViewType::TYPE_2D, 1)
+        };
+        for mip in 0..image.mip_levels {
+            let view_create_info = *vk::ImageViewCreateInfo::builder()
+                .image(image.image)
+                .view_type(view_type)
+                .format(format)
+                .subresource_range(
+                    *vk::ImageSubresourceRange::builder()
+                        .aspect_mask(vk::ImageAspectFlags::COLOR)
+                        .base_mip_level(mip)
+                        .level_count(1)
+                        .layer_count(layer_count),
+                );
+            views.push(self.device.create_image_view(&view_create_info, None)?);
+        }
+        Ok(())
+    }
+
+    /// 一時的なデスクリプタプールでディスパッチを記録して提出し、完了を待つ
+    unsafe fn run_ibl_dispatches(
+        &self,
+        dispatches: &[IblDispatch],
+        targets: &[IblTarget],
+        sampler: vk::Sampler,
+    ) -> Result<()> {
+        let pool = create_pool(
+            &self.device,
+            dispatches.len() as u32,
+            &[
+                (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1.0),
+                (vk::DescriptorType::STORAGE_IMAGE, 1.0),
+            ],
+        )?;
+        let mut sets = Vec::with_capacity(dispatches.len());
+        for dispatch in dispatches.iter() {
+            let set = match allocate_set(&self.device, pool, dispatch.layout) {
+                Ok(set) => set,
+                Err(err) => {
+                    self.device.destroy_descriptor_pool(pool, None);
+                    return Err(err.into());
+                }
+            };
+            let storage_binding = match dispatch.source {
+                Some(_) => 1,
+                None => 0,
+            };
+            let mut writer = DescriptorWriter::new().image(
+                storage_binding,
+                vk::DescriptorType::STORAGE_IMAGE,
+                vk::DescriptorImageInfo {
+                    sampler: vk::Sampler::null(),
+                    image_view: dispatch.target,
+                    image_layout: vk::ImageLayout::GENERAL,
+                },
+            );
+            if let Some(source) = dispatch.source {
+                writer = writer.image(
+                    0,
+                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    vk::DescriptorImageInfo {
+                        sampler,
+                        image_view: source,
+                        image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    },
+                );
+            }
+            writer.update(&self.device, set);
+            sets.push(set);
+        }
+
+        let barriers = |old_layout, new_layout, src_access_mask, dst_access_mask| {
+            targets
+                .iter()
+                .map(|target| {
+                    *vk::ImageMemoryBarrier::builder()
+                        .old_layout(old_layout)
+                        .new_layout(new_layout)
+                        .src_access_mask(src_access_mask)
+                        .dst_access_mask(dst_access_mask)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .image(target.image)
+                        .subresource_range(vk::ImageSubresourceRange {
+                            aspect_mask: vk::ImageAspectFlags::COLOR,
+                            base_mip_level: 0,
+                            level_count: target.mip_levels,
+                            base_array_layer: 0,
+                            layer_count: target.layer_count,
+                        })
+                })
+                .collect::<Vec<_>>()
+        };
+        let to_general = barriers(
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::GENERAL,
+            vk::AccessFlags::empty(),
+            vk::AccessFlags::SHADER_WRITE,
+        );
+        let to_read_only = barriers(
+            vk::ImageLayout::GENERAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::AccessFlags::SHADER_WRITE,
+            vk::AccessFlags::SHADER_READ,
+        );
+        let result = self.submit_setup_commands(|device, command_buffer| {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &to_general,
+            );
+            for (dispatch, &set) in dispatches.iter().zip(sets.iter()) {
+                device.cmd_bind_pipeline(
+                    command_buffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    dispatch.pipeline.pipeline,
+                );
+                device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    dispatch.pipeline.layout,
+                    0,
+                    &[set],
+                    &[],
+                );
+                if !dispatch.push_constants.is_empty() {
+                    device.cmd_push_constants(
+                        command_buffer,
+                        dispatch.pipeline.layout,
+                        vk::ShaderStageFlags::COMPUTE,
+                        0,
+                        &dispatch.push_constants,
+                    );
+                }
+                let [x, y, z] = dispatch.group_count;
+                device.cmd_dispatch(command_buffer, x, y, z);
+            }
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &to_read_only,
+            );
+        });
+        self.device.destroy_descriptor_pool(pool, None);
+        result
+    }
+}
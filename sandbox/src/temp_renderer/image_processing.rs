@@ -0,0 +1,738 @@
+use super::error::{RendererError, Result};
+use super::texture::image_barrier;
+use super::texture_format::{texture_sample_type, TextureSampleType};
+use super::{
+    BufferAccess, BufferId, ComputePipeline, DescriptorBinding, DescriptorWriter, Renderer,
+    SamplerDesc, ShaderId, TextureId, TextureKind, TextureViewDesc, LUMINANCE_HISTOGRAM_BINS,
+};
+use ash::{vk, Device};
+
+/// 画像処理のシェーダーのワークグループの幅と高さ
+pub const IMAGE_PROCESSING_GROUP_SIZE: u32 = 8;
+
+/// 画像処理のコンピュートシェーダー
+///
+/// どれも8x8のワークグループで出力の1画素を1スレッドが処理し、`image_processing_set_layout`の
+/// デスクリプタセットを使う。入力は1つのミップだけを見るビューなので、`textureSize(src, 0)`が
+/// そのミップの大きさになる。出力のフォーマット修飾子は出力するテクスチャに合わせるか、
+/// `shaderStorageImageWriteWithoutFormat`を使って省略する。整数フォーマットには
+/// `usampler2D`と`uimage2D`などで書いたシェーダーを別に用意すること。
+///
+/// ```glsl
+/// layout(local_size_x = 8, local_size_y = 8) in;
+/// layout(set = 0, binding = 0) uniform sampler2D src;
+/// layout(set = 0, binding = 1, rgba16f) uniform writeonly image2D dst;
+/// layout(set = 0, binding = 2) buffer Histogram { uint bins[256]; };
+///
+/// // gaussian_blur: directionの方向に1次元のガウスぼかしをかける
+/// layout(push_constant) uniform Params { ivec2 direction; float sigma; int radius; };
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy), size = imageSize(dst);
+///     if (any(greaterThanEqual(p, size))) return;
+///     vec4 sum = vec4(0.0);
+///     float total = 0.0;
+///     for (int i = -radius; i <= radius; ++i) {
+///         float w = exp(-float(i * i) / (2.0 * sigma * sigma));
+///         sum += w * texelFetch(src, clamp(p + direction * i, ivec2(0), size - 1), 0);
+///         total += w;
+///     }
+///     imageStore(dst, p, sum / total);
+/// }
+///
+/// // dual_filter: pass 0で縮小、1で拡大する。radiusは入力の画素単位のずらし量
+/// layout(push_constant) uniform Params { uint pass; float radius; };
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy), size = imageSize(dst);
+///     if (any(greaterThanEqual(p, size))) return;
+///     vec2 uv = (vec2(p) + 0.5) / vec2(size);
+///     vec2 o = radius / vec2(textureSize(src, 0));
+///     vec4 diagonal = texture(src, uv + o) + texture(src, uv - o)
+///         + texture(src, uv + vec2(o.x, -o.y)) + texture(src, uv + vec2(-o.x, o.y));
+///     vec4 c;
+///     if (pass == 0) {
+///         c = (texture(src, uv) * 4.0 + diagonal) / 8.0;
+///     } else {
+///         c = (texture(src, uv + vec2(2.0 * o.x, 0.0)) + texture(src, uv - vec2(2.0 * o.x, 0.0))
+///             + texture(src, uv + vec2(0.0, 2.0 * o.y)) + texture(src, uv - vec2(0.0, 2.0 * o.y))
+///             + diagonal * 2.0) / 12.0;
+///     }
+///     imageStore(dst, p, c);
+/// }
+///
+/// // downsample: 2x2の画素の最小、最大、平均を1画素にする
+/// layout(push_constant) uniform Params { uint filter; };
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy);
+///     if (any(greaterThanEqual(p, imageSize(dst)))) return;
+///     ivec2 last = textureSize(src, 0) - 1;
+///     vec4 a = texelFetch(src, min(p * 2, last), 0);
+///     vec4 b = texelFetch(src, min(p * 2 + ivec2(1, 0), last), 0);
+///     vec4 c = texelFetch(src, min(p * 2 + ivec2(0, 1), last), 0);
+///     vec4 d = texelFetch(src, min(p * 2 + ivec2(1, 1), last), 0);
+///     vec4 r = filter == 0 ? min(min(a, b), min(c, d))
+///         : filter == 1 ? max(max(a, b), max(c, d))
+///         : (a + b + c + d) * 0.25;
+///     imageStore(dst, p, r);
+/// }
+///
+/// // histogram: 対数輝度のヒストグラムに加算する。ビン0は輝度がほぼ0の画素
+/// layout(push_constant) uniform Params { float min_log_luminance; float inverse_log_luminance_range; };
+/// shared uint local_bins[256];
+/// void main() {
+///     for (uint i = gl_LocalInvocationIndex; i < 256; i += 64) local_bins[i] = 0;
+///     barrier();
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy);
+///     if (all(lessThan(p, textureSize(src, 0)))) {
+///         float l = dot(texelFetch(src, p, 0).rgb, vec3(0.2126, 0.7152, 0.0722));
+///         uint bin = l < 1e-5 ? 0 : uint(clamp((log2(l) - min_log_luminance) * inverse_log_luminance_range, 0.0, 1.0) * 254.0 + 1.0);
+///         atomicAdd(local_bins[bin], 1);
+///     }
+///     barrier();
+///     for (uint i = gl_LocalInvocationIndex; i < 256; i += 64) atomicAdd(bins[i], local_bins[i]);
+/// }
+///
+/// // copy: 出力のフォーマットに変換してコピーする
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy);
+///     if (any(greaterThanEqual(p, imageSize(dst)))) return;
+///     imageStore(dst, p, texelFetch(src, p, 0));
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageProcessingShaders {
+    pub gaussian_blur: ShaderId,
+    pub dual_filter: ShaderId,
+    pub downsample: ShaderId,
+    pub histogram: ShaderId,
+    pub copy: ShaderId,
+}
+
+/// 画像処理の入出力にする2Dテクスチャの1つのミップ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TextureLevel {
+    pub texture: TextureId,
+    pub mip_level: u32,
+}
+
+impl TextureLevel {
+    pub fn new(texture: TextureId, mip_level: u32) -> Self {
+        Self { texture, mip_level }
+    }
+}
+
+impl From<TextureId> for TextureLevel {
+    fn from(texture: TextureId) -> Self {
+        Self::new(texture, 0)
+    }
+}
+
+/// デュアルフィルタのぼかしの段階
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DualFilterPass {
+    /// 縮小しながらぼかす
+    Downsample,
+    /// 拡大しながらぼかす
+    Upsample,
+}
+
+impl DualFilterPass {
+    /// シェーダーに渡す番号
+    pub fn index(self) -> u32 {
+        match self {
+            Self::Downsample => 0,
+            Self::Upsample => 1,
+        }
+    }
+}
+
+/// 2x2の画素をまとめる方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownsampleFilter {
+    Min,
+    Max,
+    Average,
+}
+
+impl DownsampleFilter {
+    /// シェーダーに渡す番号
+    pub fn index(self) -> u32 {
+        match self {
+            Self::Min => 0,
+            Self::Max => 1,
+            Self::Average => 2,
+        }
+    }
+}
+
+/// `enable_image_processing`で作る画像処理のパイプライン
+pub struct ImageProcessing {
+    pub gaussian_blur: ComputePipeline,
+    pub dual_filter: ComputePipeline,
+    pub downsample: ComputePipeline,
+    pub histogram: ComputePipeline,
+    pub copy: ComputePipeline,
+    /// 線形フィルタリングできるフォーマットの入力に使う
+    pub linear_sampler: vk::Sampler,
+    pub nearest_sampler: vk::Sampler,
+}
+
+impl ImageProcessing {
+    /// # Safety
+    /// `device`は作成に使ったデバイスで、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device) {
+        for pipeline in [
+            &self.gaussian_blur,
+            &self.dual_filter,
+            &self.downsample,
+            &self.histogram,
+            &self.copy,
+        ] {
+            pipeline.destroy(device);
+        }
+        device.destroy_sampler(self.linear_sampler, None);
+        device.destroy_sampler(self.nearest_sampler, None);
+    }
+}
+
+/// 処理の入出力にするミップのビューと大きさ
+struct ResolvedLevel {
+    image: vk::Image,
+    view: vk::ImageView,
+    /// `view`をこの処理のために作ったか
+    temporary: bool,
+    extent: vk::Extent2D,
+    format: vk::Format,
+    mip_level: u32,
+}
+
+/// `run_kernel`で実行するパイプラインの種類
+#[derive(Clone, Copy)]
+enum Kernel {
+    GaussianBlur,
+    DualFilter,
+    Downsample,
+    Histogram,
+    Copy,
+}
+
+impl Renderer {
+    /// 画像処理のデスクリプタセットレイアウト
+    ///
+    /// バインディング0が入力、1が出力のストレージイメージ、2がヒストグラムのストレージバッファ。
+    pub fn image_processing_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, vk::ShaderStageFlags::COMPUTE),
+            DescriptorBinding::new(
+                1,
+                vk::DescriptorType::STORAGE_IMAGE,
+                vk::ShaderStageFlags::COMPUTE,
+            ),
+            DescriptorBinding::storage_buffer(2, vk::ShaderStageFlags::COMPUTE),
+        ])
+    }
+
+    /// 画像処理のパイプラインを作成する。すでに有効な場合は作り直す
+    ///
+    /// 出力にするテクスチャは`TextureDesc::storage`を指定して作成する。
+    pub fn enable_image_processing(&mut self, shaders: ImageProcessingShaders) -> Result<()> {
+        let layout = self.image_processing_set_layout()?;
+        let kernels = [
+            (shaders.gaussian_blur, 16),
+            (shaders.dual_filter, 8),
+            (shaders.downsample, 4),
+            (shaders.histogram, 8),
+            (shaders.copy, 0),
+        ];
+        let mut pipelines = Vec::with_capacity(kernels.len());
+        for (shader, push_constant_size) in kernels {
+            match self.create_compute_pipeline(shader, "main", &[layout], push_constant_size) {
+                Ok(pipeline) => pipelines.push(pipeline),
+                Err(err) => {
+                    for pipeline in pipelines {
+                        self.destroy_compute_pipeline(pipeline);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        let samplers = self
+            .create_sampler(&SamplerDesc::clamp_to_edge())
+            .and_then(
+                |linear| match self.create_sampler(&SamplerDesc::nearest()) {
+                    Ok(nearest) => Ok((linear, nearest)),
+                    Err(err) => {
+                        unsafe { self.device.destroy_sampler(linear, None) };
+                        Err(err)
+                    }
+                },
+            );
+        let (linear_sampler, nearest_sampler) = match samplers {
+            Ok(samplers) => samplers,
+            Err(err) => {
+                for pipeline in pipelines {
+                    self.destroy_compute_pipeline(pipeline);
+                }
+                return Err(err);
+            }
+        };
+        let mut pipelines = pipelines.into_iter();
+        let mut next = || pipelines.next().unwrap();
+        let image_processing = ImageProcessing {
+            gaussian_blur: next(),
+            dual_filter: next(),
+            downsample: next(),
+            histogram: next(),
+            copy: next(),
+            linear_sampler,
+            nearest_sampler,
+        };
+        if let Some(old) = self.image_processing.replace(image_processing) {
+            self.destroy_image_processing(old);
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してから画像処理のパイプラインを破棄する
+    pub fn disable_image_processing(&mut self) {
+        if let Some(image_processing) = self.image_processing.take() {
+            self.destroy_image_processing(image_processing);
+        }
+    }
+
+    /// `luminance_histogram`の出力にする`LUMINANCE_HISTOGRAM_BINS`個の`u32`のバッファを作成する
+    pub fn create_histogram_buffer(&mut self) -> Result<BufferId> {
+        self.create_buffer(
+            LUMINANCE_HISTOGRAM_BINS as vk::DeviceSize * 4,
+            vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::TRANSFER_SRC
+                | vk::BufferUsageFlags::TRANSFER_DST,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )
+    }
+
+    /// `src`に標準偏差`sigma`画素のガウスぼかしをかけて`dst`に書き込む
+    ///
+    /// 横方向の結果を`temp`に書いてから縦方向にかける。3つとも同じ大きさで、`temp`と`dst`は
+    /// ストレージイメージとして使えること。以下の処理はどれもレンダーパスの外で呼び、
+    /// 出力はフラグメントシェーダーとコンピュートシェーダーからサンプリングできる状態になる。
+    pub fn gaussian_blur(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        src: impl Into<TextureLevel>,
+        temp: impl Into<TextureLevel>,
+        dst: impl Into<TextureLevel>,
+        sigma: f32,
+    ) -> Result<()> {
+        if sigma <= 0.0 {
+            return Err(RendererError::Validation(format!(
+                "blur sigma {} must be positive",
+                sigma
+            )));
+        }
+        let (src, temp, dst) = (src.into(), temp.into(), dst.into());
+        let radius = (sigma * 3.0).ceil() as i32;
+        let pass = |direction: [i32; 2]| -> Vec<u8> {
+            direction[0]
+                .to_ne_bytes()
+                .into_iter()
+                .chain(direction[1].to_ne_bytes())
+                .chain(sigma.to_ne_bytes())
+                .chain(radius.to_ne_bytes())
+                .collect()
+        };
+        self.validate_same_extent(src, temp)?;
+        self.validate_same_extent(src, dst)?;
+        self.run_kernel(
+            command_buffer,
+            Kernel::GaussianBlur,
+            src,
+            Some(temp),
+            None,
+            &pass([1, 0]),
+        )?;
+        self.run_kernel(
+            command_buffer,
+            Kernel::GaussianBlur,
+            temp,
+            Some(dst),
+            None,
+            &pass([0, 1]),
+        )
+    }
+
+    /// デュアルフィルタ(Kawase)のぼかしの1段階を`src`から`dst`に行う
+    ///
+    /// 縮小では`dst`を`src`の半分の大きさ、拡大では倍の大きさにして、縮小を何段か行った後に
+    /// 同じ数だけ拡大すると広い範囲を少ないサンプル数でぼかせる。`radius`は入力の画素単位の
+    /// サンプルのずらし量で、通常は1。
+    pub fn dual_filter(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        pass: DualFilterPass,
+        src: impl Into<TextureLevel>,
+        dst: impl Into<TextureLevel>,
+        radius: f32,
+    ) -> Result<()> {
+        let data: Vec<u8> = pass
+            .index()
+            .to_ne_bytes()
+            .into_iter()
+            .chain(radius.to_ne_bytes())
+            .collect();
+        self.run_kernel(
+            command_buffer,
+            Kernel::DualFilter,
+            src.into(),
+            Some(dst.into()),
+            None,
+            &data,
+        )
+    }
+
+    /// `src`の2x2の画素を`filter`でまとめて、半分の大きさ(切り上げ)の`dst`に書き込む
+    ///
+    /// 深度のミップチェインを作る場合などは`Min`や`Max`を使う。
+    pub fn downsample(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        src: impl Into<TextureLevel>,
+        dst: impl Into<TextureLevel>,
+        filter: DownsampleFilter,
+    ) -> Result<()> {
+        let (src, dst) = (src.into(), dst.into());
+        let src_extent = self.level_extent(src)?;
+        let dst_extent = self.level_extent(dst)?;
+        let expected = vk::Extent2D {
+            width: src_extent.width.div_ceil(2),
+            height: src_extent.height.div_ceil(2),
+        };
+        if dst_extent != expected {
+            return Err(RendererError::Validation(format!(
+                "downsampling {}x{} needs a {}x{} destination, got {}x{}",
+                src_extent.width,
+                src_extent.height,
+                expected.width,
+                expected.height,
+                dst_extent.width,
+                dst_extent.height
+            )));
+        }
+        self.run_kernel(
+            command_buffer,
+            Kernel::Downsample,
+            src,
+            Some(dst),
+            None,
+            &filter.index().to_ne_bytes(),
+        )
+    }
+
+    /// `src`の対数輝度のヒストグラムを`histogram`に書き込む
+    ///
+    /// `histogram`は`create_histogram_buffer`で作ったもので、記録の最初に0で埋める。
+    /// 輝度の対数(底2)が`min_log_luminance`から`max_log_luminance`の範囲をビン1から255に
+    /// 割り当てる。結果を読む前に`buffer_barrier`で`COMPUTE_SHADER_WRITE`からのバリアを記録する。
+    pub fn luminance_histogram(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        src: impl Into<TextureLevel>,
+        histogram: BufferId,
+        min_log_luminance: f32,
+        max_log_luminance: f32,
+    ) -> Result<()> {
+        if min_log_luminance >= max_log_luminance {
+            return Err(RendererError::Validation(format!(
+                "histogram range {}..{} is empty",
+                min_log_luminance, max_log_luminance
+            )));
+        }
+        let buffer = self.buffers.get(histogram).ok_or_else(|| {
+            RendererError::Validation(format!("buffer {:?} was destroyed", histogram))
+        })?;
+        let usage = vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST;
+        if !buffer.usage.contains(usage) || buffer.size < LUMINANCE_HISTOGRAM_BINS as u64 * 4 {
+            return Err(RendererError::Validation(format!(
+                "histogram buffer {:?} needs {:?} usage and {} bins",
+                histogram, usage, LUMINANCE_HISTOGRAM_BINS
+            )));
+        }
+        self.zero_buffer(command_buffer, histogram, 0, vk::WHOLE_SIZE)?;
+        self.buffer_barrier(
+            command_buffer,
+            histogram,
+            BufferAccess::TRANSFER_WRITE,
+            BufferAccess {
+                stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+                access: vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            },
+        )?;
+        let data: Vec<u8> = min_log_luminance
+            .to_ne_bytes()
+            .into_iter()
+            .chain((1.0 / (max_log_luminance - min_log_luminance)).to_ne_bytes())
+            .collect();
+        self.run_kernel(
+            command_buffer,
+            Kernel::Histogram,
+            src.into(),
+            None,
+            Some(histogram),
+            &data,
+        )
+    }
+
+    /// `src`を`dst`のフォーマットに変換してコピーする。2つは同じ大きさであること
+    pub fn copy_texture(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        src: impl Into<TextureLevel>,
+        dst: impl Into<TextureLevel>,
+    ) -> Result<()> {
+        let (src, dst) = (src.into(), dst.into());
+        self.validate_same_extent(src, dst)?;
+        self.run_kernel(command_buffer, Kernel::Copy, src, Some(dst), None, &[])
+    }
+
+    /// 画像処理の1回のディスパッチを記録する
+    ///
+    /// 出力のミップを`GENERAL`にしてから書き込み、終わったら`SHADER_READ_ONLY_OPTIMAL`に戻す。
+    /// ディスパッチの数は出力があればその大きさ、なければ入力の大きさで決める。
+    fn run_kernel(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        kernel: Kernel,
+        src: TextureLevel,
+        dst: Option<TextureLevel>,
+        buffer: Option<BufferId>,
+        push_constants: &[u8],
+    ) -> Result<()> {
+        if self.image_processing.is_none() {
+            return Err(RendererError::Validation(
+                "image processing is not enabled".to_owned(),
+            ));
+        }
+        if dst.is_some_and(|dst| dst == src) {
+            return Err(RendererError::Validation(format!(
+                "mip {} of texture {:?} cannot be both the source and the destination",
+                src.mip_level, src.texture
+            )));
+        }
+        let layout = self.image_processing_set_layout()?;
+        let descriptor_set = self.allocate_transient_descriptor_set(layout)?;
+
+        let src = self.resolve_level(src, false)?;
+        let dst = match dst.map(|dst| self.resolve_level(dst, true)).transpose() {
+            Ok(dst) => dst,
+            Err(err) => {
+                self.release_level(src);
+                return Err(err);
+            }
+        };
+        let result = self.record_kernel(
+            command_buffer,
+            kernel,
+            descriptor_set,
+            &src,
+            dst.as_ref(),
+            buffer,
+            push_constants,
+        );
+        self.release_level(src);
+        if let Some(dst) = dst {
+            self.release_level(dst);
+        }
+        result
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn record_kernel(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        kernel: Kernel,
+        descriptor_set: vk::DescriptorSet,
+        src: &ResolvedLevel,
+        dst: Option<&ResolvedLevel>,
+        buffer: Option<BufferId>,
+        push_constants: &[u8],
+    ) -> Result<()> {
+        let image_processing = self.image_processing.as_ref().unwrap();
+        let filterable = texture_sample_type(src.format) == TextureSampleType::Float
+            && unsafe {
+                self.instance
+                    .get_physical_device_format_properties(self.pdevice, src.format)
+            }
+            .optimal_tiling_features
+            .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR);
+        let mut writer = DescriptorWriter::new().image(
+            0,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::DescriptorImageInfo {
+                sampler: if filterable {
+                    image_processing.linear_sampler
+                } else {
+                    image_processing.nearest_sampler
+                },
+                image_view: src.view,
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            },
+        );
+        if let Some(dst) = dst {
+            writer = writer.image(
+                1,
+                vk::DescriptorType::STORAGE_IMAGE,
+                vk::DescriptorImageInfo {
+                    sampler: vk::Sampler::null(),
+                    image_view: dst.view,
+                    image_layout: vk::ImageLayout::GENERAL,
+                },
+            );
+        }
+        if let Some(id) = buffer {
+            let buffer = self.buffers.get(id).ok_or_else(|| {
+                RendererError::Validation(format!("buffer {:?} was destroyed", id))
+            })?;
+            writer = writer.buffer(2, vk::DescriptorType::STORAGE_BUFFER, buffer);
+        }
+        unsafe { writer.update(&self.device, descriptor_set) };
+
+        let shader_stages =
+            vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER;
+        if let Some(dst) = dst {
+            // 以前の読み込みを待ってから書き込む
+            unsafe {
+                image_barrier(
+                    &self.device,
+                    command_buffer,
+                    dst.image,
+                    vk::ImageAspectFlags::COLOR,
+                    dst.mip_level,
+                    1,
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    vk::ImageLayout::GENERAL,
+                    vk::AccessFlags::empty(),
+                    vk::AccessFlags::SHADER_WRITE,
+                    shader_stages,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                );
+            }
+        }
+        let pipeline = match kernel {
+            Kernel::GaussianBlur => &image_processing.gaussian_blur,
+            Kernel::DualFilter => &image_processing.dual_filter,
+            Kernel::Downsample => &image_processing.downsample,
+            Kernel::Histogram => &image_processing.histogram,
+            Kernel::Copy => &image_processing.copy,
+        };
+        let extent = dst.map_or(src.extent, |dst| dst.extent);
+        self.dispatch(
+            command_buffer,
+            pipeline,
+            &[descriptor_set],
+            push_constants,
+            [
+                extent.width.div_ceil(IMAGE_PROCESSING_GROUP_SIZE),
+                extent.height.div_ceil(IMAGE_PROCESSING_GROUP_SIZE),
+                1,
+            ],
+        )?;
+        if let Some(dst) = dst {
+            unsafe {
+                image_barrier(
+                    &self.device,
+                    command_buffer,
+                    dst.image,
+                    vk::ImageAspectFlags::COLOR,
+                    dst.mip_level,
+                    1,
+                    vk::ImageLayout::GENERAL,
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    vk::AccessFlags::SHADER_WRITE,
+                    vk::AccessFlags::SHADER_READ,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    shader_stages,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// `level`を1つのミップだけを見るビューにする。ミップが1つなら元のビューを使う
+    fn resolve_level(&self, level: TextureLevel, storage: bool) -> Result<ResolvedLevel> {
+        let texture = self.textures.get(level.texture).ok_or_else(|| {
+            RendererError::Validation(format!("texture {:?} was destroyed", level.texture))
+        })?;
+        if texture.kind != TextureKind::Texture2D
+            || level.mip_level >= texture.mip_levels
+            || texture_sample_type(texture.format) == TextureSampleType::Depth
+        {
+            return Err(RendererError::Validation(format!(
+                "image processing needs a mip level of a color 2D texture, got mip {} of a {:?} {:?} texture",
+                level.mip_level, texture.format, texture.kind
+            )));
+        }
+        if storage && !texture.usage.contains(vk::ImageUsageFlags::STORAGE) {
+            return Err(RendererError::Validation(format!(
+                "texture {:?} must be created with TextureDesc::storage to be written",
+                level.texture
+            )));
+        }
+        let (view, temporary) = if texture.mip_levels == 1 {
+            (texture.view, false)
+        } else {
+            let view = self.create_texture_view(
+                level.texture,
+                &TextureViewDesc {
+                    view_type: vk::ImageViewType::TYPE_2D,
+                    base_mip_level: level.mip_level,
+                    mip_level_count: 1,
+                    base_layer: 0,
+                    layer_count: 1,
+                },
+            )?;
+            (view, true)
+        };
+        Ok(ResolvedLevel {
+            image: texture.image,
+            view,
+            temporary,
+            extent: mip_extent(texture.extent, level.mip_level),
+            format: texture.format,
+            mip_level: level.mip_level,
+        })
+    }
+
+    fn release_level(&mut self, level: ResolvedLevel) {
+        if level.temporary {
+            self.destroy_texture_view(level.view);
+        }
+    }
+
+    fn level_extent(&self, level: TextureLevel) -> Result<vk::Extent2D> {
+        let texture = self.textures.get(level.texture).ok_or_else(|| {
+            RendererError::Validation(format!("texture {:?} was destroyed", level.texture))
+        })?;
+        Ok(mip_extent(texture.extent, level.mip_level))
+    }
+
+    fn validate_same_extent(&self, a: TextureLevel, b: TextureLevel) -> Result<()> {
+        let (a, b) = (self.level_extent(a)?, self.level_extent(b)?);
+        if a != b {
+            return Err(RendererError::Validation(format!(
+                "image sizes {}x{} and {}x{} must match",
+                a.width, a.height, b.width, b.height
+            )));
+        }
+        Ok(())
+    }
+
+    fn destroy_image_processing(&mut self, image_processing: ImageProcessing) {
+        self.destroy_deferred(move |device, _| unsafe { image_processing.destroy(device) });
+    }
+}
+
+fn mip_extent(extent: vk::Extent2D, mip_level: u32) -> vk::Extent2D {
+    vk::Extent2D {
+        width: (extent.width >> mip_level).max(1),
+        height: (extent.height >> mip_level).max(1),
+    }
+}
@@ -0,0 +1,182 @@
+use super::error::{RendererError, Result};
+use super::{BufferId, GraphicsPipeline, Mat4, MeshId, PipelineBuilder, Renderer, VertexLayout};
+use ash::vk;
+
+/// インスタンスバッファの最初の大きさ
+const INITIAL_INSTANCE_BUFFER_SIZE: vk::DeviceSize = 64 * 1024;
+/// 1回の描画で使うインスタンスデータの先頭のアラインメント
+const INSTANCE_ALIGNMENT: vk::DeviceSize = 16;
+
+/// 変換行列だけのインスタンスデータ
+///
+/// 独自の属性を持たせる場合は、先頭に変換行列を置いた`#[repr(C)]`の構造体を作り、
+/// `InstanceTransform::layout`に属性を足したレイアウトを使う。
+///
+/// ```glsl
+/// layout(location = 0) in vec3 position;
+/// layout(location = 1) in vec3 normal;
+/// layout(location = 2) in vec2 uv;
+/// layout(location = 3) in mat4 model; // 3から6を使う
+/// layout(location = 7) in vec4 color; // 独自の属性
+/// ```
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InstanceTransform {
+    pub model: Mat4,
+}
+
+impl InstanceTransform {
+    /// 1インスタンスが`stride`バイトで、先頭の変換行列を`first_location`から4つの
+    /// `vec4`として読むレイアウト
+    pub fn layout(stride: u32, first_location: u32) -> VertexLayout {
+        (0..4).fold(VertexLayout::new(stride), |layout, column| {
+            layout.attribute(
+                first_location + column,
+                vk::Format::R32G32B32A32_SFLOAT,
+                column * 16,
+            )
+        })
+    }
+}
+
+/// フレームコンテキスト1つ分の、`draw_mesh_instanced`のインスタンスデータを詰めるバッファ
+///
+/// 足りなくなると倍の大きさで作り直す。フレームをまたいで使い回し、記録を始めるたびに先頭から詰める。
+#[derive(Debug, Default)]
+pub struct InstanceBuffer {
+    /// HOST_VISIBLEな頂点バッファ。最初の描画まで作らない
+    pub buffer: Option<BufferId>,
+    pub capacity: vk::DeviceSize,
+    /// 次に書き込む位置
+    pub offset: vk::DeviceSize,
+    /// `offset`を進めたフレームの番号
+    frame: u64,
+}
+
+impl PipelineBuilder {
+    /// `layout`をインスタンスごとに進む頂点入力のバインディング`binding`として追加する
+    pub fn instance_layout(self, binding: u32, layout: &VertexLayout) -> Self {
+        let builder = self.vertex_binding(binding, layout.stride, vk::VertexInputRate::INSTANCE);
+        layout
+            .attributes
+            .iter()
+            .fold(builder, |builder, attribute| {
+                builder.vertex_attribute(
+                    attribute.location,
+                    binding,
+                    attribute.format,
+                    attribute.offset,
+                )
+            })
+    }
+}
+
+impl Renderer {
+    /// `instances`を記録中のフレームのインスタンスバッファに書き込み、メッシュを1回のドローコールで
+    /// インスタンスの数だけ描く
+    ///
+    /// メッシュの頂点はバインディング0、インスタンスデータはバインディング1に割り当てるので、
+    /// パイプラインは`instance_layout(1, ..)`で作る。他の引数は`draw_mesh`と同じ。
+    pub fn draw_mesh_instanced<T: Copy>(
+        &mut self,
+        mesh: MeshId,
+        instances: &[T],
+        pipeline: &GraphicsPipeline,
+        descriptor_sets: &[vk::DescriptorSet],
+        push_constants: &[u8],
+    ) -> Result<()> {
+        if instances.is_empty() {
+            return Ok(());
+        }
+        let mesh = self.live_mesh(mesh)?;
+        let command_buffer = self.bind_mesh(mesh, pipeline, descriptor_sets, push_constants)?;
+        let indexed = mesh.index_buffer.is_some();
+        let submeshes = mesh.submeshes.clone();
+        let (buffer, offset) = self.write_instances(instances)?;
+        let instance_count = instances.len() as u32;
+        unsafe {
+            self.device
+                .cmd_bind_vertex_buffers(command_buffer, 1, &[buffer], &[offset]);
+            for submesh in submeshes.iter() {
+                let count = submesh.range.end - submesh.range.start;
+                if indexed {
+                    self.device.cmd_draw_indexed(
+                        command_buffer,
+                        count,
+                        instance_count,
+                        submesh.range.start,
+                        submesh.vertex_offset,
+                        0,
+                    );
+                } else {
+                    self.device.cmd_draw(
+                        command_buffer,
+                        count,
+                        instance_count,
+                        submesh.range.start,
+                        0,
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 記録中のフレームのインスタンスバッファに`instances`を詰め、バッファと書き込んだ位置を返す
+    fn write_instances<T: Copy>(
+        &mut self,
+        instances: &[T],
+    ) -> Result<(vk::Buffer, vk::DeviceSize)> {
+        let size = std::mem::size_of_val(instances) as vk::DeviceSize;
+        let frame_count = self.frame_count;
+        let current = &mut self.instance_buffers[self.current_frame];
+        if current.frame != frame_count {
+            current.frame = frame_count;
+            current.offset = 0;
+        }
+        let offset = current.offset.next_multiple_of(INSTANCE_ALIGNMENT);
+        if current.buffer.is_none() || offset + size > current.capacity {
+            self.grow_instance_buffer(size)?;
+        }
+        let current = &mut self.instance_buffers[self.current_frame];
+        let offset = current.offset.next_multiple_of(INSTANCE_ALIGNMENT);
+        current.offset = offset + size;
+        let buffer = self.buffers.get(current.buffer.unwrap()).unwrap();
+        let ptr = buffer.allocation.mapped_ptr.ok_or_else(|| {
+            RendererError::Validation("instance buffer memory is not host visible".to_owned())
+        })?;
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                instances.as_ptr() as *const u8,
+                ptr.add(offset as usize),
+                size as usize,
+            );
+        }
+        Ok((buffer.buffer, offset))
+    }
+
+    /// 記録中のフレームのインスタンスバッファを`required`バイト以上にする
+    ///
+    /// このフレームで先に描いたインスタンスは古いバッファを参照しているので、古いバッファは
+    /// 使用中のフレームが完了してから破棄し、新しいバッファは先頭から詰める。
+    fn grow_instance_buffer(&mut self, required: vk::DeviceSize) -> Result<()> {
+        let current = &self.instance_buffers[self.current_frame];
+        let capacity = required
+            .max(current.capacity * 2)
+            .max(INITIAL_INSTANCE_BUFFER_SIZE)
+            .next_power_of_two();
+        let buffer = self.create_buffer(
+            capacity,
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+        let current = &mut self.instance_buffers[self.current_frame];
+        let old = current.buffer.replace(buffer);
+        current.capacity = capacity;
+        current.offset = 0;
+        if let Some(old) = old {
+            self.destroy_buffer(old)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,425 @@
+use super::descriptor::{allocate_set, create_pool};
+use super::error::{RendererError, Result};
+use super::renderer::create_depth_sampling_view;
+use super::{
+    BufferAccess, BufferId, Camera, ComputePipeline, DescriptorBinding, DescriptorWriter, Mat4,
+    PerFrame, Renderer, SamplerDesc, ShaderId,
+};
+use ash::{vk, Device};
+
+/// 1回の`test_flare_visibility`で調べられる光源の数
+pub const MAX_FLARE_SOURCES: usize = 64;
+
+/// フレアの光源が深度バッファに隠れているかを調べるシェーダー
+///
+/// 1スレッドが1つの光源を受け持ち、画面上の光源の周りの円から深度を読んで見えている割合を書く。
+/// セット0は`lens_flare_set_layout`、プッシュ定数は`LensFlarePushConstants`。
+///
+/// ```glsl
+/// layout(local_size_x = 64) in;
+/// layout(set = 0, binding = 0) uniform sampler2D depth;
+/// struct FlareSample { vec4 position; float radius; float visibility; vec2 padding; };
+/// layout(set = 0, binding = 1) buffer Samples { FlareSample samples[]; };
+/// layout(push_constant) uniform Params {
+///     mat4 view_projection; vec2 size; uint source_count; uint reversed_z;
+/// };
+/// void main() {
+///     uint i = gl_GlobalInvocationID.x;
+///     if (i >= source_count) return;
+///     FlareSample s = samples[i];
+///     vec4 clip = view_projection * s.position;
+///     if (clip.w <= 0.0) { samples[i].visibility = 0.0; return; }
+///     vec3 ndc = clip.xyz / clip.w;
+///     // wが0の方向の光源は無限遠にあるので、何も描かれていない画素だけを見えるとする
+///     float source_depth = s.position.w == 0.0 ? (reversed_z != 0 ? 0.0 : 1.0) : ndc.z;
+///     vec2 center = (ndc.xy * 0.5 + 0.5) * size;
+///     float visible = 0.0;
+///     for (int k = 0; k < 32; ++k) {
+///         float r = sqrt((float(k) + 0.5) / 32.0) * s.radius, phi = float(k) * 2.399963;
+///         vec2 p = center + r * vec2(cos(phi), sin(phi));
+///         // 画面の外のサンプルは隠れているとみなす
+///         if (any(lessThan(p, vec2(0.0))) || any(greaterThanEqual(p, size))) continue;
+///         float d = texelFetch(depth, ivec2(p), 0).r;
+///         visible += (reversed_z != 0 ? d <= source_depth : d >= source_depth) ? 1.0 : 0.0;
+///     }
+///     samples[i].visibility = visible / 32.0;
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LensFlareShaders {
+    pub visibility: ShaderId,
+}
+
+/// 見え方を調べるフレアの光源
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlareSource {
+    /// ワールド空間の位置。`directional`なら光源へ向かう方向
+    pub position: [f32; 3],
+    /// 太陽のように無限遠にある光源
+    pub directional: bool,
+    /// 深度を読む円の半径。ピクセル単位
+    pub radius: f32,
+}
+
+impl FlareSource {
+    /// 太陽の光源。`direction`は地面から太陽へ向かう方向
+    pub fn sun(direction: [f32; 3], radius: f32) -> Self {
+        Self {
+            position: direction,
+            directional: true,
+            radius,
+        }
+    }
+}
+
+/// フレームごとのフレアの設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LensFlareSettings {
+    /// 見え方が変わったときの追従の速さ。大きいほど素早くフェードする
+    pub fade_speed: f32,
+}
+
+impl Default for LensFlareSettings {
+    fn default() -> Self {
+        Self { fade_speed: 8.0 }
+    }
+}
+
+/// `lens_flare_set_layout`のストレージバッファの要素。std430と同じ配置
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FlareSample {
+    position: [f32; 4],
+    radius: f32,
+    visibility: f32,
+    padding: [f32; 2],
+}
+
+/// 見え方を調べるシェーダーに渡すプッシュ定数
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LensFlarePushConstants {
+    pub view_projection: Mat4,
+    /// 深度バッファのピクセル数
+    pub size: [f32; 2],
+    pub source_count: u32,
+    pub reversed_z: u32,
+}
+
+impl LensFlarePushConstants {
+    fn bytes(&self) -> Vec<u8> {
+        self.view_projection
+            .iter()
+            .flatten()
+            .chain(self.size.iter())
+            .flat_map(|value| value.to_ne_bytes())
+            .chain(
+                [self.source_count, self.reversed_z]
+                    .iter()
+                    .flat_map(|value| value.to_ne_bytes()),
+            )
+            .collect()
+    }
+}
+
+/// `enable_lens_flare`で作るフレアの光源の見え方を調べるリソース
+///
+/// サンプルのバッファは`Renderer`のバッファとして別に破棄される。
+pub struct LensFlare {
+    pub pipeline: ComputePipeline,
+    pub sampler: vk::Sampler,
+    /// フレームコンテキストごとの`FlareSample`の配列。HOST_VISIBLEで、結果をそのまま読む
+    pub sample_buffers: PerFrame<BufferId>,
+    pub depth_view: vk::ImageView,
+    pub descriptor_pool: vk::DescriptorPool,
+    /// フレームコンテキストごとの`lens_flare_set_layout`のセット
+    pub sets: Vec<vk::DescriptorSet>,
+    /// フレームコンテキストごとの、結果を待っている光源の数
+    pending: Vec<usize>,
+    /// フェードした見え方。`test_flare_visibility`に渡した光源の順
+    visibility: Vec<f32>,
+}
+
+impl LensFlare {
+    /// `index`番目の光源の見え方。0で完全に隠れ、1で全て見えている
+    pub fn visibility(&self, index: usize) -> f32 {
+        self.visibility.get(index).copied().unwrap_or(0.0)
+    }
+
+    /// # Safety
+    /// `device`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device) {
+        self.pipeline.destroy(device);
+        device.destroy_sampler(self.sampler, None);
+        destroy_lens_flare_sets(device, self.descriptor_pool, self.depth_view);
+    }
+}
+
+unsafe fn destroy_lens_flare_sets(
+    device: &Device,
+    descriptor_pool: vk::DescriptorPool,
+    depth_view: vk::ImageView,
+) {
+    device.destroy_descriptor_pool(descriptor_pool, None);
+    device.destroy_image_view(depth_view, None);
+}
+
+impl Renderer {
+    /// 見え方を調べるデスクリプタセットレイアウト。バインディング0が深度、1がサンプルのバッファ
+    pub fn lens_flare_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        let stage = vk::ShaderStageFlags::COMPUTE;
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, stage),
+            DescriptorBinding::storage_buffer(1, stage),
+        ])
+    }
+
+    /// 深度バッファからフレアの光源の見え方を調べるパスを有効にする
+    ///
+    /// マルチサンプルの深度は`sampler2D`で読めないので、MSAAとは組み合わせられない。
+    pub fn enable_lens_flare(&mut self, shaders: LensFlareShaders) -> Result<()> {
+        if self.msaa_samples != vk::SampleCountFlags::TYPE_1 {
+            return Err(RendererError::Validation(
+                "lens flare visibility cannot be combined with MSAA".to_owned(),
+            ));
+        }
+        let layout = self.lens_flare_set_layout()?;
+        let pipeline = self.create_compute_pipeline(
+            shaders.visibility,
+            "main",
+            &[layout],
+            std::mem::size_of::<LensFlarePushConstants>() as u32,
+        )?;
+        let resources = self
+            .create_sampler(&SamplerDesc::nearest())
+            .and_then(|sampler| {
+                let sample_buffers = match self.create_per_frame_buffers(
+                    (std::mem::size_of::<FlareSample>() * MAX_FLARE_SOURCES) as vk::DeviceSize,
+                    vk::BufferUsageFlags::STORAGE_BUFFER,
+                ) {
+                    Ok(buffers) => buffers,
+                    Err(err) => {
+                        unsafe { self.device.destroy_sampler(sampler, None) };
+                        return Err(err);
+                    }
+                };
+                let buffers: Vec<_> = sample_buffers.iter().copied().collect();
+                match unsafe { self.create_lens_flare_sets(layout, sampler, &buffers) } {
+                    Ok((depth_view, descriptor_pool, sets)) => {
+                        Ok((sampler, sample_buffers, depth_view, descriptor_pool, sets))
+                    }
+                    Err(err) => {
+                        unsafe { self.device.destroy_sampler(sampler, None) };
+                        self.destroy_per_frame_buffers(sample_buffers)?;
+                        Err(err)
+                    }
+                }
+            });
+        let (sampler, sample_buffers, depth_view, descriptor_pool, sets) = match resources {
+            Ok(resources) => resources,
+            Err(err) => {
+                self.destroy_compute_pipeline(pipeline);
+                return Err(err);
+            }
+        };
+        let lens_flare = LensFlare {
+            pipeline,
+            sampler,
+            sample_buffers,
+            depth_view,
+            descriptor_pool,
+            sets,
+            pending: vec![0; self.frames.len()],
+            visibility: Vec::new(),
+        };
+        if let Some(old) = self.lens_flare.replace(lens_flare) {
+            self.destroy_lens_flare(old)?;
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからフレアのリソースを破棄する
+    pub fn disable_lens_flare(&mut self) -> Result<()> {
+        match self.lens_flare.take() {
+            Some(lens_flare) => self.destroy_lens_flare(lens_flare),
+            None => Ok(()),
+        }
+    }
+
+    pub fn lens_flare(&self) -> Option<&LensFlare> {
+        self.lens_flare.as_ref()
+    }
+
+    /// `sources`が`camera`の深度バッファに隠れているかを調べ、見え方を`delta_time`秒分フェードさせる
+    ///
+    /// 深度を描き終えた後、レンダーパスの外で呼ぶ。GPUの結果はこのフレームコンテキストが
+    /// 次に使われるときに読むので、フレームコンテキストの数だけ遅れて反映される。フェードに
+    /// よって壁の向こうのフレアが急に現れたり消えたりしないので、遅れは目立たない。
+    /// 結果は`LensFlare::visibility`で`sources`の順に読む。
+    pub fn test_flare_visibility(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        camera: &Camera,
+        sources: &[FlareSource],
+        settings: &LensFlareSettings,
+        delta_time: f32,
+    ) -> Result<()> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "flare visibility can only be tested between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        if sources.len() > MAX_FLARE_SOURCES {
+            return Err(RendererError::Validation(format!(
+                "{} flare sources exceed the limit of {}",
+                sources.len(),
+                MAX_FLARE_SOURCES
+            )));
+        }
+        let lens_flare = self
+            .lens_flare
+            .as_ref()
+            .ok_or_else(|| RendererError::Validation("lens flare is not enabled".to_owned()))?;
+        let buffer = self
+            .buffers
+            .get(*self.per_frame(&lens_flare.sample_buffers))
+            .unwrap();
+        let ptr = buffer.allocation.mapped_ptr.ok_or_else(|| {
+            RendererError::Validation("flare sample memory is not host visible".to_owned())
+        })?;
+        // begin_frameでフェンスを待っているので、前回このフレームコンテキストで書いた結果を読める
+        let samples =
+            unsafe { std::slice::from_raw_parts_mut(ptr as *mut FlareSample, MAX_FLARE_SOURCES) };
+        let pending = lens_flare.pending[self.current_frame];
+        let targets: Vec<f32> = (0..sources.len())
+            .map(|i| {
+                if i < pending {
+                    samples[i].visibility
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+        for (sample, source) in samples.iter_mut().zip(sources) {
+            let [x, y, z] = source.position;
+            *sample = FlareSample {
+                position: [x, y, z, if source.directional { 0.0 } else { 1.0 }],
+                radius: source.radius,
+                visibility: 0.0,
+                padding: [0.0; 2],
+            };
+        }
+
+        let push_constants = LensFlarePushConstants {
+            view_projection: camera.view_projection_matrix(),
+            size: [
+                self.surface_resolution.width as f32,
+                self.surface_resolution.height as f32,
+            ],
+            source_count: sources.len() as u32,
+            reversed_z: camera.reversed_z as u32,
+        };
+        if !sources.is_empty() {
+            unsafe { self.begin_screen_pass(command_buffer, &[], Some(self.depth_image)) };
+            self.dispatch(
+                command_buffer,
+                &lens_flare.pipeline,
+                &[lens_flare.sets[self.current_frame]],
+                &push_constants.bytes(),
+                [1, 1, 1],
+            )?;
+            unsafe { self.restore_depth_attachment(command_buffer, &[]) };
+            self.buffer_barrier(
+                command_buffer,
+                *self.per_frame(&lens_flare.sample_buffers),
+                BufferAccess::COMPUTE_SHADER_WRITE,
+                BufferAccess::HOST_READ,
+            )?;
+        }
+
+        let current_frame = self.current_frame;
+        let lens_flare = self.lens_flare.as_mut().unwrap();
+        lens_flare.pending[current_frame] = sources.len();
+        let t = 1.0 - (-delta_time * settings.fade_speed.max(0.0)).exp();
+        lens_flare.visibility.resize(sources.len(), 0.0);
+        for (visibility, target) in lens_flare.visibility.iter_mut().zip(targets) {
+            *visibility += (target - *visibility) * t;
+        }
+        Ok(())
+    }
+
+    /// 深度バッファに合わせてデスクリプタセットを作り直す
+    ///
+    /// 古いセットは使用中のフレームが完了してから破棄する。
+    pub(crate) fn recreate_lens_flare_sets(&mut self) -> Result<()> {
+        let Some((sampler, sample_buffers)) = self.lens_flare.as_ref().map(|lens_flare| {
+            let buffers: Vec<_> = lens_flare.sample_buffers.iter().copied().collect();
+            (lens_flare.sampler, buffers)
+        }) else {
+            return Ok(());
+        };
+        let layout = self.lens_flare_set_layout()?;
+        let (depth_view, descriptor_pool, sets) =
+            unsafe { self.create_lens_flare_sets(layout, sampler, &sample_buffers)? };
+        let lens_flare = self.lens_flare.as_mut().unwrap();
+        let old_pool = std::mem::replace(&mut lens_flare.descriptor_pool, descriptor_pool);
+        let old_view = std::mem::replace(&mut lens_flare.depth_view, depth_view);
+        lens_flare.sets = sets;
+        self.destroy_deferred(move |device, _| unsafe {
+            destroy_lens_flare_sets(device, old_pool, old_view)
+        });
+        Ok(())
+    }
+
+    fn destroy_lens_flare(&mut self, lens_flare: LensFlare) -> Result<()> {
+        for &id in lens_flare.sample_buffers.iter() {
+            self.destroy_buffer(id)?;
+        }
+        self.destroy_deferred(move |device, _| unsafe { lens_flare.destroy(device) });
+        Ok(())
+    }
+
+    unsafe fn create_lens_flare_sets(
+        &self,
+        layout: vk::DescriptorSetLayout,
+        sampler: vk::Sampler,
+        sample_buffers: &[BufferId],
+    ) -> Result<(vk::ImageView, vk::DescriptorPool, Vec<vk::DescriptorSet>)> {
+        let depth_view =
+            create_depth_sampling_view(&self.device, self.depth_image, self.depth_format)?;
+        let descriptor_pool = match create_pool(
+            &self.device,
+            sample_buffers.len() as u32,
+            &[
+                (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1.0),
+                (vk::DescriptorType::STORAGE_BUFFER, 1.0),
+            ],
+        ) {
+            Ok(pool) => pool,
+            Err(err) => {
+                self.device.destroy_image_view(depth_view, None);
+                return Err(err);
+            }
+        };
+        let mut sets = Vec::with_capacity(sample_buffers.len());
+        for &id in sample_buffers {
+            let set = match allocate_set(&self.device, descriptor_pool, layout) {
+                Ok(set) => set,
+                Err(err) => {
+                    destroy_lens_flare_sets(&self.device, descriptor_pool, depth_view);
+                    return Err(err.into());
+                }
+            };
+            DescriptorWriter::new()
+                .depth_image(0, depth_view, sampler)
+                .buffer(
+                    1,
+                    vk::DescriptorType::STORAGE_BUFFER,
+                    self.buffers.get(id).unwrap(),
+                )
+                .update(&self.device, set);
+            sets.push(set);
+        }
+        Ok((depth_view, descriptor_pool, sets))
+    }
+}
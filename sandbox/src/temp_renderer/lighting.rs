@@ -0,0 +1,206 @@
+use super::buffer::write_buffer;
+use super::error::{RendererError, Result};
+use super::{
+    BufferId, DescriptorBinding, DescriptorWriter, Handle, Light, LightKind, Renderer, Scene,
+};
+use ash::vk;
+
+/// 位置と向きを決めたライト
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LightInstance {
+    pub light: Light,
+    pub position: [f32; 3],
+    /// 光の進む方向。ディレクショナルライトとスポットライトで使う
+    pub direction: [f32; 3],
+}
+
+pub type LightId = Handle<LightInstance>;
+
+/// ライトのユニフォームバッファの1要素。std140と同じ配置
+///
+/// ```glsl
+/// struct Light {
+///     vec4 position_range;  // xyz: 位置、w: 範囲(0なら無制限)
+///     vec4 direction_type;  // xyz: 向き、w: 0=ディレクショナル、1=ポイント、2=スポット
+///     vec4 color_intensity; // rgb: リニアの色、a: 強度
+///     vec4 spot_cos;        // x: 内側の角度のcos、y: 外側の角度のcos、z: ポイントシャドウの番号(-1なら影なし)
+///                           // w: IESのプロファイルの番号(-1ならなし)
+/// };
+/// layout(set = 1, binding = 0) uniform Lights {
+///     uvec4 light_count; // xだけを使う
+///     Light lights[MAX_LIGHTS]; // RendererConfig::max_lightsと同じ値
+/// };
+/// ```
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct GpuLight {
+    pub position_range: [f32; 4],
+    pub direction_type: [f32; 4],
+    pub color_intensity: [f32; 4],
+    pub spot_cos: [f32; 4],
+}
+
+impl From<&LightInstance> for GpuLight {
+    fn from(instance: &LightInstance) -> Self {
+        let light = &instance.light;
+        let (kind, range, spot_cos) = match light.kind {
+            LightKind::Directional => (0.0, None, [0.0; 4]),
+            LightKind::Point { range } => (1.0, range, [0.0; 4]),
+            LightKind::Spot {
+                range,
+                inner_cone_angle,
+                outer_cone_angle,
+            } => (
+                2.0,
+                range,
+                [inner_cone_angle.cos(), outer_cone_angle.cos(), 0.0, 0.0],
+            ),
+        };
+        let spot_cos = [
+            spot_cos[0],
+            spot_cos[1],
+            spot_cos[2],
+            light.ies_profile.map_or(-1.0, |index| index as f32),
+        ];
+        let [x, y, z] = instance.position;
+        let [dx, dy, dz] = instance.direction;
+        let [r, g, b] = light.color;
+        Self {
+            position_range: [x, y, z, range.unwrap_or(0.0)],
+            direction_type: [dx, dy, dz, kind],
+            color_intensity: [r, g, b, light.intensity],
+            spot_cos,
+        }
+    }
+}
+
+/// `light_count`の`uvec4`
+const LIGHT_HEADER_SIZE: vk::DeviceSize = 16;
+
+/// フレームコンテキストごとのライトのユニフォームバッファ
+pub(crate) struct LightBuffers {
+    buffers: Vec<BufferId>,
+    descriptor_sets: Vec<vk::DescriptorSet>,
+}
+
+impl Scene {
+    /// ライトを持つノードを、ワールド変換で位置と向きを決めたライトとして返す
+    ///
+    /// `update_world_matrices`の後に呼ぶこと。
+    pub fn light_instances(&self) -> impl Iterator<Item = LightInstance> + '_ {
+        self.lights()
+            .map(|(_, light, position, direction)| LightInstance {
+                light: *light,
+                position,
+                direction,
+            })
+    }
+}
+
+impl Renderer {
+    /// ライトのデスクリプタセットレイアウト。クラスターのカリングのコンピュートシェーダーからも読む
+    pub fn light_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[DescriptorBinding::uniform_buffer(
+            0,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE,
+        )])
+    }
+
+    /// ライトを登録する。`RendererConfig::max_lights`を超える場合はエラーにする
+    pub fn add_light(&mut self, light: LightInstance) -> Result<LightId> {
+        if self.lights.len() >= self.config.max_lights as usize {
+            return Err(RendererError::Validation(format!(
+                "cannot add more than {} lights",
+                self.config.max_lights
+            )));
+        }
+        Ok(self.lights.insert(light))
+    }
+
+    pub fn light(&self, id: LightId) -> Option<&LightInstance> {
+        self.lights.get(id)
+    }
+
+    /// 位置や色を変更する。変更は次の`light_descriptor_set`から反映される
+    pub fn light_mut(&mut self, id: LightId) -> Option<&mut LightInstance> {
+        self.lights.get_mut(id)
+    }
+
+    /// ライトを削除する。ポイントシャドウがあれば一緒に破棄する
+    pub fn remove_light(&mut self, id: LightId) -> Result<LightInstance> {
+        let light = self.lights.remove(id).ok_or_else(|| {
+            RendererError::Validation(format!("light {:?} was already removed", id))
+        })?;
+        self.disable_point_shadow(id);
+        Ok(light)
+    }
+
+    /// 登録されているライトを記録中のフレームのユニフォームバッファに書き込み、
+    /// そのデスクリプタセットを返す
+    ///
+    /// `begin_frame`と`end_frame`の間で、ライトを使う描画の前に呼ぶ。
+    pub fn light_descriptor_set(&mut self) -> Result<vk::DescriptorSet> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "light buffers can only be updated between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        if self.light_buffers.is_none() {
+            self.light_buffers = Some(self.create_light_buffers()?);
+        }
+        let light_buffers = self.light_buffers.as_ref().unwrap();
+        let buffer = self
+            .buffers
+            .get(light_buffers.buffers[self.current_frame])
+            .unwrap();
+
+        // 先頭の`uvec4`に続けてライトを`vec4`単位で並べる
+        let mut data: Vec<[f32; 4]> = Vec::with_capacity(1 + self.lights.len() * 4);
+        data.push([f32::from_bits(self.lights.len() as u32), 0.0, 0.0, 0.0]);
+        for (id, light) in self.lights.iter() {
+            let mut light = GpuLight::from(light);
+            light.spot_cos[2] = self
+                .point_shadow_index(id)
+                .map_or(-1.0, |index| index as f32);
+            data.extend([
+                light.position_range,
+                light.direction_type,
+                light.color_intensity,
+                light.spot_cos,
+            ]);
+        }
+        unsafe { write_buffer(buffer, &data)? };
+        Ok(light_buffers.descriptor_sets[self.current_frame])
+    }
+
+    fn create_light_buffers(&mut self) -> Result<LightBuffers> {
+        let layout = self.light_set_layout()?;
+        let size = LIGHT_HEADER_SIZE
+            + self.config.max_lights.max(1) as vk::DeviceSize
+                * std::mem::size_of::<GpuLight>() as vk::DeviceSize;
+        let mut light_buffers = LightBuffers {
+            buffers: Vec::with_capacity(self.frames.len()),
+            descriptor_sets: Vec::with_capacity(self.frames.len()),
+        };
+        for _ in 0..self.frames.len() {
+            let id = self.create_buffer(
+                size,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )?;
+            light_buffers.buffers.push(id);
+            let descriptor_set = self.allocate_descriptor_set(layout)?;
+            unsafe {
+                DescriptorWriter::new()
+                    .buffer(
+                        0,
+                        vk::DescriptorType::UNIFORM_BUFFER,
+                        self.buffers.get(id).unwrap(),
+                    )
+                    .update(&self.device, descriptor_set);
+            }
+            light_buffers.descriptor_sets.push(descriptor_set);
+        }
+        Ok(light_buffers)
+    }
+}
@@ -0,0 +1,434 @@
+use super::error::{RendererError, Result};
+use super::{Aabb, Camera, Mat4, Mesh, MeshId, Pool, Projection, Renderer, VertexLayout};
+use ash::vk;
+use std::collections::HashMap;
+
+/// LODの1段
+///
+/// 段を切り替えるときのポップは、`LodSettings::fade_range`でクロスフェードして隠す。
+/// クロスフェード中は両方の段を描き、フラグメントシェーダーで`tempura/lod.glsl`の
+/// `tempura_lod_dither`に`LodFade::dither`の値を渡して画素を分け合う。
+///
+/// ```glsl
+/// #include "tempura/lod.glsl"
+/// if (tempura_lod_dither(gl_FragCoord.xy, lod_fade)) discard;
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct LodLevel {
+    pub mesh: MeshId,
+    /// この段を使う画面上の大きさの下限。`lod_screen_size`と同じく画面の高さに対する割合
+    pub min_screen_size: f32,
+}
+
+/// 細かい順に並んだLODの段
+///
+/// 最後の段は`min_screen_size`より小さくても使う。画面から消す場合は、最後の段の判定の後に
+/// 呼び出し側で描画を省く。
+#[derive(Debug, Clone, PartialEq)]
+pub struct LodMesh {
+    levels: Vec<LodLevel>,
+}
+
+/// LODの選択の設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LodSettings {
+    /// 画面上の大きさに掛ける値。小さくするほど早く粗い段に切り替わる
+    pub bias: f32,
+    /// 段の境界の上側で、この割合の範囲をクロスフェードする。0なら瞬時に切り替える
+    pub fade_range: f32,
+}
+
+impl Default for LodSettings {
+    fn default() -> Self {
+        Self {
+            bias: 1.0,
+            fade_range: 0.0,
+        }
+    }
+}
+
+/// 隣の粗い段とのクロスフェード
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LodFade {
+    /// 一緒に描く粗い段
+    pub level: usize,
+    /// 粗い段が覆う画素の割合
+    pub amount: f32,
+}
+
+impl LodFade {
+    /// `tempura_lod_dither`に渡す値。細かい段では正、粗い段では負になる
+    pub fn dither(&self, coarser: bool) -> f32 {
+        if coarser {
+            -self.amount
+        } else {
+            self.amount
+        }
+    }
+}
+
+/// 選んだLODの段
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LodSelection {
+    pub level: usize,
+    /// クロスフェード中なら、`level`と合わせて描く粗い段
+    pub fade: Option<LodFade>,
+}
+
+impl LodMesh {
+    /// 段が1つ以上あり、`min_screen_size`が細かい順に小さくなっていること
+    pub fn new(levels: Vec<LodLevel>) -> Result<Self> {
+        if levels.is_empty() {
+            return Err(RendererError::Validation(
+                "a LOD mesh needs at least one level".to_owned(),
+            ));
+        }
+        if levels
+            .windows(2)
+            .any(|pair| pair[1].min_screen_size >= pair[0].min_screen_size)
+        {
+            return Err(RendererError::Validation(
+                "LOD levels must have decreasing min_screen_size".to_owned(),
+            ));
+        }
+        Ok(Self { levels })
+    }
+
+    pub fn levels(&self) -> &[LodLevel] {
+        &self.levels
+    }
+
+    pub fn level(&self, index: usize) -> Option<MeshId> {
+        self.levels.get(index).map(|level| level.mesh)
+    }
+
+    /// 最も細かい段の箱。`meshes`には`Renderer::meshes`を渡す
+    pub fn bounds(&self, meshes: &Pool<Mesh>) -> Option<Aabb> {
+        meshes.get(self.levels[0].mesh)?.bounds
+    }
+
+    /// 画面上の大きさ`screen_size`に合う段を選ぶ
+    pub fn select(&self, screen_size: f32, settings: &LodSettings) -> LodSelection {
+        let size = screen_size * settings.bias;
+        let last = self.levels.len() - 1;
+        let level = self
+            .levels
+            .iter()
+            .position(|level| size >= level.min_screen_size)
+            .unwrap_or(last);
+        let fade = (level < last && settings.fade_range > 0.0)
+            .then(|| {
+                let threshold = self.levels[level].min_screen_size;
+                let amount = 1.0 - (size - threshold) / (threshold * settings.fade_range);
+                (amount > 0.0).then_some(LodFade {
+                    level: level + 1,
+                    amount: amount.min(1.0),
+                })
+            })
+            .flatten();
+        LodSelection { level, fade }
+    }
+}
+
+/// `model`で置いた`bounds`の境界球の直径の、画面の高さに対する割合
+///
+/// カメラが球の中にあれば`f32::INFINITY`を返す。
+pub fn lod_screen_size(bounds: &Aabb, model: &Mat4, camera: &Camera) -> f32 {
+    let world = bounds.transform(model);
+    let center = world.center();
+    let radius = (0..3)
+        .map(|i| (world.max[i] - world.min[i]) * 0.5)
+        .map(|e| e * e)
+        .sum::<f32>()
+        .sqrt();
+    match camera.projection {
+        Projection::Perspective { fov_y, .. } => {
+            let distance = (0..3)
+                .map(|i| center[i] - camera.position[i])
+                .map(|d| d * d)
+                .sum::<f32>()
+                .sqrt();
+            if distance <= radius {
+                return f32::INFINITY;
+            }
+            radius / (distance * (fov_y * 0.5).tan())
+        }
+        Projection::Orthographic { height, .. } => 2.0 * radius / height,
+    }
+}
+
+/// 頂点を`cell_size`の格子でまとめて、粗い段のインデックスを作る
+///
+/// 同じ格子に入る頂点を最初の頂点に置き換え、潰れた三角形を除く。返すインデックスは
+/// 元の頂点を指すので、元の頂点と一緒に`create_mesh`に渡して粗い段のメッシュを作る。
+/// 位置は`Aabb::from_vertices`と同じくロケーション0の属性から読む。
+pub fn simplify_by_clustering<V: Copy>(
+    vertices: &[V],
+    layout: &VertexLayout,
+    indices: &[u32],
+    cell_size: f32,
+) -> Result<Vec<u32>> {
+    if cell_size <= 0.0 {
+        return Err(RendererError::Validation(format!(
+            "clustering cell size {} must be positive",
+            cell_size
+        )));
+    }
+    let offset = layout
+        .attributes
+        .iter()
+        .find(|attribute| {
+            attribute.location == 0
+                && matches!(
+                    attribute.format,
+                    vk::Format::R32G32B32_SFLOAT | vk::Format::R32G32B32A32_SFLOAT
+                )
+        })
+        .map(|attribute| attribute.offset as usize)
+        .filter(|&offset| offset + 12 <= std::mem::size_of::<V>())
+        .ok_or_else(|| {
+            RendererError::Validation("vertices have no position at location 0".to_owned())
+        })?;
+    if let Some(&index) = indices
+        .iter()
+        .find(|&&index| index as usize >= vertices.len())
+    {
+        return Err(RendererError::Validation(format!(
+            "index {} is out of range for {} vertices",
+            index,
+            vertices.len()
+        )));
+    }
+    let mut cells: HashMap<[i32; 3], u32> = HashMap::new();
+    let representatives: Vec<u32> = vertices
+        .iter()
+        .enumerate()
+        .map(|(i, vertex)| {
+            let position = unsafe {
+                (vertex as *const V as *const u8)
+                    .add(offset)
+                    .cast::<[f32; 3]>()
+                    .read_unaligned()
+            };
+            let cell = position.map(|value| (value / cell_size).floor() as i32);
+            *cells.entry(cell).or_insert(i as u32)
+        })
+        .collect();
+    Ok(indices
+        .chunks_exact(3)
+        .map(|triangle| [0, 1, 2].map(|i| representatives[triangle[i] as usize]))
+        .filter(|t| t[0] != t[1] && t[1] != t[2] && t[2] != t[0])
+        .flatten()
+        .collect())
+}
+
+impl Renderer {
+    /// 使用中のフレームが完了してから全ての段のメッシュを破棄する
+    pub fn destroy_lod_mesh(&mut self, lod: LodMesh) -> Result<()> {
+        for level in lod.levels {
+            self.destroy_mesh(level.mesh)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::memory::Allocation;
+    use super::super::{Buffer, Transform};
+    use super::*;
+
+    /// GPUのバッファを持たないメッシュ
+    fn mesh(meshes: &mut Pool<Mesh>, bounds: Option<Aabb>) -> MeshId {
+        let mut buffers = Pool::new();
+        let vertex_buffer = buffers.insert(Buffer {
+            buffer: vk::Buffer::null(),
+            allocation: Allocation::external(0),
+            size: 0,
+            usage: vk::BufferUsageFlags::VERTEX_BUFFER,
+            external: true,
+        });
+        meshes.insert(Mesh {
+            vertex_buffer,
+            index_buffer: None,
+            index_type: vk::IndexType::UINT32,
+            vertex_layout: VertexLayout::new(12),
+            vertex_count: 0,
+            index_count: 0,
+            submeshes: Vec::new(),
+            bounds,
+        })
+    }
+
+    /// `min_screen_size`の段
+    fn level(min_screen_size: f32) -> LodLevel {
+        LodLevel {
+            mesh: mesh(&mut Pool::new(), None),
+            min_screen_size,
+        }
+    }
+
+    fn lod() -> LodMesh {
+        LodMesh::new(vec![level(0.5), level(0.2), level(0.05)]).unwrap()
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn new_requires_decreasing_sizes() {
+        assert!(LodMesh::new(Vec::new()).is_err());
+        assert!(LodMesh::new(vec![level(0.2), level(0.2)]).is_err());
+        assert!(LodMesh::new(vec![level(0.1), level(0.2)]).is_err());
+        assert_eq!(lod().levels().len(), 3);
+    }
+
+    #[test]
+    fn bounds_come_from_the_finest_level() {
+        let mut meshes = Pool::new();
+        let bounds = Aabb {
+            min: [-1.0; 3],
+            max: [1.0; 3],
+        };
+        let fine = mesh(&mut meshes, Some(bounds));
+        let coarse = mesh(&mut meshes, None);
+        let lod = LodMesh::new(vec![
+            LodLevel {
+                mesh: fine,
+                min_screen_size: 0.5,
+            },
+            LodLevel {
+                mesh: coarse,
+                min_screen_size: 0.1,
+            },
+        ])
+        .unwrap();
+        assert_eq!(lod.level(1), Some(coarse));
+        assert_eq!(lod.level(2), None);
+        assert_eq!(lod.bounds(&meshes), Some(bounds));
+        // 破棄したメッシュの箱は得られない
+        meshes.remove(fine);
+        assert_eq!(lod.bounds(&meshes), None);
+    }
+
+    #[test]
+    fn select_picks_level_at_thresholds() {
+        let lod = lod();
+        let settings = LodSettings::default();
+        let level = |size: f32| lod.select(size, &settings).level;
+        assert_eq!(level(f32::INFINITY), 0);
+        assert_eq!(level(0.5), 0);
+        assert_eq!(level(0.499), 1);
+        assert_eq!(level(0.2), 1);
+        assert_eq!(level(0.199), 2);
+        // 最後の段は下限より小さくても使う
+        assert_eq!(level(0.01), 2);
+        assert_eq!(level(0.0), 2);
+        assert_eq!(lod.select(0.6, &settings).fade, None);
+    }
+
+    #[test]
+    fn select_applies_bias() {
+        let settings = LodSettings {
+            bias: 0.5,
+            ..LodSettings::default()
+        };
+        assert_eq!(lod().select(0.6, &settings).level, 1);
+        assert_eq!(lod().select(1.0, &settings).level, 0);
+    }
+
+    #[test]
+    fn fade_runs_from_threshold_to_fade_range() {
+        let lod = lod();
+        let settings = LodSettings {
+            bias: 1.0,
+            fade_range: 0.5,
+        };
+        // 境界ちょうどでは粗い段が全て覆い、境界の1.5倍で細かい段だけになる
+        let at_threshold = lod.select(0.2, &settings);
+        assert_eq!(at_threshold.level, 1);
+        let fade = at_threshold.fade.unwrap();
+        assert_eq!(fade.level, 2);
+        assert_eq!(fade.amount, 1.0);
+        let halfway = lod.select(0.25, &settings).fade.unwrap();
+        assert_close(halfway.amount, 0.5);
+        let nearly_done = lod.select(0.29999, &settings).fade.unwrap();
+        assert!(nearly_done.amount < 1e-3);
+        assert_eq!(lod.select(0.3, &settings).fade, None);
+        assert_eq!(lod.select(0.5, &settings).fade.unwrap().amount, 1.0);
+        // 最後の段には粗い段がない
+        assert_eq!(lod.select(0.05, &settings).fade, None);
+        assert_eq!(fade.dither(false), 1.0);
+        assert_eq!(fade.dither(true), -1.0);
+    }
+
+    #[test]
+    fn screen_size_of_bounding_sphere() {
+        let bounds = Aabb {
+            min: [-1.0; 3],
+            max: [1.0; 3],
+        };
+        let camera = Camera {
+            projection: Projection::Perspective {
+                fov_y: 90f32.to_radians(),
+                near: 0.1,
+                far: None,
+            },
+            ..Camera::default()
+        };
+        let radius = 3f32.sqrt();
+        let model = Transform::from_translation([0.0, 0.0, -10.0]).matrix();
+        assert_close(lod_screen_size(&bounds, &model, &camera), radius / 10.0);
+        let scaled = Transform {
+            scale: [2.0; 3],
+            ..Transform::from_translation([0.0, 0.0, -10.0])
+        }
+        .matrix();
+        assert_close(lod_screen_size(&bounds, &scaled, &camera), radius / 5.0);
+        // カメラが球の中
+        let near = Transform::from_translation([0.0, 0.0, -1.0]).matrix();
+        assert_eq!(lod_screen_size(&bounds, &near, &camera), f32::INFINITY);
+
+        let orthographic = Camera {
+            projection: Projection::Orthographic {
+                height: 4.0,
+                near: 0.1,
+                far: 100.0,
+            },
+            ..camera
+        };
+        // 正射影では距離によらない
+        let far = Transform::from_translation([0.0, 0.0, -100.0]).matrix();
+        assert_close(lod_screen_size(&bounds, &far, &orthographic), radius / 2.0);
+    }
+
+    #[test]
+    fn clustering_drops_degenerate_triangles() {
+        let layout = VertexLayout::new(12).attribute(0, vk::Format::R32G32B32_SFLOAT, 0);
+        let vertices: [[f32; 3]; 5] = [
+            [0.0, 0.0, 0.0],
+            [0.1, 0.1, 0.0],
+            [2.0, 0.0, 0.0],
+            [0.0, 2.0, 0.0],
+            [2.1, 0.0, 0.0],
+        ];
+        // 2番目と最後の三角形は頂点がまとまって潰れる
+        let indices = [0, 2, 3, 0, 1, 2, 1, 2, 3, 2, 4, 3];
+        let simplified = simplify_by_clustering(&vertices, &layout, &indices, 1.0).unwrap();
+        assert_eq!(simplified, [0, 2, 3, 0, 2, 3]);
+        // 格子が細かければ何も潰れない
+        let fine = simplify_by_clustering(&vertices, &layout, &indices, 0.01).unwrap();
+        assert_eq!(fine, indices);
+    }
+
+    #[test]
+    fn clustering_rejects_invalid_input() {
+        let layout = VertexLayout::new(12).attribute(0, vk::Format::R32G32B32_SFLOAT, 0);
+        let vertices = [[0.0f32; 3]; 3];
+        assert!(simplify_by_clustering(&vertices, &layout, &[0, 1, 2], 0.0).is_err());
+        assert!(simplify_by_clustering(&vertices, &layout, &[0, 1, 3], 1.0).is_err());
+        let no_position = VertexLayout::new(12).attribute(1, vk::Format::R32G32B32_SFLOAT, 0);
+        assert!(simplify_by_clustering(&vertices, &no_position, &[0, 1, 2], 1.0).is_err());
+    }
+}
@@ -0,0 +1,465 @@
+use super::assets::ObjVertex;
+use super::error::{RendererError, Result};
+use super::{
+    texture_sample_type, BufferId, DescriptorBinding, DescriptorWriter, Handle, PipelineBuilder,
+    Renderer, ShaderId, TextureId, TextureSampleType, VertexDisplacement,
+};
+use ash::vk;
+
+/// マテリアルのアルファの扱い。glTFの`alphaMode`と同じ意味を持つ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlphaMode {
+    Opaque,
+    /// `alpha_cutoff`でアルファテストする
+    Mask,
+    /// 半透明。`enable_oit`が有効なら順序によらない透明のパスで描く
+    Blend,
+}
+
+/// 描画物が受け取る効果のフラグ。マテリアルごとに持ち、G-bufferのマテリアルのBに書き込む
+///
+/// 受け取らない効果のパスは、G-bufferかマテリアルのユニフォームのフラグを見て飛ばす。
+/// GLSLでは`tempura/packing.glsl`の`TEMPURA_RECEIVE_*`が同じ値を持つ。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ReceiveFlags(pub u32);
+
+impl ReceiveFlags {
+    /// デカールが投影される
+    pub const DECALS: Self = Self(1);
+    /// フォグがかかる
+    pub const FOG: Self = Self(1 << 1);
+    /// SSAOの結果が環境光に掛かる
+    pub const AMBIENT_OCCLUSION: Self = Self(1 << 2);
+    /// ライトマップの間接光を受け取る
+    pub const LIGHTMAPS: Self = Self(1 << 3);
+    /// 輪郭線が描かれる。`set_outline_surface`の面のアルファに書く
+    pub const OUTLINE: Self = Self(1 << 4);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn all() -> Self {
+        Self(
+            Self::DECALS.0
+                | Self::FOG.0
+                | Self::AMBIENT_OCCLUSION.0
+                | Self::LIGHTMAPS.0
+                | Self::OUTLINE.0,
+        )
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+impl Default for ReceiveFlags {
+    /// 全ての効果を受け取る
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl std::ops::BitOr for ReceiveFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// セルシェーディングの設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CelShading {
+    /// 拡散光の明るさの段数。1なら光と影の2色になる
+    pub bands: u32,
+    /// 段の境目をぼかす幅。0ならくっきり分かれる
+    pub softness: f32,
+    /// 輪郭に沿って明るくするリムライトの強さ
+    pub rim_strength: f32,
+}
+
+impl Default for CelShading {
+    fn default() -> Self {
+        Self {
+            bands: 2,
+            softness: 0.05,
+            rim_strength: 0.0,
+        }
+    }
+}
+
+/// マテリアルの陰影の付け方
+///
+/// GLSLでは`tempura/toon.glsl`の`TEMPURA_SHADING_*`が`index`と同じ値を持つ。
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ShadingModel {
+    #[default]
+    Pbr,
+    /// 明るさを段に分けるトゥーン調の陰影。輪郭線は`enable_outline`と組み合わせる
+    Cel(CelShading),
+}
+
+impl ShadingModel {
+    pub fn index(&self) -> u32 {
+        match self {
+            ShadingModel::Pbr => 0,
+            ShadingModel::Cel(_) => 1,
+        }
+    }
+}
+
+/// PBRメタリック/ラフネスモデルのマテリアルの入力。glTFの`material`と同じ意味を持つ
+///
+/// テクスチャが`None`の場合は、係数がそのまま使われるデフォルトテクスチャを割り当てる。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MaterialDesc {
+    /// リニア空間のRGBA
+    pub base_color_factor: [f32; 4],
+    /// sRGBのテクスチャ
+    pub base_color_texture: Option<TextureId>,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    /// Gにラフネス、Bにメタリック。リニアのテクスチャ
+    pub metallic_roughness_texture: Option<TextureId>,
+    /// タンジェント空間の法線。リニアのテクスチャ
+    pub normal_texture: Option<TextureId>,
+    pub normal_scale: f32,
+    /// Rに遮蔽率。リニアのテクスチャ
+    pub occlusion_texture: Option<TextureId>,
+    pub occlusion_strength: f32,
+    pub emissive_factor: [f32; 3],
+    /// sRGBのテクスチャ
+    pub emissive_texture: Option<TextureId>,
+    /// アルファがこの値未満のピクセルを捨てる。`None`ならアルファテストをしない
+    pub alpha_cutoff: Option<f32>,
+    /// 半透明のマテリアル。glTFの`alphaMode`が`BLEND`なら`true`にする
+    pub alpha_blend: bool,
+    /// 頂点の変位。`material_vertex_shader`で各パスの頂点シェーダーに埋め込む
+    pub displacement: Option<VertexDisplacement>,
+    /// このマテリアルで描く物が受け取る効果。空や水面、UIなどで一部の効果を外す
+    pub receive: ReceiveFlags,
+    pub shading: ShadingModel,
+}
+
+impl MaterialDesc {
+    /// `alpha_blend`を優先し、なければ`alpha_cutoff`の有無で決める
+    pub fn alpha_mode(&self) -> AlphaMode {
+        if self.alpha_blend {
+            AlphaMode::Blend
+        } else if self.alpha_cutoff.is_some() {
+            AlphaMode::Mask
+        } else {
+            AlphaMode::Opaque
+        }
+    }
+}
+
+impl Default for MaterialDesc {
+    fn default() -> Self {
+        Self {
+            base_color_factor: [1.0, 1.0, 1.0, 1.0],
+            base_color_texture: None,
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            normal_scale: 1.0,
+            occlusion_texture: None,
+            occlusion_strength: 1.0,
+            emissive_factor: [0.0, 0.0, 0.0],
+            emissive_texture: None,
+            alpha_cutoff: None,
+            alpha_blend: false,
+            displacement: None,
+            receive: ReceiveFlags::default(),
+            shading: ShadingModel::default(),
+        }
+    }
+}
+
+/// マテリアルのユニフォームバッファ(セット0、バインディング0)の内容。std140と同じ配置
+///
+/// ```glsl
+/// layout(set = 0, binding = 0) uniform Material {
+///     vec4 base_color_factor;
+///     vec3 emissive_factor;
+///     float metallic_factor;
+///     float roughness_factor;
+///     float normal_scale;
+///     float occlusion_strength;
+///     float alpha_cutoff; // 負ならアルファテストをしない
+///     uint receive_flags; // TEMPURA_RECEIVE_*の組み合わせ
+///     uint shading_model; // TEMPURA_SHADING_*
+///     uint cel_bands;
+///     float cel_softness;
+///     float rim_strength;
+/// };
+/// ```
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MaterialUniform {
+    pub base_color_factor: [f32; 4],
+    pub emissive_factor: [f32; 3],
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub normal_scale: f32,
+    pub occlusion_strength: f32,
+    pub alpha_cutoff: f32,
+    pub receive_flags: u32,
+    pub shading_model: u32,
+    pub cel_bands: u32,
+    pub cel_softness: f32,
+    pub rim_strength: f32,
+}
+
+impl From<&MaterialDesc> for MaterialUniform {
+    fn from(desc: &MaterialDesc) -> Self {
+        let cel = match desc.shading {
+            ShadingModel::Pbr => CelShading::default(),
+            ShadingModel::Cel(cel) => cel,
+        };
+        Self {
+            base_color_factor: desc.base_color_factor,
+            emissive_factor: desc.emissive_factor,
+            metallic_factor: desc.metallic_factor,
+            roughness_factor: desc.roughness_factor,
+            normal_scale: desc.normal_scale,
+            occlusion_strength: desc.occlusion_strength,
+            alpha_cutoff: desc.alpha_cutoff.unwrap_or(-1.0),
+            receive_flags: desc.receive.0,
+            shading_model: desc.shading.index(),
+            cel_bands: cel.bands,
+            cel_softness: cel.softness,
+            rim_strength: cel.rim_strength,
+        }
+    }
+}
+
+/// マテリアルのデスクリプタセットのバインディング。1から5は`sampler2D`
+pub const MATERIAL_BINDING_UNIFORM: u32 = 0;
+pub const MATERIAL_BINDING_BASE_COLOR: u32 = 1;
+pub const MATERIAL_BINDING_METALLIC_ROUGHNESS: u32 = 2;
+pub const MATERIAL_BINDING_NORMAL: u32 = 3;
+pub const MATERIAL_BINDING_OCCLUSION: u32 = 4;
+pub const MATERIAL_BINDING_EMISSIVE: u32 = 5;
+
+/// `pbr_pipeline_builder`のパイプラインに渡すプッシュ定数。頂点シェーダーで使う
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PbrPushConstants {
+    pub model: [[f32; 4]; 4],
+    pub view_projection: [[f32; 4]; 4],
+}
+
+pub struct Material {
+    pub desc: MaterialDesc,
+    pub uniform_buffer: BufferId,
+    pub descriptor_set: vk::DescriptorSet,
+}
+
+pub type MaterialId = Handle<Material>;
+
+/// テクスチャのないスロットに割り当てる1x1のテクスチャ
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DefaultMaterialTextures {
+    white: TextureId,
+    white_srgb: TextureId,
+    flat_normal: TextureId,
+}
+
+fn material_bindings() -> [DescriptorBinding; 6] {
+    let stages = vk::ShaderStageFlags::FRAGMENT;
+    [
+        DescriptorBinding::uniform_buffer(MATERIAL_BINDING_UNIFORM, stages),
+        DescriptorBinding::sampled_image(MATERIAL_BINDING_BASE_COLOR, stages),
+        DescriptorBinding::sampled_image(MATERIAL_BINDING_METALLIC_ROUGHNESS, stages),
+        DescriptorBinding::sampled_image(MATERIAL_BINDING_NORMAL, stages),
+        DescriptorBinding::sampled_image(MATERIAL_BINDING_OCCLUSION, stages),
+        DescriptorBinding::sampled_image(MATERIAL_BINDING_EMISSIVE, stages),
+    ]
+}
+
+impl Renderer {
+    /// マテリアルのデスクリプタセットレイアウト
+    pub fn material_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&material_bindings())
+    }
+
+    /// ユニフォームバッファを作成して、テクスチャと一緒にデスクリプタセットに書き込む
+    pub fn create_material(&mut self, desc: &MaterialDesc) -> Result<MaterialId> {
+        if let Some(displacement) = &desc.displacement {
+            displacement.validate()?;
+        }
+        if let ShadingModel::Cel(cel) = &desc.shading {
+            if cel.bands == 0 || !(0.0..=1.0).contains(&cel.softness) || cel.rim_strength < 0.0 {
+                return Err(RendererError::Validation(format!(
+                    "invalid cel shading settings: {:?}",
+                    cel
+                )));
+            }
+        }
+        let defaults = self.default_material_textures()?;
+        let textures = [
+            (
+                MATERIAL_BINDING_BASE_COLOR,
+                desc.base_color_texture,
+                defaults.white_srgb,
+            ),
+            (
+                MATERIAL_BINDING_METALLIC_ROUGHNESS,
+                desc.metallic_roughness_texture,
+                defaults.white,
+            ),
+            (
+                MATERIAL_BINDING_NORMAL,
+                desc.normal_texture,
+                defaults.flat_normal,
+            ),
+            (
+                MATERIAL_BINDING_OCCLUSION,
+                desc.occlusion_texture,
+                defaults.white,
+            ),
+            (
+                MATERIAL_BINDING_EMISSIVE,
+                desc.emissive_texture,
+                defaults.white_srgb,
+            ),
+        ];
+        if let Some(id) = textures
+            .iter()
+            .filter_map(|(_, texture, _)| *texture)
+            .find(|&id| !self.textures.contains(id))
+        {
+            return Err(RendererError::Validation(format!(
+                "material references texture {:?} that was destroyed",
+                id
+            )));
+        }
+        // マテリアルのスロットは全て`sampler2D`なので、浮動小数点として読めるものだけを受け付ける
+        if let Some((binding, texture)) = textures.iter().find_map(|(binding, texture, _)| {
+            let texture = self.textures.get((*texture)?)?;
+            (texture_sample_type(texture.format) != TextureSampleType::Float)
+                .then_some((*binding, texture))
+        }) {
+            return Err(RendererError::Validation(format!(
+                "material binding {} expects a float texture, got {:?}",
+                binding, texture.format
+            )));
+        }
+
+        let layout = self.material_set_layout()?;
+        let uniform_buffer = self.create_buffer_with_data(
+            &[MaterialUniform::from(desc)],
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+        )?;
+        let descriptor_set = match self.allocate_descriptor_set(layout) {
+            Ok(set) => set,
+            Err(err) => {
+                self.destroy_buffer(uniform_buffer)?;
+                return Err(err);
+            }
+        };
+        let mut writer = DescriptorWriter::new().buffer(
+            MATERIAL_BINDING_UNIFORM,
+            vk::DescriptorType::UNIFORM_BUFFER,
+            self.buffers.get(uniform_buffer).unwrap(),
+        );
+        for (binding, texture, default) in textures {
+            let texture = self.textures.get(texture.unwrap_or(default)).unwrap();
+            writer = writer.texture(binding, texture);
+        }
+        unsafe { writer.update(&self.device, descriptor_set) };
+
+        Ok(self.materials.insert(Material {
+            desc: *desc,
+            uniform_buffer,
+            descriptor_set,
+        }))
+    }
+
+    pub fn material(&self, id: MaterialId) -> Option<&Material> {
+        self.materials.get(id)
+    }
+
+    /// 使用中のフレームが完了してからユニフォームバッファを破棄する
+    ///
+    /// デスクリプタセットはレンダラーの破棄まで解放されない。テクスチャは破棄しない。
+    pub fn destroy_material(&mut self, id: MaterialId) -> Result<()> {
+        let material = self.materials.remove(id).ok_or_else(|| {
+            RendererError::Validation(format!("material {:?} was already destroyed", id))
+        })?;
+        self.destroy_buffer(material.uniform_buffer)
+    }
+
+    /// デフォルトのPBRシェーディング用に設定したパイプラインビルダーを返す
+    ///
+    /// 頂点は[`ObjVertex`]の配置、セット0はマテリアル、セット1は`light_descriptor_set`のライト、
+    /// プッシュ定数は頂点シェーダーの[`PbrPushConstants`]。フラグメントシェーダーは
+    /// ライトごとにLambertの拡散反射とGGXの鏡面反射を足し合わせる想定。
+    pub fn pbr_pipeline_builder(
+        &mut self,
+        vertex_shader: ShaderId,
+        fragment_shader: ShaderId,
+    ) -> Result<PipelineBuilder> {
+        let module = |id: ShaderId| {
+            self.shader_modules
+                .get(id)
+                .map(|shader| shader.module)
+                .ok_or_else(|| {
+                    RendererError::Validation(format!("shader {:?} was already destroyed", id))
+                })
+        };
+        let vertex_module = module(vertex_shader)?;
+        let fragment_module = module(fragment_shader)?;
+        let material_layout = self.material_set_layout()?;
+        let light_layout = self.light_set_layout()?;
+        Ok(PipelineBuilder::new()
+            .vertex_shader(vertex_module)
+            .fragment_shader(fragment_module)
+            .vertex_layout(0, &ObjVertex::layout())
+            .descriptor_set_layouts(&[material_layout, light_layout])
+            .push_constant_range(
+                vk::ShaderStageFlags::VERTEX,
+                0,
+                std::mem::size_of::<PbrPushConstants>() as u32,
+            ))
+    }
+
+    fn default_material_textures(&mut self) -> Result<DefaultMaterialTextures> {
+        if let Some(defaults) = self.default_material_textures {
+            return Ok(defaults);
+        }
+        let white = self.create_texture_from_levels(
+            vk::Format::R8G8B8A8_UNORM,
+            1,
+            1,
+            &[&[255, 255, 255, 255]],
+        )?;
+        let white_srgb = self.create_texture_from_levels(
+            vk::Format::R8G8B8A8_SRGB,
+            1,
+            1,
+            &[&[255, 255, 255, 255]],
+        )?;
+        let flat_normal = self.create_texture_from_levels(
+            vk::Format::R8G8B8A8_UNORM,
+            1,
+            1,
+            &[&[128, 128, 255, 255]],
+        )?;
+        let defaults = DefaultMaterialTextures {
+            white,
+            white_srgb,
+            flat_normal,
+        };
+        self.default_material_textures = Some(defaults);
+        Ok(defaults)
+    }
+}
@@ -0,0 +1,637 @@
+use super::error::{RendererError, Result};
+use super::renderer::find_memorytype_index;
+use ash::{vk, Device};
+use gpu_alloc_ash::{memory_properties_to_ash, AshMemoryDevice};
+use gpu_alloc_types::{
+    AllocationFlags, DeviceMapError, DeviceProperties, MemoryDevice, OutOfMemory,
+};
+use std::collections::HashMap;
+
+// 1回の`allocate_memory`で確保するブロックの大きさ
+const DEFAULT_BLOCK_SIZE: vk::DeviceSize = 64 * 1024 * 1024;
+// ヒープが小さい場合にブロックがヒープを占有しないようにする
+const MAX_BLOCK_HEAP_FRACTION: vk::DeviceSize = 8;
+
+/// 割り当て先のリソースの種類
+///
+/// `bufferImageGranularity`を気にしなくて済むよう、リニアなリソース(バッファ)と
+/// オプティマルなイメージは別々のブロックから割り当てる。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AllocationKind {
+    Linear,
+    Optimal,
+}
+
+/// 割り当て時の要求
+#[derive(Debug, Clone, Copy)]
+pub struct AllocationDesc {
+    pub requirements: vk::MemoryRequirements,
+    pub memory_flags: vk::MemoryPropertyFlags,
+    pub kind: AllocationKind,
+    /// 大きなレンダーターゲットなど、ブロックを共有せず専用のメモリを確保する
+    pub dedicated: Option<DedicatedResource>,
+}
+
+/// 専用割り当ての対象。Vulkan 1.1以降では`VkMemoryDedicatedAllocateInfo`で指定する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedicatedResource {
+    Buffer(vk::Buffer),
+    Image(vk::Image),
+}
+
+/// `MemoryAllocator`から割り当てられたメモリ範囲
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    pub memory: vk::DeviceMemory,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize,
+    pub memory_type_index: u32,
+    /// HOST_VISIBLEなメモリの場合、`offset`に対応する永続マップ済みのアドレス
+    pub mapped_ptr: Option<*mut u8>,
+    block: Option<BlockId>,
+}
+
+impl Allocation {
+    /// アプリケーションが管理するメモリを表す。アロケーターからは解放しない
+    pub(crate) fn external(size: vk::DeviceSize) -> Self {
+        Self {
+            memory: vk::DeviceMemory::null(),
+            offset: 0,
+            size,
+            memory_type_index: u32::MAX,
+            mapped_ptr: None,
+            block: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct BlockId {
+    memory_type_index: u32,
+    kind: AllocationKind,
+    index: usize,
+}
+
+/// アロケーターの統計情報
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// 共有ブロックの数
+    pub block_count: usize,
+    /// 専用割り当ての数
+    pub dedicated_count: usize,
+    /// 生存中の割り当ての数
+    pub allocation_count: usize,
+    /// `allocate_memory`で確保したバイト数
+    pub reserved_bytes: vk::DeviceSize,
+    /// 割り当て済みのバイト数
+    pub used_bytes: vk::DeviceSize,
+}
+
+struct MemoryBlock {
+    memory: vk::DeviceMemory,
+    size: vk::DeviceSize,
+    mapped_ptr: Option<*mut u8>,
+    /// 空き領域(オフセット, サイズ)。オフセット順に並び、隣接する領域は結合済み
+    free_ranges: Vec<(vk::DeviceSize, vk::DeviceSize)>,
+    allocation_count: usize,
+}
+
+impl MemoryBlock {
+    fn allocate(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+    ) -> Option<vk::DeviceSize> {
+        let alignment = alignment.max(1);
+        let (range_index, offset) = self.free_ranges.iter().enumerate().find_map(
+            |(index, &(range_offset, range_size))| {
+                let offset = range_offset.next_multiple_of(alignment);
+                (offset + size <= range_offset + range_size).then_some((index, offset))
+            },
+        )?;
+
+        let (range_offset, range_size) = self.free_ranges.remove(range_index);
+        let range_end = range_offset + range_size;
+        let end = offset + size;
+        // アライメントで生じた前の隙間と後ろの余りを空き領域に戻す
+        if end < range_end {
+            self.free_ranges.insert(range_index, (end, range_end - end));
+        }
+        if range_offset < offset {
+            self.free_ranges
+                .insert(range_index, (range_offset, offset - range_offset));
+        }
+        self.allocation_count += 1;
+        Some(offset)
+    }
+
+    fn free(&mut self, offset: vk::DeviceSize, size: vk::DeviceSize) {
+        let index = self
+            .free_ranges
+            .partition_point(|&(range_offset, _)| range_offset < offset);
+        self.free_ranges.insert(index, (offset, size));
+        // 後ろ、前の順に隣接する空き領域と結合する
+        if index + 1 < self.free_ranges.len() {
+            let (next_offset, next_size) = self.free_ranges[index + 1];
+            if offset + size == next_offset {
+                self.free_ranges[index].1 += next_size;
+                self.free_ranges.remove(index + 1);
+            }
+        }
+        if index > 0 {
+            let (prev_offset, prev_size) = self.free_ranges[index - 1];
+            if prev_offset + prev_size == offset {
+                self.free_ranges[index - 1].1 += self.free_ranges[index].1;
+                self.free_ranges.remove(index);
+            }
+        }
+        self.allocation_count -= 1;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.allocation_count == 0
+    }
+}
+
+/// デバイスメモリをブロック単位で確保し、そこからリソースごとの領域を切り出すアロケーター
+///
+/// `maxMemoryAllocationCount`に達しないよう、小さなリソースは同じメモリタイプの
+/// ブロックを共有する。ブロックの半分を超える要求と専用割り当ての要求は個別に確保する。
+///
+/// デバイスの情報と、メモリの確保、マップ、解放はgpu-alloc-ashの`device_properties`と
+/// `AshMemoryDevice`を使う。ブロックからの切り出しを担うgpu-allocのクレートは使えないので、
+/// このモジュールで行う。`VkMemoryDedicatedAllocateInfo`を付ける専用割り当てだけは、
+/// `AshMemoryDevice`が対応していないのでashで直接確保する。
+pub struct MemoryAllocator {
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    /// `allocate_memory`で同時に確保できる数
+    max_memory_allocation_count: u32,
+    /// `VkMemoryDedicatedAllocateInfo`を使えるか(Vulkan 1.1以降)
+    dedicated_allocation: bool,
+    blocks: HashMap<(u32, AllocationKind), Vec<Option<MemoryBlock>>>,
+    dedicated: Vec<vk::DeviceMemory>,
+    stats: MemoryStats,
+}
+
+impl MemoryAllocator {
+    /// `properties`はgpu-alloc-ashの`device_properties`で取得する
+    pub fn new(properties: &DeviceProperties, api_version: u32) -> Self {
+        let mut memory_properties = vk::PhysicalDeviceMemoryProperties {
+            memory_type_count: properties.memory_types.len() as u32,
+            memory_heap_count: properties.memory_heaps.len() as u32,
+            ..Default::default()
+        };
+        for (dst, src) in memory_properties
+            .memory_types
+            .iter_mut()
+            .zip(properties.memory_types.iter())
+        {
+            dst.property_flags = memory_properties_to_ash(src.props);
+            dst.heap_index = src.heap;
+        }
+        for (dst, src) in memory_properties
+            .memory_heaps
+            .iter_mut()
+            .zip(properties.memory_heaps.iter())
+        {
+            dst.size = src.size;
+        }
+        Self {
+            memory_properties,
+            max_memory_allocation_count: properties.max_memory_allocation_count,
+            dedicated_allocation: api_version >= vk::API_VERSION_1_1,
+            blocks: HashMap::new(),
+            dedicated: Vec::new(),
+            stats: MemoryStats::default(),
+        }
+    }
+
+    pub fn memory_properties(&self) -> &vk::PhysicalDeviceMemoryProperties {
+        &self.memory_properties
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        self.stats
+    }
+
+    /// # Safety
+    ///
+    /// `device`はこのアロケーターと同じ物理デバイスから作成されていること。
+    pub unsafe fn allocate(
+        &mut self,
+        device: &Device,
+        desc: &AllocationDesc,
+    ) -> Result<Allocation> {
+        let memory_type_index = find_memorytype_index(
+            &desc.requirements,
+            &self.memory_properties,
+            desc.memory_flags,
+        )
+        .ok_or(RendererError::NoSuitableMemoryType)?;
+        let size = desc.requirements.size;
+        let block_size = self.block_size(memory_type_index);
+
+        if desc.dedicated.is_some() || size > block_size / 2 {
+            return self.allocate_dedicated(device, memory_type_index, size, desc.dedicated);
+        }
+
+        let blocks = self
+            .blocks
+            .entry((memory_type_index, desc.kind))
+            .or_default();
+        for (index, block) in blocks.iter_mut().enumerate() {
+            let Some(block) = block else {
+                continue;
+            };
+            if let Some(offset) = block.allocate(size, desc.requirements.alignment) {
+                self.stats.allocation_count += 1;
+                self.stats.used_bytes += size;
+                return Ok(Allocation {
+                    memory: block.memory,
+                    offset,
+                    size,
+                    memory_type_index,
+                    mapped_ptr: block.mapped_ptr.map(|ptr| ptr.add(offset as usize)),
+                    block: Some(BlockId {
+                        memory_type_index,
+                        kind: desc.kind,
+                        index,
+                    }),
+                });
+            }
+        }
+
+        // 空きのあるブロックがなければ新しく確保する
+        let (memory, mapped_ptr) =
+            self.allocate_memory(device, memory_type_index, block_size, None)?;
+        let mut block = MemoryBlock {
+            memory,
+            size: block_size,
+            mapped_ptr,
+            free_ranges: vec![(0, block_size)],
+            allocation_count: 0,
+        };
+        let offset = block
+            .allocate(size, desc.requirements.alignment)
+            .expect("allocation must fit in a new block");
+        let blocks = self
+            .blocks
+            .get_mut(&(memory_type_index, desc.kind))
+            .unwrap();
+        let index = match blocks.iter().position(Option::is_none) {
+            Some(index) => {
+                blocks[index] = Some(block);
+                index
+            }
+            None => {
+                blocks.push(Some(block));
+                blocks.len() - 1
+            }
+        };
+        self.stats.block_count += 1;
+        self.stats.reserved_bytes += block_size;
+        self.stats.allocation_count += 1;
+        self.stats.used_bytes += size;
+        Ok(Allocation {
+            memory,
+            offset,
+            size,
+            memory_type_index,
+            mapped_ptr: mapped_ptr.map(|ptr| ptr.add(offset as usize)),
+            block: Some(BlockId {
+                memory_type_index,
+                kind: desc.kind,
+                index,
+            }),
+        })
+    }
+
+    /// # Safety
+    ///
+    /// `allocation`はこのアロケーターから割り当てられ、まだ解放されておらず、GPUが使用中でないこと。
+    pub unsafe fn free(&mut self, device: &Device, allocation: Allocation) {
+        self.stats.allocation_count -= 1;
+        self.stats.used_bytes -= allocation.size;
+
+        let Some(block_id) = allocation.block else {
+            if let Some(index) = self
+                .dedicated
+                .iter()
+                .position(|&memory| memory == allocation.memory)
+            {
+                self.dedicated.swap_remove(index);
+                self.stats.dedicated_count -= 1;
+                self.stats.reserved_bytes -= allocation.size;
+            }
+            AshMemoryDevice::wrap(device).deallocate_memory(allocation.memory);
+            return;
+        };
+
+        let Some(slot) = self
+            .blocks
+            .get_mut(&(block_id.memory_type_index, block_id.kind))
+            .and_then(|blocks| blocks.get_mut(block_id.index))
+        else {
+            return;
+        };
+        let Some(block) = slot else {
+            return;
+        };
+        block.free(allocation.offset, allocation.size);
+        // 空になったブロックはすぐに返却する
+        if block.is_empty() {
+            let block = slot.take().unwrap();
+            self.stats.block_count -= 1;
+            self.stats.reserved_bytes -= block.size;
+            AshMemoryDevice::wrap(device).deallocate_memory(block.memory);
+        }
+    }
+
+    /// 残っているメモリをすべて解放する
+    ///
+    /// # Safety
+    ///
+    /// 割り当てたメモリをGPUが使用中でないこと。
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        let memory_device = AshMemoryDevice::wrap(device);
+        for (_, blocks) in self.blocks.drain() {
+            for block in blocks.into_iter().flatten() {
+                memory_device.deallocate_memory(block.memory);
+            }
+        }
+        for memory in self.dedicated.drain(..) {
+            memory_device.deallocate_memory(memory);
+        }
+        self.stats = MemoryStats::default();
+    }
+
+    fn block_size(&self, memory_type_index: u32) -> vk::DeviceSize {
+        let heap_index = self.memory_properties.memory_types[memory_type_index as usize].heap_index;
+        let heap_size = self.memory_properties.memory_heaps[heap_index as usize].size;
+        DEFAULT_BLOCK_SIZE.min(heap_size / MAX_BLOCK_HEAP_FRACTION)
+    }
+
+    unsafe fn allocate_dedicated(
+        &mut self,
+        device: &Device,
+        memory_type_index: u32,
+        size: vk::DeviceSize,
+        resource: Option<DedicatedResource>,
+    ) -> Result<Allocation> {
+        let resource = resource.filter(|_| self.dedicated_allocation);
+        let (memory, mapped_ptr) =
+            self.allocate_memory(device, memory_type_index, size, resource)?;
+        self.dedicated.push(memory);
+        self.stats.dedicated_count += 1;
+        self.stats.reserved_bytes += size;
+        self.stats.allocation_count += 1;
+        self.stats.used_bytes += size;
+        Ok(Allocation {
+            memory,
+            offset: 0,
+            size,
+            memory_type_index,
+            mapped_ptr,
+            block: None,
+        })
+    }
+
+    /// `maxMemoryAllocationCount`に達していればエラーにする
+    fn check_allocation_count(&self) -> Result<()> {
+        let count = self.stats.block_count + self.stats.dedicated_count;
+        if count >= self.max_memory_allocation_count as usize {
+            return Err(RendererError::Vulkan(vk::Result::ERROR_TOO_MANY_OBJECTS));
+        }
+        Ok(())
+    }
+
+    /// メモリを確保し、HOST_VISIBLEなら永続マップする
+    unsafe fn allocate_memory(
+        &self,
+        device: &Device,
+        memory_type_index: u32,
+        size: vk::DeviceSize,
+        resource: Option<DedicatedResource>,
+    ) -> Result<(vk::DeviceMemory, Option<*mut u8>)> {
+        // `AshMemoryDevice`は上限を超えるとパニックするので先に確かめる
+        self.check_allocation_count()?;
+        let memory_device = AshMemoryDevice::wrap(device);
+        let mut memory = match resource {
+            Some(resource) => allocate_dedicated_memory(device, memory_type_index, size, resource)?,
+            None => memory_device
+                .allocate_memory(size, memory_type_index, AllocationFlags::empty())
+                .map_err(out_of_memory)?,
+        };
+        let flags = self.memory_properties.memory_types[memory_type_index as usize].property_flags;
+        if !flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+            return Ok((memory, None));
+        }
+        // 同じメモリを何度もマップできないので、確保時にまとめて永続マップしておく
+        match memory_device.map_memory(&mut memory, 0, size) {
+            Ok(ptr) => Ok((memory, Some(ptr.as_ptr()))),
+            Err(err) => {
+                memory_device.deallocate_memory(memory);
+                Err(map_error(err))
+            }
+        }
+    }
+}
+
+unsafe fn allocate_dedicated_memory(
+    device: &Device,
+    memory_type_index: u32,
+    size: vk::DeviceSize,
+    resource: DedicatedResource,
+) -> Result<vk::DeviceMemory> {
+    let mut dedicated_info = vk::MemoryDedicatedAllocateInfo::builder();
+    match resource {
+        DedicatedResource::Buffer(buffer) => dedicated_info = dedicated_info.buffer(buffer),
+        DedicatedResource::Image(image) => dedicated_info = dedicated_info.image(image),
+    }
+    let allocate_info = vk::MemoryAllocateInfo::builder()
+        .allocation_size(size)
+        .memory_type_index(memory_type_index)
+        .push_next(&mut dedicated_info);
+    Ok(device.allocate_memory(&allocate_info, None)?)
+}
+
+fn out_of_memory(err: OutOfMemory) -> RendererError {
+    RendererError::Vulkan(match err {
+        OutOfMemory::OutOfDeviceMemory => vk::Result::ERROR_OUT_OF_DEVICE_MEMORY,
+        OutOfMemory::OutOfHostMemory => vk::Result::ERROR_OUT_OF_HOST_MEMORY,
+    })
+}
+
+fn map_error(err: DeviceMapError) -> RendererError {
+    RendererError::Vulkan(match err {
+        DeviceMapError::OutOfDeviceMemory => vk::Result::ERROR_OUT_OF_DEVICE_MEMORY,
+        DeviceMapError::OutOfHostMemory => vk::Result::ERROR_OUT_OF_HOST_MEMORY,
+        DeviceMapError::MapFailed => vk::Result::ERROR_MEMORY_MAP_FAILED,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gpu_alloc_types::{MemoryHeap, MemoryPropertyFlags, MemoryType};
+
+    fn empty_block(size: vk::DeviceSize) -> MemoryBlock {
+        MemoryBlock {
+            memory: vk::DeviceMemory::null(),
+            size,
+            mapped_ptr: None,
+            free_ranges: vec![(0, size)],
+            allocation_count: 0,
+        }
+    }
+
+    #[test]
+    fn allocate_splits_free_range() {
+        let mut block = empty_block(1024);
+        assert_eq!(block.allocate(100, 1), Some(0));
+        assert_eq!(block.allocate(100, 1), Some(100));
+        assert_eq!(block.free_ranges, vec![(200, 824)]);
+        assert_eq!(block.allocation_count, 2);
+    }
+
+    #[test]
+    fn allocate_respects_alignment_and_keeps_gap() {
+        let mut block = empty_block(1024);
+        assert_eq!(block.allocate(10, 1), Some(0));
+        assert_eq!(block.allocate(16, 256), Some(256));
+        assert_eq!(block.free_ranges, vec![(10, 246), (272, 752)]);
+        // アライメントで生じた隙間は後の小さな割り当てに使われる
+        assert_eq!(block.allocate(8, 4), Some(12));
+        assert_eq!(block.free_ranges, vec![(10, 2), (20, 236), (272, 752)]);
+    }
+
+    #[test]
+    fn allocate_treats_zero_alignment_as_one() {
+        let mut block = empty_block(64);
+        assert_eq!(block.allocate(3, 0), Some(0));
+        assert_eq!(block.allocate(3, 0), Some(3));
+    }
+
+    #[test]
+    fn allocate_fails_when_nothing_fits() {
+        let mut block = empty_block(256);
+        assert_eq!(block.allocate(200, 1), Some(0));
+        assert_eq!(block.allocate(64, 1), None);
+        // 空きは足りてもアライメントを満たせない
+        assert_eq!(block.allocate(32, 128), None);
+        assert_eq!(block.free_ranges, vec![(200, 56)]);
+    }
+
+    #[test]
+    fn free_merges_adjacent_ranges() {
+        let mut block = empty_block(300);
+        let a = block.allocate(100, 1).unwrap();
+        let b = block.allocate(100, 1).unwrap();
+        let c = block.allocate(100, 1).unwrap();
+        assert!(block.free_ranges.is_empty());
+
+        block.free(b, 100);
+        assert_eq!(block.free_ranges, vec![(100, 100)]);
+        // 後ろの空きと結合する
+        block.free(a, 100);
+        assert_eq!(block.free_ranges, vec![(0, 200)]);
+        // 前の空きと結合する
+        block.free(c, 100);
+        assert_eq!(block.free_ranges, vec![(0, 300)]);
+        assert!(block.is_empty());
+    }
+
+    #[test]
+    fn free_merges_both_neighbours() {
+        let mut block = empty_block(300);
+        let a = block.allocate(100, 1).unwrap();
+        let b = block.allocate(100, 1).unwrap();
+        let c = block.allocate(100, 1).unwrap();
+        block.free(a, 100);
+        block.free(c, 100);
+        assert_eq!(block.free_ranges, vec![(0, 100), (200, 100)]);
+        block.free(b, 100);
+        assert_eq!(block.free_ranges, vec![(0, 300)]);
+    }
+
+    /// デバイスローカルのヒープ0と、ホストから見えるヒープ1
+    fn device_properties() -> DeviceProperties<'static> {
+        DeviceProperties {
+            memory_types: vec![
+                MemoryType {
+                    props: MemoryPropertyFlags::DEVICE_LOCAL,
+                    heap: 0,
+                },
+                MemoryType {
+                    props: MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT,
+                    heap: 1,
+                },
+            ]
+            .into(),
+            memory_heaps: vec![
+                MemoryHeap {
+                    size: 8 * 1024 * 1024 * 1024,
+                },
+                MemoryHeap {
+                    size: 128 * 1024 * 1024,
+                },
+            ]
+            .into(),
+            max_memory_allocation_count: 4,
+            max_memory_allocation_size: u64::MAX,
+            non_coherent_atom_size: 64,
+            buffer_device_address: false,
+        }
+    }
+
+    #[test]
+    fn memory_properties_come_from_device_properties() {
+        let allocator = MemoryAllocator::new(&device_properties(), vk::API_VERSION_1_1);
+        let properties = allocator.memory_properties();
+        assert_eq!(properties.memory_type_count, 2);
+        assert_eq!(properties.memory_heap_count, 2);
+        assert_eq!(
+            properties.memory_types[0].property_flags,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL
+        );
+        assert_eq!(
+            properties.memory_types[1].property_flags,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT
+        );
+        assert_eq!(properties.memory_types[1].heap_index, 1);
+        assert_eq!(properties.memory_heaps[1].size, 128 * 1024 * 1024);
+        let requirements = vk::MemoryRequirements {
+            size: 256,
+            alignment: 256,
+            memory_type_bits: 0b11,
+        };
+        assert_eq!(
+            find_memorytype_index(
+                &requirements,
+                properties,
+                vk::MemoryPropertyFlags::HOST_VISIBLE
+            ),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn block_size_is_limited_by_heap_size() {
+        let allocator = MemoryAllocator::new(&device_properties(), vk::API_VERSION_1_1);
+        assert_eq!(allocator.block_size(0), DEFAULT_BLOCK_SIZE);
+        assert_eq!(allocator.block_size(1), 16 * 1024 * 1024);
+    }
+
+    #[test]
+    fn allocation_count_is_limited() {
+        let mut allocator = MemoryAllocator::new(&device_properties(), vk::API_VERSION_1_1);
+        allocator.stats.block_count = 3;
+        assert!(allocator.check_allocation_count().is_ok());
+        // ブロックと専用割り当ての合計で数える
+        allocator.stats.dedicated_count = 1;
+        assert!(matches!(
+            allocator.check_allocation_count(),
+            Err(RendererError::Vulkan(vk::Result::ERROR_TOO_MANY_OBJECTS))
+        ));
+    }
+}
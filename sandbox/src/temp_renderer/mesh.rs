@@ -0,0 +1,397 @@
+use super::error::{RendererError, Result};
+use super::{Aabb, BufferId, GraphicsPipeline, Handle, PipelineBuilder, Renderer};
+use ash::vk;
+use std::ops::Range;
+
+/// 頂点属性1つ分の、シェーダーのロケーションと頂点内のオフセット
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VertexAttribute {
+    pub location: u32,
+    pub format: vk::Format,
+    pub offset: u32,
+}
+
+/// インターリーブされた頂点1つのレイアウト
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VertexLayout {
+    pub stride: u32,
+    pub attributes: Vec<VertexAttribute>,
+}
+
+impl VertexLayout {
+    pub fn new(stride: u32) -> Self {
+        Self {
+            stride,
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn attribute(mut self, location: u32, format: vk::Format, offset: u32) -> Self {
+        self.attributes.push(VertexAttribute {
+            location,
+            format,
+            offset,
+        });
+        self
+    }
+}
+
+impl PipelineBuilder {
+    /// `layout`を頂点入力のバインディング`binding`として追加する
+    pub fn vertex_layout(self, binding: u32, layout: &VertexLayout) -> Self {
+        let builder = self.vertex_binding(binding, layout.stride, vk::VertexInputRate::VERTEX);
+        layout
+            .attributes
+            .iter()
+            .fold(builder, |builder, attribute| {
+                builder.vertex_attribute(
+                    attribute.location,
+                    binding,
+                    attribute.format,
+                    attribute.offset,
+                )
+            })
+    }
+}
+
+/// メッシュの中で1回のドローコールで描く範囲
+///
+/// インデックス付きのメッシュではインデックスの範囲、そうでなければ頂点の範囲を表す。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Submesh {
+    pub range: Range<u32>,
+    /// インデックスに加算する値
+    pub vertex_offset: i32,
+}
+
+/// 頂点バッファと、あればインデックスバッファ、サブメッシュの組
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mesh {
+    pub vertex_buffer: BufferId,
+    pub index_buffer: Option<BufferId>,
+    pub index_type: vk::IndexType,
+    pub vertex_layout: VertexLayout,
+    pub vertex_count: u32,
+    pub index_count: u32,
+    pub submeshes: Vec<Submesh>,
+    /// 作成時に頂点の位置から求めたローカル空間の箱。`None`ならカリングしない
+    pub bounds: Option<Aabb>,
+}
+
+pub type MeshId = Handle<Mesh>;
+
+impl Mesh {
+    /// サブメッシュを差し替える。範囲がメッシュに収まらない場合はエラーにする
+    pub fn set_submeshes(&mut self, submeshes: Vec<Submesh>) -> Result<()> {
+        let count = if self.index_buffer.is_some() {
+            self.index_count
+        } else {
+            self.vertex_count
+        };
+        if let Some(submesh) = submeshes
+            .iter()
+            .find(|submesh| submesh.range.start > submesh.range.end || submesh.range.end > count)
+        {
+            return Err(RendererError::Validation(format!(
+                "submesh range {:?} is outside the mesh's {} elements",
+                submesh.range, count
+            )));
+        }
+        self.submeshes = submeshes;
+        Ok(())
+    }
+}
+
+impl Renderer {
+    /// 頂点とインデックスをデバイスローカルのバッファに転送してメッシュを作る
+    ///
+    /// `indices`が`None`ならインデックスなしで描画する。全体を1つのサブメッシュとする。
+    pub fn create_mesh<V: Copy>(
+        &mut self,
+        vertices: &[V],
+        indices: Option<&[u32]>,
+        vertex_layout: VertexLayout,
+    ) -> Result<MeshId> {
+        self.create_mesh_with_usage(
+            vertices,
+            indices,
+            vertex_layout,
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+        )
+    }
+
+    /// `create_mesh`と同じだが、頂点バッファを`vertex_usage`で作る
+    pub(crate) fn create_mesh_with_usage<V: Copy>(
+        &mut self,
+        vertices: &[V],
+        indices: Option<&[u32]>,
+        vertex_layout: VertexLayout,
+        vertex_usage: vk::BufferUsageFlags,
+    ) -> Result<MeshId> {
+        if vertex_layout.stride as usize != std::mem::size_of::<V>() {
+            return Err(RendererError::Validation(format!(
+                "vertex layout stride {} does not match vertex size {}",
+                vertex_layout.stride,
+                std::mem::size_of::<V>()
+            )));
+        }
+        let vertex_count = vertices.len() as u32;
+        if let Some(index) = indices
+            .into_iter()
+            .flatten()
+            .find(|&&index| index >= vertex_count)
+        {
+            return Err(RendererError::Validation(format!(
+                "index {} is out of range for {} vertices",
+                index, vertex_count
+            )));
+        }
+
+        let vertex_buffer = self.create_buffer_with_data(vertices, vertex_usage)?;
+        let index_buffer = match indices {
+            Some(indices) => {
+                match self.create_buffer_with_data(indices, vk::BufferUsageFlags::INDEX_BUFFER) {
+                    Ok(index_buffer) => Some(index_buffer),
+                    Err(err) => {
+                        self.destroy_buffer(vertex_buffer)?;
+                        return Err(err);
+                    }
+                }
+            }
+            None => None,
+        };
+        let index_count = indices.map_or(0, |indices| indices.len() as u32);
+        let count = if index_buffer.is_some() {
+            index_count
+        } else {
+            vertex_count
+        };
+
+        let bounds = Aabb::from_vertices(vertices, &vertex_layout);
+        Ok(self.meshes.insert(Mesh {
+            vertex_buffer,
+            index_buffer,
+            index_type: vk::IndexType::UINT32,
+            vertex_layout,
+            vertex_count,
+            index_count,
+            submeshes: vec![Submesh {
+                range: 0..count,
+                vertex_offset: 0,
+            }],
+            bounds,
+        }))
+    }
+
+    pub fn mesh(&self, id: MeshId) -> Option<&Mesh> {
+        self.meshes.get(id)
+    }
+
+    /// `Mesh::set_submeshes`でサブメッシュを差し替えるのに使う
+    pub fn mesh_mut(&mut self, id: MeshId) -> Option<&mut Mesh> {
+        self.meshes.get_mut(id)
+    }
+
+    /// 使用中のフレームが完了してからメッシュのバッファを破棄する
+    pub fn destroy_mesh(&mut self, id: MeshId) -> Result<()> {
+        let mesh = self.meshes.remove(id).ok_or_else(|| {
+            RendererError::Validation(format!("mesh {:?} was already destroyed", id))
+        })?;
+        self.destroy_buffer(mesh.vertex_buffer)?;
+        if let Some(index_buffer) = mesh.index_buffer {
+            self.destroy_buffer(index_buffer)?;
+        }
+        Ok(())
+    }
+
+    /// 記録中のフレームに、メッシュのすべてのサブメッシュを描くコマンドを記録する
+    ///
+    /// `descriptor_sets`はセット0から順にバインドし、`push_constants`はオフセット0から
+    /// パイプラインのプッシュ定数の範囲に書き込む。`begin_frame`と`end_frame`の間で、
+    /// パイプラインと互換性のあるレンダーパスもしくは動的レンダリングの中で呼ぶこと。
+    pub fn draw_mesh(
+        &self,
+        mesh: MeshId,
+        pipeline: &GraphicsPipeline,
+        descriptor_sets: &[vk::DescriptorSet],
+        push_constants: &[u8],
+    ) -> Result<()> {
+        let mesh = self.live_mesh(mesh)?;
+        self.record_mesh_draw(
+            mesh,
+            0..mesh.submeshes.len(),
+            pipeline,
+            descriptor_sets,
+            push_constants,
+        )
+    }
+
+    /// `draw_mesh`と同じだが、`submesh`番目のサブメッシュだけを描く
+    pub fn draw_submesh(
+        &self,
+        mesh: MeshId,
+        submesh: usize,
+        pipeline: &GraphicsPipeline,
+        descriptor_sets: &[vk::DescriptorSet],
+        push_constants: &[u8],
+    ) -> Result<()> {
+        let mesh = self.live_mesh(mesh)?;
+        if submesh >= mesh.submeshes.len() {
+            return Err(RendererError::Validation(format!(
+                "submesh {} is out of range for a mesh with {} submeshes",
+                submesh,
+                mesh.submeshes.len()
+            )));
+        }
+        self.record_mesh_draw(
+            mesh,
+            submesh..submesh + 1,
+            pipeline,
+            descriptor_sets,
+            push_constants,
+        )
+    }
+
+    /// 破棄されていないメッシュ。破棄済みならエラーにする
+    pub(crate) fn live_mesh(&self, id: MeshId) -> Result<&Mesh> {
+        self.meshes
+            .get(id)
+            .ok_or_else(|| RendererError::Validation(format!("mesh {:?} was destroyed", id)))
+    }
+
+    fn record_mesh_draw(
+        &self,
+        mesh: &Mesh,
+        submeshes: Range<usize>,
+        pipeline: &GraphicsPipeline,
+        descriptor_sets: &[vk::DescriptorSet],
+        push_constants: &[u8],
+    ) -> Result<()> {
+        let command_buffer = self.bind_mesh(mesh, pipeline, descriptor_sets, push_constants)?;
+        let indexed = mesh.index_buffer.is_some();
+        unsafe {
+            for submesh in mesh.submeshes[submeshes].iter() {
+                let count = submesh.range.end - submesh.range.start;
+                if indexed {
+                    self.device.cmd_draw_indexed(
+                        command_buffer,
+                        count,
+                        1,
+                        submesh.range.start,
+                        submesh.vertex_offset,
+                        0,
+                    );
+                } else {
+                    self.device
+                        .cmd_draw(command_buffer, count, 1, submesh.range.start, 0);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 記録中のフレームにパイプライン、デスクリプタセット、プッシュ定数とメッシュのバッファを
+    /// バインドし、そのコマンドバッファを返す
+    pub(crate) fn bind_mesh(
+        &self,
+        mesh: &Mesh,
+        pipeline: &GraphicsPipeline,
+        descriptor_sets: &[vk::DescriptorSet],
+        push_constants: &[u8],
+    ) -> Result<vk::CommandBuffer> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "meshes can only be drawn between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        let push_constant_end = pipeline
+            .push_constant_ranges
+            .iter()
+            .map(|range| range.offset + range.size)
+            .max()
+            .unwrap_or(0);
+        if !push_constants.len().is_multiple_of(4)
+            || push_constants.len() as u32 > push_constant_end
+        {
+            return Err(RendererError::Validation(format!(
+                "{} bytes of push constants must be a multiple of 4 within the pipeline's {} bytes",
+                push_constants.len(),
+                push_constant_end
+            )));
+        }
+        let vertex_buffer = self.buffer(mesh.vertex_buffer).ok_or_else(|| {
+            RendererError::Validation(format!(
+                "mesh vertex buffer {:?} was destroyed",
+                mesh.vertex_buffer
+            ))
+        })?;
+        let index_buffer = match mesh.index_buffer {
+            Some(id) => Some(self.buffer(id).ok_or_else(|| {
+                RendererError::Validation(format!("mesh index buffer {:?} was destroyed", id))
+            })?),
+            None => None,
+        };
+
+        let command_buffer = self.frames[self.current_frame].recording_command_buffer();
+        unsafe {
+            self.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.pipeline,
+            );
+            if !descriptor_sets.is_empty() {
+                self.device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    pipeline.layout,
+                    0,
+                    descriptor_sets,
+                    &[],
+                );
+            }
+            self.push_constants(command_buffer, pipeline, push_constants);
+            self.device
+                .cmd_bind_vertex_buffers(command_buffer, 0, &[vertex_buffer.buffer], &[0]);
+            if let Some(index_buffer) = index_buffer {
+                self.device.cmd_bind_index_buffer(
+                    command_buffer,
+                    index_buffer.buffer,
+                    0,
+                    mesh.index_type,
+                );
+            }
+        }
+        Ok(command_buffer)
+    }
+
+    /// プッシュ定数の範囲ごとに、その範囲と重なる範囲のステージをまとめて書き込む
+    pub(crate) unsafe fn push_constants(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        pipeline: &GraphicsPipeline,
+        data: &[u8],
+    ) {
+        let end = data.len() as u32;
+        for range in pipeline.push_constant_ranges.iter() {
+            let start = range.offset;
+            let stop = (range.offset + range.size).min(end);
+            if start >= stop {
+                continue;
+            }
+            let stages = pipeline
+                .push_constant_ranges
+                .iter()
+                .filter(|other| other.offset < stop && start < other.offset + other.size)
+                .fold(vk::ShaderStageFlags::empty(), |stages, other| {
+                    stages | other.stage_flags
+                });
+            self.device.cmd_push_constants(
+                command_buffer,
+                pipeline.layout,
+                stages,
+                start,
+                &data[start as usize..stop as usize],
+            );
+        }
+    }
+}
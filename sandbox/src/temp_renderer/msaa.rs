@@ -0,0 +1,96 @@
+use super::error::Result;
+use super::memory::{Allocation, MemoryAllocator};
+use super::texture::create_image;
+use ash::{vk, Device, Instance};
+
+/// スワップチェインに解決するマルチサンプルのカラーバッファ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsaaColorTarget {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    pub allocation: Allocation,
+}
+
+impl MsaaColorTarget {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのイメージを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        device.destroy_image_view(self.view, None);
+        device.destroy_image(self.image, None);
+        allocator.free(device, self.allocation);
+    }
+}
+
+/// `requested`以下で、カラーと深度の両方のフレームバッファが対応する最大のサンプル数
+pub(crate) unsafe fn choose_sample_count(
+    instance: &Instance,
+    pdevice: vk::PhysicalDevice,
+    requested: vk::SampleCountFlags,
+) -> vk::SampleCountFlags {
+    let limits = instance.get_physical_device_properties(pdevice).limits;
+    let supported = limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+    [
+        vk::SampleCountFlags::TYPE_64,
+        vk::SampleCountFlags::TYPE_32,
+        vk::SampleCountFlags::TYPE_16,
+        vk::SampleCountFlags::TYPE_8,
+        vk::SampleCountFlags::TYPE_4,
+        vk::SampleCountFlags::TYPE_2,
+    ]
+    .into_iter()
+    .find(|&samples| samples.as_raw() <= requested.as_raw() && supported.contains(samples))
+    .unwrap_or(vk::SampleCountFlags::TYPE_1)
+}
+
+/// `samples`が1より大きければ、解像度に合わせたマルチサンプルのカラーバッファを作る
+pub(crate) unsafe fn create_msaa_color_target(
+    device: &Device,
+    allocator: &mut MemoryAllocator,
+    extent: vk::Extent2D,
+    format: vk::Format,
+    samples: vk::SampleCountFlags,
+) -> Result<Option<MsaaColorTarget>> {
+    if samples == vk::SampleCountFlags::TYPE_1 {
+        return Ok(None);
+    }
+    let image_create_info = *vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(format)
+        .extent(extent.into())
+        .mip_levels(1)
+        .array_layers(1)
+        .samples(samples)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        // 解決した後は使わないので、対応していれば遅延割り当てのメモリに置かれる
+        .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+    let (image, allocation) = create_image(
+        device,
+        allocator,
+        &image_create_info,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        true,
+    )?;
+    let view_create_info = *vk::ImageViewCreateInfo::builder()
+        .image(image)
+        .view_type(vk::ImageViewType::TYPE_2D)
+        .format(format)
+        .subresource_range(
+            *vk::ImageSubresourceRange::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .level_count(1)
+                .layer_count(1),
+        );
+    match device.create_image_view(&view_create_info, None) {
+        Ok(view) => Ok(Some(MsaaColorTarget {
+            image,
+            view,
+            allocation,
+        })),
+        Err(err) => {
+            device.destroy_image(image, None);
+            allocator.free(device, allocation);
+            Err(err.into())
+        }
+    }
+}
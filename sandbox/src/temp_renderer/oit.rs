@@ -0,0 +1,556 @@
+use super::descriptor::{allocate_set, create_pool};
+use super::error::{RendererError, Result};
+use super::material::{AlphaMode, MaterialId};
+use super::memory::MemoryAllocator;
+use super::renderer::depth_aspect_mask;
+use super::texture::{create_screen_image, ScreenImage};
+use super::{
+    BlendMode, DescriptorBinding, DescriptorWriter, GraphicsPipeline, MeshId, PipelineBuilder,
+    Renderer, RenderingAttachment, SamplerDesc, ShaderId, HDR_FORMAT,
+};
+use ash::{vk, Device};
+
+/// 透明な物の重み付きの色の合計。RGBに色、アルファに不透明度の合計
+pub const OIT_ACCUMULATION_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+/// 透明な物を通して奥が見える割合
+pub const OIT_REVEALAGE_FORMAT: vk::Format = vk::Format::R16_SFLOAT;
+
+/// 重み付きブレンドの透明を合成するシェーダー
+///
+/// 頂点バッファなしで画面全体を覆う三角形を描き、HDRのレンダーターゲットにアルファブレンドする。
+///
+/// ```glsl
+/// // 頂点シェーダーはトーンマッピングと同じ
+///
+/// // フラグメントシェーダー
+/// layout(set = 0, binding = 0) uniform sampler2D accumulation;
+/// layout(set = 0, binding = 1) uniform sampler2D revealage;
+/// layout(location = 0) out vec4 color;
+/// void main() {
+///     ivec2 p = ivec2(gl_FragCoord.xy);
+///     float r = texelFetch(revealage, p, 0).r;
+///     if (r >= 1.0) discard;
+///     vec4 a = texelFetch(accumulation, p, 0);
+///     color = vec4(a.rgb / max(a.a, 1e-5), 1.0 - r);
+/// }
+/// ```
+///
+/// 透明なマテリアルのフラグメントシェーダーは、ロケーション0に重み付きの色、1に不透明度を
+/// 出力する。`tempura/oit.glsl`の`tempura_oit_output`を使うと重みを計算できる。
+///
+/// ```glsl
+/// #include "tempura/oit.glsl"
+/// layout(location = 0) out vec4 accumulation;
+/// layout(location = 1) out float revealage;
+/// void main() {
+///     vec4 c = shade(); // ライティングした色とベースカラーのアルファ
+///     tempura_oit_output(c, gl_FragCoord.z, accumulation, revealage);
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OitShaders {
+    pub vertex: ShaderId,
+    pub composite: ShaderId,
+}
+
+/// HDRのターゲットに合わせて作り直す透明のターゲット
+pub struct OitTargets {
+    pub accumulation: ScreenImage,
+    pub revealage: ScreenImage,
+    pub descriptor_pool: vk::DescriptorPool,
+    /// `oit_set_layout`のデスクリプタセット
+    pub descriptor_set: vk::DescriptorSet,
+    pub extent: vk::Extent2D,
+}
+
+impl OitTargets {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        device.destroy_descriptor_pool(self.descriptor_pool, None);
+        self.revealage.destroy(device, allocator);
+        self.accumulation.destroy(device, allocator);
+    }
+
+    fn images(&self) -> [vk::Image; 2] {
+        [self.accumulation.image, self.revealage.image]
+    }
+}
+
+/// `draw_material_mesh`で使うパイプライン
+#[derive(Clone, Copy)]
+pub struct MaterialPipelines<'a> {
+    /// 不透明なマテリアルと、OITが無効な場合の半透明なマテリアルで使う
+    pub main: &'a GraphicsPipeline,
+    /// `create_oit_pipeline`で作ったパイプライン。OITが有効なら半透明なマテリアルで使う
+    pub transparent: Option<&'a GraphicsPipeline>,
+}
+
+/// `resolve_transparency`まで記録を遅らせる半透明な物の描画
+struct TransparentDraw {
+    mesh: MeshId,
+    /// 借りたパイプラインのハンドルの写し。破棄はしない
+    pipeline: GraphicsPipeline,
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    push_constants: Vec<u8>,
+}
+
+/// `enable_oit`で作る合成のパイプラインと透明のターゲット
+pub struct Oit {
+    pub composite_pipeline: GraphicsPipeline,
+    pub sampler: vk::Sampler,
+    pub targets: OitTargets,
+    draws: Vec<TransparentDraw>,
+    /// `draws`を積んだフレームの`frame_count`
+    frame: u64,
+}
+
+impl Oit {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        self.composite_pipeline.destroy(device);
+        device.destroy_sampler(self.sampler, None);
+        self.targets.destroy(device, allocator);
+    }
+
+    /// 今のフレームで`resolve_transparency`を待っている描画の数
+    pub fn pending_draws(&self) -> usize {
+        self.draws.len()
+    }
+}
+
+impl Renderer {
+    /// 透明のターゲットのデスクリプタセットレイアウト。バインディング0が色の合計、1が透過率
+    pub fn oit_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        let stage = vk::ShaderStageFlags::FRAGMENT;
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, stage),
+            DescriptorBinding::sampled_image(1, stage),
+        ])
+    }
+
+    /// 重み付きブレンドの順序によらない透明(OIT)を有効にする
+    ///
+    /// 動的レンダリングが必要で、MSAAとは併用できない。`enable_hdr`の後で呼ぶ。
+    /// すでに有効な場合は作り直す。
+    pub fn enable_oit(&mut self, shaders: OitShaders) -> Result<()> {
+        if self.dynamic_rendering.is_none() {
+            return Err(RendererError::Validation(
+                "order-independent transparency needs dynamic rendering".to_owned(),
+            ));
+        }
+        if self.msaa_samples != vk::SampleCountFlags::TYPE_1 {
+            return Err(RendererError::Validation(
+                "order-independent transparency does not support MSAA".to_owned(),
+            ));
+        }
+        self.enabled_hdr()?;
+        let layout = self.oit_set_layout()?;
+        let builder = PipelineBuilder::new()
+            .vertex_shader(self.shader_module(shaders.vertex)?)
+            .fragment_shader(self.shader_module(shaders.composite)?)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .depth_test(false)
+            .depth_write(false)
+            .blend_mode(BlendMode::AlphaBlend)
+            .descriptor_set_layouts(&[layout])
+            .rendering_formats(&[HDR_FORMAT], vk::Format::UNDEFINED);
+        let pipeline = self.create_graphics_pipeline(&builder, vk::RenderPass::null(), 0)?;
+        let resources = self
+            .create_sampler(&SamplerDesc::nearest())
+            .and_then(
+                |sampler| match unsafe { self.create_oit_targets(sampler) } {
+                    Ok(targets) => Ok((sampler, targets)),
+                    Err(err) => {
+                        unsafe { self.device.destroy_sampler(sampler, None) };
+                        Err(err)
+                    }
+                },
+            );
+        let (sampler, targets) = match resources {
+            Ok(resources) => resources,
+            Err(err) => {
+                self.destroy_graphics_pipeline(pipeline);
+                return Err(err);
+            }
+        };
+        let oit = Oit {
+            composite_pipeline: pipeline,
+            sampler,
+            targets,
+            draws: Vec::new(),
+            frame: self.frame_count,
+        };
+        if let Some(old) = self.oit.replace(oit) {
+            self.destroy_deferred(move |device, allocator| unsafe {
+                old.destroy(device, allocator)
+            });
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してから透明のリソースを破棄する。半透明な物はメインのパスで描く
+    pub fn disable_oit(&mut self) {
+        if let Some(oit) = self.oit.take() {
+            self.destroy_deferred(move |device, allocator| unsafe {
+                oit.destroy(device, allocator)
+            });
+        }
+    }
+
+    pub fn oit(&self) -> Option<&Oit> {
+        self.oit.as_ref()
+    }
+
+    /// 半透明なマテリアルのパイプラインを作成する
+    ///
+    /// レイアウトやプッシュ定数は`pbr_pipeline_builder`のビルダーをそのまま使える。深度は
+    /// テストだけして書き込まない。
+    pub fn create_oit_pipeline(&self, builder: &PipelineBuilder) -> Result<GraphicsPipeline> {
+        self.enabled_oit()?;
+        let builder = builder
+            .clone()
+            .depth_test(true)
+            .depth_write(false)
+            .color_attachments(&[BlendMode::Additive, BlendMode::Revealage])
+            .rendering_formats(
+                &[OIT_ACCUMULATION_FORMAT, OIT_REVEALAGE_FORMAT],
+                self.depth_format,
+            );
+        self.create_graphics_pipeline(&builder, vk::RenderPass::null(), 0)
+    }
+
+    /// マテリアルのアルファの扱いに合わせてメッシュを描く
+    ///
+    /// マテリアルのデスクリプタセットをセット0に、`descriptor_sets`をセット1以降にバインドする。
+    /// 半透明なマテリアルはOITが有効なら記録せずに積んでおき、`resolve_transparency`で
+    /// まとめて描く。それ以外は`draw_mesh`と同じく、すぐに`pipelines.main`で描く。
+    pub fn draw_material_mesh(
+        &mut self,
+        mesh: MeshId,
+        material: MaterialId,
+        pipelines: MaterialPipelines,
+        descriptor_sets: &[vk::DescriptorSet],
+        push_constants: &[u8],
+    ) -> Result<()> {
+        let material = self.materials.get(material).ok_or_else(|| {
+            RendererError::Validation(format!("material {:?} was already destroyed", material))
+        })?;
+        let sets: Vec<_> = std::iter::once(material.descriptor_set)
+            .chain(descriptor_sets.iter().copied())
+            .collect();
+        if material.desc.alpha_mode() != AlphaMode::Blend || self.oit.is_none() {
+            return self.draw_mesh(mesh, pipelines.main, &sets, push_constants);
+        }
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "meshes can only be drawn between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        self.live_mesh(mesh)?;
+        let transparent = pipelines.transparent.ok_or_else(|| {
+            RendererError::Validation(
+                "a blended material needs a transparent pipeline while OIT is enabled".to_owned(),
+            )
+        })?;
+        let frame_count = self.frame_count;
+        let oit = self.oit.as_mut().unwrap();
+        // 前のフレームで合成しなかった描画は捨てる
+        if oit.frame != frame_count {
+            oit.draws.clear();
+            oit.frame = frame_count;
+        }
+        oit.draws.push(TransparentDraw {
+            mesh,
+            pipeline: GraphicsPipeline {
+                pipeline: transparent.pipeline,
+                layout: transparent.layout,
+                render_pass: transparent.render_pass,
+                subpass: transparent.subpass,
+                push_constant_ranges: transparent.push_constant_ranges.clone(),
+            },
+            descriptor_sets: sets,
+            push_constants: push_constants.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// 積んでおいた半透明な物を透明のターゲットに描き、HDRのレンダーターゲットに合成する
+    ///
+    /// `end_hdr_rendering`か`resolve_deferred_lighting`の後、ポストプロセスの前に呼ぶ。
+    /// 不透明な物の深度でテストするので、半透明な物は不透明な物に隠れる。終わると
+    /// HDRのレンダーターゲットは`end_hdr_rendering`の後と同じ状態になる。
+    pub fn resolve_transparency(&mut self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        let frame_count = self.frame_count;
+        let Some(oit) = self.oit.as_mut() else {
+            return Ok(());
+        };
+        let draws = std::mem::take(&mut oit.draws);
+        if oit.frame != frame_count || draws.is_empty() {
+            return Ok(());
+        }
+        let oit = self.enabled_oit()?;
+        let hdr = self.enabled_hdr()?;
+        let targets = &oit.targets;
+        let color_range = color_subresource_range();
+        let depth_range = vk::ImageSubresourceRange {
+            aspect_mask: depth_aspect_mask(self.depth_format),
+            ..color_range
+        };
+        let depth_layout = vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL;
+        // 前のフレームの合成での読み込みと、不透明な物の深度の書き込みを待つ
+        let mut barriers: Vec<_> = targets
+            .images()
+            .into_iter()
+            .map(|image| {
+                *vk::ImageMemoryBarrier::builder()
+                    .image(image)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                    .subresource_range(color_range)
+            })
+            .collect();
+        barriers.push(
+            *vk::ImageMemoryBarrier::builder()
+                .image(self.depth_image)
+                .old_layout(depth_layout)
+                .new_layout(depth_layout)
+                .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                .dst_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ)
+                .subresource_range(depth_range),
+        );
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &barriers,
+            );
+        }
+        let clear = |float32| vk::ClearValue {
+            color: vk::ClearColorValue { float32 },
+        };
+        let colors = [
+            RenderingAttachment::clear(
+                targets.accumulation.view,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                clear([0.0; 4]),
+            ),
+            RenderingAttachment::clear(
+                targets.revealage.view,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                clear([1.0; 4]),
+            ),
+        ];
+        let depth = RenderingAttachment::load(self.depth_image_view, depth_layout);
+        let render_area = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: targets.extent,
+        };
+        self.begin_rendering(command_buffer, render_area, &colors, Some(depth))?;
+        let result = draws.iter().try_for_each(|draw| {
+            self.draw_mesh(
+                draw.mesh,
+                &draw.pipeline,
+                &draw.descriptor_sets,
+                &draw.push_constants,
+            )
+        });
+        self.end_rendering(command_buffer)?;
+        result?;
+
+        let mut barriers: Vec<_> = targets
+            .images()
+            .into_iter()
+            .map(|image| {
+                *vk::ImageMemoryBarrier::builder()
+                    .image(image)
+                    .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                    .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                    .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                    .subresource_range(color_range)
+            })
+            .collect();
+        barriers.push(
+            *vk::ImageMemoryBarrier::builder()
+                .image(hdr.target.image)
+                .old_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .src_access_mask(vk::AccessFlags::SHADER_READ)
+                .dst_access_mask(
+                    vk::AccessFlags::COLOR_ATTACHMENT_READ
+                        | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                )
+                .subresource_range(color_range),
+        );
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &barriers,
+            );
+        }
+        let color =
+            RenderingAttachment::load(hdr.target.view, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+        self.begin_rendering(command_buffer, render_area, &[color], None)?;
+        let pipeline = &oit.composite_pipeline;
+        unsafe {
+            self.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.pipeline,
+            );
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.layout,
+                0,
+                &[targets.descriptor_set],
+                &[],
+            );
+            self.device.cmd_draw(command_buffer, 3, 1, 0, 0);
+        }
+        self.end_rendering(command_buffer)?;
+
+        let to_read_only = *vk::ImageMemoryBarrier::builder()
+            .image(hdr.target.image)
+            .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .subresource_range(color_range);
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_read_only],
+            );
+        }
+        // 確保した領域を次のフレームで使い回す
+        let mut draws = draws;
+        draws.clear();
+        self.oit.as_mut().unwrap().draws = draws;
+        Ok(())
+    }
+
+    /// HDRのターゲットに合わせて透明のターゲットを作り直す
+    ///
+    /// 古いターゲットは使用中のフレームが完了してから破棄する。
+    pub(crate) fn recreate_oit_targets(&mut self) -> Result<()> {
+        let Some(sampler) = self.oit.as_ref().map(|oit| oit.sampler) else {
+            return Ok(());
+        };
+        let targets = unsafe { self.create_oit_targets(sampler)? };
+        let old = std::mem::replace(&mut self.oit.as_mut().unwrap().targets, targets);
+        self.destroy_deferred(move |device, allocator| unsafe { old.destroy(device, allocator) });
+        Ok(())
+    }
+
+    fn enabled_oit(&self) -> Result<&Oit> {
+        self.oit.as_ref().ok_or_else(|| {
+            RendererError::Validation("order-independent transparency is not enabled".to_owned())
+        })
+    }
+
+    /// # Safety
+    /// HDRが有効であること
+    unsafe fn create_oit_targets(&mut self, sampler: vk::Sampler) -> Result<OitTargets> {
+        let layout = self.oit_set_layout()?;
+        let extent = self.enabled_hdr()?.target.extent;
+        let usage = vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED;
+        let accumulation = create_screen_image(
+            &self.device,
+            &mut self.allocator,
+            OIT_ACCUMULATION_FORMAT,
+            usage,
+            extent,
+        )?;
+        let revealage = match create_screen_image(
+            &self.device,
+            &mut self.allocator,
+            OIT_REVEALAGE_FORMAT,
+            usage,
+            extent,
+        ) {
+            Ok(image) => image,
+            Err(err) => {
+                accumulation.destroy(&self.device, &mut self.allocator);
+                return Err(err);
+            }
+        };
+        let mut targets = OitTargets {
+            accumulation,
+            revealage,
+            descriptor_pool: vk::DescriptorPool::null(),
+            descriptor_set: vk::DescriptorSet::null(),
+            extent,
+        };
+        match self.init_oit_targets(&mut targets, layout, sampler) {
+            Ok(()) => Ok(targets),
+            Err(err) => {
+                targets.destroy(&self.device, &mut self.allocator);
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn init_oit_targets(
+        &self,
+        targets: &mut OitTargets,
+        layout: vk::DescriptorSetLayout,
+        sampler: vk::Sampler,
+    ) -> Result<()> {
+        targets.descriptor_pool = create_pool(
+            &self.device,
+            1,
+            &[(vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 2.0)],
+        )?;
+        targets.descriptor_set = allocate_set(&self.device, targets.descriptor_pool, layout)?;
+        let image_info = |image_view| vk::DescriptorImageInfo {
+            sampler,
+            image_view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        };
+        DescriptorWriter::new()
+            .image(
+                0,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                image_info(targets.accumulation.view),
+            )
+            .image(
+                1,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                image_info(targets.revealage.view),
+            )
+            .update(&self.device, targets.descriptor_set);
+        Ok(())
+    }
+}
+
+fn color_subresource_range() -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
@@ -0,0 +1,389 @@
+use super::camera::{mat4_inverse, Camera};
+use super::error::{RendererError, Result};
+use super::memory::MemoryAllocator;
+use super::renderer::create_depth_sampling_view;
+use super::texture::{create_screen_image, ScreenImage};
+use super::{
+    ComputePipeline, DescriptorBinding, DescriptorWriter, Renderer, SamplerDesc, ShaderId,
+    HDR_FORMAT,
+};
+use ash::{vk, Device};
+
+/// 輪郭線のシェーダーのワークグループの幅と高さ
+pub const OUTLINE_GROUP_SIZE: u32 = 8;
+
+/// 輪郭線の設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutlineSettings {
+    pub enabled: bool,
+    /// 線の太さ。画素の単位で、隣の画素をこの距離で調べる
+    pub thickness: f32,
+    /// リニアなRGBの線の色。アルファは不透明度
+    pub color: [f32; 4],
+    /// カメラからの距離がこの割合より変わるところを輪郭にする
+    pub depth_threshold: f32,
+    /// 法線の`1 - cos(角度)`がこれより大きい折れ目を輪郭にする
+    pub normal_threshold: f32,
+}
+
+impl Default for OutlineSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            thickness: 1.0,
+            color: [0.0, 0.0, 0.0, 1.0],
+            depth_threshold: 0.1,
+            normal_threshold: 0.4,
+        }
+    }
+}
+
+/// 輪郭線のシェーダーに渡すプッシュ定数
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutlinePushConstants {
+    pub color: [f32; 4],
+    /// 深度`d`からカメラの距離を`-(x * d + y) / (z * d + w)`で求める。逆投影行列の要素
+    pub depth_params: [f32; 4],
+    pub thickness: f32,
+    pub depth_threshold: f32,
+    pub normal_threshold: f32,
+    /// 0ならバインディング2に面の情報がなく、法線の輪郭を調べずに全ての画素に線を描く
+    pub use_surface: u32,
+}
+
+impl OutlinePushConstants {
+    fn bytes(&self) -> Vec<u8> {
+        self.color
+            .iter()
+            .chain(self.depth_params.iter())
+            .chain([self.thickness, self.depth_threshold, self.normal_threshold].iter())
+            .flat_map(|value| value.to_ne_bytes())
+            .chain(self.use_surface.to_ne_bytes())
+            .collect()
+    }
+}
+
+/// 輪郭線のコンピュートシェーダー
+///
+/// 8x8のワークグループで1画素を1スレッドが処理し、`outline_set_layout`のセットを使う。
+/// 上下左右の`thickness`画素先との距離と法線の差から輪郭を求め、シーンの色に線の色を混ぜる。
+/// プッシュ定数は`OutlinePushConstants`。
+///
+/// ```glsl
+/// #include "tempura/toon.glsl"
+/// layout(local_size_x = 8, local_size_y = 8) in;
+/// layout(set = 0, binding = 0) uniform sampler2D scene;
+/// layout(set = 0, binding = 1) uniform sampler2D depth;
+/// // xyz: ビュー空間の法線 * 0.5 + 0.5、a: TEMPURA_RECEIVE_OUTLINEなら1。use_surfaceが0ならdepthが入っている
+/// layout(set = 0, binding = 2) uniform sampler2D surface;
+/// layout(set = 0, binding = 3, rgba16f) uniform writeonly image2D dst;
+/// layout(push_constant) uniform Params {
+///     vec4 color; vec4 depth_params;
+///     float thickness; float depth_threshold; float normal_threshold; uint use_surface;
+/// };
+/// float distance_at(ivec2 p) {
+///     float d = texelFetch(depth, clamp(p, ivec2(0), textureSize(depth, 0) - 1), 0).r;
+///     return -(depth_params.x * d + depth_params.y) / (depth_params.z * d + depth_params.w);
+/// }
+/// vec3 normal_at(ivec2 p) {
+///     return normalize(texelFetch(surface, clamp(p, ivec2(0), textureSize(surface, 0) - 1), 0).xyz * 2.0 - 1.0);
+/// }
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy), size = imageSize(dst);
+///     if (any(greaterThanEqual(p, size))) return;
+///     int o = max(int(thickness + 0.5), 1);
+///     ivec2 offsets[4] = ivec2[](ivec2(o, 0), ivec2(-o, 0), ivec2(0, o), ivec2(0, -o));
+///     vec4 neighbors = vec4(distance_at(p + offsets[0]), distance_at(p + offsets[1]),
+///         distance_at(p + offsets[2]), distance_at(p + offsets[3]));
+///     // 手前の物の側にだけ線を描くように、中央は近い方の距離にする
+///     float center = min(distance_at(p), min(min(neighbors.x, neighbors.y), min(neighbors.z, neighbors.w)));
+///     float edge = tempura_outline_depth_edge(center, neighbors, depth_threshold);
+///     if (use_surface != 0u) {
+///         edge = max(edge, tempura_outline_normal_edge(normal_at(p), normal_at(p + offsets[0]),
+///             normal_at(p + offsets[1]), normal_at(p + offsets[2]), normal_at(p + offsets[3]),
+///             normal_threshold));
+///         // 線を描かない物の上と、その物と接する輪郭は近い方の物に従う
+///         float mask = texelFetch(surface, p, 0).a;
+///         for (int i = 0; i < 4; ++i) {
+///             if (distance_at(p + offsets[i]) < distance_at(p)) {
+///                 mask = max(mask, texelFetch(surface, clamp(p + offsets[i], ivec2(0), size - 1), 0).a);
+///             }
+///         }
+///         edge *= mask;
+///     }
+///     vec3 c = texelFetch(scene, p, 0).rgb;
+///     imageStore(dst, p, vec4(mix(c, color.rgb, color.a * edge), 1.0));
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutlineShaders {
+    pub outline: ShaderId,
+}
+
+/// スワップチェインの解像度に合わせて作り直す輪郭線のリソース
+pub struct OutlineTargets {
+    /// 線を描いた色。HDRのターゲットにコピーする
+    pub output: ScreenImage,
+    /// 深度バッファの深度のアスペクトだけのビュー
+    pub depth_view: vk::ImageView,
+    pub extent: vk::Extent2D,
+}
+
+impl OutlineTargets {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        device.destroy_image_view(self.depth_view, None);
+        self.output.destroy(device, allocator);
+    }
+}
+
+/// `enable_outline`で作る輪郭線のリソース
+pub struct Outline {
+    pub pipeline: ComputePipeline,
+    pub sampler: vk::Sampler,
+    pub targets: OutlineTargets,
+    /// `set_outline_surface`で渡された法線と線の有無のビュー
+    pub surface: Option<vk::ImageView>,
+    /// `set_outline_camera`のカメラの逆投影行列の要素
+    pub depth_params: [f32; 4],
+}
+
+impl Outline {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        self.pipeline.destroy(device);
+        device.destroy_sampler(self.sampler, None);
+        self.targets.destroy(device, allocator);
+    }
+}
+
+impl Renderer {
+    /// 輪郭線のデスクリプタセットレイアウト
+    ///
+    /// バインディング0がシーンの色、1が深度、2が面の法線と線の有無、3が出力のストレージイメージ。
+    pub fn outline_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        let stage = vk::ShaderStageFlags::COMPUTE;
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, stage),
+            DescriptorBinding::sampled_image(1, stage),
+            DescriptorBinding::sampled_image(2, stage),
+            DescriptorBinding::new(3, vk::DescriptorType::STORAGE_IMAGE, stage),
+        ])
+    }
+
+    /// HDRのレンダーターゲットにかける輪郭線を有効にする。すでに有効な場合は作り直す
+    ///
+    /// `enable_hdr`の後で呼ぶ。`ShadingModel::Cel`のマテリアルと組み合わせるとトゥーン調になる。
+    /// 深度をサンプリングするのでMSAAとは併用できない。
+    pub fn enable_outline(&mut self, shaders: OutlineShaders) -> Result<()> {
+        self.enabled_hdr()?;
+        if self.msaa_samples != vk::SampleCountFlags::TYPE_1 {
+            return Err(RendererError::Validation(
+                "outlines cannot be combined with MSAA".to_owned(),
+            ));
+        }
+        let layout = self.outline_set_layout()?;
+        let pipeline = self.create_compute_pipeline(
+            shaders.outline,
+            "main",
+            &[layout],
+            std::mem::size_of::<OutlinePushConstants>() as u32,
+        )?;
+        let resources = self
+            .create_sampler(&SamplerDesc::nearest())
+            .and_then(|sampler| match unsafe { self.create_outline_targets() } {
+                Ok(targets) => Ok((sampler, targets)),
+                Err(err) => {
+                    unsafe { self.device.destroy_sampler(sampler, None) };
+                    Err(err)
+                }
+            });
+        let (sampler, targets) = match resources {
+            Ok(resources) => resources,
+            Err(err) => {
+                self.destroy_compute_pipeline(pipeline);
+                return Err(err);
+            }
+        };
+        let (surface, depth_params) = match &self.outline {
+            Some(old) => (old.surface, old.depth_params),
+            None => (None, [0.0, 0.0, 0.0, 1.0]),
+        };
+        let outline = Outline {
+            pipeline,
+            sampler,
+            targets,
+            surface,
+            depth_params,
+        };
+        if let Some(old) = self.outline.replace(outline) {
+            self.destroy_deferred(move |device, allocator| unsafe {
+                old.destroy(device, allocator)
+            });
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してから輪郭線のリソースを破棄する
+    pub fn disable_outline(&mut self) {
+        if let Some(outline) = self.outline.take() {
+            self.destroy_deferred(move |device, allocator| unsafe {
+                outline.destroy(device, allocator)
+            });
+        }
+    }
+
+    pub fn outline(&self) -> Option<&Outline> {
+        self.outline.as_ref()
+    }
+
+    /// 深度を距離に直すのに使うカメラを設定する。深度を描いたカメラを渡す
+    pub fn set_outline_camera(&mut self, camera: &Camera) -> Result<()> {
+        let inverse = mat4_inverse(&camera.projection_matrix()).ok_or_else(|| {
+            RendererError::Validation("projection matrix is not invertible".to_owned())
+        })?;
+        self.enabled_outline_mut()?.depth_params =
+            [inverse[2][2], inverse[3][2], inverse[2][3], inverse[3][3]];
+        Ok(())
+    }
+
+    /// 面のビュー空間の法線と線の有無を書いたイメージのビューを設定する
+    ///
+    /// xyzに`法線 * 0.5 + 0.5`、aにマテリアルの`ReceiveFlags::OUTLINE`を0か1で書き、
+    /// `apply_post_processing`のときに`SHADER_READ_ONLY_OPTIMAL`になっていること。
+    /// `None`なら深度の輪郭だけを全ての物に描く。
+    pub fn set_outline_surface(&mut self, surface: Option<vk::ImageView>) -> Result<()> {
+        self.enabled_outline_mut()?.surface = surface;
+        Ok(())
+    }
+
+    /// HDRのターゲットに合わせて輪郭線の出力を作り直す
+    ///
+    /// 古いリソースは使用中のフレームが完了してから破棄する。
+    pub(crate) fn recreate_outline_targets(&mut self) -> Result<()> {
+        if self.outline.is_none() {
+            return Ok(());
+        }
+        let targets = unsafe { self.create_outline_targets()? };
+        let old = std::mem::replace(&mut self.outline.as_mut().unwrap().targets, targets);
+        self.destroy_deferred(move |device, allocator| unsafe { old.destroy(device, allocator) });
+        Ok(())
+    }
+
+    /// 輪郭線を記録し、結果をHDRのターゲットに書き戻す
+    pub(crate) fn apply_outline(&mut self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        let settings = self.view_post_process().outline;
+        let Some(outline) = &self.outline else {
+            return Ok(());
+        };
+        if !settings.enabled || !self.is_pass_enabled("outline") {
+            return Ok(());
+        }
+        let hdr = self.enabled_hdr()?;
+        let (hdr_image, hdr_view) = (hdr.target.image, hdr.target.view);
+        let (sampler, depth_view, surface) =
+            (outline.sampler, outline.targets.depth_view, outline.surface);
+        let push_constants = OutlinePushConstants {
+            color: settings.color,
+            depth_params: outline.depth_params,
+            thickness: settings.thickness,
+            depth_threshold: settings.depth_threshold,
+            normal_threshold: settings.normal_threshold,
+            use_surface: surface.is_some() as u32,
+        };
+        let layout = self.outline_set_layout()?;
+        let descriptor_set = self.allocate_transient_descriptor_set(layout)?;
+        let outline = self.outline.as_ref().unwrap();
+        let targets = &outline.targets;
+        let sampled = |image_view| vk::DescriptorImageInfo {
+            sampler,
+            image_view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        };
+        let mut writer = DescriptorWriter::new()
+            .image(
+                0,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                sampled(hdr_view),
+            )
+            .depth_image(1, depth_view, sampler);
+        writer = match surface {
+            Some(surface) => writer.image(
+                2,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                sampled(surface),
+            ),
+            None => writer.depth_image(2, depth_view, sampler),
+        };
+        unsafe {
+            writer
+                .image(
+                    3,
+                    vk::DescriptorType::STORAGE_IMAGE,
+                    vk::DescriptorImageInfo {
+                        sampler: vk::Sampler::null(),
+                        image_view: targets.output.view,
+                        image_layout: vk::ImageLayout::GENERAL,
+                    },
+                )
+                .update(&self.device, descriptor_set);
+            self.begin_screen_pass(
+                command_buffer,
+                &[targets.output.image],
+                Some(self.depth_image),
+            );
+        }
+        self.dispatch(
+            command_buffer,
+            &outline.pipeline,
+            &[descriptor_set],
+            &push_constants.bytes(),
+            [
+                targets.extent.width.div_ceil(OUTLINE_GROUP_SIZE),
+                targets.extent.height.div_ceil(OUTLINE_GROUP_SIZE),
+                1,
+            ],
+        )?;
+        unsafe {
+            self.restore_depth_attachment(command_buffer, &[]);
+            self.copy_to_hdr(
+                command_buffer,
+                targets.output.image,
+                hdr_image,
+                targets.extent,
+            );
+        }
+        Ok(())
+    }
+
+    fn enabled_outline_mut(&mut self) -> Result<&mut Outline> {
+        self.outline
+            .as_mut()
+            .ok_or_else(|| RendererError::Validation("outlines are not enabled".to_owned()))
+    }
+
+    unsafe fn create_outline_targets(&mut self) -> Result<OutlineTargets> {
+        let extent = self.enabled_hdr()?.target.extent;
+        let output = create_screen_image(
+            &self.device,
+            &mut self.allocator,
+            HDR_FORMAT,
+            vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC,
+            extent,
+        )?;
+        match create_depth_sampling_view(&self.device, self.depth_image, self.depth_format) {
+            Ok(depth_view) => Ok(OutlineTargets {
+                output,
+                depth_view,
+                extent,
+            }),
+            Err(err) => {
+                output.destroy(&self.device, &mut self.allocator);
+                Err(err)
+            }
+        }
+    }
+}
@@ -0,0 +1,938 @@
+use super::descriptor::{allocate_set, create_pool};
+use super::error::{RendererError, Result};
+use super::renderer::{create_depth_sampling_view, depth_aspect_mask};
+use super::{
+    mat4_inverse, BlendMode, BufferId, Camera, ComputePipeline, DescriptorBinding,
+    DescriptorWriter, GraphicsPipeline, Mat4, PipelineBuilder, Renderer, RenderingAttachment,
+    SamplerDesc, ShaderId, HDR_FORMAT,
+};
+use ash::{vk, Device};
+
+/// 発生とシミュレーションのシェーダーのワークグループの大きさ
+pub const PARTICLE_GROUP_SIZE: u32 = 64;
+/// パーティクル1つのバイト数。`tempura/particles.glsl`の`TempuraParticle`
+pub const PARTICLE_STRIDE: vk::DeviceSize = 64;
+
+/// `particle_set_layout`のカウンターの`VkDrawIndirectCommand`2つと死んだパーティクルの数
+const COUNTER_WORDS: usize = 12;
+
+/// GPUパーティクルのシェーダー
+///
+/// 発生とシミュレーションは64スレッドのワークグループで、セット0に`particle_set_layout`を使う。
+/// 生きているパーティクルの番号の一覧を2つ持ち、シミュレーションは`current`の一覧から
+/// 生き残ったものを詰めてもう一方の一覧に書く。寿命が尽きたものは死んだ一覧に戻し、発生で再利用する。
+/// プッシュ定数は`ParticleSimulationPushConstants`。
+///
+/// ```glsl
+/// #include "tempura/particles.glsl"
+/// layout(local_size_x = 64) in;
+/// layout(set = 0, binding = 0) buffer Particles { TempuraParticle particles[]; };
+/// layout(set = 0, binding = 1) buffer Alive0 { uint alive0[]; };
+/// layout(set = 0, binding = 2) buffer Alive1 { uint alive1[]; };
+/// layout(set = 0, binding = 3) buffer Dead { uint dead[]; };
+/// // drawは一覧ごとのVkDrawIndirectCommandで、yが生きている数
+/// layout(set = 0, binding = 4) buffer Counters { uvec4 draw[2]; int dead_count; };
+/// layout(push_constant) uniform Params {
+///     vec4 position_radius; vec4 velocity_spread; vec4 gravity_drag;
+///     vec4 start_color; vec4 end_color; vec4 lifetime_curl;
+///     float delta_time; float time; uint emit_count; uint seed;
+///     uint current; uint capacity;
+/// };
+/// void push_alive(uint list, uint index) {
+///     uint slot = atomicAdd(draw[list].y, 1u);
+///     if (list == 0u) alive0[slot] = index; else alive1[slot] = index;
+/// }
+///
+/// // emit: 死んだ一覧から取り出して初期化し、currentの一覧に加える
+/// void main() {
+///     uint i = gl_GlobalInvocationID.x;
+///     if (i >= emit_count) return;
+///     int slot = atomicAdd(dead_count, -1) - 1;
+///     if (slot < 0) { atomicAdd(dead_count, 1); return; }
+///     uint index = dead[slot];
+///     vec3 offset = tempura_random_in_sphere(seed, i) * position_radius.w;
+///     vec3 velocity = velocity_spread.xyz + tempura_random_in_sphere(seed ^ 0x68e31da4u, i) * velocity_spread.w;
+///     float lifetime = mix(lifetime_curl.x, lifetime_curl.y, tempura_random(uvec2(i, seed ^ 0xb5297a4du)));
+///     particles[index] = TempuraParticle(vec4(position_radius.xyz + offset, 0.0),
+///         vec4(velocity, lifetime), start_color, end_color);
+///     push_alive(current, index);
+/// }
+///
+/// // simulate: 重力、抵抗、カールノイズで進め、生き残ったものを次の一覧に詰める
+/// void main() {
+///     uint i = gl_GlobalInvocationID.x;
+///     if (i >= draw[current].y) return;
+///     uint index = current == 0u ? alive0[i] : alive1[i];
+///     TempuraParticle p = particles[index];
+///     float age = p.position_age.w + delta_time;
+///     if (age >= p.velocity_lifetime.w) {
+///         dead[atomicAdd(dead_count, 1)] = index;
+///         return;
+///     }
+///     vec3 v = p.velocity_lifetime.xyz + gravity_drag.xyz * delta_time;
+///     v += tempura_curl_noise(p.position_age.xyz * lifetime_curl.w + time * 0.1) * lifetime_curl.z * delta_time;
+///     v *= exp(-gravity_drag.w * delta_time);
+///     particles[index].position_age = vec4(p.position_age.xyz + v * delta_time, age);
+///     particles[index].velocity_lifetime.xyz = v;
+///     push_alive(1u - current, index);
+/// }
+/// ```
+///
+/// 描画はインスタンスごとに6頂点のカメラに向いた四角形で、セット1は`particle_depth_set_layout`の
+/// 深度。深度はアタッチメントにせずに読み、手前の物に隠れる画素を捨て、近いところで薄くする。
+/// プッシュ定数は`ParticleDrawPushConstants`。
+///
+/// ```glsl
+/// // 頂点シェーダー。セット0は上と同じ
+/// layout(push_constant) uniform Params {
+///     mat4 view_projection; vec4 right_start_size; vec4 up_end_size;
+///     vec4 depth_params; float soft_fade_distance; uint current;
+/// };
+/// layout(location = 0) out vec4 out_color;
+/// layout(location = 1) out vec2 out_uv;
+/// void main() {
+///     uint index = current == 0u ? alive0[gl_InstanceIndex] : alive1[gl_InstanceIndex];
+///     TempuraParticle p = particles[index];
+///     float t = p.position_age.w / p.velocity_lifetime.w;
+///     const vec2 corners[6] = vec2[](vec2(-1, -1), vec2(1, -1), vec2(1, 1),
+///         vec2(-1, -1), vec2(1, 1), vec2(-1, 1));
+///     vec2 corner = corners[gl_VertexIndex];
+///     float size = mix(right_start_size.w, up_end_size.w, t);
+///     vec3 position = p.position_age.xyz
+///         + (right_start_size.xyz * corner.x + up_end_size.xyz * corner.y) * size;
+///     gl_Position = view_projection * vec4(position, 1.0);
+///     out_color = mix(p.start_color, p.end_color, t);
+///     out_uv = corner;
+/// }
+///
+/// // フラグメントシェーダー
+/// layout(set = 1, binding = 0) uniform sampler2D depth;
+/// layout(location = 0) in vec4 in_color;
+/// layout(location = 1) in vec2 in_uv;
+/// layout(location = 0) out vec4 color;
+/// float distance_at(float d) {
+///     return -(depth_params.x * d + depth_params.y) / (depth_params.z * d + depth_params.w);
+/// }
+/// void main() {
+///     float scene = distance_at(texelFetch(depth, ivec2(gl_FragCoord.xy), 0).r);
+///     float fade = clamp((scene - distance_at(gl_FragCoord.z)) / soft_fade_distance, 0.0, 1.0);
+///     float shape = 1.0 - smoothstep(0.5, 1.0, length(in_uv));
+///     if (fade <= 0.0 || shape <= 0.0) discard;
+///     color = vec4(in_color.rgb, in_color.a * shape * fade);
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParticleShaders {
+    pub emit: ShaderId,
+    pub simulate: ShaderId,
+    pub vertex: ShaderId,
+    pub fragment: ShaderId,
+}
+
+/// パーティクルの重ね方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParticleBlend {
+    /// 炎や火花のように光を足す。順序によらない
+    Additive,
+    /// 煙や埃のように奥を覆う。並べ替えないので、色の揃ったパーティクルに使う
+    AlphaBlend,
+}
+
+/// エミッターの設定。`ParticleEmitter::settings`を書き換えると次の`update_particles`から反映される
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParticleEmitterSettings {
+    /// ワールド空間の発生の中心
+    pub position: [f32; 3],
+    /// この半径の球の中に発生させる
+    pub spawn_radius: f32,
+    /// 1秒に発生させる数
+    pub emission_rate: f32,
+    /// 寿命の最小と最大(秒)
+    pub lifetime: [f32; 2],
+    pub initial_velocity: [f32; 3],
+    /// 初速にこの半径の球の中のばらつきを足す
+    pub velocity_spread: f32,
+    pub gravity: [f32; 3],
+    /// 1秒あたりの速度の減衰。`exp(-drag * dt)`を掛ける
+    pub drag: f32,
+    /// カールノイズの加速度
+    pub curl_noise_strength: f32,
+    /// カールノイズの模様の細かさ。位置に掛ける
+    pub curl_noise_scale: f32,
+    /// 発生時と寿命の終わりの色。アルファは不透明度。HDRのターゲットに描くので1を超えてもよい
+    pub start_color: [f32; 4],
+    pub end_color: [f32; 4],
+    /// 発生時と寿命の終わりの四角形の半分の大きさ
+    pub start_size: f32,
+    pub end_size: f32,
+    pub blend: ParticleBlend,
+    /// 不透明な物との距離がこれより近いと薄くする。ビュー空間の単位
+    pub soft_fade_distance: f32,
+}
+
+impl Default for ParticleEmitterSettings {
+    fn default() -> Self {
+        Self {
+            position: [0.0; 3],
+            spawn_radius: 0.1,
+            emission_rate: 100.0,
+            lifetime: [1.0, 2.0],
+            initial_velocity: [0.0, 1.0, 0.0],
+            velocity_spread: 0.5,
+            gravity: [0.0, -9.8, 0.0],
+            drag: 0.5,
+            curl_noise_strength: 0.0,
+            curl_noise_scale: 1.0,
+            start_color: [1.0; 4],
+            end_color: [1.0, 1.0, 1.0, 0.0],
+            start_size: 0.05,
+            end_size: 0.05,
+            blend: ParticleBlend::Additive,
+            soft_fade_distance: 0.2,
+        }
+    }
+}
+
+/// 発生とシミュレーションのシェーダーに渡すプッシュ定数
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParticleSimulationPushConstants {
+    pub position: [f32; 3],
+    pub spawn_radius: f32,
+    pub initial_velocity: [f32; 3],
+    pub velocity_spread: f32,
+    pub gravity: [f32; 3],
+    pub drag: f32,
+    pub start_color: [f32; 4],
+    pub end_color: [f32; 4],
+    pub lifetime: [f32; 2],
+    pub curl_noise_strength: f32,
+    pub curl_noise_scale: f32,
+    pub delta_time: f32,
+    /// エミッターを作ってからの時間。カールノイズを動かす
+    pub time: f32,
+    pub emit_count: u32,
+    pub seed: u32,
+    /// 発生させ、シミュレーションで読む生きている一覧
+    pub current: u32,
+    pub capacity: u32,
+}
+
+impl ParticleSimulationPushConstants {
+    fn bytes(&self) -> Vec<u8> {
+        self.position
+            .iter()
+            .chain(std::iter::once(&self.spawn_radius))
+            .chain(self.initial_velocity.iter())
+            .chain(std::iter::once(&self.velocity_spread))
+            .chain(self.gravity.iter())
+            .chain(std::iter::once(&self.drag))
+            .chain(self.start_color.iter())
+            .chain(self.end_color.iter())
+            .chain(self.lifetime.iter())
+            .chain([self.curl_noise_strength, self.curl_noise_scale].iter())
+            .chain([self.delta_time, self.time].iter())
+            .flat_map(|value| value.to_ne_bytes())
+            .chain(
+                [self.emit_count, self.seed, self.current, self.capacity]
+                    .iter()
+                    .flat_map(|value| value.to_ne_bytes()),
+            )
+            .collect()
+    }
+}
+
+/// パーティクルの描画のシェーダーに渡すプッシュ定数
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParticleDrawPushConstants {
+    pub view_projection: Mat4,
+    /// カメラの右方向と、発生時の大きさ
+    pub right_start_size: [f32; 4],
+    /// カメラの上方向と、寿命の終わりの大きさ
+    pub up_end_size: [f32; 4],
+    /// 深度`d`からカメラの距離を`-(x * d + y) / (z * d + w)`で求める。逆投影行列の要素
+    pub depth_params: [f32; 4],
+    pub soft_fade_distance: f32,
+    /// 描く生きている一覧
+    pub current: u32,
+}
+
+impl ParticleDrawPushConstants {
+    fn bytes(&self) -> Vec<u8> {
+        self.view_projection
+            .iter()
+            .flatten()
+            .chain(self.right_start_size.iter())
+            .chain(self.up_end_size.iter())
+            .chain(self.depth_params.iter())
+            .chain(std::iter::once(&self.soft_fade_distance))
+            .flat_map(|value| value.to_ne_bytes())
+            .chain(self.current.to_ne_bytes())
+            .collect()
+    }
+}
+
+/// `create_particle_emitter`で作るエミッター
+///
+/// パーティクルの状態はGPUのバッファだけにあり、`destroy_particle_emitter`で破棄する。
+/// デスクリプタセットはレンダラーの破棄まで解放されない。
+pub struct ParticleEmitter {
+    pub settings: ParticleEmitterSettings,
+    /// 同時に生きていられるパーティクルの数
+    pub capacity: u32,
+    /// `TempuraParticle`の配列
+    pub particles: BufferId,
+    /// 生きているパーティクルの番号の一覧を2つ
+    pub alive: [BufferId; 2],
+    /// 死んだパーティクルの番号の一覧
+    pub dead: BufferId,
+    /// 一覧ごとの`VkDrawIndirectCommand`と死んだパーティクルの数
+    pub counters: BufferId,
+    /// `particle_set_layout`のデスクリプタセット
+    pub descriptor_set: vk::DescriptorSet,
+    /// 次に発生させ、描く一覧
+    current: u32,
+    /// 発生させきれなかった端数
+    emission_remainder: f32,
+    pending_burst: u32,
+    time: f32,
+    seed: u32,
+}
+
+impl ParticleEmitter {
+    /// 次の`update_particles`で`count`個をまとめて発生させる
+    pub fn burst(&mut self, count: u32) {
+        self.pending_burst = self.pending_burst.saturating_add(count);
+    }
+}
+
+/// `enable_particles`で作るパイプラインと深度を読むデスクリプタセット
+pub struct Particles {
+    pub emit_pipeline: ComputePipeline,
+    pub simulate_pipeline: ComputePipeline,
+    pub additive_pipeline: GraphicsPipeline,
+    pub alpha_blend_pipeline: GraphicsPipeline,
+    pub sampler: vk::Sampler,
+    pub depth_view: vk::ImageView,
+    pub descriptor_pool: vk::DescriptorPool,
+    /// `particle_depth_set_layout`のセット。解像度が変わったら作り直す
+    pub depth_set: vk::DescriptorSet,
+}
+
+impl Particles {
+    /// # Safety
+    /// `device`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device) {
+        self.emit_pipeline.destroy(device);
+        self.simulate_pipeline.destroy(device);
+        self.additive_pipeline.destroy(device);
+        self.alpha_blend_pipeline.destroy(device);
+        device.destroy_sampler(self.sampler, None);
+        destroy_depth_set(device, self.descriptor_pool, self.depth_view);
+    }
+}
+
+unsafe fn destroy_depth_set(
+    device: &Device,
+    descriptor_pool: vk::DescriptorPool,
+    depth_view: vk::ImageView,
+) {
+    device.destroy_descriptor_pool(descriptor_pool, None);
+    device.destroy_image_view(depth_view, None);
+}
+
+impl Renderer {
+    /// パーティクルのデスクリプタセットレイアウト
+    ///
+    /// バインディング0がパーティクル、1と2が生きている一覧、3が死んだ一覧、4がカウンター。
+    pub fn particle_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        let stages = vk::ShaderStageFlags::COMPUTE | vk::ShaderStageFlags::VERTEX;
+        let bindings: Vec<_> = (0..5)
+            .map(|binding| DescriptorBinding::storage_buffer(binding, stages))
+            .collect();
+        self.descriptor_set_layout(&bindings)
+    }
+
+    /// パーティクルの描画で深度を読むデスクリプタセットレイアウト。バインディング0が深度
+    pub fn particle_depth_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[DescriptorBinding::sampled_image(
+            0,
+            vk::ShaderStageFlags::FRAGMENT,
+        )])
+    }
+
+    /// GPUパーティクルを有効にする
+    ///
+    /// 動的レンダリングが必要で、MSAAとは併用できない。`enable_hdr`の後で呼ぶ。
+    /// 描画のパイプラインのセット2以降には`set_layouts`を使う。
+    pub fn enable_particles(
+        &mut self,
+        shaders: ParticleShaders,
+        set_layouts: &[vk::DescriptorSetLayout],
+    ) -> Result<()> {
+        if self.dynamic_rendering.is_none() {
+            return Err(RendererError::Validation(
+                "particles need dynamic rendering".to_owned(),
+            ));
+        }
+        if self.msaa_samples != vk::SampleCountFlags::TYPE_1 {
+            return Err(RendererError::Validation(
+                "particles do not support MSAA".to_owned(),
+            ));
+        }
+        self.enabled_hdr()?;
+        let particle_layout = self.particle_set_layout()?;
+        let depth_layout = self.particle_depth_set_layout()?;
+        let layouts: Vec<_> = [particle_layout, depth_layout]
+            .into_iter()
+            .chain(set_layouts.iter().copied())
+            .collect();
+        let builder = PipelineBuilder::new()
+            .vertex_shader(self.shader_module(shaders.vertex)?)
+            .fragment_shader(self.shader_module(shaders.fragment)?)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .depth_test(false)
+            .depth_write(false)
+            .descriptor_set_layouts(&layouts)
+            .push_constant_range(
+                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                0,
+                std::mem::size_of::<ParticleDrawPushConstants>() as u32,
+            )
+            .rendering_formats(&[HDR_FORMAT], vk::Format::UNDEFINED);
+        let simulation_size = std::mem::size_of::<ParticleSimulationPushConstants>() as u32;
+
+        let mut pipelines = Vec::with_capacity(2);
+        let mut graphics = Vec::with_capacity(2);
+        let result = (|| {
+            for shader in [shaders.emit, shaders.simulate] {
+                pipelines.push(self.create_compute_pipeline(
+                    shader,
+                    "main",
+                    &[particle_layout],
+                    simulation_size,
+                )?);
+            }
+            for blend in [BlendMode::Additive, BlendMode::AlphaBlend] {
+                graphics.push(self.create_graphics_pipeline(
+                    &builder.clone().blend_mode(blend),
+                    vk::RenderPass::null(),
+                    0,
+                )?);
+            }
+            let sampler = self.create_sampler(&SamplerDesc::nearest())?;
+            match unsafe { self.create_particle_depth_set(depth_layout, sampler) } {
+                Ok((depth_view, descriptor_pool, depth_set)) => {
+                    Ok((sampler, depth_view, descriptor_pool, depth_set))
+                }
+                Err(err) => {
+                    unsafe { self.device.destroy_sampler(sampler, None) };
+                    Err(err)
+                }
+            }
+        })();
+        let (sampler, depth_view, descriptor_pool, depth_set) = match result {
+            Ok(resources) => resources,
+            Err(err) => {
+                for pipeline in pipelines {
+                    self.destroy_compute_pipeline(pipeline);
+                }
+                for pipeline in graphics {
+                    self.destroy_graphics_pipeline(pipeline);
+                }
+                return Err(err);
+            }
+        };
+        let mut pipelines = pipelines.into_iter();
+        let mut graphics = graphics.into_iter();
+        let particles = Particles {
+            emit_pipeline: pipelines.next().unwrap(),
+            simulate_pipeline: pipelines.next().unwrap(),
+            additive_pipeline: graphics.next().unwrap(),
+            alpha_blend_pipeline: graphics.next().unwrap(),
+            sampler,
+            depth_view,
+            descriptor_pool,
+            depth_set,
+        };
+        if let Some(old) = self.particles.replace(particles) {
+            self.destroy_deferred(move |device, _| unsafe { old.destroy(device) });
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからパーティクルのパイプラインを破棄する。エミッターは破棄しない
+    pub fn disable_particles(&mut self) {
+        if let Some(particles) = self.particles.take() {
+            self.destroy_deferred(move |device, _| unsafe { particles.destroy(device) });
+        }
+    }
+
+    pub fn particles(&self) -> Option<&Particles> {
+        self.particles.as_ref()
+    }
+
+    /// 同時に`capacity`個まで生きていられるエミッターを作成する
+    pub fn create_particle_emitter(
+        &mut self,
+        capacity: u32,
+        settings: ParticleEmitterSettings,
+    ) -> Result<ParticleEmitter> {
+        if capacity == 0 {
+            return Err(RendererError::Validation(
+                "a particle emitter needs a capacity of at least 1".to_owned(),
+            ));
+        }
+        validate_settings(&settings)?;
+        let layout = self.particle_set_layout()?;
+        let storage = vk::BufferUsageFlags::STORAGE_BUFFER;
+        let mut counters = [0u32; COUNTER_WORDS];
+        counters[0] = 6;
+        counters[4] = 6;
+        counters[8] = capacity;
+        let dead: Vec<u32> = (0..capacity).collect();
+        let mut buffers = Vec::with_capacity(5);
+        let result = (|| {
+            buffers.push(self.create_buffer(
+                capacity as vk::DeviceSize * PARTICLE_STRIDE,
+                storage,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            )?);
+            for _ in 0..2 {
+                buffers.push(self.create_buffer(
+                    capacity as vk::DeviceSize * 4,
+                    storage,
+                    vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                )?);
+            }
+            buffers.push(self.create_buffer_with_data(&dead, storage)?);
+            buffers.push(self.create_buffer_with_data(
+                &counters,
+                storage | vk::BufferUsageFlags::INDIRECT_BUFFER,
+            )?);
+            self.allocate_descriptor_set(layout)
+        })();
+        let descriptor_set = match result {
+            Ok(set) => set,
+            Err(err) => {
+                for buffer in buffers {
+                    self.destroy_buffer(buffer)?;
+                }
+                return Err(err);
+            }
+        };
+        let writer =
+            buffers
+                .iter()
+                .enumerate()
+                .fold(DescriptorWriter::new(), |writer, (binding, &id)| {
+                    writer.buffer(
+                        binding as u32,
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        self.buffers.get(id).unwrap(),
+                    )
+                });
+        unsafe { writer.update(&self.device, descriptor_set) };
+        Ok(ParticleEmitter {
+            settings,
+            capacity,
+            particles: buffers[0],
+            alive: [buffers[1], buffers[2]],
+            dead: buffers[3],
+            counters: buffers[4],
+            descriptor_set,
+            current: 0,
+            emission_remainder: 0.0,
+            pending_burst: 0,
+            time: 0.0,
+            seed: 0,
+        })
+    }
+
+    /// 使用中のフレームが完了してからエミッターのバッファを破棄する
+    pub fn destroy_particle_emitter(&mut self, emitter: ParticleEmitter) -> Result<()> {
+        for buffer in [emitter.particles, emitter.dead, emitter.counters]
+            .into_iter()
+            .chain(emitter.alive)
+        {
+            self.destroy_buffer(buffer)?;
+        }
+        Ok(())
+    }
+
+    /// `delta_time`秒分のパーティクルを発生させ、シミュレーションを進める
+    ///
+    /// レンダーパスの外で、`begin_particle_rendering`より前に呼ぶ。
+    pub fn update_particles(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        emitter: &mut ParticleEmitter,
+        delta_time: f32,
+    ) -> Result<()> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "particles can only be updated between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        validate_settings(&emitter.settings)?;
+        let particles = self.enabled_particles()?;
+        let settings = emitter.settings;
+        let emission = emitter.emission_remainder + settings.emission_rate * delta_time.max(0.0);
+        let emit_count = (emission.floor() as u32)
+            .saturating_add(emitter.pending_burst)
+            .min(emitter.capacity);
+        emitter.emission_remainder = emission.fract();
+        emitter.pending_burst = 0;
+        let current = emitter.current;
+        let next = 1 - current;
+
+        // 前のフレームの描画とシミュレーションが終わってから、次の一覧の数を0にする
+        self.memory_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::VERTEX_SHADER
+                | vk::PipelineStageFlags::DRAW_INDIRECT
+                | vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_READ
+                | vk::AccessFlags::INDIRECT_COMMAND_READ
+                | vk::AccessFlags::SHADER_WRITE,
+            vk::PipelineStageFlags::TRANSFER | vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::TRANSFER_WRITE
+                | vk::AccessFlags::SHADER_READ
+                | vk::AccessFlags::SHADER_WRITE,
+        );
+        self.zero_buffer(command_buffer, emitter.counters, next as u64 * 16 + 4, 4)?;
+        self.memory_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+        );
+
+        let push_constants = ParticleSimulationPushConstants {
+            position: settings.position,
+            spawn_radius: settings.spawn_radius,
+            initial_velocity: settings.initial_velocity,
+            velocity_spread: settings.velocity_spread,
+            gravity: settings.gravity,
+            drag: settings.drag,
+            start_color: settings.start_color,
+            end_color: settings.end_color,
+            lifetime: settings.lifetime,
+            curl_noise_strength: settings.curl_noise_strength,
+            curl_noise_scale: settings.curl_noise_scale,
+            delta_time,
+            time: emitter.time,
+            emit_count,
+            seed: emitter.seed,
+            current,
+            capacity: emitter.capacity,
+        };
+        let data = push_constants.bytes();
+        let sets = [emitter.descriptor_set];
+        if emit_count > 0 {
+            self.dispatch(
+                command_buffer,
+                &particles.emit_pipeline,
+                &sets,
+                &data,
+                [emit_count.div_ceil(PARTICLE_GROUP_SIZE), 1, 1],
+            )?;
+            self.memory_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            );
+        }
+        self.dispatch(
+            command_buffer,
+            &particles.simulate_pipeline,
+            &sets,
+            &data,
+            [emitter.capacity.div_ceil(PARTICLE_GROUP_SIZE), 1, 1],
+        )?;
+        self.memory_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_WRITE,
+            vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::DRAW_INDIRECT,
+            vk::AccessFlags::SHADER_READ | vk::AccessFlags::INDIRECT_COMMAND_READ,
+        );
+
+        emitter.current = next;
+        emitter.time += delta_time;
+        emitter.seed = emitter.seed.wrapping_add(1);
+        Ok(())
+    }
+
+    /// パーティクルをHDRのレンダーターゲットに描き始める
+    ///
+    /// `end_hdr_rendering`か`resolve_transparency`の後、ポストプロセスの前に呼ぶ。
+    /// 深度はアタッチメントにせず、シェーダーから読めるようにする。
+    pub fn begin_particle_rendering(&self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        self.enabled_particles()?;
+        let hdr = self.enabled_hdr()?;
+        let color_range = color_subresource_range();
+        let barriers = [
+            *vk::ImageMemoryBarrier::builder()
+                .image(hdr.target.image)
+                .old_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .src_access_mask(vk::AccessFlags::SHADER_READ)
+                .dst_access_mask(
+                    vk::AccessFlags::COLOR_ATTACHMENT_READ
+                        | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                )
+                .subresource_range(color_range),
+            *vk::ImageMemoryBarrier::builder()
+                .image(self.depth_image)
+                .old_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .new_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+                .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: depth_aspect_mask(self.depth_format),
+                    ..color_range
+                }),
+        ];
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COMPUTE_SHADER
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &barriers,
+            );
+        }
+        let color =
+            RenderingAttachment::load(hdr.target.view, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+        let render_area = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: hdr.target.extent,
+        };
+        self.begin_rendering(command_buffer, render_area, &[color], None)
+    }
+
+    /// エミッターの生きているパーティクルを`camera`に向けた四角形で描く
+    ///
+    /// `begin_particle_rendering`と`end_particle_rendering`の間で呼ぶ。数は間接描画で
+    /// GPUから読むので、CPUとの同期はない。`descriptor_sets`はセット2以降に割り当てる。
+    pub fn draw_particles(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        emitter: &ParticleEmitter,
+        camera: &Camera,
+        descriptor_sets: &[vk::DescriptorSet],
+    ) -> Result<()> {
+        let particles = self.enabled_particles()?;
+        let counters = self.buffers.get(emitter.counters).ok_or_else(|| {
+            RendererError::Validation(format!("buffer {:?} was destroyed", emitter.counters))
+        })?;
+        let inverse = mat4_inverse(&camera.projection_matrix()).ok_or_else(|| {
+            RendererError::Validation("projection matrix is not invertible".to_owned())
+        })?;
+        let settings = &emitter.settings;
+        let [rx, ry, rz] = camera.right();
+        let [ux, uy, uz] = camera.up();
+        let push_constants = ParticleDrawPushConstants {
+            view_projection: camera.view_projection_matrix(),
+            right_start_size: [rx, ry, rz, settings.start_size],
+            up_end_size: [ux, uy, uz, settings.end_size],
+            depth_params: [inverse[2][2], inverse[3][2], inverse[2][3], inverse[3][3]],
+            soft_fade_distance: settings.soft_fade_distance,
+            current: emitter.current,
+        };
+        let pipeline = match settings.blend {
+            ParticleBlend::Additive => &particles.additive_pipeline,
+            ParticleBlend::AlphaBlend => &particles.alpha_blend_pipeline,
+        };
+        let sets: Vec<_> = [emitter.descriptor_set, particles.depth_set]
+            .into_iter()
+            .chain(descriptor_sets.iter().copied())
+            .collect();
+        unsafe {
+            self.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.pipeline,
+            );
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.layout,
+                0,
+                &sets,
+                &[],
+            );
+            self.push_constants(command_buffer, pipeline, &push_constants.bytes());
+            self.device.cmd_draw_indirect(
+                command_buffer,
+                counters.buffer,
+                emitter.current as u64 * 16,
+                1,
+                16,
+            );
+        }
+        Ok(())
+    }
+
+    /// パーティクルの描画を終え、HDRのレンダーターゲットと深度を`end_hdr_rendering`の後と同じ状態に戻す
+    pub fn end_particle_rendering(&self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        let hdr = self.enabled_hdr()?;
+        self.end_rendering(command_buffer)?;
+        let color_range = color_subresource_range();
+        let barriers = [
+            *vk::ImageMemoryBarrier::builder()
+                .image(hdr.target.image)
+                .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .subresource_range(color_range),
+            *vk::ImageMemoryBarrier::builder()
+                .image(self.depth_image)
+                .old_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+                .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .src_access_mask(vk::AccessFlags::SHADER_READ)
+                .dst_access_mask(
+                    vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                )
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: depth_aspect_mask(self.depth_format),
+                    ..color_range
+                }),
+        ];
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COMPUTE_SHADER
+                    | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &barriers,
+            );
+        }
+        Ok(())
+    }
+
+    /// 深度バッファに合わせてデスクリプタセットを作り直す
+    ///
+    /// 古いセットは使用中のフレームが完了してから破棄する。
+    pub(crate) fn recreate_particle_depth_set(&mut self) -> Result<()> {
+        let Some(sampler) = self.particles.as_ref().map(|particles| particles.sampler) else {
+            return Ok(());
+        };
+        let layout = self.particle_depth_set_layout()?;
+        let (depth_view, descriptor_pool, depth_set) =
+            unsafe { self.create_particle_depth_set(layout, sampler)? };
+        let particles = self.particles.as_mut().unwrap();
+        let old_pool = std::mem::replace(&mut particles.descriptor_pool, descriptor_pool);
+        let old_view = std::mem::replace(&mut particles.depth_view, depth_view);
+        particles.depth_set = depth_set;
+        self.destroy_deferred(move |device, _| unsafe {
+            destroy_depth_set(device, old_pool, old_view)
+        });
+        Ok(())
+    }
+
+    fn enabled_particles(&self) -> Result<&Particles> {
+        self.particles
+            .as_ref()
+            .ok_or_else(|| RendererError::Validation("particles are not enabled".to_owned()))
+    }
+
+    fn memory_barrier(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        src_stage: vk::PipelineStageFlags,
+        src_access: vk::AccessFlags,
+        dst_stage: vk::PipelineStageFlags,
+        dst_access: vk::AccessFlags,
+    ) {
+        let barrier = *vk::MemoryBarrier::builder()
+            .src_access_mask(src_access)
+            .dst_access_mask(dst_access);
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                src_stage,
+                dst_stage,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            );
+        }
+    }
+
+    unsafe fn create_particle_depth_set(
+        &self,
+        layout: vk::DescriptorSetLayout,
+        sampler: vk::Sampler,
+    ) -> Result<(vk::ImageView, vk::DescriptorPool, vk::DescriptorSet)> {
+        let depth_view =
+            create_depth_sampling_view(&self.device, self.depth_image, self.depth_format)?;
+        let descriptor_pool = match create_pool(
+            &self.device,
+            1,
+            &[(vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1.0)],
+        ) {
+            Ok(pool) => pool,
+            Err(err) => {
+                self.device.destroy_image_view(depth_view, None);
+                return Err(err);
+            }
+        };
+        let set = match allocate_set(&self.device, descriptor_pool, layout) {
+            Ok(set) => set,
+            Err(err) => {
+                destroy_depth_set(&self.device, descriptor_pool, depth_view);
+                return Err(err.into());
+            }
+        };
+        DescriptorWriter::new()
+            .depth_image(0, depth_view, sampler)
+            .update(&self.device, set);
+        Ok((depth_view, descriptor_pool, set))
+    }
+}
+
+fn validate_settings(settings: &ParticleEmitterSettings) -> Result<()> {
+    let [min_lifetime, max_lifetime] = settings.lifetime;
+    if !(min_lifetime > 0.0 && min_lifetime <= max_lifetime) {
+        return Err(RendererError::Validation(format!(
+            "particle lifetime {:?} must be positive and ordered",
+            settings.lifetime
+        )));
+    }
+    if settings.emission_rate < 0.0 || settings.soft_fade_distance <= 0.0 {
+        return Err(RendererError::Validation(format!(
+            "invalid particle emitter settings {:?}",
+            settings
+        )));
+    }
+    Ok(())
+}
+
+fn color_subresource_range() -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
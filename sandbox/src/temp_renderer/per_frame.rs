@@ -0,0 +1,152 @@
+use super::buffer::write_buffer;
+use super::error::{RendererError, Result};
+use super::{BufferId, Renderer};
+use ash::vk;
+
+/// フレームコンテキストごとに1つずつ持つリソースのコピー
+///
+/// CPUから毎フレーム書き換えるバッファなどを、GPUが読んでいる間に上書きしないように
+/// 使い分ける。コピーの選択は`Renderer::per_frame`と`Renderer::per_frame_mut`で行う。
+/// デバッグビルドでは、書き込むコピーを最後に使ったフレームが完了しているかを確認する。
+pub struct PerFrame<T> {
+    items: Vec<T>,
+    /// コピーごとに、最後に書き込んだフレームの番号
+    last_written: Vec<Option<u64>>,
+}
+
+impl<T> PerFrame<T> {
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// フレームコンテキストの順に全てのコピーを返す
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.iter()
+    }
+
+    /// 破棄するためにコピーを取り出す
+    pub fn into_inner(self) -> Vec<T> {
+        self.items
+    }
+}
+
+impl Renderer {
+    /// フレームコンテキストの数だけ`create`を呼んでコピーを作る
+    ///
+    /// `create`にはフレームコンテキストの番号が渡される。
+    pub fn create_per_frame<T, F>(&mut self, mut create: F) -> Result<PerFrame<T>>
+    where
+        F: FnMut(&mut Self, usize) -> Result<T>,
+    {
+        let count = self.frames.len();
+        let mut items = Vec::with_capacity(count);
+        for index in 0..count {
+            items.push(create(self, index)?);
+        }
+        Ok(PerFrame {
+            items,
+            last_written: vec![None; count],
+        })
+    }
+
+    /// 記録中(`begin_frame`の前なら次に記録する)のフレームのコピー
+    pub fn per_frame<'a, T>(&self, per_frame: &'a PerFrame<T>) -> &'a T {
+        &per_frame.items[self.current_frame]
+    }
+
+    /// 記録中(`begin_frame`の前なら次に記録する)のフレームのコピーを書き込み用に返す
+    ///
+    /// デバッグビルドでは、そのコピーを前に書き込んだフレームがまだGPUで実行中なら
+    /// `RendererError::Validation`を返す。`begin_frame`と`end_frame`の間であれば
+    /// フェンスを待った後なので常に書き込める。
+    pub fn per_frame_mut<'a, T>(&self, per_frame: &'a mut PerFrame<T>) -> Result<&'a mut T> {
+        let index = self.current_frame;
+        if per_frame.items.len() != self.frames.len() {
+            return Err(RendererError::Validation(format!(
+                "per-frame resource has {} copies, but the renderer has {} frames in flight",
+                per_frame.items.len(),
+                self.frames.len()
+            )));
+        }
+        if cfg!(debug_assertions) {
+            if let Some(frame) = per_frame.last_written[index] {
+                if !self.frame_completed(frame) {
+                    return Err(RendererError::Validation(format!(
+                        "per-frame copy {} is still in use by frame {}",
+                        index, frame
+                    )));
+                }
+            }
+        }
+        per_frame.last_written[index] = Some(self.frame_count);
+        Ok(&mut per_frame.items[index])
+    }
+
+    /// HOST_VISIBLEかつHOST_COHERENTなバッファをフレームコンテキストの数だけ作る
+    pub fn create_per_frame_buffers(
+        &mut self,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+    ) -> Result<PerFrame<BufferId>> {
+        self.create_per_frame(|renderer, _| {
+            renderer.create_buffer(
+                size,
+                usage,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )
+        })
+    }
+
+    /// 記録中のフレームのバッファに`data`を書き込み、そのバッファを返す
+    pub fn write_per_frame_buffer<T: Copy>(
+        &self,
+        buffers: &mut PerFrame<BufferId>,
+        data: &[T],
+    ) -> Result<BufferId> {
+        let id = *self.per_frame_mut(buffers)?;
+        let buffer = self
+            .buffers
+            .get(id)
+            .ok_or_else(|| RendererError::Validation(format!("buffer {:?} was destroyed", id)))?;
+        unsafe { write_buffer(buffer, data)? };
+        Ok(id)
+    }
+
+    /// 使用中のフレームが完了してから全てのバッファを破棄する
+    pub fn destroy_per_frame_buffers(&mut self, buffers: PerFrame<BufferId>) -> Result<()> {
+        for id in buffers.into_inner() {
+            self.destroy_buffer(id)?;
+        }
+        Ok(())
+    }
+
+    /// `frame`番目のフレームのGPUでの実行が完了しているか
+    fn frame_completed(&self, frame: u64) -> bool {
+        // 記録中のフレームと、まだ記録していないフレームはGPUが使っていない
+        frame >= self.frame_count || self.submitted_frame_completed(frame).unwrap_or(false)
+    }
+
+    /// 提出済みの`frame`番目のフレームのGPUでの実行が完了しているか。未提出なら`false`
+    pub(crate) fn submitted_frame_completed(&self, frame: u64) -> Result<bool> {
+        if frame >= self.frame_count {
+            return Ok(false);
+        }
+        let count = self.frames.len() as u64;
+        // 同じフレームコンテキストを後のフレームが`begin_frame`していれば、フェンスを待っている。
+        // `end_frame`から次の`begin_frame`までは、最後に提出したフレームのコンテキストを待っていない
+        let reused = if self.recording {
+            self.frame_count
+        } else {
+            self.frame_count - 1
+        };
+        if frame + count <= reused {
+            return Ok(true);
+        }
+        let fence = self.frames[(frame % count) as usize].in_flight_fence;
+        Ok(unsafe { self.device.get_fence_status(fence)? })
+    }
+}
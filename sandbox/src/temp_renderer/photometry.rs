@@ -0,0 +1,502 @@
+use super::assets::f32_to_f16;
+use super::error::{RendererError, Result};
+use super::{
+    DescriptorBinding, DescriptorWriter, Light, LightKind, Renderer, SamplerDesc, TextureDesc,
+    TextureId, TextureKind,
+};
+use ash::vk;
+use std::f32::consts::PI;
+
+/// IESのプロファイルのテクスチャのフォーマット。最大の光度で割った値を入れる
+pub const IES_PROFILE_FORMAT: vk::Format = vk::Format::R16_SFLOAT;
+/// プロファイルのテクスチャの幅(水平角0から360度)と高さ(鉛直角0から180度)
+pub const IES_PROFILE_RESOLUTION: u32 = 64;
+/// IESファイルで受け付ける角度とTILTの組の数の上限。実際のファイルは多くても数百
+pub const MAX_IES_COUNT: usize = 1024;
+
+/// 物理単位のライトの強さ
+///
+/// シェーダーの`intensity`はディレクショナルライトでは照度(lx)、ポイントライトとスポットライトでは
+/// 光度(cd)として扱う。露出は`PhysicalCamera`のEVで合わせる。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PhotometricIntensity {
+    /// 照度。ディレクショナルライトに使う。晴天の太陽で約100000lx
+    Lux(f32),
+    /// 光度。ポイントライトとスポットライトに使う
+    Candela(f32),
+    /// 光束。ポイントライトとスポットライトに使い、照らす立体角で割って光度にする。
+    /// 60Wの白熱電球で約800lm
+    Lumens(f32),
+}
+
+impl Light {
+    /// 物理単位の強さのライト
+    ///
+    /// スポットライトの光束は外側の角度の円錐に集まるものとする。ディレクショナルライトには
+    /// 照度、それ以外には光度か光束を渡す。
+    pub fn photometric(
+        kind: LightKind,
+        color: [f32; 3],
+        intensity: PhotometricIntensity,
+    ) -> Result<Self> {
+        let intensity = match (kind, intensity) {
+            (LightKind::Directional, PhotometricIntensity::Lux(lux)) => lux,
+            (LightKind::Directional, _) => {
+                return Err(RendererError::Validation(
+                    "directional light intensity must be given in lux".to_owned(),
+                ))
+            }
+            (_, PhotometricIntensity::Lux(_)) => {
+                return Err(RendererError::Validation(
+                    "point and spot light intensity must be given in candela or lumens".to_owned(),
+                ))
+            }
+            (_, PhotometricIntensity::Candela(candela)) => candela,
+            (LightKind::Point { .. }, PhotometricIntensity::Lumens(lumens)) => lumens / (4.0 * PI),
+            (
+                LightKind::Spot {
+                    outer_cone_angle, ..
+                },
+                PhotometricIntensity::Lumens(lumens),
+            ) => lumens / (2.0 * PI * (1.0 - outer_cone_angle.cos())).max(f32::EPSILON),
+        };
+        Ok(Self {
+            kind,
+            color,
+            intensity,
+            ies_profile: None,
+        })
+    }
+}
+
+/// IES LM-63の配光データ。タイプCの測光だけに対応する
+///
+/// 鉛直角0度がライトの向き、水平角はライトの向きに垂直な基準方向から測る。
+#[derive(Debug, Clone, PartialEq)]
+pub struct IesProfile {
+    /// 鉛直角(度)。昇順
+    pub vertical_angles: Vec<f32>,
+    /// 水平角(度)。昇順で、最後の値で対称性を表す(0なら回転対称、90なら4象限、180なら左右対称)
+    pub horizontal_angles: Vec<f32>,
+    /// 水平角ごとに鉛直角の数だけ並べた光度(cd)。倍率とバラスト係数を掛けた値
+    pub candela: Vec<f32>,
+}
+
+impl IesProfile {
+    /// IESファイルの中身を読む
+    pub fn parse(source: &str) -> Result<Self> {
+        let invalid = |message: &str| RendererError::Validation(format!("IES: {}", message));
+        let mut lines = source.lines();
+        let tilt = lines
+            .by_ref()
+            .map(str::trim)
+            .find_map(|line| line.strip_prefix("TILT="))
+            .ok_or_else(|| invalid("missing TILT line"))?
+            .trim()
+            .to_owned();
+        let mut values = lines
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|token| !token.is_empty())
+            .map(|token| {
+                token
+                    .parse::<f32>()
+                    .map_err(|_| invalid(&format!("invalid number '{}'", token)))
+            });
+        let mut next = || {
+            values
+                .next()
+                .unwrap_or_else(|| Err(invalid("unexpected end of file")))
+        };
+        // 数は負でも小数でもない整数で、`MAX_IES_COUNT`までに限る
+        let count = |value: f32, what: &str| {
+            if value >= 0.0 && value.fract() == 0.0 && value <= MAX_IES_COUNT as f32 {
+                Ok(value as usize)
+            } else {
+                Err(invalid(&format!("invalid {} count {}", what, value)))
+            }
+        };
+        match tilt.as_str() {
+            "NONE" => {}
+            // ランプの傾きによる変化は使わないので読み飛ばす
+            "INCLUDE" => {
+                next()?;
+                let pairs = count(next()?, "TILT pair")?;
+                let values = pairs
+                    .checked_mul(2)
+                    .ok_or_else(|| invalid("too many TILT pairs"))?;
+                for _ in 0..values {
+                    next()?;
+                }
+            }
+            _ => return Err(invalid("TILT data in a separate file is not supported")),
+        }
+        let _lamp_count = next()?;
+        let _lumens_per_lamp = next()?;
+        let multiplier = next()?;
+        let vertical_count = count(next()?, "vertical angle")?;
+        let horizontal_count = count(next()?, "horizontal angle")?;
+        let photometric_type = next()?;
+        // 単位、幅、長さ、高さ
+        for _ in 0..4 {
+            next()?;
+        }
+        let ballast_factor = next()?;
+        let ballast_lamp_factor = next()?;
+        let _input_watts = next()?;
+        if photometric_type != 1.0 {
+            return Err(invalid("only type C photometry is supported"));
+        }
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err(invalid("no angles"));
+        }
+        let vertical_angles = (0..vertical_count)
+            .map(|_| next())
+            .collect::<Result<Vec<_>>>()?;
+        let horizontal_angles = (0..horizontal_count)
+            .map(|_| next())
+            .collect::<Result<Vec<_>>>()?;
+        let scale = multiplier * ballast_factor * ballast_lamp_factor;
+        let candela_count = vertical_count
+            .checked_mul(horizontal_count)
+            .ok_or_else(|| invalid("too many candela values"))?;
+        let candela = (0..candela_count)
+            .map(|_| next().map(|value| value * scale))
+            .collect::<Result<Vec<_>>>()?;
+        let ascending = |angles: &[f32]| angles.windows(2).all(|pair| pair[0] < pair[1]);
+        if !ascending(&vertical_angles) || !ascending(&horizontal_angles) {
+            return Err(invalid("angles must be in ascending order"));
+        }
+        Ok(Self {
+            vertical_angles,
+            horizontal_angles,
+            candela,
+        })
+    }
+
+    /// 最大の光度。このプロファイルを使うライトの`intensity`にする
+    pub fn max_candela(&self) -> f32 {
+        self.candela.iter().copied().fold(0.0, f32::max)
+    }
+
+    /// 鉛直角`vertical`、水平角`horizontal`(度)の光度。測定の範囲外は0
+    pub fn sample(&self, vertical: f32, horizontal: f32) -> f32 {
+        let last = *self.horizontal_angles.last().unwrap();
+        let horizontal = horizontal.rem_euclid(360.0);
+        let horizontal = if last == 0.0 {
+            0.0
+        } else if last <= 90.0 {
+            let h = horizontal % 180.0;
+            if h > 90.0 {
+                180.0 - h
+            } else {
+                h
+            }
+        } else if last <= 180.0 && horizontal > 180.0 {
+            360.0 - horizontal
+        } else {
+            horizontal
+        };
+        let Some((v0, v1, tv)) = lerp_position(&self.vertical_angles, vertical) else {
+            return 0.0;
+        };
+        let (h0, h1, th) =
+            lerp_position(&self.horizontal_angles, horizontal).unwrap_or_else(|| {
+                // 360度の手前で測定が終わっていれば、最後の角度の値を使う
+                let index = self.horizontal_angles.len() - 1;
+                (index, index, 0.0)
+            });
+        let count = self.vertical_angles.len();
+        let at = |h: usize, v: usize| self.candela[h * count + v];
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        lerp(
+            lerp(at(h0, v0), at(h0, v1), tv),
+            lerp(at(h1, v0), at(h1, v1), tv),
+            th,
+        )
+    }
+
+    /// `IES_PROFILE_RESOLUTION`四方の、最大の光度で割った値
+    pub fn texels(&self) -> Vec<f32> {
+        let max = self.max_candela().max(f32::EPSILON);
+        let size = IES_PROFILE_RESOLUTION;
+        (0..size)
+            .flat_map(|y| (0..size).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let horizontal = (x as f32 + 0.5) / size as f32 * 360.0;
+                let vertical = (y as f32 + 0.5) / size as f32 * 180.0;
+                self.sample(vertical, horizontal) / max
+            })
+            .collect()
+    }
+}
+
+/// `angles`の中で`angle`を挟む2つの位置と補間の割合。範囲外なら`None`
+fn lerp_position(angles: &[f32], angle: f32) -> Option<(usize, usize, f32)> {
+    let first = angles[0];
+    let last = *angles.last().unwrap();
+    if angle < first || angle > last {
+        return None;
+    }
+    if angles.len() == 1 {
+        return Some((0, 0, 0.0));
+    }
+    let upper = angles
+        .iter()
+        .position(|&a| a >= angle)
+        .unwrap()
+        .clamp(1, angles.len() - 1);
+    let (a0, a1) = (angles[upper - 1], angles[upper]);
+    Some((upper - 1, upper, (angle - a0) / (a1 - a0)))
+}
+
+/// `set_ies_profiles`で作るプロファイルのテクスチャ配列
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IesProfiles {
+    pub texture: TextureId,
+    /// `ies_set_layout`のセット
+    pub descriptor_set: vk::DescriptorSet,
+}
+
+impl Renderer {
+    /// IESのプロファイルのデスクリプタセットレイアウト。バインディング0が`sampler2DArray`
+    ///
+    /// ```glsl
+    /// #include "tempura/ies.glsl"
+    /// layout(set = 6, binding = 0) uniform sampler2DArray ies_profiles;
+    /// // lは光源から表面への向き。spot_cos.wがプロファイルの番号
+    /// float attenuation = tempura_ies_attenuation(ies_profiles, light.spot_cos.w, l, light.direction_type.xyz);
+    /// ```
+    pub fn ies_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[DescriptorBinding::sampled_image(
+            0,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE,
+        )])
+    }
+
+    /// ライトの`ies_profile`で参照するプロファイルを設定する
+    ///
+    /// `profiles`の順にテクスチャ配列のレイヤーにする。以前のテクスチャは使用中のフレームが
+    /// 完了してから破棄する。
+    pub fn set_ies_profiles(&mut self, profiles: &[IesProfile]) -> Result<()> {
+        if profiles.is_empty() {
+            return Err(RendererError::Validation(
+                "at least one IES profile is needed".to_owned(),
+            ));
+        }
+        let layout = self.ies_set_layout()?;
+        let texture = self.create_texture(&TextureDesc {
+            kind: TextureKind::Texture2DArray {
+                layers: profiles.len() as u32,
+            },
+            format: IES_PROFILE_FORMAT,
+            width: IES_PROFILE_RESOLUTION,
+            height: IES_PROFILE_RESOLUTION,
+            mip_levels: 1,
+            // 水平角は一周してつながる
+            sampler: SamplerDesc {
+                address_modes: [
+                    vk::SamplerAddressMode::REPEAT,
+                    vk::SamplerAddressMode::CLAMP_TO_EDGE,
+                    vk::SamplerAddressMode::CLAMP_TO_EDGE,
+                ],
+                ..SamplerDesc::clamp_to_edge()
+            },
+            storage: false,
+        })?;
+        let written = profiles
+            .iter()
+            .enumerate()
+            .try_for_each(|(layer, profile)| {
+                let data: Vec<u8> = profile
+                    .texels()
+                    .into_iter()
+                    .flat_map(|value| f32_to_f16(value).to_ne_bytes())
+                    .collect();
+                self.write_texture_layer(texture, layer as u32, 0, &data)
+            })
+            .and_then(|_| self.allocate_descriptor_set(layout));
+        let descriptor_set = match written {
+            Ok(descriptor_set) => descriptor_set,
+            Err(err) => {
+                self.destroy_texture(texture)?;
+                return Err(err);
+            }
+        };
+        unsafe {
+            DescriptorWriter::new()
+                .texture(0, self.textures.get(texture).unwrap())
+                .update(&self.device, descriptor_set);
+        }
+        if let Some(old) = self.ies_profiles.replace(IesProfiles {
+            texture,
+            descriptor_set,
+        }) {
+            self.destroy_texture(old.texture)?;
+        }
+        Ok(())
+    }
+
+    pub fn ies_profiles(&self) -> Option<&IesProfiles> {
+        self.ies_profiles.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 鉛直角3つ、水平角2つ(4象限対称)のプロファイル
+    const PROFILE: &str = "IESNA:LM-63-2002
+[TEST] tempura
+TILT=NONE
+1 1000 2 3 2 1 1 0 0 0
+0.5 1 10
+0 45 90
+0 90
+100 50 0
+80 40 0
+";
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn parses_angles_and_scales_candela() {
+        let profile = IesProfile::parse(PROFILE).unwrap();
+        assert_eq!(profile.vertical_angles, vec![0.0, 45.0, 90.0]);
+        assert_eq!(profile.horizontal_angles, vec![0.0, 90.0]);
+        // 倍率2とバラスト係数0.5を掛ける
+        assert_eq!(profile.candela, vec![100.0, 50.0, 0.0, 80.0, 40.0, 0.0]);
+        assert_eq!(profile.max_candela(), 100.0);
+    }
+
+    #[test]
+    fn skips_included_tilt_data() {
+        let source = PROFILE.replace("TILT=NONE", "TILT=INCLUDE\n1\n2\n0 90\n1 1");
+        assert_eq!(
+            IesProfile::parse(&source).unwrap(),
+            IesProfile::parse(PROFILE).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_counts() {
+        let with_counts = |counts: &str| PROFILE.replace("1 1000 2 3 2 1", counts);
+        for counts in [
+            "1 1000 2 -3 2 1",
+            "1 1000 2 3 2.5 1",
+            "1 1000 2 4000000000 4000000000 1",
+            "1 1000 2 NaN 2 1",
+            "1 1000 2 0 2 1",
+        ] {
+            assert!(
+                IesProfile::parse(&with_counts(counts)).is_err(),
+                "{}",
+                counts
+            );
+        }
+        let tilt = PROFILE.replace("TILT=NONE", "TILT=INCLUDE\n1\n1e30");
+        assert!(IesProfile::parse(&tilt).is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_or_broken_files() {
+        // タイプB
+        assert!(IesProfile::parse(&PROFILE.replace("3 2 1 1", "3 2 2 1")).is_err());
+        assert!(IesProfile::parse(&PROFILE.replace("0 45 90", "0 90 45")).is_err());
+        assert!(IesProfile::parse(&PROFILE.replace("TILT=NONE", "TILT=lamp.tlt")).is_err());
+        assert!(IesProfile::parse(&PROFILE.replace("80 40 0", "80 40")).is_err());
+        assert!(IesProfile::parse("1 1000 2 3 2 1").is_err());
+    }
+
+    #[test]
+    fn sample_folds_quadrant_symmetry() {
+        let profile = IesProfile::parse(PROFILE).unwrap();
+        assert_close(profile.sample(0.0, 0.0), 100.0);
+        assert_close(profile.sample(45.0, 90.0), 40.0);
+        assert_close(profile.sample(22.5, 0.0), 75.0);
+        assert_close(profile.sample(45.0, 45.0), 45.0);
+        // 150度は30度、270度は90度、-30度は30度に折り返す
+        assert_close(profile.sample(45.0, 150.0), 50.0 - 10.0 / 3.0);
+        assert_close(profile.sample(45.0, 270.0), 40.0);
+        assert_close(profile.sample(45.0, -30.0), 50.0 - 10.0 / 3.0);
+        // 測定の範囲外
+        assert_eq!(profile.sample(120.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn sample_folds_rotational_and_bilateral_symmetry() {
+        let rotational = IesProfile {
+            vertical_angles: vec![0.0, 90.0],
+            horizontal_angles: vec![0.0],
+            candela: vec![10.0, 0.0],
+        };
+        assert_close(rotational.sample(45.0, 0.0), 5.0);
+        assert_close(rotational.sample(45.0, 200.0), 5.0);
+        let bilateral = IesProfile {
+            vertical_angles: vec![0.0],
+            horizontal_angles: vec![0.0, 180.0],
+            candela: vec![0.0, 180.0],
+        };
+        assert_close(bilateral.sample(0.0, 90.0), 90.0);
+        assert_close(bilateral.sample(0.0, 270.0), 90.0);
+        assert_close(bilateral.sample(0.0, 300.0), 60.0);
+    }
+
+    #[test]
+    fn texels_are_normalized() {
+        let texels = IesProfile::parse(PROFILE).unwrap().texels();
+        let size = IES_PROFILE_RESOLUTION as usize;
+        assert_eq!(texels.len(), size * size);
+        assert!(texels.iter().all(|&t| (0.0..=1.0).contains(&t)));
+        // 下半分は測定の範囲外
+        assert!(texels[size * size / 2..].iter().all(|&t| t == 0.0));
+    }
+
+    #[test]
+    fn photometric_converts_units() {
+        let color = [1.0; 3];
+        let point = LightKind::Point { range: None };
+        let light =
+            Light::photometric(point, color, PhotometricIntensity::Lumens(4.0 * PI)).unwrap();
+        assert_close(light.intensity, 1.0);
+        let light = Light::photometric(point, color, PhotometricIntensity::Candela(3.0)).unwrap();
+        assert_close(light.intensity, 3.0);
+        assert_eq!(light.ies_profile, None);
+        // 外側60度の円錐の立体角はπ
+        let spot = LightKind::Spot {
+            range: None,
+            inner_cone_angle: 0.5,
+            outer_cone_angle: PI / 3.0,
+        };
+        let light = Light::photometric(spot, color, PhotometricIntensity::Lumens(PI)).unwrap();
+        assert_close(light.intensity, 1.0);
+        let sun = Light::photometric(
+            LightKind::Directional,
+            color,
+            PhotometricIntensity::Lux(100000.0),
+        )
+        .unwrap();
+        assert_eq!(sun.intensity, 100000.0);
+    }
+
+    #[test]
+    fn photometric_rejects_mismatched_units() {
+        let color = [1.0; 3];
+        let point = LightKind::Point { range: None };
+        assert!(Light::photometric(point, color, PhotometricIntensity::Lux(1.0)).is_err());
+        assert!(Light::photometric(
+            LightKind::Directional,
+            color,
+            PhotometricIntensity::Lumens(1.0)
+        )
+        .is_err());
+        assert!(Light::photometric(
+            LightKind::Directional,
+            color,
+            PhotometricIntensity::Candela(1.0)
+        )
+        .is_err());
+    }
+}
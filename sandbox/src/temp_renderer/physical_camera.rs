@@ -0,0 +1,187 @@
+use super::camera_effects::{DepthOfFieldSettings, MotionBlurSettings};
+use super::error::{RendererError, Result};
+use super::view_settings::ViewSettings;
+use super::{Camera, Projection, Renderer};
+
+/// ISO 100でのセンサーのノイズの強さ。ISOの平方根に比例して強くなる
+pub const ISO_100_GRAIN: f32 = 0.01;
+
+/// 実際のカメラのパラメーターから画角、露出、被写界深度、モーションブラーを決めるカメラ
+///
+/// 既定値は35mmフルサイズに50mm、f/2.8、24fpsで180度のシャッター(1/48秒)、ISO 100の
+/// 映画のカメラの設定。露出は物理的な明るさ(cd/m²)を前提にするので、ライトの強さも
+/// 実際の値(晴天の太陽で約100000ルクス)にする。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhysicalCamera {
+    /// 焦点距離(メートル)
+    pub focal_length: f32,
+    /// センサーの幅と高さ(メートル)
+    pub sensor_size: [f32; 2],
+    /// 絞り値
+    pub f_number: f32,
+    /// シャッター速度(秒)
+    pub shutter_speed: f32,
+    pub iso: f32,
+    /// ピントの合う距離(メートル)
+    pub focus_distance: f32,
+    /// 露出補正(EV)。正で明るくなる
+    pub exposure_compensation: f32,
+}
+
+impl Default for PhysicalCamera {
+    fn default() -> Self {
+        Self {
+            focal_length: 0.05,
+            sensor_size: [0.036, 0.024],
+            f_number: 2.8,
+            shutter_speed: 1.0 / 48.0,
+            iso: 100.0,
+            focus_distance: 10.0,
+            exposure_compensation: 0.0,
+        }
+    }
+}
+
+impl PhysicalCamera {
+    /// 幅/高さが`aspect`の画面に映るセンサーの高さ
+    ///
+    /// センサーと画面の縦横比が違う場合は、画面を埋めるようにセンサーをはみ出した分を切り取る。
+    pub fn effective_sensor_height(&self, aspect: f32) -> f32 {
+        let [width, height] = self.sensor_size;
+        if aspect > width / height {
+            width / aspect
+        } else {
+            height
+        }
+    }
+
+    /// 幅/高さが`aspect`の画面での垂直方向の画角(ラジアン)
+    pub fn fov_y(&self, aspect: f32) -> f32 {
+        2.0 * (self.effective_sensor_height(aspect) / (2.0 * self.focal_length)).atan()
+    }
+
+    /// ISO 100に換算した露出値
+    pub fn ev100(&self) -> f32 {
+        (self.f_number * self.f_number / self.shutter_speed * 100.0 / self.iso).log2()
+            - self.exposure_compensation
+    }
+
+    /// トーンマッピングの前に掛ける露出。センサーが飽和する輝度が1になる
+    pub fn exposure(&self) -> f32 {
+        // 飽和する輝度は1.2 * 2^EV100(レンズの透過率などを含めた係数)
+        1.0 / (1.2 * self.ev100().exp2())
+    }
+
+    /// ISOから決まるセンサーのノイズの強さ
+    pub fn grain(&self) -> f32 {
+        ISO_100_GRAIN * (self.iso / 100.0).sqrt()
+    }
+
+    pub fn depth_of_field(&self, aspect: f32, max_coc_radius: f32) -> DepthOfFieldSettings {
+        DepthOfFieldSettings {
+            enabled: true,
+            focus_distance: self.focus_distance,
+            f_number: self.f_number,
+            focal_length: self.focal_length,
+            sensor_height: self.effective_sensor_height(aspect),
+            max_coc_radius,
+        }
+    }
+
+    /// `frame_interval`秒ごとに描画する場合のモーションブラー
+    ///
+    /// シャッターがフレームの間隔より長い場合は、フレームの間隔で打ち切る。
+    pub fn motion_blur(&self, frame_interval: f32) -> MotionBlurSettings {
+        MotionBlurSettings {
+            enabled: true,
+            shutter: (self.shutter_speed / frame_interval).clamp(0.0, 1.0),
+            ..Default::default()
+        }
+    }
+
+    /// `camera`の射影をこのカメラの画角の透視投影にする。近くと遠くのクリップ面はそのまま使う
+    pub fn apply_to_camera(&self, camera: &mut Camera) {
+        let fov_y = self.fov_y(camera.aspect);
+        camera.projection = match camera.projection {
+            Projection::Perspective { near, far, .. } => {
+                Projection::Perspective { fov_y, near, far }
+            }
+            Projection::Orthographic { near, far, .. } => Projection::Perspective {
+                fov_y,
+                near,
+                far: Some(far),
+            },
+        };
+    }
+
+    /// 露出、ノイズ、被写界深度、モーションブラーを上書きするビューの設定
+    ///
+    /// ボケの上限は`base.depth_of_field`、モーションブラーのサンプル数などは`base.motion_blur`
+    /// のものを使う。`None`なら既定値を使う。
+    pub fn view_settings(
+        &self,
+        base: &ViewSettings,
+        aspect: f32,
+        frame_interval: f32,
+    ) -> ViewSettings {
+        let depth_of_field = base.depth_of_field.unwrap_or_default();
+        let motion_blur = base.motion_blur.unwrap_or_default();
+        ViewSettings {
+            exposure: Some(self.exposure()),
+            grain: Some(self.grain()),
+            depth_of_field: Some(self.depth_of_field(aspect, depth_of_field.max_coc_radius)),
+            motion_blur: Some(MotionBlurSettings {
+                sample_count: motion_blur.sample_count,
+                max_blur_radius: motion_blur.max_blur_radius,
+                ..self.motion_blur(frame_interval)
+            }),
+            ..*base
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        let positive = [
+            self.focal_length,
+            self.sensor_size[0],
+            self.sensor_size[1],
+            self.f_number,
+            self.shutter_speed,
+            self.iso,
+        ];
+        if positive.iter().any(|v| !v.is_finite() || *v <= 0.0)
+            || !self.exposure_compensation.is_finite()
+            || self.focus_distance <= self.focal_length
+        {
+            return Err(RendererError::Validation(format!(
+                "invalid physical camera: {:?}",
+                self
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Renderer {
+    /// `physical`の画角を`camera`に設定し、露出などを現在のビューの設定に重ねる
+    ///
+    /// 他の項目のビューの上書きはそのまま残る。被写界深度とモーションブラーの射影は
+    /// `set_camera_effect_projection`で別に設定する。
+    pub fn set_physical_camera(
+        &mut self,
+        physical: &PhysicalCamera,
+        camera: &mut Camera,
+        frame_interval: f32,
+    ) -> Result<()> {
+        physical.validate()?;
+        if !frame_interval.is_finite() || frame_interval <= 0.0 {
+            return Err(RendererError::Validation(format!(
+                "invalid frame interval: {}",
+                frame_interval
+            )));
+        }
+        let settings = physical.view_settings(&self.view_settings, camera.aspect, frame_interval);
+        self.set_view_settings(settings)?;
+        physical.apply_to_camera(camera);
+        Ok(())
+    }
+}
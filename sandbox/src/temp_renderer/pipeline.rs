@@ -0,0 +1,541 @@
+use super::error::{RendererError, Result};
+use super::renderer::depth_aspect_mask;
+use ash::{vk, Device};
+use std::ffi::{CStr, CString};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    Opaque,
+    AlphaBlend,
+    PremultipliedAlpha,
+    Additive,
+    /// 重み付きブレンドのOITの透過率。`dst * (1 - src)`
+    Revealage,
+}
+
+impl BlendMode {
+    fn attachment_state(self) -> vk::PipelineColorBlendAttachmentState {
+        let builder = vk::PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .color_blend_op(vk::BlendOp::ADD)
+            .alpha_blend_op(vk::BlendOp::ADD);
+        let (src_color, dst_color, src_alpha, dst_alpha) = match self {
+            BlendMode::Opaque => return *builder.blend_enable(false),
+            BlendMode::AlphaBlend => (
+                vk::BlendFactor::SRC_ALPHA,
+                vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+                vk::BlendFactor::ONE,
+                vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            ),
+            BlendMode::PremultipliedAlpha => (
+                vk::BlendFactor::ONE,
+                vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+                vk::BlendFactor::ONE,
+                vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            ),
+            BlendMode::Additive => (
+                vk::BlendFactor::ONE,
+                vk::BlendFactor::ONE,
+                vk::BlendFactor::ONE,
+                vk::BlendFactor::ONE,
+            ),
+            BlendMode::Revealage => (
+                vk::BlendFactor::ZERO,
+                vk::BlendFactor::ONE_MINUS_SRC_COLOR,
+                vk::BlendFactor::ZERO,
+                vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            ),
+        };
+        *builder
+            .blend_enable(true)
+            .src_color_blend_factor(src_color)
+            .dst_color_blend_factor(dst_color)
+            .src_alpha_blend_factor(src_alpha)
+            .dst_alpha_blend_factor(dst_alpha)
+    }
+}
+
+#[derive(Clone)]
+struct ShaderStage {
+    stage: vk::ShaderStageFlags,
+    module: vk::ShaderModule,
+    entry_point: CString,
+}
+
+pub struct GraphicsPipeline {
+    pub pipeline: vk::Pipeline,
+    pub layout: vk::PipelineLayout,
+    pub render_pass: vk::RenderPass,
+    pub subpass: u32,
+    /// レイアウトに含まれるプッシュ定数の範囲
+    pub push_constant_ranges: Vec<vk::PushConstantRange>,
+}
+
+impl GraphicsPipeline {
+    /// # Safety
+    /// `device`は作成に使ったデバイスで、GPUがこのパイプラインを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device) {
+        device.destroy_pipeline(self.pipeline, None);
+        device.destroy_pipeline_layout(self.layout, None);
+    }
+}
+
+/// グラフィックスパイプラインのビルダー
+///
+/// デフォルトはトライアングルリスト、裏面カリング(反時計回りが表)、深度テスト/書き込み有効、
+/// 不透明のカラーアタッチメント1つ、ビューポートとシザーは動的ステート。
+#[derive(Clone)]
+pub struct PipelineBuilder {
+    stages: Vec<ShaderStage>,
+    vertex_bindings: Vec<vk::VertexInputBindingDescription>,
+    vertex_attributes: Vec<vk::VertexInputAttributeDescription>,
+    topology: vk::PrimitiveTopology,
+    polygon_mode: vk::PolygonMode,
+    cull_mode: vk::CullModeFlags,
+    front_face: vk::FrontFace,
+    depth_bias: Option<(f32, f32)>,
+    samples: vk::SampleCountFlags,
+    depth_test: bool,
+    depth_write: bool,
+    depth_compare_op: vk::CompareOp,
+    stencil: Option<(vk::StencilOpState, vk::StencilOpState)>,
+    color_blend_attachments: Vec<vk::PipelineColorBlendAttachmentState>,
+    dynamic_states: Vec<vk::DynamicState>,
+    set_layouts: Vec<vk::DescriptorSetLayout>,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
+    /// 動的レンダリング用のカラーと深度のフォーマット
+    rendering_formats: Option<(Vec<vk::Format>, vk::Format)>,
+}
+
+impl Default for PipelineBuilder {
+    fn default() -> Self {
+        Self {
+            stages: Vec::new(),
+            vertex_bindings: Vec::new(),
+            vertex_attributes: Vec::new(),
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            polygon_mode: vk::PolygonMode::FILL,
+            cull_mode: vk::CullModeFlags::BACK,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            depth_bias: None,
+            samples: vk::SampleCountFlags::TYPE_1,
+            depth_test: true,
+            depth_write: true,
+            depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
+            stencil: None,
+            color_blend_attachments: vec![BlendMode::Opaque.attachment_state()],
+            dynamic_states: vec![vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR],
+            set_layouts: Vec::new(),
+            push_constant_ranges: Vec::new(),
+            rendering_formats: None,
+        }
+    }
+}
+
+impl PipelineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn shader_stage(
+        mut self,
+        stage: vk::ShaderStageFlags,
+        module: vk::ShaderModule,
+        entry_point: &CStr,
+    ) -> Self {
+        self.stages.push(ShaderStage {
+            stage,
+            module,
+            entry_point: entry_point.to_owned(),
+        });
+        self
+    }
+
+    /// エントリーポイントは`main`
+    pub fn vertex_shader(self, module: vk::ShaderModule) -> Self {
+        self.shader_stage(vk::ShaderStageFlags::VERTEX, module, c"main")
+    }
+
+    /// エントリーポイントは`main`
+    pub fn fragment_shader(self, module: vk::ShaderModule) -> Self {
+        self.shader_stage(vk::ShaderStageFlags::FRAGMENT, module, c"main")
+    }
+
+    pub fn vertex_binding(
+        mut self,
+        binding: u32,
+        stride: u32,
+        input_rate: vk::VertexInputRate,
+    ) -> Self {
+        self.vertex_bindings
+            .push(vk::VertexInputBindingDescription {
+                binding,
+                stride,
+                input_rate,
+            });
+        self
+    }
+
+    pub fn vertex_attribute(
+        mut self,
+        location: u32,
+        binding: u32,
+        format: vk::Format,
+        offset: u32,
+    ) -> Self {
+        self.vertex_attributes
+            .push(vk::VertexInputAttributeDescription {
+                location,
+                binding,
+                format,
+                offset,
+            });
+        self
+    }
+
+    pub fn topology(mut self, topology: vk::PrimitiveTopology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    pub fn polygon_mode(mut self, polygon_mode: vk::PolygonMode) -> Self {
+        self.polygon_mode = polygon_mode;
+        self
+    }
+
+    pub fn cull_mode(mut self, cull_mode: vk::CullModeFlags) -> Self {
+        self.cull_mode = cull_mode;
+        self
+    }
+
+    pub fn front_face(mut self, front_face: vk::FrontFace) -> Self {
+        self.front_face = front_face;
+        self
+    }
+
+    pub fn depth_bias(mut self, constant_factor: f32, slope_factor: f32) -> Self {
+        self.depth_bias = Some((constant_factor, slope_factor));
+        self
+    }
+
+    pub fn samples(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    pub fn depth_test(mut self, enable: bool) -> Self {
+        self.depth_test = enable;
+        self
+    }
+
+    pub fn depth_write(mut self, enable: bool) -> Self {
+        self.depth_write = enable;
+        self
+    }
+
+    pub fn depth_compare_op(mut self, compare_op: vk::CompareOp) -> Self {
+        self.depth_compare_op = compare_op;
+        self
+    }
+
+    /// ステンシルテストを有効にする
+    ///
+    /// 描画ごとに参照値やマスクを変える場合は`STENCIL_REFERENCE`などを`dynamic_state`で追加する。
+    pub fn stencil(mut self, front: vk::StencilOpState, back: vk::StencilOpState) -> Self {
+        self.stencil = Some((front, back));
+        self
+    }
+
+    /// 全カラーアタッチメントのブレンドモードを置き換える
+    pub fn color_attachments(mut self, blend_modes: &[BlendMode]) -> Self {
+        self.color_blend_attachments = blend_modes
+            .iter()
+            .map(|blend_mode| blend_mode.attachment_state())
+            .collect();
+        self
+    }
+
+    pub fn blend_mode(self, blend_mode: BlendMode) -> Self {
+        self.color_attachments(&[blend_mode])
+    }
+
+    /// フラグメントシェーダーとカラーアタッチメントを外し、深度だけを書き込むパイプラインにする
+    pub fn depth_only(mut self) -> Self {
+        self.stages
+            .retain(|stage| stage.stage != vk::ShaderStageFlags::FRAGMENT);
+        self.color_blend_attachments.clear();
+        self.depth_test(true).depth_write(true)
+    }
+
+    pub fn dynamic_state(mut self, dynamic_state: vk::DynamicState) -> Self {
+        if !self.dynamic_states.contains(&dynamic_state) {
+            self.dynamic_states.push(dynamic_state);
+        }
+        self
+    }
+
+    pub fn descriptor_set_layouts(mut self, set_layouts: &[vk::DescriptorSetLayout]) -> Self {
+        self.set_layouts = set_layouts.to_vec();
+        self
+    }
+
+    pub fn push_constant_range(
+        mut self,
+        stage_flags: vk::ShaderStageFlags,
+        offset: u32,
+        size: u32,
+    ) -> Self {
+        self.push_constant_ranges.push(vk::PushConstantRange {
+            stage_flags,
+            offset,
+            size,
+        });
+        self
+    }
+
+    /// 動的レンダリングで使うパイプラインにする
+    ///
+    /// 深度を使わない場合は`depth_format`に`UNDEFINED`を指定する。`build`に渡すレンダーパスはnullにする。
+    pub fn rendering_formats(
+        mut self,
+        color_formats: &[vk::Format],
+        depth_format: vk::Format,
+    ) -> Self {
+        self.rendering_formats = Some((color_formats.to_vec(), depth_format));
+        self
+    }
+
+    pub fn shader_modules(&self) -> impl Iterator<Item = vk::ShaderModule> + '_ {
+        self.stages.iter().map(|stage| stage.module)
+    }
+
+    pub(crate) fn shader_stages(
+        &self,
+    ) -> impl Iterator<Item = (vk::ShaderStageFlags, vk::ShaderModule)> + '_ {
+        self.stages.iter().map(|stage| (stage.stage, stage.module))
+    }
+
+    pub(crate) fn set_stage_module(
+        &mut self,
+        stage: vk::ShaderStageFlags,
+        module: vk::ShaderModule,
+    ) {
+        for shader_stage in self.stages.iter_mut() {
+            if shader_stage.stage == stage {
+                shader_stage.module = module;
+            }
+        }
+    }
+
+    /// Vulkanに渡す前に、誤用を分かりやすいエラーとして検出する
+    pub fn validate(&self) -> Result<()> {
+        let error = |message: String| Err(RendererError::Validation(message));
+
+        if !self
+            .stages
+            .iter()
+            .any(|stage| stage.stage == vk::ShaderStageFlags::VERTEX)
+        {
+            return error("graphics pipeline has no vertex shader stage".to_owned());
+        }
+        for (index, stage) in self.stages.iter().enumerate() {
+            if stage.module == vk::ShaderModule::null() {
+                return error(format!("shader stage {:?} has a null module", stage.stage));
+            }
+            if self.stages[..index]
+                .iter()
+                .any(|other| other.stage == stage.stage)
+            {
+                return error(format!(
+                    "shader stage {:?} is set more than once",
+                    stage.stage
+                ));
+            }
+        }
+
+        for (index, binding) in self.vertex_bindings.iter().enumerate() {
+            if self.vertex_bindings[..index]
+                .iter()
+                .any(|other| other.binding == binding.binding)
+            {
+                return error(format!(
+                    "vertex binding {} is declared twice",
+                    binding.binding
+                ));
+            }
+        }
+        for (index, attribute) in self.vertex_attributes.iter().enumerate() {
+            let binding = self
+                .vertex_bindings
+                .iter()
+                .find(|binding| binding.binding == attribute.binding);
+            match binding {
+                None => {
+                    return error(format!(
+                        "vertex attribute at location {} references undeclared binding {}",
+                        attribute.location, attribute.binding
+                    ))
+                }
+                Some(binding) if binding.stride > 0 && attribute.offset >= binding.stride => {
+                    return error(format!(
+                        "vertex attribute at location {} has offset {} outside stride {}",
+                        attribute.location, attribute.offset, binding.stride
+                    ))
+                }
+                _ => {}
+            }
+            if self.vertex_attributes[..index]
+                .iter()
+                .any(|other| other.location == attribute.location)
+            {
+                return error(format!(
+                    "vertex attribute location {} is used twice",
+                    attribute.location
+                ));
+            }
+        }
+
+        if let Some((color_formats, _)) = self.rendering_formats.as_ref() {
+            if color_formats.len() != self.color_blend_attachments.len() {
+                return error(format!(
+                    "{} color formats given for {} color attachments",
+                    color_formats.len(),
+                    self.color_blend_attachments.len()
+                ));
+            }
+        }
+
+        for range in self.push_constant_ranges.iter() {
+            if range.offset % 4 != 0 || range.size == 0 || range.size % 4 != 0 {
+                return error(format!(
+                    "push constant range (offset {}, size {}) must be a non-empty multiple of 4",
+                    range.offset, range.size
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn build(
+        &self,
+        device: &Device,
+        render_pass: vk::RenderPass,
+        subpass: u32,
+    ) -> Result<GraphicsPipeline> {
+        self.validate()?;
+
+        unsafe {
+            let layout_create_info = *vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&self.set_layouts)
+                .push_constant_ranges(&self.push_constant_ranges);
+            let layout = device.create_pipeline_layout(&layout_create_info, None)?;
+
+            let stage_create_infos: Vec<vk::PipelineShaderStageCreateInfo> = self
+                .stages
+                .iter()
+                .map(|stage| {
+                    *vk::PipelineShaderStageCreateInfo::builder()
+                        .stage(stage.stage)
+                        .module(stage.module)
+                        .name(&stage.entry_point)
+                })
+                .collect();
+
+            let vertex_input_state = *vk::PipelineVertexInputStateCreateInfo::builder()
+                .vertex_binding_descriptions(&self.vertex_bindings)
+                .vertex_attribute_descriptions(&self.vertex_attributes);
+
+            let input_assembly_state =
+                *vk::PipelineInputAssemblyStateCreateInfo::builder().topology(self.topology);
+
+            // ビューポートとシザーは動的ステートで設定する前提で数だけ指定する
+            let viewport_state = *vk::PipelineViewportStateCreateInfo::builder()
+                .viewport_count(1)
+                .scissor_count(1);
+
+            let (depth_bias_constant_factor, depth_bias_slope_factor) =
+                self.depth_bias.unwrap_or((0.0, 0.0));
+            let rasterization_state = *vk::PipelineRasterizationStateCreateInfo::builder()
+                .polygon_mode(self.polygon_mode)
+                .cull_mode(self.cull_mode)
+                .front_face(self.front_face)
+                .depth_bias_enable(self.depth_bias.is_some())
+                .depth_bias_constant_factor(depth_bias_constant_factor)
+                .depth_bias_slope_factor(depth_bias_slope_factor)
+                .line_width(1.0);
+
+            let multisample_state = *vk::PipelineMultisampleStateCreateInfo::builder()
+                .rasterization_samples(self.samples);
+
+            let (front, back) = self.stencil.unwrap_or_default();
+            let depth_stencil_state = *vk::PipelineDepthStencilStateCreateInfo::builder()
+                .depth_test_enable(self.depth_test)
+                .depth_write_enable(self.depth_write)
+                .depth_compare_op(self.depth_compare_op)
+                .stencil_test_enable(self.stencil.is_some())
+                .front(front)
+                .back(back)
+                .max_depth_bounds(1.0);
+
+            let color_blend_state = *vk::PipelineColorBlendStateCreateInfo::builder()
+                .logic_op(vk::LogicOp::CLEAR)
+                .attachments(&self.color_blend_attachments);
+
+            let dynamic_state =
+                *vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&self.dynamic_states);
+
+            let mut rendering_create_info = vk::PipelineRenderingCreateInfo::default();
+            if let Some((color_formats, depth_format)) = self.rendering_formats.as_ref() {
+                let stencil_format = if *depth_format != vk::Format::UNDEFINED
+                    && depth_aspect_mask(*depth_format).contains(vk::ImageAspectFlags::STENCIL)
+                {
+                    *depth_format
+                } else {
+                    vk::Format::UNDEFINED
+                };
+                rendering_create_info = *vk::PipelineRenderingCreateInfo::builder()
+                    .color_attachment_formats(color_formats)
+                    .depth_attachment_format(*depth_format)
+                    .stencil_attachment_format(stencil_format);
+            }
+
+            let mut pipeline_create_info = vk::GraphicsPipelineCreateInfo::builder()
+                .stages(&stage_create_infos)
+                .vertex_input_state(&vertex_input_state)
+                .input_assembly_state(&input_assembly_state)
+                .viewport_state(&viewport_state)
+                .rasterization_state(&rasterization_state)
+                .multisample_state(&multisample_state)
+                .depth_stencil_state(&depth_stencil_state)
+                .color_blend_state(&color_blend_state)
+                .dynamic_state(&dynamic_state)
+                .layout(layout)
+                .render_pass(render_pass)
+                .subpass(subpass);
+            if self.rendering_formats.is_some() {
+                pipeline_create_info = pipeline_create_info.push_next(&mut rendering_create_info);
+            }
+
+            let pipeline = match device.create_graphics_pipelines(
+                vk::PipelineCache::null(),
+                &[*pipeline_create_info],
+                None,
+            ) {
+                Ok(pipelines) => pipelines[0],
+                Err((_, err)) => {
+                    device.destroy_pipeline_layout(layout, None);
+                    return Err(err.into());
+                }
+            };
+
+            Ok(GraphicsPipeline {
+                pipeline,
+                layout,
+                render_pass,
+                subpass,
+                push_constant_ranges: self.push_constant_ranges.clone(),
+            })
+        }
+    }
+}
@@ -0,0 +1,637 @@
+use super::assets::ObjVertex;
+use super::buffer::write_buffer;
+use super::error::{RendererError, Result};
+use super::memory::{Allocation, MemoryAllocator};
+use super::texture::create_image;
+use super::{
+    BufferId, DescriptorBinding, DescriptorWriter, GraphImage, ImageAccess, ImportedImage, LightId,
+    LightKind, Mat4, PassContext, PbrPushConstants, PipelineBuilder, RenderGraph, Renderer,
+    RenderingAttachment, ShaderId,
+};
+use ash::{vk, Device};
+
+/// 同時に影を落とせるポイントライトの数
+pub const MAX_POINT_SHADOWS: usize = 4;
+
+/// ポイントライト1つ分のキューブシャドウマップの設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PointShadowSettings {
+    /// キューブマップの各面の幅と高さ
+    pub resolution: u32,
+    pub near_plane: f32,
+    /// `None`ならライトの範囲を使う。範囲のないライトでは指定すること
+    pub far_plane: Option<f32>,
+    pub depth_bias_constant: f32,
+    pub depth_bias_slope: f32,
+}
+
+impl Default for PointShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 512,
+            near_plane: 0.05,
+            far_plane: None,
+            depth_bias_constant: 1.25,
+            depth_bias_slope: 1.75,
+        }
+    }
+}
+
+/// ポイントシャドウ1つ分のユニフォームの要素。std140と同じ配置
+///
+/// ```glsl
+/// struct PointShadow {
+///     vec4 position_far;  // xyz: ライトの位置、w: 遠い平面
+///     vec4 depth_params;  // x: far / (far - near)、y: -far * near / (far - near)
+/// };
+/// layout(set = 3, binding = 0) uniform PointShadows {
+///     PointShadow point_shadows[4]; // MAX_POINT_SHADOWS
+/// };
+/// layout(set = 3, binding = 1) uniform samplerCubeShadow point_shadow_map0;
+/// // binding 2から4も同様
+///
+/// float point_shadow(int index, vec3 world_position) {
+///     vec3 d = world_position - point_shadows[index].position_far.xyz;
+///     // シャドウパスと同じ透視投影の深度を、主軸の距離から求めて比較する
+///     float z = max(abs(d.x), max(abs(d.y), abs(d.z)));
+///     vec4 p = point_shadows[index].depth_params;
+///     float reference = p.x + p.y / z;
+///     switch (index) {
+///     case 0: return texture(point_shadow_map0, vec4(d, reference));
+///     // ...
+///     }
+/// }
+/// ```
+///
+/// シャドウを持つライトは、ライトのユニフォームの`spot_cos.z`にこの配列の番号が入る。
+/// 影のないライトでは-1。
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct GpuPointShadow {
+    pub position_far: [f32; 4],
+    pub depth_params: [f32; 4],
+}
+
+/// ポイントライトのキューブシャドウマップ
+pub struct PointShadowMap {
+    pub light: LightId,
+    pub image: vk::Image,
+    /// サンプリング用のキューブのビュー
+    pub cube_view: vk::ImageView,
+    /// +X、-X、+Y、-Y、+Z、-Zの順の、面ごとに描画するビュー
+    pub face_views: [vk::ImageView; 6],
+    pub allocation: Allocation,
+    pub format: vk::Format,
+    pub settings: PointShadowSettings,
+    pub far_plane: f32,
+}
+
+impl PointShadowMap {
+    pub fn extent(&self) -> vk::Extent2D {
+        vk::Extent2D {
+            width: self.settings.resolution,
+            height: self.settings.resolution,
+        }
+    }
+
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのシャドウマップを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        destroy_cube_image(
+            device,
+            allocator,
+            self.image,
+            self.allocation,
+            self.cube_view,
+            &self.face_views,
+        );
+    }
+}
+
+/// シャドウのないスロットに割り当てる1x1のキューブと、フレームコンテキストごとのデスクリプタセット
+pub(crate) struct PointShadowResources {
+    image: vk::Image,
+    allocation: Allocation,
+    cube_view: vk::ImageView,
+    face_views: [vk::ImageView; 6],
+    sampler: vk::Sampler,
+    uniform_buffers: Vec<BufferId>,
+    descriptor_sets: Vec<vk::DescriptorSet>,
+}
+
+impl PointShadowResources {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUが使い終わっていること。
+    /// ユニフォームバッファはレンダラーのバッファとして破棄される
+    pub(crate) unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        device.destroy_sampler(self.sampler, None);
+        destroy_cube_image(
+            device,
+            allocator,
+            self.image,
+            self.allocation,
+            self.cube_view,
+            &self.face_views,
+        );
+    }
+}
+
+unsafe fn destroy_cube_image(
+    device: &Device,
+    allocator: &mut MemoryAllocator,
+    image: vk::Image,
+    allocation: Allocation,
+    cube_view: vk::ImageView,
+    face_views: &[vk::ImageView; 6],
+) {
+    for &view in face_views.iter() {
+        device.destroy_image_view(view, None);
+    }
+    device.destroy_image_view(cube_view, None);
+    device.destroy_image(image, None);
+    allocator.free(device, allocation);
+}
+
+/// `position`のポイントライトから、キューブマップの各面を描くビュー射影行列
+///
+/// 面の順番と向きはVulkanのキューブマップの規約に合わせてある。通常のカメラとは
+/// 左右が反転するので、パイプラインでは表面を時計回りにする。
+pub fn point_shadow_face_view_projections(
+    position: [f32; 3],
+    near_plane: f32,
+    far_plane: f32,
+) -> [Mat4; 6] {
+    // 面ごとの(s軸、t軸、主軸)。s、tがそのままNDCのx、yになる
+    const FACES: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+        ([0.0, 0.0, -1.0], [0.0, -1.0, 0.0], [1.0, 0.0, 0.0]),
+        ([0.0, 0.0, 1.0], [0.0, -1.0, 0.0], [-1.0, 0.0, 0.0]),
+        ([1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+        ([1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, -1.0, 0.0]),
+        ([1.0, 0.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, 1.0]),
+        ([-1.0, 0.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, -1.0]),
+    ];
+    let (a, b) = depth_params(near_plane, far_plane);
+    let dot = |v: [f32; 3]| v[0] * position[0] + v[1] * position[1] + v[2] * position[2];
+    FACES.map(|(s, t, major)| {
+        let mut m = [[0.0; 4]; 4];
+        for axis in 0..3 {
+            m[axis] = [s[axis], t[axis], a * major[axis], major[axis]];
+        }
+        m[3] = [-dot(s), -dot(t), -a * dot(major) + b, -dot(major)];
+        m
+    })
+}
+
+/// 主軸の距離`z`の深度を`x + y / z`で求める係数
+fn depth_params(near_plane: f32, far_plane: f32) -> (f32, f32) {
+    let range = (far_plane - near_plane).max(f32::EPSILON);
+    (far_plane / range, -far_plane * near_plane / range)
+}
+
+impl Renderer {
+    /// ポイントシャドウのデスクリプタセットレイアウト
+    ///
+    /// `pbr_pipeline_builder`のパイプラインで使う場合は、ディレクショナルライトのシャドウの
+    /// 後ろのセット3に追加する。
+    pub fn point_shadow_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        let stages = vk::ShaderStageFlags::FRAGMENT;
+        let mut bindings = vec![DescriptorBinding::uniform_buffer(0, stages)];
+        bindings.extend(
+            (1..=MAX_POINT_SHADOWS as u32)
+                .map(|binding| DescriptorBinding::sampled_image(binding, stages)),
+        );
+        self.descriptor_set_layout(&bindings)
+    }
+
+    /// ライトにキューブシャドウマップを割り当てる。すでにある場合は設定を変更し、
+    /// 解像度が変わったら作り直す
+    pub fn enable_point_shadow(
+        &mut self,
+        light: LightId,
+        settings: PointShadowSettings,
+    ) -> Result<()> {
+        let instance = self
+            .lights
+            .get(light)
+            .ok_or_else(|| RendererError::Validation(format!("light {:?} was removed", light)))?;
+        let range = match instance.light.kind {
+            LightKind::Point { range } => range,
+            _ => {
+                return Err(RendererError::Validation(format!(
+                    "light {:?} is not a point light",
+                    light
+                )))
+            }
+        };
+        let far_plane = settings.far_plane.or(range).ok_or_else(|| {
+            RendererError::Validation(format!(
+                "light {:?} has no range, so the shadow needs a far plane",
+                light
+            ))
+        })?;
+        if settings.resolution == 0
+            || settings.near_plane <= 0.0
+            || far_plane <= settings.near_plane
+        {
+            return Err(RendererError::Validation(format!(
+                "point shadow needs a non-zero resolution and 0 < near ({}) < far ({})",
+                settings.near_plane, far_plane
+            )));
+        }
+
+        if let Some(index) = self.point_shadow_index(light) {
+            let shadow = &mut self.point_shadows[index];
+            if shadow.settings.resolution == settings.resolution {
+                shadow.settings = settings;
+                shadow.far_plane = far_plane;
+                return Ok(());
+            }
+            let old = self.point_shadows.remove(index);
+            self.destroy_point_shadow(old);
+        } else if self.point_shadows.len() >= MAX_POINT_SHADOWS {
+            return Err(RendererError::Validation(format!(
+                "cannot enable more than {} point shadows",
+                MAX_POINT_SHADOWS
+            )));
+        }
+        if self.point_shadow_resources.is_none() {
+            self.point_shadow_resources = Some(self.create_point_shadow_resources()?);
+        }
+
+        let format = self.shadow_map_format();
+        let (image, allocation, cube_view, face_views) =
+            unsafe { self.create_cube_depth_image(settings.resolution, format)? };
+        self.point_shadows.push(PointShadowMap {
+            light,
+            image,
+            cube_view,
+            face_views,
+            allocation,
+            format,
+            settings,
+            far_plane,
+        });
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからライトのキューブシャドウマップを破棄する
+    pub fn disable_point_shadow(&mut self, light: LightId) {
+        if let Some(index) = self.point_shadow_index(light) {
+            let shadow = self.point_shadows.remove(index);
+            self.destroy_point_shadow(shadow);
+        }
+    }
+
+    pub fn point_shadow(&self, light: LightId) -> Option<&PointShadowMap> {
+        self.point_shadows
+            .iter()
+            .find(|shadow| shadow.light == light)
+    }
+
+    /// ライトのユニフォームの`spot_cos.z`に書き込む番号
+    pub(crate) fn point_shadow_index(&self, light: LightId) -> Option<usize> {
+        self.point_shadows
+            .iter()
+            .position(|shadow| shadow.light == light)
+    }
+
+    /// ポイントシャドウのパス用に設定したパイプラインビルダーを返す
+    ///
+    /// `shadow_pipeline_builder`と同じく深度だけを書き込む。プッシュ定数の`view_projection`に
+    /// `add_point_shadow_pass`が渡す面の行列を使う。
+    pub fn point_shadow_pipeline_builder(
+        &self,
+        vertex_shader: ShaderId,
+        light: LightId,
+    ) -> Result<PipelineBuilder> {
+        let shadow = self.enabled_point_shadow(light)?;
+        let vertex_module = self
+            .shader_modules
+            .get(vertex_shader)
+            .ok_or_else(|| {
+                RendererError::Validation(format!(
+                    "shader {:?} was already destroyed",
+                    vertex_shader
+                ))
+            })?
+            .module;
+        Ok(PipelineBuilder::new()
+            .vertex_shader(vertex_module)
+            .vertex_layout(0, &ObjVertex::layout())
+            .color_attachments(&[])
+            .front_face(vk::FrontFace::CLOCKWISE)
+            .depth_bias(
+                shadow.settings.depth_bias_constant,
+                shadow.settings.depth_bias_slope,
+            )
+            .push_constant_range(
+                vk::ShaderStageFlags::VERTEX,
+                0,
+                std::mem::size_of::<PbrPushConstants>() as u32,
+            )
+            .rendering_formats(&[], shadow.format))
+    }
+
+    /// ライトのキューブシャドウマップの6面をクリアして`draw`で描くパスをグラフに追加する
+    ///
+    /// `draw`には面の番号とその面のビュー射影行列が渡される。返すイメージを、
+    /// ライティングのパスで`ImageAccess::FRAGMENT_SHADER_READ`として宣言する。
+    /// 影を使うフレームでは毎回描くこと。動的レンダリングが有効であること。
+    /// シーンは`Scene::meshes_for(ScenePass::Shadow)`で描く。
+    pub fn add_point_shadow_pass<'a, F>(
+        &self,
+        graph: &mut RenderGraph<'a>,
+        light: LightId,
+        mut draw: F,
+    ) -> Result<GraphImage>
+    where
+        F: FnMut(&PassContext, vk::CommandBuffer, usize, Mat4) + 'a,
+    {
+        let shadow = self.enabled_point_shadow(light)?;
+        if self.dynamic_rendering.is_none() {
+            return Err(RendererError::Validation(
+                "shadow passes require dynamic rendering".to_owned(),
+            ));
+        }
+        let position = self.lights.get(light).unwrap().position;
+        let view_projections = point_shadow_face_view_projections(
+            position,
+            shadow.settings.near_plane,
+            shadow.far_plane,
+        );
+        let image = graph.import_image(
+            "point_shadow_map",
+            ImportedImage {
+                image: shadow.image,
+                view: shadow.cube_view,
+                aspect_mask: vk::ImageAspectFlags::DEPTH,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: None,
+            },
+        );
+        let face_views = shadow.face_views;
+        let render_area = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: shadow.extent(),
+        };
+        graph
+            .add_pass("point_shadow")
+            .image(image, ImageAccess::DEPTH_ATTACHMENT_WRITE)
+            .execute(move |ctx, command_buffer| {
+                let renderer = ctx.renderer;
+                let dynamic_rendering = renderer.dynamic_rendering.as_ref().unwrap();
+                for (face, &view) in face_views.iter().enumerate() {
+                    let depth_info = RenderingAttachment::clear(
+                        view,
+                        vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                        vk::ClearValue {
+                            depth_stencil: vk::ClearDepthStencilValue {
+                                depth: 1.0,
+                                stencil: 0,
+                            },
+                        },
+                    )
+                    .info();
+                    let rendering_info = vk::RenderingInfo::builder()
+                        .render_area(render_area)
+                        .layer_count(1)
+                        .depth_attachment(&depth_info);
+                    unsafe {
+                        dynamic_rendering.cmd_begin_rendering(
+                            &renderer.device,
+                            command_buffer,
+                            &rendering_info,
+                        );
+                        renderer.set_viewport_and_scissor(command_buffer, render_area);
+                    }
+                    draw(ctx, command_buffer, face, view_projections[face]);
+                    unsafe {
+                        dynamic_rendering.cmd_end_rendering(&renderer.device, command_buffer)
+                    };
+                }
+            });
+        Ok(image)
+    }
+
+    /// ポイントシャドウのライトの位置と遠い平面を記録中のフレームのユニフォームバッファに書き込み、
+    /// デスクリプタセットを返す
+    pub fn update_point_shadows(&mut self) -> Result<vk::DescriptorSet> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "shadows can only be updated between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        if self.point_shadow_resources.is_none() {
+            self.point_shadow_resources = Some(self.create_point_shadow_resources()?);
+        }
+        let resources = self.point_shadow_resources.as_ref().unwrap();
+        let mut uniform = [GpuPointShadow::default(); MAX_POINT_SHADOWS];
+        let mut writer = DescriptorWriter::new().buffer(
+            0,
+            vk::DescriptorType::UNIFORM_BUFFER,
+            self.buffers
+                .get(resources.uniform_buffers[self.current_frame])
+                .unwrap(),
+        );
+        for (slot, gpu_shadow) in uniform.iter_mut().enumerate() {
+            let cube_view = match self.point_shadows.get(slot) {
+                Some(shadow) => {
+                    let [x, y, z] = self.lights.get(shadow.light).unwrap().position;
+                    let (a, b) = depth_params(shadow.settings.near_plane, shadow.far_plane);
+                    *gpu_shadow = GpuPointShadow {
+                        position_far: [x, y, z, shadow.far_plane],
+                        depth_params: [a, b, 0.0, 0.0],
+                    };
+                    shadow.cube_view
+                }
+                None => resources.cube_view,
+            };
+            writer = writer.image(
+                1 + slot as u32,
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                vk::DescriptorImageInfo {
+                    sampler: resources.sampler,
+                    image_view: cube_view,
+                    image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                },
+            );
+        }
+        let buffer = self
+            .buffers
+            .get(resources.uniform_buffers[self.current_frame])
+            .unwrap();
+        let descriptor_set = resources.descriptor_sets[self.current_frame];
+        unsafe {
+            write_buffer(buffer, &uniform)?;
+            writer.update(&self.device, descriptor_set);
+        }
+        Ok(descriptor_set)
+    }
+
+    fn enabled_point_shadow(&self, light: LightId) -> Result<&PointShadowMap> {
+        self.point_shadow(light).ok_or_else(|| {
+            RendererError::Validation(format!("light {:?} has no point shadow", light))
+        })
+    }
+
+    pub(crate) fn destroy_point_shadow(&mut self, shadow: PointShadowMap) {
+        self.destroy_deferred(move |device, allocator| unsafe {
+            shadow.destroy(device, allocator)
+        });
+    }
+
+    /// 6レイヤーのキューブ互換の深度イメージと、キューブのビュー、面ごとのビューを作る
+    unsafe fn create_cube_depth_image(
+        &mut self,
+        resolution: u32,
+        format: vk::Format,
+    ) -> Result<(vk::Image, Allocation, vk::ImageView, [vk::ImageView; 6])> {
+        let image_create_info = *vk::ImageCreateInfo::builder()
+            .flags(vk::ImageCreateFlags::CUBE_COMPATIBLE)
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: resolution,
+                height: resolution,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(6)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let (image, allocation) = create_image(
+            &self.device,
+            &mut self.allocator,
+            &image_create_info,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            true,
+        )?;
+        let mut views = Vec::with_capacity(7);
+        let view_requests = std::iter::once((vk::ImageViewType::CUBE, 0, 6))
+            .chain((0..6).map(|layer| (vk::ImageViewType::TYPE_2D, layer, 1)));
+        for (view_type, base_array_layer, layer_count) in view_requests {
+            let view_create_info = *vk::ImageViewCreateInfo::builder()
+                .image(image)
+                .view_type(view_type)
+                .format(format)
+                .subresource_range(
+                    *vk::ImageSubresourceRange::builder()
+                        .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                        .level_count(1)
+                        .base_array_layer(base_array_layer)
+                        .layer_count(layer_count),
+                );
+            match self.device.create_image_view(&view_create_info, None) {
+                Ok(view) => views.push(view),
+                Err(err) => {
+                    for view in views {
+                        self.device.destroy_image_view(view, None);
+                    }
+                    self.device.destroy_image(image, None);
+                    self.allocator.free(&self.device, allocation);
+                    return Err(err.into());
+                }
+            }
+        }
+        let face_views = [views[1], views[2], views[3], views[4], views[5], views[6]];
+        Ok((image, allocation, views[0], face_views))
+    }
+
+    fn create_point_shadow_resources(&mut self) -> Result<PointShadowResources> {
+        let layout = self.point_shadow_set_layout()?;
+        let format = self.shadow_map_format();
+        unsafe {
+            let (image, allocation, cube_view, face_views) =
+                self.create_cube_depth_image(1, format)?;
+            // 空きスロットのキューブはサンプリングされないので、レイアウトだけを合わせる
+            let barrier = *vk::ImageMemoryBarrier::builder()
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::DEPTH,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 6,
+                });
+            let transitioned = self.submit_setup_commands(|device, command_buffer| {
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[barrier],
+                );
+            });
+            let sampler_info = *vk::SamplerCreateInfo::builder()
+                .mag_filter(vk::Filter::LINEAR)
+                .min_filter(vk::Filter::LINEAR)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .compare_enable(true)
+                .compare_op(vk::CompareOp::LESS_OR_EQUAL);
+            let sampler = match transitioned
+                .and_then(|_| Ok(self.device.create_sampler(&sampler_info, None)?))
+            {
+                Ok(sampler) => sampler,
+                Err(err) => {
+                    destroy_cube_image(
+                        &self.device,
+                        &mut self.allocator,
+                        image,
+                        allocation,
+                        cube_view,
+                        &face_views,
+                    );
+                    return Err(err);
+                }
+            };
+            let mut resources = PointShadowResources {
+                image,
+                allocation,
+                cube_view,
+                face_views,
+                sampler,
+                uniform_buffers: Vec::with_capacity(self.frames.len()),
+                descriptor_sets: Vec::with_capacity(self.frames.len()),
+            };
+            for _ in 0..self.frames.len() {
+                let result = self
+                    .create_buffer(
+                        std::mem::size_of::<[GpuPointShadow; MAX_POINT_SHADOWS]>()
+                            as vk::DeviceSize,
+                        vk::BufferUsageFlags::UNIFORM_BUFFER,
+                        vk::MemoryPropertyFlags::HOST_VISIBLE
+                            | vk::MemoryPropertyFlags::HOST_COHERENT,
+                    )
+                    .and_then(|id| {
+                        resources.uniform_buffers.push(id);
+                        self.allocate_descriptor_set(layout)
+                    });
+                match result {
+                    Ok(set) => resources.descriptor_sets.push(set),
+                    Err(err) => {
+                        for &id in resources.uniform_buffers.iter() {
+                            self.destroy_buffer(id)?;
+                        }
+                        self.destroy_deferred(move |device, allocator| {
+                            resources.destroy(device, allocator)
+                        });
+                        return Err(err);
+                    }
+                }
+            }
+            Ok(resources)
+        }
+    }
+}
@@ -0,0 +1,623 @@
+use super::anti_aliasing::{AntiAliasingMode, AntiAliasingSettings};
+use super::camera_effects::{DepthOfFieldSettings, MotionBlurSettings, MAX_MOTION_BLUR_SAMPLES};
+use super::descriptor::{allocate_set, create_pool};
+use super::error::{RendererError, Result};
+use super::memory::{Allocation, MemoryAllocator};
+use super::outline::OutlineSettings;
+use super::screen_space_reflections::ScreenSpaceReflectionSettings;
+use super::texture::{create_image, image_barrier};
+use super::ToneMappingSettings;
+use super::{
+    ComputePipeline, DescriptorBinding, DescriptorWriter, Renderer, SamplerDesc, ShaderId,
+    HDR_FORMAT,
+};
+use ash::{vk, Device};
+
+/// ブルームの縮小バッファのミップ数の上限
+pub const BLOOM_MAX_MIP_LEVELS: u32 = 6;
+/// ブルームのシェーダーのワークグループの幅と高さ
+pub const BLOOM_GROUP_SIZE: u32 = 8;
+
+/// ブルームの設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BloomSettings {
+    pub enabled: bool,
+    /// HDRの色とぼかした色を混ぜる割合。0から1
+    pub intensity: f32,
+    /// 拡大するときのテントフィルタの半径。UV座標の単位
+    pub radius: f32,
+}
+
+impl Default for BloomSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            intensity: 0.04,
+            radius: 0.005,
+        }
+    }
+}
+
+/// `apply_post_processing`でHDRのレンダーターゲットにかける効果の設定
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PostProcessSettings {
+    pub reflections: ScreenSpaceReflectionSettings,
+    pub outline: OutlineSettings,
+    pub anti_aliasing: AntiAliasingSettings,
+    pub depth_of_field: DepthOfFieldSettings,
+    pub motion_blur: MotionBlurSettings,
+    pub bloom: BloomSettings,
+}
+
+/// ブルームのコンピュートシェーダー
+///
+/// しきい値を使わず、HDRの画像を縮小しながらぼかしたミップチェインを拡大しながら足し合わせ、
+/// 最後に元の画像と混ぜる(Call of Duty: Advanced Warfareの方法)。どちらも8x8の
+/// ワークグループで出力の1画素を1スレッドが処理し、`bloom_set_layout`のデスクリプタセットを使う。
+///
+/// ```glsl
+/// layout(local_size_x = 8, local_size_y = 8) in;
+/// layout(set = 0, binding = 0) uniform sampler2D src;
+/// layout(set = 0, binding = 1, rgba16f) uniform image2D dst;
+///
+/// // downsample: 13タップで縮小する。最初の段ではKarisの平均で明るすぎる画素のちらつきを抑える
+/// layout(push_constant) uniform Params { uint karis_average; };
+/// vec3 fetch(vec2 uv, vec2 t, float x, float y) { return texture(src, uv + t * vec2(x, y)).rgb; }
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy), size = imageSize(dst);
+///     if (any(greaterThanEqual(p, size))) return;
+///     vec2 uv = (vec2(p) + 0.5) / vec2(size), t = 1.0 / vec2(textureSize(src, 0));
+///     vec3 a = fetch(uv, t, -2, 2), b = fetch(uv, t, 0, 2), c = fetch(uv, t, 2, 2);
+///     vec3 d = fetch(uv, t, -2, 0), e = fetch(uv, t, 0, 0), f = fetch(uv, t, 2, 0);
+///     vec3 g = fetch(uv, t, -2, -2), h = fetch(uv, t, 0, -2), i = fetch(uv, t, 2, -2);
+///     vec3 j = fetch(uv, t, -1, 1), k = fetch(uv, t, 1, 1), l = fetch(uv, t, -1, -1), m = fetch(uv, t, 1, -1);
+///     vec3 groups[5] = vec3[](
+///         (a + b + d + e) * 0.25, (b + c + e + f) * 0.25, (d + e + g + h) * 0.25,
+///         (e + f + h + i) * 0.25, (j + k + l + m) * 0.25);
+///     float weights[5] = float[](0.125, 0.125, 0.125, 0.125, 0.5);
+///     vec3 sum = vec3(0.0);
+///     float total = 0.0;
+///     for (int n = 0; n < 5; ++n) {
+///         float w = weights[n];
+///         if (karis_average != 0) w /= 1.0 + dot(groups[n], vec3(0.2126, 0.7152, 0.0722));
+///         sum += groups[n] * w;
+///         total += w;
+///     }
+///     imageStore(dst, p, vec4(sum / total, 1.0));
+/// }
+///
+/// // upsample: 3x3のテントフィルタで拡大して足す。compositeでは元の画像とintensityで混ぜる
+/// layout(push_constant) uniform Params { float radius; float intensity; uint composite; };
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy), size = imageSize(dst);
+///     if (any(greaterThanEqual(p, size))) return;
+///     vec2 uv = (vec2(p) + 0.5) / vec2(size);
+///     vec2 o = vec2(radius, radius * float(size.x) / float(size.y));
+///     vec3 c = texture(src, uv).rgb * 4.0
+///         + (texture(src, uv + vec2(o.x, 0.0)).rgb + texture(src, uv - vec2(o.x, 0.0)).rgb
+///             + texture(src, uv + vec2(0.0, o.y)).rgb + texture(src, uv - vec2(0.0, o.y)).rgb) * 2.0
+///         + texture(src, uv + o).rgb + texture(src, uv - o).rgb
+///         + texture(src, uv + vec2(o.x, -o.y)).rgb + texture(src, uv + vec2(-o.x, o.y)).rgb;
+///     c /= 16.0;
+///     vec3 current = imageLoad(dst, p).rgb;
+///     imageStore(dst, p, vec4(composite != 0 ? mix(current, c, intensity) : current + c, 1.0));
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BloomShaders {
+    pub downsample: ShaderId,
+    pub upsample: ShaderId,
+}
+
+/// HDRのレンダーターゲットの半分の解像度から始まるブルームのミップチェイン
+///
+/// 常に`GENERAL`レイアウトで使う。HDRのターゲットを作り直すたびに作り直す。
+pub struct BloomChain {
+    pub image: vk::Image,
+    pub allocation: Allocation,
+    /// ミップごとのビュー
+    pub views: Vec<vk::ImageView>,
+    pub extents: Vec<vk::Extent2D>,
+    pub descriptor_pool: vk::DescriptorPool,
+    /// `i`番目はミップ`i - 1`(最初はHDRのターゲット)からミップ`i`に縮小する
+    pub downsample_sets: Vec<vk::DescriptorSet>,
+    /// `i`番目はミップ`i + 1`を拡大してミップ`i`に足す
+    pub upsample_sets: Vec<vk::DescriptorSet>,
+    /// ミップ0を拡大してHDRのターゲットに混ぜる
+    pub composite_set: vk::DescriptorSet,
+}
+
+impl BloomChain {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのチェインを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        device.destroy_descriptor_pool(self.descriptor_pool, None);
+        for &view in self.views.iter() {
+            device.destroy_image_view(view, None);
+        }
+        device.destroy_image(self.image, None);
+        allocator.free(device, self.allocation);
+    }
+}
+
+/// `enable_bloom`で作るブルームのリソース
+pub struct Bloom {
+    pub downsample_pipeline: ComputePipeline,
+    pub upsample_pipeline: ComputePipeline,
+    pub sampler: vk::Sampler,
+    pub chain: BloomChain,
+}
+
+impl Bloom {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        self.downsample_pipeline.destroy(device);
+        self.upsample_pipeline.destroy(device);
+        device.destroy_sampler(self.sampler, None);
+        self.chain.destroy(device, allocator);
+    }
+}
+
+impl Renderer {
+    /// ブルームのデスクリプタセットレイアウト。バインディング0が入力、1が出力のストレージイメージ
+    pub fn bloom_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, vk::ShaderStageFlags::COMPUTE),
+            DescriptorBinding::new(
+                1,
+                vk::DescriptorType::STORAGE_IMAGE,
+                vk::ShaderStageFlags::COMPUTE,
+            ),
+        ])
+    }
+
+    /// HDRのレンダーターゲットにかけるブルームを有効にする。すでに有効な場合は作り直す
+    ///
+    /// `enable_hdr`の後で呼ぶ。効果の強さは`set_post_process`で設定する。
+    pub fn enable_bloom(&mut self, shaders: BloomShaders) -> Result<()> {
+        self.enabled_hdr()?;
+        let layout = self.bloom_set_layout()?;
+        let downsample_pipeline =
+            self.create_compute_pipeline(shaders.downsample, "main", &[layout], 4)?;
+        let upsample_pipeline =
+            match self.create_compute_pipeline(shaders.upsample, "main", &[layout], 12) {
+                Ok(pipeline) => pipeline,
+                Err(err) => {
+                    self.destroy_compute_pipeline(downsample_pipeline);
+                    return Err(err);
+                }
+            };
+        let resources = self
+            .create_sampler(&SamplerDesc::clamp_to_edge())
+            .and_then(|sampler| {
+                let chain = unsafe { self.create_bloom_chain(sampler) };
+                match chain {
+                    Ok(chain) => Ok((sampler, chain)),
+                    Err(err) => {
+                        unsafe { self.device.destroy_sampler(sampler, None) };
+                        Err(err)
+                    }
+                }
+            });
+        let (sampler, chain) = match resources {
+            Ok(resources) => resources,
+            Err(err) => {
+                self.destroy_compute_pipeline(downsample_pipeline);
+                self.destroy_compute_pipeline(upsample_pipeline);
+                return Err(err);
+            }
+        };
+        let bloom = Bloom {
+            downsample_pipeline,
+            upsample_pipeline,
+            sampler,
+            chain,
+        };
+        if let Some(old) = self.bloom.replace(bloom) {
+            self.destroy_deferred(move |device, allocator| unsafe {
+                old.destroy(device, allocator)
+            });
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからブルームのリソースを破棄する
+    pub fn disable_bloom(&mut self) {
+        if let Some(bloom) = self.bloom.take() {
+            self.destroy_deferred(move |device, allocator| unsafe {
+                bloom.destroy(device, allocator)
+            });
+        }
+    }
+
+    pub fn post_process(&self) -> &PostProcessSettings {
+        &self.post_process
+    }
+
+    /// ポストプロセスの設定を変更する。次の`apply_post_processing`から反映される
+    pub fn set_post_process(&mut self, settings: PostProcessSettings) -> Result<()> {
+        self.validate_post_process(&settings)?;
+        // ビューの上書きと重ねても正しい設定でなければならない
+        let resolved = self
+            .view_settings
+            .resolve(&ToneMappingSettings::default(), &settings);
+        self.validate_post_process(&resolved.post_process)?;
+        self.post_process = settings;
+        Ok(())
+    }
+
+    pub(crate) fn validate_post_process(&self, settings: &PostProcessSettings) -> Result<()> {
+        let bloom = &settings.bloom;
+        if !(0.0..=1.0).contains(&bloom.intensity) || bloom.radius <= 0.0 {
+            return Err(RendererError::Validation(format!(
+                "bloom intensity {} must be within 0 to 1 and radius {} must be positive",
+                bloom.intensity, bloom.radius
+            )));
+        }
+        let reflections = &settings.reflections;
+        if reflections.intensity < 0.0
+            || reflections.max_distance <= 0.0
+            || reflections.thickness <= 0.0
+            || reflections.max_steps == 0
+            || !(0.0..=1.0).contains(&reflections.max_roughness)
+            || !(0.0..=1.0).contains(&reflections.roughness)
+        {
+            return Err(RendererError::Validation(format!(
+                "invalid screen-space reflection settings: {:?}",
+                reflections
+            )));
+        }
+        let outline = &settings.outline;
+        if outline.thickness < 1.0
+            || outline.depth_threshold <= 0.0
+            || outline.normal_threshold <= 0.0
+            || !(0.0..=1.0).contains(&outline.color[3])
+        {
+            return Err(RendererError::Validation(format!(
+                "invalid outline settings: {:?}",
+                outline
+            )));
+        }
+        let anti_aliasing = &settings.anti_aliasing;
+        if !(0.0..1.0).contains(&anti_aliasing.history_weight) {
+            return Err(RendererError::Validation(format!(
+                "TAA history weight {} must be within 0 to 1",
+                anti_aliasing.history_weight
+            )));
+        }
+        let depth_of_field = &settings.depth_of_field;
+        if depth_of_field.f_number <= 0.0
+            || depth_of_field.focal_length <= 0.0
+            || depth_of_field.focus_distance <= depth_of_field.focal_length
+            || depth_of_field.sensor_height <= 0.0
+            || depth_of_field.max_coc_radius < 0.0
+        {
+            return Err(RendererError::Validation(format!(
+                "invalid depth of field settings: {:?}",
+                depth_of_field
+            )));
+        }
+        let motion_blur = &settings.motion_blur;
+        if !(0.0..=1.0).contains(&motion_blur.shutter)
+            || !(1..=MAX_MOTION_BLUR_SAMPLES).contains(&motion_blur.sample_count)
+            || motion_blur.max_blur_radius < 0.0
+        {
+            return Err(RendererError::Validation(format!(
+                "invalid motion blur settings: {:?}",
+                motion_blur
+            )));
+        }
+        // マルチサンプルの深度はモーションベクトルのシェーダーで読めない
+        if anti_aliasing.mode == AntiAliasingMode::Taa
+            && self.msaa_samples != vk::SampleCountFlags::TYPE_1
+        {
+            return Err(RendererError::Validation(
+                "TAA cannot be combined with MSAA".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// 有効なポストプロセスをHDRのレンダーターゲットにかける
+    ///
+    /// `end_hdr_rendering`の後、`update_auto_exposure`と`tone_map`の前にレンダーパスの外で呼ぶ。
+    /// `set_view_settings`で上書きした項目はその設定でかける。
+    /// SSR、輪郭線、アンチエイリアス、被写界深度、モーションブラー、ブルームの順にかける。
+    pub fn apply_post_processing(&mut self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        self.apply_screen_space_reflections(command_buffer)?;
+        self.apply_outline(command_buffer)?;
+        self.apply_anti_aliasing(command_buffer)?;
+        self.apply_camera_effects(command_buffer)?;
+        let hdr = self.enabled_hdr()?;
+        if let Some(bloom) = &self.bloom {
+            if self.view_post_process().bloom.enabled && self.is_pass_enabled("bloom") {
+                self.record_bloom(command_buffer, bloom, hdr.target.image, hdr.target.extent)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// HDRのターゲットに合わせてブルームのミップチェインを作り直す
+    ///
+    /// 古いチェインは使用中のフレームが完了してから破棄する。
+    pub(crate) fn recreate_bloom_chain(&mut self) -> Result<()> {
+        let Some(sampler) = self.bloom.as_ref().map(|bloom| bloom.sampler) else {
+            return Ok(());
+        };
+        let chain = unsafe { self.create_bloom_chain(sampler)? };
+        let old = std::mem::replace(&mut self.bloom.as_mut().unwrap().chain, chain);
+        self.destroy_deferred(move |device, allocator| unsafe { old.destroy(device, allocator) });
+        Ok(())
+    }
+
+    fn record_bloom(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        bloom: &Bloom,
+        hdr_image: vk::Image,
+        hdr_extent: vk::Extent2D,
+    ) -> Result<()> {
+        let chain = &bloom.chain;
+        let levels = chain.views.len() as u32;
+        let compute_barrier = || unsafe {
+            let barrier = *vk::MemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            );
+        };
+        let groups = |extent: vk::Extent2D| {
+            [
+                extent.width.div_ceil(BLOOM_GROUP_SIZE),
+                extent.height.div_ceil(BLOOM_GROUP_SIZE),
+                1,
+            ]
+        };
+
+        // HDRの描画を待ち、前のフレームの内容は捨てる
+        unsafe {
+            let memory_barrier = *vk::MemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ);
+            let chain_barrier = *vk::ImageMemoryBarrier::builder()
+                .image(chain.image)
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::GENERAL)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: levels,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                });
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[memory_barrier],
+                &[],
+                &[chain_barrier],
+            );
+        }
+
+        for (level, &set) in chain.downsample_sets.iter().enumerate() {
+            let karis_average = (level == 0) as u32;
+            self.dispatch(
+                command_buffer,
+                &bloom.downsample_pipeline,
+                &[set],
+                &karis_average.to_ne_bytes(),
+                groups(chain.extents[level]),
+            )?;
+            compute_barrier();
+        }
+
+        let settings = &self.view_post_process().bloom;
+        let upsample = |composite: bool| -> Vec<u8> {
+            settings
+                .radius
+                .to_ne_bytes()
+                .into_iter()
+                .chain(settings.intensity.to_ne_bytes())
+                .chain((composite as u32).to_ne_bytes())
+                .collect()
+        };
+        for (level, &set) in chain.upsample_sets.iter().enumerate().rev() {
+            self.dispatch(
+                command_buffer,
+                &bloom.upsample_pipeline,
+                &[set],
+                &upsample(false),
+                groups(chain.extents[level]),
+            )?;
+            compute_barrier();
+        }
+
+        unsafe {
+            // 最初の縮小での読み込みを待ってから書き込めるようにする
+            image_barrier(
+                &self.device,
+                command_buffer,
+                hdr_image,
+                vk::ImageAspectFlags::COLOR,
+                0,
+                1,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::ImageLayout::GENERAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+            );
+        }
+        self.dispatch(
+            command_buffer,
+            &bloom.upsample_pipeline,
+            &[chain.composite_set],
+            &upsample(true),
+            groups(hdr_extent),
+        )?;
+        unsafe {
+            image_barrier(
+                &self.device,
+                command_buffer,
+                hdr_image,
+                vk::ImageAspectFlags::COLOR,
+                0,
+                1,
+                vk::ImageLayout::GENERAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+            );
+        }
+        Ok(())
+    }
+
+    /// # Safety
+    /// HDRが有効であること
+    unsafe fn create_bloom_chain(&mut self, sampler: vk::Sampler) -> Result<BloomChain> {
+        let layout = self.bloom_set_layout()?;
+        let hdr = self.enabled_hdr()?;
+        let hdr_view = hdr.target.view;
+        let base = vk::Extent2D {
+            width: (hdr.target.extent.width / 2).max(1),
+            height: (hdr.target.extent.height / 2).max(1),
+        };
+        let levels = (u32::BITS - base.width.min(base.height).leading_zeros())
+            .clamp(1, BLOOM_MAX_MIP_LEVELS);
+        let image_create_info = *vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(HDR_FORMAT)
+            .extent(base.into())
+            .mip_levels(levels)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let (image, allocation) = create_image(
+            &self.device,
+            &mut self.allocator,
+            &image_create_info,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            true,
+        )?;
+        let mut chain = BloomChain {
+            image,
+            allocation,
+            views: Vec::with_capacity(levels as usize),
+            extents: (0..levels)
+                .map(|level| vk::Extent2D {
+                    width: (base.width >> level).max(1),
+                    height: (base.height >> level).max(1),
+                })
+                .collect(),
+            descriptor_pool: vk::DescriptorPool::null(),
+            downsample_sets: Vec::new(),
+            upsample_sets: Vec::new(),
+            composite_set: vk::DescriptorSet::null(),
+        };
+        match self.init_bloom_chain(&mut chain, layout, sampler, hdr_view) {
+            Ok(()) => Ok(chain),
+            Err(err) => {
+                chain.destroy(&self.device, &mut self.allocator);
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn init_bloom_chain(
+        &self,
+        chain: &mut BloomChain,
+        layout: vk::DescriptorSetLayout,
+        sampler: vk::Sampler,
+        hdr_view: vk::ImageView,
+    ) -> Result<()> {
+        let levels = chain.extents.len() as u32;
+        for level in 0..levels {
+            let view_create_info = *vk::ImageViewCreateInfo::builder()
+                .image(chain.image)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(HDR_FORMAT)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: level,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                });
+            chain
+                .views
+                .push(self.device.create_image_view(&view_create_info, None)?);
+        }
+        chain.descriptor_pool = create_pool(
+            &self.device,
+            levels * 2,
+            &[
+                (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1.0),
+                (vk::DescriptorType::STORAGE_IMAGE, 1.0),
+            ],
+        )?;
+        let write_set = |src: vk::ImageView, src_layout: vk::ImageLayout, dst: vk::ImageView| {
+            let set = allocate_set(&self.device, chain.descriptor_pool, layout)?;
+            DescriptorWriter::new()
+                .image(
+                    0,
+                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    vk::DescriptorImageInfo {
+                        sampler,
+                        image_view: src,
+                        image_layout: src_layout,
+                    },
+                )
+                .image(
+                    1,
+                    vk::DescriptorType::STORAGE_IMAGE,
+                    vk::DescriptorImageInfo {
+                        sampler: vk::Sampler::null(),
+                        image_view: dst,
+                        image_layout: vk::ImageLayout::GENERAL,
+                    },
+                )
+                .update(&self.device, set);
+            Ok::<_, vk::Result>(set)
+        };
+        let mut downsample_sets = Vec::with_capacity(levels as usize);
+        let mut upsample_sets = Vec::with_capacity(levels as usize - 1);
+        for (level, &view) in chain.views.iter().enumerate() {
+            let set = match level {
+                0 => write_set(hdr_view, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, view)?,
+                _ => write_set(chain.views[level - 1], vk::ImageLayout::GENERAL, view)?,
+            };
+            downsample_sets.push(set);
+            if level + 1 < chain.views.len() {
+                upsample_sets.push(write_set(
+                    chain.views[level + 1],
+                    vk::ImageLayout::GENERAL,
+                    view,
+                )?);
+            }
+        }
+        chain.composite_set = write_set(chain.views[0], vk::ImageLayout::GENERAL, hdr_view)?;
+        chain.downsample_sets = downsample_sets;
+        chain.upsample_sets = upsample_sets;
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use super::error::{RendererError, Result};
+use super::memory::Allocation;
+use super::{Buffer, BufferId, Renderer, Texture, TextureId, TextureKind};
+use ash::{vk, Device};
+
+impl Renderer {
+    /// 記録中のコマンドバッファで`f`を呼ぶ
+    ///
+    /// ashで直接コマンドを記録する場合に使う。`begin_frame`と`end_frame`の間だけ呼べる。
+    /// `split_submission`の後は続きのコマンドバッファが渡される。
+    pub fn record_raw<R, F: FnOnce(&Device, vk::CommandBuffer) -> R>(&self, f: F) -> Result<R> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "raw commands can only be recorded between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        let command_buffer = self.frames[self.current_frame].recording_command_buffer();
+        Ok(f(&self.device, command_buffer))
+    }
+
+    /// アプリケーションが作成したバッファを登録し、`BufferId`で使えるようにする
+    ///
+    /// `destroy_buffer`とレンダラーの破棄では登録を外すだけで、バッファは破棄しない。
+    /// メモリをマップしていない扱いなので、`write_buffer`などCPUからの書き込みはできない。
+    ///
+    /// # Safety
+    /// `buffer`はレンダラーのデバイスで作成された`size`バイト以上のバッファで、`usage`を
+    /// 指定して作成されていること。登録を外し、それを使うフレームが完了するまで破棄しないこと。
+    pub unsafe fn register_external_buffer(
+        &mut self,
+        buffer: vk::Buffer,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+    ) -> BufferId {
+        self.buffers.insert(Buffer {
+            buffer,
+            allocation: Allocation::external(size),
+            size,
+            usage,
+            external: true,
+        })
+    }
+
+    /// アプリケーションが作成したイメージを登録し、`TextureId`で使えるようにする
+    ///
+    /// `destroy_texture`とレンダラーの破棄では登録を外すだけで、イメージとビュー、
+    /// サンプラーは破棄しない。
+    ///
+    /// # Safety
+    /// `image`、`view`、`sampler`はレンダラーのデバイスで作成されたもので、`view`は`image`の
+    /// `mip_levels`個のミップを持つ`format`の2Dビューであること。サンプリングする時点で
+    /// `SHADER_READ_ONLY_OPTIMAL`になっていること。登録を外し、それを使うフレームが
+    /// 完了するまで破棄しないこと。
+    pub unsafe fn register_external_texture(
+        &mut self,
+        image: vk::Image,
+        view: vk::ImageView,
+        sampler: vk::Sampler,
+        extent: vk::Extent2D,
+        format: vk::Format,
+        mip_levels: u32,
+    ) -> TextureId {
+        self.textures.insert(Texture {
+            image,
+            allocation: Allocation::external(0),
+            view,
+            sampler,
+            extent,
+            format,
+            mip_levels,
+            kind: TextureKind::Texture2D,
+            usage: vk::ImageUsageFlags::SAMPLED,
+            external: true,
+        })
+    }
+
+    /// 記録中のフレームの次の提出で、`semaphore`のシグナルを`stage`で待つ
+    ///
+    /// 次の提出は`split_submission`か`end_frame`のどちらか先に呼ばれた方になる。
+    /// バイナリセマフォであること。
+    pub fn wait_semaphore(
+        &mut self,
+        semaphore: vk::Semaphore,
+        stage: vk::PipelineStageFlags,
+    ) -> Result<()> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "semaphores can only be added between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        self.frames[self.current_frame]
+            .external_waits
+            .push((semaphore, stage));
+        Ok(())
+    }
+
+    /// 記録中のフレームの`end_frame`の提出が完了したら`semaphore`をシグナルする
+    ///
+    /// 別のキューやAPIでフレームの結果を使う場合に、そちらで待つ。バイナリセマフォであること。
+    pub fn signal_semaphore(&mut self, semaphore: vk::Semaphore) -> Result<()> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "semaphores can only be added between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        self.frames[self.current_frame]
+            .external_signals
+            .push(semaphore);
+        Ok(())
+    }
+}
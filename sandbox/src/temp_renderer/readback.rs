@@ -0,0 +1,423 @@
+use super::buffer::create_buffer;
+use super::error::{RendererError, Result};
+use super::texture::image_barrier;
+use super::texture_format::sampled_aspect_mask;
+use super::{format_block, Buffer, BufferId, Handle, Renderer, TextureKind, TextureLevel};
+use ash::vk;
+use std::collections::VecDeque;
+
+/// リングバッファ内の各読み戻しの先頭のアラインメント。テクセルブロックの最大のバイト数に合わせる
+const READBACK_ALIGNMENT: vk::DeviceSize = 16;
+
+/// GPUからホストメモリへの読み戻し1件
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Readback {
+    /// リングバッファ内のオフセット
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize,
+    /// コピーを記録したフレームの番号
+    pub frame: u64,
+    /// コピーを記録したフレームコンテキスト
+    pub frame_index: usize,
+}
+
+pub type ReadbackId = Handle<Readback>;
+
+/// 読み戻し先のHOST_VISIBLEなリングバッファ
+///
+/// 確保は末尾から順に行い、先頭から連続して解放された分だけ空きが戻る。
+pub struct ReadbackRing {
+    pub buffer: Buffer,
+    /// 確保した順の読み戻しと、アラインメントを含めたバイト数
+    entries: VecDeque<(ReadbackId, Readback, vk::DeviceSize)>,
+    head: vk::DeviceSize,
+}
+
+impl ReadbackRing {
+    /// `size`バイトを確保できるオフセット。空きがなければ`None`
+    fn find_space(&self, size: vk::DeviceSize) -> Option<vk::DeviceSize> {
+        let capacity = self.buffer.size;
+        let Some(tail) = self.entries.front().map(|(_, readback, _)| readback.offset) else {
+            return (size <= capacity).then_some(0);
+        };
+        if self.head > tail {
+            // 末尾に入らなければ先頭に戻る
+            if self.head + size <= capacity {
+                Some(self.head)
+            } else {
+                (size <= tail).then_some(0)
+            }
+        } else {
+            (self.head + size <= tail).then_some(self.head)
+        }
+    }
+}
+
+impl Renderer {
+    /// `buffer`の`offset`から`size`バイトをホストメモリに読み戻すコピーを記録中のフレームに記録する
+    ///
+    /// 書き込みは事前に`buffer_barrier`で`BufferAccess::TRANSFER_READ`へのバリアを記録しておくこと。
+    /// フレームを止めずに`poll_readback`で完了を確認し、`readback_data`で結果を読んで
+    /// `release_readback`で解放する。
+    pub fn readback_buffer(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        buffer: BufferId,
+        offset: vk::DeviceSize,
+        size: vk::DeviceSize,
+    ) -> Result<ReadbackId> {
+        let source = self.buffers.get(buffer).ok_or_else(|| {
+            RendererError::Validation(format!("buffer {:?} was destroyed", buffer))
+        })?;
+        if !source.usage.contains(vk::BufferUsageFlags::TRANSFER_SRC)
+            || size == 0
+            || offset + size > source.size
+        {
+            return Err(RendererError::Validation(format!(
+                "buffer {:?} needs TRANSFER_SRC usage and {}+{} within its {} bytes",
+                buffer, offset, size, source.size
+            )));
+        }
+        let source = source.buffer;
+        let (id, destination, readback_offset) = self.allocate_readback(size)?;
+        let region = vk::BufferCopy {
+            src_offset: offset,
+            dst_offset: readback_offset,
+            size,
+        };
+        unsafe {
+            self.device
+                .cmd_copy_buffer(command_buffer, source, destination, &[region]);
+            self.host_read_barrier(command_buffer);
+        }
+        Ok(id)
+    }
+
+    /// テクスチャのミップの`region`をホストメモリに読み戻すコピーを記録中のフレームに記録する
+    ///
+    /// 2Dテクスチャの非圧縮フォーマットに対応する。結果は行の間に隙間のない
+    /// `region`の大きさの画素列になる。テクスチャは`SHADER_READ_ONLY_OPTIMAL`のまま戻る。
+    pub fn readback_texture(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        level: impl Into<TextureLevel>,
+        region: vk::Rect2D,
+    ) -> Result<ReadbackId> {
+        let level = level.into();
+        let texture = self.textures.get(level.texture).ok_or_else(|| {
+            RendererError::Validation(format!("texture {:?} was destroyed", level.texture))
+        })?;
+        let width = (texture.extent.width >> level.mip_level).max(1);
+        let height = (texture.extent.height >> level.mip_level).max(1);
+        let in_range = region.offset.x >= 0
+            && region.offset.y >= 0
+            && region.extent.width > 0
+            && region.extent.height > 0
+            && region.offset.x as u32 + region.extent.width <= width
+            && region.offset.y as u32 + region.extent.height <= height;
+        let block = format_block(texture.format).filter(|block| {
+            block.width == 1
+                && texture.kind == TextureKind::Texture2D
+                && level.mip_level < texture.mip_levels
+                && in_range
+        });
+        let Some(block) = block else {
+            return Err(RendererError::Validation(format!(
+                "cannot read back {:?} from mip {} of a {:?} {:?} texture",
+                region, level.mip_level, texture.format, texture.kind
+            )));
+        };
+        if !texture.usage.contains(vk::ImageUsageFlags::TRANSFER_SRC) {
+            return Err(RendererError::Validation(format!(
+                "texture {:?} needs TRANSFER_SRC usage to be read back",
+                level.texture
+            )));
+        }
+        let image = texture.image;
+        let aspect_mask = sampled_aspect_mask(texture.format);
+        let size = block.level_size(region.extent.width, region.extent.height) as vk::DeviceSize;
+        let (id, destination, readback_offset) = self.allocate_readback(size)?;
+        let copy = vk::BufferImageCopy {
+            buffer_offset: readback_offset,
+            buffer_row_length: 0,
+            buffer_image_height: 0,
+            image_subresource: vk::ImageSubresourceLayers {
+                aspect_mask,
+                mip_level: level.mip_level,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            image_offset: vk::Offset3D {
+                x: region.offset.x,
+                y: region.offset.y,
+                z: 0,
+            },
+            image_extent: vk::Extent3D {
+                width: region.extent.width,
+                height: region.extent.height,
+                depth: 1,
+            },
+        };
+        let shader_stages =
+            vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER;
+        unsafe {
+            image_barrier(
+                &self.device,
+                command_buffer,
+                image,
+                aspect_mask,
+                level.mip_level,
+                1,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::TRANSFER_READ,
+                shader_stages,
+                vk::PipelineStageFlags::TRANSFER,
+            );
+            self.device.cmd_copy_image_to_buffer(
+                command_buffer,
+                image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                destination,
+                &[copy],
+            );
+            image_barrier(
+                &self.device,
+                command_buffer,
+                image,
+                aspect_mask,
+                level.mip_level,
+                1,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::SHADER_READ,
+                vk::PipelineStageFlags::TRANSFER,
+                shader_stages,
+            );
+            self.host_read_barrier(command_buffer);
+        }
+        Ok(id)
+    }
+
+    /// 読み戻しが完了していれば`true`。待たずに返る
+    pub fn poll_readback(&self, id: ReadbackId) -> Result<bool> {
+        self.submitted_frame_completed(self.pending_readback(id)?.frame)
+    }
+
+    /// 読み戻しの完了を待つ。記録中のフレームのものは提出されるまで待てないのでエラーになる
+    pub fn wait_readback(&self, id: ReadbackId) -> Result<()> {
+        let readback = self.pending_readback(id)?;
+        if readback.frame >= self.frame_count {
+            return Err(RendererError::Validation(format!(
+                "readback {:?} has not been submitted yet",
+                id
+            )));
+        }
+        if !self.submitted_frame_completed(readback.frame)? {
+            let fence = self.frames[readback.frame_index].in_flight_fence;
+            unsafe { self.device.wait_for_fences(&[fence], true, u64::MAX)? };
+        }
+        Ok(())
+    }
+
+    /// 完了した読み戻しの結果。完了していなければ`None`
+    ///
+    /// 結果は`release_readback`を呼ぶまで有効。
+    pub fn readback_data(&self, id: ReadbackId) -> Result<Option<&[u8]>> {
+        if !self.poll_readback(id)? {
+            return Ok(None);
+        }
+        let readback = self.pending_readback(id)?;
+        let ring = self.readback_ring.as_ref().unwrap();
+        let ptr = ring.buffer.allocation.mapped_ptr.ok_or_else(|| {
+            RendererError::Validation("readback memory is not host visible".to_owned())
+        })?;
+        Ok(Some(unsafe {
+            std::slice::from_raw_parts(ptr.add(readback.offset as usize), readback.size as usize)
+        }))
+    }
+
+    /// 読み戻しを解放し、リングバッファの領域を再利用できるようにする
+    ///
+    /// 完了していない読み戻しを解放すると、その領域はGPUが書き込み終わるまで再利用されない。
+    pub fn release_readback(&mut self, id: ReadbackId) -> Result<()> {
+        self.readbacks.remove(id).ok_or_else(|| {
+            RendererError::Validation(format!("readback {:?} was already released", id))
+        })?;
+        self.reclaim_readbacks()
+    }
+
+    fn pending_readback(&self, id: ReadbackId) -> Result<&Readback> {
+        self.readbacks.get(id).ok_or_else(|| {
+            RendererError::Validation(format!("readback {:?} was already released", id))
+        })
+    }
+
+    /// 解放済みでGPUの書き込みも終わった読み戻しをリングバッファの先頭から取り除く
+    fn reclaim_readbacks(&mut self) -> Result<()> {
+        let Some(ring) = self.readback_ring.as_ref() else {
+            return Ok(());
+        };
+        let mut reclaimed = 0;
+        for (id, readback, _) in ring.entries.iter() {
+            if self.readbacks.contains(*id) || !self.submitted_frame_completed(readback.frame)? {
+                break;
+            }
+            reclaimed += 1;
+        }
+        let ring = self.readback_ring.as_mut().unwrap();
+        ring.entries.drain(..reclaimed);
+        if ring.entries.is_empty() {
+            ring.head = 0;
+        }
+        Ok(())
+    }
+
+    /// リングバッファから`size`バイトを確保し、記録中のフレームの読み戻しとして登録する
+    fn allocate_readback(
+        &mut self,
+        size: vk::DeviceSize,
+    ) -> Result<(ReadbackId, vk::Buffer, vk::DeviceSize)> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "readbacks must be recorded between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        self.reclaim_readbacks()?;
+        if self.readback_ring.is_none() {
+            let buffer = unsafe {
+                create_buffer(
+                    &self.device,
+                    &mut self.allocator,
+                    self.config.readback_ring_size,
+                    vk::BufferUsageFlags::TRANSFER_DST,
+                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                )?
+            };
+            self.readback_ring = Some(ReadbackRing {
+                buffer,
+                entries: VecDeque::new(),
+                head: 0,
+            });
+        }
+        let ring = self.readback_ring.as_mut().unwrap();
+        let aligned = size.next_multiple_of(READBACK_ALIGNMENT);
+        let offset = ring.find_space(aligned).ok_or_else(|| {
+            RendererError::Validation(format!(
+                "readback ring of {} bytes has no room for {} bytes; release finished readbacks",
+                ring.buffer.size, size
+            ))
+        })?;
+        let readback = Readback {
+            offset,
+            size,
+            frame: self.frame_count,
+            frame_index: self.current_frame,
+        };
+        let id = self.readbacks.insert(readback);
+        ring.entries.push_back((id, readback, aligned));
+        ring.head = offset + aligned;
+        Ok((id, ring.buffer.buffer, offset))
+    }
+
+    /// 転送の書き込みをホストから読めるようにする
+    unsafe fn host_read_barrier(&self, command_buffer: vk::CommandBuffer) {
+        let barrier = *vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ);
+        self.device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::HOST,
+            vk::DependencyFlags::empty(),
+            &[barrier],
+            &[],
+            &[],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::memory::Allocation;
+    use super::super::Pool;
+    use super::*;
+
+    /// `allocated`の順に確保済みで、末尾が`head`のリングバッファ
+    fn ring_buffer(
+        capacity: vk::DeviceSize,
+        allocated: &[(vk::DeviceSize, vk::DeviceSize)],
+        head: vk::DeviceSize,
+    ) -> ReadbackRing {
+        let mut readbacks = Pool::new();
+        let entries = allocated
+            .iter()
+            .map(|&(offset, size)| {
+                let readback = Readback {
+                    offset,
+                    size,
+                    frame: 0,
+                    frame_index: 0,
+                };
+                (readbacks.insert(readback), readback, size)
+            })
+            .collect();
+        ReadbackRing {
+            buffer: Buffer {
+                buffer: vk::Buffer::null(),
+                allocation: Allocation::external(capacity),
+                size: capacity,
+                usage: vk::BufferUsageFlags::TRANSFER_DST,
+                external: true,
+            },
+            entries,
+            head,
+        }
+    }
+
+    #[test]
+    fn empty_ring_starts_at_zero() {
+        let ring = ring_buffer(64, &[], 0);
+        assert_eq!(ring.find_space(64), Some(0));
+        assert_eq!(ring.find_space(80), None);
+    }
+
+    #[test]
+    fn allocates_after_head_until_the_end() {
+        let ring = ring_buffer(64, &[(0, 32)], 32);
+        assert_eq!(ring.find_space(32), Some(32));
+        // 末尾に入らず、先頭も使用中
+        assert_eq!(ring.find_space(48), None);
+    }
+
+    #[test]
+    fn wraps_to_the_start_when_the_end_is_full() {
+        // 先頭の16バイトは解放済み
+        let ring = ring_buffer(64, &[(16, 32)], 48);
+        assert_eq!(ring.find_space(16), Some(48));
+        assert_eq!(ring.find_space(32), None);
+        assert_eq!(ring.find_space(16 + 1), None);
+
+        let ring = ring_buffer(64, &[(32, 16)], 48);
+        assert_eq!(ring.find_space(32), Some(0));
+        assert_eq!(ring.find_space(48), None);
+    }
+
+    #[test]
+    fn wrapped_head_stops_at_the_oldest_readback() {
+        let ring = ring_buffer(64, &[(32, 32), (0, 16)], 16);
+        assert_eq!(ring.find_space(16), Some(16));
+        assert_eq!(ring.find_space(32), None);
+    }
+
+    #[test]
+    fn full_ring_has_no_space() {
+        let ring = ring_buffer(64, &[(0, 64)], 64);
+        assert_eq!(ring.find_space(16), None);
+
+        let ring = ring_buffer(64, &[(32, 32), (0, 32)], 32);
+        assert_eq!(ring.find_space(16), None);
+    }
+}
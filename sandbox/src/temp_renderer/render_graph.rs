@@ -0,0 +1,1176 @@
+use super::error::{RendererError, Result};
+use super::memory::{Allocation, AllocationDesc, AllocationKind};
+use super::renderer::depth_aspect_mask;
+use super::{Buffer, Renderer};
+use ash::{vk, Device};
+use std::collections::HashSet;
+
+/// レンダーグラフ内のイメージ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GraphImage(usize);
+
+/// レンダーグラフ内のバッファ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GraphBuffer(usize);
+
+/// パスがイメージをどのように使うか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageAccess {
+    pub stage: vk::PipelineStageFlags,
+    pub access: vk::AccessFlags,
+    pub layout: vk::ImageLayout,
+}
+
+impl ImageAccess {
+    pub const COLOR_ATTACHMENT_WRITE: Self = Self {
+        stage: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        access: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+    };
+    /// ブレンドやロードで以前の内容も読む
+    pub const COLOR_ATTACHMENT_READ_WRITE: Self = Self {
+        stage: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        access: vk::AccessFlags::from_raw(
+            vk::AccessFlags::COLOR_ATTACHMENT_READ.as_raw()
+                | vk::AccessFlags::COLOR_ATTACHMENT_WRITE.as_raw(),
+        ),
+        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+    };
+    pub const DEPTH_ATTACHMENT_WRITE: Self = Self {
+        stage: vk::PipelineStageFlags::from_raw(
+            vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS.as_raw()
+                | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS.as_raw(),
+        ),
+        access: vk::AccessFlags::from_raw(
+            vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ.as_raw()
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE.as_raw(),
+        ),
+        layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+    };
+    pub const DEPTH_ATTACHMENT_READ: Self = Self {
+        stage: vk::PipelineStageFlags::from_raw(
+            vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS.as_raw()
+                | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS.as_raw(),
+        ),
+        access: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ,
+        layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+    };
+    pub const FRAGMENT_SHADER_READ: Self = Self {
+        stage: vk::PipelineStageFlags::FRAGMENT_SHADER,
+        access: vk::AccessFlags::SHADER_READ,
+        layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+    };
+    /// 深度イメージをサンプリングしながら、同じパスで読み込み専用の深度アタッチメントにもする。
+    /// デスクリプタは`DescriptorWriter::depth_image`で書き込む
+    pub const FRAGMENT_SHADER_DEPTH_READ: Self = Self {
+        stage: vk::PipelineStageFlags::from_raw(
+            vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS.as_raw()
+                | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS.as_raw()
+                | vk::PipelineStageFlags::FRAGMENT_SHADER.as_raw(),
+        ),
+        access: vk::AccessFlags::from_raw(
+            vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ.as_raw()
+                | vk::AccessFlags::SHADER_READ.as_raw(),
+        ),
+        layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+    };
+    pub const COMPUTE_SHADER_READ: Self = Self {
+        stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+        access: vk::AccessFlags::SHADER_READ,
+        layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+    };
+    /// コンピュートシェーダーで深度イメージをサンプリングする。
+    /// デスクリプタは`DescriptorWriter::depth_image`で書き込む
+    pub const COMPUTE_SHADER_DEPTH_READ: Self = Self {
+        stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+        access: vk::AccessFlags::SHADER_READ,
+        layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+    };
+    /// ストレージイメージとしての書き込み
+    pub const COMPUTE_SHADER_WRITE: Self = Self {
+        stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+        access: vk::AccessFlags::SHADER_WRITE,
+        layout: vk::ImageLayout::GENERAL,
+    };
+    pub const TRANSFER_READ: Self = Self {
+        stage: vk::PipelineStageFlags::TRANSFER,
+        access: vk::AccessFlags::TRANSFER_READ,
+        layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+    };
+    pub const TRANSFER_WRITE: Self = Self {
+        stage: vk::PipelineStageFlags::TRANSFER,
+        access: vk::AccessFlags::TRANSFER_WRITE,
+        layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+    };
+
+    fn is_write(&self) -> bool {
+        self.access.intersects(WRITE_ACCESS)
+    }
+}
+
+/// パスがバッファをどのように使うか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferAccess {
+    pub stage: vk::PipelineStageFlags,
+    pub access: vk::AccessFlags,
+}
+
+impl BufferAccess {
+    pub const VERTEX_BUFFER: Self = Self {
+        stage: vk::PipelineStageFlags::VERTEX_INPUT,
+        access: vk::AccessFlags::VERTEX_ATTRIBUTE_READ,
+    };
+    pub const INDEX_BUFFER: Self = Self {
+        stage: vk::PipelineStageFlags::VERTEX_INPUT,
+        access: vk::AccessFlags::INDEX_READ,
+    };
+    /// 間接描画と間接ディスパッチの引数として読む
+    pub const INDIRECT_BUFFER: Self = Self {
+        stage: vk::PipelineStageFlags::DRAW_INDIRECT,
+        access: vk::AccessFlags::INDIRECT_COMMAND_READ,
+    };
+    /// `begin_conditional`の条件として読む
+    pub const CONDITIONAL_RENDERING_READ: Self = Self {
+        stage: vk::PipelineStageFlags::CONDITIONAL_RENDERING_EXT,
+        access: vk::AccessFlags::CONDITIONAL_RENDERING_READ_EXT,
+    };
+    pub const VERTEX_SHADER_READ: Self = Self {
+        stage: vk::PipelineStageFlags::VERTEX_SHADER,
+        access: vk::AccessFlags::SHADER_READ,
+    };
+    pub const FRAGMENT_SHADER_READ: Self = Self {
+        stage: vk::PipelineStageFlags::FRAGMENT_SHADER,
+        access: vk::AccessFlags::SHADER_READ,
+    };
+    pub const COMPUTE_SHADER_READ: Self = Self {
+        stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+        access: vk::AccessFlags::SHADER_READ,
+    };
+    pub const COMPUTE_SHADER_WRITE: Self = Self {
+        stage: vk::PipelineStageFlags::COMPUTE_SHADER,
+        access: vk::AccessFlags::SHADER_WRITE,
+    };
+    pub const TRANSFER_READ: Self = Self {
+        stage: vk::PipelineStageFlags::TRANSFER,
+        access: vk::AccessFlags::TRANSFER_READ,
+    };
+    pub const TRANSFER_WRITE: Self = Self {
+        stage: vk::PipelineStageFlags::TRANSFER,
+        access: vk::AccessFlags::TRANSFER_WRITE,
+    };
+    /// フェンスを待った後にHOST_VISIBLEなメモリをホストから読む
+    pub const HOST_READ: Self = Self {
+        stage: vk::PipelineStageFlags::HOST,
+        access: vk::AccessFlags::HOST_READ,
+    };
+
+    fn is_write(&self) -> bool {
+        self.access.intersects(WRITE_ACCESS)
+    }
+}
+
+const WRITE_ACCESS: vk::AccessFlags = vk::AccessFlags::from_raw(
+    vk::AccessFlags::SHADER_WRITE.as_raw()
+        | vk::AccessFlags::COLOR_ATTACHMENT_WRITE.as_raw()
+        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE.as_raw()
+        | vk::AccessFlags::TRANSFER_WRITE.as_raw()
+        | vk::AccessFlags::HOST_WRITE.as_raw()
+        | vk::AccessFlags::MEMORY_WRITE.as_raw(),
+);
+
+/// グラフが作成して、フレームの終わりに破棄するイメージ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransientImageDesc {
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+    pub usage: vk::ImageUsageFlags,
+}
+
+/// グラフの外から持ち込むイメージ。バリアは全ミップと全レイヤーにかける
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImportedImage {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    pub aspect_mask: vk::ImageAspectFlags,
+    /// グラフ実行前のレイアウト。`UNDEFINED`なら最初の使用で内容を捨てる
+    pub initial_layout: vk::ImageLayout,
+    /// グラフ実行後に遷移させるレイアウト。`None`なら最後の使用時のまま
+    pub final_layout: Option<vk::ImageLayout>,
+}
+
+enum ImageSource {
+    Imported(ImportedImage),
+    Transient(TransientImageDesc),
+}
+
+struct ImageEntry {
+    name: String,
+    source: ImageSource,
+}
+
+struct BufferEntry {
+    name: String,
+    buffer: vk::Buffer,
+}
+
+type ExecuteFn<'a> = Box<dyn FnOnce(&PassContext, vk::CommandBuffer) + 'a>;
+
+struct Pass<'a> {
+    name: String,
+    images: Vec<(GraphImage, ImageAccess)>,
+    buffers: Vec<(GraphBuffer, BufferAccess)>,
+    /// グラフの出力に関係なく必ず実行する
+    side_effect: bool,
+    execute: Option<ExecuteFn<'a>>,
+}
+
+/// パスの実行時に渡される、グラフのリソースの実体
+pub struct PassContext<'r> {
+    pub renderer: &'r Renderer,
+    images: Vec<(vk::Image, vk::ImageView)>,
+    buffers: Vec<vk::Buffer>,
+}
+
+impl PassContext<'_> {
+    pub fn device(&self) -> &Device {
+        &self.renderer.device
+    }
+
+    pub fn image(&self, image: GraphImage) -> vk::Image {
+        self.images[image.0].0
+    }
+
+    pub fn image_view(&self, image: GraphImage) -> vk::ImageView {
+        self.images[image.0].1
+    }
+
+    pub fn buffer(&self, buffer: GraphBuffer) -> vk::Buffer {
+        self.buffers[buffer.0]
+    }
+}
+
+/// パスと、パスが読み書きするリソースを宣言して実行するレンダーグラフ
+///
+/// 同じリソースを使うパス同士は宣言順に実行し、その読み書きの依存関係から実行順を求める。
+/// 依存のないパスは、直前に実行したパスの結果を使うものを先にして一時イメージの生存期間を短くする。
+/// インポートしたリソースへ書き込まないパスのうち、その結果がどこからも読まれないものは実行しない。
+/// パスの間には使い方に応じたバリアとレイアウト遷移を挿入し、生存期間が重ならない一時イメージは
+/// 同じメモリを使い回す。
+#[derive(Default)]
+pub struct RenderGraph<'a> {
+    images: Vec<ImageEntry>,
+    buffers: Vec<BufferEntry>,
+    passes: Vec<Pass<'a>>,
+}
+
+/// `RenderGraph::add_pass`で作るパスのビルダー
+pub struct PassBuilder<'g, 'a> {
+    graph: &'g mut RenderGraph<'a>,
+    pass: Pass<'a>,
+}
+
+impl<'a> PassBuilder<'_, 'a> {
+    pub fn image(mut self, image: GraphImage, access: ImageAccess) -> Self {
+        self.pass.images.push((image, access));
+        self
+    }
+
+    pub fn buffer(mut self, buffer: GraphBuffer, access: BufferAccess) -> Self {
+        self.pass.buffers.push((buffer, access));
+        self
+    }
+
+    /// 出力が使われなくても実行する(読み戻しなど)
+    pub fn side_effect(mut self) -> Self {
+        self.pass.side_effect = true;
+        self
+    }
+
+    pub fn execute<F: FnOnce(&PassContext, vk::CommandBuffer) + 'a>(mut self, f: F) {
+        self.pass.execute = Some(Box::new(f));
+        self.graph.passes.push(self.pass);
+    }
+}
+
+impl<'a> RenderGraph<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn import_image(&mut self, name: &str, image: ImportedImage) -> GraphImage {
+        self.images.push(ImageEntry {
+            name: name.to_owned(),
+            source: ImageSource::Imported(image),
+        });
+        GraphImage(self.images.len() - 1)
+    }
+
+    pub fn create_image(&mut self, name: &str, desc: TransientImageDesc) -> GraphImage {
+        self.images.push(ImageEntry {
+            name: name.to_owned(),
+            source: ImageSource::Transient(desc),
+        });
+        GraphImage(self.images.len() - 1)
+    }
+
+    pub fn import_buffer(&mut self, name: &str, buffer: &Buffer) -> GraphBuffer {
+        self.buffers.push(BufferEntry {
+            name: name.to_owned(),
+            buffer: buffer.buffer,
+        });
+        GraphBuffer(self.buffers.len() - 1)
+    }
+
+    pub fn add_pass<'g>(&'g mut self, name: &str) -> PassBuilder<'g, 'a> {
+        PassBuilder {
+            graph: self,
+            pass: Pass {
+                name: name.to_owned(),
+                images: Vec::new(),
+                buffers: Vec::new(),
+                side_effect: false,
+                execute: None,
+            },
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        for pass in self.passes.iter() {
+            for (index, (image, _)) in pass.images.iter().enumerate() {
+                if image.0 >= self.images.len() {
+                    return Err(RendererError::Validation(format!(
+                        "pass '{}' uses an image from another graph",
+                        pass.name
+                    )));
+                }
+                if pass.images[..index].iter().any(|(other, _)| other == image) {
+                    return Err(RendererError::Validation(format!(
+                        "pass '{}' declares image '{}' more than once",
+                        pass.name, self.images[image.0].name
+                    )));
+                }
+            }
+            for (index, (buffer, _)) in pass.buffers.iter().enumerate() {
+                if buffer.0 >= self.buffers.len() {
+                    return Err(RendererError::Validation(format!(
+                        "pass '{}' uses a buffer from another graph",
+                        pass.name
+                    )));
+                }
+                if pass.buffers[..index]
+                    .iter()
+                    .any(|(other, _)| other == buffer)
+                {
+                    return Err(RendererError::Validation(format!(
+                        "pass '{}' declares buffer '{}' more than once",
+                        pass.name, self.buffers[buffer.0].name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 実行するパスのインデックスを宣言順に返す。`disabled_passes`に名前があるパスは実行しない
+    fn cull_passes(&self, disabled_passes: &HashSet<String>) -> Vec<usize> {
+        let mut needed_images: Vec<bool> = self
+            .images
+            .iter()
+            .map(|entry| matches!(entry.source, ImageSource::Imported(_)))
+            .collect();
+        let mut needed_buffers = vec![true; self.buffers.len()];
+        let mut keep = vec![false; self.passes.len()];
+
+        // 後ろから見ていき、必要なリソースに書き込むパスが読むリソースも必要とする
+        for (index, pass) in self.passes.iter().enumerate().rev() {
+            if disabled_passes.contains(&pass.name) {
+                continue;
+            }
+            let writes_needed = pass
+                .images
+                .iter()
+                .any(|(image, access)| access.is_write() && needed_images[image.0])
+                || pass
+                    .buffers
+                    .iter()
+                    .any(|(buffer, access)| access.is_write() && needed_buffers[buffer.0]);
+            if !(pass.side_effect || writes_needed) {
+                continue;
+            }
+            keep[index] = true;
+            for (image, _) in pass.images.iter() {
+                needed_images[image.0] = true;
+            }
+            for (buffer, _) in pass.buffers.iter() {
+                needed_buffers[buffer.0] = true;
+            }
+        }
+        (0..self.passes.len())
+            .filter(|&index| keep[index])
+            .collect()
+    }
+
+    /// `passes`を依存関係を満たす実行順に並べ替える
+    ///
+    /// 同じリソースへの書き込みの後の読み書き(RAW、WAW)と、読み込みの後の書き込み(WAR)を
+    /// 宣言順の依存とする。実行できるパスが複数あれば、最も後に実行したパスに依存するものを選び、
+    /// 同じなら宣言順にする。
+    fn schedule_passes(&self, passes: &[usize]) -> Vec<usize> {
+        #[derive(Default, Clone)]
+        struct Hazards {
+            last_writer: Option<usize>,
+            readers: Vec<usize>,
+        }
+        fn access(hazards: &mut Hazards, node: usize, is_write: bool, deps: &mut Vec<usize>) {
+            deps.extend(hazards.last_writer);
+            if is_write {
+                deps.append(&mut hazards.readers);
+                hazards.last_writer = Some(node);
+            } else {
+                hazards.readers.push(node);
+            }
+        }
+
+        let mut images = vec![Hazards::default(); self.images.len()];
+        let mut buffers = vec![Hazards::default(); self.buffers.len()];
+        let mut deps: Vec<Vec<usize>> = Vec::with_capacity(passes.len());
+        for (node, &pass_index) in passes.iter().enumerate() {
+            let pass = &self.passes[pass_index];
+            let mut pass_deps = Vec::new();
+            for (image, image_access) in pass.images.iter() {
+                access(
+                    &mut images[image.0],
+                    node,
+                    image_access.is_write(),
+                    &mut pass_deps,
+                );
+            }
+            for (buffer, buffer_access) in pass.buffers.iter() {
+                access(
+                    &mut buffers[buffer.0],
+                    node,
+                    buffer_access.is_write(),
+                    &mut pass_deps,
+                );
+            }
+            pass_deps.sort_unstable();
+            pass_deps.dedup();
+            pass_deps.retain(|&dep| dep != node);
+            deps.push(pass_deps);
+        }
+
+        // 依存は常に宣言の前のパスに向くので循環はない
+        let mut position: Vec<Option<usize>> = vec![None; passes.len()];
+        let mut order = Vec::with_capacity(passes.len());
+        while order.len() < passes.len() {
+            let next = (0..passes.len())
+                .filter(|&node| {
+                    position[node].is_none()
+                        && deps[node].iter().all(|&dep| position[dep].is_some())
+                })
+                .max_by_key(|&node| {
+                    let latest_dep = deps[node].iter().filter_map(|&dep| position[dep]).max();
+                    (latest_dep, std::cmp::Reverse(node))
+                })
+                .unwrap();
+            position[next] = Some(order.len());
+            order.push(passes[next]);
+        }
+        order
+    }
+}
+
+/// リソースの直前の使われ方
+#[derive(Clone, Copy)]
+struct ResourceState {
+    layout: vk::ImageLayout,
+    /// 最後の書き込み
+    write: Option<(vk::PipelineStageFlags, vk::AccessFlags)>,
+    /// 最後の書き込み以降、結果が見えるようになったステージ
+    visible_stages: vk::PipelineStageFlags,
+    /// 最後の書き込み以降に読んだステージ
+    read_stages: vk::PipelineStageFlags,
+}
+
+impl ResourceState {
+    fn new(layout: vk::ImageLayout) -> Self {
+        Self {
+            layout,
+            write: None,
+            visible_stages: vk::PipelineStageFlags::empty(),
+            read_stages: vk::PipelineStageFlags::empty(),
+        }
+    }
+
+    /// メモリを引き継いだ一時イメージの最初の使用で、前の持ち主の読み書きの完了を待たせる
+    fn inherit(&mut self, previous: &ResourceState) {
+        self.write = previous.write;
+        self.read_stages = previous.read_stages;
+        self.visible_stages = vk::PipelineStageFlags::empty();
+    }
+
+    /// `stage`/`access`で`layout`として使う前に必要なバリアのsrc/dstを返し、状態を更新する
+    fn transition(
+        &mut self,
+        stage: vk::PipelineStageFlags,
+        access: vk::AccessFlags,
+        layout: vk::ImageLayout,
+        is_write: bool,
+    ) -> Option<Barrier> {
+        let layout_changed = self.layout != layout;
+        let (mut src_stage, src_access) = self.write.unwrap_or_default();
+        let needs_barrier = layout_changed
+            || is_write
+            || (self.write.is_some() && !self.visible_stages.contains(stage));
+        if is_write || layout_changed {
+            // 書き込み前は、それまでの読み込みの完了も待つ(WAR)
+            src_stage |= self.read_stages;
+        }
+
+        let barrier = needs_barrier.then(|| Barrier {
+            // グラフ内で未使用なら同じステージから待たせ、取得セマフォなどの待機とつなげる
+            src_stage: if src_stage.is_empty() {
+                stage
+            } else {
+                src_stage
+            },
+            dst_stage: stage,
+            src_access,
+            dst_access: access,
+            old_layout: self.layout,
+            new_layout: layout,
+        });
+
+        self.layout = layout;
+        if is_write {
+            self.write = Some((stage, access & WRITE_ACCESS));
+            self.visible_stages = vk::PipelineStageFlags::empty();
+            self.read_stages = vk::PipelineStageFlags::empty();
+        } else {
+            if layout_changed {
+                // レイアウト遷移は書き込みとして扱われるので、以前見えていたステージは無効になる
+                self.visible_stages = vk::PipelineStageFlags::empty();
+            }
+            self.visible_stages |= stage;
+            self.read_stages |= stage;
+        }
+        barrier
+    }
+}
+
+struct Barrier {
+    src_stage: vk::PipelineStageFlags,
+    dst_stage: vk::PipelineStageFlags,
+    src_access: vk::AccessFlags,
+    dst_access: vk::AccessFlags,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+}
+
+/// フォーマットからイメージのアスペクトを決める
+pub fn format_aspect_mask(format: vk::Format) -> vk::ImageAspectFlags {
+    match format {
+        vk::Format::D16_UNORM
+        | vk::Format::X8_D24_UNORM_PACK32
+        | vk::Format::D32_SFLOAT
+        | vk::Format::D16_UNORM_S8_UINT
+        | vk::Format::D24_UNORM_S8_UINT
+        | vk::Format::D32_SFLOAT_S8_UINT => depth_aspect_mask(format),
+        vk::Format::S8_UINT => vk::ImageAspectFlags::STENCIL,
+        _ => vk::ImageAspectFlags::COLOR,
+    }
+}
+
+/// 一時イメージの実体
+struct TransientImages {
+    images: Vec<(vk::Image, vk::ImageView)>,
+    /// 一時イメージごとに、同じメモリを直前に使っていたイメージのグラフ内のインデックス
+    aliases: Vec<Option<usize>>,
+    allocations: Vec<Allocation>,
+}
+
+/// 一時イメージで使い回すメモリ
+struct TransientSlot {
+    /// 最後に使われる実行順
+    last_use: usize,
+    allocation: Allocation,
+    requirements: vk::MemoryRequirements,
+    /// 最後にこのメモリを使うイメージのグラフ内のインデックス
+    owner: usize,
+}
+
+impl TransientImages {
+    unsafe fn destroy(self, device: &Device, allocator: &mut super::MemoryAllocator) {
+        for (image, view) in self.images {
+            device.destroy_image_view(view, None);
+            device.destroy_image(image, None);
+        }
+        for allocation in self.allocations {
+            allocator.free(device, allocation);
+        }
+    }
+}
+
+impl Renderer {
+    /// デバッグ用に、`name`のパスを以降のフレームで実行するかどうかを切り替える
+    ///
+    /// 無効にしたパスの出力だけを使うパスも実行されなくなる。無効にしたパスが書き込むはずだった
+    /// イメージの内容は未定義になる。
+    ///
+    /// グラフの外で記録するポストプロセスは次の名前で切り替える。無効にすると設定で有効でもかけない。
+    ///
+    /// - `"auto_exposure"`: 露出をその時点の値に固定する
+    /// - `"bloom"`: ブルーム
+    /// - `"fxaa"`: FXAA
+    /// - `"taa"`: TAA。投影行列のジッターもなくなる
+    /// - `"ssr"`: スクリーンスペース反射
+    /// - `"depth_of_field"`: 被写界深度
+    /// - `"motion_blur"`: モーションブラー
+    /// - `"outline"`: 輪郭線
+    pub fn set_pass_enabled(&mut self, name: &str, enabled: bool) {
+        if enabled {
+            self.disabled_passes.remove(name);
+        } else {
+            self.disabled_passes.insert(name.to_owned());
+        }
+    }
+
+    pub fn is_pass_enabled(&self, name: &str) -> bool {
+        !self.disabled_passes.contains(name)
+    }
+
+    /// 取得中のスワップチェインイメージをインポートする。実行後は`PRESENT_SRC_KHR`になる
+    pub fn import_present_image(&self, graph: &mut RenderGraph) -> GraphImage {
+        graph.import_image(
+            "present",
+            ImportedImage {
+                image: self.present_images[self.present_index as usize],
+                view: self.present_image_views[self.present_index as usize],
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: Some(vk::ImageLayout::PRESENT_SRC_KHR),
+            },
+        )
+    }
+
+    /// 深度バッファをインポートする。前のフレームの内容は捨てる
+    ///
+    /// `ImageAccess::FRAGMENT_SHADER_DEPTH_READ`でサンプリングできる。ステンシルを含む
+    /// フォーマットでは深度だけのビューを別に作ること。MSAAが有効な場合、深度バッファは
+    /// `msaa_samples`のマルチサンプルイメージになる。
+    pub fn import_depth_image(&self, graph: &mut RenderGraph) -> GraphImage {
+        graph.import_image(
+            "depth",
+            ImportedImage {
+                image: self.depth_image,
+                view: self.depth_image_view,
+                aspect_mask: depth_aspect_mask(self.depth_format),
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: None,
+            },
+        )
+    }
+
+    /// `graph`のパスを`command_buffer`に記録する
+    ///
+    /// 一時イメージはこの中で作成し、記録中のフレームが完了した後に破棄する。
+    pub fn execute_render_graph(
+        &mut self,
+        graph: RenderGraph,
+        command_buffer: vk::CommandBuffer,
+    ) -> Result<()> {
+        graph.validate()?;
+        let order = graph.schedule_passes(&graph.cull_passes(&self.disabled_passes));
+        let transients = unsafe { self.create_transient_images(&graph, &order)? };
+
+        let mut images = Vec::with_capacity(graph.images.len());
+        let mut states = Vec::with_capacity(graph.images.len());
+        let mut aliases = Vec::with_capacity(graph.images.len());
+        let mut transient_iter = transients.images.iter().zip(transients.aliases.iter());
+        for entry in graph.images.iter() {
+            match &entry.source {
+                ImageSource::Imported(imported) => {
+                    images.push((imported.image, imported.view));
+                    states.push(ResourceState::new(imported.initial_layout));
+                    aliases.push(None);
+                }
+                ImageSource::Transient(_) => {
+                    let (&image, &alias) = transient_iter.next().unwrap();
+                    images.push(image);
+                    states.push(ResourceState::new(vk::ImageLayout::UNDEFINED));
+                    aliases.push(alias);
+                }
+            }
+        }
+        let mut buffer_states =
+            vec![ResourceState::new(vk::ImageLayout::UNDEFINED); graph.buffers.len()];
+        let context = PassContext {
+            renderer: self,
+            images,
+            buffers: graph.buffers.iter().map(|entry| entry.buffer).collect(),
+        };
+
+        let RenderGraph {
+            images: image_entries,
+            mut passes,
+            ..
+        } = graph;
+        let aspect_mask = |index: usize| match &image_entries[index].source {
+            ImageSource::Imported(imported) => imported.aspect_mask,
+            ImageSource::Transient(desc) => format_aspect_mask(desc.format),
+        };
+
+        for &pass_index in order.iter() {
+            let pass = &mut passes[pass_index];
+            let mut src_stage = vk::PipelineStageFlags::empty();
+            let mut dst_stage = vk::PipelineStageFlags::empty();
+            let mut image_barriers = Vec::new();
+            let mut buffer_barriers = Vec::new();
+
+            for &(image, access) in pass.images.iter() {
+                // 前の持ち主の最後の使用は必ずこれより前の実行順にある
+                if let Some(previous) = aliases[image.0].take() {
+                    let previous = states[previous];
+                    states[image.0].inherit(&previous);
+                }
+                if let Some(barrier) = states[image.0].transition(
+                    access.stage,
+                    access.access,
+                    access.layout,
+                    access.is_write(),
+                ) {
+                    src_stage |= barrier.src_stage;
+                    dst_stage |= barrier.dst_stage;
+                    image_barriers.push(image_barrier(
+                        context.images[image.0].0,
+                        aspect_mask(image.0),
+                        &barrier,
+                    ));
+                }
+            }
+            for &(buffer, access) in pass.buffers.iter() {
+                if let Some(barrier) = buffer_states[buffer.0].transition(
+                    access.stage,
+                    access.access,
+                    vk::ImageLayout::UNDEFINED,
+                    access.is_write(),
+                ) {
+                    // 初回の読み込みはグラフの外で書き込まれた内容なのでバリアは要らない
+                    if barrier.src_access.is_empty() && !access.is_write() {
+                        continue;
+                    }
+                    src_stage |= barrier.src_stage;
+                    dst_stage |= barrier.dst_stage;
+                    buffer_barriers.push(
+                        *vk::BufferMemoryBarrier::builder()
+                            .buffer(context.buffers[buffer.0])
+                            .offset(0)
+                            .size(vk::WHOLE_SIZE)
+                            .src_access_mask(barrier.src_access)
+                            .dst_access_mask(barrier.dst_access)
+                            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED),
+                    );
+                }
+            }
+
+            unsafe {
+                if !image_barriers.is_empty() || !buffer_barriers.is_empty() {
+                    self.device.cmd_pipeline_barrier(
+                        command_buffer,
+                        src_stage,
+                        dst_stage,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &buffer_barriers,
+                        &image_barriers,
+                    );
+                }
+            }
+            if let Some(execute) = pass.execute.take() {
+                execute(&context, command_buffer);
+            }
+        }
+
+        // インポートしたイメージを指定のレイアウトにしてグラフの外に返す
+        let mut final_barriers = Vec::new();
+        let mut src_stage = vk::PipelineStageFlags::empty();
+        for (index, entry) in image_entries.iter().enumerate() {
+            let ImageSource::Imported(imported) = &entry.source else {
+                continue;
+            };
+            let Some(final_layout) = imported.final_layout else {
+                continue;
+            };
+            if let Some(barrier) = states[index].transition(
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::AccessFlags::empty(),
+                final_layout,
+                false,
+            ) {
+                if barrier.old_layout != barrier.new_layout {
+                    src_stage |= barrier.src_stage;
+                    final_barriers.push(image_barrier(
+                        context.images[index].0,
+                        imported.aspect_mask,
+                        &barrier,
+                    ));
+                }
+            }
+        }
+        if !final_barriers.is_empty() {
+            unsafe {
+                self.device.cmd_pipeline_barrier(
+                    command_buffer,
+                    src_stage,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &final_barriers,
+                );
+            }
+        }
+
+        drop(context);
+        self.destroy_deferred(move |device, allocator| unsafe {
+            transients.destroy(device, allocator)
+        });
+        Ok(())
+    }
+
+    /// 一時イメージを作成し、生存期間が重ならないもの同士でメモリを共有させる
+    unsafe fn create_transient_images(
+        &mut self,
+        graph: &RenderGraph,
+        order: &[usize],
+    ) -> Result<TransientImages> {
+        let mut transients = TransientImages {
+            images: Vec::new(),
+            aliases: Vec::new(),
+            allocations: Vec::new(),
+        };
+        let mut slots: Vec<TransientSlot> = Vec::new();
+
+        for (index, entry) in graph.images.iter().enumerate() {
+            let ImageSource::Transient(desc) = &entry.source else {
+                continue;
+            };
+            let uses: Vec<usize> = order
+                .iter()
+                .enumerate()
+                .filter(|(_, &pass_index)| {
+                    graph.passes[pass_index]
+                        .images
+                        .iter()
+                        .any(|(image, _)| image.0 == index)
+                })
+                .map(|(position, _)| position)
+                .collect();
+
+            let result = self.create_transient_image(desc, index, &uses, &mut slots);
+            match result {
+                Ok((image, alias)) => {
+                    transients.images.push(image);
+                    transients.aliases.push(alias);
+                }
+                Err(err) => {
+                    transients
+                        .allocations
+                        .extend(slots.into_iter().map(|slot| slot.allocation));
+                    transients.destroy(&self.device, &mut self.allocator);
+                    return Err(err);
+                }
+            }
+        }
+        transients
+            .allocations
+            .extend(slots.into_iter().map(|slot| slot.allocation));
+        Ok(transients)
+    }
+
+    /// 一時イメージを作成し、メモリを引き継いだ場合は前の持ち主のインデックスも返す
+    unsafe fn create_transient_image(
+        &mut self,
+        desc: &TransientImageDesc,
+        index: usize,
+        uses: &[usize],
+        slots: &mut Vec<TransientSlot>,
+    ) -> Result<((vk::Image, vk::ImageView), Option<usize>)> {
+        let image_info = *vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(desc.format)
+            .extent(desc.extent.into())
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(desc.usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let image = self.device.create_image(&image_info, None)?;
+        let requirements = self.device.get_image_memory_requirements(image);
+
+        // 使われないイメージはメモリを持たせない(ビューも作らない)
+        let (Some(&first_use), Some(&last_use)) = (uses.first(), uses.last()) else {
+            return Ok(((image, vk::ImageView::null()), None));
+        };
+
+        let reusable = slots.iter_mut().find(|slot| {
+            slot.last_use < first_use
+                && slot.requirements.size >= requirements.size
+                && slot.allocation.offset % requirements.alignment.max(1) == 0
+                && requirements.memory_type_bits & (1 << slot.allocation.memory_type_index) != 0
+        });
+        let (allocation, alias) = match reusable {
+            Some(slot) => {
+                let previous = std::mem::replace(&mut slot.owner, index);
+                slot.last_use = last_use;
+                (slot.allocation, Some(previous))
+            }
+            None => {
+                let desc = AllocationDesc {
+                    requirements,
+                    memory_flags: vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                    kind: AllocationKind::Optimal,
+                    dedicated: None,
+                };
+                let allocation = match self.allocator.allocate(&self.device, &desc) {
+                    Ok(allocation) => allocation,
+                    Err(err) => {
+                        self.device.destroy_image(image, None);
+                        return Err(err);
+                    }
+                };
+                slots.push(TransientSlot {
+                    last_use,
+                    allocation,
+                    requirements,
+                    owner: index,
+                });
+                (allocation, None)
+            }
+        };
+        if let Err(err) = self
+            .device
+            .bind_image_memory(image, allocation.memory, allocation.offset)
+        {
+            self.device.destroy_image(image, None);
+            return Err(err.into());
+        }
+
+        let view_info = *vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(desc.format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: format_aspect_mask(desc.format),
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            });
+        match self.device.create_image_view(&view_info, None) {
+            Ok(view) => Ok(((image, view), alias)),
+            Err(err) => {
+                self.device.destroy_image(image, None);
+                Err(err.into())
+            }
+        }
+    }
+}
+
+fn image_barrier(
+    image: vk::Image,
+    aspect_mask: vk::ImageAspectFlags,
+    barrier: &Barrier,
+) -> vk::ImageMemoryBarrier {
+    *vk::ImageMemoryBarrier::builder()
+        .image(image)
+        .old_layout(barrier.old_layout)
+        .new_layout(barrier.new_layout)
+        .src_access_mask(barrier.src_access)
+        .dst_access_mask(barrier.dst_access)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .subresource_range(vk::ImageSubresourceRange {
+            aspect_mask,
+            // ミップマップのあるイメージも、全ミップをまとめて遷移させる
+            base_mip_level: 0,
+            level_count: vk::REMAINING_MIP_LEVELS,
+            base_array_layer: 0,
+            // カスケードシャドウのような配列のイメージは、全レイヤーをまとめて遷移させる
+            layer_count: vk::REMAINING_ARRAY_LAYERS,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transient(graph: &mut RenderGraph, name: &str) -> GraphImage {
+        graph.create_image(
+            name,
+            TransientImageDesc {
+                format: vk::Format::R8G8B8A8_UNORM,
+                extent: vk::Extent2D {
+                    width: 4,
+                    height: 4,
+                },
+                usage: vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+            },
+        )
+    }
+
+    fn imported(graph: &mut RenderGraph, name: &str) -> GraphImage {
+        graph.import_image(
+            name,
+            ImportedImage {
+                image: vk::Image::null(),
+                view: vk::ImageView::null(),
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: None,
+            },
+        )
+    }
+
+    fn order(graph: &RenderGraph) -> Vec<String> {
+        graph
+            .schedule_passes(&graph.cull_passes(&HashSet::new()))
+            .into_iter()
+            .map(|index| graph.passes[index].name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn dependent_passes_keep_declaration_order() {
+        let mut graph = RenderGraph::new();
+        let a = transient(&mut graph, "a");
+        let out = imported(&mut graph, "out");
+        graph
+            .add_pass("write")
+            .image(a, ImageAccess::COLOR_ATTACHMENT_WRITE)
+            .execute(|_, _| {});
+        graph
+            .add_pass("read")
+            .image(a, ImageAccess::FRAGMENT_SHADER_READ)
+            .image(out, ImageAccess::COLOR_ATTACHMENT_WRITE)
+            .execute(|_, _| {});
+        assert_eq!(order(&graph), ["write", "read"]);
+    }
+
+    #[test]
+    fn consumer_runs_right_after_its_producer() {
+        let mut graph = RenderGraph::new();
+        let a = transient(&mut graph, "a");
+        let x = imported(&mut graph, "x");
+        let y = imported(&mut graph, "y");
+        graph
+            .add_pass("produce")
+            .image(a, ImageAccess::COLOR_ATTACHMENT_WRITE)
+            .execute(|_, _| {});
+        graph
+            .add_pass("independent")
+            .image(x, ImageAccess::COLOR_ATTACHMENT_WRITE)
+            .execute(|_, _| {});
+        graph
+            .add_pass("consume")
+            .image(a, ImageAccess::FRAGMENT_SHADER_READ)
+            .image(y, ImageAccess::COLOR_ATTACHMENT_WRITE)
+            .execute(|_, _| {});
+        assert_eq!(order(&graph), ["produce", "consume", "independent"]);
+    }
+
+    #[test]
+    fn write_after_read_waits_for_reader() {
+        let mut graph = RenderGraph::new();
+        let x = imported(&mut graph, "x");
+        let y = imported(&mut graph, "y");
+        graph
+            .add_pass("read")
+            .image(x, ImageAccess::FRAGMENT_SHADER_READ)
+            .image(y, ImageAccess::COLOR_ATTACHMENT_WRITE)
+            .execute(|_, _| {});
+        graph
+            .add_pass("overwrite")
+            .image(x, ImageAccess::COLOR_ATTACHMENT_WRITE)
+            .execute(|_, _| {});
+        assert_eq!(order(&graph), ["read", "overwrite"]);
+    }
+
+    #[test]
+    fn unused_and_disabled_passes_are_culled() {
+        let mut graph = RenderGraph::new();
+        let a = transient(&mut graph, "a");
+        let b = transient(&mut graph, "b");
+        let out = imported(&mut graph, "out");
+        graph
+            .add_pass("unused")
+            .image(b, ImageAccess::COLOR_ATTACHMENT_WRITE)
+            .execute(|_, _| {});
+        graph
+            .add_pass("produce")
+            .image(a, ImageAccess::COLOR_ATTACHMENT_WRITE)
+            .execute(|_, _| {});
+        graph
+            .add_pass("present")
+            .image(a, ImageAccess::FRAGMENT_SHADER_READ)
+            .image(out, ImageAccess::COLOR_ATTACHMENT_WRITE)
+            .execute(|_, _| {});
+        assert_eq!(order(&graph), ["produce", "present"]);
+
+        let disabled = HashSet::from(["present".to_owned()]);
+        assert!(graph.cull_passes(&disabled).is_empty());
+    }
+
+    #[test]
+    fn aliased_image_waits_for_previous_owner() {
+        let mut previous = ResourceState::new(vk::ImageLayout::UNDEFINED);
+        let write = ImageAccess::COLOR_ATTACHMENT_WRITE;
+        previous.transition(write.stage, write.access, write.layout, true);
+        let read = ImageAccess::COMPUTE_SHADER_READ;
+        previous.transition(read.stage, read.access, read.layout, false);
+
+        let mut state = ResourceState::new(vk::ImageLayout::UNDEFINED);
+        state.inherit(&previous);
+        let storage = ImageAccess::COMPUTE_SHADER_WRITE;
+        let barrier = state
+            .transition(storage.stage, storage.access, storage.layout, true)
+            .unwrap();
+        assert_eq!(
+            barrier.src_stage,
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                | vk::PipelineStageFlags::COMPUTE_SHADER
+        );
+        assert_eq!(barrier.src_access, vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
+        assert_eq!(barrier.old_layout, vk::ImageLayout::UNDEFINED);
+        assert_eq!(barrier.new_layout, vk::ImageLayout::GENERAL);
+    }
+
+    #[test]
+    fn first_use_without_alias_has_no_source_access() {
+        let mut state = ResourceState::new(vk::ImageLayout::UNDEFINED);
+        let write = ImageAccess::COLOR_ATTACHMENT_WRITE;
+        let barrier = state
+            .transition(write.stage, write.access, write.layout, true)
+            .unwrap();
+        assert_eq!(barrier.src_stage, write.stage);
+        assert!(barrier.src_access.is_empty());
+    }
+
+    #[test]
+    fn image_barriers_cover_every_mip_and_layer() {
+        let mut state = ResourceState::new(vk::ImageLayout::UNDEFINED);
+        let read = ImageAccess::FRAGMENT_SHADER_READ;
+        let barrier = state
+            .transition(read.stage, read.access, read.layout, false)
+            .unwrap();
+        let range = image_barrier(vk::Image::null(), vk::ImageAspectFlags::COLOR, &barrier)
+            .subresource_range;
+        assert_eq!(range.base_mip_level, 0);
+        assert_eq!(range.level_count, vk::REMAINING_MIP_LEVELS);
+        assert_eq!(range.base_array_layer, 0);
+        assert_eq!(range.layer_count, vk::REMAINING_ARRAY_LAYERS);
+    }
+}
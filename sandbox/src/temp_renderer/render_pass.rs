@@ -0,0 +1,321 @@
+use super::error::Result;
+use super::Renderer;
+use ash::{vk, Device};
+
+/// レンダーパスと、それが想定するアタッチメントのフォーマット
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderPass {
+    pub render_pass: vk::RenderPass,
+    pub color_format: vk::Format,
+    pub depth_format: Option<vk::Format>,
+}
+
+impl RenderPass {
+    /// スワップチェインイメージに描画して表示する、カラー+深度のフォワードパス
+    ///
+    /// カラーと深度はパスの開始時にクリアし、終了時にカラーは`PRESENT_SRC_KHR`になる。
+    /// `samples`が1より大きい場合、アタッチメントは
+    /// [マルチサンプルのカラー, 深度, スワップチェインイメージ]の順になり、
+    /// サブパスの終わりにスワップチェインイメージへ解決する。
+    ///
+    /// # Safety
+    ///
+    /// `device`は有効なデバイスであること。
+    pub unsafe fn forward(
+        device: &Device,
+        color_format: vk::Format,
+        depth_format: vk::Format,
+        samples: vk::SampleCountFlags,
+    ) -> Result<Self> {
+        Self::color_depth(
+            device,
+            color_format,
+            depth_format,
+            samples,
+            vk::ImageLayout::PRESENT_SRC_KHR,
+        )
+    }
+
+    /// 後のパスでサンプリングするイメージに描画する、カラー+深度のパス
+    ///
+    /// アタッチメントの順序は`forward`と同じで、終了時にカラー(解決先)は
+    /// `SHADER_READ_ONLY_OPTIMAL`になる。
+    ///
+    /// # Safety
+    ///
+    /// `device`は有効なデバイスであること。
+    pub unsafe fn offscreen(
+        device: &Device,
+        color_format: vk::Format,
+        depth_format: vk::Format,
+        samples: vk::SampleCountFlags,
+    ) -> Result<Self> {
+        Self::color_depth(
+            device,
+            color_format,
+            depth_format,
+            samples,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        )
+    }
+
+    unsafe fn color_depth(
+        device: &Device,
+        color_format: vk::Format,
+        depth_format: vk::Format,
+        samples: vk::SampleCountFlags,
+        final_layout: vk::ImageLayout,
+    ) -> Result<Self> {
+        let sampled = final_layout == vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        let multisampled = samples != vk::SampleCountFlags::TYPE_1;
+        let mut attachments = vec![
+            vk::AttachmentDescription {
+                format: color_format,
+                samples,
+                load_op: vk::AttachmentLoadOp::CLEAR,
+                // マルチサンプルの内容は解決した後に要らない
+                store_op: if multisampled {
+                    vk::AttachmentStoreOp::DONT_CARE
+                } else {
+                    vk::AttachmentStoreOp::STORE
+                },
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: if multisampled {
+                    vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+                } else {
+                    final_layout
+                },
+                ..Default::default()
+            },
+            vk::AttachmentDescription {
+                format: depth_format,
+                samples,
+                load_op: vk::AttachmentLoadOp::CLEAR,
+                // オフスクリーンの深度は後のパスで読めるように保存する
+                store_op: if sampled {
+                    vk::AttachmentStoreOp::STORE
+                } else {
+                    vk::AttachmentStoreOp::DONT_CARE
+                },
+                stencil_load_op: vk::AttachmentLoadOp::CLEAR,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                ..Default::default()
+            },
+        ];
+        if multisampled {
+            attachments.push(vk::AttachmentDescription {
+                format: color_format,
+                samples: vk::SampleCountFlags::TYPE_1,
+                load_op: vk::AttachmentLoadOp::DONT_CARE,
+                store_op: vk::AttachmentStoreOp::STORE,
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout,
+                ..Default::default()
+            });
+        }
+        let color_attachment_refs = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let resolve_attachment_refs = [vk::AttachmentReference {
+            attachment: 2,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let depth_attachment_ref = vk::AttachmentReference {
+            attachment: 1,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        };
+        // スワップチェインイメージの取得と、前のフレームの深度書き込みを待つ。
+        // サンプリングするイメージでは前のフレームの読み込みも待つ
+        let mut src_stage_mask = vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS;
+        if sampled {
+            src_stage_mask |= vk::PipelineStageFlags::FRAGMENT_SHADER;
+        }
+        let mut dependencies = vec![vk::SubpassDependency {
+            src_subpass: vk::SUBPASS_EXTERNAL,
+            dst_subpass: 0,
+            src_stage_mask,
+            dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+            src_access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            ..Default::default()
+        }];
+        if sampled {
+            // 後のパスのフラグメントシェーダーで読めるようにする
+            dependencies.push(vk::SubpassDependency {
+                src_subpass: 0,
+                dst_subpass: vk::SUBPASS_EXTERNAL,
+                src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                dst_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER,
+                src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dst_access_mask: vk::AccessFlags::SHADER_READ,
+                ..Default::default()
+            });
+        }
+        let mut subpass = vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(&color_attachment_refs)
+            .depth_stencil_attachment(&depth_attachment_ref);
+        if multisampled {
+            subpass = subpass.resolve_attachments(&resolve_attachment_refs);
+        }
+        let subpasses = [*subpass];
+        let create_info = *vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses)
+            .dependencies(&dependencies);
+        let render_pass = device.create_render_pass(&create_info, None)?;
+
+        Ok(Self {
+            render_pass,
+            color_format,
+            depth_format: Some(depth_format),
+        })
+    }
+
+    /// # Safety
+    ///
+    /// このレンダーパスを使うフレームバッファやコマンドをGPUが使用中でないこと。
+    pub unsafe fn destroy(&self, device: &Device) {
+        device.destroy_render_pass(self.render_pass, None);
+    }
+}
+
+/// フレームバッファとその解像度
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framebuffer {
+    pub framebuffer: vk::Framebuffer,
+    pub extent: vk::Extent2D,
+}
+
+impl Framebuffer {
+    /// # Safety
+    ///
+    /// `attachments`は`render_pass`のアタッチメントと互換性のあるイメージビューであること。
+    pub unsafe fn new(
+        device: &Device,
+        render_pass: &RenderPass,
+        attachments: &[vk::ImageView],
+        extent: vk::Extent2D,
+    ) -> Result<Self> {
+        let create_info = *vk::FramebufferCreateInfo::builder()
+            .render_pass(render_pass.render_pass)
+            .attachments(attachments)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+        let framebuffer = device.create_framebuffer(&create_info, None)?;
+        Ok(Self {
+            framebuffer,
+            extent,
+        })
+    }
+
+    /// # Safety
+    ///
+    /// このフレームバッファを使うコマンドをGPUが使用中でないこと。
+    pub unsafe fn destroy(&self, device: &Device) {
+        device.destroy_framebuffer(self.framebuffer, None);
+    }
+}
+
+/// スワップチェインイメージごとに、共通の深度バッファと組み合わせたフレームバッファを作る
+///
+/// `msaa_image_view`があれば、それに描画してスワップチェインイメージへ解決する。
+pub(crate) unsafe fn create_swapchain_framebuffers(
+    device: &Device,
+    render_pass: &RenderPass,
+    present_image_views: &[vk::ImageView],
+    depth_image_view: vk::ImageView,
+    msaa_image_view: Option<vk::ImageView>,
+    extent: vk::Extent2D,
+) -> Result<Vec<Framebuffer>> {
+    let mut framebuffers = Vec::with_capacity(present_image_views.len());
+    for &image_view in present_image_views {
+        let attachments = match msaa_image_view {
+            Some(msaa_image_view) => vec![msaa_image_view, depth_image_view, image_view],
+            None => vec![image_view, depth_image_view],
+        };
+        match Framebuffer::new(device, render_pass, &attachments, extent) {
+            Ok(framebuffer) => framebuffers.push(framebuffer),
+            Err(err) => {
+                for framebuffer in framebuffers.iter() {
+                    framebuffer.destroy(device);
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(framebuffers)
+}
+
+impl Renderer {
+    /// 取得中のスワップチェインイメージに対してフォワードパスを開始する
+    ///
+    /// ビューポートとシザーはスワップチェイン全体に設定する。
+    pub fn begin_forward_pass(&self, command_buffer: vk::CommandBuffer, clear_color: [f32; 4]) {
+        let framebuffer = &self.framebuffers[self.present_index as usize];
+        let clear_values = [
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: clear_color,
+                },
+            },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            },
+        ];
+        let render_area = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: framebuffer.extent,
+        };
+        let begin_info = *vk::RenderPassBeginInfo::builder()
+            .render_pass(self.forward_pass.render_pass)
+            .framebuffer(framebuffer.framebuffer)
+            .render_area(render_area)
+            .clear_values(&clear_values);
+        unsafe {
+            self.device.cmd_begin_render_pass(
+                command_buffer,
+                &begin_info,
+                vk::SubpassContents::INLINE,
+            );
+            self.set_viewport_and_scissor(command_buffer, render_area);
+        }
+    }
+
+    pub(crate) unsafe fn set_viewport_and_scissor(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        render_area: vk::Rect2D,
+    ) {
+        let viewport = vk::Viewport {
+            x: render_area.offset.x as f32,
+            y: render_area.offset.y as f32,
+            width: render_area.extent.width as f32,
+            height: render_area.extent.height as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        };
+        self.device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+        self.device
+            .cmd_set_scissor(command_buffer, 0, &[render_area]);
+    }
+
+    pub fn end_forward_pass(&self, command_buffer: vk::CommandBuffer) {
+        unsafe { self.device.cmd_end_render_pass(command_buffer) };
+    }
+}
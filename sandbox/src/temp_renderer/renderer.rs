@@ -1,14 +1,81 @@
+use super::ambient_occlusion::AmbientOcclusion;
+use super::anti_aliasing::AntiAliasing;
+use super::auto_exposure::AutoExposure;
+use super::budget::BudgetTracker;
+use super::buffer::Buffer;
+use super::camera::{Mat4, MAT4_IDENTITY};
+use super::camera_effects::CameraEffects;
+use super::clustered::ClusteredLighting;
+use super::color_grading::ColorGrading;
+use super::color_vision::ColorVisionFilter;
+use super::conditional_rendering::{query_conditional_rendering_support, ConditionalRendering};
+use super::culling::CullingStats;
+use super::deferred::{DeferredLighting, RenderPath};
+use super::descriptor::{DescriptorAllocator, DescriptorLayoutCache};
+use super::displacement::DisplacementUniforms;
+use super::display::SurfaceSource;
+use super::dynamic_rendering::{
+    query_dynamic_rendering_support, DynamicRendering, DynamicRenderingSupport,
+};
+use super::environment::{Environment, EnvironmentShaders};
+use super::error::{RendererError, Result};
+use super::gpu_driven::{query_draw_indirect_count_support, GpuDriven};
+use super::gpu_primitives::GpuPrimitives;
+use super::handle::Pool;
+use super::hdr::Hdr;
+use super::hdr_output::{DisplayLuminance, LuminanceProbe};
+use super::hi_z::HiZ;
+use super::hot_reload::ShaderHotReload;
+use super::ibl::{IblImage, IblShaders};
+use super::image_processing::ImageProcessing;
+use super::instancing::InstanceBuffer;
+use super::lens_flare::LensFlare;
+use super::lighting::{LightBuffers, LightInstance};
+use super::material::{DefaultMaterialTextures, Material};
+use super::memory::{Allocation, MemoryAllocator, MemoryStats};
+use super::mesh::Mesh;
+use super::msaa::{choose_sample_count, create_msaa_color_target, MsaaColorTarget};
+use super::oit::Oit;
+use super::outline::Outline;
+use super::particles::Particles;
+use super::photometry::IesProfiles;
+use super::point_shadow::{PointShadowMap, PointShadowResources};
+use super::post_process::{Bloom, PostProcessSettings};
+use super::readback::{Readback, ReadbackRing};
+use super::render_pass::{create_swapchain_framebuffers, Framebuffer, RenderPass};
+use super::screen_space_reflections::ScreenSpaceReflections;
+use super::shader::load_shader_module;
+use super::shadow::ShadowMap;
+use super::skinning::Skinning;
+use super::texture::{create_image, Texture};
+use super::texture_feedback::TextureFeedback;
+use super::texture_format::TextureFormatSupport;
+use super::view_settings::ViewSettings;
+use super::{
+    DeletionQueue, DisplaySelection, FrameContext, GraphicsPipeline, PipelineBuilder,
+    RendererBuilder, RendererConfig, ShaderId, ShaderModule, ShaderSource,
+};
 use ash::extensions::{
     ext::DebugUtils,
-    khr::{Surface, Swapchain},
+    khr::{self, Surface, Swapchain},
 };
 use ash::vk::PhysicalDevice;
 use ash::{vk, Device, Entry, Instance};
+use gpu_alloc_types::DeviceProperties;
 use raw_window_handle::HasRawWindowHandle;
 use std::borrow::Cow;
-use std::ffi::CStr;
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
 
+// ウィンドウシステムがサーフェスのサイズを決めない場合に使う初期解像度
+const DEFAULT_SURFACE_RESOLUTION: vk::Extent2D = vk::Extent2D {
+    width: 1920,
+    height: 1080,
+};
+
+const ENGINE_NAME: &CStr = c"tempura-renderer";
+
 unsafe extern "system" fn vulkan_debug_callback(
     message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
     message_type: vk::DebugUtilsMessageTypeFlagsEXT,
@@ -16,7 +83,7 @@ unsafe extern "system" fn vulkan_debug_callback(
     _user_data: *mut std::os::raw::c_void,
 ) -> vk::Bool32 {
     let callback_data = *p_callback_data;
-    let message_id_number: i32 = callback_data.message_id_number as i32;
+    let message_id_number: i32 = callback_data.message_id_number;
 
     let message_id_name = if callback_data.p_message_id_name.is_null() {
         Cow::from("")
@@ -50,117 +117,1031 @@ pub struct Renderer {
     pub swapchain_loader: Swapchain,
     pub pdevice: PhysicalDevice,
     pub device: Device,
+    pub queue_family_index: u32,
+    pub present_queue: vk::Queue,
     pub debug_callback: vk::DebugUtilsMessengerEXT,
     pub surface: vk::SurfaceKHR,
+    pub surface_format: vk::SurfaceFormatKHR,
+    pub surface_resolution: vk::Extent2D,
+    pub swapchain: vk::SwapchainKHR,
     pub command_pool: vk::CommandPool,
+    pub setup_command_buffer: vk::CommandBuffer,
+    pub present_images: Vec<vk::Image>,
     pub present_image_views: Vec<vk::ImageView>,
     pub depth_image: vk::Image,
     pub depth_image_view: vk::ImageView,
-    pub depth_image_memory: vk::DeviceMemory,
+    pub depth_image_allocation: Allocation,
+    /// スワップチェインへの描画のサンプル数。カラーバッファと深度バッファはこのサンプル数で作る
+    pub msaa_samples: vk::SampleCountFlags,
+    /// `msaa_samples`が1より大きい場合のみ`Some`。描画後にスワップチェインイメージへ解決する
+    pub msaa_color_target: Option<MsaaColorTarget>,
+    /// スワップチェインイメージと深度バッファに描画するフォワードパス
+    pub forward_pass: RenderPass,
+    /// `present_images`と同じ順序のフォワードパス用フレームバッファ
+    pub framebuffers: Vec<Framebuffer>,
+    /// 動的レンダリングが有効な場合のみ`Some`
+    pub dynamic_rendering: Option<DynamicRendering>,
+    /// 条件付きレンダリングが有効な場合のみ`Some`
+    pub conditional_rendering: Option<ConditionalRendering>,
+    /// 描画の数をGPUが決める間接描画が有効な場合のみ`Some`
+    pub draw_indirect_count: Option<khr::DrawIndirectCount>,
     pub setup_commands_reuse_fence: vk::Fence,
-    pub draw_commands_reuse_fence: vk::Fence,
-    pub rendering_complete_semaphore: vk::Semaphore,
-    pub present_complete_semaphore: vk::Semaphore,
+    pub frames: Vec<FrameContext>,
+    /// 記録中もしくは次に記録する`frames`のインデックス
+    pub current_frame: usize,
+    /// `begin_frame`で取得したスワップチェインイメージのインデックス
+    pub present_index: u32,
+    /// `begin_frame`から`end_frame`までの間`true`
+    pub recording: bool,
+    pub depth_format: vk::Format,
+    /// 提出済みのフレーム数
+    pub frame_count: u64,
+    pub deletion_queue: DeletionQueue,
+    pub allocator: MemoryAllocator,
+    pub shader_modules: Pool<ShaderModule>,
+    pub buffers: Pool<Buffer>,
+    pub meshes: Pool<Mesh>,
+    pub textures: Pool<Texture>,
+    /// 解放されていない読み戻し
+    pub readbacks: Pool<Readback>,
+    /// 最初の読み戻しで作成する
+    pub readback_ring: Option<ReadbackRing>,
+    pub texture_format_support: TextureFormatSupport,
+    pub materials: Pool<Material>,
+    /// 最初の`create_material`で作成する
+    pub(crate) default_material_textures: Option<DefaultMaterialTextures>,
+    pub lights: Pool<LightInstance>,
+    /// 最初の`light_descriptor_set`で作成する
+    pub(crate) light_buffers: Option<LightBuffers>,
+    /// `enable_shadows`で作成する
+    pub shadow_map: Option<ShadowMap>,
+    /// `enable_point_shadow`の順。番号がシェーダーのポイントシャドウの番号になる
+    pub point_shadows: Vec<PointShadowMap>,
+    pub(crate) point_shadow_resources: Option<PointShadowResources>,
+    pub environment_shaders: Option<EnvironmentShaders>,
+    /// `set_environment`で読み込んだ環境マップ
+    pub environment: Option<Environment>,
+    /// `enable_hdr`で作成する
+    pub hdr: Option<Hdr>,
+    /// `enable_auto_exposure`で作成する
+    pub auto_exposure: Option<AutoExposure>,
+    /// `enable_image_processing`で作成する
+    pub image_processing: Option<ImageProcessing>,
+    /// `apply_post_processing`で使う設定
+    pub post_process: PostProcessSettings,
+    /// `set_view_settings`で設定する、ビューごとの上書き
+    pub view_settings: ViewSettings,
+    /// `enable_bloom`で作成する
+    pub bloom: Option<Bloom>,
+    /// `enable_anti_aliasing`で作成する
+    pub anti_aliasing: Option<AntiAliasing>,
+    /// `set_view_projection`で設定するジッターなしのビュー射影行列
+    pub view_projection: Mat4,
+    /// `enable_texture_feedback`で作成する
+    pub texture_feedback: Option<TextureFeedback>,
+    /// `enable_ambient_occlusion`で作成する
+    pub ambient_occlusion: Option<AmbientOcclusion>,
+    /// `update_vertex_displacement`で作成する
+    pub vertex_displacement: Option<DisplacementUniforms>,
+    /// `enable_screen_space_reflections`で作成する
+    pub screen_space_reflections: Option<ScreenSpaceReflections>,
+    /// `enable_outline`で作成する
+    pub outline: Option<Outline>,
+    /// `enable_camera_effects`で作成する
+    pub camera_effects: Option<CameraEffects>,
+    /// `enable_hdr`と`set_color_grading_lut`で作成する
+    pub color_grading: Option<ColorGrading>,
+    /// `set_color_vision_filter`で設定する
+    pub color_vision_filter: Option<ColorVisionFilter>,
+    /// `RendererConfig::render_path`のうち、デバイスと設定で使えるもの
+    pub render_path: RenderPath,
+    /// `RendererConfig::depth_prepass`と`set_depth_prepass`で切り替える
+    pub depth_prepass: bool,
+    /// `enable_deferred_lighting`で作成する
+    pub deferred_lighting: Option<DeferredLighting>,
+    /// `enable_oit`で作成する
+    pub oit: Option<Oit>,
+    /// `cull_scene`で今のフレームに足した統計情報
+    pub culling_stats: CullingStats,
+    /// `culling_stats`を足したフレームの`frame_count`
+    pub culling_stats_frame: u64,
+    /// `enable_clustered_lighting`で作成する
+    pub clustered_lighting: Option<ClusteredLighting>,
+    /// `enable_skinning`で作成する
+    pub skinning: Option<Skinning>,
+    /// `enable_gpu_primitives`で作成する
+    pub gpu_primitives: Option<GpuPrimitives>,
+    /// `enable_gpu_driven`で作成する
+    pub gpu_driven: Option<GpuDriven>,
+    /// `enable_hi_z`で作成する
+    pub hi_z: Option<HiZ>,
+    /// `set_ies_profiles`で作成する
+    pub ies_profiles: Option<IesProfiles>,
+    /// `enable_lens_flare`で作成する
+    pub lens_flare: Option<LensFlare>,
+    /// `enable_particles`で作成する
+    pub particles: Option<Particles>,
+    /// `enable_hdr_calibration`で作成するキャリブレーション画面のパイプライン
+    pub hdr_calibration: Option<GraphicsPipeline>,
+    /// `enable_luminance_probe`で作成する
+    pub luminance_probe: Option<LuminanceProbe>,
+    /// `set_display_luminance`で設定する
+    pub display_luminance: Option<DisplayLuminance>,
+    /// フレームコンテキストごとの`draw_mesh_instanced`のインスタンスデータ
+    pub instance_buffers: Vec<InstanceBuffer>,
+    pub ibl_shaders: Option<IblShaders>,
+    /// 環境マップによらないので、最初に計算したものを使い続ける
+    pub brdf_lut: Option<IblImage>,
+    pub descriptor_layout_cache: DescriptorLayoutCache,
+    /// レンダラーの破棄まで使うデスクリプタセット用
+    pub descriptor_allocator: DescriptorAllocator,
+    pub shader_hot_reload: ShaderHotReload,
+    pub budget_tracker: BudgetTracker,
+    /// レンダーグラフで実行しないパスの名前
+    pub disabled_passes: HashSet<String>,
+    /// インスタンスとデバイス、サーフェスがアプリケーションのもので、破棄しない
+    pub(crate) external_context: bool,
+    pub config: RendererConfig,
 }
 
 impl Renderer {
-    pub fn new(window_handle: &dyn HasRawWindowHandle) -> Self {
+    /// デフォルト設定でレンダラーを作成する。設定を変える場合は[`RendererBuilder`]を使う
+    pub fn new(window_handle: &dyn HasRawWindowHandle) -> Result<Self> {
+        RendererBuilder::new().build(window_handle)
+    }
+
+    pub fn with_config(
+        window_handle: &dyn HasRawWindowHandle,
+        config: RendererConfig,
+    ) -> Result<Self> {
+        Self::with_surface_source(SurfaceSource::Window(window_handle), config)
+    }
+
+    /// ウィンドウシステムを使わず、VK_KHR_displayでディスプレイに直接出力するレンダラーを作成する
+    ///
+    /// 出力先は[`enumerate_displays`](super::enumerate_displays)で調べる。
+    pub fn with_display(selection: DisplaySelection, config: RendererConfig) -> Result<Self> {
+        Self::with_surface_source(SurfaceSource::Display(selection), config)
+    }
+
+    fn with_surface_source(surface_source: SurfaceSource, config: RendererConfig) -> Result<Self> {
         unsafe {
             let entry = Entry::linked();
-            let instance = create_instance(&entry, window_handle);
+            let instance = create_instance(&entry, &surface_source, &config)?;
+            // デバイスまでの作成に失敗したら、作成したものを逆の順序で破棄する
+            let mut guard = CreationGuard::new(());
+            let instance_to_destroy = instance.clone();
+            guard.push(move |_| instance_to_destroy.destroy_instance(None));
             let debug_utils_loader = DebugUtils::new(&entry, &instance);
-            let debug_callback = create_debug_call_back(&debug_utils_loader);
-            let surface = create_surface(&entry, &instance, window_handle);
+            let debug_callback = if config.enable_debug_utils {
+                let debug_callback = create_debug_call_back(&debug_utils_loader)?;
+                guard.push(move |_| {
+                    debug_utils_loader.destroy_debug_utils_messenger(debug_callback, None)
+                });
+                debug_callback
+            } else {
+                vk::DebugUtilsMessengerEXT::null()
+            };
+            let surface = surface_source.create_surface(&entry, &instance)?;
             let surface_loader = Surface::new(&entry, &instance);
+            let surface_to_destroy = surface_loader.clone();
+            guard.push(move |_| surface_to_destroy.destroy_surface(surface, None));
             let (pdevice, queue_family_index) =
-                get_physical_device(&entry, &instance, &surface, &surface_loader);
-            let device = create_device(&instance, &pdevice, queue_family_index);
+                get_physical_device(&instance, &surface, &surface_loader, &config)?;
+            let device_properties =
+                gpu_alloc_ash::device_properties(&instance, config.api_version, pdevice)?;
+            let dynamic_rendering_support = if config.dynamic_rendering {
+                query_dynamic_rendering_support(&instance, pdevice, &config)?
+            } else {
+                None
+            };
+            let conditional_rendering_support = config.conditional_rendering
+                && query_conditional_rendering_support(&instance, pdevice, &config)?;
+            let draw_indirect_count_support = config.draw_indirect_count
+                && query_draw_indirect_count_support(&instance, pdevice)?;
+            let texture_format_support = TextureFormatSupport::query(&instance, pdevice);
+            let device = create_device(
+                &instance,
+                &pdevice,
+                queue_family_index,
+                &config,
+                dynamic_rendering_support,
+                conditional_rendering_support,
+                draw_indirect_count_support,
+                &texture_format_support,
+            )?;
             let present_queue = device.get_device_queue(queue_family_index, 0);
+            // ここから先の破棄は`from_device_context`が受け持つ
+            guard.finish();
+            Self::from_device_context(
+                DeviceContext {
+                    entry,
+                    instance,
+                    debug_callback,
+                    surface,
+                    pdevice,
+                    device,
+                    queue_family_index,
+                    present_queue,
+                    dynamic_rendering_support,
+                    conditional_rendering_support,
+                    draw_indirect_count_support,
+                    texture_format_support,
+                    device_properties,
+                    external: false,
+                },
+                config,
+            )
+        }
+    }
+
+    /// デバイスまでのコンテキストから、スワップチェインとフレームのリソースを作成する
+    ///
+    /// 失敗したら、作成したリソースとデバッグメッセンジャーを破棄する。`context.external`でなければ
+    /// デバイス、サーフェス、インスタンスも破棄する。
+    pub(crate) unsafe fn from_device_context(
+        context: DeviceContext,
+        config: RendererConfig,
+    ) -> Result<Self> {
+        let DeviceContext {
+            entry,
+            instance,
+            debug_callback,
+            surface,
+            pdevice,
+            device,
+            queue_family_index,
+            present_queue,
+            dynamic_rendering_support,
+            conditional_rendering_support,
+            draw_indirect_count_support,
+            texture_format_support,
+            device_properties,
+            external,
+        } = context;
+        let debug_utils_loader = DebugUtils::new(&entry, &instance);
+        let surface_loader = Surface::new(&entry, &instance);
+
+        // 失敗したら作成と逆の順序で破棄する。状態はアロケーターとフレームコンテキスト
+        let allocator = MemoryAllocator::new(&device_properties, config.api_version);
+        let mut guard = CreationGuard::new((allocator, Vec::<FrameContext>::new()));
+        {
+            let (instance, device) = (instance.clone(), device.clone());
+            let surface_loader = surface_loader.clone();
+            let debug_utils_loader = debug_utils_loader.clone();
+            let device_to_destroy = device.clone();
+            guard.push(move |_| {
+                if debug_callback != vk::DebugUtilsMessengerEXT::null() {
+                    debug_utils_loader.destroy_debug_utils_messenger(debug_callback, None);
+                }
+                if !external {
+                    device.destroy_device(None);
+                    surface_loader.destroy_surface(surface, None);
+                    instance.destroy_instance(None);
+                }
+            });
+            guard.push(move |(allocator, frames)| {
+                for frame in frames.iter_mut() {
+                    frame.destroy(&device_to_destroy);
+                }
+                allocator.destroy(&device_to_destroy);
+            });
+        }
+        let dynamic_rendering = dynamic_rendering_support.map(|support| match support {
+            DynamicRenderingSupport::Core => DynamicRendering::Core,
+            DynamicRenderingSupport::Extension => {
+                DynamicRendering::Extension(khr::DynamicRendering::new(&instance, &device))
+            }
+        });
+        let conditional_rendering =
+            conditional_rendering_support.then(|| ConditionalRendering::new(&instance, &device));
+        let draw_indirect_count =
+            draw_indirect_count_support.then(|| khr::DrawIndirectCount::new(&instance, &device));
+
+        let surface_format = choose_surface_format(&pdevice, &surface_loader, &surface, &config)?;
+        let depth_format = choose_depth_format(&instance, &pdevice, config.depth_format)?;
+        let swapchain_loader = Swapchain::new(&instance, &device);
+        let (swapchain, surface_resolution) = create_swapchain(
+            &pdevice,
+            &surface_loader,
+            &surface,
+            &surface_format,
+            &swapchain_loader,
+            vk::SwapchainKHR::null(),
+            DEFAULT_SURFACE_RESOLUTION,
+            config.preferred_present_mode,
+        )?;
+        let swapchain_to_destroy = swapchain_loader.clone();
+        guard.push(move |_| swapchain_to_destroy.destroy_swapchain(swapchain, None));
+
+        let command_pool = create_command_pool(&device, queue_family_index)?;
+        let device_to_destroy = device.clone();
+        guard.push(move |_| device_to_destroy.destroy_command_pool(command_pool, None));
+        let setup_command_buffer = create_command_buffers(&device, &command_pool, 1)?[0];
+
+        let present_images = swapchain_loader.get_swapchain_images(swapchain)?;
+        let present_image_views =
+            create_present_image_views(&device, &present_images, &surface_format)?;
+        let (device_to_destroy, views) = (device.clone(), present_image_views.clone());
+        guard.push(move |_| {
+            for view in views {
+                device_to_destroy.destroy_image_view(view, None);
+            }
+        });
+        let msaa_samples = choose_sample_count(&instance, pdevice, config.msaa_samples);
+        // G-bufferは動的レンダリングで描き、マルチサンプルには対応しない
+        let render_path =
+            if dynamic_rendering.is_some() && msaa_samples == vk::SampleCountFlags::TYPE_1 {
+                config.render_path
+            } else {
+                RenderPath::Forward
+            };
+        let depth_prepass = config.depth_prepass && dynamic_rendering.is_some();
+        let (depth_image, depth_image_allocation) = create_depth_image(
+            &device,
+            &mut guard.state().0,
+            &surface_resolution,
+            depth_format,
+            msaa_samples,
+        )?;
+        let device_to_destroy = device.clone();
+        guard.push(move |(allocator, _)| {
+            device_to_destroy.destroy_image(depth_image, None);
+            allocator.free(&device_to_destroy, depth_image_allocation);
+        });
+        let msaa_color_target = create_msaa_color_target(
+            &device,
+            &mut guard.state().0,
+            surface_resolution,
+            surface_format.format,
+            msaa_samples,
+        )?;
+        if let Some(target) = msaa_color_target {
+            let device_to_destroy = device.clone();
+            guard.push(move |(allocator, _)| target.destroy(&device_to_destroy, allocator));
+        }
+
+        let fence_create_info =
+            *vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+
+        let setup_commands_reuse_fence = device.create_fence(&fence_create_info, None)?;
+        let device_to_destroy = device.clone();
+        guard.push(move |_| device_to_destroy.destroy_fence(setup_commands_reuse_fence, None));
+
+        let depth_image_view = create_depth_image_view(&device, &depth_image, depth_format)?;
+        let device_to_destroy = device.clone();
+        guard.push(move |_| device_to_destroy.destroy_image_view(depth_image_view, None));
+        let forward_pass =
+            RenderPass::forward(&device, surface_format.format, depth_format, msaa_samples)?;
+        let device_to_destroy = device.clone();
+        guard.push(move |_| forward_pass.destroy(&device_to_destroy));
+        let framebuffers = create_swapchain_framebuffers(
+            &device,
+            &forward_pass,
+            &present_image_views,
+            depth_image_view,
+            msaa_color_target.map(|target| target.view),
+            surface_resolution,
+        )?;
+        let (device_to_destroy, framebuffers_to_destroy) = (device.clone(), framebuffers.clone());
+        guard.push(move |_| {
+            for framebuffer in framebuffers_to_destroy {
+                framebuffer.destroy(&device_to_destroy);
+            }
+        });
+
+        for _ in 0..config.frames_in_flight_count() {
+            let frame = FrameContext::new(&device, command_pool)?;
+            guard.state().1.push(frame);
+        }
+        let budget_tracker = BudgetTracker::new(
+            &instance,
+            pdevice,
+            queue_family_index,
+            &device,
+            config.frames_in_flight_count(),
+        )?;
+        // 以降は失敗しないので、破棄はDropに任せる
+        let (allocator, frames) = guard.finish();
+        let instance_buffers = (0..frames.len())
+            .map(|_| InstanceBuffer::default())
+            .collect();
+
+        Ok(Self {
+            entry,
+            instance,
+            debug_utils_loader,
+            surface_loader,
+            swapchain_loader,
+            pdevice,
+            device,
+            queue_family_index,
+            present_queue,
+            debug_callback,
+            surface,
+            surface_format,
+            surface_resolution,
+            swapchain,
+            command_pool,
+            setup_command_buffer,
+            present_images,
+            present_image_views,
+            depth_image,
+            depth_image_view,
+            depth_image_allocation,
+            msaa_samples,
+            msaa_color_target,
+            forward_pass,
+            framebuffers,
+            dynamic_rendering,
+            conditional_rendering,
+            draw_indirect_count,
+            setup_commands_reuse_fence,
+            frames,
+            current_frame: 0,
+            present_index: 0,
+            recording: false,
+            depth_format,
+            frame_count: 0,
+            deletion_queue: DeletionQueue::new(),
+            allocator,
+            shader_modules: Pool::new(),
+            buffers: Pool::new(),
+            meshes: Pool::new(),
+            textures: Pool::new(),
+            readbacks: Pool::new(),
+            readback_ring: None,
+            texture_format_support,
+            materials: Pool::new(),
+            default_material_textures: None,
+            lights: Pool::new(),
+            light_buffers: None,
+            shadow_map: None,
+            point_shadows: Vec::new(),
+            point_shadow_resources: None,
+            environment_shaders: None,
+            environment: None,
+            hdr: None,
+            auto_exposure: None,
+            image_processing: None,
+            post_process: PostProcessSettings::default(),
+            view_settings: ViewSettings::default(),
+            bloom: None,
+            anti_aliasing: None,
+            view_projection: MAT4_IDENTITY,
+            texture_feedback: None,
+            ambient_occlusion: None,
+            vertex_displacement: None,
+            screen_space_reflections: None,
+            outline: None,
+            camera_effects: None,
+            color_grading: None,
+            color_vision_filter: None,
+            render_path,
+            depth_prepass,
+            deferred_lighting: None,
+            oit: None,
+            culling_stats: CullingStats::default(),
+            culling_stats_frame: 0,
+            clustered_lighting: None,
+            gpu_primitives: None,
+            gpu_driven: None,
+            hi_z: None,
+            ies_profiles: None,
+            lens_flare: None,
+            particles: None,
+            hdr_calibration: None,
+            luminance_probe: None,
+            display_luminance: None,
+            instance_buffers,
+            skinning: None,
+            ibl_shaders: None,
+            brdf_lut: None,
+            descriptor_layout_cache: DescriptorLayoutCache::new(),
+            descriptor_allocator: DescriptorAllocator::default(),
+            shader_hot_reload: ShaderHotReload::default(),
+            budget_tracker,
+            disabled_passes: HashSet::new(),
+            external_context: external,
+            config,
+        })
+    }
+
+    /// 現在GPUが使用中かもしれないリソースの破棄を、使用中のフレームが完了するまで遅らせる
+    pub fn destroy_deferred<F: FnOnce(&Device, &mut MemoryAllocator) + 'static>(&mut self, f: F) {
+        // 記録中のフレームが使っている可能性があるので、次に提出されるフレームの完了を待つ
+        self.deletion_queue.push(self.frame_count + 1, f);
+    }
+
+    /// デバイスメモリの使用状況
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.allocator.stats()
+    }
+
+    /// セットアップ用コマンドバッファに記録して提出し、実行の完了を待つ
+    pub(crate) fn submit_setup_commands<F: FnOnce(&Device, vk::CommandBuffer)>(
+        &self,
+        f: F,
+    ) -> Result<()> {
+        record_submit_commandbuffer(
+            &self.device,
+            self.setup_command_buffer,
+            self.setup_commands_reuse_fence,
+            self.present_queue,
+            &[],
+            &[],
+            &[],
+            f,
+        )?;
+        unsafe {
+            self.device
+                .wait_for_fences(&[self.setup_commands_reuse_fence], true, u64::MAX)?
+        };
+        Ok(())
+    }
+
+    /// SPIR-Vファイルまたはバイト列からシェーダーモジュールを作成する
+    ///
+    /// 作成したモジュールはレンダラーが管理し、`destroy_shader_module`を呼ばなければ
+    /// レンダラーの破棄時にまとめて破棄される。
+    pub fn load_shader_spv<'a, S: Into<ShaderSource<'a>>>(
+        &mut self,
+        source: S,
+    ) -> Result<ShaderId> {
+        let shader_module = unsafe { load_shader_module(&self.device, source.into())? };
+        Ok(self.shader_modules.insert(shader_module))
+    }
+
+    pub fn shader_module(&self, id: ShaderId) -> Result<vk::ShaderModule> {
+        self.shader_modules
+            .get(id)
+            .map(|shader_module| shader_module.module)
+            .ok_or_else(|| {
+                RendererError::Validation(format!("shader {:?} is destroyed or unknown", id))
+            })
+    }
+
+    /// パイプライン作成後はシェーダーモジュールが不要なので、すぐに破棄してよい
+    pub fn destroy_shader_module(&mut self, id: ShaderId) -> Result<()> {
+        let shader_module = self.shader_modules.remove(id).ok_or_else(|| {
+            RendererError::Validation(format!("shader {:?} was already destroyed", id))
+        })?;
+        unsafe {
+            self.device
+                .destroy_shader_module(shader_module.module, None)
+        };
+        Ok(())
+    }
+
+    /// `builder`が参照するシェーダーモジュールが破棄されていないことを確認してからパイプラインを作成する
+    ///
+    /// `render_pass`がフォワードパスかHDRのパスなら、サンプル数は`msaa_samples`に合わせる。
+    pub fn create_graphics_pipeline(
+        &self,
+        builder: &PipelineBuilder,
+        render_pass: vk::RenderPass,
+        subpass: u32,
+    ) -> Result<GraphicsPipeline> {
+        if let Some(module) = builder.shader_modules().find(|&module| {
+            !self
+                .shader_modules
+                .iter()
+                .any(|(_, shader_module)| shader_module.module == module)
+        }) {
+            return Err(RendererError::Validation(format!(
+                "pipeline references shader module {:?} that is destroyed or unknown",
+                module
+            )));
+        }
+        if let Some(samples) = self.render_pass_samples(render_pass) {
+            let builder = builder.clone().samples(samples);
+            return builder.build(&self.device, render_pass, subpass);
+        }
+        builder.build(&self.device, render_pass, subpass)
+    }
+
+    /// レンダラーが作ったレンダーパスなら、そのアタッチメントのサンプル数
+    pub(crate) fn render_pass_samples(
+        &self,
+        render_pass: vk::RenderPass,
+    ) -> Option<vk::SampleCountFlags> {
+        let hdr_pass = self.hdr.as_ref().and_then(|hdr| hdr.render_pass);
+        (render_pass == self.forward_pass.render_pass
+            || hdr_pass.is_some_and(|pass| pass.render_pass == render_pass))
+        .then_some(self.msaa_samples)
+    }
+
+    /// 使用中のフレームが完了してからパイプラインを破棄する
+    pub fn destroy_graphics_pipeline(&mut self, pipeline: GraphicsPipeline) {
+        self.destroy_deferred(move |device, _| unsafe { pipeline.destroy(device) });
+    }
+
+    /// スワップチェインと解像度依存のリソース(イメージビュー、深度バッファ、フレームバッファ)を作り直す
+    pub fn recreate_swapchain(&mut self, width: u32, height: u32) -> Result<()> {
+        // 最小化中などはサイズ0のスワップチェインを作れないのでスキップ
+        if width == 0 || height == 0 {
+            return Ok(());
+        }
+
+        unsafe {
+            self.device.device_wait_idle()?;
+            self.destroy_swapchain_resources();
 
-            let surface_format = surface_loader
-                .get_physical_device_surface_formats(pdevice, surface)
-                .unwrap()[0];
-            let swapchain_loader = Swapchain::new(&instance, &device);
+            let old_swapchain = self.swapchain;
             let (swapchain, surface_resolution) = create_swapchain(
-                &pdevice,
-                &surface_loader,
-                &surface,
-                &surface_format,
-                &swapchain_loader,
-            );
+                &self.pdevice,
+                &self.surface_loader,
+                &self.surface,
+                &self.surface_format,
+                &self.swapchain_loader,
+                old_swapchain,
+                vk::Extent2D { width, height },
+                self.config.preferred_present_mode,
+            )?;
+            self.swapchain_loader.destroy_swapchain(old_swapchain, None);
+            self.swapchain = swapchain;
+            self.surface_resolution = surface_resolution;
 
-            let command_pool = create_command_pool(&device, queue_family_index);
-            let command_buffers = create_command_buffers(&device, &command_pool);
-            let setup_command_buffer = command_buffers[0];
-            let draw_command_buffer = command_buffers[1];
-
-            let present_image_views =
-                create_present_image_views(&device, &swapchain_loader, &swapchain, &surface_format);
-            let device_memory_properties = instance.get_physical_device_memory_properties(pdevice);
-            let (depth_image, depth_image_memory) =
-                create_depth_image(&instance, &pdevice, &device, &surface_resolution);
-
-            let fence_create_info =
-                *vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
-
-            let draw_commands_reuse_fence = device
-                .create_fence(&fence_create_info, None)
-                .expect("Create fence failed.");
-            let setup_commands_reuse_fence = device
-                .create_fence(&fence_create_info, None)
-                .expect("Create fence failed.");
-
-            optimize_depth_image_layout(
-                &device,
-                &setup_command_buffer,
-                &setup_commands_reuse_fence,
-                &present_queue,
-                &depth_image,
-            );
+            self.present_images = self.swapchain_loader.get_swapchain_images(swapchain)?;
+            self.present_image_views = create_present_image_views(
+                &self.device,
+                &self.present_images,
+                &self.surface_format,
+            )?;
+
+            let (depth_image, depth_image_allocation) = create_depth_image(
+                &self.device,
+                &mut self.allocator,
+                &surface_resolution,
+                self.depth_format,
+                self.msaa_samples,
+            )?;
+            self.depth_image = depth_image;
+            self.depth_image_allocation = depth_image_allocation;
+            self.depth_image_view =
+                create_depth_image_view(&self.device, &depth_image, self.depth_format)?;
+            self.msaa_color_target = create_msaa_color_target(
+                &self.device,
+                &mut self.allocator,
+                surface_resolution,
+                self.surface_format.format,
+                self.msaa_samples,
+            )?;
+            self.framebuffers = create_swapchain_framebuffers(
+                &self.device,
+                &self.forward_pass,
+                &self.present_image_views,
+                self.depth_image_view,
+                self.msaa_color_target.map(|target| target.view),
+                surface_resolution,
+            )?;
+            self.recreate_hdr_target()?;
+            self.recreate_ambient_occlusion_targets()?;
+            self.recreate_hi_z_pyramid()?;
+            self.recreate_lens_flare_sets()?;
+            self.recreate_particle_depth_set()?;
+        }
+        Ok(())
+    }
+
+    /// 次のフレームの記録を開始する
+    ///
+    /// 同じフレームコンテキストを使っていたフレームの完了を待ち、スワップチェインイメージを取得して
+    /// 記録を開始したコマンドバッファを返す。スワップチェインがout-of-dateで作り直した場合は
+    /// `None`を返すので、そのフレームの描画はスキップする。
+    pub fn begin_frame(&mut self) -> Result<Option<vk::CommandBuffer>> {
+        if self.config.shader_hot_reload {
+            let failures = self.poll_shader_hot_reload();
+            self.shader_hot_reload.failures.extend(failures);
+        }
+
+        unsafe {
+            let frame = &mut self.frames[self.current_frame];
+            self.device
+                .wait_for_fences(&[frame.in_flight_fence], true, u64::MAX)?;
+            frame.descriptor_allocator.reset(&self.device)?;
+            self.collect_budgets()?;
+            let frame = &self.frames[self.current_frame];
+
+            // このフレームコンテキストを前回使ったフレームまでは完了している
+            let completed_frame = (self.frame_count + 1).saturating_sub(self.frames.len() as u64);
+            self.deletion_queue
+                .flush_completed(&self.device, &mut self.allocator, completed_frame);
+
+            let present_index = match self.swapchain_loader.acquire_next_image(
+                self.swapchain,
+                u64::MAX,
+                frame.image_available_semaphore,
+                vk::Fence::null(),
+            ) {
+                Ok((present_index, _suboptimal)) => present_index,
+                Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                    self.recreate_swapchain(
+                        self.surface_resolution.width,
+                        self.surface_resolution.height,
+                    )?;
+                    return Ok(None);
+                }
+                Err(err) => return Err(err.into()),
+            };
+            self.present_index = present_index;
+
+            // イメージの取得に成功してからリセットしないと、スキップした場合にフェンスが二度と
+            // シグナルされなくなる
+            self.device.reset_fences(&[frame.in_flight_fence])?;
+            let frame = &mut self.frames[self.current_frame];
+            frame.recording_index = 0;
+            frame.swapchain_image_waited = false;
+            let frame = &self.frames[self.current_frame];
+            self.device.reset_command_buffer(
+                frame.command_buffer,
+                vk::CommandBufferResetFlags::RELEASE_RESOURCES,
+            )?;
+            let command_buffer_begin_info = *vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            self.device
+                .begin_command_buffer(frame.command_buffer, &command_buffer_begin_info)?;
+            self.reset_budget_queries(frame.command_buffer);
+            self.recording = true;
 
-            let depth_image_view_info = *vk::ImageViewCreateInfo::builder()
-                .subresource_range(
-                    *vk::ImageSubresourceRange::builder()
-                        .aspect_mask(vk::ImageAspectFlags::DEPTH)
-                        .level_count(1)
-                        .layer_count(1),
-                )
-                .image(depth_image)
-                .format(vk::Format::D16_UNORM)
-                .view_type(vk::ImageViewType::TYPE_2D);
-
-            let depth_image_view = device
-                .create_image_view(&depth_image_view_info, None)
-                .unwrap();
-
-            let semaphore_create_info = vk::SemaphoreCreateInfo::default();
-
-            let present_complete_semaphore = device
-                .create_semaphore(&semaphore_create_info, None)
-                .unwrap();
-            let rendering_complete_semaphore = device
-                .create_semaphore(&semaphore_create_info, None)
-                .unwrap();
-
-            Self {
-                entry,
-                instance,
-                debug_utils_loader,
-                surface_loader,
-                swapchain_loader,
-                pdevice,
-                device,
-                debug_callback,
-                surface,
-                command_pool,
-                present_image_views,
-                depth_image,
-                depth_image_view,
-                depth_image_memory,
-                setup_commands_reuse_fence,
-                draw_commands_reuse_fence,
-                rendering_complete_semaphore,
-                present_complete_semaphore,
+            Ok(Some(frame.command_buffer))
+        }
+    }
+
+    /// `begin_frame`で開始したフレームの記録を終了して提出し、表示する
+    ///
+    /// 記録終了時にスワップチェインイメージは`PRESENT_SRC_KHR`レイアウトになっている必要がある。
+    /// スワップチェインがout-of-date/suboptimalになった場合は自動的に作り直す。
+    pub fn end_frame(&mut self) -> Result<()> {
+        self.recording = false;
+        unsafe {
+            let frame = &mut self.frames[self.current_frame];
+            let command_buffer = frame.recording_command_buffer();
+            self.device.end_command_buffer(command_buffer)?;
+
+            // `split_submission`で取得を待っていなければ、ここで待つ
+            let (mut wait_semaphores, mut wait_mask): (Vec<_>, Vec<_>) =
+                frame.external_waits.drain(..).unzip();
+            if !frame.swapchain_image_waited {
+                wait_semaphores.push(frame.image_available_semaphore);
+                wait_mask.push(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT);
+            }
+            let command_buffers = [command_buffer];
+            let present_wait_semaphores = [frame.render_finished_semaphore];
+            let mut signal_semaphores = present_wait_semaphores.to_vec();
+            signal_semaphores.append(&mut frame.external_signals);
+            let submit_info = vk::SubmitInfo::builder()
+                .command_buffers(&command_buffers)
+                .signal_semaphores(&signal_semaphores)
+                .wait_semaphores(&wait_semaphores)
+                .wait_dst_stage_mask(&wait_mask);
+            // フェンスは提出順で前にある同じフレームの提出の完了も待つ
+            self.device
+                .queue_submit(self.present_queue, &[*submit_info], frame.in_flight_fence)?;
+            self.frame_count += 1;
+            self.current_frame = (self.current_frame + 1) % self.frames.len();
+
+            let swapchains = [self.swapchain];
+            let image_indices = [self.present_index];
+            let present_info = *vk::PresentInfoKHR::builder()
+                .wait_semaphores(&present_wait_semaphores)
+                .swapchains(&swapchains)
+                .image_indices(&image_indices);
+
+            match self
+                .swapchain_loader
+                .queue_present(self.present_queue, &present_info)
+            {
+                Ok(false) => Ok(()),
+                Ok(true) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => self.recreate_swapchain(
+                    self.surface_resolution.width,
+                    self.surface_resolution.height,
+                ),
+                Err(err) => Err(err.into()),
+            }
+        }
+    }
+
+    /// `begin_frame`と`end_frame`の間で`f`を呼んでコマンドを記録する
+    pub fn draw_frame<F: FnOnce(&Device, vk::CommandBuffer, vk::Image)>(
+        &mut self,
+        f: F,
+    ) -> Result<()> {
+        if let Some(command_buffer) = self.begin_frame()? {
+            let present_image = self.present_images[self.present_index as usize];
+            f(&self.device, command_buffer, present_image);
+            self.end_frame()?;
+        }
+        Ok(())
+    }
+
+    unsafe fn destroy_swapchain_resources(&mut self) {
+        for framebuffer in self.framebuffers.drain(..) {
+            framebuffer.destroy(&self.device);
+        }
+        self.device.destroy_image_view(self.depth_image_view, None);
+        self.device.destroy_image(self.depth_image, None);
+        self.allocator
+            .free(&self.device, self.depth_image_allocation);
+        if let Some(target) = self.msaa_color_target.take() {
+            target.destroy(&self.device, &mut self.allocator);
+        }
+        for &image_view in self.present_image_views.iter() {
+            self.device.destroy_image_view(image_view, None);
+        }
+        self.present_image_views.clear();
+    }
+}
+
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        unsafe {
+            // Dropではエラーを返せないので、待機に失敗してもそのまま破棄を続ける
+            let _ = self.device.device_wait_idle();
+            self.deletion_queue
+                .flush_all(&self.device, &mut self.allocator);
+
+            // 作成と逆の順序で破棄する
+            if let Some(outline) = self.outline.take() {
+                outline.destroy(&self.device, &mut self.allocator);
+            }
+            if let Some(particles) = self.particles.take() {
+                particles.destroy(&self.device);
+            }
+            if let Some(probe) = self.luminance_probe.take() {
+                probe.destroy(&self.device);
+            }
+            if let Some(pipeline) = self.hdr_calibration.take() {
+                pipeline.destroy(&self.device);
+            }
+            if let Some(lens_flare) = self.lens_flare.take() {
+                lens_flare.destroy(&self.device);
+            }
+            if let Some(hi_z) = self.hi_z.take() {
+                hi_z.destroy(&self.device, &mut self.allocator);
+            }
+            if let Some(gpu_driven) = self.gpu_driven.take() {
+                gpu_driven.destroy(&self.device);
+            }
+            if let Some(skinning) = self.skinning.take() {
+                skinning.destroy(&self.device);
+            }
+            if let Some(oit) = self.oit.take() {
+                oit.destroy(&self.device, &mut self.allocator);
+            }
+            if let Some(gpu_primitives) = self.gpu_primitives.take() {
+                gpu_primitives.destroy(&self.device);
+            }
+            if let Some(clustered_lighting) = self.clustered_lighting.take() {
+                clustered_lighting.destroy(&self.device);
+            }
+            if let Some(deferred_lighting) = self.deferred_lighting.take() {
+                deferred_lighting.destroy(&self.device, &mut self.allocator);
+            }
+            if let Some(color_grading) = self.color_grading.take() {
+                color_grading.destroy(&self.device);
+            }
+            if let Some(camera_effects) = self.camera_effects.take() {
+                camera_effects.destroy(&self.device, &mut self.allocator);
+            }
+            if let Some(reflections) = self.screen_space_reflections.take() {
+                reflections.destroy(&self.device, &mut self.allocator);
+            }
+            if let Some(ambient_occlusion) = self.ambient_occlusion.take() {
+                ambient_occlusion.destroy(&self.device, &mut self.allocator);
+            }
+            if let Some(anti_aliasing) = self.anti_aliasing.take() {
+                anti_aliasing.destroy(&self.device, &mut self.allocator);
+            }
+            if let Some(image_processing) = self.image_processing.take() {
+                image_processing.destroy(&self.device);
+            }
+            if let Some(bloom) = self.bloom.take() {
+                bloom.destroy(&self.device, &mut self.allocator);
+            }
+            if let Some(auto_exposure) = self.auto_exposure.take() {
+                auto_exposure.destroy(&self.device);
+            }
+            if let Some(hdr) = self.hdr.take() {
+                hdr.destroy(&self.device, &mut self.allocator);
+            }
+            if let Some(shadow_map) = self.shadow_map.take() {
+                shadow_map.destroy(&self.device, &mut self.allocator);
+            }
+            for point_shadow in self.point_shadows.drain(..) {
+                point_shadow.destroy(&self.device, &mut self.allocator);
+            }
+            if let Some(resources) = self.point_shadow_resources.take() {
+                resources.destroy(&self.device, &mut self.allocator);
+            }
+            if let Some(environment) = self.environment.take() {
+                environment.destroy(&self.device, &mut self.allocator);
+            }
+            if let Some(brdf_lut) = self.brdf_lut.take() {
+                brdf_lut.destroy(&self.device, &mut self.allocator);
+            }
+            for texture in self.textures.drain() {
+                texture.destroy(&self.device, &mut self.allocator);
+            }
+            for buffer in self.buffers.drain() {
+                buffer.destroy(&self.device, &mut self.allocator);
+            }
+            if let Some(ring) = self.readback_ring.take() {
+                ring.buffer.destroy(&self.device, &mut self.allocator);
+            }
+            for reloadable in self.shader_hot_reload.pipelines.drain() {
+                reloadable.pipeline.destroy(&self.device);
+            }
+            for shader_module in self.shader_modules.drain() {
+                self.device
+                    .destroy_shader_module(shader_module.module, None);
+            }
+            self.descriptor_allocator.destroy(&self.device);
+            self.descriptor_layout_cache.destroy(&self.device);
+            self.budget_tracker.destroy(&self.device);
+            for frame in self.frames.iter_mut() {
+                frame.destroy(&self.device);
+            }
+            self.device
+                .destroy_fence(self.setup_commands_reuse_fence, None);
+            self.destroy_swapchain_resources();
+            self.forward_pass.destroy(&self.device);
+            self.allocator.destroy(&self.device);
+            self.device.destroy_command_pool(self.command_pool, None);
+            self.swapchain_loader
+                .destroy_swapchain(self.swapchain, None);
+            if self.debug_callback != vk::DebugUtilsMessengerEXT::null() {
+                self.debug_utils_loader
+                    .destroy_debug_utils_messenger(self.debug_callback, None);
+            }
+            if !self.external_context {
+                self.device.destroy_device(None);
+                self.surface_loader.destroy_surface(self.surface, None);
+                self.instance.destroy_instance(None);
+            }
+        }
+    }
+}
+
+/// レンダラーが使うデバイスまでのVulkanオブジェクト
+pub(crate) struct DeviceContext {
+    pub(crate) entry: Entry,
+    pub(crate) instance: Instance,
+    pub(crate) debug_callback: vk::DebugUtilsMessengerEXT,
+    pub(crate) surface: vk::SurfaceKHR,
+    pub(crate) pdevice: PhysicalDevice,
+    pub(crate) device: Device,
+    pub(crate) queue_family_index: u32,
+    pub(crate) present_queue: vk::Queue,
+    pub(crate) dynamic_rendering_support: Option<DynamicRenderingSupport>,
+    pub(crate) conditional_rendering_support: bool,
+    pub(crate) draw_indirect_count_support: bool,
+    pub(crate) texture_format_support: TextureFormatSupport,
+    /// アロケーターに渡すメモリの情報
+    pub(crate) device_properties: DeviceProperties<'static>,
+    /// `true`なら`entry`からデバイスまでをレンダラーが破棄しない
+    pub(crate) external: bool,
+}
+
+/// 作成途中でエラーになったときに、それまでに作成したものを逆の順序で破棄する
+///
+/// 作成したものごとに`push`で破棄の処理を登録し、全て作成できたら`finish`で登録を捨てる。
+/// `state`はアロケーターなど、作成中に使い続けて破棄の処理にも渡すもの。
+pub(crate) struct CreationGuard<T> {
+    state: Option<T>,
+    cleanups: Vec<Cleanup<T>>,
+}
+
+type Cleanup<T> = Box<dyn FnOnce(&mut T)>;
+
+impl<T> CreationGuard<T> {
+    pub(crate) fn new(state: T) -> Self {
+        Self {
+            state: Some(state),
+            cleanups: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push<F: FnOnce(&mut T) + 'static>(&mut self, cleanup: F) {
+        self.cleanups.push(Box::new(cleanup));
+    }
+
+    pub(crate) fn state(&mut self) -> &mut T {
+        self.state.as_mut().unwrap()
+    }
+
+    /// 作成が完了したので、破棄の処理を捨てて`state`を返す
+    pub(crate) fn finish(mut self) -> T {
+        self.cleanups.clear();
+        self.state.take().unwrap()
+    }
+}
+
+impl<T> Drop for CreationGuard<T> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.as_mut() {
+            while let Some(cleanup) = self.cleanups.pop() {
+                cleanup(state);
             }
         }
     }
@@ -168,36 +1149,68 @@ impl Renderer {
 
 // 以下、Vulkanオブジェクト作成用関数
 
-unsafe fn create_instance(entry: &Entry, window_handle: &dyn HasRawWindowHandle) -> Instance {
-    let app_info = vk::ApplicationInfo {
-        api_version: vk::make_api_version(0, 1, 0, 0),
-        ..Default::default()
-    };
+unsafe fn create_instance(
+    entry: &Entry,
+    surface_source: &SurfaceSource,
+    config: &RendererConfig,
+) -> Result<Instance> {
+    let app_info = *vk::ApplicationInfo::builder()
+        .application_name(&config.application_name)
+        .application_version(config.application_version)
+        .engine_name(ENGINE_NAME)
+        .api_version(config.api_version);
 
-    let layer_names = [CStr::from_bytes_with_nul_unchecked(
-        b"VK_LAYER_KHRONOS_validation\0",
-    )];
-    let layer_names_raw: Vec<*const c_char> = layer_names
+    let available_layers = entry.enumerate_instance_layer_properties()?;
+    for layer_name in config.enabled_layers.iter() {
+        let found = available_layers
+            .iter()
+            .any(|layer| CStr::from_ptr(layer.layer_name.as_ptr()) == layer_name.as_c_str());
+        if !found {
+            return Err(RendererError::MissingLayer(
+                layer_name.to_string_lossy().into_owned(),
+            ));
+        }
+    }
+    let layer_names_raw: Vec<*const c_char> = config
+        .enabled_layers
         .iter()
         .map(|raw_name| raw_name.as_ptr())
         .collect();
 
-    let mut extension_names = ash_window::enumerate_required_extensions(&window_handle)
-        .unwrap()
-        .to_vec();
-    extension_names.push(DebugUtils::name().as_ptr());
+    let mut extension_names = surface_source.required_instance_extensions()?;
+    for extension_name in config.required_instance_extensions() {
+        if !extension_names
+            .iter()
+            .any(|&name| CStr::from_ptr(name) == extension_name)
+        {
+            extension_names.push(extension_name.as_ptr());
+        }
+    }
+
+    let available_extensions = entry.enumerate_instance_extension_properties(None)?;
+    for &extension_name in extension_names.iter() {
+        let extension_name = CStr::from_ptr(extension_name);
+        let found = available_extensions
+            .iter()
+            .any(|extension| CStr::from_ptr(extension.extension_name.as_ptr()) == extension_name);
+        if !found {
+            return Err(RendererError::MissingExtension(
+                extension_name.to_string_lossy().into_owned(),
+            ));
+        }
+    }
 
     let create_info = *vk::InstanceCreateInfo::builder()
         .application_info(&app_info)
         .enabled_layer_names(&layer_names_raw)
         .enabled_extension_names(&extension_names);
 
-    entry
-        .create_instance(&create_info, None)
-        .expect("Instance creation error")
+    Ok(entry.create_instance(&create_info, None)?)
 }
 
-unsafe fn create_debug_call_back(debug_utils_loader: &DebugUtils) -> vk::DebugUtilsMessengerEXT {
+pub(crate) unsafe fn create_debug_call_back(
+    debug_utils_loader: &DebugUtils,
+) -> Result<vk::DebugUtilsMessengerEXT> {
     let debug_info = *vk::DebugUtilsMessengerCreateInfoEXT::builder()
         .message_severity(
             vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
@@ -210,87 +1223,199 @@ unsafe fn create_debug_call_back(debug_utils_loader: &DebugUtils) -> vk::DebugUt
                 | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
         )
         .pfn_user_callback(Some(vulkan_debug_callback));
-    debug_utils_loader
-        .create_debug_utils_messenger(&debug_info, None)
-        .unwrap()
-}
-
-unsafe fn create_surface(
-    entry: &Entry,
-    instance: &Instance,
-    window_handle: &dyn HasRawWindowHandle,
-) -> vk::SurfaceKHR {
-    ash_window::create_surface(entry, instance, &window_handle, None).unwrap()
+    Ok(debug_utils_loader.create_debug_utils_messenger(&debug_info, None)?)
 }
 
 unsafe fn get_physical_device(
-    entry: &Entry,
     instance: &Instance,
     surface: &vk::SurfaceKHR,
     surface_loader: &Surface,
-) -> (PhysicalDevice, u32) {
-    let pdevices = instance
-        .enumerate_physical_devices()
-        .expect("Physical device error");
-    let (pdevice, queue_family_index) = pdevices
+    config: &RendererConfig,
+) -> Result<(PhysicalDevice, u32)> {
+    let pdevices = instance.enumerate_physical_devices()?;
+    let mut candidates = Vec::with_capacity(pdevices.len());
+    for &pdevice in pdevices.iter() {
+        let extensions = instance
+            .enumerate_device_extension_properties(pdevice)?
+            .iter()
+            .map(|extension| CStr::from_ptr(extension.extension_name.as_ptr()).to_owned())
+            .collect();
+        let queue_families = instance.get_physical_device_queue_family_properties(pdevice);
+        let mut presentable_graphics_queues = Vec::with_capacity(queue_families.len());
+        for (index, info) in queue_families.iter().enumerate() {
+            presentable_graphics_queues.push(
+                info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+                    && surface_loader.get_physical_device_surface_support(
+                        pdevice,
+                        index as u32,
+                        *surface,
+                    )?,
+            );
+        }
+        candidates.push(DeviceCandidate {
+            api_version: instance.get_physical_device_properties(pdevice).api_version,
+            extensions,
+            presentable_graphics_queues,
+        });
+    }
+    let (index, queue_family_index) = choose_physical_device(&candidates, config)?;
+    Ok((pdevices[index], queue_family_index))
+}
+
+/// デバイスの選択に使う物理デバイスの情報
+struct DeviceCandidate {
+    api_version: u32,
+    extensions: Vec<CString>,
+    /// キューファミリーごとに、グラフィックスとサーフェスへの表示ができるか
+    presentable_graphics_queues: Vec<bool>,
+}
+
+/// `config`のAPIバージョンと拡張に対応し、グラフィックスと表示ができるキューファミリーを持つ
+/// 最初の候補の位置と、そのキューファミリー
+fn choose_physical_device(
+    candidates: &[DeviceCandidate],
+    config: &RendererConfig,
+) -> Result<(usize, u32)> {
+    let mut required_extensions = vec![Swapchain::name()];
+    required_extensions.extend(config.device_extensions.iter().map(|name| name.as_c_str()));
+    candidates
         .iter()
-        .find_map(|pdevice| {
-            instance
-                .get_physical_device_queue_family_properties(*pdevice)
+        .enumerate()
+        .filter(|(_, candidate)| {
+            candidate.api_version >= config.api_version
+                && required_extensions
+                    .iter()
+                    .all(|&name| candidate.extensions.iter().any(|e| e.as_c_str() == name))
+        })
+        .find_map(|(index, candidate)| {
+            let queue = candidate
+                .presentable_graphics_queues
                 .iter()
-                .enumerate()
-                .find_map(|(index, info)| {
-                    let supports_graphic_and_surface = info
-                        .queue_flags
-                        .contains(vk::QueueFlags::GRAPHICS)
-                        && surface_loader
-                            .get_physical_device_surface_support(*pdevice, index as u32, *surface)
-                            .unwrap();
-                    if supports_graphic_and_surface {
-                        Some((*pdevice, index))
-                    } else {
-                        None
-                    }
-                })
+                .position(|&supported| supported)?;
+            Some((index, queue as u32))
         })
-        .expect("Couldn't find suitable device.");
-    (pdevice, queue_family_index as u32)
+        .ok_or(RendererError::NoSuitableDevice)
 }
 
+#[allow(clippy::too_many_arguments)]
 unsafe fn create_device(
     instance: &Instance,
     pdevice: &vk::PhysicalDevice,
     queue_family_index: u32,
-) -> Device {
-    let device_extension_names_raw = [Swapchain::name().as_ptr()];
+    config: &RendererConfig,
+    dynamic_rendering: Option<DynamicRenderingSupport>,
+    conditional_rendering: bool,
+    draw_indirect_count: bool,
+    texture_format_support: &TextureFormatSupport,
+) -> Result<Device> {
+    let mut device_extension_names_raw = vec![Swapchain::name().as_ptr()];
+    device_extension_names_raw.extend(config.device_extensions.iter().map(|name| name.as_ptr()));
+    if dynamic_rendering == Some(DynamicRenderingSupport::Extension) {
+        device_extension_names_raw.push(khr::DynamicRendering::name().as_ptr());
+    }
+    if conditional_rendering {
+        device_extension_names_raw.push(ConditionalRendering::name().as_ptr());
+    }
+    if draw_indirect_count {
+        device_extension_names_raw.push(khr::DrawIndirectCount::name().as_ptr());
+    }
+    let mut dynamic_rendering_features =
+        *vk::PhysicalDeviceDynamicRenderingFeatures::builder().dynamic_rendering(true);
+    let mut conditional_rendering_features =
+        *vk::PhysicalDeviceConditionalRenderingFeaturesEXT::builder().conditional_rendering(true);
     let features = vk::PhysicalDeviceFeatures {
         shader_clip_distance: 1,
+        texture_compression_astc_ldr: texture_format_support.astc_ldr.into(),
+        texture_compression_etc2: texture_format_support.etc2.into(),
+        texture_compression_bc: texture_format_support.bc.into(),
+        multi_draw_indirect: draw_indirect_count.into(),
+        draw_indirect_first_instance: draw_indirect_count.into(),
         ..Default::default()
     };
     let priorities = [1.0];
     let queue_info = *vk::DeviceQueueCreateInfo::builder()
         .queue_family_index(queue_family_index)
         .queue_priorities(&priorities);
-    let device_create_info = *vk::DeviceCreateInfo::builder()
+    let mut device_create_info = vk::DeviceCreateInfo::builder()
         .queue_create_infos(std::slice::from_ref(&queue_info))
         .enabled_extension_names(&device_extension_names_raw)
         .enabled_features(&features);
-    let device: Device = instance
-        .create_device(*pdevice, &device_create_info, None)
-        .unwrap();
-    device
+    if dynamic_rendering.is_some() {
+        device_create_info = device_create_info.push_next(&mut dynamic_rendering_features);
+    }
+    if conditional_rendering {
+        device_create_info = device_create_info.push_next(&mut conditional_rendering_features);
+    }
+    Ok(instance.create_device(*pdevice, &device_create_info, None)?)
 }
 
+unsafe fn choose_surface_format(
+    pdevice: &PhysicalDevice,
+    surface_loader: &Surface,
+    surface: &vk::SurfaceKHR,
+    config: &RendererConfig,
+) -> Result<vk::SurfaceFormatKHR> {
+    let surface_formats = surface_loader.get_physical_device_surface_formats(*pdevice, *surface)?;
+    let preferred = config.preferred_surface_format.and_then(|preferred| {
+        surface_formats
+            .iter()
+            .find(|format| {
+                format.format == preferred.format && format.color_space == preferred.color_space
+            })
+            .cloned()
+    });
+    preferred
+        .or_else(|| surface_formats.first().cloned())
+        .ok_or(RendererError::NoSurfaceFormat)
+}
+
+unsafe fn choose_depth_format(
+    instance: &Instance,
+    pdevice: &PhysicalDevice,
+    preferred: vk::Format,
+) -> Result<vk::Format> {
+    let candidates = [
+        preferred,
+        vk::Format::D32_SFLOAT,
+        vk::Format::D24_UNORM_S8_UINT,
+        vk::Format::D16_UNORM,
+    ];
+    candidates
+        .iter()
+        .cloned()
+        .find(|&format| {
+            instance
+                .get_physical_device_format_properties(*pdevice, format)
+                .optimal_tiling_features
+                .contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
+        })
+        .ok_or(RendererError::NoSuitableDepthFormat)
+}
+
+pub(crate) fn depth_aspect_mask(depth_format: vk::Format) -> vk::ImageAspectFlags {
+    match depth_format {
+        vk::Format::D16_UNORM_S8_UINT
+        | vk::Format::D24_UNORM_S8_UINT
+        | vk::Format::D32_SFLOAT_S8_UINT => {
+            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+        }
+        _ => vk::ImageAspectFlags::DEPTH,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 unsafe fn create_swapchain(
     pdevice: &PhysicalDevice,
     surface_loader: &Surface,
     surface: &vk::SurfaceKHR,
     surface_format: &vk::SurfaceFormatKHR,
     swapchain_loader: &Swapchain,
-) -> (vk::SwapchainKHR, vk::Extent2D) {
-    let surface_capabilities = surface_loader
-        .get_physical_device_surface_capabilities(*pdevice, *surface)
-        .unwrap();
+    old_swapchain: vk::SwapchainKHR,
+    desired_resolution: vk::Extent2D,
+    preferred_present_mode: vk::PresentModeKHR,
+) -> Result<(vk::SwapchainKHR, vk::Extent2D)> {
+    let surface_capabilities =
+        surface_loader.get_physical_device_surface_capabilities(*pdevice, *surface)?;
     let mut desired_image_count = surface_capabilities.min_image_count + 1;
     if surface_capabilities.max_image_count > 0
         && desired_image_count > surface_capabilities.max_image_count
@@ -298,9 +1423,15 @@ unsafe fn create_swapchain(
         desired_image_count = surface_capabilities.max_image_count;
     }
     let surface_resolution = match surface_capabilities.current_extent.width {
-        std::u32::MAX => vk::Extent2D {
-            width: 1920,
-            height: 1080,
+        u32::MAX => vk::Extent2D {
+            width: desired_resolution.width.clamp(
+                surface_capabilities.min_image_extent.width,
+                surface_capabilities.max_image_extent.width,
+            ),
+            height: desired_resolution.height.clamp(
+                surface_capabilities.min_image_extent.height,
+                surface_capabilities.max_image_extent.height,
+            ),
         },
         _ => surface_capabilities.current_extent,
     };
@@ -312,13 +1443,12 @@ unsafe fn create_swapchain(
     } else {
         surface_capabilities.current_transform
     };
-    let present_modes = surface_loader
-        .get_physical_device_surface_present_modes(*pdevice, *surface)
-        .unwrap();
+    let present_modes =
+        surface_loader.get_physical_device_surface_present_modes(*pdevice, *surface)?;
     let present_mode = present_modes
         .iter()
         .cloned()
-        .find(|&mode| mode == vk::PresentModeKHR::MAILBOX)
+        .find(|&mode| mode == preferred_present_mode)
         .unwrap_or(vk::PresentModeKHR::FIFO);
     let swapchain_create_info = *vk::SwapchainCreateInfoKHR::builder()
         .surface(*surface)
@@ -326,73 +1456,76 @@ unsafe fn create_swapchain(
         .image_color_space(surface_format.color_space)
         .image_format(surface_format.format)
         .image_extent(surface_resolution)
-        .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+        .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_DST)
         .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
         .pre_transform(pre_transform)
         .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
         .present_mode(present_mode)
         .clipped(true)
-        .image_array_layers(1);
-    let swapchain = swapchain_loader
-        .create_swapchain(&swapchain_create_info, None)
-        .unwrap();
-    (swapchain, surface_resolution)
+        .image_array_layers(1)
+        .old_swapchain(old_swapchain);
+    let swapchain = swapchain_loader.create_swapchain(&swapchain_create_info, None)?;
+    Ok((swapchain, surface_resolution))
 }
 
-unsafe fn create_command_pool(device: &Device, queue_family_index: u32) -> vk::CommandPool {
+unsafe fn create_command_pool(device: &Device, queue_family_index: u32) -> Result<vk::CommandPool> {
     let pool_create_info = *vk::CommandPoolCreateInfo::builder()
         .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
         .queue_family_index(queue_family_index);
-    device.create_command_pool(&pool_create_info, None).unwrap()
+    Ok(device.create_command_pool(&pool_create_info, None)?)
 }
 
 unsafe fn create_command_buffers(
     device: &Device,
     pool: &vk::CommandPool,
-) -> Vec<vk::CommandBuffer> {
+    count: u32,
+) -> Result<Vec<vk::CommandBuffer>> {
     let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
-        .command_buffer_count(2)
+        .command_buffer_count(count)
         .command_pool(*pool)
         .level(vk::CommandBufferLevel::PRIMARY);
 
-    device
-        .allocate_command_buffers(&command_buffer_allocate_info)
-        .unwrap()
+    Ok(device.allocate_command_buffers(&command_buffer_allocate_info)?)
 }
 
 unsafe fn create_present_image_views(
     device: &Device,
-    swapchain_loader: &Swapchain,
-    swapchain: &vk::SwapchainKHR,
+    present_images: &[vk::Image],
     surface_format: &vk::SurfaceFormatKHR,
-) -> Vec<vk::ImageView> {
-    let present_images = swapchain_loader.get_swapchain_images(*swapchain).unwrap();
-    present_images
-        .iter()
-        .map(|&image| {
-            let create_view_info = *vk::ImageViewCreateInfo::builder()
-                .view_type(vk::ImageViewType::TYPE_2D)
-                .format(surface_format.format)
-                .components(vk::ComponentMapping {
-                    r: vk::ComponentSwizzle::R,
-                    g: vk::ComponentSwizzle::G,
-                    b: vk::ComponentSwizzle::B,
-                    a: vk::ComponentSwizzle::A,
-                })
-                .subresource_range(vk::ImageSubresourceRange {
-                    aspect_mask: vk::ImageAspectFlags::COLOR,
-                    base_mip_level: 0,
-                    level_count: 1,
-                    base_array_layer: 0,
-                    layer_count: 1,
-                })
-                .image(image);
-            device.create_image_view(&create_view_info, None).unwrap()
-        })
-        .collect()
+) -> Result<Vec<vk::ImageView>> {
+    let mut views = Vec::with_capacity(present_images.len());
+    for &image in present_images {
+        let create_view_info = *vk::ImageViewCreateInfo::builder()
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(surface_format.format)
+            .components(vk::ComponentMapping {
+                r: vk::ComponentSwizzle::R,
+                g: vk::ComponentSwizzle::G,
+                b: vk::ComponentSwizzle::B,
+                a: vk::ComponentSwizzle::A,
+            })
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .image(image);
+        match device.create_image_view(&create_view_info, None) {
+            Ok(view) => views.push(view),
+            Err(err) => {
+                for &view in views.iter() {
+                    device.destroy_image_view(view, None);
+                }
+                return Err(err.into());
+            }
+        }
+    }
+    Ok(views)
 }
 
-fn find_memorytype_index(
+pub(crate) fn find_memorytype_index(
     memory_req: &vk::MemoryRequirements,
     memory_prop: &vk::PhysicalDeviceMemoryProperties,
     flags: vk::MemoryPropertyFlags,
@@ -408,87 +1541,69 @@ fn find_memorytype_index(
 }
 
 unsafe fn create_depth_image(
-    instance: &Instance,
-    pdevice: &PhysicalDevice,
     device: &Device,
+    allocator: &mut MemoryAllocator,
     surface_resolution: &vk::Extent2D,
-) -> (vk::Image, vk::DeviceMemory) {
-    let device_memory_properties = instance.get_physical_device_memory_properties(*pdevice);
+    depth_format: vk::Format,
+    samples: vk::SampleCountFlags,
+) -> Result<(vk::Image, Allocation)> {
     let depth_image_create_info = *vk::ImageCreateInfo::builder()
         .image_type(vk::ImageType::TYPE_2D)
-        .format(vk::Format::D16_UNORM)
+        .format(depth_format)
         .extent((*surface_resolution).into())
         .mip_levels(1)
         .array_layers(1)
-        .samples(vk::SampleCountFlags::TYPE_1)
+        .samples(samples)
         .tiling(vk::ImageTiling::OPTIMAL)
-        .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
+        // 後のパスでシーンの深度をサンプリングできるようにする
+        .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
         .sharing_mode(vk::SharingMode::EXCLUSIVE);
-    let depth_image = device.create_image(&depth_image_create_info, None).unwrap();
-    let depth_image_memory_req = device.get_image_memory_requirements(depth_image);
-    let depth_image_memory_index = find_memorytype_index(
-        &depth_image_memory_req,
-        &device_memory_properties,
+    // 解像度に依存する大きなレンダーターゲットなので専用割り当てにする
+    create_image(
+        device,
+        allocator,
+        &depth_image_create_info,
         vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        true,
     )
-    .expect("Unable to find suitable memory index for depth image.");
-    let depth_image_allocate_info = *vk::MemoryAllocateInfo::builder()
-        .allocation_size(depth_image_memory_req.size)
-        .memory_type_index(depth_image_memory_index);
-
-    let depth_image_memory = device
-        .allocate_memory(&depth_image_allocate_info, None)
-        .unwrap();
-
-    device
-        .bind_image_memory(depth_image, depth_image_memory, 0)
-        .expect("Unable to bind depth image memory");
+}
 
-    (depth_image, depth_image_memory)
+/// 深度バッファの深度のアスペクトだけのビュー。ステンシルを含むフォーマットでもサンプリングできる
+pub(crate) unsafe fn create_depth_sampling_view(
+    device: &Device,
+    depth_image: vk::Image,
+    depth_format: vk::Format,
+) -> Result<vk::ImageView> {
+    let view_create_info = *vk::ImageViewCreateInfo::builder()
+        .image(depth_image)
+        .view_type(vk::ImageViewType::TYPE_2D)
+        .format(depth_format)
+        .subresource_range(
+            *vk::ImageSubresourceRange::builder()
+                .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                .level_count(1)
+                .layer_count(1),
+        );
+    Ok(device.create_image_view(&view_create_info, None)?)
 }
 
-unsafe fn optimize_depth_image_layout(
+unsafe fn create_depth_image_view(
     device: &Device,
-    setup_command_buffer: &vk::CommandBuffer,
-    setup_commands_reuse_fence: &vk::Fence,
-    present_queue: &vk::Queue,
     depth_image: &vk::Image,
-) {
-    record_submit_commandbuffer(
-        device,
-        *setup_command_buffer,
-        *setup_commands_reuse_fence,
-        *present_queue,
-        &[],
-        &[],
-        &[],
-        |device, setup_command_buffer| {
-            let layout_transition_barriers = *vk::ImageMemoryBarrier::builder()
-                .image(*depth_image)
-                .dst_access_mask(
-                    vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
-                        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
-                )
-                .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
-                .old_layout(vk::ImageLayout::UNDEFINED)
-                .subresource_range(
-                    *vk::ImageSubresourceRange::builder()
-                        .aspect_mask(vk::ImageAspectFlags::DEPTH)
-                        .layer_count(1)
-                        .level_count(1),
-                );
-
-            device.cmd_pipeline_barrier(
-                setup_command_buffer,
-                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-                vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
-                vk::DependencyFlags::empty(),
-                &[],
-                &[],
-                &[layout_transition_barriers],
-            );
-        },
-    );
+    depth_format: vk::Format,
+) -> Result<vk::ImageView> {
+    let depth_image_view_info = *vk::ImageViewCreateInfo::builder()
+        .subresource_range(
+            *vk::ImageSubresourceRange::builder()
+                .aspect_mask(depth_aspect_mask(depth_format))
+                .level_count(1)
+                .layer_count(1),
+        )
+        .image(*depth_image)
+        .format(depth_format)
+        .view_type(vk::ImageViewType::TYPE_2D);
+
+    Ok(device.create_image_view(&depth_image_view_info, None)?)
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -501,33 +1616,23 @@ fn record_submit_commandbuffer<F: FnOnce(&Device, vk::CommandBuffer)>(
     wait_semaphores: &[vk::Semaphore],
     signal_semaphores: &[vk::Semaphore],
     f: F,
-) {
+) -> Result<()> {
     unsafe {
-        device
-            .wait_for_fences(&[command_buffer_reuse_fence], true, std::u64::MAX)
-            .expect("Wait for fence failed.");
+        device.wait_for_fences(&[command_buffer_reuse_fence], true, u64::MAX)?;
 
-        device
-            .reset_fences(&[command_buffer_reuse_fence])
-            .expect("Reset fences failed.");
+        device.reset_fences(&[command_buffer_reuse_fence])?;
 
-        device
-            .reset_command_buffer(
-                command_buffer,
-                vk::CommandBufferResetFlags::RELEASE_RESOURCES,
-            )
-            .expect("Reset command buffer failed.");
+        device.reset_command_buffer(
+            command_buffer,
+            vk::CommandBufferResetFlags::RELEASE_RESOURCES,
+        )?;
 
         let command_buffer_begin_info = *vk::CommandBufferBeginInfo::builder()
             .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
 
-        device
-            .begin_command_buffer(command_buffer, &command_buffer_begin_info)
-            .expect("Begin commandbuffer");
+        device.begin_command_buffer(command_buffer, &command_buffer_begin_info)?;
         f(device, command_buffer);
-        device
-            .end_command_buffer(command_buffer)
-            .expect("End commandbuffer");
+        device.end_command_buffer(command_buffer)?;
 
         let command_buffers = vec![command_buffer];
 
@@ -537,8 +1642,113 @@ fn record_submit_commandbuffer<F: FnOnce(&Device, vk::CommandBuffer)>(
             .command_buffers(&command_buffers)
             .signal_semaphores(signal_semaphores);
 
-        device
-            .queue_submit(submit_queue, &[submit_info], command_buffer_reuse_fence)
-            .expect("queue submit failed.");
+        device.queue_submit(submit_queue, &[submit_info], command_buffer_reuse_fence)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn candidate(api_version: u32, extensions: &[&CStr], queues: &[bool]) -> DeviceCandidate {
+        DeviceCandidate {
+            api_version,
+            extensions: extensions.iter().map(|&name| name.to_owned()).collect(),
+            presentable_graphics_queues: queues.to_vec(),
+        }
+    }
+
+    #[test]
+    fn chooses_first_device_with_presentable_graphics_queue() {
+        let candidates = [
+            candidate(vk::API_VERSION_1_3, &[Swapchain::name()], &[false]),
+            candidate(
+                vk::API_VERSION_1_3,
+                &[Swapchain::name()],
+                &[false, true, true],
+            ),
+        ];
+        let config = RendererConfig::default();
+        assert!(matches!(
+            choose_physical_device(&candidates, &config),
+            Ok((1, 1))
+        ));
+    }
+
+    #[test]
+    fn config_requirements_can_rule_out_every_device() {
+        let candidates = [
+            candidate(vk::API_VERSION_1_1, &[Swapchain::name()], &[true]),
+            candidate(vk::API_VERSION_1_3, &[Swapchain::name()], &[true]),
+        ];
+        let missing_extension = RendererConfig {
+            device_extensions: vec![c"VK_TEST_missing_extension".to_owned()],
+            ..RendererConfig::default()
+        };
+        assert!(matches!(
+            choose_physical_device(&candidates, &missing_extension),
+            Err(RendererError::NoSuitableDevice)
+        ));
+        let newer_api = RendererConfig {
+            api_version: vk::make_api_version(0, 1, 4, 0),
+            ..RendererConfig::default()
+        };
+        assert!(matches!(
+            choose_physical_device(&candidates, &newer_api),
+            Err(RendererError::NoSuitableDevice)
+        ));
+        // バージョンで1つ目を除く
+        let api_1_2 = RendererConfig {
+            api_version: vk::API_VERSION_1_2,
+            ..RendererConfig::default()
+        };
+        assert!(matches!(
+            choose_physical_device(&candidates, &api_1_2),
+            Ok((1, 0))
+        ));
+        // スワップチェインの拡張は常に必要
+        let without_swapchain = [candidate(vk::API_VERSION_1_3, &[], &[true])];
+        assert!(matches!(
+            choose_physical_device(&without_swapchain, &RendererConfig::default()),
+            Err(RendererError::NoSuitableDevice)
+        ));
+        assert!(matches!(
+            choose_physical_device(&[], &RendererConfig::default()),
+            Err(RendererError::NoSuitableDevice)
+        ));
+    }
+
+    #[test]
+    fn creation_guard_unwinds_in_reverse_order() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        {
+            let mut guard = CreationGuard::new(0);
+            for name in ["instance", "surface", "device"] {
+                let log = log.clone();
+                guard.push(move |count: &mut i32| {
+                    *count += 1;
+                    log.borrow_mut().push((name, *count));
+                });
+            }
+            *guard.state() = 10;
+        }
+        assert_eq!(
+            *log.borrow(),
+            [("device", 11), ("surface", 12), ("instance", 13)]
+        );
+    }
+
+    #[test]
+    fn finished_creation_guard_keeps_everything() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut guard = CreationGuard::new(vec![1]);
+        let cleanup_log = log.clone();
+        guard.push(move |_| cleanup_log.borrow_mut().push("destroyed"));
+        guard.state().push(2);
+        assert_eq!(guard.finish(), [1, 2]);
+        assert!(log.borrow().is_empty());
     }
 }
@@ -0,0 +1,609 @@
+use super::culling::Bvh;
+use super::error::{RendererError, Result};
+use super::{
+    mat4_mul, Camera, Handle, Mat4, Mesh, MeshId, Pool, Renderer, ViewSettings, MAT4_IDENTITY,
+};
+use std::collections::HashSet;
+
+/// 平行移動、回転、拡大縮小。`S`、`R`、`T`の順に適用する
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub translation: [f32; 3],
+    /// 単位クォータニオン(x, y, z, w)
+    pub rotation: [f32; 4],
+    pub scale: [f32; 3],
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: [0.0, 0.0, 0.0],
+            rotation: [0.0, 0.0, 0.0, 1.0],
+            scale: [1.0, 1.0, 1.0],
+        }
+    }
+}
+
+impl Transform {
+    pub fn from_translation(translation: [f32; 3]) -> Self {
+        Self {
+            translation,
+            ..Default::default()
+        }
+    }
+
+    /// `axis`は正規化済みであること
+    pub fn from_axis_angle(axis: [f32; 3], angle: f32) -> Self {
+        let (sin, cos) = (angle * 0.5).sin_cos();
+        Self {
+            rotation: [axis[0] * sin, axis[1] * sin, axis[2] * sin, cos],
+            ..Default::default()
+        }
+    }
+
+    pub fn matrix(&self) -> Mat4 {
+        let [x, y, z, w] = self.rotation;
+        let [sx, sy, sz] = self.scale;
+        let [tx, ty, tz] = self.translation;
+        [
+            [
+                (1.0 - 2.0 * (y * y + z * z)) * sx,
+                2.0 * (x * y + z * w) * sx,
+                2.0 * (x * z - y * w) * sx,
+                0.0,
+            ],
+            [
+                2.0 * (x * y - z * w) * sy,
+                (1.0 - 2.0 * (x * x + z * z)) * sy,
+                2.0 * (y * z + x * w) * sy,
+                0.0,
+            ],
+            [
+                2.0 * (x * z + y * w) * sz,
+                2.0 * (y * z - x * w) * sz,
+                (1.0 - 2.0 * (x * x + y * y)) * sz,
+                0.0,
+            ],
+            [tx, ty, tz, 1.0],
+        ]
+    }
+}
+
+/// ライトの種類。向きはノードの-Z方向
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightKind {
+    Directional,
+    Point {
+        /// `None`なら減衰だけで範囲を区切らない
+        range: Option<f32>,
+    },
+    Spot {
+        range: Option<f32>,
+        /// 減衰が始まる角度(ラジアン)
+        inner_cone_angle: f32,
+        /// 光が届かなくなる角度(ラジアン)
+        outer_cone_angle: f32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    /// リニア空間の色
+    pub color: [f32; 3],
+    /// 単位は`PhotometricIntensity`を参照
+    pub intensity: f32,
+    /// `set_ies_profiles`に渡したプロファイルの番号。角度ごとの減衰に使う
+    pub ies_profile: Option<u32>,
+}
+
+/// メッシュをどのパスで描くか
+///
+/// 細かいメッシュを`NoShadow`にし、同じ位置の粗いメッシュを`ShadowOnly`にすると、
+/// 影だけを安い代理のメッシュで描ける。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum RenderMode {
+    /// メインのビューに描き、影も落とす
+    #[default]
+    Visible,
+    /// メインのビューに描くが、影は落とさない
+    NoShadow,
+    /// メインのビューには描かず、影だけを落とす。影の代理のメッシュや、
+    /// 画面外の物の影を落とす演出に使う
+    ShadowOnly,
+}
+
+/// メッシュを描くパスの種類。[`Scene::meshes_for`]で描くメッシュを絞り込む
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ScenePass {
+    /// メインのビューの描画と、その深度のプリパスやモーションベクトル
+    Main,
+    /// シャドウマップとポイントシャドウ
+    Shadow,
+}
+
+impl RenderMode {
+    /// `pass`でこのモードのメッシュを描くか
+    pub fn is_drawn_in(self, pass: ScenePass) -> bool {
+        match pass {
+            ScenePass::Main => self != RenderMode::ShadowOnly,
+            ScenePass::Shadow => self != RenderMode::NoShadow,
+        }
+    }
+}
+
+/// シーンの1ノード。変換と親子関係は[`Scene`]のメソッドで変更する
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub name: String,
+    pub mesh: Option<MeshId>,
+    /// `mesh`を描くパス
+    pub render_mode: RenderMode,
+    pub light: Option<Light>,
+    /// カメラの位置と向きはノードのワールド変換で決まる
+    pub camera: Option<Camera>,
+    /// `camera`で描くときに`set_view_settings`で全体の設定に重ねる上書き
+    pub view_settings: ViewSettings,
+    transform: Transform,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    world_matrix: Mat4,
+    dirty: bool,
+}
+
+impl Node {
+    pub fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
+    }
+
+    /// 最後の`update_world_matrices`の時点のワールド変換
+    pub fn world_matrix(&self) -> &Mat4 {
+        &self.world_matrix
+    }
+}
+
+pub type NodeId = Handle<Node>;
+
+/// 親子関係を持つノードの集まり
+///
+/// 変換を変更したノードには印を付けておき、`update_world_matrices`でそのノード以下の
+/// ワールド変換だけを計算し直す。カリングに使うBVHも同時に作り直す。
+#[derive(Default)]
+pub struct Scene {
+    nodes: Pool<Node>,
+    roots: Vec<NodeId>,
+    bvh: Bvh,
+    /// メッシュを持つノードが変わったのでBVHを作り直す
+    bvh_dirty: bool,
+}
+
+impl Scene {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `parent`が`None`ならルートノードとして追加する
+    pub fn add_node(
+        &mut self,
+        name: &str,
+        transform: Transform,
+        parent: Option<NodeId>,
+    ) -> Result<NodeId> {
+        if let Some(parent) = parent {
+            self.get(parent)?;
+        }
+        let id = self.nodes.insert(Node {
+            name: name.to_owned(),
+            mesh: None,
+            render_mode: RenderMode::default(),
+            light: None,
+            camera: None,
+            view_settings: ViewSettings::default(),
+            transform,
+            parent,
+            children: Vec::new(),
+            world_matrix: MAT4_IDENTITY,
+            dirty: true,
+        });
+        match parent {
+            Some(parent) => self.nodes.get_mut(parent).unwrap().children.push(id),
+            None => self.roots.push(id),
+        }
+        Ok(id)
+    }
+
+    /// ノードを子孫ごと取り除く。取り除いたノードを返すので、メッシュは呼び出し側で破棄する
+    pub fn remove_node(&mut self, id: NodeId) -> Result<Vec<Node>> {
+        self.get(id)?;
+        self.detach(id);
+        self.bvh_dirty = true;
+        let mut removed = Vec::new();
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            let node = self.nodes.remove(id).unwrap();
+            stack.extend(node.children.iter().copied());
+            removed.push(node);
+        }
+        Ok(removed)
+    }
+
+    /// 親を付け替える。ワールド変換は保たず、ローカル変換をそのまま新しい親に対して使う
+    pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>) -> Result<()> {
+        self.get(id)?;
+        if let Some(parent) = parent {
+            self.get(parent)?;
+            // 自分の子孫を親にすると循環する
+            let mut ancestor = Some(parent);
+            while let Some(current) = ancestor {
+                if current == id {
+                    return Err(RendererError::Validation(format!(
+                        "{:?} cannot be parented to its own descendant {:?}",
+                        id, parent
+                    )));
+                }
+                ancestor = self.nodes.get(current).unwrap().parent;
+            }
+        }
+        self.detach(id);
+        match parent {
+            Some(parent) => self.nodes.get_mut(parent).unwrap().children.push(id),
+            None => self.roots.push(id),
+        }
+        let node = self.nodes.get_mut(id).unwrap();
+        node.parent = parent;
+        node.dirty = true;
+        Ok(())
+    }
+
+    pub fn set_transform(&mut self, id: NodeId, transform: Transform) -> Result<()> {
+        let node = self.get_mut(id)?;
+        node.transform = transform;
+        node.dirty = true;
+        Ok(())
+    }
+
+    pub fn node(&self, id: NodeId) -> Option<&Node> {
+        self.nodes.get(id)
+    }
+
+    /// 名前、メッシュ、ライト、カメラを変更する
+    pub fn node_mut(&mut self, id: NodeId) -> Option<&mut Node> {
+        // メッシュが変わるかもしれない
+        self.bvh_dirty = true;
+        self.nodes.get_mut(id)
+    }
+
+    /// 最後の`update_world_matrices`の時点のメッシュの箱の階層
+    pub fn bvh(&self) -> &Bvh {
+        &self.bvh
+    }
+
+    pub fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = (NodeId, &Node)> {
+        self.nodes.iter()
+    }
+
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        self.nodes
+            .iter()
+            .find(|(_, node)| node.name == name)
+            .map(|(id, _)| id)
+    }
+
+    /// 変換が変わったノードとその子孫のワールド変換を計算し直す
+    ///
+    /// ワールド変換かメッシュが変わっていれば、`meshes`の箱からBVHも作り直す。`meshes`には
+    /// `Renderer::meshes`を渡す。
+    pub fn update_world_matrices(&mut self, meshes: &Pool<Mesh>) {
+        let mut stack: Vec<(NodeId, Mat4, bool)> = self
+            .roots
+            .iter()
+            .map(|&root| (root, MAT4_IDENTITY, false))
+            .collect();
+        while let Some((id, parent_matrix, parent_changed)) = stack.pop() {
+            let node = self.nodes.get_mut(id).unwrap();
+            let changed = parent_changed || node.dirty;
+            if changed {
+                node.world_matrix = mat4_mul(&parent_matrix, &node.transform.matrix());
+                node.dirty = false;
+                self.bvh_dirty |= node.mesh.is_some();
+            }
+            let world_matrix = node.world_matrix;
+            stack.extend(
+                node.children
+                    .iter()
+                    .map(|&child| (child, world_matrix, changed)),
+            );
+        }
+        if self.bvh_dirty {
+            self.bvh = Bvh::build(self.nodes.iter().filter_map(|(id, node)| {
+                let mesh = meshes.get(node.mesh?);
+                Some((
+                    id,
+                    mesh.and_then(|mesh| mesh.bounds)
+                        .map(|bounds| bounds.transform(&node.world_matrix)),
+                ))
+            }));
+            self.bvh_dirty = false;
+        }
+    }
+
+    /// メッシュを持つノードとそのワールド変換
+    pub fn meshes(&self) -> impl Iterator<Item = (NodeId, MeshId, &Mat4)> {
+        self.nodes
+            .iter()
+            .filter_map(|(id, node)| node.mesh.map(|mesh| (id, mesh, &node.world_matrix)))
+    }
+
+    /// `pass`で描くメッシュを持つノードとそのワールド変換。ノードの`render_mode`で絞り込む
+    pub fn meshes_for(&self, pass: ScenePass) -> impl Iterator<Item = (NodeId, MeshId, &Mat4)> {
+        self.nodes.iter().filter_map(move |(id, node)| {
+            node.mesh
+                .filter(|_| node.render_mode.is_drawn_in(pass))
+                .map(|mesh| (id, mesh, &node.world_matrix))
+        })
+    }
+
+    /// ライトを持つノードと、ワールド空間での位置と向き
+    pub fn lights(&self) -> impl Iterator<Item = (NodeId, &Light, [f32; 3], [f32; 3])> {
+        self.nodes.iter().filter_map(|(id, node)| {
+            node.light.as_ref().map(|light| {
+                let m = &node.world_matrix;
+                let position = [m[3][0], m[3][1], m[3][2]];
+                let direction = normalize([-m[2][0], -m[2][1], -m[2][2]]);
+                (id, light, position, direction)
+            })
+        })
+    }
+
+    /// ノードのカメラを、ワールド変換の位置と-Z方向に合わせて返す
+    pub fn world_camera(&self, id: NodeId) -> Option<Camera> {
+        let node = self.nodes.get(id)?;
+        let mut camera = node.camera?;
+        let m = &node.world_matrix;
+        camera.position = [m[3][0], m[3][1], m[3][2]];
+        let [x, y, z] = normalize([-m[2][0], -m[2][1], -m[2][2]]);
+        camera.yaw = x.atan2(-z);
+        camera.pitch = y.clamp(-1.0, 1.0).asin();
+        Some(camera)
+    }
+
+    fn get(&self, id: NodeId) -> Result<&Node> {
+        self.nodes
+            .get(id)
+            .ok_or_else(|| RendererError::Validation(format!("{:?} is not in the scene", id)))
+    }
+
+    fn get_mut(&mut self, id: NodeId) -> Result<&mut Node> {
+        self.nodes
+            .get_mut(id)
+            .ok_or_else(|| RendererError::Validation(format!("{:?} is not in the scene", id)))
+    }
+
+    /// 親の子もしくはルートの一覧から外す
+    fn detach(&mut self, id: NodeId) {
+        let siblings = match self.nodes.get(id).unwrap().parent {
+            Some(parent) => &mut self.nodes.get_mut(parent).unwrap().children,
+            None => &mut self.roots,
+        };
+        siblings.retain(|&sibling| sibling != id);
+    }
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let length = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    if length > f32::EPSILON {
+        v.map(|value| value / length)
+    } else {
+        [0.0, 0.0, -1.0]
+    }
+}
+
+impl Renderer {
+    /// シーンのノードが持つメッシュをすべて破棄する。複数のノードが共有するメッシュは1回だけ破棄する
+    pub fn destroy_scene(&mut self, mut scene: Scene) -> Result<()> {
+        let meshes: HashSet<MeshId> = scene
+            .nodes
+            .drain()
+            .into_iter()
+            .filter_map(|node| node.mesh)
+            .collect();
+        for mesh in meshes {
+            self.destroy_mesh(mesh)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    fn transform_point(m: &Mat4, p: [f32; 3]) -> [f32; 3] {
+        [0, 1, 2].map(|j| m[0][j] * p[0] + m[1][j] * p[1] + m[2][j] * p[2] + m[3][j])
+    }
+
+    fn assert_close(a: [f32; 3], b: [f32; 3]) {
+        assert!(
+            (0..3).all(|i| (a[i] - b[i]).abs() < 1e-5),
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    /// root -> child -> grandchild と、別のルートの other
+    fn hierarchy() -> (Scene, [NodeId; 4]) {
+        let mut scene = Scene::new();
+        let root = scene
+            .add_node("root", Transform::from_translation([1.0, 0.0, 0.0]), None)
+            .unwrap();
+        let child = scene
+            .add_node(
+                "child",
+                Transform::from_translation([0.0, 2.0, 0.0]),
+                Some(root),
+            )
+            .unwrap();
+        let grandchild = scene
+            .add_node(
+                "grandchild",
+                Transform::from_translation([0.0, 0.0, 3.0]),
+                Some(child),
+            )
+            .unwrap();
+        let other = scene
+            .add_node("other", Transform::from_translation([5.0, 0.0, 0.0]), None)
+            .unwrap();
+        (scene, [root, child, grandchild, other])
+    }
+
+    fn world_position(scene: &Scene, id: NodeId) -> [f32; 3] {
+        transform_point(scene.node(id).unwrap().world_matrix(), [0.0; 3])
+    }
+
+    #[test]
+    fn transform_matrix_applies_scale_rotation_translation() {
+        let transform = Transform {
+            translation: [1.0, 2.0, 3.0],
+            scale: [2.0, 2.0, 2.0],
+            ..Transform::from_axis_angle([0.0, 1.0, 0.0], FRAC_PI_2)
+        };
+        let m = transform.matrix();
+        // Y軸まわりに90度回すと+Xは-Zを向く
+        assert_close(transform_point(&m, [1.0, 0.0, 0.0]), [1.0, 2.0, 1.0]);
+        assert_close(transform_point(&m, [0.0, 0.0, 1.0]), [3.0, 2.0, 3.0]);
+        assert_close(transform_point(&m, [0.0, 1.0, 0.0]), [1.0, 4.0, 3.0]);
+        assert_eq!(Transform::default().matrix(), MAT4_IDENTITY);
+    }
+
+    #[test]
+    fn world_matrices_compose_parents() {
+        let (mut scene, [root, child, grandchild, other]) = hierarchy();
+        scene.update_world_matrices(&Pool::new());
+        assert_close(world_position(&scene, root), [1.0, 0.0, 0.0]);
+        assert_close(world_position(&scene, child), [1.0, 2.0, 0.0]);
+        assert_close(world_position(&scene, grandchild), [1.0, 2.0, 3.0]);
+        assert_close(world_position(&scene, other), [5.0, 0.0, 0.0]);
+
+        // 親の回転は子の位置も回す
+        let rotated = Transform {
+            translation: [1.0, 0.0, 0.0],
+            ..Transform::from_axis_angle([0.0, 1.0, 0.0], FRAC_PI_2)
+        };
+        scene.set_transform(root, rotated).unwrap();
+        scene.update_world_matrices(&Pool::new());
+        assert_close(world_position(&scene, grandchild), [4.0, 2.0, 0.0]);
+    }
+
+    #[test]
+    fn dirty_node_updates_only_its_descendants() {
+        let (mut scene, [root, child, grandchild, other]) = hierarchy();
+        scene.update_world_matrices(&Pool::new());
+        assert!(scene.nodes.iter().all(|(_, node)| !node.dirty));
+
+        // 印の付いていないノードは計算し直さないので、書き換えた値が残る
+        let stale = [[9.0; 4]; 4];
+        scene.nodes.get_mut(other).unwrap().world_matrix = stale;
+        scene
+            .set_transform(child, Transform::from_translation([0.0, 4.0, 0.0]))
+            .unwrap();
+        assert!(scene.node(child).unwrap().dirty);
+        assert!(!scene.node(grandchild).unwrap().dirty);
+        scene.update_world_matrices(&Pool::new());
+        assert_eq!(*scene.node(other).unwrap().world_matrix(), stale);
+        assert_close(world_position(&scene, root), [1.0, 0.0, 0.0]);
+        // 孫は印がなくても親の変更で計算し直す
+        assert_close(world_position(&scene, child), [1.0, 4.0, 0.0]);
+        assert_close(world_position(&scene, grandchild), [1.0, 4.0, 3.0]);
+        assert!(scene.nodes.iter().all(|(_, node)| !node.dirty));
+    }
+
+    #[test]
+    fn set_parent_rejects_cycles() {
+        let (mut scene, [root, child, grandchild, _]) = hierarchy();
+        assert!(scene.set_parent(root, Some(grandchild)).is_err());
+        assert!(scene.set_parent(root, Some(child)).is_err());
+        assert!(scene.set_parent(child, Some(child)).is_err());
+        // 失敗しても親子関係は変わらない
+        assert_eq!(scene.node(root).unwrap().parent(), None);
+        assert_eq!(scene.node(root).unwrap().children(), [child]);
+        assert_eq!(scene.node(child).unwrap().parent(), Some(root));
+    }
+
+    #[test]
+    fn set_parent_detaches_and_reparents() {
+        let (mut scene, [root, child, grandchild, other]) = hierarchy();
+        scene.update_world_matrices(&Pool::new());
+
+        // ルートにする
+        scene.set_parent(child, None).unwrap();
+        assert_eq!(scene.node(child).unwrap().parent(), None);
+        assert!(scene.node(root).unwrap().children().is_empty());
+        assert_eq!(scene.roots(), [root, other, child]);
+        scene.update_world_matrices(&Pool::new());
+        // ローカル変換はそのまま使うので、ワールドの位置は変わる
+        assert_close(world_position(&scene, child), [0.0, 2.0, 0.0]);
+        assert_close(world_position(&scene, grandchild), [0.0, 2.0, 3.0]);
+
+        // 別のノードの子にする
+        scene.set_parent(child, Some(other)).unwrap();
+        assert_eq!(scene.roots(), [root, other]);
+        assert_eq!(scene.node(other).unwrap().children(), [child]);
+        assert_eq!(scene.node(child).unwrap().parent(), Some(other));
+        scene.update_world_matrices(&Pool::new());
+        assert_close(world_position(&scene, grandchild), [5.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn remove_node_takes_descendants() {
+        let (mut scene, [root, child, grandchild, other]) = hierarchy();
+        let removed = scene.remove_node(child).unwrap();
+        let mut names: Vec<_> = removed.iter().map(|node| node.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["child", "grandchild"]);
+        assert!(scene.node(child).is_none());
+        assert!(scene.node(grandchild).is_none());
+        assert!(scene.node(root).unwrap().children().is_empty());
+        assert!(scene.set_parent(other, Some(child)).is_err());
+        assert!(scene
+            .add_node("orphan", Transform::default(), Some(grandchild))
+            .is_err());
+        assert_eq!(scene.find("other"), Some(other));
+        assert_eq!(scene.find("child"), None);
+    }
+
+    #[test]
+    fn world_camera_follows_node() {
+        let mut scene = Scene::new();
+        let id = scene
+            .add_node(
+                "camera",
+                Transform {
+                    translation: [1.0, 2.0, 3.0],
+                    ..Transform::from_axis_angle([0.0, 1.0, 0.0], FRAC_PI_2)
+                },
+                None,
+            )
+            .unwrap();
+        scene.node_mut(id).unwrap().camera = Some(Camera::default());
+        scene.update_world_matrices(&Pool::new());
+        let camera = scene.world_camera(id).unwrap();
+        assert_eq!(camera.position, [1.0, 2.0, 3.0]);
+        // -Zを90度回すと-Xを向く
+        assert!((camera.yaw + FRAC_PI_2).abs() < 1e-5);
+        assert!(camera.pitch.abs() < 1e-5);
+    }
+}
@@ -0,0 +1,514 @@
+use super::camera::{mat4_inverse, Camera, Mat4, MAT4_IDENTITY};
+use super::error::{RendererError, Result};
+use super::memory::MemoryAllocator;
+use super::renderer::create_depth_sampling_view;
+use super::texture::{create_screen_image, ScreenImage};
+use super::{
+    BufferId, ComputePipeline, DescriptorBinding, DescriptorWriter, PerFrame, Renderer,
+    SamplerDesc, ShaderId, HDR_FORMAT,
+};
+use ash::{vk, Device};
+
+/// SSRのシェーダーのワークグループの幅と高さ
+pub const SCREEN_SPACE_REFLECTION_GROUP_SIZE: u32 = 8;
+
+/// SSRの設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScreenSpaceReflectionSettings {
+    pub enabled: bool,
+    /// 反射の強さ。鏡面反射の重みの近似として、環境マップの反射との差に掛ける
+    pub intensity: f32,
+    /// レイを進める最大の距離。ビュー空間の単位
+    pub max_distance: f32,
+    /// レイが深度バッファの面の後ろにこの厚さまで入っていれば当たったとみなす
+    pub thickness: f32,
+    pub max_steps: u32,
+    /// これより粗い面ではSSRを使わず、環境マップの反射のままにする
+    pub max_roughness: f32,
+    /// `set_reflection_surface`で面の情報を渡していない場合に使う粗さ
+    pub roughness: f32,
+}
+
+impl Default for ScreenSpaceReflectionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            intensity: 1.0,
+            max_distance: 10.0,
+            thickness: 0.1,
+            max_steps: 64,
+            max_roughness: 0.6,
+            roughness: 0.2,
+        }
+    }
+}
+
+/// SSRのユニフォームバッファの内容。std140と同じ配置
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScreenSpaceReflectionUniform {
+    pub projection: Mat4,
+    pub inverse_projection: Mat4,
+    /// ビュー空間からワールド空間への変換。環境マップを引くのに使う
+    pub inverse_view: Mat4,
+    pub max_distance: f32,
+    pub thickness: f32,
+    pub max_roughness: f32,
+    pub intensity: f32,
+    pub roughness: f32,
+    pub max_steps: u32,
+    /// 0ならバインディング2に面の情報がなく、法線を深度から復元する
+    pub use_surface: u32,
+    /// 何も描かれていない画素の深度(`Camera::clear_depth`)
+    pub far_depth: f32,
+    /// 環境マップの最後のミップ。粗さ1でこのミップを引く
+    pub environment_max_lod: f32,
+}
+
+/// SSRのコンピュートシェーダー
+///
+/// 8x8のワークグループで1画素を1スレッドが処理し、`ssr_set_layout`のセットを使う。
+/// ビュー空間で反射ベクトルに沿って深度バッファをレイマーチし、当たった画素の色で
+/// 環境マップの反射を置き換える。前のパスのIBLで環境マップの反射はシーンの色に
+/// 含まれているので、外れた画素や粗い面はそのままにすれば環境マップにフォールバックする。
+///
+/// ```glsl
+/// layout(local_size_x = 8, local_size_y = 8) in;
+/// layout(set = 0, binding = 0) uniform sampler2D scene;
+/// layout(set = 0, binding = 1) uniform sampler2D depth;
+/// // xyz: ビュー空間の法線 * 0.5 + 0.5、a: 粗さ。use_surfaceが0ならdepthが入っている
+/// layout(set = 0, binding = 2) uniform sampler2D surface;
+/// layout(set = 0, binding = 3) uniform samplerCube environment; // IBLのプリフィルタ済みキューブマップ
+/// layout(set = 0, binding = 4, rgba16f) uniform writeonly image2D dst;
+/// layout(set = 0, binding = 5) uniform Params {
+///     mat4 projection; mat4 inverse_projection; mat4 inverse_view;
+///     float max_distance; float thickness; float max_roughness; float intensity;
+///     float roughness; uint max_steps; uint use_surface; float far_depth; float environment_max_lod;
+/// };
+/// vec3 view_position(vec2 uv) {
+///     vec4 p = inverse_projection * vec4(uv * 2.0 - 1.0, textureLod(depth, uv, 0.0).r, 1.0);
+///     return p.xyz / p.w;
+/// }
+/// void main() {
+///     ivec2 p = ivec2(gl_GlobalInvocationID.xy), size = imageSize(dst);
+///     if (any(greaterThanEqual(p, size))) return;
+///     vec2 t = 1.0 / vec2(size), uv = (vec2(p) + 0.5) * t;
+///     vec3 color = texelFetch(scene, p, 0).rgb;
+///     vec3 position = view_position(uv), normal;
+///     float r = roughness;
+///     if (use_surface != 0) {
+///         vec4 s = texelFetch(surface, p, 0);
+///         normal = normalize(s.xyz * 2.0 - 1.0);
+///         r = s.a;
+///     } else {
+///         normal = normalize(cross(view_position(uv + vec2(0.0, t.y)) - position,
+///                                  view_position(uv + vec2(t.x, 0.0)) - position));
+///     }
+///     if (texelFetch(depth, p, 0).r == far_depth || r > max_roughness) {
+///         imageStore(dst, p, vec4(color, 1.0));
+///         return;
+///     }
+///     vec3 v = normalize(-position), reflected = reflect(-v, normal);
+///     vec3 environment_color =
+///         textureLod(environment, mat3(inverse_view) * reflected, r * environment_max_lod).rgb;
+///     vec3 ray = position + normal * thickness;
+///     vec3 step = reflected * (max_distance / float(max_steps));
+///     vec2 hit_uv = uv;
+///     float hit = 0.0;
+///     for (uint i = 0; i < max_steps; ++i) {
+///         ray += step;
+///         vec4 clip = projection * vec4(ray, 1.0);
+///         if (clip.w <= 0.0) break;
+///         hit_uv = clip.xy / clip.w * 0.5 + 0.5;
+///         if (any(lessThan(hit_uv, vec2(0.0))) || any(greaterThan(hit_uv, vec2(1.0)))) break;
+///         // ビュー空間は-zが前なので、面の方が手前ならレイは面の後ろにある
+///         float behind = view_position(hit_uv).z - ray.z;
+///         if (behind > 0.0 && behind < thickness) { hit = 1.0; break; }
+///     }
+///     // 画面の端と粗さの上限に近づくほど環境マップに戻す
+///     vec2 edge = smoothstep(0.0, 0.1, hit_uv) * smoothstep(0.0, 0.1, 1.0 - hit_uv);
+///     float confidence = hit * edge.x * edge.y * (1.0 - smoothstep(0.5 * max_roughness, max_roughness, r));
+///     vec3 hit_color = textureLod(scene, hit_uv, 0.0).rgb;
+///     color += (hit_color - environment_color) * confidence * intensity;
+///     imageStore(dst, p, vec4(max(color, vec3(0.0)), 1.0));
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScreenSpaceReflectionShaders {
+    pub ssr: ShaderId,
+}
+
+/// スワップチェインの解像度に合わせて作り直すSSRのリソース
+pub struct ScreenSpaceReflectionTargets {
+    /// 反射を合成した色。HDRのターゲットにコピーする
+    pub output: ScreenImage,
+    /// 深度バッファの深度のアスペクトだけのビュー
+    pub depth_view: vk::ImageView,
+    pub extent: vk::Extent2D,
+}
+
+impl ScreenSpaceReflectionTargets {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        device.destroy_image_view(self.depth_view, None);
+        self.output.destroy(device, allocator);
+    }
+}
+
+/// `enable_screen_space_reflections`で作るSSRのリソース
+pub struct ScreenSpaceReflections {
+    pub pipeline: ComputePipeline,
+    pub sampler: vk::Sampler,
+    /// `Renderer`のバッファとして別に破棄される
+    pub uniform_buffers: PerFrame<BufferId>,
+    pub targets: ScreenSpaceReflectionTargets,
+    /// `set_reflection_surface`で渡された法線と粗さのビュー
+    pub surface: Option<vk::ImageView>,
+    pub view: Mat4,
+    pub projection: Mat4,
+    pub far_depth: f32,
+}
+
+impl ScreenSpaceReflections {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのリソースを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        self.pipeline.destroy(device);
+        device.destroy_sampler(self.sampler, None);
+        self.targets.destroy(device, allocator);
+    }
+}
+
+impl Renderer {
+    /// SSRのデスクリプタセットレイアウト
+    ///
+    /// バインディング0がシーンの色、1が深度、2が面の法線と粗さ、3が環境マップ、
+    /// 4が出力のストレージイメージ、5が[`ScreenSpaceReflectionUniform`]。
+    pub fn ssr_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        let stage = vk::ShaderStageFlags::COMPUTE;
+        self.descriptor_set_layout(&[
+            DescriptorBinding::sampled_image(0, stage),
+            DescriptorBinding::sampled_image(1, stage),
+            DescriptorBinding::sampled_image(2, stage),
+            DescriptorBinding::sampled_image(3, stage),
+            DescriptorBinding::new(4, vk::DescriptorType::STORAGE_IMAGE, stage),
+            DescriptorBinding::uniform_buffer(5, stage),
+        ])
+    }
+
+    /// HDRのレンダーターゲットにかけるSSRを有効にする。すでに有効な場合は作り直す
+    ///
+    /// `enable_hdr`の後で呼ぶ。外れたレイのフォールバックに`set_environment`の環境マップを使う。
+    /// 深度をサンプリングするのでMSAAとは併用できない。
+    pub fn enable_screen_space_reflections(
+        &mut self,
+        shaders: ScreenSpaceReflectionShaders,
+    ) -> Result<()> {
+        self.enabled_hdr()?;
+        if self.msaa_samples != vk::SampleCountFlags::TYPE_1 {
+            return Err(RendererError::Validation(
+                "screen-space reflections cannot be combined with MSAA".to_owned(),
+            ));
+        }
+        let layout = self.ssr_set_layout()?;
+        let pipeline = self.create_compute_pipeline(shaders.ssr, "main", &[layout], 0)?;
+        let resources = self
+            .create_sampler(&SamplerDesc::nearest())
+            .and_then(|sampler| {
+                let targets = unsafe { self.create_screen_space_reflection_targets() };
+                match targets.and_then(|targets| {
+                    match self.create_per_frame_buffers(
+                        std::mem::size_of::<ScreenSpaceReflectionUniform>() as vk::DeviceSize,
+                        vk::BufferUsageFlags::UNIFORM_BUFFER,
+                    ) {
+                        Ok(buffers) => Ok((targets, buffers)),
+                        Err(err) => {
+                            unsafe { targets.destroy(&self.device, &mut self.allocator) };
+                            Err(err)
+                        }
+                    }
+                }) {
+                    Ok((targets, buffers)) => Ok((sampler, targets, buffers)),
+                    Err(err) => {
+                        unsafe { self.device.destroy_sampler(sampler, None) };
+                        Err(err)
+                    }
+                }
+            });
+        let (sampler, targets, uniform_buffers) = match resources {
+            Ok(resources) => resources,
+            Err(err) => {
+                self.destroy_compute_pipeline(pipeline);
+                return Err(err);
+            }
+        };
+        let (view, projection, far_depth, surface) = match &self.screen_space_reflections {
+            Some(old) => (old.view, old.projection, old.far_depth, old.surface),
+            None => (MAT4_IDENTITY, MAT4_IDENTITY, 1.0, None),
+        };
+        self.disable_screen_space_reflections()?;
+        self.screen_space_reflections = Some(ScreenSpaceReflections {
+            pipeline,
+            sampler,
+            uniform_buffers,
+            targets,
+            surface,
+            view,
+            projection,
+            far_depth,
+        });
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからSSRのリソースを破棄する
+    pub fn disable_screen_space_reflections(&mut self) -> Result<()> {
+        let Some(reflections) = self.screen_space_reflections.take() else {
+            return Ok(());
+        };
+        let ScreenSpaceReflections {
+            pipeline,
+            sampler,
+            uniform_buffers,
+            targets,
+            ..
+        } = reflections;
+        self.destroy_per_frame_buffers(uniform_buffers)?;
+        self.destroy_deferred(move |device, allocator| unsafe {
+            pipeline.destroy(device);
+            device.destroy_sampler(sampler, None);
+            targets.destroy(device, allocator);
+        });
+        Ok(())
+    }
+
+    pub fn screen_space_reflections(&self) -> Option<&ScreenSpaceReflections> {
+        self.screen_space_reflections.as_ref()
+    }
+
+    /// レイマーチに使うカメラを設定する。深度を描いたカメラを毎フレーム渡す
+    pub fn set_reflection_camera(&mut self, camera: &Camera) -> Result<()> {
+        let reflections = self.enabled_screen_space_reflections_mut()?;
+        reflections.view = camera.view_matrix();
+        reflections.projection = camera.projection_matrix();
+        reflections.far_depth = camera.clear_depth();
+        Ok(())
+    }
+
+    /// 面のビュー空間の法線と粗さを書いたイメージのビューを設定する
+    ///
+    /// xyzに`法線 * 0.5 + 0.5`、aに粗さを書き、`apply_post_processing`のときに
+    /// `SHADER_READ_ONLY_OPTIMAL`になっていること。`None`なら法線を深度から復元し、
+    /// 粗さは設定の`roughness`を使う。
+    pub fn set_reflection_surface(&mut self, surface: Option<vk::ImageView>) -> Result<()> {
+        self.enabled_screen_space_reflections_mut()?.surface = surface;
+        Ok(())
+    }
+
+    /// HDRのターゲットに合わせてSSRの出力を作り直す
+    ///
+    /// 古いリソースは使用中のフレームが完了してから破棄する。
+    pub(crate) fn recreate_screen_space_reflection_targets(&mut self) -> Result<()> {
+        if self.screen_space_reflections.is_none() {
+            return Ok(());
+        }
+        let targets = unsafe { self.create_screen_space_reflection_targets()? };
+        let old = std::mem::replace(
+            &mut self.screen_space_reflections.as_mut().unwrap().targets,
+            targets,
+        );
+        self.destroy_deferred(move |device, allocator| unsafe { old.destroy(device, allocator) });
+        Ok(())
+    }
+
+    /// SSRを記録し、結果をHDRのターゲットに書き戻す
+    pub(crate) fn apply_screen_space_reflections(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+    ) -> Result<()> {
+        let settings = self.view_post_process().reflections;
+        if self.screen_space_reflections.is_none()
+            || !settings.enabled
+            || !self.is_pass_enabled("ssr")
+        {
+            return Ok(());
+        }
+        let (environment_view, environment_sampler, environment_max_lod) = match &self.environment {
+            Some(environment) => match &environment.ibl {
+                Some(ibl) => (
+                    ibl.prefiltered.view,
+                    environment.sampler,
+                    (ibl.prefiltered.mip_levels - 1) as f32,
+                ),
+                None => (environment.view, environment.sampler, 0.0),
+            },
+            None => {
+                return Err(RendererError::Validation(
+                    "screen-space reflections need an environment map to fall back to".to_owned(),
+                ))
+            }
+        };
+        let hdr_image = self.enabled_hdr()?.target.image;
+        let hdr_view = self.enabled_hdr()?.target.view;
+        let layout = self.ssr_set_layout()?;
+        let descriptor_set = self.allocate_transient_descriptor_set(layout)?;
+
+        let mut reflections = self.screen_space_reflections.take().unwrap();
+        let inverse = mat4_inverse(&reflections.projection)
+            .zip(mat4_inverse(&reflections.view))
+            .ok_or_else(|| {
+                RendererError::Validation(
+                    "reflection camera matrices are not invertible".to_owned(),
+                )
+            });
+        let written = inverse.and_then(|(inverse_projection, inverse_view)| {
+            let uniform = ScreenSpaceReflectionUniform {
+                projection: reflections.projection,
+                inverse_projection,
+                inverse_view,
+                max_distance: settings.max_distance,
+                thickness: settings.thickness,
+                max_roughness: settings.max_roughness,
+                intensity: settings.intensity,
+                roughness: settings.roughness,
+                max_steps: settings.max_steps,
+                use_surface: reflections.surface.is_some() as u32,
+                far_depth: reflections.far_depth,
+                environment_max_lod,
+            };
+            self.write_per_frame_buffer(&mut reflections.uniform_buffers, &[uniform])
+        });
+        let result = written.and_then(|uniform_buffer| {
+            let sampled = |image_view| vk::DescriptorImageInfo {
+                sampler: reflections.sampler,
+                image_view,
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            };
+            let targets = &reflections.targets;
+            let mut writer = DescriptorWriter::new()
+                .image(
+                    0,
+                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    sampled(hdr_view),
+                )
+                .depth_image(1, targets.depth_view, reflections.sampler);
+            writer = match reflections.surface {
+                Some(surface) => writer.image(
+                    2,
+                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                    sampled(surface),
+                ),
+                None => writer.depth_image(2, targets.depth_view, reflections.sampler),
+            };
+            unsafe {
+                writer
+                    .image(
+                        3,
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::DescriptorImageInfo {
+                            sampler: environment_sampler,
+                            image_view: environment_view,
+                            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                        },
+                    )
+                    .image(
+                        4,
+                        vk::DescriptorType::STORAGE_IMAGE,
+                        vk::DescriptorImageInfo {
+                            sampler: vk::Sampler::null(),
+                            image_view: targets.output.view,
+                            image_layout: vk::ImageLayout::GENERAL,
+                        },
+                    )
+                    .buffer(
+                        5,
+                        vk::DescriptorType::UNIFORM_BUFFER,
+                        self.buffers.get(uniform_buffer).unwrap(),
+                    )
+                    .update(&self.device, descriptor_set);
+            }
+            self.record_screen_space_reflections(
+                command_buffer,
+                &reflections,
+                descriptor_set,
+                hdr_image,
+            )
+        });
+        self.screen_space_reflections = Some(reflections);
+        result
+    }
+
+    fn record_screen_space_reflections(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        reflections: &ScreenSpaceReflections,
+        descriptor_set: vk::DescriptorSet,
+        hdr_image: vk::Image,
+    ) -> Result<()> {
+        let targets = &reflections.targets;
+        unsafe {
+            self.begin_screen_pass(
+                command_buffer,
+                &[targets.output.image],
+                Some(self.depth_image),
+            );
+        }
+        self.dispatch(
+            command_buffer,
+            &reflections.pipeline,
+            &[descriptor_set],
+            &[],
+            [
+                targets
+                    .extent
+                    .width
+                    .div_ceil(SCREEN_SPACE_REFLECTION_GROUP_SIZE),
+                targets
+                    .extent
+                    .height
+                    .div_ceil(SCREEN_SPACE_REFLECTION_GROUP_SIZE),
+                1,
+            ],
+        )?;
+        unsafe {
+            self.restore_depth_attachment(command_buffer, &[]);
+            self.copy_to_hdr(
+                command_buffer,
+                targets.output.image,
+                hdr_image,
+                targets.extent,
+            );
+        }
+        Ok(())
+    }
+
+    fn enabled_screen_space_reflections_mut(&mut self) -> Result<&mut ScreenSpaceReflections> {
+        self.screen_space_reflections.as_mut().ok_or_else(|| {
+            RendererError::Validation("screen-space reflections are not enabled".to_owned())
+        })
+    }
+
+    unsafe fn create_screen_space_reflection_targets(
+        &mut self,
+    ) -> Result<ScreenSpaceReflectionTargets> {
+        let extent = self.enabled_hdr()?.target.extent;
+        let output = create_screen_image(
+            &self.device,
+            &mut self.allocator,
+            HDR_FORMAT,
+            vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC,
+            extent,
+        )?;
+        match create_depth_sampling_view(&self.device, self.depth_image, self.depth_format) {
+            Ok(depth_view) => Ok(ScreenSpaceReflectionTargets {
+                output,
+                depth_view,
+                extent,
+            }),
+            Err(err) => {
+                output.destroy(&self.device, &mut self.allocator);
+                Err(err)
+            }
+        }
+    }
+}
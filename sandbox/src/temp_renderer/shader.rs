@@ -0,0 +1,114 @@
+use super::error::{RendererError, Result};
+use super::handle::Handle;
+use ash::{vk, Device};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+/// SPIR-Vの読み込み元
+#[derive(Debug, Clone, Copy)]
+pub enum ShaderSource<'a> {
+    Path(&'a Path),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> From<&'a Path> for ShaderSource<'a> {
+    fn from(path: &'a Path) -> Self {
+        ShaderSource::Path(path)
+    }
+}
+
+impl<'a> From<&'a PathBuf> for ShaderSource<'a> {
+    fn from(path: &'a PathBuf) -> Self {
+        ShaderSource::Path(path)
+    }
+}
+
+impl<'a> From<&'a str> for ShaderSource<'a> {
+    fn from(path: &'a str) -> Self {
+        ShaderSource::Path(Path::new(path))
+    }
+}
+
+impl<'a> From<&'a [u8]> for ShaderSource<'a> {
+    fn from(bytes: &'a [u8]) -> Self {
+        ShaderSource::Bytes(bytes)
+    }
+}
+
+impl<'a, const N: usize> From<&'a [u8; N]> for ShaderSource<'a> {
+    fn from(bytes: &'a [u8; N]) -> Self {
+        ShaderSource::Bytes(bytes)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ShaderModule {
+    pub module: vk::ShaderModule,
+    /// ファイルから読み込んだ場合のパス。ホットリロードの監視対象になる
+    pub path: Option<PathBuf>,
+    pub modified: Option<SystemTime>,
+}
+
+pub type ShaderId = Handle<ShaderModule>;
+
+/// SPIR-Vのバイト列を検証して`u32`列に変換する
+///
+/// `include_bytes!`で埋め込んだデータは4バイトアラインされているとは限らないのでコピーする。
+pub fn read_spirv(bytes: &[u8]) -> Result<Vec<u32>> {
+    if bytes.is_empty() || !bytes.len().is_multiple_of(4) {
+        return Err(RendererError::InvalidSpirv(format!(
+            "size {} is not a non-zero multiple of 4",
+            bytes.len()
+        )));
+    }
+    let code = ash::util::read_spv(&mut Cursor::new(bytes))
+        .map_err(|err| RendererError::InvalidSpirv(err.to_string()))?;
+    if code[0] != SPIRV_MAGIC {
+        return Err(RendererError::InvalidSpirv(format!(
+            "bad magic number {:#010x}",
+            code[0]
+        )));
+    }
+    Ok(code)
+}
+
+pub(crate) unsafe fn create_shader_module(
+    device: &Device,
+    bytes: &[u8],
+) -> Result<vk::ShaderModule> {
+    let code = read_spirv(bytes)?;
+    let create_info = *vk::ShaderModuleCreateInfo::builder().code(&code);
+    Ok(device.create_shader_module(&create_info, None)?)
+}
+
+pub(crate) unsafe fn load_shader_module(
+    device: &Device,
+    source: ShaderSource,
+) -> Result<ShaderModule> {
+    match source {
+        ShaderSource::Path(path) => {
+            // 読み込み中に書き換えられても次のポーリングで拾えるよう、先に更新時刻を取る
+            let modified = file_modified(path);
+            let bytes = std::fs::read(path)?;
+            Ok(ShaderModule {
+                module: create_shader_module(device, &bytes)?,
+                path: Some(path.to_owned()),
+                modified,
+            })
+        }
+        ShaderSource::Bytes(bytes) => Ok(ShaderModule {
+            module: create_shader_module(device, bytes)?,
+            path: None,
+            modified: None,
+        }),
+    }
+}
+
+pub(crate) fn file_modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
@@ -0,0 +1,132 @@
+use super::error::Result;
+use std::path::Path;
+
+/// シェーダーライブラリのバージョン。GLSLでは`TEMPURA_SHADER_LIBRARY_VERSION`で参照できる
+///
+/// 関数の名前や引数を変えるなど、互換性のない変更をしたら上げる。
+pub const SHADER_LIBRARY_VERSION: u32 = 1;
+
+/// シェーダーライブラリの1ファイル
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShaderInclude {
+    /// `#include`で指定する名前。`tempura/`から始まる
+    pub name: &'static str,
+    pub source: &'static str,
+}
+
+/// 組み込みのシェーダーライブラリ
+///
+/// - `tempura/common.glsl`: バージョン、円周率、輝度などの共通の定義
+/// - `tempura/brdf.glsl`: LambertとGGXのBRDF
+/// - `tempura/tonemap.glsl`: [`ToneMapOperator`](super::ToneMapOperator)と同じトーンマッピングとsRGBの変換
+/// - `tempura/packing.glsl`: 八面体写像の法線とR9G9B9E5の色のパック、描画物が受け取る効果のフラグ
+/// - `tempura/noise.glsl`: インターリーブドグラディエントノイズ、ハッシュ、Halton列、値ノイズ
+/// - `tempura/shadow.glsl`: カスケードの選択とPCF、ポイントシャドウの比較
+/// - `tempura/displacement.glsl`: 風、旗、呼吸の頂点の変位
+/// - `tempura/clustered.glsl`: クラスター化したライトの一覧の参照
+/// - `tempura/oit.glsl`: 重み付きブレンドの順序によらない透明の重みと出力
+/// - `tempura/lod.glsl`: LODのクロスフェードのディザ
+/// - `tempura/ies.glsl`: IESのプロファイルによるライトの角度ごとの減衰
+/// - `tempura/hdr_output.glsl`: PQとscRGBのHDRの出力の変換
+/// - `tempura/color_vision.glsl`: UIへの色覚のフィルター
+/// - `tempura/particles.glsl`: GPUパーティクルの構造体、乱数、カールノイズ
+/// - `tempura/toon.glsl`: セルシェーディングの陰影と輪郭線の検出
+///
+/// 関数と定数には`tempura_`と`TEMPURA_`の接頭辞が付く。ファイル同士も`#include`で
+/// 依存するので、`GL_GOOGLE_include_directive`が使えるコンパイラーで読み込む。
+///
+/// ```glsl
+/// #version 450
+/// #extension GL_GOOGLE_include_directive : require
+/// #include "tempura/brdf.glsl"
+/// #include "tempura/tonemap.glsl"
+/// ```
+pub const SHADER_INCLUDES: &[ShaderInclude] = &[
+    ShaderInclude {
+        name: "tempura/common.glsl",
+        source: include_str!("shaders/tempura/common.glsl"),
+    },
+    ShaderInclude {
+        name: "tempura/brdf.glsl",
+        source: include_str!("shaders/tempura/brdf.glsl"),
+    },
+    ShaderInclude {
+        name: "tempura/tonemap.glsl",
+        source: include_str!("shaders/tempura/tonemap.glsl"),
+    },
+    ShaderInclude {
+        name: "tempura/packing.glsl",
+        source: include_str!("shaders/tempura/packing.glsl"),
+    },
+    ShaderInclude {
+        name: "tempura/noise.glsl",
+        source: include_str!("shaders/tempura/noise.glsl"),
+    },
+    ShaderInclude {
+        name: "tempura/shadow.glsl",
+        source: include_str!("shaders/tempura/shadow.glsl"),
+    },
+    ShaderInclude {
+        name: "tempura/displacement.glsl",
+        source: include_str!("shaders/tempura/displacement.glsl"),
+    },
+    ShaderInclude {
+        name: "tempura/clustered.glsl",
+        source: include_str!("shaders/tempura/clustered.glsl"),
+    },
+    ShaderInclude {
+        name: "tempura/oit.glsl",
+        source: include_str!("shaders/tempura/oit.glsl"),
+    },
+    ShaderInclude {
+        name: "tempura/lod.glsl",
+        source: include_str!("shaders/tempura/lod.glsl"),
+    },
+    ShaderInclude {
+        name: "tempura/ies.glsl",
+        source: include_str!("shaders/tempura/ies.glsl"),
+    },
+    ShaderInclude {
+        name: "tempura/hdr_output.glsl",
+        source: include_str!("shaders/tempura/hdr_output.glsl"),
+    },
+    ShaderInclude {
+        name: "tempura/color_vision.glsl",
+        source: include_str!("shaders/tempura/color_vision.glsl"),
+    },
+    ShaderInclude {
+        name: "tempura/particles.glsl",
+        source: include_str!("shaders/tempura/particles.glsl"),
+    },
+    ShaderInclude {
+        name: "tempura/toon.glsl",
+        source: include_str!("shaders/tempura/toon.glsl"),
+    },
+];
+
+/// `#include`の名前からシェーダーライブラリのソースを返す
+///
+/// shadercのインクルードのコールバックなどから呼ぶ。ライブラリの外の名前には`None`を返すので、
+/// その場合はアプリケーションのファイルを探す。
+pub fn shader_include(name: &str) -> Option<&'static str> {
+    let name = name.trim_start_matches("./");
+    SHADER_INCLUDES
+        .iter()
+        .find(|include| include.name == name)
+        .map(|include| include.source)
+}
+
+/// シェーダーライブラリを`dir`の下に書き出す
+///
+/// `glslc -I <dir>`のように、ファイルからインクルードを解決するコンパイラーで使う。
+/// 既存のファイルは上書きする。
+pub fn write_shader_library(dir: &Path) -> Result<()> {
+    for include in SHADER_INCLUDES {
+        let path = dir.join(include.name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, include.source)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,58 @@
+// Lambertの拡散反射とGGXの鏡面反射。roughnessは知覚的な粗さ(alpha = roughness^2)
+#ifndef TEMPURA_BRDF_GLSL
+#define TEMPURA_BRDF_GLSL
+
+#include "tempura/common.glsl"
+
+// 法線分布関数
+float tempura_d_ggx(float n_dot_h, float roughness) {
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (TEMPURA_PI * d * d);
+}
+
+// 高さ相関のSmithの可視性関数。G / (4 n.l n.v)まで含む
+float tempura_v_smith_ggx_correlated(float n_dot_v, float n_dot_l, float roughness) {
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float gv = n_dot_l * sqrt(n_dot_v * n_dot_v * (1.0 - a2) + a2);
+    float gl = n_dot_v * sqrt(n_dot_l * n_dot_l * (1.0 - a2) + a2);
+    return 0.5 / max(gv + gl, TEMPURA_EPSILON);
+}
+
+vec3 tempura_f_schlick(float v_dot_h, vec3 f0) {
+    return f0 + (1.0 - f0) * pow(1.0 - v_dot_h, 5.0);
+}
+
+// IBLの鏡面反射用。粗いほど縁のフレネルを弱める
+vec3 tempura_f_schlick_roughness(float n_dot_v, vec3 f0, float roughness) {
+    return f0 + (max(vec3(1.0 - roughness), f0) - f0) * pow(1.0 - n_dot_v, 5.0);
+}
+
+float tempura_lambert() {
+    return 1.0 / TEMPURA_PI;
+}
+
+// 金属性のワークフローの反射率。誘電体のF0は0.04
+vec3 tempura_f0(vec3 base_color, float metallic) {
+    return mix(vec3(0.04), base_color, metallic);
+}
+
+// 1つのライトからの反射。nとvとlは正規化済みで、戻り値にライトの放射輝度を掛ける
+vec3 tempura_brdf(vec3 n, vec3 v, vec3 l, vec3 base_color, float metallic, float roughness) {
+    vec3 h = normalize(v + l);
+    float n_dot_v = max(dot(n, v), TEMPURA_EPSILON);
+    float n_dot_l = tempura_saturate(dot(n, l));
+    float n_dot_h = tempura_saturate(dot(n, h));
+    float v_dot_h = tempura_saturate(dot(v, h));
+    // alphaが0だと鏡面反射が発散する
+    roughness = max(roughness, 0.045);
+    vec3 f = tempura_f_schlick(v_dot_h, tempura_f0(base_color, metallic));
+    vec3 specular = f * tempura_d_ggx(n_dot_h, roughness) *
+                    tempura_v_smith_ggx_correlated(n_dot_v, n_dot_l, roughness);
+    vec3 diffuse = (1.0 - f) * (1.0 - metallic) * base_color * tempura_lambert();
+    return (diffuse + specular) * n_dot_l;
+}
+
+#endif
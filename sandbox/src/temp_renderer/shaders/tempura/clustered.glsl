@@ -0,0 +1,22 @@
+// クラスター化したライトの一覧の参照。バッファの配置はClusteredLightingのドキュメントを参照
+#ifndef TEMPURA_CLUSTERED_GLSL
+#define TEMPURA_CLUSTERED_GLSL
+
+#include "tempura/common.glsl"
+
+// frag_coordはgl_FragCoord.xy、view_depthはカメラの前方向の距離。
+// gridとparamsはクラスターのバッファの先頭のcluster_gridとcluster_params
+uint tempura_cluster_index(vec2 frag_coord, float view_depth, uvec4 grid, vec4 params) {
+    uvec2 tile = min(uvec2(frag_coord * params.zw * vec2(grid.xy)), grid.xy - 1u);
+    // 深度は指数的に分割する。nearより手前とfarより奥は端のスライスに入れる
+    float slice = log(max(view_depth, params.x) / params.x) / log(params.y / params.x);
+    uint z = min(uint(max(slice, 0.0) * float(grid.z)), grid.z - 1u);
+    return tile.x + grid.x * (tile.y + grid.y * z);
+}
+
+// クラスターの一覧の、そのクラスターの先頭(ライトの数)の位置
+uint tempura_cluster_offset(uint cluster, uvec4 grid) {
+    return cluster * (grid.w + 1u);
+}
+
+#endif
@@ -0,0 +1,14 @@
+// UIに色覚のフィルターをかける。行列はRenderer::color_vision_ui_matrixの値
+#ifndef TEMPURA_COLOR_VISION_GLSL
+#define TEMPURA_COLOR_VISION_GLSL
+
+#include "tempura/tonemap.glsl"
+
+// `color`はsRGBのガンマをかけた0から1の色。SRGBフォーマットのスワップチェインに書く
+// シェーダーでは、リニアな色に`(matrix * vec4(c, 0.0)).rgb`を直接掛けてもよい
+vec3 tempura_color_vision(vec3 color, mat4 matrix) {
+    vec3 linear = tempura_srgb_to_linear(color);
+    return tempura_linear_to_srgb(clamp((matrix * vec4(linear, 0.0)).rgb, 0.0, 1.0));
+}
+
+#endif
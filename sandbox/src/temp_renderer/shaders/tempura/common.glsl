@@ -0,0 +1,23 @@
+// 他のファイルから読み込まれる共通の定義
+#ifndef TEMPURA_COMMON_GLSL
+#define TEMPURA_COMMON_GLSL
+
+// SHADER_LIBRARY_VERSIONと同じ値。互換性のない変更をしたら上げる
+#define TEMPURA_SHADER_LIBRARY_VERSION 1
+
+const float TEMPURA_PI = 3.14159265359;
+const float TEMPURA_EPSILON = 1e-5;
+
+float tempura_saturate(float x) {
+    return clamp(x, 0.0, 1.0);
+}
+
+vec3 tempura_saturate(vec3 x) {
+    return clamp(x, vec3(0.0), vec3(1.0));
+}
+
+float tempura_luminance(vec3 color) {
+    return dot(color, vec3(0.2126, 0.7152, 0.0722));
+}
+
+#endif
@@ -0,0 +1,27 @@
+// 頂点の変位。オブジェクト空間の位置を受け取り、変位後の位置を返す
+#ifndef TEMPURA_DISPLACEMENT_GLSL
+#define TEMPURA_DISPLACEMENT_GLSL
+
+#include "tempura/common.glsl"
+
+// 原点からの高さ(y)の2乗に比例してdirectionに揺らす。草木向け
+vec3 tempura_wind(vec3 position, vec3 direction, float strength, float frequency, float time) {
+    float phase = time * frequency * 2.0 * TEMPURA_PI + dot(position.xz, vec2(0.7, 0.3));
+    float sway = sin(phase) + 0.3 * sin(phase * 2.7);
+    float height = max(position.y, 0.0);
+    return position + direction * (strength * sway * height * height);
+}
+
+// UVのuに沿って進む波を法線の方向に加える。u = 0の辺(竿の側)は動かない
+vec3 tempura_flag_wave(vec3 position, vec3 normal, vec2 uv, float amplitude, float wavelength,
+                       float speed, float time) {
+    float phase = (uv.x / wavelength - time * speed) * 2.0 * TEMPURA_PI;
+    return position + normal * (amplitude * uv.x * sin(phase));
+}
+
+// 法線の方向に周期的に膨らませる。rateは1秒あたりの回数
+vec3 tempura_breathing(vec3 position, vec3 normal, float amplitude, float rate, float time) {
+    return position + normal * (amplitude * (0.5 - 0.5 * cos(time * rate * 2.0 * TEMPURA_PI)));
+}
+
+#endif
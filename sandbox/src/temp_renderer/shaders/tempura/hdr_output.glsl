@@ -0,0 +1,49 @@
+// HDRのスワップチェインへの出力の変換。色はBT.709の原色のリニアな値で、単位はnit
+#ifndef TEMPURA_HDR_OUTPUT_GLSL
+#define TEMPURA_HDR_OUTPUT_GLSL
+
+#include "tempura/common.glsl"
+
+#define TEMPURA_OUTPUT_SRGB 0
+#define TEMPURA_OUTPUT_PQ 1
+#define TEMPURA_OUTPUT_SCRGB 2
+
+// scRGBの1.0に当たる輝度
+#define TEMPURA_SCRGB_WHITE_NITS 80.0
+// PQで表せる最大の輝度
+#define TEMPURA_PQ_MAX_NITS 10000.0
+
+vec3 tempura_bt709_to_bt2020(vec3 c) {
+    const mat3 m = mat3(
+        0.6274, 0.0691, 0.0164,
+        0.3293, 0.9195, 0.0880,
+        0.0433, 0.0114, 0.8956);
+    return m * c;
+}
+
+// SMPTE ST 2084の逆EOTF。`nits`は0から10000
+vec3 tempura_pq_encode(vec3 nits) {
+    const float m1 = 0.1593017578125, m2 = 78.84375;
+    const float c1 = 0.8359375, c2 = 18.8515625, c3 = 18.6875;
+    vec3 y = pow(clamp(nits / TEMPURA_PQ_MAX_NITS, 0.0, 1.0), vec3(m1));
+    return pow((c1 + c2 * y) / (1.0 + c3 * y), vec3(m2));
+}
+
+vec3 tempura_pq_decode(vec3 encoded) {
+    const float m1 = 0.1593017578125, m2 = 78.84375;
+    const float c1 = 0.8359375, c2 = 18.8515625, c3 = 18.6875;
+    vec3 e = pow(clamp(encoded, 0.0, 1.0), vec3(1.0 / m2));
+    return pow(max(e - c1, 0.0) / (c2 - c3 * e), vec3(1.0 / m1)) * TEMPURA_PQ_MAX_NITS;
+}
+
+// OutputEncoding::indexの番号でスワップチェインに書く値にする。
+// SDRでは`paper_white_nits`を1とするリニアな値を返すので、SRGBフォーマットのスワップチェインに書く
+vec3 tempura_encode_output(vec3 nits, uint encoding, float paper_white_nits) {
+    switch (encoding) {
+    case TEMPURA_OUTPUT_PQ: return tempura_pq_encode(tempura_bt709_to_bt2020(max(nits, 0.0)));
+    case TEMPURA_OUTPUT_SCRGB: return nits / TEMPURA_SCRGB_WHITE_NITS;
+    default: return tempura_saturate(nits / paper_white_nits);
+    }
+}
+
+#endif
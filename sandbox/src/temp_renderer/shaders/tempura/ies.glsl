@@ -0,0 +1,19 @@
+// IESのプロファイルによるライトの角度ごとの減衰
+#ifndef TEMPURA_IES_GLSL
+#define TEMPURA_IES_GLSL
+
+#include "tempura/common.glsl"
+
+// lは光源から表面への向き、directionはライトの向き。layerが負ならプロファイルなしで1を返す。
+// 水平角はライトの向きとワールドのYから作る基準方向から測る
+float tempura_ies_attenuation(sampler2DArray profiles, float layer, vec3 l, vec3 direction) {
+    if (layer < 0.0) return 1.0;
+    vec3 up = abs(direction.y) < 0.999 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+    vec3 x = normalize(cross(up, direction));
+    vec3 y = cross(direction, x);
+    float vertical = acos(clamp(dot(l, direction), -1.0, 1.0)) / TEMPURA_PI;
+    float horizontal = fract(atan(dot(l, y), dot(l, x)) / (2.0 * TEMPURA_PI) + 1.0);
+    return texture(profiles, vec3(horizontal, vertical, layer)).r;
+}
+
+#endif
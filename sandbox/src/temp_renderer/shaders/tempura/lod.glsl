@@ -0,0 +1,14 @@
+// LODの段を切り替えるときのクロスフェード
+#ifndef TEMPURA_LOD_GLSL
+#define TEMPURA_LOD_GLSL
+
+#include "tempura/noise.glsl"
+
+// 画素を捨てるなら`true`。`fade`はLodFade::ditherの値で、細かい段では正、粗い段では負になる。
+// 同じ画素で同じノイズを使うので、2つの段はちょうど画素を分け合う
+bool tempura_lod_dither(vec2 pixel, float fade) {
+    float noise = tempura_interleaved_gradient_noise(pixel);
+    return fade >= 0.0 ? noise < fade : noise >= -fade;
+}
+
+#endif
@@ -0,0 +1,55 @@
+// 乱数とディザ用のノイズ
+#ifndef TEMPURA_NOISE_GLSL
+#define TEMPURA_NOISE_GLSL
+
+#include "tempura/common.glsl"
+
+// 画素の座標から[0, 1)の値を作る。隣接画素で値が散るのでTAAやブラーと相性がよい
+float tempura_interleaved_gradient_noise(vec2 pixel) {
+    return fract(52.9829189 * fract(dot(pixel, vec2(0.06711056, 0.00583715))));
+}
+
+// フレームごとにずらす版。黄金比ずつ回す
+float tempura_interleaved_gradient_noise(vec2 pixel, uint frame) {
+    return fract(tempura_interleaved_gradient_noise(pixel) + float(frame) * 0.61803399);
+}
+
+// PCGのハッシュ
+uint tempura_hash(uint x) {
+    uint state = x * 747796405u + 2891336453u;
+    uint word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+uint tempura_hash(uvec2 v) {
+    return tempura_hash(v.x ^ tempura_hash(v.y));
+}
+
+// [0, 1)の一様乱数
+float tempura_random(uvec2 v) {
+    return float(tempura_hash(v) >> 8) / 16777216.0;
+}
+
+// 基数baseのファンデルコルプト列のindex番目
+float tempura_halton(uint index, uint base) {
+    float f = 1.0, r = 0.0;
+    for (uint i = index; i > 0u; i /= base) {
+        f /= float(base);
+        r += f * float(i % base);
+    }
+    return r;
+}
+
+// 2次元の値ノイズ。戻り値は[0, 1]
+float tempura_value_noise(vec2 p) {
+    uvec2 i = uvec2(ivec2(floor(p)));
+    vec2 f = fract(p);
+    vec2 u = f * f * (3.0 - 2.0 * f);
+    float a = tempura_random(i);
+    float b = tempura_random(i + uvec2(1u, 0u));
+    float c = tempura_random(i + uvec2(0u, 1u));
+    float d = tempura_random(i + uvec2(1u, 1u));
+    return mix(mix(a, b, u.x), mix(c, d, u.x), u.y);
+}
+
+#endif
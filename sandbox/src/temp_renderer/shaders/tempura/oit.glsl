@@ -0,0 +1,19 @@
+// 重み付きブレンドの順序によらない透明(McGuire and Bavoil 2013)
+#ifndef TEMPURA_OIT_GLSL
+#define TEMPURA_OIT_GLSL
+
+// depthはgl_FragCoord.z。手前で不透明なほど大きな重みにする
+float tempura_oit_weight(float depth, float alpha) {
+    float a = min(1.0, alpha * 10.0) + 0.01;
+    float d = 1.0 - depth * 0.9;
+    return clamp(a * a * a * 1e8 * d * d * d, 1e-2, 3e3);
+}
+
+// 透明なパスのフラグメントシェーダーの出力。colorはリニアでアルファを掛けていない色
+void tempura_oit_output(vec4 color, float depth, out vec4 accumulation, out float revealage) {
+    float w = tempura_oit_weight(depth, color.a);
+    accumulation = vec4(color.rgb * color.a, color.a) * w;
+    revealage = color.a;
+}
+
+#endif
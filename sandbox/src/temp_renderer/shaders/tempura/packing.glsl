@@ -0,0 +1,73 @@
+// G-bufferやストレージバッファに詰めるための変換
+#ifndef TEMPURA_PACKING_GLSL
+#define TEMPURA_PACKING_GLSL
+
+#include "tempura/common.glsl"
+
+vec2 tempura_sign_not_zero(vec2 v) {
+    return vec2(v.x >= 0.0 ? 1.0 : -1.0, v.y >= 0.0 ? 1.0 : -1.0);
+}
+
+// 正規化された法線を[-1, 1]の2成分に八面体写像で詰める
+vec2 tempura_encode_octahedral(vec3 n) {
+    vec2 p = n.xy / (abs(n.x) + abs(n.y) + abs(n.z));
+    return n.z >= 0.0 ? p : (1.0 - abs(p.yx)) * tempura_sign_not_zero(p);
+}
+
+vec3 tempura_decode_octahedral(vec2 e) {
+    vec3 n = vec3(e, 1.0 - abs(e.x) - abs(e.y));
+    float t = tempura_saturate(-n.z);
+    n.xy += vec2(n.x >= 0.0 ? -t : t, n.y >= 0.0 ? -t : t);
+    return normalize(n);
+}
+
+// 八面体写像した法線を16ビットずつ1つのuintに詰める
+uint tempura_pack_normal(vec3 n) {
+    return packSnorm2x16(tempura_encode_octahedral(n));
+}
+
+vec3 tempura_unpack_normal(uint packed) {
+    return tempura_decode_octahedral(unpackSnorm2x16(packed));
+}
+
+// HDRの色を共有指数の32ビットにする(R9G9B9E5)
+uint tempura_pack_rgb9e5(vec3 color) {
+    const float max_value = 65408.0;
+    vec3 c = clamp(color, vec3(0.0), vec3(max_value));
+    float max_channel = max(c.r, max(c.g, c.b));
+    int exponent = max(-16, int(floor(log2(max(max_channel, 1e-10))))) + 16;
+    float scale = exp2(float(exponent - 15 - 9));
+    if (uint(floor(max_channel / scale + 0.5)) == 512u) {
+        exponent += 1;
+        scale *= 2.0;
+    }
+    uvec3 m = uvec3(floor(c / scale + 0.5));
+    return m.r | (m.g << 9) | (m.b << 18) | (uint(exponent) << 27);
+}
+
+vec3 tempura_unpack_rgb9e5(uint packed) {
+    float scale = exp2(float(int(packed >> 27) - 15 - 9));
+    return vec3(packed & 511u, (packed >> 9) & 511u, (packed >> 18) & 511u) * scale;
+}
+
+// 描画物が受け取る効果のフラグ。ReceiveFlagsと同じ値
+#define TEMPURA_RECEIVE_DECALS 1u
+#define TEMPURA_RECEIVE_FOG 2u
+#define TEMPURA_RECEIVE_AMBIENT_OCCLUSION 4u
+#define TEMPURA_RECEIVE_LIGHTMAPS 8u
+#define TEMPURA_RECEIVE_OUTLINE 16u
+
+// フラグをUNORM8のチャンネルに書き込める値にする
+float tempura_pack_receive_flags(uint flags) {
+    return float(flags & 255u) / 255.0;
+}
+
+uint tempura_unpack_receive_flags(float packed) {
+    return uint(packed * 255.0 + 0.5);
+}
+
+bool tempura_receives(uint flags, uint flag) {
+    return (flags & flag) != 0u;
+}
+
+#endif
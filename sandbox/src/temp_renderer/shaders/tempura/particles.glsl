@@ -0,0 +1,48 @@
+// GPUパーティクルのデータと、発生とシミュレーションの補助
+#ifndef TEMPURA_PARTICLES_GLSL
+#define TEMPURA_PARTICLES_GLSL
+
+#include "tempura/noise.glsl"
+
+// ParticleEmitterのパーティクルのバッファの要素。std430で64バイト
+struct TempuraParticle {
+    vec4 position_age;      // xyzが位置、wが経過時間
+    vec4 velocity_lifetime; // xyzが速度、wが寿命
+    vec4 start_color;
+    vec4 end_color;
+};
+
+// [0, 1)の一様乱数を3つ
+vec3 tempura_random3(uint seed, uint index) {
+    return vec3(
+        tempura_random(uvec2(index, seed)),
+        tempura_random(uvec2(index, seed ^ 0x9e3779b9u)),
+        tempura_random(uvec2(index, seed ^ 0x85ebca6bu)));
+}
+
+// 半径1の球の中の一様な点
+vec3 tempura_random_in_sphere(uint seed, uint index) {
+    vec3 r = tempura_random3(seed, index);
+    float z = r.x * 2.0 - 1.0, phi = r.y * 2.0 * TEMPURA_PI;
+    vec3 direction = vec3(sqrt(1.0 - z * z) * vec2(cos(phi), sin(phi)), z);
+    return direction * pow(r.z, 1.0 / 3.0);
+}
+
+// 2次元の値ノイズを3つの面に並べたベクトルポテンシャル
+vec3 tempura_noise_potential(vec3 p) {
+    return vec3(
+        tempura_value_noise(p.yz),
+        tempura_value_noise(p.zx + vec2(31.4, 47.2)),
+        tempura_value_noise(p.xy + vec2(17.1, 89.5)));
+}
+
+// ポテンシャルの回転。発散がないので、渦を巻いてもパーティクルが1点に集まらない
+vec3 tempura_curl_noise(vec3 p) {
+    const float e = 0.1;
+    vec3 dx = tempura_noise_potential(p + vec3(e, 0.0, 0.0)) - tempura_noise_potential(p - vec3(e, 0.0, 0.0));
+    vec3 dy = tempura_noise_potential(p + vec3(0.0, e, 0.0)) - tempura_noise_potential(p - vec3(0.0, e, 0.0));
+    vec3 dz = tempura_noise_potential(p + vec3(0.0, 0.0, e)) - tempura_noise_potential(p - vec3(0.0, 0.0, e));
+    return vec3(dy.z - dz.y, dz.x - dx.z, dx.y - dy.x) / (2.0 * e);
+}
+
+#endif
@@ -0,0 +1,52 @@
+// シャドウマップのサンプリング。ディレクショナルライトはセット2、ポイントライトはセット3の
+// レイアウトを前提に、サンプラーを引数で受け取る
+#ifndef TEMPURA_SHADOW_GLSL
+#define TEMPURA_SHADOW_GLSL
+
+#include "tempura/common.glsl"
+
+// view_depthはカメラからの距離。cascade_splits[i]以下になる最初のカスケードを返す
+uint tempura_select_cascade(float view_depth, vec4 cascade_splits, uint cascade_count) {
+    for (uint i = 0u; i + 1u < cascade_count; ++i) {
+        if (view_depth <= cascade_splits[i]) {
+            return i;
+        }
+    }
+    return cascade_count - 1u;
+}
+
+// 比較サンプラーで(2 * pcf_radius + 1)^2回フェッチして平均する。1回のフェッチが2x2のPCFになる
+float tempura_shadow_pcf(sampler2DArrayShadow shadow_map, vec4 light_clip, uint cascade,
+                         float texel_size, float pcf_radius) {
+    vec3 p = light_clip.xyz / light_clip.w;
+    vec2 uv = p.xy * 0.5 + 0.5;
+    // シャドウマップの外は影にしない
+    if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0))) || p.z > 1.0) {
+        return 1.0;
+    }
+    int radius = int(pcf_radius);
+    float sum = 0.0;
+    for (int y = -radius; y <= radius; ++y) {
+        for (int x = -radius; x <= radius; ++x) {
+            vec2 offset = vec2(x, y) * texel_size;
+            sum += texture(shadow_map, vec4(uv + offset, float(cascade), p.z));
+        }
+    }
+    float side = float(2 * radius + 1);
+    return sum / (side * side);
+}
+
+// ポイントシャドウの比較値。depth_paramsはGpuPointShadowのdepth_params
+float tempura_point_shadow_reference(vec3 light_to_position, vec4 depth_params) {
+    vec3 d = abs(light_to_position);
+    float z = max(d.x, max(d.y, d.z));
+    return depth_params.x + depth_params.y / z;
+}
+
+float tempura_point_shadow(samplerCubeShadow shadow_map, vec3 light_to_position,
+                           vec4 depth_params) {
+    return texture(shadow_map,
+                   vec4(light_to_position, tempura_point_shadow_reference(light_to_position, depth_params)));
+}
+
+#endif
@@ -0,0 +1,47 @@
+// ToneMapOperatorと同じトーンマッピング。入力は露出を掛けたリニアの色
+#ifndef TEMPURA_TONEMAP_GLSL
+#define TEMPURA_TONEMAP_GLSL
+
+#include "tempura/common.glsl"
+
+#define TEMPURA_TONEMAP_REINHARD 0
+#define TEMPURA_TONEMAP_ACES 1
+#define TEMPURA_TONEMAP_FILMIC 2
+
+vec3 tempura_tonemap_reinhard(vec3 c) {
+    return c / (1.0 + c);
+}
+
+// Narkowiczの近似
+vec3 tempura_tonemap_aces(vec3 c) {
+    return tempura_saturate((c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14));
+}
+
+vec3 tempura_uncharted2_curve(vec3 x) {
+    const float a = 0.15, b = 0.50, c = 0.10, d = 0.20, e = 0.02, f = 0.30;
+    return ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f;
+}
+
+// Uncharted 2のカーブ。白は11.2
+vec3 tempura_tonemap_filmic(vec3 c) {
+    return tempura_uncharted2_curve(c) / tempura_uncharted2_curve(vec3(11.2));
+}
+
+// ToneMapOperator::indexの番号で選ぶ
+vec3 tempura_tonemap(vec3 c, uint operator_index) {
+    switch (operator_index) {
+    case TEMPURA_TONEMAP_REINHARD: return tempura_tonemap_reinhard(c);
+    case TEMPURA_TONEMAP_FILMIC: return tempura_tonemap_filmic(c);
+    default: return tempura_tonemap_aces(c);
+    }
+}
+
+vec3 tempura_linear_to_srgb(vec3 c) {
+    return mix(c * 12.92, 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, step(vec3(0.0031308), c));
+}
+
+vec3 tempura_srgb_to_linear(vec3 c) {
+    return mix(c / 12.92, pow((c + 0.055) / 1.055, vec3(2.4)), step(vec3(0.04045), c));
+}
+
+#endif
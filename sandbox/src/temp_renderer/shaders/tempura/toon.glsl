@@ -0,0 +1,41 @@
+// セルシェーディングの陰影と、輪郭線の検出
+#ifndef TEMPURA_TOON_GLSL
+#define TEMPURA_TOON_GLSL
+
+#include "tempura/common.glsl"
+
+// ShadingModel::indexと同じ値
+#define TEMPURA_SHADING_PBR 0u
+#define TEMPURA_SHADING_CEL 1u
+
+// ランバートの明るさを影と`bands`段の光に分ける。`softness`はn・lの単位で段の境目をぼかす幅
+float tempura_cel_bands(float n_dot_l, uint bands, float softness) {
+    float b = float(max(bands, 1u));
+    float x = tempura_saturate(n_dot_l) * b;
+    float edge = smoothstep(0.0, max(softness * b, 1e-4), fract(x));
+    return min(floor(x) + edge, b) / b;
+}
+
+// 光の当たっている側の輪郭を明るくするリムライトの強さ
+float tempura_cel_rim(vec3 n, vec3 v, float n_dot_l, float strength) {
+    float rim = 1.0 - tempura_saturate(dot(n, v));
+    return strength * smoothstep(0.6, 0.7, rim) * step(0.0, n_dot_l);
+}
+
+// 中央と上下左右のカメラからの距離の差から、深度の輪郭の強さを0から1で返す。
+// 差は距離に対する比なので、遠くの面が斜めになっても輪郭になりにくい
+float tempura_outline_depth_edge(float center, vec4 neighbors, float threshold) {
+    vec4 difference = abs(neighbors - center) / max(center, TEMPURA_EPSILON);
+    float edge = max(max(difference.x, difference.y), max(difference.z, difference.w));
+    return smoothstep(threshold, threshold * 1.5, edge);
+}
+
+// 中央と上下左右の法線の角度の差から、折れ目の輪郭の強さを0から1で返す。
+// `threshold`は1 - cos(角度)
+float tempura_outline_normal_edge(vec3 center, vec3 n0, vec3 n1, vec3 n2, vec3 n3, float threshold) {
+    vec4 difference = 1.0 - vec4(dot(center, n0), dot(center, n1), dot(center, n2), dot(center, n3));
+    float edge = max(max(difference.x, difference.y), max(difference.z, difference.w));
+    return smoothstep(threshold, threshold * 1.5, edge);
+}
+
+#endif
@@ -0,0 +1,601 @@
+use super::assets::ObjVertex;
+use super::buffer::write_buffer;
+use super::error::{RendererError, Result};
+use super::memory::{Allocation, MemoryAllocator};
+use super::texture::create_image;
+use super::{
+    BufferId, Camera, DescriptorBinding, DescriptorWriter, GraphImage, ImageAccess, ImportedImage,
+    Mat4, PassContext, PbrPushConstants, PipelineBuilder, Projection, RenderGraph, Renderer,
+    RenderingAttachment, ShaderId,
+};
+use ash::{vk, Device};
+
+/// カスケードの最大数
+pub const MAX_SHADOW_CASCADES: usize = 4;
+
+/// シャドウマップの解像度とバイアス、サンプリングの設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowSettings {
+    /// シャドウマップの幅と高さ
+    pub resolution: u32,
+    /// 深度バイアスの定数項
+    pub depth_bias_constant: f32,
+    /// 深度バイアスの傾きに比例する項
+    pub depth_bias_slope: f32,
+    /// PCFで参照する範囲(テクセル)。1なら3x3
+    pub pcf_radius: u32,
+    /// カスケードの数。1から[`MAX_SHADOW_CASCADES`]まで。広いシーンでは3か4にする
+    pub cascade_count: u32,
+    /// カスケードの分割位置の、対数分割と均等分割の混ぜ具合。1なら対数分割だけ
+    pub cascade_split_lambda: f32,
+    /// カメラからこの距離までに影を落とす。遠くの平面がない射影でも使う
+    pub max_distance: f32,
+    /// ピクセルをカスケードの番号で色付けするデバッグ表示
+    pub debug_cascades: bool,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 2048,
+            depth_bias_constant: 1.25,
+            depth_bias_slope: 1.75,
+            pcf_radius: 1,
+            cascade_count: 1,
+            cascade_split_lambda: 0.75,
+            max_distance: 100.0,
+            debug_cascades: false,
+        }
+    }
+}
+
+/// シャドウのユニフォームバッファの内容。std140と同じ配置
+///
+/// ```glsl
+/// layout(set = 2, binding = 0) uniform Shadow {
+///     mat4 light_view_projections[4];
+///     vec4 cascade_splits; // カスケードiが受け持つビュー空間の距離の終わり
+///     float texel_size;
+///     float pcf_radius;
+///     uint cascade_count;
+///     uint debug_cascades;
+/// };
+/// layout(set = 2, binding = 1) uniform sampler2DArrayShadow shadow_map;
+/// ```
+///
+/// フラグメントシェーダーは、カメラからの距離が`cascade_splits[i]`以下になる最初の`i`を
+/// カスケードとして選び、レイヤー`i`を参照する。`shadow_map`は比較サンプラーなので、
+/// 1回のフェッチで2x2のPCFになる。`pcf_radius`の範囲を`texel_size`ずつずらしてフェッチし、
+/// 平均する。`debug_cascades`が0でなければ、カスケードの番号で色を付ける。
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowUniform {
+    pub light_view_projections: [Mat4; MAX_SHADOW_CASCADES],
+    pub cascade_splits: [f32; MAX_SHADOW_CASCADES],
+    pub texel_size: f32,
+    pub pcf_radius: f32,
+    pub cascade_count: u32,
+    pub debug_cascades: u32,
+}
+
+/// カスケードごとのライトの行列と、カスケードが受け持つ距離
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowCascades {
+    pub view_projections: [Mat4; MAX_SHADOW_CASCADES],
+    /// カスケードiが受け持つ、カメラからの距離の終わり
+    pub splits: [f32; MAX_SHADOW_CASCADES],
+    pub count: usize,
+}
+
+impl ShadowCascades {
+    /// 1枚のシャドウマップ。`directional_shadow_camera`の行列などを使う
+    pub fn single(view_projection: Mat4) -> Self {
+        Self {
+            view_projections: [view_projection; MAX_SHADOW_CASCADES],
+            splits: [f32::MAX; MAX_SHADOW_CASCADES],
+            count: 1,
+        }
+    }
+
+    /// カメラの視錐台を距離で分割し、それぞれを覆うライトの行列を作る
+    ///
+    /// 各カスケードは視錐台の断片を囲む球を覆うので、カメラが回転しても範囲の大きさが
+    /// 変わらない。さらに平行移動をシャドウマップのテクセル単位に揃えて、
+    /// カメラの移動で影の縁がちらつかないようにする。
+    pub fn fit(camera: &Camera, light_direction: [f32; 3], settings: &ShadowSettings) -> Self {
+        let count = (settings.cascade_count as usize).clamp(1, MAX_SHADOW_CASCADES);
+        let (near, far, half_extent_per_distance) = match camera.projection {
+            Projection::Perspective { fov_y, near, far } => {
+                let tan_half_fov = (fov_y * 0.5).tan();
+                let far = far.unwrap_or(f32::MAX).min(settings.max_distance);
+                // 単位距離あたりの、断面の中心から角までの距離
+                let diagonal = tan_half_fov * (1.0 + camera.aspect * camera.aspect).sqrt();
+                (near, far, Some(diagonal))
+            }
+            Projection::Orthographic { near, far, .. } => {
+                (near, far.min(settings.max_distance), None)
+            }
+        };
+
+        let mut cascades = Self {
+            view_projections: [[[0.0; 4]; 4]; MAX_SHADOW_CASCADES],
+            splits: [far; MAX_SHADOW_CASCADES],
+            count,
+        };
+        let forward = camera.forward();
+        let mut start = near;
+        for index in 0..count {
+            let t = (index + 1) as f32 / count as f32;
+            let log_split = near * (far / near.max(f32::EPSILON)).powf(t);
+            let uniform_split = near + (far - near) * t;
+            let end = settings.cascade_split_lambda * log_split
+                + (1.0 - settings.cascade_split_lambda) * uniform_split;
+            cascades.splits[index] = end;
+
+            let middle = (start + end) * 0.5;
+            let radius = match (half_extent_per_distance, camera.projection) {
+                (Some(diagonal), _) => {
+                    let near_corner = (middle - start).hypot(start * diagonal);
+                    let far_corner = (end - middle).hypot(end * diagonal);
+                    near_corner.max(far_corner)
+                }
+                (None, Projection::Orthographic { height, .. }) => {
+                    let half_diagonal = height * 0.5 * (1.0 + camera.aspect * camera.aspect).sqrt();
+                    (end - middle).hypot(half_diagonal)
+                }
+                (None, _) => unreachable!(),
+            };
+            // 半径を切り上げて、フレームごとの微小な変化でテクセルの大きさが変わらないようにする
+            let radius = (radius * 16.0).ceil() / 16.0;
+            let center = [
+                camera.position[0] + forward[0] * middle,
+                camera.position[1] + forward[1] * middle,
+                camera.position[2] + forward[2] * middle,
+            ];
+            let light_camera = directional_shadow_camera(light_direction, center, radius);
+            let view_projection = light_camera.view_projection_matrix();
+            cascades.view_projections[index] = snap_to_texels(view_projection, settings.resolution);
+            start = end;
+        }
+        cascades
+    }
+}
+
+/// ワールドの原点がシャドウマップのテクセルの境界に来るように、行列を平行移動する
+fn snap_to_texels(mut view_projection: Mat4, resolution: u32) -> Mat4 {
+    let half_resolution = resolution as f32 * 0.5;
+    for translation in view_projection[3].iter_mut().take(2) {
+        let origin = *translation * half_resolution;
+        *translation += (origin.round() - origin) / half_resolution;
+    }
+    view_projection
+}
+
+/// ディレクショナルライトのシャドウマップと、フレームコンテキストごとのデスクリプタセット
+///
+/// カスケードごとにイメージのレイヤーを1つ使う。
+pub struct ShadowMap {
+    pub image: vk::Image,
+    /// 全レイヤーをサンプリングする配列のビュー
+    pub array_view: vk::ImageView,
+    /// カスケードごとに描画するビュー
+    pub layer_views: Vec<vk::ImageView>,
+    pub allocation: Allocation,
+    /// 比較が有効なサンプラー
+    pub sampler: vk::Sampler,
+    pub format: vk::Format,
+    pub settings: ShadowSettings,
+    uniform_buffers: Vec<BufferId>,
+    descriptor_sets: Vec<vk::DescriptorSet>,
+}
+
+impl ShadowMap {
+    pub fn extent(&self) -> vk::Extent2D {
+        vk::Extent2D {
+            width: self.settings.resolution,
+            height: self.settings.resolution,
+        }
+    }
+
+    /// ユニフォームバッファは`Renderer`のバッファとして別に破棄される
+    ///
+    /// # Safety
+    ///
+    /// `device`と`allocator`で作成されたシャドウマップで、GPUが使用中でないこと。
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        device.destroy_sampler(self.sampler, None);
+        for &view in self.layer_views.iter() {
+            device.destroy_image_view(view, None);
+        }
+        device.destroy_image_view(self.array_view, None);
+        device.destroy_image(self.image, None);
+        allocator.free(device, self.allocation);
+    }
+}
+
+/// `direction`に進む平行光で、`center`を中心とする半径`radius`の球を覆うカメラ
+///
+/// 返すカメラの`view_projection_matrix`を`ShadowCascades::single`でシャドウパスに使う。
+/// 球の外にある遮蔽物は描かれないので、影を落とす物体が収まるように`radius`を選ぶ。
+pub fn directional_shadow_camera(direction: [f32; 3], center: [f32; 3], radius: f32) -> Camera {
+    let length =
+        (direction[0] * direction[0] + direction[1] * direction[1] + direction[2] * direction[2])
+            .sqrt()
+            .max(f32::EPSILON);
+    let mut camera = Camera {
+        position: [
+            center[0] - direction[0] / length * radius,
+            center[1] - direction[1] / length * radius,
+            center[2] - direction[2] / length * radius,
+        ],
+        projection: Projection::Orthographic {
+            height: radius * 2.0,
+            near: 0.0,
+            far: radius * 2.0,
+        },
+        aspect: 1.0,
+        reversed_z: false,
+        ..Default::default()
+    };
+    camera.look_at(center);
+    camera
+}
+
+impl Renderer {
+    /// シャドウのデスクリプタセットレイアウト
+    ///
+    /// `pbr_pipeline_builder`のパイプラインで影を使う場合は、`descriptor_set_layouts`で
+    /// マテリアルとライトの後ろのセット2に追加する。
+    pub fn shadow_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[
+            DescriptorBinding::uniform_buffer(0, vk::ShaderStageFlags::FRAGMENT),
+            DescriptorBinding::sampled_image(1, vk::ShaderStageFlags::FRAGMENT),
+        ])
+    }
+
+    /// シャドウマップを作成する。すでにある場合は設定を変更し、解像度かカスケードの数が
+    /// 変わったら作り直す
+    pub fn enable_shadows(&mut self, settings: ShadowSettings) -> Result<()> {
+        if settings.resolution == 0 {
+            return Err(RendererError::Validation(
+                "shadow map resolution must be greater than zero".to_owned(),
+            ));
+        }
+        if !(1..=MAX_SHADOW_CASCADES as u32).contains(&settings.cascade_count) {
+            return Err(RendererError::Validation(format!(
+                "shadow cascade count {} must be between 1 and {}",
+                settings.cascade_count, MAX_SHADOW_CASCADES
+            )));
+        }
+        if let Some(shadow_map) = self.shadow_map.as_mut() {
+            if shadow_map.settings.resolution == settings.resolution
+                && shadow_map.settings.cascade_count == settings.cascade_count
+            {
+                shadow_map.settings = settings;
+                return Ok(());
+            }
+        }
+        let shadow_map = self.create_shadow_map(settings)?;
+        if let Some(old) = self.shadow_map.replace(shadow_map) {
+            self.destroy_shadow_map(old)?;
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからシャドウマップを破棄する
+    pub fn disable_shadows(&mut self) -> Result<()> {
+        match self.shadow_map.take() {
+            Some(shadow_map) => self.destroy_shadow_map(shadow_map),
+            None => Ok(()),
+        }
+    }
+
+    pub fn shadow_map(&self) -> Option<&ShadowMap> {
+        self.shadow_map.as_ref()
+    }
+
+    /// シャドウパス用に設定したパイプラインビルダーを返す
+    ///
+    /// 深度だけを書き込み、設定の深度バイアスをかける。頂点は[`ObjVertex`]の配置、
+    /// プッシュ定数は[`PbrPushConstants`]で、`view_projection`にライトの行列を渡す。
+    /// 変位やアルファテストのあるマテリアルは`material_depth_shaders`で生成したシェーダーを使い、
+    /// `depth_variant_pipeline_builder`で設定を足す。
+    pub fn shadow_pipeline_builder(&self, vertex_shader: ShaderId) -> Result<PipelineBuilder> {
+        let shadow_map = self.enabled_shadow_map()?;
+        let vertex_module = self
+            .shader_modules
+            .get(vertex_shader)
+            .ok_or_else(|| {
+                RendererError::Validation(format!(
+                    "shader {:?} was already destroyed",
+                    vertex_shader
+                ))
+            })?
+            .module;
+        Ok(PipelineBuilder::new()
+            .vertex_shader(vertex_module)
+            .vertex_layout(0, &ObjVertex::layout())
+            .color_attachments(&[])
+            .depth_bias(
+                shadow_map.settings.depth_bias_constant,
+                shadow_map.settings.depth_bias_slope,
+            )
+            .push_constant_range(
+                vk::ShaderStageFlags::VERTEX,
+                0,
+                std::mem::size_of::<PbrPushConstants>() as u32,
+            )
+            .rendering_formats(&[], shadow_map.format))
+    }
+
+    /// シャドウマップの各カスケードをクリアして`draw`で描くパスをグラフに追加する
+    ///
+    /// `draw`はカスケードごとに番号を付けて呼ばれるので、`ShadowCascades`の対応する行列で描く。
+    /// 返すイメージを、ライティングのパスで`ImageAccess::FRAGMENT_SHADER_READ`として宣言する。
+    /// 動的レンダリングが有効であること。シーンは`Scene::meshes_for(ScenePass::Shadow)`で描く。
+    pub fn add_shadow_pass<'a, F>(
+        &self,
+        graph: &mut RenderGraph<'a>,
+        mut draw: F,
+    ) -> Result<GraphImage>
+    where
+        F: FnMut(&PassContext, vk::CommandBuffer, usize) + 'a,
+    {
+        let shadow_map = self.enabled_shadow_map()?;
+        if self.dynamic_rendering.is_none() {
+            return Err(RendererError::Validation(
+                "shadow passes require dynamic rendering".to_owned(),
+            ));
+        }
+        let image = graph.import_image(
+            "shadow_map",
+            ImportedImage {
+                image: shadow_map.image,
+                view: shadow_map.array_view,
+                aspect_mask: vk::ImageAspectFlags::DEPTH,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: None,
+            },
+        );
+        let layer_views = shadow_map.layer_views.clone();
+        let render_area = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: shadow_map.extent(),
+        };
+        graph
+            .add_pass("shadow")
+            .image(image, ImageAccess::DEPTH_ATTACHMENT_WRITE)
+            .execute(move |ctx, command_buffer| {
+                let renderer = ctx.renderer;
+                let dynamic_rendering = renderer.dynamic_rendering.as_ref().unwrap();
+                for (cascade, &view) in layer_views.iter().enumerate() {
+                    let depth_info = RenderingAttachment::clear(
+                        view,
+                        vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                        vk::ClearValue {
+                            depth_stencil: vk::ClearDepthStencilValue {
+                                depth: 1.0,
+                                stencil: 0,
+                            },
+                        },
+                    )
+                    .info();
+                    let rendering_info = vk::RenderingInfo::builder()
+                        .render_area(render_area)
+                        .layer_count(1)
+                        .depth_attachment(&depth_info);
+                    unsafe {
+                        dynamic_rendering.cmd_begin_rendering(
+                            &renderer.device,
+                            command_buffer,
+                            &rendering_info,
+                        );
+                        renderer.set_viewport_and_scissor(command_buffer, render_area);
+                    }
+                    draw(ctx, command_buffer, cascade);
+                    unsafe {
+                        dynamic_rendering.cmd_end_rendering(&renderer.device, command_buffer)
+                    };
+                }
+            });
+        Ok(image)
+    }
+
+    /// カスケードの行列を記録中のフレームのユニフォームバッファに書き込み、
+    /// シャドウのデスクリプタセットを返す
+    pub fn update_shadow(&mut self, cascades: &ShadowCascades) -> Result<vk::DescriptorSet> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "shadows can only be updated between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        let shadow_map = self.enabled_shadow_map()?;
+        if cascades.count != shadow_map.layer_views.len() {
+            return Err(RendererError::Validation(format!(
+                "{} cascades given for a shadow map with {}",
+                cascades.count,
+                shadow_map.layer_views.len()
+            )));
+        }
+        let uniform = ShadowUniform {
+            light_view_projections: cascades.view_projections,
+            cascade_splits: cascades.splits,
+            texel_size: 1.0 / shadow_map.settings.resolution as f32,
+            pcf_radius: shadow_map.settings.pcf_radius as f32,
+            cascade_count: cascades.count as u32,
+            debug_cascades: shadow_map.settings.debug_cascades.into(),
+        };
+        let buffer = self
+            .buffers
+            .get(shadow_map.uniform_buffers[self.current_frame])
+            .unwrap();
+        unsafe { write_buffer(buffer, &[uniform])? };
+        Ok(shadow_map.descriptor_sets[self.current_frame])
+    }
+
+    fn enabled_shadow_map(&self) -> Result<&ShadowMap> {
+        self.shadow_map
+            .as_ref()
+            .ok_or_else(|| RendererError::Validation("shadows are not enabled".to_owned()))
+    }
+
+    fn create_shadow_map(&mut self, settings: ShadowSettings) -> Result<ShadowMap> {
+        let format = self.shadow_map_format();
+        let layout = self.shadow_set_layout()?;
+        unsafe {
+            let image_create_info = *vk::ImageCreateInfo::builder()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(format)
+                .extent(vk::Extent3D {
+                    width: settings.resolution,
+                    height: settings.resolution,
+                    depth: 1,
+                })
+                .mip_levels(1)
+                .array_layers(settings.cascade_count)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE);
+            let (image, allocation) = create_image(
+                &self.device,
+                &mut self.allocator,
+                &image_create_info,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                true,
+            )?;
+            let mut views = Vec::with_capacity(settings.cascade_count as usize + 1);
+            let view_requests =
+                std::iter::once((vk::ImageViewType::TYPE_2D_ARRAY, 0, settings.cascade_count))
+                    .chain(
+                        (0..settings.cascade_count)
+                            .map(|layer| (vk::ImageViewType::TYPE_2D, layer, 1)),
+                    );
+            for (view_type, base_array_layer, layer_count) in view_requests {
+                let view_create_info = *vk::ImageViewCreateInfo::builder()
+                    .image(image)
+                    .view_type(view_type)
+                    .format(format)
+                    .subresource_range(
+                        *vk::ImageSubresourceRange::builder()
+                            .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                            .level_count(1)
+                            .base_array_layer(base_array_layer)
+                            .layer_count(layer_count),
+                    );
+                match self.device.create_image_view(&view_create_info, None) {
+                    Ok(view) => views.push(view),
+                    Err(err) => {
+                        for view in views {
+                            self.device.destroy_image_view(view, None);
+                        }
+                        self.device.destroy_image(image, None);
+                        self.allocator.free(&self.device, allocation);
+                        return Err(err.into());
+                    }
+                }
+            }
+            let array_view = views[0];
+            // 範囲外は影にならないように、白(最も遠い深度)の境界色で比較する
+            let sampler_info = *vk::SamplerCreateInfo::builder()
+                .mag_filter(vk::Filter::LINEAR)
+                .min_filter(vk::Filter::LINEAR)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+                .border_color(vk::BorderColor::FLOAT_OPAQUE_WHITE)
+                .compare_enable(true)
+                .compare_op(vk::CompareOp::LESS_OR_EQUAL);
+            let sampler = match self.device.create_sampler(&sampler_info, None) {
+                Ok(sampler) => sampler,
+                Err(err) => {
+                    for view in views {
+                        self.device.destroy_image_view(view, None);
+                    }
+                    self.device.destroy_image(image, None);
+                    self.allocator.free(&self.device, allocation);
+                    return Err(err.into());
+                }
+            };
+            let mut shadow_map = ShadowMap {
+                image,
+                array_view,
+                layer_views: views[1..].to_vec(),
+                allocation,
+                sampler,
+                format,
+                settings,
+                uniform_buffers: Vec::with_capacity(self.frames.len()),
+                descriptor_sets: Vec::with_capacity(self.frames.len()),
+            };
+
+            for _ in 0..self.frames.len() {
+                let result = self
+                    .create_buffer(
+                        std::mem::size_of::<ShadowUniform>() as vk::DeviceSize,
+                        vk::BufferUsageFlags::UNIFORM_BUFFER,
+                        vk::MemoryPropertyFlags::HOST_VISIBLE
+                            | vk::MemoryPropertyFlags::HOST_COHERENT,
+                    )
+                    .and_then(|id| {
+                        shadow_map.uniform_buffers.push(id);
+                        self.allocate_descriptor_set(layout)
+                    });
+                let descriptor_set = match result {
+                    Ok(set) => set,
+                    Err(err) => {
+                        self.destroy_shadow_map(shadow_map)?;
+                        return Err(err);
+                    }
+                };
+                DescriptorWriter::new()
+                    .buffer(
+                        0,
+                        vk::DescriptorType::UNIFORM_BUFFER,
+                        self.buffers
+                            .get(*shadow_map.uniform_buffers.last().unwrap())
+                            .unwrap(),
+                    )
+                    .image(
+                        1,
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::DescriptorImageInfo {
+                            sampler,
+                            image_view: array_view,
+                            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                        },
+                    )
+                    .update(&self.device, descriptor_set);
+                shadow_map.descriptor_sets.push(descriptor_set);
+            }
+            Ok(shadow_map)
+        }
+    }
+
+    /// 32ビット浮動小数点の深度でサンプリングできればそれを、できなければ必須のD16を使う
+    pub(crate) fn shadow_map_format(&self) -> vk::Format {
+        let required = vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT
+            | vk::FormatFeatureFlags::SAMPLED_IMAGE;
+        let properties = unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.pdevice, vk::Format::D32_SFLOAT)
+        };
+        if properties.optimal_tiling_features.contains(required) {
+            vk::Format::D32_SFLOAT
+        } else {
+            vk::Format::D16_UNORM
+        }
+    }
+
+    /// デスクリプタセットはレンダラーの破棄まで解放されない
+    fn destroy_shadow_map(&mut self, shadow_map: ShadowMap) -> Result<()> {
+        for &id in shadow_map.uniform_buffers.iter() {
+            self.destroy_buffer(id)?;
+        }
+        self.destroy_deferred(move |device, allocator| unsafe {
+            shadow_map.destroy(device, allocator)
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,440 @@
+use super::assets::ObjVertex;
+use super::error::{RendererError, Result};
+use super::{
+    BufferAccess, BufferId, ComputePipeline, DescriptorBinding, DescriptorWriter, Mat4, Mesh,
+    MeshId, PerFrame, Renderer, ShaderId,
+};
+use ash::{vk, Device};
+
+/// 変形のシェーダーのワークグループの大きさ
+pub const SKINNING_GROUP_SIZE: u32 = 64;
+
+/// 頂点ごとのスキンの影響。ウェイトの合計は1にしておく
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SkinVertex {
+    pub joints: [u32; 4],
+    pub weights: [f32; 4],
+}
+
+/// モーフターゲット1つ分の、頂点ごとの差分
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MorphTarget {
+    pub positions: Vec<[f32; 3]>,
+    /// 空なら法線は変えない
+    pub normals: Vec<[f32; 3]>,
+}
+
+/// 頂点を変形するコンピュートシェーダー
+///
+/// 頂点は[`ObjVertex`]の配置で、モーフターゲットを足してからスキンを掛ける。
+///
+/// ```glsl
+/// layout(local_size_x = 64) in;
+/// struct Vertex { float px, py, pz, nx, ny, nz, u, v; };
+/// struct SkinVertex { uvec4 joints; vec4 weights; };
+/// layout(set = 0, binding = 0) readonly buffer Source { Vertex source[]; };
+/// layout(set = 0, binding = 1) readonly buffer Skin { SkinVertex skin[]; };
+/// // [(target * vertex_count + i) * 2]が位置、その次が法線の差分
+/// layout(set = 0, binding = 2) readonly buffer Morph { vec4 morph[]; };
+/// // joint_count個の行列の後に、モーフのウェイトを16個ずつ行列に詰めて並べる
+/// layout(set = 0, binding = 3) readonly buffer Palette { mat4 palette[]; };
+/// layout(set = 0, binding = 4) writeonly buffer Deformed { Vertex deformed[]; };
+/// layout(push_constant) uniform Params {
+///     uint vertex_count; uint joint_count; uint morph_target_count; uint has_skin;
+/// };
+/// float morph_weight(uint t) {
+///     return palette[joint_count + t / 16][(t % 16) / 4][t % 4];
+/// }
+/// void main() {
+///     uint i = gl_GlobalInvocationID.x;
+///     if (i >= vertex_count) return;
+///     Vertex v = source[i];
+///     vec3 p = vec3(v.px, v.py, v.pz), n = vec3(v.nx, v.ny, v.nz);
+///     for (uint t = 0; t < morph_target_count; ++t) {
+///         uint base = (t * vertex_count + i) * 2;
+///         p += morph_weight(t) * morph[base].xyz;
+///         n += morph_weight(t) * morph[base + 1].xyz;
+///     }
+///     if (has_skin != 0) {
+///         SkinVertex s = skin[i];
+///         mat4 m = s.weights.x * palette[s.joints.x] + s.weights.y * palette[s.joints.y]
+///                + s.weights.z * palette[s.joints.z] + s.weights.w * palette[s.joints.w];
+///         p = (m * vec4(p, 1.0)).xyz;
+///         n = mat3(m) * n;
+///     }
+///     n = normalize(n);
+///     deformed[i] = Vertex(p.x, p.y, p.z, n.x, n.y, n.z, v.u, v.v);
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SkinningShaders {
+    pub deform: ShaderId,
+}
+
+/// `enable_skinning`で作るパイプライン
+pub struct Skinning {
+    pub pipeline: ComputePipeline,
+}
+
+impl Skinning {
+    /// # Safety
+    /// `device`は作成に使ったデバイスで、GPUがこのパイプラインを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device) {
+        self.pipeline.destroy(device);
+    }
+}
+
+/// スキンとモーフで変形するメッシュ
+///
+/// 変形した頂点はフレームコンテキストごとのバッファに書き込み、`deformed_mesh`で
+/// 深度、影、メイン、モーションベクトルの全てのパスに同じものを使う。バッファは
+/// `destroy_deformable_mesh`で破棄する。
+pub struct DeformableMesh {
+    /// 変形前の頂点と、インデックスとサブメッシュ
+    pub mesh: MeshId,
+    pub skin: Option<BufferId>,
+    pub morph_targets: Option<BufferId>,
+    pub joint_count: u32,
+    pub morph_target_count: u32,
+    /// 関節の行列とモーフのウェイト
+    pub palettes: PerFrame<BufferId>,
+    /// 変形した頂点。頂点バッファとしても、ストレージバッファとしても読める
+    pub deformed: PerFrame<BufferId>,
+    /// `deformed`を頂点バッファにして、インデックスとサブメッシュを`mesh`と共有するメッシュ
+    pub deformed_meshes: PerFrame<MeshId>,
+    /// フレームコンテキストごとの`skinning_set_layout`のデスクリプタセット
+    pub descriptor_sets: Vec<vk::DescriptorSet>,
+    /// フレームコンテキストごとの、最後に変形したフレームの`frame_count`
+    deformed_frames: Vec<Option<u64>>,
+}
+
+impl Renderer {
+    /// 変形のデスクリプタセットレイアウト。バインディング0から4がストレージバッファ
+    pub fn skinning_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        let bindings: Vec<_> = (0..5)
+            .map(|binding| {
+                DescriptorBinding::storage_buffer(binding, vk::ShaderStageFlags::COMPUTE)
+            })
+            .collect();
+        self.descriptor_set_layout(&bindings)
+    }
+
+    /// `deform_mesh`を使えるようにする
+    pub fn enable_skinning(&mut self, shaders: SkinningShaders) -> Result<()> {
+        let layout = self.skinning_set_layout()?;
+        let pipeline = self.create_compute_pipeline(shaders.deform, "main", &[layout], 16)?;
+        if let Some(old) = self.skinning.replace(Skinning { pipeline }) {
+            self.destroy_deferred(move |device, _| unsafe { old.destroy(device) });
+        }
+        Ok(())
+    }
+
+    /// 使用中のフレームが完了してからパイプラインを破棄する
+    pub fn disable_skinning(&mut self) {
+        if let Some(skinning) = self.skinning.take() {
+            self.destroy_deferred(move |device, _| unsafe { skinning.destroy(device) });
+        }
+    }
+
+    pub fn skinning(&self) -> Option<&Skinning> {
+        self.skinning.as_ref()
+    }
+
+    /// 変形前の頂点、スキン、モーフターゲットを転送して変形するメッシュを作る
+    ///
+    /// `skin`は頂点と同じ数で、関節の番号は`joint_count`未満にする。変形で形が変わるので
+    /// メッシュの箱は持たず、カリングしない。
+    pub fn create_deformable_mesh(
+        &mut self,
+        vertices: &[ObjVertex],
+        indices: Option<&[u32]>,
+        skin: Option<&[SkinVertex]>,
+        joint_count: u32,
+        morph_targets: &[MorphTarget],
+    ) -> Result<DeformableMesh> {
+        let vertex_count = vertices.len();
+        if skin.is_none() && morph_targets.is_empty() {
+            return Err(RendererError::Validation(
+                "a deformable mesh needs a skin or morph targets".to_owned(),
+            ));
+        }
+        if let Some(skin) = skin {
+            if skin.len() != vertex_count {
+                return Err(RendererError::Validation(format!(
+                    "skin has {} vertices, but the mesh has {}",
+                    skin.len(),
+                    vertex_count
+                )));
+            }
+            if let Some(joint) = skin
+                .iter()
+                .flat_map(|vertex| vertex.joints)
+                .find(|&joint| joint >= joint_count)
+            {
+                return Err(RendererError::Validation(format!(
+                    "joint {} is out of range for {} joints",
+                    joint, joint_count
+                )));
+            }
+        }
+        if let Some(target) = morph_targets.iter().find(|target| {
+            target.positions.len() != vertex_count
+                || !(target.normals.is_empty() || target.normals.len() == vertex_count)
+        }) {
+            return Err(RendererError::Validation(format!(
+                "morph target with {} positions and {} normals does not match {} vertices",
+                target.positions.len(),
+                target.normals.len(),
+                vertex_count
+            )));
+        }
+
+        let storage = vk::BufferUsageFlags::STORAGE_BUFFER;
+        let mesh = self.create_mesh_with_usage(
+            vertices,
+            indices,
+            ObjVertex::layout(),
+            vk::BufferUsageFlags::VERTEX_BUFFER | storage,
+        )?;
+        self.meshes.get_mut(mesh).unwrap().bounds = None;
+        let mut buffers = Vec::new();
+        let result =
+            self.create_deformation_buffers(mesh, skin, joint_count, morph_targets, &mut buffers);
+        match result {
+            Ok(deformable) => Ok(deformable),
+            Err(err) => {
+                for buffer in buffers {
+                    self.destroy_buffer(buffer)?;
+                }
+                self.destroy_mesh(mesh)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// 使用中のフレームが完了してから全てのバッファを破棄する
+    ///
+    /// デスクリプタセットはレンダラーの破棄まで解放されない。
+    pub fn destroy_deformable_mesh(&mut self, deformable: DeformableMesh) -> Result<()> {
+        for buffer in deformable
+            .skin
+            .into_iter()
+            .chain(deformable.morph_targets)
+            .chain(deformable.palettes.into_inner())
+            .chain(deformable.deformed.into_inner())
+        {
+            self.destroy_buffer(buffer)?;
+        }
+        // 変形したメッシュのバッファは上で破棄したので、プールから外すだけにする
+        for mesh in deformable.deformed_meshes.into_inner() {
+            self.meshes.remove(mesh);
+        }
+        self.destroy_mesh(deformable.mesh)
+    }
+
+    /// 記録中のフレームのバッファに頂点を変形する
+    ///
+    /// `joints`は関節ごとの、メッシュのローカル空間での行列(関節のワールド変換に
+    /// メッシュのワールド変換の逆行列とバインドポーズの逆行列を掛けたもの)。
+    /// `morph_weights`はモーフターゲットごとのウェイト。描画の前に毎フレーム1回呼ぶ。
+    /// 変形した頂点は頂点入力、頂点シェーダー、コンピュートシェーダーから読めるように同期する。
+    pub fn deform_mesh(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        deformable: &mut DeformableMesh,
+        joints: &[Mat4],
+        morph_weights: &[f32],
+    ) -> Result<()> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "meshes can only be deformed between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        if joints.len() != deformable.joint_count as usize
+            || morph_weights.len() != deformable.morph_target_count as usize
+        {
+            return Err(RendererError::Validation(format!(
+                "{} joints and {} morph weights do not match the mesh's {} joints and {} targets",
+                joints.len(),
+                morph_weights.len(),
+                deformable.joint_count,
+                deformable.morph_target_count
+            )));
+        }
+        let skinning = self
+            .skinning
+            .as_ref()
+            .ok_or_else(|| RendererError::Validation("skinning is not enabled".to_owned()))?;
+        let mut palette: Vec<f32> = joints.iter().flatten().flatten().copied().collect();
+        palette.extend_from_slice(morph_weights);
+        palette.resize(
+            palette_len(deformable.joint_count, morph_weights.len()),
+            0.0,
+        );
+        self.write_per_frame_buffer(&mut deformable.palettes, &palette)?;
+        let deformed = *self.per_frame_mut(&mut deformable.deformed)?;
+
+        let vertex_count = self.live_mesh(deformable.mesh)?.vertex_count;
+        let data: Vec<u8> = [
+            vertex_count,
+            deformable.joint_count,
+            deformable.morph_target_count,
+            deformable.skin.is_some() as u32,
+        ]
+        .iter()
+        .flat_map(|value| value.to_ne_bytes())
+        .collect();
+        // 前のフレームでこのバッファを読んだ描画は、フェンスを待ったので完了している
+        self.dispatch(
+            command_buffer,
+            &skinning.pipeline,
+            &[deformable.descriptor_sets[self.current_frame]],
+            &data,
+            [vertex_count.div_ceil(SKINNING_GROUP_SIZE), 1, 1],
+        )?;
+        self.buffer_barrier(
+            command_buffer,
+            deformed,
+            BufferAccess::COMPUTE_SHADER_WRITE,
+            BufferAccess {
+                stage: vk::PipelineStageFlags::VERTEX_INPUT
+                    | vk::PipelineStageFlags::VERTEX_SHADER
+                    | vk::PipelineStageFlags::COMPUTE_SHADER,
+                access: vk::AccessFlags::VERTEX_ATTRIBUTE_READ | vk::AccessFlags::SHADER_READ,
+            },
+        )?;
+        deformable.deformed_frames[self.current_frame] = Some(self.frame_count);
+        Ok(())
+    }
+
+    /// 今のフレームで変形した頂点を指すメッシュ。`draw_mesh`で全てのパスに使う
+    pub fn deformed_mesh(&self, deformable: &DeformableMesh) -> Result<MeshId> {
+        self.deformed_vertex_buffer(deformable)?;
+        Ok(*self.per_frame(&deformable.deformed_meshes))
+    }
+
+    /// 今のフレームで変形した頂点のバッファ
+    ///
+    /// ストレージバッファとしても読めるので、変形した頂点に物を付けるシェーダーは
+    /// CPUを介さずに位置を読める。頂点`i`の位置は`float`の`8 * i`から3つ、法線はその次の3つ。
+    pub fn deformed_vertex_buffer(&self, deformable: &DeformableMesh) -> Result<BufferId> {
+        if deformable.deformed_frames.get(self.current_frame).copied()
+            != Some(Some(self.frame_count))
+        {
+            return Err(RendererError::Validation(
+                "the mesh has not been deformed in this frame".to_owned(),
+            ));
+        }
+        Ok(*self.per_frame(&deformable.deformed))
+    }
+
+    /// 前のフレームで変形した頂点のバッファ。モーションベクトルのパスで前の位置を読む
+    ///
+    /// 前のフレームで変形していなければ`None`で、その場合は今のフレームの頂点を使う。
+    pub fn previous_deformed_vertex_buffer(&self, deformable: &DeformableMesh) -> Option<BufferId> {
+        let count = deformable.deformed_frames.len();
+        let previous = (self.current_frame + count - 1) % count;
+        let frame = self.frame_count.checked_sub(1)?;
+        (deformable.deformed_frames[previous] == Some(frame))
+            .then(|| deformable.deformed.iter().nth(previous).copied())
+            .flatten()
+    }
+
+    fn create_deformation_buffers(
+        &mut self,
+        mesh: MeshId,
+        skin: Option<&[SkinVertex]>,
+        joint_count: u32,
+        morph_targets: &[MorphTarget],
+        buffers: &mut Vec<BufferId>,
+    ) -> Result<DeformableMesh> {
+        let source = self.meshes.get(mesh).unwrap().clone();
+        let storage = vk::BufferUsageFlags::STORAGE_BUFFER;
+        let skin = match skin {
+            Some(skin) => {
+                let buffer = self.create_buffer_with_data(skin, storage)?;
+                buffers.push(buffer);
+                Some(buffer)
+            }
+            None => None,
+        };
+        let morph = if morph_targets.is_empty() {
+            None
+        } else {
+            let data: Vec<[f32; 4]> = morph_targets
+                .iter()
+                .flat_map(|target| {
+                    target.positions.iter().enumerate().flat_map(|(i, p)| {
+                        let n = target.normals.get(i).copied().unwrap_or_default();
+                        [[p[0], p[1], p[2], 0.0], [n[0], n[1], n[2], 0.0]]
+                    })
+                })
+                .collect();
+            let buffer = self.create_buffer_with_data(&data, storage)?;
+            buffers.push(buffer);
+            Some(buffer)
+        };
+        let palette_size = palette_len(joint_count, morph_targets.len()) * 4;
+        let palettes = self.create_per_frame_buffers(palette_size as vk::DeviceSize, storage)?;
+        buffers.extend(palettes.iter().copied());
+        let vertex_size =
+            source.vertex_count as vk::DeviceSize * source.vertex_layout.stride as u64;
+        let deformed = self.create_per_frame(|renderer, _| {
+            renderer.create_buffer(
+                vertex_size,
+                vk::BufferUsageFlags::VERTEX_BUFFER | storage,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            )
+        })?;
+        buffers.extend(deformed.iter().copied());
+
+        let layout = self.skinning_set_layout()?;
+        let palette_ids: Vec<_> = palettes.iter().copied().collect();
+        let deformed_ids: Vec<_> = deformed.iter().copied().collect();
+        let mut descriptor_sets = Vec::with_capacity(palette_ids.len());
+        for (palette, output) in palette_ids.into_iter().zip(deformed_ids.iter().copied()) {
+            let descriptor_set = self.allocate_descriptor_set(layout)?;
+            // 使わないバインディングには変形前の頂点を入れておく
+            let vertex_buffer = source.vertex_buffer;
+            let bindings = [
+                vertex_buffer,
+                skin.unwrap_or(vertex_buffer),
+                morph.unwrap_or(vertex_buffer),
+                palette,
+                output,
+            ];
+            let mut writer = DescriptorWriter::new();
+            for (binding, id) in bindings.into_iter().enumerate() {
+                let buffer = self.buffers.get(id).unwrap();
+                writer = writer.buffer(binding as u32, vk::DescriptorType::STORAGE_BUFFER, buffer);
+            }
+            unsafe { writer.update(&self.device, descriptor_set) };
+            descriptor_sets.push(descriptor_set);
+        }
+        let deformed_meshes = self.create_per_frame(|renderer, index| {
+            Ok(renderer.meshes.insert(Mesh {
+                vertex_buffer: deformed_ids[index],
+                ..source.clone()
+            }))
+        })?;
+        let frame_count = self.frames.len();
+        Ok(DeformableMesh {
+            mesh,
+            skin,
+            morph_targets: morph,
+            joint_count,
+            morph_target_count: morph_targets.len() as u32,
+            palettes,
+            deformed,
+            deformed_meshes,
+            descriptor_sets,
+            deformed_frames: vec![None; frame_count],
+        })
+    }
+}
+
+/// 関節の行列とモーフのウェイトの`f32`の数。ウェイトは行列16個分ずつ確保する
+fn palette_len(joint_count: u32, morph_target_count: usize) -> usize {
+    (joint_count as usize * 16 + morph_target_count.div_ceil(16) * 16).max(16)
+}
@@ -0,0 +1,134 @@
+use super::{mat4_mul, Camera, Mat4, Projection, Renderer};
+use ash::vk;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+/// 左右の画像を1枚の出力にどう詰めるか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StereoLayout {
+    /// 左半分に左目、右半分に右目
+    SideBySide,
+    /// 上半分に左目、下半分に右目
+    TopBottom,
+}
+
+impl StereoLayout {
+    /// `extent`の出力の中で`eye`を描く範囲
+    pub fn render_area(self, extent: vk::Extent2D, eye: Eye) -> vk::Rect2D {
+        let index = match eye {
+            Eye::Left => 0,
+            Eye::Right => 1,
+        };
+        match self {
+            Self::SideBySide => {
+                let width = extent.width / 2;
+                vk::Rect2D {
+                    offset: vk::Offset2D {
+                        x: (width * index) as i32,
+                        y: 0,
+                    },
+                    extent: vk::Extent2D {
+                        width,
+                        height: extent.height,
+                    },
+                }
+            }
+            Self::TopBottom => {
+                let height = extent.height / 2;
+                vk::Rect2D {
+                    offset: vk::Offset2D {
+                        x: 0,
+                        y: (height * index) as i32,
+                    },
+                    extent: vk::Extent2D {
+                        width: extent.width,
+                        height,
+                    },
+                }
+            }
+        }
+    }
+
+    /// 片目の範囲の幅/高さ
+    pub fn eye_aspect(self, extent: vk::Extent2D) -> f32 {
+        let area = self.render_area(extent, Eye::Left).extent;
+        area.width as f32 / area.height.max(1) as f32
+    }
+}
+
+/// 中央のカメラから左右の目のカメラを作る
+///
+/// 左右の目はカメラの右方向に`eye_separation`だけ離れ、`convergence_distance`の距離にある
+/// 物体の視差が0になるように、射影を水平にずらす(オフアクシス射影)。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StereoCamera {
+    pub camera: Camera,
+    /// 両目の間隔。ワールド空間の単位
+    pub eye_separation: f32,
+    pub convergence_distance: f32,
+}
+
+impl StereoCamera {
+    pub fn new(camera: Camera) -> Self {
+        Self {
+            camera,
+            eye_separation: 0.064,
+            convergence_distance: 2.0,
+        }
+    }
+
+    /// 目の位置に動かしたカメラ。射影は中央のカメラのまま
+    pub fn eye_camera(&self, eye: Eye) -> Camera {
+        let offset = self.eye_offset(eye);
+        let right = self.camera.right();
+        let mut camera = self.camera;
+        for (position, axis) in camera.position.iter_mut().zip(right) {
+            *position += axis * offset;
+        }
+        camera
+    }
+
+    pub fn view_matrix(&self, eye: Eye) -> Mat4 {
+        self.eye_camera(eye).view_matrix()
+    }
+
+    /// 収束距離で視差が0になるように水平にずらした射影
+    ///
+    /// 平行投影ではずらさない。
+    pub fn projection_matrix(&self, eye: Eye) -> Mat4 {
+        let mut m = self.camera.projection_matrix();
+        if let Projection::Perspective { .. } = self.camera.projection {
+            // 目がずれた分だけ、収束距離の点がNDCの中央に来るようにxをずらす
+            m[2][0] = -m[0][0] * self.eye_offset(eye) / self.convergence_distance.max(f32::EPSILON);
+        }
+        m
+    }
+
+    pub fn view_projection_matrix(&self, eye: Eye) -> Mat4 {
+        mat4_mul(&self.projection_matrix(eye), &self.view_matrix(eye))
+    }
+
+    fn eye_offset(&self, eye: Eye) -> f32 {
+        match eye {
+            Eye::Left => -self.eye_separation * 0.5,
+            Eye::Right => self.eye_separation * 0.5,
+        }
+    }
+}
+
+impl Renderer {
+    /// スワップチェインイメージの中で`eye`を描く範囲
+    pub fn stereo_render_area(&self, layout: StereoLayout, eye: Eye) -> vk::Rect2D {
+        layout.render_area(self.surface_resolution, eye)
+    }
+
+    /// 指定した範囲にビューポートとシザーを設定する。`begin_swapchain_rendering`の後に
+    /// 片目ずつ呼んで描く
+    pub fn set_render_area(&self, command_buffer: vk::CommandBuffer, render_area: vk::Rect2D) {
+        unsafe { self.set_viewport_and_scissor(command_buffer, render_area) };
+    }
+}
@@ -0,0 +1,83 @@
+use super::error::{RendererError, Result};
+use super::Renderer;
+use ash::vk;
+
+/// フレーム内のコマンドを何回に分けてキューに提出するか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SubmitPolicy {
+    /// `split_submission`のたびに、それまでのコマンドを提出する
+    ///
+    /// 記録に時間がかかるフレームでも、先に記録したシャドウなどのパスをGPUが早く実行し始められる。
+    #[default]
+    Split,
+    /// `split_submission`を無視して、`end_frame`でまとめて1回だけ提出する
+    Single,
+}
+
+impl Renderer {
+    pub fn set_submit_policy(&mut self, policy: SubmitPolicy) {
+        self.config.submit_policy = policy;
+    }
+
+    /// 記録中のコマンドを提出し、続きを記録するコマンドバッファを返す
+    ///
+    /// スワップチェインイメージの取得を待つのは、`uses_swapchain_image`を指定した最初の提出か
+    /// `end_frame`の提出になる。それまでのコマンドでスワップチェインイメージに触れた場合は
+    /// `uses_swapchain_image`を`true`にすること。提出の間の依存関係は、同じキューへの提出順で
+    /// パイプラインバリアがそのまま有効になる。[`SubmitPolicy::Single`]の場合は何もせず、
+    /// 記録中のコマンドバッファを返す。
+    pub fn split_submission(&mut self, uses_swapchain_image: bool) -> Result<vk::CommandBuffer> {
+        if !self.recording {
+            return Err(RendererError::Validation(
+                "submissions can only be split between begin_frame and end_frame".to_owned(),
+            ));
+        }
+        let frame = &self.frames[self.current_frame];
+        let command_buffer = frame.recording_command_buffer();
+        if self.config.submit_policy == SubmitPolicy::Single {
+            return Ok(command_buffer);
+        }
+
+        unsafe {
+            self.device.end_command_buffer(command_buffer)?;
+            let wait_for_image = uses_swapchain_image && !frame.swapchain_image_waited;
+            let (mut wait_semaphores, mut wait_mask): (Vec<_>, Vec<_>) =
+                frame.external_waits.iter().copied().unzip();
+            if wait_for_image {
+                wait_semaphores.push(frame.image_available_semaphore);
+                wait_mask.push(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT);
+            }
+            let command_buffers = [command_buffer];
+            let submit_info = vk::SubmitInfo::builder()
+                .command_buffers(&command_buffers)
+                .wait_semaphores(&wait_semaphores)
+                .wait_dst_stage_mask(&wait_mask);
+            self.device
+                .queue_submit(self.present_queue, &[*submit_info], vk::Fence::null())?;
+
+            let next_index = frame.recording_index;
+            if next_index == frame.split_command_buffers.len() {
+                let allocate_info = *vk::CommandBufferAllocateInfo::builder()
+                    .command_buffer_count(1)
+                    .command_pool(self.command_pool)
+                    .level(vk::CommandBufferLevel::PRIMARY);
+                let allocated = self.device.allocate_command_buffers(&allocate_info)?[0];
+                self.frames[self.current_frame]
+                    .split_command_buffers
+                    .push(allocated);
+            }
+            let frame = &mut self.frames[self.current_frame];
+            frame.swapchain_image_waited |= wait_for_image;
+            frame.external_waits.clear();
+            frame.recording_index += 1;
+            let next = frame.recording_command_buffer();
+
+            self.device
+                .reset_command_buffer(next, vk::CommandBufferResetFlags::RELEASE_RESOURCES)?;
+            let begin_info = *vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            self.device.begin_command_buffer(next, &begin_info)?;
+            Ok(next)
+        }
+    }
+}
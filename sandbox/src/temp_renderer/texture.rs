@@ -0,0 +1,583 @@
+use super::buffer::{create_buffer, write_buffer, Buffer};
+use super::error::{RendererError, Result};
+use super::handle::Handle;
+use super::memory::{
+    Allocation, AllocationDesc, AllocationKind, DedicatedResource, MemoryAllocator,
+};
+use super::texture_format::{sampled_aspect_mask, texture_sample_type, TextureSampleType};
+use super::Renderer;
+use ash::{vk, Device};
+
+/// サンプリング用のイメージ、メモリ、ビュー、サンプラーの組
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Texture {
+    pub image: vk::Image,
+    pub allocation: Allocation,
+    pub view: vk::ImageView,
+    pub sampler: vk::Sampler,
+    pub extent: vk::Extent2D,
+    pub format: vk::Format,
+    pub mip_levels: u32,
+    pub kind: TextureKind,
+    pub usage: vk::ImageUsageFlags,
+    /// `register_external_texture`で登録したもので、レンダラーは破棄しない
+    pub external: bool,
+}
+
+pub type TextureId = Handle<Texture>;
+
+/// テクスチャの次元とレイヤー数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum TextureKind {
+    #[default]
+    Texture2D,
+    /// `layers`枚の2Dテクスチャの配列
+    Texture2DArray { layers: u32 },
+    /// +X、-X、+Y、-Y、+Z、-Zの順の6レイヤー
+    Cube,
+    /// 奥行き`depth`の3Dテクスチャ。スライスごとに転送する
+    Texture3D { depth: u32 },
+}
+
+impl TextureKind {
+    /// イメージのレイヤー数。3Dテクスチャは1
+    pub fn array_layers(&self) -> u32 {
+        match *self {
+            TextureKind::Texture2D | TextureKind::Texture3D { .. } => 1,
+            TextureKind::Texture2DArray { layers } => layers,
+            TextureKind::Cube => 6,
+        }
+    }
+
+    /// ミップ0の奥行き。3Dテクスチャ以外は1
+    pub fn depth(&self) -> u32 {
+        match *self {
+            TextureKind::Texture3D { depth } => depth,
+            _ => 1,
+        }
+    }
+
+    /// 全体をサンプリングするビューの種類
+    pub fn view_type(&self) -> vk::ImageViewType {
+        match self {
+            TextureKind::Texture2D => vk::ImageViewType::TYPE_2D,
+            TextureKind::Texture2DArray { .. } => vk::ImageViewType::TYPE_2D_ARRAY,
+            TextureKind::Cube => vk::ImageViewType::CUBE,
+            TextureKind::Texture3D { .. } => vk::ImageViewType::TYPE_3D,
+        }
+    }
+}
+
+impl Texture {
+    /// # Safety
+    ///
+    /// `device`と`allocator`で作成されたテクスチャで、GPUが使用中でないこと。
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        if self.external {
+            return;
+        }
+        device.destroy_sampler(self.sampler, None);
+        device.destroy_image_view(self.view, None);
+        device.destroy_image(self.image, None);
+        allocator.free(device, self.allocation);
+    }
+}
+
+/// `width`x`height`のイメージに必要なミップレベル数
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    u32::BITS - width.max(height).max(1).leading_zeros()
+}
+
+/// イメージを作成してメモリを割り当てる。`dedicated`なら他のリソースとメモリを共有しない
+pub(crate) unsafe fn create_image(
+    device: &Device,
+    allocator: &mut MemoryAllocator,
+    create_info: &vk::ImageCreateInfo,
+    memory_flags: vk::MemoryPropertyFlags,
+    dedicated: bool,
+) -> Result<(vk::Image, Allocation)> {
+    let image = device.create_image(create_info, None)?;
+    let kind = if create_info.tiling == vk::ImageTiling::LINEAR {
+        AllocationKind::Linear
+    } else {
+        AllocationKind::Optimal
+    };
+    let desc = AllocationDesc {
+        requirements: device.get_image_memory_requirements(image),
+        memory_flags,
+        kind,
+        dedicated: dedicated.then_some(DedicatedResource::Image(image)),
+    };
+    let allocation = match allocator.allocate(device, &desc) {
+        Ok(allocation) => allocation,
+        Err(err) => {
+            device.destroy_image(image, None);
+            return Err(err);
+        }
+    };
+    if let Err(err) = device.bind_image_memory(image, allocation.memory, allocation.offset) {
+        device.destroy_image(image, None);
+        allocator.free(device, allocation);
+        return Err(err.into());
+    }
+    Ok((image, allocation))
+}
+
+/// スワップチェインの解像度に合わせて作り直す、ミップのない画面サイズのイメージ
+pub struct ScreenImage {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    pub allocation: Allocation,
+}
+
+impl ScreenImage {
+    /// # Safety
+    /// `device`と`allocator`は作成に使ったもので、GPUがこのイメージを使い終わっていること
+    pub unsafe fn destroy(&self, device: &Device, allocator: &mut MemoryAllocator) {
+        device.destroy_image_view(self.view, None);
+        device.destroy_image(self.image, None);
+        allocator.free(device, self.allocation);
+    }
+}
+
+/// `extent`の2Dカラーイメージとビューを専用割り当てで作成する
+pub(crate) unsafe fn create_screen_image(
+    device: &Device,
+    allocator: &mut MemoryAllocator,
+    format: vk::Format,
+    usage: vk::ImageUsageFlags,
+    extent: vk::Extent2D,
+) -> Result<ScreenImage> {
+    let image_create_info = *vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(format)
+        .extent(extent.into())
+        .mip_levels(1)
+        .array_layers(1)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .usage(usage)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+    let (image, allocation) = create_image(
+        device,
+        allocator,
+        &image_create_info,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        true,
+    )?;
+    let view_create_info = *vk::ImageViewCreateInfo::builder()
+        .image(image)
+        .view_type(vk::ImageViewType::TYPE_2D)
+        .format(format)
+        .subresource_range(
+            *vk::ImageSubresourceRange::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .level_count(1)
+                .layer_count(1),
+        );
+    match device.create_image_view(&view_create_info, None) {
+        Ok(view) => Ok(ScreenImage {
+            image,
+            view,
+            allocation,
+        }),
+        Err(err) => {
+            device.destroy_image(image, None);
+            allocator.free(device, allocation);
+            Err(err.into())
+        }
+    }
+}
+
+/// ステージングバッファの`offset`からミップ`mip_level`全体への転送
+pub(crate) fn level_copy_region(
+    offset: vk::DeviceSize,
+    mip_level: u32,
+    extent: vk::Extent2D,
+    aspect_mask: vk::ImageAspectFlags,
+) -> vk::BufferImageCopy {
+    vk::BufferImageCopy {
+        buffer_offset: offset,
+        buffer_row_length: 0,
+        buffer_image_height: 0,
+        image_subresource: vk::ImageSubresourceLayers {
+            aspect_mask,
+            mip_level,
+            base_array_layer: 0,
+            layer_count: 1,
+        },
+        image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+        image_extent: vk::Extent3D {
+            width: (extent.width >> mip_level).max(1),
+            height: (extent.height >> mip_level).max(1),
+            depth: 1,
+        },
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) unsafe fn image_barrier(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    aspect_mask: vk::ImageAspectFlags,
+    base_mip_level: u32,
+    level_count: u32,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+    src_access_mask: vk::AccessFlags,
+    dst_access_mask: vk::AccessFlags,
+    src_stage_mask: vk::PipelineStageFlags,
+    dst_stage_mask: vk::PipelineStageFlags,
+) {
+    let barrier = *vk::ImageMemoryBarrier::builder()
+        .image(image)
+        .old_layout(old_layout)
+        .new_layout(new_layout)
+        .src_access_mask(src_access_mask)
+        .dst_access_mask(dst_access_mask)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .subresource_range(vk::ImageSubresourceRange {
+            aspect_mask,
+            base_mip_level,
+            level_count,
+            base_array_layer: 0,
+            layer_count: 1,
+        });
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        src_stage_mask,
+        dst_stage_mask,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[],
+        &[barrier],
+    );
+}
+
+/// ミップ0に転送済みのイメージから`cmd_blit_image`で残りのミップを作り、
+/// 全レベルを`SHADER_READ_ONLY_OPTIMAL`にする
+///
+/// 呼び出し時点で全レベルが`TRANSFER_DST_OPTIMAL`であること。
+unsafe fn record_generate_mipmaps(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    extent: vk::Extent2D,
+    mip_levels: u32,
+) {
+    let mut width = extent.width as i32;
+    let mut height = extent.height as i32;
+    for level in 1..mip_levels {
+        image_barrier(
+            device,
+            command_buffer,
+            image,
+            vk::ImageAspectFlags::COLOR,
+            level - 1,
+            1,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::AccessFlags::TRANSFER_READ,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::TRANSFER,
+        );
+
+        let next_width = (width / 2).max(1);
+        let next_height = (height / 2).max(1);
+        let blit = vk::ImageBlit {
+            src_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: level - 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            src_offsets: [
+                vk::Offset3D { x: 0, y: 0, z: 0 },
+                vk::Offset3D {
+                    x: width,
+                    y: height,
+                    z: 1,
+                },
+            ],
+            dst_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: level,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            dst_offsets: [
+                vk::Offset3D { x: 0, y: 0, z: 0 },
+                vk::Offset3D {
+                    x: next_width,
+                    y: next_height,
+                    z: 1,
+                },
+            ],
+        };
+        device.cmd_blit_image(
+            command_buffer,
+            image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[blit],
+            vk::Filter::LINEAR,
+        );
+
+        image_barrier(
+            device,
+            command_buffer,
+            image,
+            vk::ImageAspectFlags::COLOR,
+            level - 1,
+            1,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::AccessFlags::TRANSFER_READ,
+            vk::AccessFlags::SHADER_READ,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+        );
+        width = next_width;
+        height = next_height;
+    }
+
+    // 最後のレベルはブリット元にならないので転送先のまま残っている
+    image_barrier(
+        device,
+        command_buffer,
+        image,
+        vk::ImageAspectFlags::COLOR,
+        mip_levels - 1,
+        1,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        vk::AccessFlags::TRANSFER_WRITE,
+        vk::AccessFlags::SHADER_READ,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::FRAGMENT_SHADER,
+    );
+}
+
+impl Renderer {
+    /// RGBA8(sRGB)のピクセル列からミップマップ付きのテクスチャを作成する
+    ///
+    /// フォーマットがリニアフィルタでのブリットに対応していない場合はミップ0だけを作る。
+    pub fn create_texture_from_rgba(
+        &mut self,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+    ) -> Result<TextureId> {
+        if width == 0 || height == 0 {
+            return Err(RendererError::Validation(format!(
+                "texture extent {}x{} must not be zero",
+                width, height
+            )));
+        }
+        let expected_len = width as usize * height as usize * 4;
+        if pixels.len() != expected_len {
+            return Err(RendererError::Validation(format!(
+                "expected {} bytes of RGBA pixels for {}x{}, got {}",
+                expected_len,
+                width,
+                height,
+                pixels.len()
+            )));
+        }
+
+        let format = vk::Format::R8G8B8A8_SRGB;
+        let format_properties = unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.pdevice, format)
+        };
+        let mip_levels = if format_properties
+            .optimal_tiling_features
+            .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
+        {
+            mip_level_count(width, height)
+        } else {
+            1
+        };
+        let extent = vk::Extent2D { width, height };
+
+        unsafe {
+            let staging = create_buffer(
+                &self.device,
+                &mut self.allocator,
+                pixels.len() as vk::DeviceSize,
+                vk::BufferUsageFlags::TRANSFER_SRC,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )?;
+            let regions = [level_copy_region(0, 0, extent, vk::ImageAspectFlags::COLOR)];
+            let result = write_buffer(&staging, pixels)
+                .and_then(|_| self.upload_texture(&staging, &regions, extent, format, mip_levels));
+            // 転送の完了を待っているので、ステージングバッファはすぐに破棄できる
+            staging.destroy(&self.device, &mut self.allocator);
+            Ok(self.textures.insert(result?))
+        }
+    }
+
+    pub fn texture(&self, id: TextureId) -> Option<&Texture> {
+        self.textures.get(id)
+    }
+
+    /// 使用中のフレームが完了してからテクスチャを破棄する
+    pub fn destroy_texture(&mut self, id: TextureId) -> Result<()> {
+        let texture = self.textures.remove(id).ok_or_else(|| {
+            RendererError::Validation(format!("texture {:?} was already destroyed", id))
+        })?;
+        self.destroy_deferred(move |device, allocator| unsafe {
+            texture.destroy(device, allocator)
+        });
+        Ok(())
+    }
+
+    /// `regions`でステージングバッファからミップを転送する。
+    /// ミップ0だけを転送する場合は残りのミップをブリットで作る
+    pub(crate) unsafe fn upload_texture(
+        &mut self,
+        staging: &Buffer,
+        regions: &[vk::BufferImageCopy],
+        extent: vk::Extent2D,
+        format: vk::Format,
+        mip_levels: u32,
+    ) -> Result<Texture> {
+        let image_info = *vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(extent.into())
+            .mip_levels(mip_levels)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(
+                vk::ImageUsageFlags::TRANSFER_SRC
+                    | vk::ImageUsageFlags::TRANSFER_DST
+                    | vk::ImageUsageFlags::SAMPLED,
+            )
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let (image, allocation) = create_image(
+            &self.device,
+            &mut self.allocator,
+            &image_info,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            false,
+        )?;
+        match self.init_texture(staging, regions, image, extent, format, mip_levels) {
+            Ok((view, sampler)) => Ok(Texture {
+                image,
+                allocation,
+                view,
+                sampler,
+                extent,
+                format,
+                mip_levels,
+                kind: TextureKind::Texture2D,
+                usage: image_info.usage,
+                external: false,
+            }),
+            Err(err) => {
+                self.device.destroy_image(image, None);
+                self.allocator.free(&self.device, allocation);
+                Err(err)
+            }
+        }
+    }
+
+    /// ピクセルを転送してミップを作り、ビューとサンプラーを作成する
+    unsafe fn init_texture(
+        &self,
+        staging: &Buffer,
+        regions: &[vk::BufferImageCopy],
+        image: vk::Image,
+        extent: vk::Extent2D,
+        format: vk::Format,
+        mip_levels: u32,
+    ) -> Result<(vk::ImageView, vk::Sampler)> {
+        let aspect_mask = sampled_aspect_mask(format);
+        self.submit_setup_commands(|device, command_buffer| {
+            image_barrier(
+                device,
+                command_buffer,
+                image,
+                aspect_mask,
+                0,
+                mip_levels,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::TRANSFER_WRITE,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::TRANSFER,
+            );
+            device.cmd_copy_buffer_to_image(
+                command_buffer,
+                staging.buffer,
+                image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                regions,
+            );
+            if regions.len() as u32 == mip_levels {
+                image_barrier(
+                    device,
+                    command_buffer,
+                    image,
+                    aspect_mask,
+                    0,
+                    mip_levels,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    vk::AccessFlags::TRANSFER_WRITE,
+                    vk::AccessFlags::SHADER_READ,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                );
+            } else {
+                record_generate_mipmaps(device, command_buffer, image, extent, mip_levels);
+            }
+        })?;
+
+        let view_info = *vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask,
+                base_mip_level: 0,
+                level_count: mip_levels,
+                base_array_layer: 0,
+                layer_count: 1,
+            });
+        let view = self.device.create_image_view(&view_info, None)?;
+
+        // 整数と深度のフォーマットは線形フィルタリングできるとは限らない
+        let (filter, mipmap_mode) = if texture_sample_type(format) == TextureSampleType::Float {
+            (vk::Filter::LINEAR, vk::SamplerMipmapMode::LINEAR)
+        } else {
+            (vk::Filter::NEAREST, vk::SamplerMipmapMode::NEAREST)
+        };
+        let sampler_info = *vk::SamplerCreateInfo::builder()
+            .mag_filter(filter)
+            .min_filter(filter)
+            .mipmap_mode(mipmap_mode)
+            .address_mode_u(vk::SamplerAddressMode::REPEAT)
+            .address_mode_v(vk::SamplerAddressMode::REPEAT)
+            .address_mode_w(vk::SamplerAddressMode::REPEAT)
+            .min_lod(0.0)
+            .max_lod(mip_levels as f32);
+        let sampler = match self.device.create_sampler(&sampler_info, None) {
+            Ok(sampler) => sampler,
+            Err(err) => {
+                self.device.destroy_image_view(view, None);
+                return Err(err.into());
+            }
+        };
+
+        Ok((view, sampler))
+    }
+}
@@ -0,0 +1,384 @@
+use super::error::{RendererError, Result};
+use super::{
+    format_block, BufferAccess, BufferId, DescriptorBinding, DescriptorWriter, ReadbackId,
+    Renderer, TextureId,
+};
+use ash::vk;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+/// テクスチャを縦横に分ける領域の数
+pub const TEXTURE_FEEDBACK_GRID: u32 = 8;
+/// テクスチャ1枚あたりのフィードバックの要素数
+pub const TEXTURE_FEEDBACK_REGIONS: usize =
+    (TEXTURE_FEEDBACK_GRID * TEXTURE_FEEDBACK_GRID) as usize;
+/// フィードバックバッファで、一度もサンプリングされなかった領域の値
+pub const TEXTURE_FEEDBACK_UNSAMPLED: u32 = u32::MAX;
+
+const SLOT_SIZE: vk::DeviceSize = (TEXTURE_FEEDBACK_REGIONS * std::mem::size_of::<u32>()) as _;
+
+/// 完了したフレームで、テクスチャの領域ごとにサンプリングされた最も細かいミップ
+///
+/// `Display`で領域を格子状に並べ、サンプリングされなかった領域を`.`で表す。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextureUsage {
+    /// 計測したフレームの番号
+    pub frame: u64,
+    /// 行優先で`TEXTURE_FEEDBACK_REGIONS`個。サンプリングされなければ`TEXTURE_FEEDBACK_UNSAMPLED`
+    pub region_mips: Vec<u32>,
+}
+
+impl TextureUsage {
+    /// 領域`(x, y)`でサンプリングされた最も細かいミップ
+    pub fn region_mip(&self, x: u32, y: u32) -> Option<u32> {
+        let mip = self.region_mips[(y * TEXTURE_FEEDBACK_GRID + x) as usize];
+        (mip != TEXTURE_FEEDBACK_UNSAMPLED).then_some(mip)
+    }
+
+    /// テクスチャ全体でサンプリングされた最も細かいミップ。これより細かいミップは常駐しなくてよい
+    pub fn finest_mip(&self) -> Option<u32> {
+        self.region_mips
+            .iter()
+            .copied()
+            .filter(|&mip| mip != TEXTURE_FEEDBACK_UNSAMPLED)
+            .min()
+    }
+}
+
+impl fmt::Display for TextureUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for y in 0..TEXTURE_FEEDBACK_GRID {
+            for x in 0..TEXTURE_FEEDBACK_GRID {
+                match self.region_mip(x, y) {
+                    Some(mip) => write!(f, "{:>3}", mip)?,
+                    None => write!(f, "  .")?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// サンプリングされなかったミップが使っているメモリ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextureWaste {
+    pub texture: TextureId,
+    /// 全ミップのバイト数
+    pub resident_bytes: u64,
+    /// `finest_mip`より細かいミップのバイト数。一度もサンプリングされなければ全体
+    pub wasted_bytes: u64,
+    pub finest_mip: Option<u32>,
+}
+
+/// フィードバックを取ったテクスチャの無駄なメモリの一覧
+///
+/// `Display`で無駄の多い順に1行ずつ並べ、最後に合計を出す。
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TextureWasteReport {
+    /// `wasted_bytes`の降順
+    pub textures: Vec<TextureWaste>,
+}
+
+impl TextureWasteReport {
+    pub fn total_resident_bytes(&self) -> u64 {
+        self.textures.iter().map(|waste| waste.resident_bytes).sum()
+    }
+
+    pub fn total_wasted_bytes(&self) -> u64 {
+        self.textures.iter().map(|waste| waste.wasted_bytes).sum()
+    }
+}
+
+impl fmt::Display for TextureWasteReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn mib(bytes: u64) -> f64 {
+            bytes as f64 / (1024.0 * 1024.0)
+        }
+
+        for waste in self.textures.iter() {
+            let finest_mip = waste
+                .finest_mip
+                .map_or("-".to_owned(), |mip| mip.to_string());
+            writeln!(
+                f,
+                "{:<24} {:>8.2}/{:<8.2}MiB wasted, finest mip {}",
+                format!("{:?}", waste.texture),
+                mib(waste.wasted_bytes),
+                mib(waste.resident_bytes),
+                finest_mip
+            )?;
+        }
+        writeln!(
+            f,
+            "total {:.2}/{:.2}MiB wasted",
+            mib(self.total_wasted_bytes()),
+            mib(self.total_resident_bytes())
+        )
+    }
+}
+
+/// `enable_texture_feedback`で作るフィードバックのリソース
+pub struct TextureFeedback {
+    /// スロットごとに`TEXTURE_FEEDBACK_REGIONS`個の`u32`。`Renderer`のバッファとして別に破棄される
+    pub buffer: BufferId,
+    /// `texture_feedback_set_layout`のデスクリプタセット
+    pub descriptor_set: vk::DescriptorSet,
+    /// スロット番号の順のテクスチャ
+    pub slots: Vec<TextureId>,
+    pub capacity: u32,
+    /// 記録した順の読み戻しと、その時点のスロット数
+    pending: VecDeque<(ReadbackId, usize)>,
+    usages: HashMap<TextureId, TextureUsage>,
+}
+
+impl Renderer {
+    /// フィードバックのデスクリプタセットレイアウト。バインディング0がストレージバッファ
+    pub fn texture_feedback_set_layout(&mut self) -> Result<vk::DescriptorSetLayout> {
+        self.descriptor_set_layout(&[DescriptorBinding::storage_buffer(
+            0,
+            vk::ShaderStageFlags::FRAGMENT | vk::ShaderStageFlags::COMPUTE,
+        )])
+    }
+
+    /// テクスチャのどのミップと領域がサンプリングされたかを記録する解析を有効にする
+    ///
+    /// 最大`max_textures`枚のテクスチャに`texture_feedback_slot`でスロットを割り当てる。
+    /// シェーダーは`texture_feedback_set_layout`のセットを使い、サンプリングごとに
+    /// 次のように記録する。すでに有効な場合は記録を捨てて作り直す。
+    ///
+    /// ```glsl
+    /// layout(set = 3, binding = 0) buffer TextureFeedback { uint region_mips[]; };
+    /// void record_feedback(uint slot, sampler2D tex, vec2 uv) {
+    ///     ivec2 region = clamp(ivec2(fract(uv) * 8.0), ivec2(0), ivec2(7));
+    ///     uint mip = uint(max(textureQueryLod(tex, uv).x, 0.0));
+    ///     atomicMin(region_mips[slot * 64 + region.y * 8 + region.x], mip);
+    /// }
+    /// ```
+    pub fn enable_texture_feedback(&mut self, max_textures: u32) -> Result<()> {
+        if max_textures == 0 {
+            return Err(RendererError::Validation(
+                "texture feedback needs at least one slot".to_owned(),
+            ));
+        }
+        self.disable_texture_feedback()?;
+        let layout = self.texture_feedback_set_layout()?;
+        let buffer = self.create_buffer(
+            SLOT_SIZE * max_textures as vk::DeviceSize,
+            vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::TRANSFER_SRC
+                | vk::BufferUsageFlags::TRANSFER_DST,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )?;
+        let descriptor_set = match self.allocate_descriptor_set(layout) {
+            Ok(descriptor_set) => descriptor_set,
+            Err(err) => {
+                self.destroy_buffer(buffer)?;
+                return Err(err);
+            }
+        };
+        unsafe {
+            DescriptorWriter::new()
+                .buffer(
+                    0,
+                    vk::DescriptorType::STORAGE_BUFFER,
+                    self.buffers.get(buffer).unwrap(),
+                )
+                .update(&self.device, descriptor_set);
+        }
+        self.texture_feedback = Some(TextureFeedback {
+            buffer,
+            descriptor_set,
+            slots: Vec::new(),
+            capacity: max_textures,
+            pending: VecDeque::new(),
+            usages: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    /// 解析をやめ、使用中のフレームが完了してからバッファを破棄する
+    pub fn disable_texture_feedback(&mut self) -> Result<()> {
+        let Some(feedback) = self.texture_feedback.take() else {
+            return Ok(());
+        };
+        for (readback, _) in feedback.pending {
+            self.release_readback(readback)?;
+        }
+        self.destroy_buffer(feedback.buffer)
+    }
+
+    pub fn texture_feedback(&self) -> Option<&TextureFeedback> {
+        self.texture_feedback.as_ref()
+    }
+
+    /// シェーダーに渡す`texture`のスロット番号。初めてのテクスチャには新しいスロットを割り当てる
+    pub fn texture_feedback_slot(&mut self, texture: TextureId) -> Result<u32> {
+        if !self.textures.contains(texture) {
+            return Err(RendererError::Validation(format!(
+                "texture {:?} was destroyed",
+                texture
+            )));
+        }
+        let feedback = self.enabled_texture_feedback_mut()?;
+        if let Some(slot) = feedback.slots.iter().position(|&slot| slot == texture) {
+            return Ok(slot as u32);
+        }
+        if feedback.slots.len() as u32 >= feedback.capacity {
+            return Err(RendererError::Validation(format!(
+                "all {} texture feedback slots are in use",
+                feedback.capacity
+            )));
+        }
+        feedback.slots.push(texture);
+        Ok(feedback.slots.len() as u32 - 1)
+    }
+
+    /// 完了したフレームの読み戻しを取り込み、このフレームのフィードバックを初期化する
+    ///
+    /// フレームの最初の、フィードバックを記録するパスより前にレンダーパスの外で呼ぶ。
+    pub fn begin_texture_feedback(&mut self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        self.collect_texture_feedback()?;
+        let buffer = self.enabled_texture_feedback()?.buffer;
+        let raw_buffer = self.buffers.get(buffer).unwrap().buffer;
+        unsafe {
+            self.device.cmd_fill_buffer(
+                command_buffer,
+                raw_buffer,
+                0,
+                vk::WHOLE_SIZE,
+                TEXTURE_FEEDBACK_UNSAMPLED,
+            );
+        }
+        self.buffer_barrier(
+            command_buffer,
+            buffer,
+            BufferAccess::TRANSFER_WRITE,
+            BufferAccess {
+                stage: vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COMPUTE_SHADER,
+                access: vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            },
+        )
+    }
+
+    /// このフレームのフィードバックを読み戻す。数フレーム後の`begin_texture_feedback`で取り込む
+    ///
+    /// フィードバックを記録するパスの後にレンダーパスの外で呼ぶ。
+    pub fn end_texture_feedback(&mut self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        let feedback = self.enabled_texture_feedback()?;
+        let buffer = feedback.buffer;
+        let slots = feedback.slots.len();
+        if slots == 0 {
+            return Ok(());
+        }
+        self.buffer_barrier(
+            command_buffer,
+            buffer,
+            BufferAccess {
+                stage: vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COMPUTE_SHADER,
+                access: vk::AccessFlags::SHADER_WRITE,
+            },
+            BufferAccess::TRANSFER_READ,
+        )?;
+        let readback = self.readback_buffer(
+            command_buffer,
+            buffer,
+            0,
+            SLOT_SIZE * slots as vk::DeviceSize,
+        )?;
+        self.texture_feedback
+            .as_mut()
+            .unwrap()
+            .pending
+            .push_back((readback, slots));
+        Ok(())
+    }
+
+    /// 最後に取り込んだフレームでの`texture`の使われ方
+    ///
+    /// ストリーミングで常駐させるミップを決めるのに使う。
+    pub fn texture_usage(&self, texture: TextureId) -> Option<&TextureUsage> {
+        self.texture_feedback.as_ref()?.usages.get(&texture)
+    }
+
+    /// スロットを割り当てたテクスチャの、サンプリングされなかったミップのメモリ
+    ///
+    /// フォーマットのブロックが分からないテクスチャと破棄されたテクスチャは含まない。
+    pub fn texture_waste_report(&self) -> Result<TextureWasteReport> {
+        let feedback = self.enabled_texture_feedback()?;
+        let mut textures: Vec<_> = feedback
+            .slots
+            .iter()
+            .filter_map(|&id| {
+                let texture = self.textures.get(id)?;
+                let block = format_block(texture.format)?;
+                let finest_mip = feedback.usages.get(&id).and_then(TextureUsage::finest_mip);
+                let layers = texture.kind.array_layers() as u64;
+                let level_bytes: Vec<u64> = (0..texture.mip_levels)
+                    .map(|level| {
+                        let width = (texture.extent.width >> level).max(1);
+                        let height = (texture.extent.height >> level).max(1);
+                        let depth = (texture.kind.depth() >> level).max(1) as u64;
+                        block.level_size(width, height) as u64 * layers * depth
+                    })
+                    .collect();
+                let wasted = finest_mip.map_or(level_bytes.len(), |mip| mip as usize);
+                Some(TextureWaste {
+                    texture: id,
+                    resident_bytes: level_bytes.iter().sum(),
+                    wasted_bytes: level_bytes.iter().take(wasted).sum(),
+                    finest_mip,
+                })
+            })
+            .collect();
+        textures.sort_by_key(|waste| std::cmp::Reverse(waste.wasted_bytes));
+        Ok(TextureWasteReport { textures })
+    }
+
+    /// 完了した読み戻しを古い順に取り込んで解放する
+    fn collect_texture_feedback(&mut self) -> Result<()> {
+        loop {
+            let Some(&(readback, slots)) = self.enabled_texture_feedback()?.pending.front() else {
+                return Ok(());
+            };
+            let Some(data) = self.readback_data(readback)? else {
+                return Ok(());
+            };
+            let region_mips: Vec<u32> = data
+                .chunks_exact(4)
+                .map(|bytes| u32::from_ne_bytes(bytes.try_into().unwrap()))
+                .collect();
+            let frame = self.readbacks.get(readback).unwrap().frame;
+            self.release_readback(readback)?;
+            let feedback = self.texture_feedback.as_mut().unwrap();
+            feedback.pending.pop_front();
+            for (slot, region_mips) in region_mips
+                .chunks_exact(TEXTURE_FEEDBACK_REGIONS)
+                .take(slots)
+                .enumerate()
+            {
+                feedback.usages.insert(
+                    feedback.slots[slot],
+                    TextureUsage {
+                        frame,
+                        region_mips: region_mips.to_vec(),
+                    },
+                );
+            }
+        }
+    }
+
+    fn enabled_texture_feedback(&self) -> Result<&TextureFeedback> {
+        self.texture_feedback
+            .as_ref()
+            .ok_or_else(|| RendererError::Validation("texture feedback is not enabled".to_owned()))
+    }
+
+    fn enabled_texture_feedback_mut(&mut self) -> Result<&mut TextureFeedback> {
+        self.texture_feedback
+            .as_mut()
+            .ok_or_else(|| RendererError::Validation("texture feedback is not enabled".to_owned()))
+    }
+}
@@ -0,0 +1,333 @@
+use super::buffer::{create_buffer, write_buffer};
+use super::error::{RendererError, Result};
+use super::texture::{level_copy_region, mip_level_count, TextureId};
+use super::Renderer;
+use ash::vk::PhysicalDevice;
+use ash::{vk, Instance};
+
+// ステージングバッファ内の各ミップの先頭を揃える境界。どのブロックサイズの倍数にもなる
+const LEVEL_ALIGNMENT: usize = 16;
+
+/// 圧縮テクスチャフォーマットの系統
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CompressionFamily {
+    /// モバイル向け。ブロックサイズを選べる
+    Astc,
+    /// OpenGL ES 3.0以降のモバイルGPUで広く使える
+    Etc2,
+    /// デスクトップ向け
+    Bc,
+}
+
+/// デバイスがサンプリングに対応している圧縮フォーマットの系統
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TextureFormatSupport {
+    pub astc_ldr: bool,
+    pub etc2: bool,
+    pub bc: bool,
+}
+
+impl TextureFormatSupport {
+    /// デバイスの機能と、各系統の代表的なフォーマットのフォーマット機能を調べる
+    pub(crate) unsafe fn query(instance: &Instance, pdevice: PhysicalDevice) -> Self {
+        let features = instance.get_physical_device_features(pdevice);
+        let sampled = |format| {
+            instance
+                .get_physical_device_format_properties(pdevice, format)
+                .optimal_tiling_features
+                .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE)
+        };
+        Self {
+            astc_ldr: features.texture_compression_astc_ldr == vk::TRUE
+                && sampled(vk::Format::ASTC_4X4_SRGB_BLOCK),
+            etc2: features.texture_compression_etc2 == vk::TRUE
+                && sampled(vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK),
+            bc: features.texture_compression_bc == vk::TRUE && sampled(vk::Format::BC7_SRGB_BLOCK),
+        }
+    }
+
+    pub fn supports(&self, family: CompressionFamily) -> bool {
+        match family {
+            CompressionFamily::Astc => self.astc_ldr,
+            CompressionFamily::Etc2 => self.etc2,
+            CompressionFamily::Bc => self.bc,
+        }
+    }
+
+    /// 優先する圧縮フォーマットの系統。ASTC、ETC2、BCの順に選ぶ
+    pub fn preferred_family(&self) -> Option<CompressionFamily> {
+        [
+            CompressionFamily::Astc,
+            CompressionFamily::Etc2,
+            CompressionFamily::Bc,
+        ]
+        .into_iter()
+        .find(|&family| self.supports(family))
+    }
+
+    /// KTX2(Basis Universal)などをトランスコードする際の、アルファ付きカラーの出力フォーマット
+    ///
+    /// 圧縮フォーマットに対応していなければ非圧縮のRGBA8を返す。
+    pub fn transcode_target(&self, srgb: bool) -> vk::Format {
+        match (self.preferred_family(), srgb) {
+            (Some(CompressionFamily::Astc), true) => vk::Format::ASTC_4X4_SRGB_BLOCK,
+            (Some(CompressionFamily::Astc), false) => vk::Format::ASTC_4X4_UNORM_BLOCK,
+            (Some(CompressionFamily::Etc2), true) => vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK,
+            (Some(CompressionFamily::Etc2), false) => vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK,
+            (Some(CompressionFamily::Bc), true) => vk::Format::BC7_SRGB_BLOCK,
+            (Some(CompressionFamily::Bc), false) => vk::Format::BC7_UNORM_BLOCK,
+            (None, true) => vk::Format::R8G8B8A8_SRGB,
+            (None, false) => vk::Format::R8G8B8A8_UNORM,
+        }
+    }
+}
+
+/// フォーマットのテクセルブロック。非圧縮フォーマットは1x1のブロックとして扱う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatBlock {
+    pub width: u32,
+    pub height: u32,
+    pub bytes: u32,
+}
+
+impl FormatBlock {
+    const fn new(width: u32, height: u32, bytes: u32) -> Self {
+        Self {
+            width,
+            height,
+            bytes,
+        }
+    }
+
+    /// `width`x`height`のイメージ1枚のバイト数
+    pub fn level_size(&self, width: u32, height: u32) -> usize {
+        let blocks_x = width.div_ceil(self.width) as usize;
+        let blocks_y = height.div_ceil(self.height) as usize;
+        blocks_x * blocks_y * self.bytes as usize
+    }
+}
+
+/// テクスチャとしてアップロードできるフォーマットのブロック。未対応のフォーマットは`None`
+pub fn format_block(format: vk::Format) -> Option<FormatBlock> {
+    use vk::Format as F;
+    let block = match format {
+        F::R8G8B8A8_UNORM | F::R8G8B8A8_SRGB | F::B8G8R8A8_UNORM | F::B8G8R8A8_SRGB => {
+            FormatBlock::new(1, 1, 4)
+        }
+        F::R16G16B16A16_SFLOAT => FormatBlock::new(1, 1, 8),
+        F::R32G32B32A32_SFLOAT => FormatBlock::new(1, 1, 16),
+
+        F::R8_UINT | F::R8_SINT => FormatBlock::new(1, 1, 1),
+        F::R16_UINT | F::R16_SINT => FormatBlock::new(1, 1, 2),
+        F::R32_UINT | F::R32_SINT | F::R8G8B8A8_UINT | F::R8G8B8A8_SINT => {
+            FormatBlock::new(1, 1, 4)
+        }
+        F::R32G32_UINT | F::R32G32_SINT | F::R16G16B16A16_UINT | F::R16G16B16A16_SINT => {
+            FormatBlock::new(1, 1, 8)
+        }
+        F::R32G32B32A32_UINT | F::R32G32B32A32_SINT => FormatBlock::new(1, 1, 16),
+
+        // ステンシルを含むフォーマットは深度とステンシルを別々に転送する必要があるので扱わない
+        F::D16_UNORM => FormatBlock::new(1, 1, 2),
+        F::D32_SFLOAT => FormatBlock::new(1, 1, 4),
+
+        F::BC1_RGB_UNORM_BLOCK
+        | F::BC1_RGB_SRGB_BLOCK
+        | F::BC1_RGBA_UNORM_BLOCK
+        | F::BC1_RGBA_SRGB_BLOCK
+        | F::BC4_UNORM_BLOCK
+        | F::BC4_SNORM_BLOCK => FormatBlock::new(4, 4, 8),
+        F::BC2_UNORM_BLOCK
+        | F::BC2_SRGB_BLOCK
+        | F::BC3_UNORM_BLOCK
+        | F::BC3_SRGB_BLOCK
+        | F::BC5_UNORM_BLOCK
+        | F::BC5_SNORM_BLOCK
+        | F::BC6H_UFLOAT_BLOCK
+        | F::BC6H_SFLOAT_BLOCK
+        | F::BC7_UNORM_BLOCK
+        | F::BC7_SRGB_BLOCK => FormatBlock::new(4, 4, 16),
+
+        F::ETC2_R8G8B8_UNORM_BLOCK
+        | F::ETC2_R8G8B8_SRGB_BLOCK
+        | F::ETC2_R8G8B8A1_UNORM_BLOCK
+        | F::ETC2_R8G8B8A1_SRGB_BLOCK
+        | F::EAC_R11_UNORM_BLOCK
+        | F::EAC_R11_SNORM_BLOCK => FormatBlock::new(4, 4, 8),
+        F::ETC2_R8G8B8A8_UNORM_BLOCK
+        | F::ETC2_R8G8B8A8_SRGB_BLOCK
+        | F::EAC_R11G11_UNORM_BLOCK
+        | F::EAC_R11G11_SNORM_BLOCK => FormatBlock::new(4, 4, 16),
+
+        F::ASTC_4X4_UNORM_BLOCK | F::ASTC_4X4_SRGB_BLOCK => FormatBlock::new(4, 4, 16),
+        F::ASTC_5X4_UNORM_BLOCK | F::ASTC_5X4_SRGB_BLOCK => FormatBlock::new(5, 4, 16),
+        F::ASTC_5X5_UNORM_BLOCK | F::ASTC_5X5_SRGB_BLOCK => FormatBlock::new(5, 5, 16),
+        F::ASTC_6X5_UNORM_BLOCK | F::ASTC_6X5_SRGB_BLOCK => FormatBlock::new(6, 5, 16),
+        F::ASTC_6X6_UNORM_BLOCK | F::ASTC_6X6_SRGB_BLOCK => FormatBlock::new(6, 6, 16),
+        F::ASTC_8X5_UNORM_BLOCK | F::ASTC_8X5_SRGB_BLOCK => FormatBlock::new(8, 5, 16),
+        F::ASTC_8X6_UNORM_BLOCK | F::ASTC_8X6_SRGB_BLOCK => FormatBlock::new(8, 6, 16),
+        F::ASTC_8X8_UNORM_BLOCK | F::ASTC_8X8_SRGB_BLOCK => FormatBlock::new(8, 8, 16),
+        F::ASTC_10X5_UNORM_BLOCK | F::ASTC_10X5_SRGB_BLOCK => FormatBlock::new(10, 5, 16),
+        F::ASTC_10X6_UNORM_BLOCK | F::ASTC_10X6_SRGB_BLOCK => FormatBlock::new(10, 6, 16),
+        F::ASTC_10X8_UNORM_BLOCK | F::ASTC_10X8_SRGB_BLOCK => FormatBlock::new(10, 8, 16),
+        F::ASTC_10X10_UNORM_BLOCK | F::ASTC_10X10_SRGB_BLOCK => FormatBlock::new(10, 10, 16),
+        F::ASTC_12X10_UNORM_BLOCK | F::ASTC_12X10_SRGB_BLOCK => FormatBlock::new(12, 10, 16),
+        F::ASTC_12X12_UNORM_BLOCK | F::ASTC_12X12_SRGB_BLOCK => FormatBlock::new(12, 12, 16),
+
+        _ => return None,
+    };
+    Some(block)
+}
+
+/// テクスチャをシェーダーで読むときの値の種類
+///
+/// GLSLでは`Float`は`sampler2D`、`Uint`は`usampler2D`、`Sint`は`isampler2D`、`Depth`は
+/// 比較サンプラーなら`sampler2DShadow`、そうでなければ`sampler2D`で宣言する。
+/// `Uint`と`Sint`は線形フィルタリングできない。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TextureSampleType {
+    Float,
+    Uint,
+    Sint,
+    Depth,
+}
+
+/// フォーマットをシェーダーで読むときの値の種類
+pub fn texture_sample_type(format: vk::Format) -> TextureSampleType {
+    use vk::Format as F;
+    match format {
+        F::R8_UINT
+        | F::R8G8_UINT
+        | F::R8G8B8A8_UINT
+        | F::R16_UINT
+        | F::R16G16_UINT
+        | F::R16G16B16A16_UINT
+        | F::R32_UINT
+        | F::R32G32_UINT
+        | F::R32G32B32A32_UINT
+        | F::A2B10G10R10_UINT_PACK32 => TextureSampleType::Uint,
+        F::R8_SINT
+        | F::R8G8_SINT
+        | F::R8G8B8A8_SINT
+        | F::R16_SINT
+        | F::R16G16_SINT
+        | F::R16G16B16A16_SINT
+        | F::R32_SINT
+        | F::R32G32_SINT
+        | F::R32G32B32A32_SINT => TextureSampleType::Sint,
+        F::D16_UNORM
+        | F::X8_D24_UNORM_PACK32
+        | F::D32_SFLOAT
+        | F::D16_UNORM_S8_UINT
+        | F::D24_UNORM_S8_UINT
+        | F::D32_SFLOAT_S8_UINT => TextureSampleType::Depth,
+        _ => TextureSampleType::Float,
+    }
+}
+
+/// サンプリングするビューのアスペクト。深度フォーマットでは深度だけを読む
+pub(crate) fn sampled_aspect_mask(format: vk::Format) -> vk::ImageAspectFlags {
+    if texture_sample_type(format) == TextureSampleType::Depth {
+        vk::ImageAspectFlags::DEPTH
+    } else {
+        vk::ImageAspectFlags::COLOR
+    }
+}
+
+impl Renderer {
+    /// デバイスが対応している圧縮フォーマット
+    pub fn texture_format_support(&self) -> TextureFormatSupport {
+        self.texture_format_support
+    }
+
+    /// エンコード済みのミップ列からテクスチャを作成する
+    ///
+    /// `levels`はミップ0から順に並べる。各レベルのバイト数と、フォーマットがこのデバイスで
+    /// サンプリングできるかを検証する。ミップはここでは生成しない。
+    pub fn create_texture_from_levels(
+        &mut self,
+        format: vk::Format,
+        width: u32,
+        height: u32,
+        levels: &[&[u8]],
+    ) -> Result<TextureId> {
+        if width == 0 || height == 0 {
+            return Err(RendererError::Validation(format!(
+                "texture extent {}x{} must not be zero",
+                width, height
+            )));
+        }
+        let block = format_block(format).ok_or_else(|| {
+            RendererError::Validation(format!(
+                "format {:?} cannot be uploaded as a texture",
+                format
+            ))
+        })?;
+        let format_properties = unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.pdevice, format)
+        };
+        if !format_properties
+            .optimal_tiling_features
+            .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE)
+        {
+            return Err(RendererError::Validation(format!(
+                "format {:?} is not supported for sampling on this device",
+                format
+            )));
+        }
+        let max_levels = mip_level_count(width, height);
+        if levels.is_empty() || levels.len() as u32 > max_levels {
+            return Err(RendererError::Validation(format!(
+                "expected 1 to {} mip levels for {}x{}, got {}",
+                max_levels,
+                width,
+                height,
+                levels.len()
+            )));
+        }
+
+        let extent = vk::Extent2D { width, height };
+        let mut regions = Vec::with_capacity(levels.len());
+        let mut data = Vec::new();
+        for (level, level_data) in levels.iter().enumerate() {
+            let level = level as u32;
+            let expected_len = block.level_size((width >> level).max(1), (height >> level).max(1));
+            if level_data.len() != expected_len {
+                return Err(RendererError::Validation(format!(
+                    "expected {} bytes for mip level {} of {:?} {}x{}, got {}",
+                    expected_len,
+                    level,
+                    format,
+                    width,
+                    height,
+                    level_data.len()
+                )));
+            }
+            data.resize(data.len().next_multiple_of(LEVEL_ALIGNMENT), 0);
+            regions.push(level_copy_region(
+                data.len() as vk::DeviceSize,
+                level,
+                extent,
+                sampled_aspect_mask(format),
+            ));
+            data.extend_from_slice(level_data);
+        }
+
+        unsafe {
+            let staging = create_buffer(
+                &self.device,
+                &mut self.allocator,
+                data.len() as vk::DeviceSize,
+                vk::BufferUsageFlags::TRANSFER_SRC,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )?;
+            let result = write_buffer(&staging, &data).and_then(|_| {
+                self.upload_texture(&staging, &regions, extent, format, levels.len() as u32)
+            });
+            // 転送の完了を待っているので、ステージングバッファはすぐに破棄できる
+            staging.destroy(&self.device, &mut self.allocator);
+            Ok(self.textures.insert(result?))
+        }
+    }
+}
@@ -0,0 +1,543 @@
+use super::buffer::{create_buffer, write_buffer};
+use super::error::{RendererError, Result};
+use super::texture::create_image;
+use super::texture_format::{sampled_aspect_mask, texture_sample_type, TextureSampleType};
+use super::{format_block, mip_level_count, Renderer, Texture, TextureId, TextureKind};
+use ash::vk;
+
+/// テクスチャのサンプラーの設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplerDesc {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    /// u、v、wの順
+    pub address_modes: [vk::SamplerAddressMode; 3],
+    pub border_color: vk::BorderColor,
+    /// `Some`なら深度比較を行うサンプラー(`sampler2DShadow`など)にする
+    pub compare_op: Option<vk::CompareOp>,
+    /// `Some`なら異方性フィルタリングを有効にする。デバイスの機能が有効であること
+    pub max_anisotropy: Option<f32>,
+}
+
+impl Default for SamplerDesc {
+    fn default() -> Self {
+        Self {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            address_modes: [vk::SamplerAddressMode::REPEAT; 3],
+            border_color: vk::BorderColor::FLOAT_TRANSPARENT_BLACK,
+            compare_op: None,
+            max_anisotropy: None,
+        }
+    }
+}
+
+impl SamplerDesc {
+    /// 全ての軸を端の色で延長する。キューブマップやLUTに使う
+    pub fn clamp_to_edge() -> Self {
+        Self {
+            address_modes: [vk::SamplerAddressMode::CLAMP_TO_EDGE; 3],
+            ..Self::default()
+        }
+    }
+
+    /// フィルタリングしない。整数フォーマット(`usampler2D`、`isampler2D`)に使う
+    pub fn nearest() -> Self {
+        Self {
+            mag_filter: vk::Filter::NEAREST,
+            min_filter: vk::Filter::NEAREST,
+            mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+            ..Self::clamp_to_edge()
+        }
+    }
+
+    /// 深度テクスチャを`compare_op`で比較する(`sampler2DShadow`)。線形フィルタリングで2x2のPCFになる
+    pub fn comparison(compare_op: vk::CompareOp) -> Self {
+        Self {
+            mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+            compare_op: Some(compare_op),
+            ..Self::clamp_to_edge()
+        }
+    }
+}
+
+/// `create_texture`で作る空のテクスチャ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextureDesc {
+    pub kind: TextureKind,
+    pub format: vk::Format,
+    pub width: u32,
+    pub height: u32,
+    pub mip_levels: u32,
+    pub sampler: SamplerDesc,
+    /// コンピュートシェーダーから書き込めるようにする。フォーマットがストレージイメージに対応していること
+    pub storage: bool,
+}
+
+/// テクスチャの一部のミップとレイヤーを見るビュー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextureViewDesc {
+    pub view_type: vk::ImageViewType,
+    pub base_mip_level: u32,
+    pub mip_level_count: u32,
+    pub base_layer: u32,
+    pub layer_count: u32,
+}
+
+impl Renderer {
+    /// 設定からサンプラーを作成する。`destroy_sampler`で破棄する
+    pub fn create_sampler(&self, desc: &SamplerDesc) -> Result<vk::Sampler> {
+        let [address_mode_u, address_mode_v, address_mode_w] = desc.address_modes;
+        let mut sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(desc.mag_filter)
+            .min_filter(desc.min_filter)
+            .mipmap_mode(desc.mipmap_mode)
+            .address_mode_u(address_mode_u)
+            .address_mode_v(address_mode_v)
+            .address_mode_w(address_mode_w)
+            .border_color(desc.border_color)
+            .min_lod(0.0)
+            .max_lod(vk::LOD_CLAMP_NONE);
+        if let Some(compare_op) = desc.compare_op {
+            sampler_info = sampler_info.compare_enable(true).compare_op(compare_op);
+        }
+        if let Some(max_anisotropy) = desc.max_anisotropy {
+            sampler_info = sampler_info
+                .anisotropy_enable(true)
+                .max_anisotropy(max_anisotropy);
+        }
+        Ok(unsafe { self.device.create_sampler(&sampler_info, None)? })
+    }
+
+    /// 使用中のフレームが完了してからサンプラーを破棄する
+    pub fn destroy_sampler(&mut self, sampler: vk::Sampler) {
+        self.destroy_deferred(move |device, _| unsafe { device.destroy_sampler(sampler, None) });
+    }
+
+    /// テクスチャのサンプラーを`desc`で作り直す。前のサンプラーは使用中のフレームが
+    /// 完了してから破棄する
+    pub fn set_texture_sampler(&mut self, id: TextureId, desc: &SamplerDesc) -> Result<()> {
+        let texture = self
+            .textures
+            .get(id)
+            .ok_or_else(|| RendererError::Validation(format!("texture {:?} was destroyed", id)))?;
+        if texture.external {
+            return Err(RendererError::Validation(format!(
+                "texture {:?} is external, so its sampler is owned by the application",
+                id
+            )));
+        }
+        self.validate_sampler_format(texture.format, desc)?;
+        let sampler = self.create_sampler(desc)?;
+        let texture = self.textures.get_mut(id).unwrap();
+        let old = std::mem::replace(&mut texture.sampler, sampler);
+        self.destroy_sampler(old);
+        Ok(())
+    }
+
+    /// 中身が未定義の空のテクスチャを作成する
+    ///
+    /// 全てのミップとレイヤーは`SHADER_READ_ONLY_OPTIMAL`になっている。`write_texture_layer`で
+    /// レイヤー(3Dテクスチャではスライス)ごと、ミップごとに転送する。
+    pub fn create_texture(&mut self, desc: &TextureDesc) -> Result<TextureId> {
+        let depth = desc.kind.depth();
+        let array_layers = desc.kind.array_layers();
+        if desc.width == 0 || desc.height == 0 || depth == 0 || array_layers == 0 {
+            return Err(RendererError::Validation(format!(
+                "texture extent {}x{}x{} with {} layers must not be zero",
+                desc.width, desc.height, depth, array_layers
+            )));
+        }
+        if desc.kind == TextureKind::Cube && desc.width != desc.height {
+            return Err(RendererError::Validation(format!(
+                "cube map faces must be square, got {}x{}",
+                desc.width, desc.height
+            )));
+        }
+        // 3Dテクスチャでは奥行きも半分ずつにする
+        let max_levels = mip_level_count(desc.width.max(depth), desc.height);
+        if desc.mip_levels == 0 || desc.mip_levels > max_levels {
+            return Err(RendererError::Validation(format!(
+                "expected 1 to {} mip levels, got {}",
+                max_levels, desc.mip_levels
+            )));
+        }
+        if format_block(desc.format).is_none() {
+            return Err(RendererError::Validation(format!(
+                "format {:?} cannot be uploaded as a texture",
+                desc.format
+            )));
+        }
+        if texture_sample_type(desc.format) == TextureSampleType::Depth
+            && matches!(desc.kind, TextureKind::Texture3D { .. })
+        {
+            return Err(RendererError::Validation(format!(
+                "depth format {:?} cannot be used for a 3D texture",
+                desc.format
+            )));
+        }
+        self.validate_sampler_format(desc.format, &desc.sampler)?;
+        let mut usage = vk::ImageUsageFlags::TRANSFER_SRC
+            | vk::ImageUsageFlags::TRANSFER_DST
+            | vk::ImageUsageFlags::SAMPLED;
+        if desc.storage {
+            let storage_supported = unsafe {
+                self.instance
+                    .get_physical_device_format_properties(self.pdevice, desc.format)
+            }
+            .optimal_tiling_features
+            .contains(vk::FormatFeatureFlags::STORAGE_IMAGE);
+            if !storage_supported {
+                return Err(RendererError::Validation(format!(
+                    "format {:?} cannot be used as a storage image",
+                    desc.format
+                )));
+            }
+            usage |= vk::ImageUsageFlags::STORAGE;
+        }
+        let aspect_mask = sampled_aspect_mask(desc.format);
+        let (image_type, flags) = match desc.kind {
+            TextureKind::Texture3D { .. } => {
+                (vk::ImageType::TYPE_3D, vk::ImageCreateFlags::empty())
+            }
+            TextureKind::Cube => (
+                vk::ImageType::TYPE_2D,
+                vk::ImageCreateFlags::CUBE_COMPATIBLE,
+            ),
+            _ => (vk::ImageType::TYPE_2D, vk::ImageCreateFlags::empty()),
+        };
+        let image_info = *vk::ImageCreateInfo::builder()
+            .flags(flags)
+            .image_type(image_type)
+            .format(desc.format)
+            .extent(vk::Extent3D {
+                width: desc.width,
+                height: desc.height,
+                depth,
+            })
+            .mip_levels(desc.mip_levels)
+            .array_layers(array_layers)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+
+        unsafe {
+            let (image, allocation) = create_image(
+                &self.device,
+                &mut self.allocator,
+                &image_info,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                false,
+            )?;
+            let barrier = *vk::ImageMemoryBarrier::builder()
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask,
+                    base_mip_level: 0,
+                    level_count: desc.mip_levels,
+                    base_array_layer: 0,
+                    layer_count: array_layers,
+                });
+            let view_info = *vk::ImageViewCreateInfo::builder()
+                .image(image)
+                .view_type(desc.kind.view_type())
+                .format(desc.format)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask,
+                    base_mip_level: 0,
+                    level_count: desc.mip_levels,
+                    base_array_layer: 0,
+                    layer_count: array_layers,
+                });
+            let created = self
+                .submit_setup_commands(|device, command_buffer| {
+                    device.cmd_pipeline_barrier(
+                        command_buffer,
+                        vk::PipelineStageFlags::TOP_OF_PIPE,
+                        vk::PipelineStageFlags::FRAGMENT_SHADER,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &[],
+                        &[barrier],
+                    );
+                })
+                .and_then(|_| Ok(self.device.create_image_view(&view_info, None)?))
+                .and_then(|view| match self.create_sampler(&desc.sampler) {
+                    Ok(sampler) => Ok((view, sampler)),
+                    Err(err) => {
+                        self.device.destroy_image_view(view, None);
+                        Err(err)
+                    }
+                });
+            match created {
+                Ok((view, sampler)) => Ok(self.textures.insert(Texture {
+                    image,
+                    allocation,
+                    view,
+                    sampler,
+                    extent: vk::Extent2D {
+                        width: desc.width,
+                        height: desc.height,
+                    },
+                    format: desc.format,
+                    mip_levels: desc.mip_levels,
+                    kind: desc.kind,
+                    usage,
+                    external: false,
+                })),
+                Err(err) => {
+                    self.device.destroy_image(image, None);
+                    self.allocator.free(&self.device, allocation);
+                    Err(err)
+                }
+            }
+        }
+    }
+
+    /// テクスチャの`layer`番目のレイヤー(3Dテクスチャではスライス)の`mip_level`に転送する
+    ///
+    /// `data`はそのミップのサイズでフォーマットのブロック単位に詰めたもの。転送の完了を待って
+    /// 返す。記録中のフレームが使っているテクスチャには書き込まないこと。
+    pub fn write_texture_layer(
+        &mut self,
+        id: TextureId,
+        layer: u32,
+        mip_level: u32,
+        data: &[u8],
+    ) -> Result<()> {
+        let texture = *self
+            .textures
+            .get(id)
+            .ok_or_else(|| RendererError::Validation(format!("texture {:?} was destroyed", id)))?;
+        let layer_limit = match texture.kind {
+            TextureKind::Texture3D { depth } => (depth >> mip_level).max(1),
+            kind => kind.array_layers(),
+        };
+        if mip_level >= texture.mip_levels || layer >= layer_limit {
+            return Err(RendererError::Validation(format!(
+                "layer {} of mip level {} is out of range for texture {:?} ({} layers, {} mip levels)",
+                layer, mip_level, id, layer_limit, texture.mip_levels
+            )));
+        }
+        let block = format_block(texture.format).ok_or_else(|| {
+            RendererError::Validation(format!(
+                "format {:?} cannot be uploaded as a texture",
+                texture.format
+            ))
+        })?;
+        let width = (texture.extent.width >> mip_level).max(1);
+        let height = (texture.extent.height >> mip_level).max(1);
+        let expected_len = block.level_size(width, height);
+        if data.len() != expected_len {
+            return Err(RendererError::Validation(format!(
+                "expected {} bytes for a {}x{} layer of {:?}, got {}",
+                expected_len,
+                width,
+                height,
+                texture.format,
+                data.len()
+            )));
+        }
+
+        let aspect_mask = sampled_aspect_mask(texture.format);
+        let (base_array_layer, z) = match texture.kind {
+            TextureKind::Texture3D { .. } => (0, layer),
+            _ => (layer, 0),
+        };
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask,
+            base_mip_level: mip_level,
+            level_count: 1,
+            base_array_layer,
+            layer_count: 1,
+        };
+        let region = vk::BufferImageCopy {
+            buffer_offset: 0,
+            buffer_row_length: 0,
+            buffer_image_height: 0,
+            image_subresource: vk::ImageSubresourceLayers {
+                aspect_mask,
+                mip_level,
+                base_array_layer,
+                layer_count: 1,
+            },
+            image_offset: vk::Offset3D {
+                x: 0,
+                y: 0,
+                z: z as i32,
+            },
+            image_extent: vk::Extent3D {
+                width,
+                height,
+                depth: 1,
+            },
+        };
+        let barrier = |old_layout, new_layout, src_access_mask, dst_access_mask| {
+            *vk::ImageMemoryBarrier::builder()
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_access_mask(src_access_mask)
+                .dst_access_mask(dst_access_mask)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(texture.image)
+                .subresource_range(subresource_range)
+        };
+        let to_transfer = barrier(
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::AccessFlags::SHADER_READ,
+            vk::AccessFlags::TRANSFER_WRITE,
+        );
+        let to_read_only = barrier(
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::AccessFlags::SHADER_READ,
+        );
+
+        unsafe {
+            let staging = create_buffer(
+                &self.device,
+                &mut self.allocator,
+                data.len() as vk::DeviceSize,
+                vk::BufferUsageFlags::TRANSFER_SRC,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )?;
+            let result = write_buffer(&staging, data).and_then(|_| {
+                self.submit_setup_commands(|device, command_buffer| {
+                    device.cmd_pipeline_barrier(
+                        command_buffer,
+                        vk::PipelineStageFlags::FRAGMENT_SHADER,
+                        vk::PipelineStageFlags::TRANSFER,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &[],
+                        &[to_transfer],
+                    );
+                    device.cmd_copy_buffer_to_image(
+                        command_buffer,
+                        staging.buffer,
+                        texture.image,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        &[region],
+                    );
+                    device.cmd_pipeline_barrier(
+                        command_buffer,
+                        vk::PipelineStageFlags::TRANSFER,
+                        vk::PipelineStageFlags::FRAGMENT_SHADER,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &[],
+                        &[to_read_only],
+                    );
+                })
+            });
+            // 転送の完了を待っているので、ステージングバッファはすぐに破棄できる
+            staging.destroy(&self.device, &mut self.allocator);
+            result
+        }
+    }
+
+    /// テクスチャの一部のミップとレイヤーを見るビューを作成する
+    ///
+    /// キューブマップの1面を2Dとして、配列の一部を2D配列として使う場合などに使う。
+    /// `destroy_texture_view`で、テクスチャより先に破棄すること。
+    pub fn create_texture_view(
+        &self,
+        id: TextureId,
+        desc: &TextureViewDesc,
+    ) -> Result<vk::ImageView> {
+        let texture = self
+            .textures
+            .get(id)
+            .ok_or_else(|| RendererError::Validation(format!("texture {:?} was destroyed", id)))?;
+        let array_layers = texture.kind.array_layers();
+        let compatible = match (texture.kind, desc.view_type) {
+            (TextureKind::Texture3D { .. }, view_type) => view_type == vk::ImageViewType::TYPE_3D,
+            (_, vk::ImageViewType::TYPE_2D) => desc.layer_count == 1,
+            (_, vk::ImageViewType::TYPE_2D_ARRAY) => true,
+            (TextureKind::Cube, vk::ImageViewType::CUBE) => desc.layer_count == 6,
+            _ => false,
+        };
+        let in_range = desc.mip_level_count > 0
+            && desc.layer_count > 0
+            && desc.base_mip_level + desc.mip_level_count <= texture.mip_levels
+            && desc.base_layer + desc.layer_count <= array_layers;
+        if !compatible || !in_range {
+            return Err(RendererError::Validation(format!(
+                "{:?} view of mips {}+{} and layers {}+{} is invalid for a {:?} texture with {} mip levels",
+                desc.view_type,
+                desc.base_mip_level,
+                desc.mip_level_count,
+                desc.base_layer,
+                desc.layer_count,
+                texture.kind,
+                texture.mip_levels
+            )));
+        }
+        let aspect_mask = sampled_aspect_mask(texture.format);
+        let view_info = *vk::ImageViewCreateInfo::builder()
+            .image(texture.image)
+            .view_type(desc.view_type)
+            .format(texture.format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask,
+                base_mip_level: desc.base_mip_level,
+                level_count: desc.mip_level_count,
+                base_array_layer: desc.base_layer,
+                layer_count: desc.layer_count,
+            });
+        Ok(unsafe { self.device.create_image_view(&view_info, None)? })
+    }
+
+    /// 使用中のフレームが完了してからビューを破棄する
+    pub fn destroy_texture_view(&mut self, view: vk::ImageView) {
+        self.destroy_deferred(move |device, _| unsafe { device.destroy_image_view(view, None) });
+    }
+
+    /// `format`のテクスチャを`desc`のサンプラーで読めるか確認する
+    ///
+    /// 比較は深度フォーマットだけで行える。線形フィルタリングはフォーマットが対応している
+    /// 場合だけ使え、整数フォーマットは常に対応していない。
+    fn validate_sampler_format(&self, format: vk::Format, desc: &SamplerDesc) -> Result<()> {
+        let sample_type = texture_sample_type(format);
+        if desc.compare_op.is_some() && sample_type != TextureSampleType::Depth {
+            return Err(RendererError::Validation(format!(
+                "comparison samplers can only be used with depth formats, not {:?}",
+                format
+            )));
+        }
+        let linear = desc.mag_filter == vk::Filter::LINEAR
+            || desc.min_filter == vk::Filter::LINEAR
+            || desc.mipmap_mode == vk::SamplerMipmapMode::LINEAR;
+        let filterable = unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.pdevice, format)
+        }
+        .optimal_tiling_features
+        .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR);
+        if linear
+            && (!filterable
+                || matches!(
+                    sample_type,
+                    TextureSampleType::Uint | TextureSampleType::Sint
+                ))
+        {
+            return Err(RendererError::Validation(format!(
+                "format {:?} cannot be sampled with linear filtering; use SamplerDesc::nearest",
+                format
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use super::anti_aliasing::AntiAliasingSettings;
+use super::camera_effects::{DepthOfFieldSettings, MotionBlurSettings};
+use super::error::{RendererError, Result};
+use super::outline::OutlineSettings;
+use super::post_process::{BloomSettings, PostProcessSettings};
+use super::screen_space_reflections::ScreenSpaceReflectionSettings;
+use super::{Renderer, ToneMapOperator, ToneMappingSettings};
+
+/// ビューごとに全体の設定を上書きする項目。`None`の項目は全体の設定を使う
+///
+/// 監視カメラの映像を`saturation: Some(0.0)`で白黒にするなど、カメラごとの見た目に使う。
+/// [`Node::view_settings`](super::Node::view_settings)でカメラのノードに持たせられる。
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ViewSettings {
+    pub exposure: Option<f32>,
+    pub tone_map_operator: Option<ToneMapOperator>,
+    pub saturation: Option<f32>,
+    pub grain: Option<f32>,
+    pub reflections: Option<ScreenSpaceReflectionSettings>,
+    pub outline: Option<OutlineSettings>,
+    pub anti_aliasing: Option<AntiAliasingSettings>,
+    pub depth_of_field: Option<DepthOfFieldSettings>,
+    pub motion_blur: Option<MotionBlurSettings>,
+    pub bloom: Option<BloomSettings>,
+}
+
+/// 全体の設定にビューの上書きを重ねた、ビューで実際に使う設定
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ResolvedViewSettings {
+    pub tone_mapping: ToneMappingSettings,
+    pub post_process: PostProcessSettings,
+}
+
+impl ViewSettings {
+    /// 全体の設定に、このビューで`Some`の項目を重ねる
+    pub fn resolve(
+        &self,
+        tone_mapping: &ToneMappingSettings,
+        post_process: &PostProcessSettings,
+    ) -> ResolvedViewSettings {
+        ResolvedViewSettings {
+            tone_mapping: ToneMappingSettings {
+                operator: self.tone_map_operator.unwrap_or(tone_mapping.operator),
+                exposure: self.exposure.unwrap_or(tone_mapping.exposure),
+                saturation: self.saturation.unwrap_or(tone_mapping.saturation),
+                grain: self.grain.unwrap_or(tone_mapping.grain),
+            },
+            post_process: PostProcessSettings {
+                reflections: self.reflections.unwrap_or(post_process.reflections),
+                outline: self.outline.unwrap_or(post_process.outline),
+                anti_aliasing: self.anti_aliasing.unwrap_or(post_process.anti_aliasing),
+                depth_of_field: self.depth_of_field.unwrap_or(post_process.depth_of_field),
+                motion_blur: self.motion_blur.unwrap_or(post_process.motion_blur),
+                bloom: self.bloom.unwrap_or(post_process.bloom),
+            },
+        }
+    }
+}
+
+impl Renderer {
+    /// これから描くビューの上書きを設定する。`ViewSettings::default()`で全体の設定に戻す
+    ///
+    /// 次の`apply_post_processing`と`tone_map`から反映される。1フレームで複数のビューを
+    /// 描く場合は、ビューごとにポストプロセスの前で設定する。TAAの履歴はビューごとに
+    /// 分かれないので、TAAを使うビューを切り替えたら`reset_anti_aliasing_history`を呼ぶ。
+    pub fn set_view_settings(&mut self, settings: ViewSettings) -> Result<()> {
+        let tone_mapping = self
+            .hdr
+            .as_ref()
+            .map(|hdr| hdr.settings)
+            .unwrap_or_default();
+        let resolved = settings.resolve(&tone_mapping, &self.post_process);
+        self.validate_post_process(&resolved.post_process)?;
+        validate_tone_mapping(&resolved.tone_mapping)?;
+        self.view_settings = settings;
+        Ok(())
+    }
+
+    pub fn view_settings(&self) -> &ViewSettings {
+        &self.view_settings
+    }
+
+    /// 現在のビューで使うトーンマッピングとポストプロセスの設定
+    pub fn resolved_view_settings(&self) -> ResolvedViewSettings {
+        let tone_mapping = self
+            .hdr
+            .as_ref()
+            .map(|hdr| hdr.settings)
+            .unwrap_or_default();
+        self.view_settings
+            .resolve(&tone_mapping, &self.post_process)
+    }
+
+    /// 現在のビューで使うポストプロセスの設定
+    pub(crate) fn view_post_process(&self) -> PostProcessSettings {
+        self.view_settings
+            .resolve(&ToneMappingSettings::default(), &self.post_process)
+            .post_process
+    }
+}
+
+pub(crate) fn validate_tone_mapping(settings: &ToneMappingSettings) -> Result<()> {
+    if !settings.exposure.is_finite()
+        || settings.exposure < 0.0
+        || !settings.saturation.is_finite()
+        || settings.saturation < 0.0
+        || !settings.grain.is_finite()
+        || settings.grain < 0.0
+    {
+        return Err(RendererError::Validation(format!(
+            "invalid tone mapping settings: {:?}",
+            settings
+        )));
+    }
+    Ok(())
+}